@@ -0,0 +1,73 @@
+//! Criterion benchmarks of parsing (with and without
+//! `FrontendOptions::preallocate`), each pass of the `-O2` pipeline,
+//! and emission, over the corpus from `waffle::bench::corpus()` plus
+//! the round-trip test modules. Set `WAFFLE_BENCH_CORPUS` to a
+//! directory of `.wasm` files to add larger modules.
+//!
+//! Run with `cargo bench --features bench --bench phases`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use waffle::bench::{corpus, module_options, parse, run_pass};
+use waffle::passes::pipeline::Pipeline;
+use waffle::{FrontendOptions, OptLevel, OptOptions};
+
+fn modules() -> Vec<(String, Vec<u8>)> {
+    let mut modules = corpus(None).unwrap();
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roundtrip");
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wat"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        modules.push((name, wat::parse_file(&path).unwrap()));
+    }
+    modules
+}
+
+fn phases(c: &mut Criterion) {
+    let frontend = FrontendOptions::default();
+    let preallocate = FrontendOptions {
+        preallocate: true,
+        ..FrontendOptions::default()
+    };
+    let pipeline = Pipeline::from(OptOptions::level(OptLevel::O2));
+    for (name, bytes) in modules() {
+        let mut group = c.benchmark_group(&name);
+        group.sample_size(10);
+        group.bench_function("parse", |b| b.iter(|| parse(&bytes, &frontend).unwrap()));
+        group.bench_function("parse-preallocate", |b| {
+            b.iter(|| parse(&bytes, &preallocate).unwrap())
+        });
+
+        // Each pass sees the module as the passes before it left it.
+        let mut module = parse(&bytes, &frontend).unwrap();
+        let options = module_options(&module, &pipeline);
+        for &pass in &pipeline.passes {
+            group.bench_function(pass.name(), |b| {
+                b.iter_batched(
+                    || {
+                        // Unshare the bodies so the copy isn't timed.
+                        let mut module = module.clone();
+                        module.per_func_body(|_| {});
+                        module
+                    },
+                    |mut module| {
+                        run_pass(&mut module, pass, &options);
+                        module
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+            run_pass(&mut module, pass, &options);
+        }
+
+        group.bench_function("emit", |b| b.iter(|| module.to_wasm_bytes().unwrap()));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, phases);
+criterion_main!(benches);
@@ -86,7 +86,7 @@ impl CFG {
         body.validate().unwrap();
         module
             .funcs
-            .push(FuncDecl::Body(sig, "func0".to_string(), body));
+            .push(FuncDecl::Body(sig, "func0".to_string(), body.into()));
 
         Some(module)
     }
@@ -0,0 +1,20 @@
+#!/usr/bin/env bash
+#
+# Download large Wasm modules for the benchmark corpus:
+#
+#   scripts/fetch-bench-corpus.sh bench-corpus https://.../big.wasm ...
+#   WAFFLE_BENCH_CORPUS=bench-corpus cargo bench --features bench
+
+set -e
+
+if [ $# -lt 2 ]; then
+    echo "usage: $0 DIR URL..."
+    exit 1
+fi
+
+dir=$1
+shift
+mkdir -p "$dir"
+for url in "$@"; do
+    curl -fL -o "$dir/$(basename "$url")" "$url"
+done
@@ -0,0 +1,153 @@
+//! Compilation caching.
+//!
+//! Compiling a function body (reducifying, stackifying, and
+//! allocating locals) is the most expensive part of producing Wasm
+//! from IR. When a mostly-unchanged module is built repeatedly, a
+//! `CompileCache` lets the backend reuse the encoded bytes of
+//! functions it has seen before, keyed by their structural hash.
+
+use crate::backend::BackendOptions;
+use crate::ir::FunctionBody;
+use anyhow::Result;
+use std::hash::Hash;
+use std::path::PathBuf;
+use twox_hash::xxh3::HasherExt;
+
+/// A store of compiled function bodies. Lookups and insertions may
+/// happen concurrently from the backend's worker threads.
+pub trait CompileCache: Sync {
+    /// Return the encoded body previously stored under `key`, if any.
+    fn get(&self, key: u128) -> Option<Vec<u8>>;
+    /// Store an encoded body under `key`. Failures are not reported:
+    /// a cache that loses an entry only costs a recompilation.
+    fn put(&self, key: u128, bytes: &[u8]);
+}
+
+/// The key under which `body`'s compiled form is cached. It covers
+/// everything the backend's output depends on: the body's structure
+/// and signature, the backend options (with the cost model identified
+/// by its `cache_id()`), and the backend itself (by crate version,
+/// since structural hashes are not stable across versions).
+///
+/// A wrong hit would silently miscompile, so the key is 128 bits
+/// wide, making accidental collisions negligible even in a cache
+/// shared by many builds.
+pub fn cache_key(body: &FunctionBody, options: &BackendOptions) -> u128 {
+    let mut hasher = twox_hash::xxh3::Hash128::default();
+    body.hash_structure(&mut hasher);
+    options.remat_globals.hash(&mut hasher);
+    options.remat_addresses.hash(&mut hasher);
+    options.max_remat_size.hash(&mut hasher);
+    options.schedule.hash(&mut hasher);
+    options.cost_model.cache_id().hash(&mut hasher);
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    hasher.finish_ext()
+}
+
+/// A `CompileCache` that keeps one file per function in a directory.
+/// Each file starts with its key, which is checked on lookup, so that
+/// a misnamed or truncated file is a miss rather than a wrong body.
+#[derive(Clone, Debug)]
+pub struct FsCompileCache {
+    dir: PathBuf,
+}
+
+impl FsCompileCache {
+    /// Use `dir` as the cache directory, creating it if necessary.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<FsCompileCache> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FsCompileCache { dir })
+    }
+
+    fn path(&self, key: u128) -> PathBuf {
+        self.dir.join(format!("{:032x}.wasmfunc", key))
+    }
+}
+
+impl CompileCache for FsCompileCache {
+    fn get(&self, key: u128) -> Option<Vec<u8>> {
+        let path = self.path(key);
+        let mut entry = std::fs::read(&path).ok()?;
+        if entry.len() < 16 || entry[..16] != key.to_le_bytes() {
+            log::debug!("cache: ignoring mismatched {}", path.display());
+            return None;
+        }
+        Some(entry.split_off(16))
+    }
+
+    fn put(&self, key: u128, bytes: &[u8]) {
+        // Write to a temporary file and rename it into place, so that
+        // concurrent readers never see a partial entry.
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let entry = [&key.to_le_bytes()[..], bytes].concat();
+        if std::fs::write(&tmp, entry).is_err() || std::fs::rename(&tmp, &path).is_err() {
+            log::debug!("cache: could not store {}", path.display());
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testgen::gen_module;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemCache {
+        entries: Mutex<HashMap<u128, Vec<u8>>>,
+        hits: AtomicUsize,
+    }
+
+    impl CompileCache for MemCache {
+        fn get(&self, key: u128) -> Option<Vec<u8>> {
+            let entry = self.entries.lock().unwrap().get(&key).cloned();
+            if entry.is_some() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+            entry
+        }
+        fn put(&self, key: u128, bytes: &[u8]) {
+            self.entries.lock().unwrap().insert(key, bytes.to_vec());
+        }
+    }
+
+    #[test]
+    fn second_build_hits() {
+        let module = gen_module(3, &Default::default());
+        let cache = MemCache::default();
+        let first = module.to_wasm_bytes_with_cache(&cache).unwrap();
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 0);
+        let second = module.to_wasm_bytes_with_cache(&cache).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, module.to_wasm_bytes().unwrap());
+        assert_eq!(cache.hits.load(Ordering::Relaxed), module.funcs.len());
+    }
+
+    #[test]
+    fn fs_entries_are_checked() {
+        let module = gen_module(1, &Default::default());
+        let body = module.funcs.values().next().unwrap().body().unwrap();
+        let options = BackendOptions::default();
+        let key = cache_key(body, &options);
+        let size = BackendOptions {
+            cost_model: std::sync::Arc::new(crate::SizeCostModel),
+            ..BackendOptions::default()
+        };
+        assert_ne!(cache_key(body, &size), key);
+
+        let dir = std::env::temp_dir().join(format!("waffle-cache-{}", std::process::id()));
+        let cache = FsCompileCache::new(&dir).unwrap();
+        cache.put(key, b"body");
+        assert_eq!(cache.get(key).as_deref(), Some(&b"body"[..]));
+        assert_eq!(cache.get(key + 1), None);
+        // An entry under the wrong name is not used.
+        std::fs::copy(cache.path(key), cache.path(key + 1)).unwrap();
+        assert_eq!(cache.get(key + 1), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! Frame-size estimation: how much operand stack and how many locals
+//! the backend's output for a function uses.
+//!
+//! The figures are computed from the same trees, control structure,
+//! and local allocation that `WasmFuncBackend::lower()` emits, so
+//! they are exact for the code this version of the backend produces.
+
+use super::{BackendOptions, CompileContext, WasmBlock, WasmFuncBackend};
+use crate::ir::{FunctionBody, Type, Value, ValueDef};
+use anyhow::Result;
+
+/// The stack frame of a compiled function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The maximum height of the operand stack, in values.
+    pub max_stack: usize,
+    /// The number of locals, including parameters.
+    pub locals: usize,
+    /// The total size of all locals, in bytes, counting references
+    /// as eight bytes.
+    pub locals_bytes: usize,
+    /// The maximum nesting depth of `block`, `loop`, and `if`
+    /// constructs.
+    pub max_nesting: usize,
+    /// The maximum nesting depth of `if` constructs alone.
+    pub max_if_nesting: usize,
+}
+
+fn type_bytes(ty: Type) -> usize {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 | Type::FuncRef | Type::TypedFuncRef(..) => 8,
+        Type::V128 => 16,
+    }
+}
+
+/// The maximum nesting depth of all constructs, and of `if`s, in
+/// `blocks`.
+fn nesting(blocks: &[WasmBlock<'_>]) -> (usize, usize) {
+    let mut max = (0, 0);
+    for block in blocks {
+        let (depth, if_depth) = match block {
+            WasmBlock::Block { body, .. } | WasmBlock::Loop { body, .. } => {
+                let (depth, if_depth) = nesting(&body[..]);
+                (depth + 1, if_depth)
+            }
+            WasmBlock::If {
+                if_true, if_false, ..
+            } => {
+                let (t, t_if) = nesting(&if_true[..]);
+                let (f, f_if) = nesting(&if_false[..]);
+                (t.max(f) + 1, t_if.max(f_if) + 1)
+            }
+            _ => (0, 0),
+        };
+        max = (max.0.max(depth), max.1.max(if_depth));
+    }
+    max
+}
+
+impl<'a> WasmFuncBackend<'a> {
+    /// Compute the frame that `compile(body)` would produce.
+    pub fn frame_info(body: &'a FunctionBody) -> Result<FrameInfo> {
+        WasmFuncBackend::frame_info_with_options(body, &BackendOptions::default())
+    }
+
+    /// Compute the frame that `compile_with_options(body, options)`
+    /// would produce.
+    pub fn frame_info_with_options(
+        body: &'a FunctionBody,
+        options: &BackendOptions,
+    ) -> Result<FrameInfo> {
+        let backend = WasmFuncBackend::new(body, options)?;
+        let ctx = backend.context()?;
+        let max_stack = ctx
+            .ctrl
+            .iter()
+            .map(|block| backend.block_height(&ctx, block))
+            .max()
+            .unwrap_or(0);
+        let (max_nesting, max_if_nesting) = nesting(&ctx.ctrl[..]);
+        Ok(FrameInfo {
+            max_stack,
+            locals: ctx.locals.locals.len(),
+            locals_bytes: ctx.locals.locals.values().map(|&ty| type_bytes(ty)).sum(),
+            max_nesting,
+            max_if_nesting,
+        })
+    }
+
+    /// The maximum stack height while executing `block`, which starts
+    /// (and ends) with an empty stack.
+    fn block_height(&self, ctx: &CompileContext<'_>, block: &WasmBlock<'_>) -> usize {
+        let sub = |blocks: &[WasmBlock<'_>]| {
+            blocks
+                .iter()
+                .map(|block| self.block_height(ctx, block))
+                .max()
+                .unwrap_or(0)
+        };
+        match block {
+            WasmBlock::Block { body, .. } | WasmBlock::Loop { body, .. } => sub(&body[..]),
+            WasmBlock::If {
+                cond,
+                if_true,
+                if_false,
+                ..
+            } => self
+                .value_height(ctx, *cond)
+                .max(sub(&if_true[..]))
+                .max(sub(&if_false[..])),
+            WasmBlock::Select { selector, .. } => self.value_height(ctx, *selector),
+            WasmBlock::Leaf { block } => self.body.blocks[*block]
+                .insts
+                .iter()
+                .filter(|inst| {
+                    !ctx.trees.owner.contains_key(inst) && !ctx.trees.remat.contains(inst)
+                })
+                .map(|&inst| self.inst_height(ctx, inst))
+                .max()
+                .unwrap_or(0),
+            WasmBlock::BlockParams { from, to } => {
+                let from = from
+                    .iter()
+                    .zip(to.iter())
+                    .filter(|(_, &(_, to))| !ctx.locals.values[to].is_empty())
+                    .map(|(&from, _)| from)
+                    .collect::<Vec<_>>();
+                self.values_height(ctx, &from[..])
+            }
+            WasmBlock::Return { values } => self.values_height(ctx, &values[..]),
+            WasmBlock::Br { .. } | WasmBlock::Unreachable => 0,
+        }
+    }
+
+    /// The maximum height while pushing `values` in order.
+    fn values_height(&self, ctx: &CompileContext<'_>, values: &[Value]) -> usize {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| i + self.value_height(ctx, value))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The maximum height while pushing one use of `value`.
+    fn value_height(&self, ctx: &CompileContext<'_>, value: Value) -> usize {
+        let value = self.body.resolve_alias(value);
+        if ctx.trees.remat.contains(&value) {
+            self.inst_height(ctx, value)
+        } else {
+            1
+        }
+    }
+
+    /// The maximum height while computing `value` and its tree.
+    fn inst_height(&self, ctx: &CompileContext<'_>, value: Value) -> usize {
+        match &self.body.values[value] {
+            &ValueDef::Operator(_, args, tys) => {
+                let args = self.body.arg_pool[args]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &arg)| {
+                        let arg = self.body.resolve_alias(arg);
+                        if ctx.trees.owner.contains_key(&arg) || ctx.trees.remat.contains(&arg) {
+                            i + self.inst_height(ctx, arg)
+                        } else {
+                            i + 1
+                        }
+                    })
+                    .max()
+                    .unwrap_or(0);
+                args.max(tys.len())
+            }
+            _ => 1,
+        }
+    }
+}
@@ -13,6 +13,8 @@ use std::ops::Range;
 pub struct Localifier {
     pub values: PerEntity<Value, SmallVec<[Local; 2]>>,
     pub locals: EntityVec<Local, Type>,
+    /// The values live at the end of each block.
+    pub(crate) block_end_live: PerEntity<Block, HashSet<Value>>,
 }
 
 impl Localifier {
@@ -38,7 +40,9 @@ struct Context<'a> {
     points: usize,
 }
 
-trait Visitor {
+/// Callbacks for a backward walk over a block, in the order in which
+/// the backend's output uses and defines values.
+pub(crate) trait Visitor {
     fn visit_use(&mut self, _: Value) {}
     fn visit_def(&mut self, _: Value) {}
     fn post_inst(&mut self, _: Value) {}
@@ -49,13 +53,13 @@ trait Visitor {
     fn pre_params(&mut self) {}
 }
 
-struct BlockVisitor<'a, V: Visitor> {
+pub(crate) struct BlockVisitor<'a, V: Visitor> {
     body: &'a FunctionBody,
     trees: &'a Trees,
-    visitor: V,
+    pub(crate) visitor: V,
 }
 impl<'a, V: Visitor> BlockVisitor<'a, V> {
-    fn new(body: &'a FunctionBody, trees: &'a Trees, visitor: V) -> Self {
+    pub(crate) fn new(body: &'a FunctionBody, trees: &'a Trees, visitor: V) -> Self {
         log::trace!(
             "localify: running on:\n{}",
             body.display_verbose("| ", None)
@@ -66,7 +70,7 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
             visitor,
         }
     }
-    fn visit_block(&mut self, block: Block) {
+    pub(crate) fn visit_block(&mut self, block: Block) {
         self.visitor.post_term();
         self.body.blocks[block].terminator.visit_uses(|u| {
             self.visit_use(u);
@@ -107,9 +111,10 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
             self.visit_use(value);
             return;
         }
-        if self.trees.owner.contains_key(&value) {
-            // If this is a treeified value, then don't process the use,
-            // but process the instruction directly here.
+        if self.trees.owner.contains_key(&value) || self.trees.remat.contains(&value) {
+            // If this is a treeified or rematerialized value, then
+            // don't process the use, but process the instruction
+            // directly here.
             self.visit_inst(value, /* root = */ false);
         } else {
             // Otherwise, this is a proper use.
@@ -305,6 +310,7 @@ impl<'a> Context<'a> {
         self.compute_liveness();
         self.find_ranges();
         self.allocate();
+        self.results.block_end_live = self.block_end_live;
         self.results
     }
 }
@@ -1,31 +1,135 @@
 //! Backend: IR to Wasm.
 
 use crate::cfg::CFGInfo;
+use crate::diagnostics::{Diagnostics, Severity};
 use crate::entity::EntityRef;
-use crate::ir::{ExportKind, FuncDecl, FunctionBody, ImportKind, Module, Type, Value, ValueDef};
+use crate::ir::{ExportKind, FuncDecl, FunctionBody, ImportKind, InitExpr, InitOp, Module};
+use crate::ir::{Local, Type, Value, ValueDef};
+use crate::passes::cost::{CostModel, DefaultCostModel};
+use crate::passes::downgrade::{self, TargetFeatures};
+use crate::passes::resolve_aliases;
 use crate::Operator;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use rayon::prelude::*;
 use std::borrow::Cow;
-
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::Arc;
+
+pub mod cache;
+pub mod frame;
+use cache::CompileCache;
 pub mod reducify;
 use reducify::Reducifier;
+pub mod reloc;
+pub mod schedule;
+use schedule::Schedule;
 pub mod stackify;
 use stackify::{Context as StackifyContext, WasmBlock};
 pub mod treeify;
 use treeify::Trees;
 pub mod localify;
 use localify::Localifier;
+pub mod offset_map;
+use offset_map::OffsetMap;
+pub mod stack_map;
+use stack_map::StackMap;
+pub mod validate;
+
+/// Options controlling code generation.
+#[derive(Clone, Debug)]
+pub struct BackendOptions {
+    /// Repeat `global.get`s at each use instead of keeping the value
+    /// in a local, when the function cannot change the global.
+    pub remat_globals: bool,
+    /// Recompute small address computations (integer arithmetic on
+    /// one value and constants) at each use instead of keeping the
+    /// result in a local.
+    pub remat_addresses: bool,
+    /// The largest size, per `cost_model`, of a rematerialized
+    /// address computation, not counting its non-constant operand.
+    pub max_remat_size: u32,
+    /// Operator costs used to decide what to rematerialize.
+    pub cost_model: Arc<dyn CostModel>,
+    /// Reorder independent instructions within blocks so that more
+    /// values can stay on the operand stack.
+    pub schedule: bool,
+    /// Emit a relocatable object file, with `linking` and
+    /// `reloc.CODE` sections for a linker such as `wasm-ld`. See
+    /// `reloc` for what is and is not relocated.
+    pub relocatable: bool,
+    /// Append a `waffle.offset_map` custom section mapping code
+    /// offsets to IR values and source locations. See `offset_map`.
+    /// Relocatable output carries no map.
+    pub offset_map: bool,
+    /// Keep reference-typed values in locals and record which locals
+    /// hold live references at each call, in `EmitInfo::stack_maps`
+    /// and a `waffle.stack_maps` custom section. See `stack_map`.
+    /// Relocatable output carries no section.
+    pub stack_maps: bool,
+    /// Where to report code the backend had to duplicate or metadata
+    /// it had to drop.
+    pub diagnostics: Diagnostics,
+    /// Validate the output, with the features the module uses, and
+    /// fail with the offending function's IR if it is invalid. See
+    /// `validate`.
+    pub validate: bool,
+    /// The features of the engine the output is for. Features it
+    /// lacks are lowered where possible, on a copy of the module, and
+    /// compilation fails otherwise; code it can run more simply with
+    /// features it has is raised into them. See `passes::downgrade`.
+    pub target_features: Option<TargetFeatures>,
+}
+
+impl std::default::Default for BackendOptions {
+    fn default() -> Self {
+        BackendOptions {
+            remat_globals: true,
+            remat_addresses: true,
+            max_remat_size: 3,
+            cost_model: Arc::new(DefaultCostModel),
+            schedule: true,
+            relocatable: false,
+            offset_map: false,
+            stack_maps: false,
+            diagnostics: Diagnostics::default(),
+            validate: false,
+            target_features: None,
+        }
+    }
+}
 
 pub struct WasmFuncBackend<'a> {
     body: Cow<'a, FunctionBody>,
     cfg: CFGInfo,
+    options: BackendOptions,
 }
 
 struct CompileContext<'a> {
     trees: Trees,
     ctrl: Vec<WasmBlock<'a>>,
     locals: Localifier,
+    /// The live references at each call, for stack maps.
+    safepoints: HashMap<Value, Vec<(Value, Local)>>,
+    info: RefCell<EmitInfo>,
+}
+
+/// Where the backend placed the code for a function body. Offsets are
+/// relative to the start of the function body (its locals
+/// declaration).
+#[derive(Clone, Debug, Default)]
+pub struct EmitInfo {
+    /// The code computing each value. A value kept in a local has one
+    /// range, which includes the operands folded into it; a folded or
+    /// rematerialized value has a range inside that of each use.
+    pub values: BTreeMap<Value, Vec<Range<u32>>>,
+    /// The branch hints of conditional branches, as `(offset,
+    /// likely)` pairs.
+    pub branch_hints: Vec<(u32, bool)>,
+    /// The live references at each call, if `BackendOptions::stack_maps`
+    /// is set, in code order.
+    pub stack_maps: Vec<StackMap>,
 }
 
 macro_rules! op {
@@ -36,33 +140,96 @@ macro_rules! op {
 
 impl<'a> WasmFuncBackend<'a> {
     pub fn compile(body: &'a FunctionBody) -> Result<wasm_encoder::Function> {
+        WasmFuncBackend::compile_with_options(body, &BackendOptions::default())
+    }
+
+    pub fn compile_with_options(
+        body: &'a FunctionBody,
+        options: &BackendOptions,
+    ) -> Result<wasm_encoder::Function> {
+        WasmFuncBackend::new(body, options)?.lower()
+    }
+
+    /// Like `compile_with_options()`, but also return where the code
+    /// for each value went, and the body as compiled: the backend's
+    /// reducification, alias resolution, and scheduling may change
+    /// it, and `EmitInfo` refers to its values.
+    pub fn compile_with_emit_info(
+        body: &'a FunctionBody,
+        options: &BackendOptions,
+    ) -> Result<(wasm_encoder::Function, EmitInfo, Cow<'a, FunctionBody>)> {
+        let backend = WasmFuncBackend::new(body, options)?;
+        let (func, info) = backend.lower_with_info()?;
+        Ok((func, info, backend.body))
+    }
+
+    fn new(body: &'a FunctionBody, options: &BackendOptions) -> Result<WasmFuncBackend<'a>> {
         body.validate()?;
         log::debug!("Backend compiling:\n{}\n", body.display_verbose("| ", None));
         // For ownership reasons (to avoid a self-referential struct
-        // with the `Cow::Owned` case when the Reducifier modifies the
-        // body), we have to run the Reducifier first, own its result
+        // with the `Cow::Owned` case when the Reducifier or scheduler
+        // modifies the body), we have to run them first, own the result
         // in this stack frame, then construct the `WasmFuncBackend`
         // state and run the rest of the compilation in `lower()`.
-        let body = Reducifier::new(body).run();
+        let orig_blocks = body.blocks.len();
+        let mut body = Reducifier::new(body).run();
+        if let Cow::Owned(reducified) = &body {
+            options.diagnostics.report(
+                Severity::Note,
+                "backend",
+                None,
+                format!(
+                    "irreducible control flow: duplicated {} blocks",
+                    reducified.blocks.len() - orig_blocks
+                ),
+            );
+        }
+        if resolve_aliases::has_aliases(&body) {
+            resolve_aliases::run(body.to_mut());
+        }
         let cfg = CFGInfo::new(&body);
-        let state = WasmFuncBackend { body, cfg };
-        state.lower()
+        if options.schedule {
+            let schedule = Schedule::compute(&body, &cfg);
+            if !schedule.is_empty() {
+                let changed = schedule.apply(body.to_mut());
+                log::debug!("Scheduling reordered {} blocks", changed);
+            }
+        }
+        Ok(WasmFuncBackend {
+            body,
+            cfg,
+            options: options.clone(),
+        })
     }
 
-    pub fn lower(&self) -> Result<wasm_encoder::Function> {
+    fn context(&self) -> Result<CompileContext<'_>> {
         log::debug!("CFG:\n{:?}\n", self.cfg);
-        let trees = Trees::compute(&self.body);
+        let trees = Trees::compute(&self.body, &self.options);
         log::debug!("Trees:\n{:?}\n", trees);
         let ctrl = StackifyContext::new(&self.body, &self.cfg)?.compute();
         log::debug!("Ctrl:\n{:?}\n", ctrl);
         let locals = Localifier::compute(&self.body, &self.cfg, &trees);
         log::debug!("Locals:\n{:?}\n", locals);
+        let safepoints = match self.options.stack_maps {
+            true => stack_map::compute(&self.body, &self.cfg, &trees, &locals),
+            false => HashMap::new(),
+        };
 
-        let ctx = CompileContext {
+        Ok(CompileContext {
             trees,
             ctrl,
             locals,
-        };
+            safepoints,
+            info: RefCell::default(),
+        })
+    }
+
+    pub fn lower(&self) -> Result<wasm_encoder::Function> {
+        Ok(self.lower_with_info()?.0)
+    }
+
+    fn lower_with_info(&self) -> Result<(wasm_encoder::Function, EmitInfo)> {
+        let ctx = self.context()?;
 
         let mut func = wasm_encoder::Function::new(
             ctx.locals
@@ -91,7 +258,7 @@ impl<'a> WasmFuncBackend<'a> {
 
         log::debug!("Compiled to:\n{:?}\n", func);
 
-        Ok(func)
+        Ok((func, ctx.info.into_inner()))
     }
 
     fn lower_block(
@@ -124,10 +291,15 @@ impl<'a> WasmFuncBackend<'a> {
             }
             WasmBlock::If {
                 cond,
+                hint,
                 if_true,
                 if_false,
             } => {
                 self.lower_value(ctx, *cond, func);
+                if let Some(likely) = *hint {
+                    let offset = func.byte_len() as u32;
+                    ctx.info.borrow_mut().branch_hints.push((offset, likely));
+                }
                 func.instruction(&wasm_encoder::Instruction::If(
                     wasm_encoder::BlockType::Empty,
                 ));
@@ -253,6 +425,7 @@ impl<'a> WasmFuncBackend<'a> {
         func: &mut wasm_encoder::Function,
     ) {
         log::trace!("lower_inst: value {} root {}", value, root);
+        let start = func.byte_len() as u32;
         match &self.body.values[value] {
             &ValueDef::Operator(ref op, args, tys) => {
                 for &arg in &self.body.arg_pool[args] {
@@ -265,8 +438,16 @@ impl<'a> WasmFuncBackend<'a> {
                     }
                 }
                 self.lower_op(op, func);
+                if let Some(live) = ctx.safepoints.get(&value) {
+                    ctx.info.borrow_mut().stack_maps.push(StackMap {
+                        offset: func.byte_len() as u32,
+                        call: value,
+                        live: live.clone(),
+                    });
+                }
                 if root {
-                    for &local in &ctx.locals.values[value] {
+                    // The last result is on top of the stack.
+                    for &local in ctx.locals.values[value].iter().rev() {
                         func.instruction(
                             &wasm_encoder::Instruction::LocalSet(local.index() as u32),
                         );
@@ -282,6 +463,13 @@ impl<'a> WasmFuncBackend<'a> {
             }
             def => unreachable!("Unexpected inst: {:?}", def),
         }
+        let end = func.byte_len() as u32;
+        ctx.info
+            .borrow_mut()
+            .values
+            .entry(value)
+            .or_default()
+            .push(start..end);
     }
 
     fn lower_op(&self, op: &Operator, func: &mut wasm_encoder::Function) {
@@ -615,100 +803,100 @@ impl<'a> WasmFuncBackend<'a> {
             Operator::V128Load8Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Load8Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Load16Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Load16Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Load32Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Load32Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Load64Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Load64Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Store8Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Store8Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Store16Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Store16Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Store32Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Store32Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
             Operator::V128Store64Lane { memory, lane } => {
                 Some(wasm_encoder::Instruction::V128Store64Lane {
                     memarg: wasm_encoder::MemArg::from(*memory),
-                    lane: *lane,
+                    lane: lane.get(),
                 })
             }
-            Operator::V128Const { value } => {
-                Some(wasm_encoder::Instruction::V128Const(*value as i128))
-            }
+            Operator::V128Const { value } => Some(wasm_encoder::Instruction::V128Const(
+                self.body.imm_pool[*value] as i128,
+            )),
 
-            Operator::I8x16Shuffle { lanes } => {
-                Some(wasm_encoder::Instruction::I8x16Shuffle(lanes.clone()))
-            }
+            Operator::I8x16Shuffle { lanes } => Some(wasm_encoder::Instruction::I8x16Shuffle(
+                self.body.imm_pool.lanes(*lanes),
+            )),
 
             Operator::I8x16ExtractLaneS { lane } => {
-                Some(wasm_encoder::Instruction::I8x16ExtractLaneS(*lane))
+                Some(wasm_encoder::Instruction::I8x16ExtractLaneS(lane.get()))
             }
             Operator::I8x16ExtractLaneU { lane } => {
-                Some(wasm_encoder::Instruction::I8x16ExtractLaneU(*lane))
+                Some(wasm_encoder::Instruction::I8x16ExtractLaneU(lane.get()))
             }
             Operator::I8x16ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::I8x16ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::I8x16ReplaceLane(lane.get()))
             }
             Operator::I16x8ExtractLaneS { lane } => {
-                Some(wasm_encoder::Instruction::I16x8ExtractLaneS(*lane))
+                Some(wasm_encoder::Instruction::I16x8ExtractLaneS(lane.get()))
             }
             Operator::I16x8ExtractLaneU { lane } => {
-                Some(wasm_encoder::Instruction::I16x8ExtractLaneU(*lane))
+                Some(wasm_encoder::Instruction::I16x8ExtractLaneU(lane.get()))
             }
             Operator::I16x8ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::I16x8ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::I16x8ReplaceLane(lane.get()))
             }
             Operator::I32x4ExtractLane { lane } => {
-                Some(wasm_encoder::Instruction::I32x4ExtractLane(*lane))
+                Some(wasm_encoder::Instruction::I32x4ExtractLane(lane.get()))
             }
             Operator::I32x4ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::I32x4ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::I32x4ReplaceLane(lane.get()))
             }
             Operator::I64x2ExtractLane { lane } => {
-                Some(wasm_encoder::Instruction::I64x2ExtractLane(*lane))
+                Some(wasm_encoder::Instruction::I64x2ExtractLane(lane.get()))
             }
             Operator::I64x2ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::I64x2ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::I64x2ReplaceLane(lane.get()))
             }
             Operator::F32x4ExtractLane { lane } => {
-                Some(wasm_encoder::Instruction::F32x4ExtractLane(*lane))
+                Some(wasm_encoder::Instruction::F32x4ExtractLane(lane.get()))
             }
             Operator::F32x4ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::F32x4ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::F32x4ReplaceLane(lane.get()))
             }
             Operator::F64x2ExtractLane { lane } => {
-                Some(wasm_encoder::Instruction::F64x2ExtractLane(*lane))
+                Some(wasm_encoder::Instruction::F64x2ExtractLane(lane.get()))
             }
             Operator::F64x2ReplaceLane { lane } => {
-                Some(wasm_encoder::Instruction::F64x2ReplaceLane(*lane))
+                Some(wasm_encoder::Instruction::F64x2ReplaceLane(lane.get()))
             }
 
             Operator::I8x16Swizzle => Some(wasm_encoder::Instruction::I8x16Swizzle),
@@ -983,7 +1171,22 @@ impl<'a> WasmFuncBackend<'a> {
     }
 }
 
-pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
+pub fn compile(
+    module: &Module<'_>,
+    cache: Option<&dyn CompileCache>,
+    options: &BackendOptions,
+) -> anyhow::Result<Vec<u8>> {
+    if let Some(target) = &options.target_features {
+        let mut module = module.clone();
+        downgrade::downgrade(&mut module, target)?;
+        downgrade::upgrade(&mut module, target)?;
+        let options = BackendOptions {
+            target_features: None,
+            ..options.clone()
+        };
+        return compile(&module, cache, &options);
+    }
+
     let mut into_mod = wasm_encoder::Module::new();
 
     let mut types = wasm_encoder::TypeSection::new();
@@ -1093,7 +1296,10 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
                 mutable: global_data.mutable,
                 shared: false,
             },
-            &const_init(global_data.ty, global_data.value),
+            &match &global_data.init {
+                Some(init) => init_expr(wasm_encoder::ConstExpr::empty(), init)?,
+                None => const_init(global_data.ty, global_data.value),
+            },
         );
     }
     into_mod.section(&globals);
@@ -1169,37 +1375,159 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             }
         }
     }
+
+    // Functions named by `ref.func` must be declared in some element
+    // segment; those not already placed in a table get a declarative
+    // segment.
+    let in_tables = module
+        .tables
+        .values()
+        .filter_map(|table_data| table_data.func_elements.as_ref())
+        .flatten()
+        .copied()
+        .collect::<std::collections::HashSet<_>>();
+    let mut declared = std::collections::BTreeSet::new();
+    for func_decl in module.funcs.values() {
+        if let Some(body) = func_decl.body() {
+            for value in body.values.values() {
+                if let ValueDef::Operator(Operator::RefFunc { func_index }, _, _) = value {
+                    if !in_tables.contains(func_index) {
+                        declared.insert(func_index.index() as u32);
+                    }
+                }
+            }
+        }
+    }
+    if !declared.is_empty() {
+        let declared = declared.into_iter().collect::<Vec<_>>();
+        elem.declared(wasm_encoder::Elements::Functions(&declared[..]));
+    }
     into_mod.section(&elem);
 
     let mut code = wasm_encoder::CodeSection::new();
 
+    // Each body comes with its emit info (for branch hints and stack
+    // maps) and, if asked for, its offset-map entries. Bodies that
+    // need any of these bypass the cache, which stores only bytes.
+    let offset_map = options.offset_map && !options.relocatable;
+    let stack_maps = options.stack_maps && !options.relocatable;
     let bodies = module
         .funcs
         .entries()
         .skip(num_func_imports)
         .collect::<Vec<_>>()
         .par_iter()
-        .map(|(func, func_decl)| -> Result<_> {
+        .map(|&(func, func_decl)| -> Result<_> {
             match func_decl {
                 FuncDecl::Lazy(_, _name, reader) => {
                     let data = &module.orig_bytes.unwrap()[reader.range()];
-                    Ok(Cow::Borrowed(data))
+                    Ok((Cow::Borrowed(data), EmitInfo::default(), vec![]))
+                }
+                FuncDecl::Compiled(_, _name, bytes) => {
+                    Ok((Cow::Borrowed(&bytes[..]), EmitInfo::default(), vec![]))
+                }
+                FuncDecl::Body(_, name, body)
+                    if offset_map
+                        || stack_maps
+                        || body.blocks.values().any(|def| def.branch_hint.is_some()) =>
+                {
+                    log::debug!("Compiling {} \"{}\" with emit info", func, name);
+                    let (compiled, info, body) =
+                        WasmFuncBackend::compile_with_emit_info(body, options)?;
+                    let entries = match offset_map {
+                        true => offset_map::func_entries(module, func, &body, &info),
+                        false => vec![],
+                    };
+                    Ok((Cow::Owned(compiled.into_raw_body()), info, entries))
                 }
-                FuncDecl::Compiled(_, _name, bytes) => Ok(Cow::Borrowed(&bytes[..])),
                 FuncDecl::Body(_, name, body) => {
+                    let key = cache.map(|_| cache::cache_key(body, options));
+                    if let (Some(cache), Some(key)) = (cache, key) {
+                        if let Some(bytes) = cache.get(key) {
+                            log::debug!("Reusing cached {} \"{}\"", func, name);
+                            return Ok((Cow::Owned(bytes), EmitInfo::default(), vec![]));
+                        }
+                    }
                     log::debug!("Compiling {} \"{}\"", func, name);
-                    WasmFuncBackend::compile(body).map(|func| Cow::Owned(func.into_raw_body()))
+                    let bytes =
+                        WasmFuncBackend::compile_with_options(body, options)?.into_raw_body();
+                    if let (Some(cache), Some(key)) = (cache, key) {
+                        cache.put(key, &bytes);
+                    }
+                    Ok((Cow::Owned(bytes), EmitInfo::default(), vec![]))
                 }
                 FuncDecl::Import(_, _) => unreachable!("Should have skipped imports"),
                 FuncDecl::None => panic!("FuncDecl::None at compilation time"),
             }
         })
         .collect::<Result<Vec<_>>>()?;
-
-    for body in bodies {
-        code.raw(&body);
+    let mut hints = vec![];
+    let mut maps = vec![];
+    let mut entries = vec![];
+    let bodies = bodies
+        .into_iter()
+        .map(|(bytes, info, body_entries)| {
+            hints.push(info.branch_hints);
+            maps.push(info.stack_maps);
+            entries.push(body_entries);
+            bytes
+        })
+        .collect::<Vec<_>>();
+
+    // Relocation padding moves instructions, so relocatable output
+    // carries no branch hints.
+    let any_hints = hints.iter().any(|hints| !hints.is_empty());
+    if options.relocatable && any_hints {
+        options.diagnostics.report(
+            Severity::Warning,
+            "backend",
+            None,
+            "dropped branch hints from relocatable output".to_owned(),
+        );
+    }
+    let has_hints = !options.relocatable && any_hints;
+    if has_hints {
+        into_mod.section(&branch_hint_section(num_func_imports, &hints[..]));
     }
-    into_mod.section(&code);
+
+    let relocs = if options.relocatable {
+        let (contents, relocs) = reloc::code_section(module, &bodies[..])?;
+        let index = reloc::count_sections(into_mod.as_slice())?;
+        into_mod.section(&wasm_encoder::RawSection {
+            id: wasm_encoder::SectionId::Code as u8,
+            data: &contents[..],
+        });
+        Some((index, relocs))
+    } else {
+        for body in bodies {
+            code.raw(&body);
+        }
+        into_mod.section(&code);
+        None
+    };
+
+    // The code section is now in place, so offsets can be moved from
+    // body-relative to module offsets.
+    let bases = match offset_map || stack_maps {
+        true => offset_map::body_offsets(into_mod.as_slice())?,
+        false => vec![],
+    };
+    let offset_map = match offset_map {
+        true => {
+            let mut map = OffsetMap::default();
+            for (&base, body_entries) in bases.iter().zip(entries) {
+                map.entries.extend(body_entries.into_iter().map(|entry| {
+                    offset_map::OffsetMapEntry {
+                        code: base + entry.code.start..base + entry.code.end,
+                        ..entry
+                    }
+                }));
+            }
+            map.entries.sort_by_key(|entry| entry.code.start);
+            Some(map)
+        }
+        false => None,
+    };
 
     let mut data = wasm_encoder::DataSection::new();
     for (mem, mem_data) in module.memories.entries() {
@@ -1213,6 +1541,13 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
     }
     into_mod.section(&data);
 
+    // The linking section must follow all known sections, and the
+    // relocations must follow the linking section.
+    if let Some((code_index, relocs)) = relocs {
+        into_mod.section(&reloc::linking_section(module));
+        into_mod.section(&reloc::reloc_code_section(code_index, &relocs[..]));
+    }
+
     let mut names = wasm_encoder::NameSection::new();
     let mut func_names = wasm_encoder::NameMap::new();
     for (func, decl) in module.funcs.entries() {
@@ -1222,6 +1557,16 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
     into_mod.section(&names);
 
     for (custom_name, &custom_data) in &module.custom_sections {
+        if has_hints && custom_name == BRANCH_HINT_SECTION {
+            continue;
+        }
+        // An offset map or stack maps from an earlier compile no
+        // longer match the code.
+        if custom_name == offset_map::OFFSET_MAP_SECTION
+            || custom_name == stack_map::STACK_MAP_SECTION
+        {
+            continue;
+        }
         let section = wasm_encoder::CustomSection {
             name: custom_name.into(),
             data: custom_data.into(),
@@ -1229,7 +1574,75 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
         into_mod.section(&section);
     }
 
-    Ok(into_mod.finish())
+    if stack_maps {
+        into_mod.section(&stack_map::section(num_func_imports, &bases[..], &maps[..]));
+    }
+    if let Some(map) = offset_map {
+        into_mod.section(&wasm_encoder::CustomSection {
+            name: offset_map::OFFSET_MAP_SECTION.into(),
+            data: map.encode().into(),
+        });
+    }
+
+    let bytes = into_mod.finish();
+    if options.validate {
+        validate::validate(module, &bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// The name of the custom section holding branch hints.
+const BRANCH_HINT_SECTION: &str = "metadata.code.branch_hint";
+
+/// Encode the branch hints of the defined functions, given in order
+/// after `num_func_imports` imports, as a branch-hint section.
+fn branch_hint_section(
+    num_func_imports: usize,
+    hints: &[Vec<(u32, bool)>],
+) -> wasm_encoder::CustomSection<'static> {
+    use wasm_encoder::Encode;
+    let hinted = hints
+        .iter()
+        .enumerate()
+        .filter(|(_, hints)| !hints.is_empty())
+        .collect::<Vec<_>>();
+    let mut data = vec![];
+    (hinted.len() as u32).encode(&mut data);
+    for (i, hints) in hinted {
+        ((num_func_imports + i) as u32).encode(&mut data);
+        (hints.len() as u32).encode(&mut data);
+        for &(offset, likely) in hints {
+            offset.encode(&mut data);
+            1u32.encode(&mut data);
+            data.push(likely as u8);
+        }
+    }
+    wasm_encoder::CustomSection {
+        name: BRANCH_HINT_SECTION.into(),
+        data: data.into(),
+    }
+}
+
+fn init_expr(expr: wasm_encoder::ConstExpr, init: &InitExpr) -> Result<wasm_encoder::ConstExpr> {
+    Ok(match init {
+        &InitExpr::Const(Type::I32, bits) => expr.with_i32_const(bits as u32 as i32),
+        &InitExpr::Const(Type::I64, bits) => expr.with_i64_const(bits as i64),
+        &InitExpr::Const(Type::F32, bits) => expr.with_f32_const(f32::from_bits(bits as u32)),
+        &InitExpr::Const(Type::F64, bits) => expr.with_f64_const(f64::from_bits(bits)),
+        &InitExpr::Const(ty, _) => bail!("Cannot emit a constant initializer of type {}", ty),
+        &InitExpr::GlobalGet(global) => expr.with_global_get(global.index() as u32),
+        InitExpr::Binary(op, a, b) => {
+            let expr = init_expr(init_expr(expr, a)?, b)?;
+            match op {
+                InitOp::I32Add => expr.with_i32_add(),
+                InitOp::I32Sub => expr.with_i32_sub(),
+                InitOp::I32Mul => expr.with_i32_mul(),
+                InitOp::I64Add => expr.with_i64_add(),
+                InitOp::I64Sub => expr.with_i64_sub(),
+                InitOp::I64Mul => expr.with_i64_mul(),
+            }
+        }
+    })
 }
 
 fn const_init(ty: Type, value: Option<u64>) -> wasm_encoder::ConstExpr {
@@ -1246,3 +1659,44 @@ fn const_init(ty: Type, value: Option<u64>) -> wasm_encoder::ConstExpr {
         _ => unimplemented!(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{ConstVal, ExportKind, FrontendOptions, InterpContext, Module};
+
+    #[test]
+    fn multi_result_locals() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (func $pair (result i32 i32 i64)
+                  i32.const 1
+                  i32.const 2
+                  i64.const 3)
+                (func (export "diff") (result i32)
+                  (local i64)
+                  call $pair
+                  local.set 0
+                  i32.sub))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        // Each result is stored to its own local: 1 - 2, not 2 - 1.
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let diff = module
+            .exports
+            .iter()
+            .find_map(|export| match export.kind {
+                ExportKind::Func(func) => Some(func),
+                _ => None,
+            })
+            .unwrap();
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, diff, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(-1i32 as u32)]);
+    }
+}
@@ -0,0 +1,270 @@
+//! Offset maps: which IR value, and which original source location,
+//! each range of emitted code came from.
+//!
+//! Engines report traps by code offset in the module they ran. With
+//! `BackendOptions::offset_map` set, `compile()` appends a
+//! `waffle.offset_map` custom section that maps those offsets back
+//! through the IR to the pre-waffle module and its source, so crash
+//! reports from production can be symbolized.
+
+use super::EmitInfo;
+use crate::entity::EntityRef;
+use crate::ir::{Func, FunctionBody, Module, Value};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::ops::Range;
+use wasm_encoder::Encode;
+
+/// The name of the custom section holding the offset map.
+pub const OFFSET_MAP_SECTION: &str = "waffle.offset_map";
+
+/// A source location, with the file name spelled out so that the map
+/// stands alone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OffsetMapLoc {
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// The code computing one value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OffsetMapEntry {
+    /// The emitted code, as byte offsets in the output module.
+    pub code: Range<u32>,
+    pub func: Func,
+    /// The value in the body as compiled. Reducification may have
+    /// copied the body first, so this need not name a value of the
+    /// module's own body; `wasm_offset` and `loc` do not depend on it.
+    pub value: Value,
+    /// The byte offset of the operator the value came from in the
+    /// original module, if the frontend recorded it (see
+    /// `FrontendOptions::wasm_offsets`).
+    pub wasm_offset: Option<u32>,
+    /// The value's source location, if it has one.
+    pub loc: Option<OffsetMapLoc>,
+}
+
+/// An offset map, with entries sorted by the start of their code.
+/// Ranges nest: the code of a folded operand lies inside that of its
+/// use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+    pub entries: Vec<OffsetMapEntry>,
+}
+
+const HAS_WASM_OFFSET: u8 = 1;
+const HAS_LOC: u8 = 2;
+
+/// The entries for one compiled function body, with code offsets
+/// relative to the start of the body, as in `info`.
+pub(crate) fn func_entries(
+    module: &Module,
+    func: Func,
+    body: &FunctionBody,
+    info: &EmitInfo,
+) -> Vec<OffsetMapEntry> {
+    let mut entries = vec![];
+    for (&value, ranges) in &info.values {
+        let loc = body.source_locs[value];
+        let loc = loc.is_valid().then(|| {
+            let data = &module.debug.source_locs[loc];
+            OffsetMapLoc {
+                file: module.debug.source_files[data.file].clone(),
+                line: data.line,
+                col: data.col,
+            }
+        });
+        for range in ranges {
+            entries.push(OffsetMapEntry {
+                code: range.clone(),
+                func,
+                value,
+                wasm_offset: body.wasm_offsets[value],
+                loc: loc.clone(),
+            });
+        }
+    }
+    entries
+}
+
+/// The offsets, in `bytes`, of the start of each function body in
+/// the code section.
+pub(crate) fn body_offsets(bytes: &[u8]) -> Result<Vec<u32>> {
+    let mut offsets = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload? {
+            offsets.push(body.range().start as u32);
+        }
+    }
+    Ok(offsets)
+}
+
+impl OffsetMap {
+    /// The innermost entry whose code contains `offset`.
+    pub fn lookup(&self, offset: u32) -> Option<&OffsetMapEntry> {
+        let end = self.entries.partition_point(|e| e.code.start <= offset);
+        self.entries[..end]
+            .iter()
+            .filter(|e| offset < e.code.end)
+            .min_by_key(|e| e.code.end - e.code.start)
+    }
+
+    /// Encode the map as the contents of its custom section.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut files: Vec<&str> = vec![];
+        let mut file_ids: HashMap<&str, u32> = HashMap::new();
+        for entry in &self.entries {
+            if let Some(loc) = &entry.loc {
+                file_ids.entry(&loc.file).or_insert_with(|| {
+                    files.push(&loc.file);
+                    (files.len() - 1) as u32
+                });
+            }
+        }
+
+        let mut data = vec![];
+        (files.len() as u32).encode(&mut data);
+        for file in &files {
+            file.encode(&mut data);
+        }
+        (self.entries.len() as u32).encode(&mut data);
+        for entry in &self.entries {
+            entry.code.start.encode(&mut data);
+            entry.code.end.encode(&mut data);
+            (entry.func.index() as u32).encode(&mut data);
+            (entry.value.index() as u32).encode(&mut data);
+            let flags = entry.wasm_offset.map_or(0, |_| HAS_WASM_OFFSET)
+                | entry.loc.as_ref().map_or(0, |_| HAS_LOC);
+            data.push(flags);
+            if let Some(offset) = entry.wasm_offset {
+                offset.encode(&mut data);
+            }
+            if let Some(loc) = &entry.loc {
+                file_ids[&loc.file[..]].encode(&mut data);
+                loc.line.encode(&mut data);
+                loc.col.encode(&mut data);
+            }
+        }
+        data
+    }
+
+    /// Decode the contents of an offset-map custom section.
+    pub fn decode(data: &[u8]) -> Result<OffsetMap> {
+        let mut reader = wasmparser::BinaryReader::new(data, 0, wasmparser::WasmFeatures::all());
+        let files = (0..reader.read_var_u32()?)
+            .map(|_| Ok(reader.read_string()?.to_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        let mut entries = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let start = reader.read_var_u32()?;
+            let end = reader.read_var_u32()?;
+            let func = Func::new(reader.read_var_u32()? as usize);
+            let value = Value::new(reader.read_var_u32()? as usize);
+            let flags = reader.read_u8()?;
+            let wasm_offset = match flags & HAS_WASM_OFFSET {
+                0 => None,
+                _ => Some(reader.read_var_u32()?),
+            };
+            let loc = match flags & HAS_LOC {
+                0 => None,
+                _ => {
+                    let file = reader.read_var_u32()? as usize;
+                    let Some(file) = files.get(file) else {
+                        bail!("Offset map refers to unknown file {}", file);
+                    };
+                    Some(OffsetMapLoc {
+                        file: file.clone(),
+                        line: reader.read_var_u32()?,
+                        col: reader.read_var_u32()?,
+                    })
+                }
+            };
+            entries.push(OffsetMapEntry {
+                code: start..end,
+                func,
+                value,
+                wasm_offset,
+                loc,
+            });
+        }
+        if !reader.eof() {
+            bail!("Trailing bytes after offset map");
+        }
+        Ok(OffsetMap { entries })
+    }
+
+    /// Find and decode the offset map in a compiled module, if it has
+    /// one.
+    pub fn from_wasm_bytes(bytes: &[u8]) -> Result<Option<OffsetMap>> {
+        for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+            if let wasmparser::Payload::CustomSection(section) = payload? {
+                if section.name() == OFFSET_MAP_SECTION {
+                    return OffsetMap::decode(section.data()).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, SignatureData, Terminator, Type};
+    use crate::{BackendOptions, Operator};
+
+    #[test]
+    fn traps_map_back_to_source() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::I32],
+            returns: vec![Type::I32],
+        });
+        let file = module.debug.intern_file("lib.rs");
+        let loc = module.debug.intern_loc(file, 12, 5);
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let y = body.blocks[entry].params[1].1;
+        let div = body.add_op(entry, Operator::I32DivS, &[x, y], &[Type::I32]);
+        body.source_locs[div] = loc;
+        body.wasm_offsets[div] = Some(0x42);
+        body.set_terminator(entry, Terminator::Return { values: vec![div] });
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let options = BackendOptions {
+            offset_map: true,
+            ..BackendOptions::default()
+        };
+        let bytes = module.to_wasm_bytes_with_options(&options, None).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let map = OffsetMap::from_wasm_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(OffsetMap::decode(&map.encode()).unwrap(), map);
+
+        // Where an engine would report the trap.
+        let mut trap = None;
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let mut ops = body.get_operators_reader().unwrap();
+                while !ops.eof() {
+                    let (op, offset) = ops.read_with_offset().unwrap();
+                    if let wasmparser::Operator::I32DivS = op {
+                        trap = Some(offset as u32);
+                    }
+                }
+            }
+        }
+        let entry = map.lookup(trap.unwrap()).unwrap();
+        assert_eq!(entry.func, func);
+        assert_eq!(entry.value, div);
+        assert_eq!(entry.wasm_offset, Some(0x42));
+        let loc = entry.loc.as_ref().unwrap();
+        assert_eq!((&loc.file[..], loc.line, loc.col), ("lib.rs", 12, 5));
+
+        // Nothing maps outside the code section.
+        assert!(map.lookup(0).is_none());
+    }
+}
@@ -328,7 +328,7 @@ impl<'a> Reducifier<'a> {
             let new_block = if ctx != 0 {
                 log::trace!("cloning block {} in new context", block);
                 let new_block = new_body.add_block();
-                new_body.blocks[new_block].desc = format!("Cloned {}", block);
+                new_body.blocks[new_block].origin = self.body.blocks[block].origin.clone();
                 let params = new_body.blocks[block].params.clone();
                 for (ty, val) in params {
                     let blockparam = new_body.add_blockparam(new_block, ty);
@@ -568,7 +568,7 @@ mod test {
         // control flow).
         module
             .funcs
-            .push(FuncDecl::Body(sig, "func0".to_string(), body));
+            .push(FuncDecl::Body(sig, "func0".to_string(), body.into()));
         let wasm = module.to_wasm_bytes().unwrap();
         log::debug!("wasm bytes: {:?}", wasm);
     }
@@ -0,0 +1,400 @@
+//! Relocatable output: the `linking` and `reloc.CODE` custom sections
+//! of the WebAssembly tool conventions, which let a linker such as
+//! `wasm-ld` consume the module as an object file.
+//!
+//! Every function, global, and table gets a symbol (undefined for
+//! imports, exported under its export name, and local otherwise), and
+//! every data segment a data symbol covering the whole segment.
+//! Function and global indices in code (`call`, `ref.func`,
+//! `global.get`, `global.set`) and the type and table of
+//! `call_indirect` are re-encoded as padded five-byte LEBs and
+//! relocated, so the linker can renumber them.
+//!
+//! The IR does not know which integer constants are addresses, so
+//! memory addresses in code are not relocated: data keeps the
+//! addresses of its active segments' offsets. Element segments are
+//! likewise not relocated.
+
+use crate::entity::EntityRef;
+use crate::ir::{ExportKind, ImportKind, Module};
+use anyhow::Result;
+use std::borrow::Cow;
+use wasm_encoder::Encode;
+
+const R_WASM_FUNCTION_INDEX_LEB: u8 = 0;
+const R_WASM_TYPE_INDEX_LEB: u8 = 6;
+const R_WASM_GLOBAL_INDEX_LEB: u8 = 7;
+const R_WASM_TABLE_NUMBER_LEB: u8 = 20;
+
+const WASM_SEGMENT_INFO: u8 = 5;
+const WASM_SYMBOL_TABLE: u8 = 8;
+
+const SYMTAB_FUNCTION: u8 = 0;
+const SYMTAB_DATA: u8 = 1;
+const SYMTAB_GLOBAL: u8 = 2;
+const SYMTAB_TABLE: u8 = 5;
+
+const WASM_SYM_BINDING_LOCAL: u32 = 0x2;
+const WASM_SYM_UNDEFINED: u32 = 0x10;
+const WASM_SYM_EXPORTED: u32 = 0x20;
+
+/// One relocation in the code section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Reloc {
+    ty: u8,
+    /// The offset of the padded LEB, from the start of the code
+    /// section's contents.
+    offset: u32,
+    /// The symbol index, or the type index for type relocations.
+    index: u32,
+}
+
+/// Append `value` as a five-byte LEB.
+fn padded_leb(value: u32, sink: &mut Vec<u8>) {
+    for i in 0..5 {
+        let byte = ((value >> (7 * i)) & 0x7f) as u8;
+        sink.push(if i < 4 { byte | 0x80 } else { byte });
+    }
+}
+
+/// The first symbol indices of the module's globals and tables.
+/// Functions come first, at their own indices, and data segments
+/// follow the tables.
+struct Symbols {
+    globals: u32,
+    tables: u32,
+}
+
+impl Symbols {
+    fn new(module: &Module) -> Symbols {
+        let globals = module.funcs.len() as u32;
+        let tables = globals + module.globals.len() as u32;
+        Symbols { globals, tables }
+    }
+}
+
+/// Re-encode a function body's relocatable immediates as padded LEBs,
+/// appending the new body to `out` and its relocations, with offsets
+/// relative to the start of the body, to `relocs`.
+fn relocate_body(
+    body: &[u8],
+    symbols: &Symbols,
+    out: &mut Vec<u8>,
+    relocs: &mut Vec<Reloc>,
+) -> Result<()> {
+    let reader = wasmparser::BinaryReader::new(body, 0, wasmparser::WasmFeatures::all());
+    let mut ops = wasmparser::FunctionBody::new(reader).get_operators_reader()?;
+    let start = out.len();
+    out.extend_from_slice(&body[..ops.original_position()]);
+    while !ops.eof() {
+        let (op, pos) = ops.read_with_offset()?;
+        let end = ops.original_position();
+        let mut reloc = |ty: u8, value: u32, index: u32, out: &mut Vec<u8>| {
+            relocs.push(Reloc {
+                ty,
+                offset: (out.len() - start) as u32,
+                index,
+            });
+            padded_leb(value, out);
+        };
+        match op {
+            wasmparser::Operator::Call { function_index } => {
+                out.push(0x10);
+                reloc(
+                    R_WASM_FUNCTION_INDEX_LEB,
+                    function_index,
+                    function_index,
+                    out,
+                );
+            }
+            wasmparser::Operator::RefFunc { function_index } => {
+                out.push(0xd2);
+                reloc(
+                    R_WASM_FUNCTION_INDEX_LEB,
+                    function_index,
+                    function_index,
+                    out,
+                );
+            }
+            wasmparser::Operator::GlobalGet { global_index } => {
+                out.push(0x23);
+                let symbol = symbols.globals + global_index;
+                reloc(R_WASM_GLOBAL_INDEX_LEB, global_index, symbol, out);
+            }
+            wasmparser::Operator::GlobalSet { global_index } => {
+                out.push(0x24);
+                let symbol = symbols.globals + global_index;
+                reloc(R_WASM_GLOBAL_INDEX_LEB, global_index, symbol, out);
+            }
+            wasmparser::Operator::CallIndirect {
+                type_index,
+                table_index,
+            } => {
+                out.push(0x11);
+                reloc(R_WASM_TYPE_INDEX_LEB, type_index, type_index, out);
+                let symbol = symbols.tables + table_index;
+                reloc(R_WASM_TABLE_NUMBER_LEB, table_index, symbol, out);
+            }
+            _ => out.extend_from_slice(&body[pos..end]),
+        }
+    }
+    Ok(())
+}
+
+/// Build the contents of the code section from the function bodies,
+/// with relocatable immediates, and the relocations against it.
+pub(crate) fn code_section(
+    module: &Module,
+    bodies: &[Cow<'_, [u8]>],
+) -> Result<(Vec<u8>, Vec<Reloc>)> {
+    let symbols = Symbols::new(module);
+    let mut contents = vec![];
+    (bodies.len() as u32).encode(&mut contents);
+    let mut relocs = vec![];
+    let mut body = vec![];
+    for original in bodies {
+        body.clear();
+        let mut body_relocs = vec![];
+        relocate_body(original, &symbols, &mut body, &mut body_relocs)?;
+        (body.len() as u32).encode(&mut contents);
+        let base = contents.len() as u32;
+        relocs.extend(body_relocs.into_iter().map(|reloc| Reloc {
+            offset: base + reloc.offset,
+            ..reloc
+        }));
+        contents.extend_from_slice(&body);
+    }
+    Ok((contents, relocs))
+}
+
+/// The number of sections in the (complete up to a section boundary)
+/// module `bytes`.
+pub(crate) fn count_sections(bytes: &[u8]) -> Result<u32> {
+    let mut reader = wasmparser::BinaryReader::new(bytes, 0, wasmparser::WasmFeatures::all());
+    // Magic and version.
+    reader.read_bytes(8)?;
+    let mut count = 0;
+    while !reader.eof() {
+        reader.read_u8()?;
+        let size = reader.read_var_u32()?;
+        reader.read_bytes(size as usize)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn subsection(ty: u8, payload: &[u8], sink: &mut Vec<u8>) {
+    sink.push(ty);
+    payload.encode(sink);
+}
+
+/// Build the `linking` section.
+pub(crate) fn linking_section(module: &Module) -> wasm_encoder::CustomSection<'static> {
+    let export_name = |kind: ExportKind| {
+        module
+            .exports
+            .iter()
+            .find(|export| export.kind == kind)
+            .map(|export| export.name.as_str())
+    };
+    let import_kinds = module
+        .imports
+        .iter()
+        .map(|import| import.kind.clone())
+        .collect::<Vec<_>>();
+
+    let mut count = 0u32;
+    let mut symtab = vec![];
+    let mut symbol = |kind: u8, index: u32, imported: bool, export: Option<&str>, name: String| {
+        symtab.push(kind);
+        if imported {
+            WASM_SYM_UNDEFINED.encode(&mut symtab);
+            index.encode(&mut symtab);
+        } else {
+            match export {
+                Some(export) => {
+                    WASM_SYM_EXPORTED.encode(&mut symtab);
+                    index.encode(&mut symtab);
+                    export.encode(&mut symtab);
+                }
+                None => {
+                    WASM_SYM_BINDING_LOCAL.encode(&mut symtab);
+                    index.encode(&mut symtab);
+                    name.encode(&mut symtab);
+                }
+            }
+        }
+        count += 1;
+    };
+    for (func, decl) in module.funcs.entries() {
+        let imported = import_kinds.contains(&ImportKind::Func(func));
+        let name = match decl.name() {
+            "" => format!("func{}", func.index()),
+            name => name.to_owned(),
+        };
+        let export = export_name(ExportKind::Func(func));
+        symbol(SYMTAB_FUNCTION, func.index() as u32, imported, export, name);
+    }
+    for (global, _) in module.globals.entries() {
+        let imported = import_kinds.contains(&ImportKind::Global(global));
+        let export = export_name(ExportKind::Global(global));
+        let name = format!("{}", global);
+        symbol(SYMTAB_GLOBAL, global.index() as u32, imported, export, name);
+    }
+    for (table, _) in module.tables.entries() {
+        let imported = import_kinds.contains(&ImportKind::Table(table));
+        let export = export_name(ExportKind::Table(table));
+        let name = format!("{}", table);
+        symbol(SYMTAB_TABLE, table.index() as u32, imported, export, name);
+    }
+
+    // Data symbols and segment info, one per segment, in the order
+    // of the data section.
+    let mut segment_info = vec![];
+    let mut segments = 0u32;
+    for (memory, data) in module.memories.entries() {
+        for (i, segment) in data.segments.iter().enumerate() {
+            let name = format!("{}.segment{}", memory, i);
+            symtab.push(SYMTAB_DATA);
+            WASM_SYM_BINDING_LOCAL.encode(&mut symtab);
+            name.encode(&mut symtab);
+            segments.encode(&mut symtab);
+            0u32.encode(&mut symtab);
+            (segment.data.len() as u32).encode(&mut symtab);
+            count += 1;
+
+            format!(".data.{}", name).encode(&mut segment_info);
+            0u32.encode(&mut segment_info); // Alignment (log2).
+            0u32.encode(&mut segment_info); // Flags.
+            segments += 1;
+        }
+    }
+
+    let mut data = vec![];
+    2u32.encode(&mut data); // Version.
+    let mut payload = vec![];
+    segments.encode(&mut payload);
+    payload.extend_from_slice(&segment_info);
+    subsection(WASM_SEGMENT_INFO, &payload, &mut data);
+    payload.clear();
+    count.encode(&mut payload);
+    payload.extend_from_slice(&symtab);
+    subsection(WASM_SYMBOL_TABLE, &payload, &mut data);
+
+    wasm_encoder::CustomSection {
+        name: "linking".into(),
+        data: data.into(),
+    }
+}
+
+/// Build the `reloc.CODE` section for the code section at index
+/// `code_section` in the module.
+pub(crate) fn reloc_code_section(
+    code_section: u32,
+    relocs: &[Reloc],
+) -> wasm_encoder::CustomSection<'static> {
+    let mut data = vec![];
+    code_section.encode(&mut data);
+    (relocs.len() as u32).encode(&mut data);
+    for reloc in relocs {
+        data.push(reloc.ty);
+        reloc.offset.encode(&mut data);
+        reloc.index.encode(&mut data);
+    }
+    wasm_encoder::CustomSection {
+        name: "reloc.CODE".into(),
+        data: data.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir::{FuncDecl, FunctionBody, GlobalData, Import, ImportKind, Module};
+    use crate::ir::{SignatureData, Terminator, Type};
+    use crate::{BackendOptions, Operator};
+
+    #[test]
+    fn calls_and_globals_are_relocated() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let import = module.funcs.push(FuncDecl::Import(sig, "host".to_owned()));
+        module.imports.push(Import {
+            module: "env".to_owned(),
+            name: "host".to_owned(),
+            kind: ImportKind::Func(import),
+        });
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(7),
+            mutable: true,
+            init: None,
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let call = Operator::Call {
+            function_index: import,
+        };
+        let r = body.add_op(entry, call, &[x], &[Type::I32]);
+        let set = Operator::GlobalSet {
+            global_index: global,
+        };
+        body.add_op(entry, set, &[r], &[]);
+        body.set_terminator(entry, Terminator::Return { values: vec![r] });
+        module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let options = BackendOptions {
+            relocatable: true,
+            ..BackendOptions::default()
+        };
+        let bytes = module.to_wasm_bytes_with_options(&options, None).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        let mut code = None;
+        let mut relocs = None;
+        let mut symbols = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::CodeSectionStart { range, .. } => code = Some(range),
+                wasmparser::Payload::CustomSection(section) => match section.as_known() {
+                    wasmparser::KnownCustom::Linking(linking) => {
+                        for subsection in linking {
+                            if let wasmparser::Linking::SymbolTable(table) = subsection.unwrap() {
+                                symbols = table.count();
+                            }
+                        }
+                    }
+                    wasmparser::KnownCustom::Reloc(reloc) => {
+                        relocs = Some(reloc.entries().into_iter().collect::<Vec<_>>())
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        // Two functions and a global.
+        assert_eq!(symbols, 3);
+        let code = code.unwrap();
+        let relocs = relocs.unwrap();
+        assert_eq!(relocs.len(), 2);
+        // The call's symbol is the import's; the global's follows the
+        // functions'. Both have index 0 in the code.
+        for (reloc, symbol) in relocs.iter().zip([0u32, 2]) {
+            let reloc = reloc.as_ref().unwrap();
+            assert_eq!(reloc.index, symbol);
+            let at = code.start + reloc.offset as usize;
+            let mut reader = wasmparser::BinaryReader::new(
+                &bytes[at..at + 5],
+                0,
+                wasmparser::WasmFeatures::all(),
+            );
+            assert_eq!(reader.read_var_u32().unwrap(), 0);
+            assert!(reader.eof());
+        }
+    }
+}
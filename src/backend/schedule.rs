@@ -0,0 +1,267 @@
+//! Scheduling: reordering instructions within a block so that more
+//! values can be left on the operand stack.
+//!
+//! Treeification can place a pure value under its single use from
+//! anywhere, but an effectful value (a load, a `global.get`, a call)
+//! only if it is the last effectful instruction before that use.
+//! This pass sinks such values down to just before their use, past
+//! instructions they are independent of, so that the stackifier
+//! sees an adjacent def-use pair instead of a def that must go
+//! through a local. Memory dependences come from `MemorySSA`; other
+//! effects, and the order of traps, from the operators' side-effect
+//! lists.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::{Block, FunctionBody, Value, ValueDef};
+use crate::passes::memory_ssa::MemorySSA;
+use crate::{Operator, SideEffect};
+
+/// New instruction orders for the blocks of a function body.
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    blocks: Vec<(Block, Vec<Value>)>,
+}
+
+impl Schedule {
+    /// Schedule the reachable blocks of `body`.
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo) -> Schedule {
+        let mssa = MemorySSA::new(body, cfg);
+
+        // Count the uses of each value.
+        let mut uses: PerEntity<Value, usize> = PerEntity::default();
+        for block_def in body.blocks.values() {
+            for &inst in &block_def.insts {
+                if let ValueDef::Operator(_, args, _) = &body.values[inst] {
+                    for &arg in &body.arg_pool[*args] {
+                        uses[body.resolve_alias(arg)] += 1;
+                    }
+                }
+            }
+            block_def.terminator.visit_uses(|u| {
+                uses[body.resolve_alias(u)] += 1;
+            });
+        }
+
+        let mut blocks = vec![];
+        for &block in cfg.rpo.values() {
+            let mut insts = body.blocks[block].insts.clone();
+            let mut moved = false;
+            // Visit defs last to first, so that a def is sunk past
+            // instructions that are already in their final order.
+            for i in (0..insts.len()).rev() {
+                let def = insts[i];
+                let user = match Self::sink_target(body, &insts[i + 1..], def, &uses) {
+                    Some(user) => i + 1 + user,
+                    None => continue,
+                };
+                if user > i + 1
+                    && insts[i + 1..user]
+                        .iter()
+                        .all(|&inst| Self::independent(body, &mssa, def, inst))
+                {
+                    insts.remove(i);
+                    insts.insert(user - 1, def);
+                    moved = true;
+                }
+            }
+            if moved {
+                blocks.push((block, insts));
+            }
+        }
+        Schedule { blocks }
+    }
+
+    /// Does the schedule leave every block as it was?
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Reorder the blocks of `body`, which must be the body the
+    /// schedule was computed for. Returns the number of blocks
+    /// changed.
+    pub fn apply(self, body: &mut FunctionBody) -> usize {
+        let changed = self.blocks.len();
+        for (block, insts) in self.blocks {
+            body.blocks[block].insts = insts;
+        }
+        changed
+    }
+
+    /// If `def` is an effectful single-result value whose only use is
+    /// as the last effectful argument of one of `rest`, return that
+    /// user's index in `rest`.
+    fn sink_target(
+        body: &FunctionBody,
+        rest: &[Value],
+        def: Value,
+        uses: &PerEntity<Value, usize>,
+    ) -> Option<usize> {
+        match &body.values[def] {
+            ValueDef::Operator(op, _, tys) if tys.len() == 1 && !op.is_pure() => {}
+            _ => return None,
+        }
+        if uses[def] != 1 {
+            return None;
+        }
+        rest.iter().position(|&inst| match &body.values[inst] {
+            ValueDef::Operator(_, args, _) => body.arg_pool[*args]
+                .iter()
+                .map(|&arg| body.resolve_alias(arg))
+                .rev()
+                .find(|&arg| match &body.values[arg] {
+                    ValueDef::Operator(op, ..) => !op.is_pure(),
+                    _ => false,
+                })
+                .is_some_and(|arg| arg == def),
+            _ => false,
+        })
+    }
+
+    /// May `def` be moved from before `inst` to after it?
+    fn independent(body: &FunctionBody, mssa: &MemorySSA, def: Value, inst: Value) -> bool {
+        let (def_op, inst_op) = match (&body.values[def], &body.values[inst]) {
+            (ValueDef::Operator(def_op, ..), ValueDef::Operator(inst_op, ..)) => (def_op, inst_op),
+            // `PickOutput`s have no effects.
+            _ => return true,
+        };
+        if inst_op.is_pure() {
+            return true;
+        }
+        let (def_effects, inst_effects) = (def_op.effects(), inst_op.effects());
+        if has(def_effects, SideEffect::All) || has(inst_effects, SideEffect::All) {
+            return false;
+        }
+
+        // Traps must stay in order with each other and with writes.
+        let def_writes = writes_any(def_op);
+        let inst_writes = writes_any(inst_op);
+        if has(def_effects, SideEffect::Trap)
+            && (has(inst_effects, SideEffect::Trap) || inst_writes)
+        {
+            return false;
+        }
+        if has(inst_effects, SideEffect::Trap) && def_writes {
+            return false;
+        }
+
+        // A memory access must see the same state after moving: no
+        // write may be in between, and a write may not pass an access.
+        if let Some(before) = mssa.state_before(def) {
+            if mssa.state_after(inst) != mssa.state_before(inst) {
+                return false;
+            }
+            if mssa.state_after(def) != Some(before) && mssa.state_before(inst).is_some() {
+                return false;
+            }
+        }
+
+        // Likewise for globals, tables, and locals, each as a whole.
+        [
+            (SideEffect::ReadGlobal, SideEffect::WriteGlobal),
+            (SideEffect::ReadTable, SideEffect::WriteTable),
+            (SideEffect::ReadLocal, SideEffect::WriteLocal),
+        ]
+        .iter()
+        .all(|&(read, write)| {
+            let def_accesses = has(def_effects, read) || has(def_effects, write);
+            let inst_accesses = has(inst_effects, read) || has(inst_effects, write);
+            !(has(def_effects, write) && inst_accesses || has(inst_effects, write) && def_accesses)
+        })
+    }
+}
+
+fn has(effects: &[SideEffect], effect: SideEffect) -> bool {
+    effects.contains(&effect) || effects.contains(&SideEffect::All)
+}
+
+fn writes_any(op: &Operator) -> bool {
+    let effects = op.effects();
+    [
+        SideEffect::WriteMem,
+        SideEffect::WriteGlobal,
+        SideEffect::WriteTable,
+        SideEffect::WriteLocal,
+    ]
+    .iter()
+    .any(|&effect| has(effects, effect))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{GlobalData, Module, SignatureData, Terminator, Type};
+    use crate::{BackendOptions, Memory, MemoryArg};
+
+    #[test]
+    fn loads_sink_to_their_uses() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32, Type::I32, Type::I32],
+        });
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(0),
+            mutable: true,
+            init: None,
+        });
+        let memory = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory: Memory::from(0),
+        };
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let p = body.blocks[entry].params[0].1;
+        let one = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let a = body.add_op(entry, Operator::I32Load { memory }, &[p], &[Type::I32]);
+        let b = body.add_op(
+            entry,
+            Operator::GlobalGet {
+                global_index: global,
+            },
+            &[],
+            &[Type::I32],
+        );
+        let c = body.add_op(entry, Operator::I32Add, &[b, b], &[Type::I32]);
+        let u = body.add_op(entry, Operator::I32Mul, &[a, one], &[Type::I32]);
+        // A load may not pass a store.
+        let a2 = body.add_op(entry, Operator::I32Load { memory }, &[p], &[Type::I32]);
+        let st = body.add_op(entry, Operator::I32Store { memory }, &[p, p], &[]);
+        let u2 = body.add_op(entry, Operator::I32Add, &[a2, one], &[Type::I32]);
+        // Keep the `global.get` from being rematerialized.
+        let set = Operator::GlobalSet {
+            global_index: global,
+        };
+        let gs = body.add_op(entry, set, &[c], &[]);
+        body.set_terminator(
+            entry,
+            Terminator::Return {
+                values: vec![u, u2, c],
+            },
+        );
+
+        let cfg = CFGInfo::new(&body);
+        let mut scheduled = body.clone();
+        assert_eq!(Schedule::compute(&body, &cfg).apply(&mut scheduled), 1);
+        assert_eq!(
+            scheduled.blocks[entry].insts,
+            vec![one, b, c, a, u, a2, st, u2, gs]
+        );
+        scheduled.validate().unwrap();
+
+        let unscheduled = BackendOptions {
+            schedule: false,
+            ..BackendOptions::default()
+        };
+        // The sunk load no longer goes through a `local.set` and
+        // `local.get` pair.
+        let with = body.compile().unwrap().into_raw_body();
+        let without = body
+            .compile_with_options(&unscheduled)
+            .unwrap()
+            .into_raw_body();
+        assert_eq!(with.len() + 4, without.len());
+    }
+}
@@ -0,0 +1,272 @@
+//! Stack maps: which locals hold live references at each call, for
+//! runtimes that find GC roots by scanning frames.
+//!
+//! With `BackendOptions::stack_maps` set, reference-typed values are
+//! never folded into trees or rematerialized, so each one stays in a
+//! local while it is live, and any copy of it on the operand stack at
+//! a call is also in that local. The maps are conservative: at a call,
+//! they include every reference that the instruction tree containing
+//! the call uses, even past its last use.
+
+use super::localify::{BlockVisitor, Localifier, Visitor};
+use super::treeify::Trees;
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{Func, FunctionBody, Local, Value, ValueDef};
+use crate::Operator;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use wasm_encoder::Encode;
+
+/// The name of the custom section holding the stack maps.
+pub const STACK_MAP_SECTION: &str = "waffle.stack_maps";
+
+/// The references live across one call in a compiled body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackMap {
+    /// The offset just past the call instruction, i.e. its return
+    /// address, relative to the start of the function body.
+    pub offset: u32,
+    /// The call.
+    pub call: Value,
+    /// The reference-typed values live across the call, with the
+    /// locals holding them, in order of local.
+    pub live: Vec<(Value, Local)>,
+}
+
+/// One stack map as stored in the custom section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackMapRecord {
+    /// The return address of the call, as a byte offset in the module.
+    pub offset: u32,
+    pub func: Func,
+    /// The locals holding live references, in order.
+    pub locals: Vec<Local>,
+}
+
+fn is_call(op: &Operator) -> bool {
+    op.is_call() || matches!(op, Operator::CallRef { .. })
+}
+
+/// Add the calls in the tree rooted at `value` to `calls`.
+fn tree_calls(body: &FunctionBody, trees: &Trees, value: Value, calls: &mut Vec<Value>) {
+    if let ValueDef::Operator(op, args, _) = &body.values[value] {
+        if is_call(op) {
+            calls.push(value);
+        }
+        for &arg in &body.arg_pool[*args] {
+            let arg = body.resolve_alias(arg);
+            if trees.owner.contains_key(&arg) || trees.remat.contains(&arg) {
+                tree_calls(body, trees, arg, calls);
+            }
+        }
+    }
+}
+
+struct SafepointVisitor<'a> {
+    body: &'a FunctionBody,
+    trees: &'a Trees,
+    locals: &'a Localifier,
+    live: HashSet<Value>,
+    maps: &'a mut HashMap<Value, Vec<(Value, Local)>>,
+}
+
+impl<'a> Visitor for SafepointVisitor<'a> {
+    fn visit_use(&mut self, value: Value) {
+        self.live.insert(value);
+    }
+    fn visit_def(&mut self, value: Value) {
+        self.live.remove(&value);
+    }
+    fn pre_inst(&mut self, inst: Value) {
+        // Here `live` holds what is live after `inst`, less `inst`
+        // itself, plus everything its tree uses.
+        let mut calls = vec![];
+        tree_calls(self.body, self.trees, inst, &mut calls);
+        if calls.is_empty() {
+            return;
+        }
+        let mut refs = vec![];
+        for &value in &self.live {
+            let tys = self.body.values[value].tys(&self.body.type_pool);
+            for (ty, &local) in tys.iter().zip(self.locals.values[value].iter()) {
+                if ty.is_ref() {
+                    refs.push((value, local));
+                }
+            }
+        }
+        refs.sort_by_key(|&(_, local)| local);
+        for call in calls {
+            self.maps.insert(call, refs.clone());
+        }
+    }
+}
+
+/// The references live across each call in `body`, keyed by call.
+pub(crate) fn compute(
+    body: &FunctionBody,
+    cfg: &CFGInfo,
+    trees: &Trees,
+    locals: &Localifier,
+) -> HashMap<Value, Vec<(Value, Local)>> {
+    let mut maps = HashMap::new();
+    for &block in cfg.rpo.values() {
+        let live = locals.block_end_live[block]
+            .iter()
+            .map(|&value| body.resolve_alias(value))
+            .collect();
+        let visitor = SafepointVisitor {
+            body,
+            trees,
+            locals,
+            live,
+            maps: &mut maps,
+        };
+        BlockVisitor::new(body, trees, visitor).visit_block(block);
+    }
+    maps
+}
+
+/// Encode the stack maps of the defined functions, given in order
+/// after `num_func_imports` imports with the module offsets of their
+/// bodies, as a stack-map section.
+pub(crate) fn section(
+    num_func_imports: usize,
+    bases: &[u32],
+    maps: &[Vec<StackMap>],
+) -> wasm_encoder::CustomSection<'static> {
+    let mut data = vec![];
+    (maps.iter().map(|maps| maps.len()).sum::<usize>() as u32).encode(&mut data);
+    for (i, (&base, maps)) in bases.iter().zip(maps).enumerate() {
+        for map in maps {
+            (base + map.offset).encode(&mut data);
+            ((num_func_imports + i) as u32).encode(&mut data);
+            (map.live.len() as u32).encode(&mut data);
+            for &(_, local) in &map.live {
+                (local.index() as u32).encode(&mut data);
+            }
+        }
+    }
+    wasm_encoder::CustomSection {
+        name: STACK_MAP_SECTION.into(),
+        data: data.into(),
+    }
+}
+
+/// Decode the contents of a stack-map custom section.
+pub fn decode(data: &[u8]) -> Result<Vec<StackMapRecord>> {
+    let mut reader = wasmparser::BinaryReader::new(data, 0, wasmparser::WasmFeatures::all());
+    let mut records = vec![];
+    for _ in 0..reader.read_var_u32()? {
+        let offset = reader.read_var_u32()?;
+        let func = Func::new(reader.read_var_u32()? as usize);
+        let locals = (0..reader.read_var_u32()?)
+            .map(|_| Ok(Local::new(reader.read_var_u32()? as usize)))
+            .collect::<Result<Vec<_>>>()?;
+        records.push(StackMapRecord {
+            offset,
+            func,
+            locals,
+        });
+    }
+    if !reader.eof() {
+        bail!("Trailing bytes after stack maps");
+    }
+    Ok(records)
+}
+
+/// Find and decode the stack maps in a compiled module, if it has
+/// them.
+pub fn from_wasm_bytes(bytes: &[u8]) -> Result<Option<Vec<StackMapRecord>>> {
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == STACK_MAP_SECTION {
+                return decode(section.data()).map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::WasmFuncBackend;
+    use crate::ir::{FuncDecl, Import, ImportKind, Module, SignatureData, Terminator, Type};
+    use crate::BackendOptions;
+
+    #[test]
+    fn refs_live_across_calls() {
+        let mut module = Module::empty();
+        let host_sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let host = module
+            .funcs
+            .push(FuncDecl::Import(host_sig, "host".to_owned()));
+        module.imports.push(Import {
+            module: "env".to_owned(),
+            name: "host".to_owned(),
+            kind: ImportKind::Func(host),
+        });
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::FuncRef, Type::I32],
+            returns: vec![Type::FuncRef],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let r = body.blocks[entry].params[0].1;
+        let x = body.blocks[entry].params[1].1;
+        let call = Operator::Call {
+            function_index: host,
+        };
+        let first = body.add_op(entry, call, &[x], &[Type::I32]);
+        let second = body.add_op(entry, call, &[first], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![r] });
+
+        let options = BackendOptions {
+            stack_maps: true,
+            ..BackendOptions::default()
+        };
+        let (_, info, _) = WasmFuncBackend::compile_with_emit_info(&body, &options).unwrap();
+        // The first call is folded into the second, its only use; both
+        // see the reference in its parameter local.
+        let calls = info
+            .stack_maps
+            .iter()
+            .map(|map| map.call)
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec![first, second]);
+        for map in &info.stack_maps {
+            assert_eq!(map.live, vec![(r, Local::new(0))]);
+        }
+
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        let bytes = module.to_wasm_bytes_with_options(&options, None).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let records = from_wasm_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(records.len(), 2);
+
+        // Each record's offset is the return address of a call.
+        let mut returns = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let mut ops = body.get_operators_reader().unwrap();
+                while !ops.eof() {
+                    let (op, _) = ops.read_with_offset().unwrap();
+                    if let wasmparser::Operator::Call { .. } = op {
+                        returns.push(ops.original_position() as u32);
+                    }
+                }
+            }
+        }
+        for (record, offset) in records.iter().zip(returns) {
+            assert_eq!(record.offset, offset);
+            assert_eq!(record.func, func);
+            assert_eq!(record.locals, vec![Local::new(0)]);
+        }
+    }
+}
@@ -33,9 +33,11 @@ pub enum WasmBlock<'a> {
     Leaf { block: Block },
     /// A translated unconditional branch.
     Br { target: WasmLabel },
-    /// A translated conditional.
+    /// A translated conditional, with the branch hint of the block
+    /// it came from.
     If {
         cond: Value,
+        hint: Option<bool>,
         if_true: Vec<WasmBlock<'a>>,
         if_false: Vec<WasmBlock<'a>>,
     },
@@ -111,7 +113,7 @@ enum StackEntry<'a> {
     FinishLoop(Block),
     FinishBlock(Block),
     Else,
-    FinishIf(Value),
+    FinishIf(Value, Option<bool>),
     DoBranch(Block, &'a BlockTarget),
 }
 
@@ -143,10 +145,10 @@ impl<'a, 'b> Context<'a, 'b> {
                 log::trace!(
                     "block {} ({}) rpo {} has succ {} ({})",
                     block,
-                    body.blocks[block].desc,
+                    body.blocks[block].origin,
                     block_rpo,
                     succ,
-                    body.blocks[succ].desc,
+                    body.blocks[succ].origin,
                 );
                 let succ_rpo = cfg.rpo_pos[succ].unwrap();
                 log::trace!(" -> succ rpo {}", succ_rpo);
@@ -155,9 +157,9 @@ impl<'a, 'b> Context<'a, 'b> {
                         anyhow::bail!(
                             "Irreducible control flow: edge from {} ({}) to {} ({})",
                             block,
-                            body.blocks[block].desc,
+                            body.blocks[block].origin,
                             succ,
-                            body.blocks[succ].desc
+                            body.blocks[succ].origin
                         );
                     }
                     // Backward branch.
@@ -220,8 +222,8 @@ impl<'a, 'b> Context<'a, 'b> {
             StackEntry::Else => {
                 self.else_();
             }
-            StackEntry::FinishIf(cond) => {
-                self.finish_if(cond);
+            StackEntry::FinishIf(cond, hint) => {
+                self.finish_if(cond, hint);
             }
             StackEntry::DoBranch(source, target) => {
                 self.do_branch(source, target);
@@ -381,12 +383,13 @@ impl<'a, 'b> Context<'a, 'b> {
         self.result.push(vec![]);
     }
 
-    fn finish_if(&mut self, cond: Value) {
+    fn finish_if(&mut self, cond: Value, hint: Option<bool>) {
         let else_body = self.result.pop().unwrap();
         let if_body = self.result.pop().unwrap();
         self.ctrl_stack.pop();
         self.result.last_mut().unwrap().push(WasmBlock::If {
             cond,
+            hint,
             if_true: if_body,
             if_false: else_body,
         });
@@ -420,7 +423,8 @@ impl<'a, 'b> Context<'a, 'b> {
                     ref if_false,
                 } => {
                     self.ctrl_stack.push(CtrlEntry::IfThenElse);
-                    self.process_stack.push(StackEntry::FinishIf(cond));
+                    let hint = self.body.blocks[block].branch_hint;
+                    self.process_stack.push(StackEntry::FinishIf(cond, hint));
                     self.process_stack
                         .push(StackEntry::DoBranch(block, if_false));
                     self.process_stack.push(StackEntry::Else);
@@ -1,8 +1,10 @@
 //! Treeification: placing some values "under" others if only used
-//! once, to generate more AST-like Wasm code.
+//! once, to generate more AST-like Wasm code, and regenerating cheap
+//! values at each use rather than keeping them in locals.
 
+use crate::backend::BackendOptions;
 use crate::entity::EntityRef;
-use crate::ir::{FunctionBody, Value, ValueDef};
+use crate::ir::{FunctionBody, Global, Value, ValueDef};
 use crate::Operator;
 use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use std::convert::TryFrom;
@@ -23,23 +25,75 @@ pub struct Trees {
     pub remat: HashSet<Value>,
 }
 
-fn is_remat(op: &Operator) -> bool {
-    // Only ops with no args can be always-rematerialized.
-    match op {
+fn is_const(op: &Operator) -> bool {
+    matches!(
+        op,
         Operator::I32Const { .. }
-        | Operator::I64Const { .. }
-        | Operator::F32Const { .. }
-        | Operator::F64Const { .. } => true,
-        _ => false,
+            | Operator::I64Const { .. }
+            | Operator::F32Const { .. }
+            | Operator::F64Const { .. }
+    )
+}
+
+/// Globals whose value cannot change during the function, so that a
+/// `global.get` of them may be repeated at any point: those the body
+/// never sets, provided it makes no calls (which could set them).
+fn stable_globals(body: &FunctionBody) -> Option<HashSet<Global>> {
+    let mut set = HashSet::default();
+    for block_def in body.blocks.values() {
+        for &inst in &block_def.insts {
+            if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                match op {
+                    Operator::GlobalSet { global_index } => {
+                        set.insert(*global_index);
+                    }
+                    _ if op.is_call() || matches!(op, Operator::CallRef { .. }) => return None,
+                    _ => {}
+                }
+            }
+        }
     }
+    let stable = body
+        .values
+        .values()
+        .filter_map(|def| match def {
+            ValueDef::Operator(Operator::GlobalGet { global_index }, ..) => Some(*global_index),
+            _ => None,
+        })
+        .filter(|global| !set.contains(global))
+        .collect();
+    Some(stable)
+}
+
+/// Is `op` an address-like computation that may be rematerialized:
+/// integer arithmetic that cannot trap?
+fn is_remat_arith(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Add
+            | Operator::I32Sub
+            | Operator::I32Shl
+            | Operator::I32And
+            | Operator::I32Or
+            | Operator::I64Add
+            | Operator::I64Sub
+            | Operator::I64Shl
+            | Operator::I64And
+            | Operator::I64Or
+    )
 }
 
 impl Trees {
-    pub fn compute(body: &FunctionBody) -> Trees {
+    pub fn compute(body: &FunctionBody, options: &BackendOptions) -> Trees {
         let mut owner = HashMap::default();
         let mut owned = HashMap::default();
         let mut remat = HashSet::default();
         let mut multi_use = HashSet::default();
+        let stable_globals = if options.remat_globals {
+            stable_globals(body).unwrap_or_default()
+        } else {
+            HashSet::default()
+        };
 
         for block_def in body.blocks.values() {
             let mut last_non_pure = None;
@@ -53,10 +107,29 @@ impl Trees {
                         }
                         // If this is an always-rematerialized operator,
                         // mark it as such and continue.
-                        if is_remat(&op) {
+                        if is_const(&op) {
                             remat.insert(value);
                             continue;
                         }
+                        if let Operator::GlobalGet { global_index } = op {
+                            if stable_globals.contains(&global_index)
+                                && !Self::is_pinned_ref(body, value, options)
+                            {
+                                remat.insert(value);
+                                continue;
+                            }
+                        }
+                        // If this is a cheap computation from one
+                        // value with a local, rematerialize it too;
+                        // that value must then stay in its local.
+                        if let Some(base) = Self::remat_base(body, value, &remat, options) {
+                            remat.insert(value);
+                            if let Some(old_owner) = owner.remove(&base) {
+                                owned.remove(&old_owner);
+                            }
+                            multi_use.insert(base);
+                            continue;
+                        }
 
                         // For each of the args, if the value is produced
                         // by a single-output op and is movable, and is
@@ -71,6 +144,8 @@ impl Trees {
                             } else if let Some(old_owner) = owner.remove(&arg) {
                                 owned.remove(&old_owner);
                                 multi_use.insert(arg);
+                            } else if Self::is_pinned_ref(body, arg, options) {
+                                continue;
                             } else if Self::is_movable(body, arg) || Some(arg) == last_non_pure {
                                 let pos = u16::try_from(i).unwrap();
                                 let value_arg = ValueArg(value, pos);
@@ -109,6 +184,52 @@ impl Trees {
         }
     }
 
+    /// If `value` is an address-like computation from constants and
+    /// one other value with a local, small enough to rematerialize
+    /// under `options`, return that other value.
+    fn remat_base(
+        body: &FunctionBody,
+        value: Value,
+        remat: &HashSet<Value>,
+        options: &BackendOptions,
+    ) -> Option<Value> {
+        if !options.remat_addresses {
+            return None;
+        }
+        let (op, args) = match &body.values[value] {
+            ValueDef::Operator(op, args, tys) if tys.len() == 1 && is_remat_arith(op) => {
+                (op, &body.arg_pool[*args])
+            }
+            _ => return None,
+        };
+        let mut size = options.cost_model.size(op);
+        let mut base = None;
+        for &arg in args {
+            let arg = body.resolve_alias(arg);
+            match &body.values[arg] {
+                ValueDef::Operator(arg_op, ..) if is_const(arg_op) => {
+                    size += options.cost_model.size(arg_op);
+                }
+                ValueDef::Operator(..) | ValueDef::BlockParam(..)
+                    if base.is_none() && !remat.contains(&arg) =>
+                {
+                    base = Some(arg);
+                }
+                _ => return None,
+            }
+        }
+        base.filter(|_| size <= options.max_remat_size)
+    }
+
+    /// Must `value` stay in a local because it is a reference and
+    /// `options` asks for stack maps, which list only locals?
+    fn is_pinned_ref(body: &FunctionBody, value: Value, options: &BackendOptions) -> bool {
+        options.stack_maps
+            && body.values[value]
+                .ty(&body.type_pool)
+                .is_some_and(|ty| ty.is_ref())
+    }
+
     fn is_single_output_op(body: &FunctionBody, value: Value) -> Option<Operator> {
         match &body.values[value] {
             &ValueDef::Operator(op, _, ref tys) if tys.len() == 1 => Some(op),
@@ -122,3 +243,69 @@ impl Trees {
             .unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ir::{FuncDecl, GlobalData, Module, SignatureData, Terminator, Type};
+    use crate::{BackendOptions, ConstVal, FrontendOptions, InterpContext, InterpResult, Operator};
+
+    #[test]
+    fn remat_saves_locals() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(10),
+            mutable: true,
+            init: None,
+        });
+        let mut body = crate::ir::FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let four = body.add_op(entry, Operator::I32Const { value: 4 }, &[], &[Type::I32]);
+        let addr = body.add_op(entry, Operator::I32Add, &[x, four], &[Type::I32]);
+        let g = body.add_op(
+            entry,
+            Operator::GlobalGet {
+                global_index: global,
+            },
+            &[],
+            &[Type::I32],
+        );
+        let a = body.add_op(entry, Operator::I32Add, &[addr, g], &[Type::I32]);
+        let b = body.add_op(entry, Operator::I32Mul, &[a, addr], &[Type::I32]);
+        let c = body.add_op(entry, Operator::I32Sub, &[b, g], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![c] });
+
+        let no_remat = BackendOptions {
+            remat_globals: false,
+            remat_addresses: false,
+            ..BackendOptions::default()
+        };
+        let with = crate::backend::WasmFuncBackend::frame_info(&body).unwrap();
+        let without =
+            crate::backend::WasmFuncBackend::frame_info_with_options(&body, &no_remat).unwrap();
+        assert_eq!(with.locals, 2);
+        assert_eq!(without.locals, 4);
+
+        module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        for options in [BackendOptions::default(), no_remat] {
+            let bytes = module.to_wasm_bytes_with_options(&options, None).unwrap();
+            wasmparser::Validator::new().validate_all(&bytes).unwrap();
+            let mut compiled =
+                Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+            compiled.expand_all_funcs().unwrap();
+            let mut ctx = InterpContext::new(&compiled).unwrap();
+            // ((3 + 4) + 10) * (3 + 4) - 10
+            match ctx.call(&compiled, crate::Func::from(0), &[ConstVal::I32(3)]) {
+                InterpResult::Ok(vals) => assert_eq!(&vals[..], &[ConstVal::I32(109)]),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Validation of the backend's output.
+//!
+//! With `BackendOptions::validate`, the compiled module is checked
+//! with wasmparser's validator, configured with the features the
+//! module uses (see `FeatureSet`), so that invalid output is caught
+//! where it is produced rather than by the engine that loads it. An
+//! error within a function's code names the function and, if it has
+//! IR, includes it.
+
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, ImportKind, Module};
+use crate::Func;
+use anyhow::{anyhow, Result};
+
+/// Validate `bytes`, compiled from `module`.
+pub fn validate(module: &Module, bytes: &[u8]) -> Result<()> {
+    let features = module.detect_features()?.wasm_features();
+    let err = match wasmparser::Validator::new_with_features(features).validate_all(bytes) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    let func_imports = module
+        .imports
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Func(..)))
+        .count();
+    let func = match func_at(bytes, err.offset()) {
+        Some(index) => Func::new(func_imports + index),
+        None => return Err(anyhow!("Backend produced invalid Wasm: {}", err)),
+    };
+    let decl = &module.funcs[func];
+    let mut message = format!(
+        "Backend produced invalid Wasm in {} \"{}\": {}",
+        func,
+        decl.name(),
+        err
+    );
+    if let FuncDecl::Body(_, _, body) = decl {
+        message += &format!("\n{}", body.display("| ", Some(module)));
+    }
+    Err(anyhow!(message))
+}
+
+/// The index among defined functions of the body in `bytes` that
+/// contains `offset`, if any.
+fn func_at(bytes: &[u8], offset: usize) -> Option<usize> {
+    let mut index = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload.ok()? {
+            if body.range().contains(&offset) {
+                return Some(index);
+            }
+            index += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir::{FuncDecl, FunctionBody, SignatureData, Terminator, Type};
+    use crate::{BackendOptions, Module, Operator};
+
+    #[test]
+    fn invalid_output_names_the_function() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        // `i32.add` of an `i64`: the IR type-checks nothing here, so
+        // the backend emits it as is.
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.add_op(entry, Operator::I64Const { value: 1 }, &[], &[Type::I64]);
+        let sum = body.add_op(entry, Operator::I32Add, &[x, x], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![sum] });
+        module
+            .funcs
+            .push(FuncDecl::Body(sig, "bad".to_owned(), body.into()));
+
+        assert!(module.to_wasm_bytes().is_ok());
+        let options = BackendOptions {
+            validate: true,
+            ..BackendOptions::default()
+        };
+        let err = module
+            .to_wasm_bytes_with_options(&options, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Backend produced invalid Wasm in func0 \"bad\": "));
+        assert!(err.contains("i32add"));
+    }
+}
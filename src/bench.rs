@@ -0,0 +1,213 @@
+//! Performance measurement: how long parsing, each pass of the
+//! optimization pipeline, and emission take on a module. This backs
+//! both the criterion suite in `benches/` and `waffle-util bench`,
+//! which can also save results and check later ones against them.
+
+use crate::passes::pipeline::{Pass, Pipeline};
+use crate::testgen::{gen_module, GenOptions};
+use crate::{FrontendOptions, Module, OptOptions};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The environment variable naming a directory of extra `.wasm`
+/// modules, such as large real-world ones too big to check in, to add
+/// to the corpus.
+pub const CORPUS_ENV: &str = "WAFFLE_BENCH_CORPUS";
+
+/// The benchmark corpus: generated modules of a few sizes, plus every
+/// `.wasm` file in `dir` (if given) or in the directory named by
+/// `WAFFLE_BENCH_CORPUS` (if set). Each is returned with a name.
+pub fn corpus(dir: Option<&Path>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut modules = vec![];
+    for (name, funcs, max_blocks) in [("gen-small", 20, 8), ("gen-large", 500, 32)] {
+        let options = GenOptions {
+            funcs,
+            max_blocks,
+            memory: true,
+            ..GenOptions::default()
+        };
+        modules.push((name.to_owned(), gen_module(1, &options).to_wasm_bytes()?));
+    }
+    let env_dir = std::env::var_os(CORPUS_ENV);
+    if let Some(dir) = dir.or(env_dir.as_deref().map(Path::new)) {
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        paths.sort();
+        for path in paths {
+            if path.extension().is_some_and(|ext| ext == "wasm") {
+                let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                modules.push((name, std::fs::read(&path)?));
+            }
+        }
+    }
+    Ok(modules)
+}
+
+/// Parse `bytes` and expand every function body into IR.
+pub fn parse<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Result<Module<'a>> {
+    let mut module = Module::from_wasm_bytes(bytes, options)?;
+    module.expand_all_funcs()?;
+    Ok(module)
+}
+
+/// Run `pass` on every function body in `module`.
+pub fn run_pass(module: &mut Module, pass: Pass, options: &OptOptions) {
+    module.per_func_body(|body| pass.run(body, options));
+}
+
+/// `pipeline`'s options, with the effect summaries of `module` if
+/// they have none, as `waffle-util optimize` would run them.
+pub fn module_options(module: &Module, pipeline: &Pipeline) -> OptOptions {
+    let mut options = pipeline.options.clone();
+    if options.effects.is_none() {
+        options.effects = Some(Arc::new(module.effect_summaries()));
+    }
+    options
+}
+
+/// How long each phase took on one module.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhaseTimes {
+    /// Parsing and expanding every function.
+    pub parse: Duration,
+    /// Each pass of the pipeline, over every function, in order.
+    pub passes: Vec<(Pass, Duration)>,
+    /// Compiling the optimized module back to Wasm.
+    pub emit: Duration,
+}
+
+impl PhaseTimes {
+    /// The phases by name, in order.
+    pub fn phases(&self) -> Vec<(String, Duration)> {
+        let mut phases = vec![("parse".to_owned(), self.parse)];
+        phases.extend(
+            self.passes
+                .iter()
+                .map(|(pass, time)| (pass.name().to_owned(), *time)),
+        );
+        phases.push(("emit".to_owned(), self.emit));
+        phases
+    }
+}
+
+/// Time each phase on `bytes` with `pipeline`, taking the fastest of
+/// `iters` runs of each.
+pub fn measure(
+    bytes: &[u8],
+    frontend: &FrontendOptions,
+    pipeline: &Pipeline,
+    iters: usize,
+) -> Result<PhaseTimes> {
+    fn time<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+        let start = Instant::now();
+        let result = f();
+        (result, start.elapsed())
+    }
+    let mut times = PhaseTimes {
+        parse: Duration::MAX,
+        passes: pipeline
+            .passes
+            .iter()
+            .map(|&p| (p, Duration::MAX))
+            .collect(),
+        emit: Duration::MAX,
+    };
+    for _ in 0..iters.max(1) {
+        let (module, parse) = time(|| parse(bytes, frontend));
+        let mut module = module?;
+        times.parse = times.parse.min(parse);
+        let options = module_options(&module, pipeline);
+        for (i, &pass) in pipeline.passes.iter().enumerate() {
+            let ((), elapsed) = time(|| run_pass(&mut module, pass, &options));
+            times.passes[i].1 = times.passes[i].1.min(elapsed);
+        }
+        let (bytes, emit) = time(|| module.to_wasm_bytes());
+        bytes?;
+        times.emit = times.emit.min(emit);
+    }
+    Ok(times)
+}
+
+/// Results for a corpus: phase times by module name.
+pub type BenchResults = BTreeMap<String, PhaseTimes>;
+
+/// Write `results` as text, one `module phase nanoseconds` line per
+/// phase, for `compare()` to read back later.
+pub fn results_to_text(results: &BenchResults) -> String {
+    let mut text = String::new();
+    for (name, times) in results {
+        for (phase, time) in times.phases() {
+            text += &format!("{} {} {}\n", name, phase, time.as_nanos());
+        }
+    }
+    text
+}
+
+/// Compare `results` against a baseline written by
+/// `results_to_text()`, and describe every phase that got slower by
+/// more than `threshold` percent. Phases missing from either side are
+/// ignored.
+pub fn compare(results: &BenchResults, baseline: &str, threshold: f64) -> Result<Vec<String>> {
+    let mut base = BTreeMap::new();
+    for line in baseline.lines().filter(|line| !line.trim().is_empty()) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (name, phase, nanos) = match &fields[..] {
+            [name, phase, nanos] => (*name, *phase, nanos.parse::<u64>()?),
+            _ => bail!("Malformed baseline line: {}", line),
+        };
+        base.insert((name.to_owned(), phase.to_owned()), nanos);
+    }
+    let mut regressions = vec![];
+    for (name, times) in results {
+        for (phase, time) in times.phases() {
+            let key = (name.clone(), phase);
+            let Some(&before) = base.get(&key) else {
+                continue;
+            };
+            let after = time.as_nanos() as f64;
+            let change = (after - before as f64) / (before as f64).max(1.0) * 100.0;
+            if change > threshold {
+                regressions.push(format!(
+                    "{} {}: {:?} -> {:?} (+{:.1}%)",
+                    key.0,
+                    key.1,
+                    Duration::from_nanos(before),
+                    time,
+                    change
+                ));
+            }
+        }
+    }
+    Ok(regressions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measure_and_compare() {
+        let corpus = corpus(None).unwrap();
+        let pipeline = Pipeline::from(OptOptions::default());
+        let mut results = BenchResults::new();
+        let (name, bytes) = &corpus[0];
+        let times = measure(bytes, &FrontendOptions::default(), &pipeline, 1).unwrap();
+        assert_eq!(times.passes.len(), pipeline.passes.len());
+        results.insert(name.clone(), times);
+
+        let text = results_to_text(&results);
+        assert!(compare(&results, &text, 0.0).unwrap().is_empty());
+        // A baseline where everything took no time at all.
+        let fast = text
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_owned() + " 0\n")
+            .collect::<String>();
+        let regressions = compare(&results, &fast, 10.0).unwrap();
+        assert!(regressions[0].starts_with("gen-small parse: "));
+        assert!(compare(&results, "gen-small parse", 10.0).is_err());
+    }
+}
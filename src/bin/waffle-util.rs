@@ -2,9 +2,34 @@
 
 use anyhow::Result;
 use log::debug;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use waffle::{entity::EntityRef, FrontendOptions, Func, Module, OptOptions};
+use waffle::equiv::{self, Budget, Equivalence};
+use waffle::passes::extract::extract_func;
+use waffle::passes::func_order::{reorder_funcs, FuncOrder};
+use waffle::passes::index_assign::assign_indices;
+use waffle::passes::instrument::{
+    self, CoverageOptions, GasOptions, HeapOptions, MemcheckOptions, TraceOptions,
+};
+use waffle::passes::link::{check_compat, merge, MergeOptions};
+use waffle::passes::overrides::{FuncMatcher, FuncOverride};
+use waffle::passes::pgo::{collect as collect_profile, pgo, PgoOptions, Profile};
+use waffle::passes::pipeline::Pass;
+use waffle::passes::preinit::{preinit, PreinitOptions};
+use waffle::passes::relaxed_simd;
+use waffle::passes::specialize::SpecializeOptions;
+use waffle::passes::split::{split, SplitOptions};
+use waffle::passes::split_funcs::{self, SplitFuncOptions};
+use waffle::passes::strings::{self, StringOptions};
+use waffle::passes::terminator_stats::TerminatorStats;
+use waffle::testgen::{self, GenOptions};
+use waffle::{
+    entity::EntityRef, BackendOptions, ConstVal, EmitInfo, ExportKind, FrontendOptions,
+    FsCompileCache, Func, Memory, Module, OptLevel, OptOptions, PrintDecorator, SizeCostModel,
+    SwitchLowering, Type, UnreachableCode, Value,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "waffle-util", about = "WAFFLE utility.")]
@@ -18,6 +43,12 @@ struct Options {
     )]
     basic_opts: bool,
 
+    #[structopt(
+        help = "Optimize at this level: 1, 2, 3, s (size), or z (size, aggressively)",
+        short = "O"
+    )]
+    opt_level: Option<OptLevel>,
+
     #[structopt(
         help = "Enable parsing of debug-info from input",
         short = "g",
@@ -25,9 +56,105 @@ struct Options {
     )]
     debug_info: bool,
 
+    #[structopt(
+        help = "Keep functions using unsupported features as their original bytecode",
+        long = "lenient"
+    )]
+    lenient: bool,
+
+    #[structopt(
+        help = "How to translate unreachable code: elide, drop, keep, or trap",
+        long = "unreachable-code"
+    )]
+    unreachable_code: Option<UnreachableCode>,
+
+    #[structopt(
+        help = "Size function bodies from their bytecode before parsing",
+        long = "preallocate"
+    )]
+    preallocate: bool,
+
+    #[structopt(
+        help = "Record the Wasm byte offset of each operator in the IR",
+        long = "wasm-offsets"
+    )]
+    wasm_offsets: bool,
+
     #[structopt(help = "Transform to maximal SSA", long = "max-ssa")]
     max_ssa: bool,
 
+    #[structopt(
+        help = "Do not optimize functions whose names match this pattern (* and ? wildcards)",
+        long = "no-opt-func"
+    )]
+    no_opt_funcs: Vec<String>,
+
+    #[structopt(
+        help = "Transform functions whose names match this pattern to maximal SSA",
+        long = "max-ssa-func"
+    )]
+    max_ssa_funcs: Vec<String>,
+
+    #[structopt(
+        help = "Canonicalize the module for reproducible output",
+        long = "canonicalize"
+    )]
+    canonicalize: bool,
+
+    #[structopt(
+        help = "Replace relaxed-SIMD operators with strict ones for engine-independent results",
+        long = "deterministic-simd"
+    )]
+    deterministic_simd: bool,
+
+    #[structopt(
+        help = "Give the most referenced functions, globals, and types the smallest indices",
+        long = "assign-indices"
+    )]
+    assign_indices: bool,
+
+    #[structopt(
+        help = "Keep all non-constant values in locals instead of rematerializing them",
+        long = "no-remat"
+    )]
+    no_remat: bool,
+
+    #[structopt(
+        help = "Emit a relocatable object file with linking and relocation sections",
+        long = "relocatable"
+    )]
+    relocatable: bool,
+
+    #[structopt(
+        help = "Emit a custom section mapping code offsets to IR values and source locations",
+        long = "offset-map"
+    )]
+    offset_map: bool,
+
+    #[structopt(
+        help = "Emit a custom section listing the locals that hold live references at each call",
+        long = "stack-maps"
+    )]
+    stack_maps: bool,
+
+    #[structopt(
+        help = "Validate the output, failing with the offending function's IR if it is invalid",
+        long = "validate-output"
+    )]
+    validate_output: bool,
+
+    #[structopt(
+        help = "Split functions over engine size limits into continuation functions",
+        long = "split-funcs"
+    )]
+    split_funcs: bool,
+
+    #[structopt(
+        help = "Cache compiled function bodies in this directory",
+        long = "cache-dir"
+    )]
+    cache_dir: Option<PathBuf>,
+
     #[structopt(subcommand)]
     command: Command,
 }
@@ -38,6 +165,11 @@ enum Command {
     PrintIR {
         #[structopt(help = "Wasm file to parse")]
         wasm: PathBuf,
+        #[structopt(
+            help = "Re-run when the input changes, printing only the functions that changed",
+            long = "watch"
+        )]
+        watch: bool,
     },
     #[structopt(name = "print-func", about = "Parse Wasm and print one function body")]
     PrintFunc {
@@ -46,6 +178,181 @@ enum Command {
         #[structopt(help = "Index of Wasm function to print")]
         func: usize,
     },
+    #[structopt(
+        name = "disasm-func",
+        about = "Print one optimized function's IR with the Wasm emitted for each value"
+    )]
+    DisasmFunc {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Index of Wasm function to disassemble")]
+        func: usize,
+    },
+    #[structopt(
+        name = "extract-func",
+        about = "Write one function and its dependencies as a standalone module"
+    )]
+    ExtractFunc {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Index of Wasm function to extract")]
+        func: usize,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+    },
+    #[structopt(
+        name = "check-compat",
+        about = "Check that one Wasm module's exports satisfy another's imports"
+    )]
+    CheckCompat {
+        #[structopt(help = "Wasm file providing the exports")]
+        provider: PathBuf,
+        #[structopt(help = "Wasm file whose imports to check")]
+        consumer: PathBuf,
+        #[structopt(
+            help = "Check only imports from this module name (may be repeated)",
+            long = "module"
+        )]
+        modules: Vec<String>,
+    },
+    #[structopt(name = "merge", about = "Merge two Wasm modules into one")]
+    Merge {
+        #[structopt(help = "First Wasm file")]
+        a: PathBuf,
+        #[structopt(help = "Second Wasm file")]
+        b: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(
+            help = "Link imports from one module (named by its file stem) to the other's exports",
+            long = "resolve-imports"
+        )]
+        resolve_imports: bool,
+    },
+    #[structopt(
+        name = "split",
+        about = "Split a Wasm module into a primary and a lazily loaded secondary module"
+    )]
+    Split {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Keep functions whose names match this pattern in the primary module",
+            long = "keep"
+        )]
+        keep: Vec<String>,
+        #[structopt(help = "Primary Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(help = "Secondary Wasm file to produce", long = "rest")]
+        rest: PathBuf,
+        #[structopt(
+            help = "Module name the secondary imports the primary under",
+            long = "primary-name",
+            default_value = "primary"
+        )]
+        primary_name: String,
+    },
+    #[structopt(
+        name = "preinit",
+        about = "Run a module's initialization ahead of time and snapshot the result"
+    )]
+    Preinit {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Exported function to run", long = "invoke")]
+        invoke: String,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(help = "Limit interpreter fuel for initialization", long = "fuel")]
+        fuel: Option<u64>,
+        #[structopt(help = "Keep the initialization function's export", long = "keep-init")]
+        keep_init: bool,
+    },
+    #[structopt(
+        name = "specialize",
+        about = "Fix the values of exported config globals and strip the code they disable"
+    )]
+    Specialize {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Fix an exported global's value, as name=value (repeatable)",
+            long = "define"
+        )]
+        define: Vec<String>,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+    },
+    #[structopt(
+        name = "profile",
+        about = "Collect execution counts by running exports in the interpreter"
+    )]
+    Profile {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Exported function to run (repeatable)", long = "invoke")]
+        invoke: Vec<String>,
+        #[structopt(help = "Profile JSON file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(help = "Limit interpreter fuel", long = "fuel")]
+        fuel: Option<u64>,
+    },
+    #[structopt(
+        name = "heap-profile",
+        about = "Run exports in the interpreter with heap monitoring and print allocation statistics"
+    )]
+    HeapProfile {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Exported function to run (repeatable)", long = "invoke")]
+        invoke: Vec<String>,
+        #[structopt(help = "Limit interpreter fuel", long = "fuel")]
+        fuel: Option<u64>,
+        #[structopt(flatten)]
+        heap: HeapArgs,
+    },
+    #[structopt(name = "pgo", about = "Optimize Wasm using a collected profile")]
+    Pgo {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Profile JSON file from `profile`", long = "profile")]
+        profile: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(help = "Do not inline hot calls", long = "no-inline")]
+        no_inline: bool,
+        #[structopt(
+            help = "Minimum call-site count for inlining",
+            long = "inline-threshold",
+            default_value = "100"
+        )]
+        inline_threshold: u64,
+        #[structopt(help = "Do not emit branch hints", long = "no-branch-hints")]
+        no_branch_hints: bool,
+        #[structopt(help = "Do not reorder functions", long = "no-layout")]
+        no_layout: bool,
+    },
+    #[structopt(
+        name = "reorder-funcs",
+        about = "Renumber functions by a chosen strategy"
+    )]
+    ReorderFuncs {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Order: original, call-graph, most-called, or hot-first",
+            long = "order",
+            default_value = "call-graph"
+        )]
+        order: FuncOrder,
+        #[structopt(
+            help = "Profile JSON file from `profile`, for hot-first",
+            long = "profile"
+        )]
+        profile: Option<PathBuf>,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+    },
     #[structopt(name = "roundtrip", about = "Round-trip Wasm through IR")]
     RoundTrip {
         #[structopt(help = "Wasm file to parse", short = "i")]
@@ -53,19 +360,564 @@ enum Command {
         #[structopt(help = "Wasm file to produce", short = "o")]
         output: PathBuf,
     },
+    #[structopt(
+        name = "optimize",
+        about = "Optimize Wasm and print a size and pass-timing summary"
+    )]
+    Optimize {
+        #[structopt(help = "Wasm file to parse", short = "i")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(
+            help = "Comma-separated passes to run instead of the -O level's pipeline",
+            long = "passes",
+            use_delimiter = true
+        )]
+        passes: Option<Vec<Pass>>,
+        #[structopt(
+            help = "Allow removing and speculating operators that may trap",
+            long = "no-preserve-traps"
+        )]
+        no_preserve_traps: bool,
+        #[structopt(
+            help = "Switch lowering policy: preserve, branches, tables, or auto",
+            long = "switch-lowering"
+        )]
+        switch_lowering: Option<SwitchLowering>,
+        #[structopt(
+            help = "Do not reorder instructions to keep values on the stack",
+            long = "no-schedule"
+        )]
+        no_schedule: bool,
+        #[structopt(
+            help = "Largest rematerialized address computation, in cost-model size units",
+            long = "max-remat-size"
+        )]
+        max_remat_size: Option<u32>,
+        #[structopt(help = "Validate the output", long = "validate")]
+        validate: bool,
+        #[structopt(
+            help = "Re-run when the input changes, printing only the functions that changed",
+            long = "watch"
+        )]
+        watch: bool,
+    },
+    #[structopt(
+        name = "instrument",
+        about = "Add coverage, gas, tracing, memory-checking, or heap-monitoring instrumentation"
+    )]
+    Instrument {
+        #[structopt(subcommand)]
+        kind: Instrumentation,
+    },
+    #[structopt(
+        name = "diff",
+        about = "Compare two Wasm modules function by function, by structural hash"
+    )]
+    Diff {
+        #[structopt(help = "Old Wasm file")]
+        old: PathBuf,
+        #[structopt(help = "New Wasm file")]
+        new: PathBuf,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Print per-function terminator, br_table, and frame statistics"
+    )]
+    Stats {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Print how often each operator occurs instead, most frequent first",
+            long = "opcodes"
+        )]
+        opcodes: bool,
+    },
+    #[structopt(
+        name = "strings",
+        about = "List the strings in data segments and the functions that refer to them"
+    )]
+    Strings {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Minimum string length in bytes",
+            long = "min-len",
+            default_value = "4"
+        )]
+        min_len: usize,
+    },
+    #[structopt(
+        name = "features",
+        about = "List the post-MVP proposals a module uses, without expanding it"
+    )]
+    Features {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+    },
+    #[structopt(
+        name = "host-surface",
+        about = "Describe what a module needs from and provides to its host, as JSON"
+    )]
+    HostSurface {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+    },
+    #[structopt(
+        name = "equiv",
+        about = "Check whether two functions of a module are equivalent"
+    )]
+    Equiv {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
+        #[structopt(help = "Index of the first function")]
+        a: usize,
+        #[structopt(help = "Index of the second function")]
+        b: usize,
+        #[structopt(
+            help = "Most inputs to run each function on",
+            long = "inputs",
+            default_value = "1000"
+        )]
+        inputs: usize,
+        #[structopt(help = "Fuel for each run", long = "fuel", default_value = "100000")]
+        fuel: u64,
+        #[structopt(help = "Seed for random inputs", long = "seed", default_value = "0")]
+        seed: u64,
+    },
+    #[structopt(name = "gen", about = "Generate a random IR module and print it")]
+    Gen {
+        #[structopt(help = "Random seed")]
+        seed: u64,
+        #[structopt(help = "Also write the module as Wasm to this file", short = "o")]
+        output: Option<PathBuf>,
+        #[structopt(help = "Number of functions", long = "funcs", default_value = "4")]
+        funcs: usize,
+        #[structopt(help = "Do not generate floating-point code", long = "no-floats")]
+        no_floats: bool,
+        #[structopt(help = "Do not generate memory accesses", long = "no-memory")]
+        no_memory: bool,
+        #[structopt(help = "Do not generate calls", long = "no-calls")]
+        no_calls: bool,
+        #[structopt(help = "Generate loops (which may not terminate)", long = "loops")]
+        loops: bool,
+    },
+    #[cfg(feature = "bench")]
+    #[structopt(
+        name = "bench",
+        about = "Time parsing, each pass, and emission over a corpus of modules"
+    )]
+    Bench {
+        #[structopt(help = "Extra Wasm files to measure")]
+        wasm: Vec<PathBuf>,
+        #[structopt(
+            help = "Also measure every .wasm file in this directory",
+            long = "corpus"
+        )]
+        corpus: Option<PathBuf>,
+        #[structopt(
+            help = "Runs of each phase; the fastest is kept",
+            long = "iters",
+            default_value = "5"
+        )]
+        iters: usize,
+        #[structopt(help = "Write results to this file as a baseline", long = "save")]
+        save: Option<PathBuf>,
+        #[structopt(
+            help = "Fail if any phase is slower than in this baseline",
+            long = "baseline"
+        )]
+        baseline: Option<PathBuf>,
+        #[structopt(
+            help = "Percent slowdown that counts as a regression",
+            long = "threshold",
+            default_value = "10"
+        )]
+        threshold: f64,
+    },
 }
 
-fn apply_options(opts: &Options, module: &mut Module) -> Result<()> {
+#[derive(Debug, StructOpt)]
+struct InputOutput {
+    #[structopt(help = "Wasm file to parse", short = "i")]
+    input: PathBuf,
+    #[structopt(help = "Wasm file to produce", short = "o")]
+    output: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+enum Instrumentation {
+    #[structopt(name = "coverage", about = "Count executions of each block in memory")]
+    Coverage {
+        #[structopt(flatten)]
+        io: InputOutput,
+        #[structopt(
+            help = "Memory holding the counters",
+            long = "memory",
+            default_value = "0"
+        )]
+        memory: usize,
+        #[structopt(help = "Address of the first counter", long = "base")]
+        base: u32,
+    },
+    #[structopt(
+        name = "gas",
+        about = "Charge each block's cost to an imported function"
+    )]
+    Gas {
+        #[structopt(flatten)]
+        io: InputOutput,
+        #[structopt(
+            help = "Imported gas function, as module.name",
+            long = "import",
+            default_value = "env.gas"
+        )]
+        import: ImportName,
+        #[structopt(
+            help = "Cost table: latency or size",
+            long = "cost",
+            default_value = "latency"
+        )]
+        cost: CostTable,
+    },
+    #[structopt(
+        name = "trace",
+        about = "Call an imported function on entry to every function"
+    )]
+    Trace {
+        #[structopt(flatten)]
+        io: InputOutput,
+        #[structopt(
+            help = "Imported trace function, as module.name",
+            long = "import",
+            default_value = "env.trace"
+        )]
+        import: ImportName,
+    },
+    #[structopt(
+        name = "memcheck",
+        about = "Call an imported function before every load and store"
+    )]
+    Memcheck {
+        #[structopt(flatten)]
+        io: InputOutput,
+        #[structopt(
+            help = "Imported check function, as module.name",
+            long = "import",
+            default_value = "env.memcheck"
+        )]
+        import: ImportName,
+    },
+    #[structopt(
+        name = "heap",
+        about = "Call imported monitor functions on memory growth and allocation"
+    )]
+    Heap {
+        #[structopt(flatten)]
+        io: InputOutput,
+        #[structopt(flatten)]
+        heap: HeapArgs,
+    },
+}
+
+/// Heap-monitoring options, shared by `instrument heap` and
+/// `heap-profile`.
+#[derive(Debug, StructOpt)]
+struct HeapArgs {
+    #[structopt(
+        help = "Module of the imported monitor functions",
+        long = "monitor",
+        default_value = "heap_monitor"
+    )]
+    monitor: String,
+    #[structopt(
+        help = "Also monitor the exported allocator with this name",
+        long = "malloc",
+        requires = "free"
+    )]
+    malloc: Option<String>,
+    #[structopt(
+        help = "Also monitor the exported deallocator with this name",
+        long = "free",
+        requires = "malloc"
+    )]
+    free: Option<String>,
+}
+
+impl HeapArgs {
+    fn options(&self) -> HeapOptions {
+        HeapOptions {
+            monitor: self.monitor.clone(),
+            allocator: self.malloc.clone().zip(self.free.clone()),
+        }
+    }
+}
+
+/// An import's module and name, written `module.name`.
+#[derive(Clone, Debug)]
+struct ImportName(String, String);
+
+impl std::str::FromStr for ImportName {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ImportName> {
+        match s.split_once('.') {
+            Some((module, name)) => Ok(ImportName(module.to_owned(), name.to_owned())),
+            None => anyhow::bail!("Expected module.name, got: {}", s),
+        }
+    }
+}
+
+/// Prints the Wasm instructions emitted for each value under its IR
+/// line. Each instruction is attributed to the innermost value whose
+/// code contains it, so folded operands are printed under themselves
+/// rather than again under their users.
+struct Disasm {
+    insts: BTreeMap<Value, Vec<String>>,
+    unattributed: Vec<String>,
+}
+
+impl Disasm {
+    fn new(body: &[u8], info: &EmitInfo) -> Result<Disasm> {
+        let reader = wasmparser::BinaryReader::new(body, 0, wasmparser::WasmFeatures::all());
+        let mut ops = wasmparser::FunctionBody::new(reader).get_operators_reader()?;
+        let mut disasm = Disasm {
+            insts: BTreeMap::new(),
+            unattributed: vec![],
+        };
+        while !ops.eof() {
+            let (op, offset) = ops.read_with_offset()?;
+            let offset = offset as u32;
+            let owner = info
+                .values
+                .iter()
+                .flat_map(|(&value, ranges)| ranges.iter().map(move |range| (value, range)))
+                .filter(|(_, range)| range.contains(&offset))
+                .min_by_key(|(_, range)| range.end - range.start)
+                .map(|(value, _)| value);
+            let line = format!("    {:#06x}: {:?}", offset, op);
+            match owner {
+                Some(value) => disasm.insts.entry(value).or_default().push(line),
+                None => disasm.unattributed.push(line),
+            }
+        }
+        Ok(disasm)
+    }
+}
+
+impl PrintDecorator for Disasm {
+    fn after_inst(&self, value: Value, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.insts.get(&value) {
+            Some(lines) => {
+                for line in lines {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
+            None => write!(f, " ; not emitted"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CostTable {
+    Latency,
+    Size,
+}
+
+impl std::str::FromStr for CostTable {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<CostTable> {
+        match s {
+            "latency" => Ok(CostTable::Latency),
+            "size" => Ok(CostTable::Size),
+            _ => anyhow::bail!("Unknown cost table: {}", s),
+        }
+    }
+}
+
+fn backend_options(opts: &Options) -> BackendOptions {
+    BackendOptions {
+        remat_globals: !opts.no_remat,
+        remat_addresses: !opts.no_remat,
+        relocatable: opts.relocatable,
+        offset_map: opts.offset_map,
+        stack_maps: opts.stack_maps,
+        validate: opts.validate_output,
+        ..BackendOptions::default()
+    }
+}
+
+fn compile(opts: &Options, module: &Module, backend_options: &BackendOptions) -> Result<Vec<u8>> {
+    match &opts.cache_dir {
+        Some(dir) => {
+            module.to_wasm_bytes_with_options(backend_options, Some(&FsCompileCache::new(dir)?))
+        }
+        None => module.to_wasm_bytes_with_options(backend_options, None),
+    }
+}
+
+fn add_overrides(opts: &Options, module: &mut Module) {
+    for pattern in &opts.no_opt_funcs {
+        let over = FuncOverride {
+            no_opt: true,
+            ..FuncOverride::default()
+        };
+        module
+            .func_overrides
+            .add(FuncMatcher::Name(pattern.clone()), over);
+    }
+    for pattern in &opts.max_ssa_funcs {
+        let over = FuncOverride {
+            max_ssa: Some(true),
+            ..FuncOverride::default()
+        };
+        module
+            .func_overrides
+            .add(FuncMatcher::Name(pattern.clone()), over);
+    }
+}
+
+/// Apply the global options that come before optimization.
+fn prepare_module(opts: &Options, module: &mut Module) -> Result<()> {
+    if opts.deterministic_simd {
+        let count = relaxed_simd::determinize(module)?;
+        debug!("Determinized relaxed SIMD in {} functions", count);
+    }
     module.expand_all_funcs()?;
-    if opts.basic_opts {
-        module.per_func_body(|body| body.optimize(&OptOptions::default()));
+    add_overrides(opts, module);
+    Ok(())
+}
+
+/// Apply the global options that come after optimization.
+fn finish_module(opts: &Options, module: &mut Module, backend: &BackendOptions) -> Result<()> {
+    if opts.canonicalize {
+        module.canonicalize()?;
+    }
+    if opts.assign_indices {
+        let report = assign_indices(module)?;
+        eprint!("{}", report);
     }
-    if opts.max_ssa {
-        module.per_func_body(|body| body.convert_to_max_ssa(None));
+    if opts.split_funcs {
+        let split_options = SplitFuncOptions {
+            backend: backend.clone(),
+            ..SplitFuncOptions::default()
+        };
+        let created = split_funcs::run(module, &split_options)?;
+        debug!("Split off {} continuation functions", created.len());
     }
     Ok(())
 }
 
+fn apply_options(opts: &Options, module: &mut Module) -> Result<()> {
+    prepare_module(opts, module)?;
+    let level = match (opts.opt_level, opts.basic_opts) {
+        (Some(level), _) => Some(level),
+        (None, true) => Some(OptLevel::O2),
+        (None, false) => None,
+    };
+    if let Some(level) = level {
+        let opt_options = OptOptions {
+            effects: Some(std::sync::Arc::new(module.effect_summaries())),
+            ..OptOptions::level(level)
+        };
+        module.optimize(&opt_options);
+    }
+    module.convert_to_max_ssa(opts.max_ssa);
+    finish_module(opts, module, &backend_options(opts))
+}
+
+/// Parse a constant of type `ty`: an integer, or for floats, a
+/// decimal number.
+fn parse_const(ty: Type, text: &str) -> Result<ConstVal> {
+    let int = || -> Result<u64> {
+        match text.strip_prefix('-') {
+            Some(abs) => Ok(abs.parse::<u64>()?.wrapping_neg()),
+            None => Ok(text.parse::<u64>()?),
+        }
+    };
+    Ok(match ty {
+        Type::I32 => ConstVal::I32(int()? as u32),
+        Type::I64 => ConstVal::I64(int()?),
+        Type::F32 => ConstVal::F32(text.parse::<f32>()?.to_bits()),
+        Type::F64 => ConstVal::F64(text.parse::<f64>()?.to_bits()),
+        _ => anyhow::bail!("Cannot define a value of type {}", ty),
+    })
+}
+
+/// A function's index, name, content hash, and printed IR, as of one
+/// run in watch mode.
+type FuncSnapshot = (Func, String, u64, String);
+
+fn snapshot(module: &Module) -> Vec<FuncSnapshot> {
+    module
+        .funcs
+        .entries()
+        .map(|(func, decl)| {
+            let ir = match decl.body() {
+                Some(body) => body.display("", Some(module)).to_string(),
+                None => String::new(),
+            };
+            (func, decl.name().to_owned(), decl.content_hash(), ir)
+        })
+        .collect()
+}
+
+/// Run `run` on the contents of `input`, and again each time the file
+/// changes, printing the functions whose content hash differs from
+/// the previous run (by index, as `diff` compares them). Errors are
+/// printed and do not end the loop, since the file may be caught
+/// half-written.
+fn watch_input(
+    input: &Path,
+    mut run: impl FnMut(&[u8]) -> Result<Vec<FuncSnapshot>>,
+) -> Result<()> {
+    let stamp = || {
+        let metadata = std::fs::metadata(input).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+    let mut last_stamp = None;
+    let mut last: Vec<FuncSnapshot> = vec![];
+    loop {
+        let current = stamp();
+        if current.is_none() || current == last_stamp {
+            std::thread::sleep(Duration::from_millis(250));
+            continue;
+        }
+        last_stamp = current;
+        let funcs = match std::fs::read(input)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| run(&bytes))
+        {
+            Ok(funcs) => funcs,
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                continue;
+            }
+        };
+        let mut changed = 0;
+        for (func, name, hash, ir) in &funcs {
+            let what = match last.get(func.index()) {
+                Some((_, _, old_hash, _)) if old_hash == hash => continue,
+                Some(_) => "changed",
+                None => "added",
+            };
+            println!("{}: {} \"{}\"", what, func, name);
+            print!("{}", ir);
+            changed += 1;
+        }
+        for (func, name, ..) in last.iter().skip(funcs.len()) {
+            println!("removed: {} \"{}\"", func, name);
+            changed += 1;
+        }
+        if changed == 0 {
+            println!("no functions changed");
+        }
+        last = funcs;
+        eprintln!("watching {} for changes", input.display());
+    }
+}
+
 fn main() -> Result<()> {
     let opts = Options::from_args();
 
@@ -77,9 +929,22 @@ fn main() -> Result<()> {
 
     let mut options = FrontendOptions::default();
     options.debug = opts.debug_info;
+    options.lenient = opts.lenient;
+    options.preallocate = opts.preallocate;
+    options.wasm_offsets = opts.wasm_offsets;
+    if let Some(mode) = opts.unreachable_code {
+        options.unreachable_code = mode;
+    }
 
     match &opts.command {
-        Command::PrintIR { wasm } => {
+        Command::PrintIR { wasm, watch: true } => {
+            watch_input(wasm, |bytes| {
+                let mut module = Module::from_wasm_bytes(bytes, &options)?;
+                apply_options(&opts, &mut module)?;
+                Ok(snapshot(&module))
+            })?;
+        }
+        Command::PrintIR { wasm, watch: false } => {
             let bytes = std::fs::read(wasm)?;
             debug!("Loaded {} bytes of Wasm data", bytes.len());
             let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
@@ -99,14 +964,618 @@ fn main() -> Result<()> {
                     .display_verbose("", Some(&module))
             );
         }
+        Command::DisasmFunc { wasm, func } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            let body = module
+                .funcs
+                .get(Func::new(*func))
+                .and_then(|decl| decl.body())
+                .ok_or_else(|| anyhow::anyhow!("No function body {}", func))?;
+            let (compiled, info, body) = body.compile_with_emit_info(&backend_options(&opts))?;
+            let disasm = Disasm::new(&compiled.into_raw_body(), &info)?;
+            println!(
+                "{}",
+                body.display_with_decorator("", Some(&module), &disasm)
+            );
+            if !disasm.unattributed.is_empty() {
+                println!("control and local traffic:");
+                for line in &disasm.unattributed {
+                    println!("{}", line);
+                }
+            }
+        }
+        Command::ExtractFunc { wasm, func, output } => {
+            let bytes = std::fs::read(wasm)?;
+            let module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let mut extracted = extract_func(&module, Func::new(*func))?;
+            apply_options(&opts, &mut extracted)?;
+            let produced = compile(&opts, &extracted, &backend_options(&opts))?;
+            println!(
+                "extracted {} of {} functions, {} bytes",
+                extracted.funcs.len(),
+                module.funcs.len(),
+                produced.len()
+            );
+            std::fs::write(output, &produced[..])?;
+        }
+        Command::CheckCompat {
+            provider,
+            consumer,
+            modules,
+        } => {
+            let provider_bytes = std::fs::read(provider)?;
+            let consumer_bytes = std::fs::read(consumer)?;
+            let provider = Module::from_wasm_bytes(&provider_bytes[..], &options)?;
+            let consumer = Module::from_wasm_bytes(&consumer_bytes[..], &options)?;
+            let mismatches = check_compat(&provider, &consumer)
+                .into_iter()
+                .filter(|mismatch| modules.is_empty() || modules.contains(&mismatch.module))
+                .collect::<Vec<_>>();
+            for mismatch in &mismatches {
+                println!("{}", mismatch);
+            }
+            if !mismatches.is_empty() {
+                anyhow::bail!("{} import(s) not satisfied", mismatches.len());
+            }
+        }
+        Command::Merge {
+            a,
+            b,
+            output,
+            resolve_imports,
+        } => {
+            let a_bytes = std::fs::read(a)?;
+            let b_bytes = std::fs::read(b)?;
+            let a_module = Module::from_wasm_bytes(&a_bytes[..], &options)?;
+            let b_module = Module::from_wasm_bytes(&b_bytes[..], &options)?;
+            let stem = |path: &PathBuf| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            };
+            let merge_options = MergeOptions {
+                names: [stem(a), stem(b)],
+                resolve_imports: *resolve_imports,
+            };
+            let (mut merged, manifest) = merge(&a_module, &b_module, &merge_options)?;
+            apply_options(&opts, &mut merged)?;
+            let produced = compile(&opts, &merged, &backend_options(&opts))?;
+            std::fs::write(output, &produced[..])?;
+            print!("{}", manifest);
+        }
+        Command::Split {
+            wasm,
+            keep,
+            output,
+            rest,
+            primary_name,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let split_options = SplitOptions {
+                keep: keep
+                    .iter()
+                    .map(|pattern| FuncMatcher::Name(pattern.clone()))
+                    .collect(),
+                primary_name: primary_name.clone(),
+            };
+            let (mut primary, mut secondary, manifest) = split(&module, &split_options)?;
+            for (module, path) in [(&mut primary, output), (&mut secondary, rest)] {
+                apply_options(&opts, module)?;
+                let produced = compile(&opts, module, &backend_options(&opts))?;
+                std::fs::write(path, &produced[..])?;
+            }
+            print!("{}", manifest);
+        }
+        Command::Preinit {
+            wasm,
+            invoke,
+            output,
+            fuel,
+            keep_init,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let preinit_options = PreinitOptions {
+                fuel: *fuel,
+                keep_init_export: *keep_init,
+            };
+            preinit(&mut module, invoke, &preinit_options)?;
+            apply_options(&opts, &mut module)?;
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
+            std::fs::write(output, &produced[..])?;
+        }
+        Command::Specialize {
+            wasm,
+            define,
+            output,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let mut specialize_options = SpecializeOptions::default();
+            for define in define {
+                let Some((name, value)) = define.split_once('=') else {
+                    anyhow::bail!("Expected name=value, not {:?}", define);
+                };
+                let ty = module
+                    .exports
+                    .iter()
+                    .find_map(|export| match export.kind {
+                        ExportKind::Global(global) if export.name == name => {
+                            Some(module.globals[global].ty)
+                        }
+                        _ => None,
+                    })
+                    .ok_or_else(|| anyhow::anyhow!("No global is exported as {}", name))?;
+                specialize_options
+                    .defines
+                    .insert(name.to_owned(), parse_const(ty, value)?);
+            }
+            let report = module.specialize(&specialize_options)?;
+            println!(
+                "{} branches folded, {} functions removed",
+                report.folded_branches, report.removed_funcs
+            );
+            apply_options(&opts, &mut module)?;
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
+            std::fs::write(output, &produced[..])?;
+        }
+        Command::Profile {
+            wasm,
+            invoke,
+            output,
+            fuel,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let profile = collect_profile(&mut module, invoke, *fuel)?;
+            let blocks: usize = profile.funcs.values().map(|f| f.blocks.len()).sum();
+            println!(
+                "{} functions and {} blocks ran",
+                profile.funcs.len(),
+                blocks
+            );
+            std::fs::write(output, profile.to_json())?;
+        }
+        Command::HeapProfile {
+            wasm,
+            invoke,
+            fuel,
+            heap,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let stats = instrument::heap_stats(&mut module, &heap.options(), invoke, *fuel)?;
+            print!("{}", stats);
+        }
+        Command::Pgo {
+            wasm,
+            profile,
+            output,
+            no_inline,
+            inline_threshold,
+            no_branch_hints,
+            no_layout,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let profile = Profile::from_json(&std::fs::read_to_string(profile)?)?;
+            let pgo_options = PgoOptions {
+                inline: !no_inline,
+                inline_threshold: *inline_threshold,
+                branch_hints: !no_branch_hints,
+                layout: !no_layout,
+                ..PgoOptions::default()
+            };
+            let report = pgo(&mut module, &profile, &pgo_options)?;
+            apply_options(&opts, &mut module)?;
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
+            std::fs::write(output, &produced[..])?;
+            print!("{}", report);
+        }
+        Command::ReorderFuncs {
+            wasm,
+            order,
+            profile,
+            output,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let profile = match profile {
+                Some(path) => Some(Profile::from_json(&std::fs::read_to_string(path)?)?),
+                None => None,
+            };
+            let moved = reorder_funcs(&mut module, *order, profile.as_ref())?;
+            apply_options(&opts, &mut module)?;
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
+            std::fs::write(output, &produced[..])?;
+            println!("{} functions moved", moved);
+        }
         Command::RoundTrip { input, output } => {
             let bytes = std::fs::read(input)?;
             debug!("Loaded {} bytes of Wasm data", bytes.len());
             let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
             apply_options(&opts, &mut module)?;
-            let produced = module.to_wasm_bytes()?;
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
             std::fs::write(output, &produced[..])?;
         }
+        Command::Optimize {
+            input,
+            output,
+            passes,
+            no_preserve_traps,
+            switch_lowering,
+            no_schedule,
+            max_remat_size,
+            validate,
+            watch,
+        } => {
+            let optimize = |bytes: &[u8]| -> Result<Vec<FuncSnapshot>> {
+                let start = Instant::now();
+                let mut module = Module::from_wasm_bytes(bytes, &options)?;
+                prepare_module(&opts, &mut module)?;
+                let mut timings = vec![("parse".to_owned(), start.elapsed())];
+
+                let level = opts.opt_level.unwrap_or(OptLevel::O2);
+                let mut opt_options = OptOptions {
+                    effects: Some(std::sync::Arc::new(module.effect_summaries())),
+                    preserve_traps: !no_preserve_traps,
+                    ..OptOptions::level(level)
+                };
+                if let Some(policy) = switch_lowering {
+                    opt_options.switch_lowering = *policy;
+                }
+                let mut pipeline = match passes {
+                    Some(passes) => passes.clone(),
+                    None => opt_options.pipeline(),
+                };
+                if opts.max_ssa {
+                    pipeline.push(Pass::MaxSsa);
+                }
+                for pass in pipeline {
+                    let start = Instant::now();
+                    module.per_func_body_with_overrides(|body, over| {
+                        let skip = over.is_some_and(|over| over.no_opt);
+                        let options = over
+                            .and_then(|over| over.opt_options.as_ref())
+                            .unwrap_or(&opt_options);
+                        if !skip {
+                            pass.run(body, options);
+                        }
+                    });
+                    timings.push((pass.name().to_owned(), start.elapsed()));
+                }
+
+                let backend_options = BackendOptions {
+                    schedule: !no_schedule,
+                    max_remat_size: max_remat_size
+                        .unwrap_or(BackendOptions::default().max_remat_size),
+                    ..backend_options(&opts)
+                };
+                let start = Instant::now();
+                finish_module(&opts, &mut module, &backend_options)?;
+                timings.push(("finish".to_owned(), start.elapsed()));
+                let start = Instant::now();
+                let produced = compile(&opts, &module, &backend_options)?;
+                timings.push(("compile".to_owned(), start.elapsed()));
+                if *validate {
+                    waffle::wasmparser::Validator::new().validate_all(&produced)?;
+                }
+                std::fs::write(output, &produced[..])?;
+
+                let (before, after) = (bytes.len() as f64, produced.len() as f64);
+                println!(
+                    "size: {} -> {} bytes ({:+.1}%)",
+                    bytes.len(),
+                    produced.len(),
+                    (after - before) * 100.0 / before.max(1.0)
+                );
+                for (name, time) in timings {
+                    println!("{:>14}: {:>8.3} ms", name, time.as_secs_f64() * 1000.0);
+                }
+                Ok(match watch {
+                    true => snapshot(&module),
+                    false => vec![],
+                })
+            };
+            match watch {
+                true => watch_input(input, optimize)?,
+                false => {
+                    optimize(&std::fs::read(input)?)?;
+                }
+            }
+        }
+        Command::Instrument { kind } => {
+            let io = match kind {
+                Instrumentation::Coverage { io, .. }
+                | Instrumentation::Gas { io, .. }
+                | Instrumentation::Trace { io, .. }
+                | Instrumentation::Memcheck { io, .. }
+                | Instrumentation::Heap { io, .. } => io,
+            };
+            let bytes = std::fs::read(&io.input)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            match kind {
+                Instrumentation::Coverage { memory, base, .. } => {
+                    let coverage_options = CoverageOptions {
+                        memory: Memory::new(*memory),
+                        base: *base,
+                    };
+                    let counters = instrument::coverage(&mut module, &coverage_options)?;
+                    println!("counter\taddress\tfunc\tblock");
+                    for (i, (func, block)) in counters.into_iter().enumerate() {
+                        let address = *base as usize + 4 * i;
+                        println!("{}\t{:#x}\t{}\t{}", i, address, func, block);
+                    }
+                }
+                Instrumentation::Gas { import, cost, .. } => {
+                    let gas_options = GasOptions {
+                        import: (import.0.clone(), import.1.clone()),
+                        cost_model: match cost {
+                            CostTable::Latency => std::sync::Arc::new(waffle::DefaultCostModel),
+                            CostTable::Size => std::sync::Arc::new(SizeCostModel),
+                        },
+                    };
+                    instrument::gas(&mut module, &gas_options)?;
+                }
+                Instrumentation::Trace { import, .. } => {
+                    let trace_options = TraceOptions {
+                        import: (import.0.clone(), import.1.clone()),
+                    };
+                    instrument::trace(&mut module, &trace_options)?;
+                }
+                Instrumentation::Memcheck { import, .. } => {
+                    let memcheck_options = MemcheckOptions {
+                        import: (import.0.clone(), import.1.clone()),
+                    };
+                    instrument::memcheck(&mut module, &memcheck_options)?;
+                }
+                Instrumentation::Heap { heap, .. } => {
+                    instrument::heap(&mut module, &heap.options())?;
+                }
+            }
+            let produced = compile(&opts, &module, &backend_options(&opts))?;
+            std::fs::write(&io.output, &produced[..])?;
+        }
+        Command::Diff { old, new } => {
+            let old_bytes = std::fs::read(old)?;
+            let new_bytes = std::fs::read(new)?;
+            let mut old = Module::from_wasm_bytes(&old_bytes[..], &options)?;
+            let mut new = Module::from_wasm_bytes(&new_bytes[..], &options)?;
+            apply_options(&opts, &mut old)?;
+            apply_options(&opts, &mut new)?;
+            if old.fingerprint() == new.fingerprint() {
+                println!("modules are identical");
+                return Ok(());
+            }
+            let hashes = |module: &Module| {
+                module
+                    .funcs
+                    .entries()
+                    .map(|(func, decl)| (func, decl.name().to_owned(), decl.content_hash()))
+                    .collect::<Vec<_>>()
+            };
+            let old_hashes = hashes(&old);
+            let new_hashes = hashes(&new);
+            for (func, name, hash) in &new_hashes {
+                match old_hashes.get(func.index()) {
+                    Some((_, _, old_hash)) if old_hash == hash => {}
+                    Some(_) => match old_hashes.iter().find(|(_, _, h)| h == hash) {
+                        Some((old_func, ..)) => {
+                            println!("moved:   {} \"{}\" (was {})", func, name, old_func)
+                        }
+                        None => println!("changed: {} \"{}\"", func, name),
+                    },
+                    None => println!("added:   {} \"{}\"", func, name),
+                }
+            }
+            for (func, name, _) in old_hashes.iter().skip(new_hashes.len()) {
+                println!("removed: {} \"{}\"", func, name);
+            }
+        }
+        Command::Stats { wasm, opcodes } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            if *opcodes {
+                let histogram = module.opcode_histogram()?;
+                let total = histogram.total();
+                println!("opcode\tcount\t%");
+                for (name, count) in histogram.by_frequency() {
+                    println!(
+                        "{}\t{}\t{:.2}",
+                        name,
+                        count,
+                        100.0 * count as f64 / total as f64
+                    );
+                }
+                println!("total\t{}", total);
+                return Ok(());
+            }
+            println!("func\tblocks\tbr\tbr_if\treturn\tunreach\tmax_if\tmax_stack\tlocals");
+            let mut tables = vec![];
+            for (func, decl) in module.funcs.entries() {
+                let body = match decl.body() {
+                    Some(body) => body,
+                    None => continue,
+                };
+                let stats = TerminatorStats::compute(body);
+                let frame = body.frame_info()?;
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    func,
+                    body.blocks.len(),
+                    stats.branches,
+                    stats.cond_branches,
+                    stats.returns,
+                    stats.unreachables,
+                    frame.max_if_nesting,
+                    frame.max_stack,
+                    frame.locals
+                );
+                tables.extend(stats.tables.into_iter().map(|table| (func, table)));
+            }
+            if !tables.is_empty() {
+                println!();
+                println!("func\tblock\tentries\ttargets\tclusters\tdensity%");
+                for (func, table) in tables {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        func,
+                        table.block,
+                        table.entries,
+                        table.distinct_targets,
+                        table.clusters,
+                        table.density_percent()
+                    );
+                }
+            }
+        }
+        Command::Strings { wasm, min_len } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            module.expand_all_funcs()?;
+            let string_options = StringOptions {
+                min_len: *min_len,
+                ..StringOptions::default()
+            };
+            println!("memory\taddr\tfuncs\tdata_refs\ttext");
+            for string in strings::scan(&module, &string_options).strings {
+                let funcs = string
+                    .funcs
+                    .iter()
+                    .map(|func| func.to_string())
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}\t{:#x}\t{}\t{}\t{:?}",
+                    string.memory,
+                    string.range.start,
+                    funcs.join(","),
+                    string.data_refs.len(),
+                    string.text
+                );
+            }
+        }
+        Command::Features { wasm } => {
+            let bytes = std::fs::read(wasm)?;
+            let module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            let features = module.detect_features()?;
+            if features.is_empty() {
+                println!("mvp");
+            }
+            for feature in features.iter() {
+                println!("{}", feature);
+            }
+        }
+        Command::HostSurface { wasm } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            module.expand_all_funcs()?;
+            print!("{}", module.host_surface().to_json());
+        }
+        Command::Equiv {
+            wasm,
+            a,
+            b,
+            inputs,
+            fuel,
+            seed,
+        } => {
+            let bytes = std::fs::read(wasm)?;
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            module.expand_all_funcs()?;
+            let (a, b) = (Func::new(*a), Func::new(*b));
+            let (body_a, body_b) = match (module.funcs[a].body(), module.funcs[b].body()) {
+                (Some(body_a), Some(body_b)) => (body_a, body_b),
+                _ => anyhow::bail!("{} and {} must both have bodies", a, b),
+            };
+            let budget = Budget {
+                inputs: *inputs,
+                fuel: *fuel,
+                seed: *seed,
+            };
+            let result = equiv::check(&module, body_a, body_b, budget)?;
+            println!("{}", result);
+            if let Equivalence::Counterexample { .. } = result {
+                anyhow::bail!("{} and {} differ", a, b);
+            }
+        }
+        Command::Gen {
+            seed,
+            output,
+            funcs,
+            no_floats,
+            no_memory,
+            no_calls,
+            loops,
+        } => {
+            let gen_options = GenOptions {
+                funcs: *funcs,
+                floats: !no_floats,
+                memory: !no_memory,
+                calls: !no_calls,
+                loops: *loops,
+                ..GenOptions::default()
+            };
+            let mut module = testgen::gen_module(*seed, &gen_options);
+            apply_options(&opts, &mut module)?;
+            println!("{}", module.display());
+            for (func, decl) in module.funcs.entries() {
+                if let Some(body) = decl.body() {
+                    println!("{}:\n{}", func, body.display_verbose("  ", Some(&module)));
+                }
+            }
+            if let Some(output) = output {
+                let produced = compile(&opts, &module, &backend_options(&opts))?;
+                std::fs::write(output, &produced[..])?;
+            }
+        }
+        #[cfg(feature = "bench")]
+        Command::Bench {
+            wasm,
+            corpus,
+            iters,
+            save,
+            baseline,
+            threshold,
+        } => {
+            use waffle::bench;
+            use waffle::passes::pipeline::Pipeline;
+
+            let mut modules = bench::corpus(corpus.as_deref())?;
+            for path in wasm {
+                let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                modules.push((name, std::fs::read(path)?));
+            }
+            let pipeline =
+                Pipeline::from(OptOptions::level(opts.opt_level.unwrap_or(OptLevel::O2)));
+            let mut results = bench::BenchResults::new();
+            for (name, bytes) in modules {
+                let times = bench::measure(&bytes[..], &options, &pipeline, *iters)?;
+                println!("{} ({} bytes):", name, bytes.len());
+                for (phase, time) in times.phases() {
+                    println!("{:>14}: {:>8.3} ms", phase, time.as_secs_f64() * 1000.0);
+                }
+                results.insert(name, times);
+            }
+            if let Some(save) = save {
+                std::fs::write(save, bench::results_to_text(&results))?;
+            }
+            if let Some(baseline) = baseline {
+                let baseline = std::fs::read_to_string(baseline)?;
+                let regressions = bench::compare(&results, &baseline, *threshold)?;
+                for regression in &regressions {
+                    println!("regression: {}", regression);
+                }
+                if !regressions.is_empty() {
+                    anyhow::bail!("{} phase(s) regressed", regressions.len());
+                }
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,214 @@
+//! Automatic bisection of optimizer miscompiles.
+//!
+//! Given a module, a pipeline of passes, and an oracle that says
+//! whether a compiled module behaves correctly, `find_culprit()`
+//! finds the first pass whose output fails the oracle, and then the
+//! function whose optimization by that pass makes it fail. The
+//! oracle is typically a test harness run on the output; each step
+//! recompiles the module from its original bytes, so an oracle may
+//! be called a logarithmic number of times in the pipeline length
+//! and the function count.
+
+use crate::ir::{Func, FuncDecl, Module};
+use crate::passes::pipeline::{Pass, Pipeline};
+use crate::FrontendOptions;
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// The pass, and if it could be narrowed down, the function, that
+/// breaks a module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Culprit {
+    /// The position of the pass in the pipeline.
+    pub index: usize,
+    /// The pass.
+    pub pass: Pass,
+    /// The function whose optimization by the pass alone breaks the
+    /// module, or `None` if the failure needs it to optimize several
+    /// functions.
+    pub func: Option<Func>,
+    /// The name of `func`.
+    pub name: Option<String>,
+}
+
+/// Compile `module_bytes` after running the first `count` passes of
+/// `pipeline`, the last of them only on `last_funcs` if given.
+fn build(
+    module_bytes: &[u8],
+    pipeline: &Pipeline,
+    count: usize,
+    last_funcs: Option<&BTreeSet<Func>>,
+) -> Result<Vec<u8>> {
+    let mut module = Module::from_wasm_bytes(module_bytes, &FrontendOptions::default())?;
+    module.expand_all_funcs()?;
+    for (func, decl) in module.funcs.entries_mut() {
+        let body = match decl {
+            FuncDecl::Body(_, _, body) => Arc::make_mut(body),
+            _ => continue,
+        };
+        for (i, pass) in pipeline.passes[..count].iter().enumerate() {
+            let last = i + 1 == count;
+            if last && last_funcs.is_some_and(|funcs| !funcs.contains(&func)) {
+                continue;
+            }
+            pass.run(body, &pipeline.options);
+        }
+    }
+    module.to_wasm_bytes()
+}
+
+/// Find the pass, and function, in `pipeline` that makes the module
+/// in `module_bytes` fail `oracle`, which returns whether a compiled
+/// module is correct. A module that fails to compile counts as
+/// incorrect. Returns `None` if the fully optimized module passes,
+/// and an error if the unoptimized one does not.
+///
+/// The search assumes that once a prefix of the pipeline breaks the
+/// module, every longer prefix does too.
+pub fn find_culprit<O: FnMut(&[u8]) -> bool>(
+    module_bytes: &[u8],
+    pipeline: &Pipeline,
+    mut oracle: O,
+) -> Result<Option<Culprit>> {
+    let mut passes = |count: usize, last_funcs: Option<&BTreeSet<Func>>| match build(
+        module_bytes,
+        pipeline,
+        count,
+        last_funcs,
+    ) {
+        Ok(bytes) => oracle(&bytes[..]),
+        Err(e) => {
+            log::debug!("bisect: {} passes fail to compile: {}", count, e);
+            false
+        }
+    };
+
+    let n = pipeline.passes.len();
+    if !passes(0, None) {
+        bail!("Module fails the oracle without optimization");
+    }
+    if passes(n, None) {
+        return Ok(None);
+    }
+
+    // Find the shortest failing prefix: `good` passes, `bad` fails.
+    let (mut good, mut bad) = (0, n);
+    while bad - good > 1 {
+        let mid = (good + bad) / 2;
+        if passes(mid, None) {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+    let index = bad - 1;
+    log::debug!(
+        "bisect: pass {} ({}) breaks the module",
+        index,
+        pipeline.passes[index]
+    );
+
+    // Narrow the functions the culprit pass must run on.
+    let module = Module::from_wasm_bytes(module_bytes, &FrontendOptions::default())?;
+    let mut candidates = module
+        .funcs
+        .entries()
+        .filter(|(_, decl)| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Body(..)))
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    while candidates.len() > 1 {
+        let (first, second) = candidates.split_at(candidates.len() / 2);
+        if !passes(bad, Some(&first.iter().copied().collect())) {
+            candidates = first.to_vec();
+        } else if !passes(bad, Some(&second.iter().copied().collect())) {
+            candidates = second.to_vec();
+        } else {
+            // The failure needs functions from both halves.
+            break;
+        }
+    }
+    let func = match &candidates[..] {
+        &[func] => Some(func),
+        _ => None,
+    };
+    Ok(Some(Culprit {
+        index,
+        pass: pipeline.passes[index],
+        func,
+        name: func.map(|func| module.funcs[func].name().to_owned()),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+    use crate::ir::{Export, ExportKind, FunctionBody, SignatureData, Terminator, Type, ValueDef};
+    use crate::{Operator, OptOptions};
+
+    #[test]
+    fn finds_pass_and_function() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        for name in ["f", "g", "h"] {
+            let mut body = FunctionBody::new(&module, sig);
+            let one = body.add_op(
+                body.entry,
+                Operator::I32Const { value: 1 },
+                &[],
+                &[Type::I32],
+            );
+            let sum = body.add_op(body.entry, Operator::I32Add, &[one, one], &[Type::I32]);
+            body.set_terminator(body.entry, Terminator::Return { values: vec![sum] });
+            let func = module
+                .funcs
+                .push(FuncDecl::Body(sig, name.to_owned(), body.into()));
+            module.exports.push(Export {
+                name: name.to_owned(),
+                kind: ExportKind::Func(func),
+            });
+        }
+        let bytes = module.to_wasm_bytes().unwrap();
+
+        // Pretend that constant-folding `g` is a miscompile.
+        let g = Func::new(1);
+        let oracle = |bytes: &[u8]| {
+            let module = Module::from_wasm_bytes(bytes, &FrontendOptions::default()).unwrap();
+            let body = module.clone_and_expand_body(g).unwrap();
+            let adds = body
+                .values
+                .values()
+                .any(|def| matches!(def, ValueDef::Operator(Operator::I32Add, ..)));
+            adds
+        };
+        let pipeline = Pipeline {
+            passes: vec![
+                Pass::EmptyBlocks,
+                Pass::Switch,
+                Pass::BasicOpt,
+                Pass::EmptyBlocks,
+            ],
+            options: OptOptions::default(),
+        };
+        let culprit = find_culprit(&bytes, &pipeline, oracle).unwrap().unwrap();
+        assert_eq!(
+            culprit,
+            Culprit {
+                index: 2,
+                pass: Pass::BasicOpt,
+                func: Some(g),
+                name: Some("g".to_owned()),
+            }
+        );
+
+        let clean = Pipeline {
+            passes: vec![Pass::Switch],
+            ..pipeline
+        };
+        assert_eq!(find_culprit(&bytes, &clean, oracle).unwrap(), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Tracking whether analyses are up to date.
+//!
+//! An analysis computed from a function body goes stale when the body
+//! changes, and a pass that keeps using it afterward sees blocks and
+//! values that are no longer there. `Tracked` pairs an analysis with
+//! the generation of the body it was computed from (see
+//! `FunctionBody::generation()`), so that it can tell whether it is
+//! still valid and recompute itself if not.
+
+use super::{BlockOrder, CFGInfo};
+use crate::ir::FunctionBody;
+use crate::passes::memory_ssa::MemorySSA;
+use crate::passes::nullability::NullabilityAnalysis;
+use crate::passes::ranges::RangeAnalysis;
+
+/// An analysis that can be computed from a function body alone.
+pub trait Analysis: Sized {
+    fn compute(body: &FunctionBody) -> Self;
+
+    /// The generation of `body` on which the analysis depends:
+    /// `FunctionBody::generation()`, unless it depends only on the
+    /// CFG.
+    fn generation(body: &FunctionBody) -> u64 {
+        body.generation()
+    }
+}
+
+impl Analysis for CFGInfo {
+    fn compute(body: &FunctionBody) -> Self {
+        CFGInfo::new(body)
+    }
+}
+
+impl Analysis for BlockOrder {
+    fn compute(body: &FunctionBody) -> Self {
+        BlockOrder::compute(body)
+    }
+
+    fn generation(body: &FunctionBody) -> u64 {
+        body.cfg_generation()
+    }
+}
+
+impl Analysis for MemorySSA {
+    fn compute(body: &FunctionBody) -> Self {
+        MemorySSA::new(body, &CFGInfo::new(body))
+    }
+}
+
+impl Analysis for RangeAnalysis {
+    fn compute(body: &FunctionBody) -> Self {
+        RangeAnalysis::new(body, &CFGInfo::new(body))
+    }
+}
+
+impl Analysis for NullabilityAnalysis {
+    fn compute(body: &FunctionBody) -> Self {
+        NullabilityAnalysis::new(body, &CFGInfo::new(body))
+    }
+}
+
+/// An analysis with the generation of the body it was computed from.
+#[derive(Clone, Debug)]
+pub struct Tracked<A> {
+    generation: u64,
+    analysis: A,
+}
+
+impl<A: Analysis> Tracked<A> {
+    pub fn compute(body: &FunctionBody) -> Self {
+        Tracked {
+            generation: A::generation(body),
+            analysis: A::compute(body),
+        }
+    }
+
+    /// Whether the analysis still describes `body`. This is false for
+    /// any other body, except an unchanged clone.
+    pub fn is_valid(&self, body: &FunctionBody) -> bool {
+        self.generation == A::generation(body)
+    }
+
+    /// The analysis, if it still describes `body`.
+    pub fn get(&self, body: &FunctionBody) -> Option<&A> {
+        self.is_valid(body).then_some(&self.analysis)
+    }
+
+    /// The analysis, recomputed first if `body` has changed.
+    pub fn refresh(&mut self, body: &FunctionBody) -> &A {
+        if !self.is_valid(body) {
+            *self = Tracked::compute(body);
+        }
+        &self.analysis
+    }
+
+    /// The analysis, whether or not it is still valid.
+    pub fn into_inner(self) -> A {
+        self.analysis
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Module, SignatureData, Terminator, Type};
+    use crate::Operator;
+
+    #[test]
+    fn edits_invalidate_analyses() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        body.set_terminator(entry, Terminator::Return { values: vec![] });
+
+        let mut cfg = Tracked::<CFGInfo>::compute(&body);
+        let order = Tracked::<BlockOrder>::compute(&body);
+        assert!(cfg.is_valid(&body));
+        let clone = body.clone();
+        assert!(cfg.is_valid(&clone));
+        assert!(!cfg.is_valid(&FunctionBody::new(&module, sig)));
+
+        // A new instruction changes the body but not its CFG.
+        let value = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        assert!(!cfg.is_valid(&body));
+        assert!(cfg.is_valid(&clone));
+        assert!(order.is_valid(&body));
+        assert_eq!(cfg.refresh(&body).def_block[value], entry);
+        assert!(cfg.get(&body).is_some());
+
+        // So does a direct edit, once marked.
+        body.blocks[entry].insts.clear();
+        body.mark_changed();
+        assert!(cfg.get(&body).is_none());
+        assert!(!order.is_valid(&body));
+    }
+}
@@ -8,11 +8,47 @@ use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::ir::{Block, FunctionBody, Terminator, Value, ValueDef};
 use smallvec::SmallVec;
 
+pub mod analysis;
 pub mod domtree;
 pub mod postorder;
 
+pub use analysis::{Analysis, Tracked};
+
 declare_entity!(RPOIndex, "rpo");
 
+/// The reachable blocks of a function body in reverse postorder (see
+/// `FunctionBody::rpo()`), and the position of each.
+/// `FunctionBody::compute_rpo()` caches one on the body.
+#[derive(Clone, Debug, Default)]
+pub struct BlockOrder {
+    pub rpo: EntityVec<RPOIndex, Block>,
+    /// Position of each block in `rpo`, if reachable.
+    pub rpo_pos: PerEntity<Block, Option<RPOIndex>>,
+}
+
+impl BlockOrder {
+    /// Compute the order from `body`'s current edges.
+    pub fn compute(body: &FunctionBody) -> BlockOrder {
+        let mut rpo = postorder::calculate(body.entry, |block| &body.blocks[block].succs[..]);
+        rpo.reverse();
+        let rpo = EntityVec::from(rpo);
+        let mut rpo_pos = PerEntity::default();
+        for (pos, &block) in rpo.entries() {
+            rpo_pos[block] = Some(pos);
+        }
+        BlockOrder { rpo, rpo_pos }
+    }
+
+    /// The reachable blocks in postorder.
+    pub fn postorder(&self) -> impl Iterator<Item = Block> + '_ {
+        self.rpo.values().rev().copied()
+    }
+
+    pub fn is_reachable(&self, block: Block) -> bool {
+        self.rpo_pos[block].is_some()
+    }
+}
+
 /// Auxiliary analyses of the control-flow graph.
 #[derive(Clone, Debug)]
 pub struct CFGInfo {
@@ -20,7 +56,8 @@ pub struct CFGInfo {
     pub entry: Block,
     /// Blocks that end in return.
     pub return_blocks: Vec<Block>,
-    /// Reverse-postorder traversal of blocks.
+    /// Reverse-postorder traversal of blocks; see
+    /// `FunctionBody::rpo()` for the order.
     pub rpo: EntityVec<RPOIndex, Block>,
     /// Position of each block in RPO, if reachable.
     pub rpo_pos: PerEntity<Block, Option<RPOIndex>>,
@@ -77,7 +114,8 @@ impl CFGInfo {
             });
         }
 
-        let postorder = postorder::calculate(f.entry, |block| &f.blocks[block].succs[..]);
+        let order = f.compute_rpo();
+        let postorder = order.postorder().collect::<Vec<_>>();
 
         let domtree =
             domtree::calculate(|block| &f.blocks[block].preds[..], &postorder[..], f.entry);
@@ -110,13 +148,8 @@ impl CFGInfo {
             def_block[value] = def_block[underlying_value];
         }
 
-        let mut rpo = postorder;
-        rpo.reverse();
-        let rpo = EntityVec::from(rpo);
-        let mut rpo_pos = PerEntity::default();
-        for (rpo, &block) in rpo.entries() {
-            rpo_pos[block] = Some(rpo);
-        }
+        let rpo = order.rpo.clone();
+        let rpo_pos = order.rpo_pos.clone();
 
         CFGInfo {
             entry: f.entry,
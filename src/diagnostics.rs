@@ -0,0 +1,144 @@
+//! Diagnostics: warnings and notes from the frontend, passes, and
+//! backend, for embedders to show to their own users.
+//!
+//! Every diagnostic is also logged, at the level matching its
+//! severity, so that nothing is lost without a sink.
+
+use crate::ir::Func;
+use std::sync::{Arc, Mutex};
+
+/// How serious a diagnostic is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Something worth knowing that changes no behavior, such as the
+    /// cost of a transform.
+    Note,
+    /// Something dropped or left untransformed, which may matter.
+    Warning,
+    /// Something wrong that was recovered from.
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Note => write!(f, "note"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// One diagnostic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What reported it: "frontend", "backend", or a pass name.
+    pub source: &'static str,
+    /// The function it concerns, if any.
+    pub func: Option<Func>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}: ", self.severity, self.source)?;
+        if let Some(func) = self.func {
+            write!(f, "{}: ", func)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A receiver of diagnostics. Sinks may be called from several
+/// threads at once, as the backend compiles functions in parallel.
+pub trait DiagnosticSink: std::fmt::Debug + Send + Sync {
+    fn report(&self, diagnostic: &Diagnostic);
+}
+
+/// A sink that keeps every diagnostic, in the order reported.
+#[derive(Debug, Default)]
+pub struct CollectSink {
+    diagnostics: Mutex<Vec<Diagnostic>>,
+}
+
+impl CollectSink {
+    /// Take the diagnostics reported so far.
+    pub fn take(&self) -> Vec<Diagnostic> {
+        std::mem::take(&mut *self.diagnostics.lock().unwrap())
+    }
+}
+
+impl DiagnosticSink for CollectSink {
+    fn report(&self, diagnostic: &Diagnostic) {
+        self.diagnostics.lock().unwrap().push(diagnostic.clone());
+    }
+}
+
+/// Where to report diagnostics: an optional sink, shared by clones.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    sink: Option<Arc<dyn DiagnosticSink>>,
+}
+
+impl Diagnostics {
+    pub fn new(sink: Arc<dyn DiagnosticSink>) -> Diagnostics {
+        Diagnostics { sink: Some(sink) }
+    }
+
+    /// Log a diagnostic and pass it to the sink, if any.
+    pub fn report(
+        &self,
+        severity: Severity,
+        source: &'static str,
+        func: Option<Func>,
+        message: String,
+    ) {
+        let diagnostic = Diagnostic {
+            severity,
+            source,
+            func,
+            message,
+        };
+        match severity {
+            Severity::Note => log::debug!("{}", diagnostic),
+            Severity::Warning => log::warn!("{}", diagnostic),
+            Severity::Error => log::error!("{}", diagnostic),
+        }
+        if let Some(sink) = &self.sink {
+            sink.report(&diagnostic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FrontendOptions, Module};
+
+    #[test]
+    fn dropped_custom_section_is_reported() {
+        let mut module = wasm_encoder::Module::new();
+        module.section(&wasm_encoder::CustomSection {
+            name: "producers.extra".into(),
+            data: (&[1u8, 2, 3][..]).into(),
+        });
+        let bytes = module.finish();
+
+        let sink = Arc::new(CollectSink::default());
+        let options = FrontendOptions {
+            diagnostics: Diagnostics::new(sink.clone()),
+            ..FrontendOptions::default()
+        };
+        let module = Module::from_wasm_bytes(&bytes[..], &options).unwrap();
+        assert!(sink.take().is_empty());
+        let _ = module.without_orig_bytes();
+        let diagnostics = sink.take();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "warning: frontend: dropped custom section `producers.extra`"
+        );
+    }
+}
@@ -83,6 +83,12 @@ macro_rules! declare_entity {
 
 /// A vector that *defines* an entity index space, holding the data
 /// for each entity.
+///
+/// Indices are assigned in sequence by `push()`, so index order is
+/// creation order, and every iterator (`iter()`, `values()`,
+/// `entries()`, and their `_mut` forms) visits entities in that order.
+/// This is a guarantee that tools may rely on for reproducible
+/// output.
 #[derive(Clone, Debug)]
 pub struct EntityVec<Idx: EntityRef, T: Clone + Debug>(Vec<T>, PhantomData<Idx>);
 
@@ -111,22 +117,36 @@ impl<Idx: EntityRef, T: Clone + Debug> EntityVec<Idx, T> {
         self.0.len()
     }
 
-    /// Get an iterator over the index-space.
+    /// Reserve capacity for at least `additional` more entities.
+    pub fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+
+    /// Get the number of entities this entity space has room for
+    /// without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Get an iterator over the index-space, in index order.
     pub fn iter(&self) -> impl DoubleEndedIterator<Item = Idx> {
         (0..self.0.len()).map(|index| Idx::new(index))
     }
 
-    /// Get an iterator over (borrows of) entity values.
+    /// Get an iterator over (borrows of) entity values, in index
+    /// order.
     pub fn values(&self) -> impl DoubleEndedIterator<Item = &T> {
         self.0.iter()
     }
 
-    /// Get an iterator over (mutable borrows of) entity values.
+    /// Get an iterator over (mutable borrows of) entity values, in
+    /// index order.
     pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut T> {
         self.0.iter_mut()
     }
 
-    /// Get an iterator over index, borrow-of-entity tuples.
+    /// Get an iterator over index, borrow-of-entity tuples, in index
+    /// order.
     pub fn entries(&self) -> impl DoubleEndedIterator<Item = (Idx, &T)> {
         self.0
             .iter()
@@ -134,7 +154,8 @@ impl<Idx: EntityRef, T: Clone + Debug> EntityVec<Idx, T> {
             .map(|(index, t)| (Idx::new(index), t))
     }
 
-    /// Get an iterator over index, mutable-borrow-of-entity tuples.
+    /// Get an iterator over index, mutable-borrow-of-entity tuples,
+    /// in index order.
     pub fn entries_mut(&mut self) -> impl Iterator<Item = (Idx, &mut T)> {
         self.0
             .iter_mut()
@@ -200,9 +221,432 @@ impl<Idx: EntityRef, T: Clone + Debug + Default> IndexMut<Idx> for PerEntity<Idx
     }
 }
 
+impl<Idx: EntityRef, T: Clone + Debug + Default> PerEntity<Idx, T> {
+    /// Reserve capacity for state for the first `len` entities, so
+    /// that assigning it does not reallocate.
+    pub fn reserve(&mut self, len: usize) {
+        self.0.reserve(len.saturating_sub(self.0.len()));
+    }
+
+    /// Get the number of entities there is room for state for without
+    /// reallocating.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
 impl<Idx: EntityRef, T: Clone + Debug + Default + PartialEq> PartialEq for PerEntity<Idx, T> {
     fn eq(&self, other: &Self) -> bool {
         self.0 == other.0
     }
 }
 impl<Idx: EntityRef, T: Clone + Debug + Default + PartialEq + Eq> Eq for PerEntity<Idx, T> {}
+
+/// A set of entities in an index-space, stored as a dense bitset.
+#[derive(Clone, Debug)]
+pub struct EntitySet<Idx: EntityRef>(Vec<u64>, PhantomData<Idx>);
+
+// Equality and hashing ignore trailing zero words, which removals
+// leave behind: equal sets compare equal however they were built.
+impl<Idx: EntityRef> PartialEq for EntitySet<Idx> {
+    fn eq(&self, other: &Self) -> bool {
+        self.words() == other.words()
+    }
+}
+impl<Idx: EntityRef> Eq for EntitySet<Idx> {}
+
+impl<Idx: EntityRef> std::hash::Hash for EntitySet<Idx> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.words().hash(state);
+    }
+}
+
+impl<Idx: EntityRef> std::default::Default for EntitySet<Idx> {
+    fn default() -> Self {
+        Self(vec![], PhantomData)
+    }
+}
+
+impl<Idx: EntityRef> EntitySet<Idx> {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `idx` to the set, returning `true` if it was not already
+    /// present.
+    pub fn insert(&mut self, idx: Idx) -> bool {
+        let (word, bit) = (idx.index() / 64, 1u64 << (idx.index() % 64));
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+        let added = self.0[word] & bit == 0;
+        self.0[word] |= bit;
+        added
+    }
+
+    /// Remove `idx` from the set, returning `true` if it was present.
+    pub fn remove(&mut self, idx: Idx) -> bool {
+        let (word, bit) = (idx.index() / 64, 1u64 << (idx.index() % 64));
+        match self.0.get_mut(word) {
+            Some(w) => {
+                let present = *w & bit != 0;
+                *w &= !bit;
+                present
+            }
+            None => false,
+        }
+    }
+
+    /// Is `idx` in the set?
+    pub fn contains(&self, idx: Idx) -> bool {
+        let (word, bit) = (idx.index() / 64, 1u64 << (idx.index() % 64));
+        self.0.get(word).map(|w| w & bit != 0).unwrap_or(false)
+    }
+
+    /// Remove all entities from the set.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&w| w == 0)
+    }
+
+    /// Get the number of entities in the set.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Get an iterator over the entities in the set, in index order.
+    pub fn iter(&self) -> impl Iterator<Item = Idx> + '_ {
+        self.0.iter().enumerate().flat_map(|(word, &w)| {
+            (0..64)
+                .filter(move |bit| w & (1u64 << bit) != 0)
+                .map(move |bit| Idx::new(word * 64 + bit))
+        })
+    }
+
+    /// Add every entity in `other` to this set. Returns `true` if
+    /// this set changed.
+    pub fn union_with(&mut self, other: &Self) -> bool {
+        if other.0.len() > self.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        let mut changed = false;
+        for (w, &o) in self.0.iter_mut().zip(other.0.iter()) {
+            changed |= o & !*w != 0;
+            *w |= o;
+        }
+        changed
+    }
+
+    /// Remove every entity not in `other` from this set. Returns
+    /// `true` if this set changed.
+    pub fn intersect_with(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (i, w) in self.0.iter_mut().enumerate() {
+            let o = other.0.get(i).copied().unwrap_or(0);
+            changed |= *w & !o != 0;
+            *w &= o;
+        }
+        changed
+    }
+
+    /// Remove every entity in `other` from this set. Returns `true`
+    /// if this set changed.
+    pub fn difference_with(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (w, &o) in self.0.iter_mut().zip(other.0.iter()) {
+            changed |= *w & o != 0;
+            *w &= !o;
+        }
+        changed
+    }
+
+    /// The words of the bitset, without trailing zero words.
+    fn words(&self) -> &[u64] {
+        let len = self.0.iter().rposition(|&w| w != 0).map_or(0, |i| i + 1);
+        &self.0[..len]
+    }
+
+    /// Is every entity in this set also in `other`?
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .enumerate()
+            .all(|(i, &w)| w & !other.0.get(i).copied().unwrap_or(0) == 0)
+    }
+}
+
+impl<Idx: EntityRef> std::iter::FromIterator<Idx> for EntitySet<Idx> {
+    fn from_iter<I: IntoIterator<Item = Idx>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<Idx: EntityRef> std::iter::Extend<Idx> for EntitySet<Idx> {
+    fn extend<I: IntoIterator<Item = Idx>>(&mut self, iter: I) {
+        for idx in iter {
+            self.insert(idx);
+        }
+    }
+}
+
+/// A map from entities to values, stored densely, in which every
+/// entity not yet assigned maps to a default value. Like `PerEntity`,
+/// but the default may be any value, not only `V::default()`, and
+/// the assigned entries may be iterated.
+#[derive(Clone, Debug)]
+pub struct SecondaryMap<Idx: EntityRef, V: Clone> {
+    values: Vec<V>,
+    default: V,
+    _phantom: PhantomData<Idx>,
+}
+
+impl<Idx: EntityRef, V: Clone + Default> std::default::Default for SecondaryMap<Idx, V> {
+    fn default() -> Self {
+        Self::with_default(V::default())
+    }
+}
+
+impl<Idx: EntityRef, V: Clone> SecondaryMap<Idx, V> {
+    /// Create a map in which every entity maps to `default`.
+    pub fn with_default(default: V) -> Self {
+        Self {
+            values: vec![],
+            default,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Get the value for `idx`, which is the default if it was never
+    /// assigned.
+    pub fn get(&self, idx: Idx) -> &V {
+        self.values.get(idx.index()).unwrap_or(&self.default)
+    }
+
+    /// Reset every entity to the default value.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Get an iterator over index, value tuples for every entity up
+    /// to the highest one assigned. Unassigned entities in that range
+    /// yield the default.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = (Idx, &V)> {
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(index, v)| (Idx::new(index), v))
+    }
+
+    /// Get an iterator over index, mutable-value tuples, as for
+    /// `entries()`.
+    pub fn entries_mut(&mut self) -> impl DoubleEndedIterator<Item = (Idx, &mut V)> {
+        self.values
+            .iter_mut()
+            .enumerate()
+            .map(|(index, v)| (Idx::new(index), v))
+    }
+
+    /// Get an iterator over the values, as for `entries()`.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> {
+        self.values.iter()
+    }
+}
+
+impl<Idx: EntityRef, V: Clone> Index<Idx> for SecondaryMap<Idx, V> {
+    type Output = V;
+    fn index(&self, idx: Idx) -> &V {
+        self.get(idx)
+    }
+}
+
+impl<Idx: EntityRef, V: Clone> IndexMut<Idx> for SecondaryMap<Idx, V> {
+    fn index_mut(&mut self, idx: Idx) -> &mut V {
+        if idx.index() >= self.values.len() {
+            self.values.resize(idx.index() + 1, self.default.clone());
+        }
+        &mut self.values[idx.index()]
+    }
+}
+
+/// A map from a small number of entities in a large index-space to
+/// values. Lookup, insertion, and removal take constant time, and
+/// iteration and clearing take time proportional to the number of
+/// entries rather than the size of the index-space.
+#[derive(Clone, Debug)]
+pub struct SparseMap<Idx: EntityRef, V: Clone> {
+    /// The entries, in no particular order.
+    dense: Vec<(Idx, V)>,
+    /// For each entity, a position in `dense`, which is only
+    /// meaningful if the entry there names that entity.
+    sparse: Vec<u32>,
+}
+
+impl<Idx: EntityRef, V: Clone> std::default::Default for SparseMap<Idx, V> {
+    fn default() -> Self {
+        Self {
+            dense: vec![],
+            sparse: vec![],
+        }
+    }
+}
+
+impl<Idx: EntityRef, V: Clone> SparseMap<Idx, V> {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn position(&self, idx: Idx) -> Option<usize> {
+        let pos = *self.sparse.get(idx.index())? as usize;
+        match self.dense.get(pos) {
+            Some(&(key, _)) if key == idx => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// Get the value for `idx`, if any.
+    pub fn get(&self, idx: Idx) -> Option<&V> {
+        self.position(idx).map(|pos| &self.dense[pos].1)
+    }
+
+    /// Get a mutable borrow of the value for `idx`, if any.
+    pub fn get_mut(&mut self, idx: Idx) -> Option<&mut V> {
+        self.position(idx).map(move |pos| &mut self.dense[pos].1)
+    }
+
+    /// Does the map have a value for `idx`?
+    pub fn contains_key(&self, idx: Idx) -> bool {
+        self.position(idx).is_some()
+    }
+
+    /// Set the value for `idx`, returning the old value, if any.
+    pub fn insert(&mut self, idx: Idx, value: V) -> Option<V> {
+        if let Some(pos) = self.position(idx) {
+            return Some(std::mem::replace(&mut self.dense[pos].1, value));
+        }
+        if idx.index() >= self.sparse.len() {
+            self.sparse.resize(idx.index() + 1, 0);
+        }
+        self.sparse[idx.index()] = self.dense.len() as u32;
+        self.dense.push((idx, value));
+        None
+    }
+
+    /// Remove the value for `idx`, returning it, if any.
+    pub fn remove(&mut self, idx: Idx) -> Option<V> {
+        let pos = self.position(idx)?;
+        let (_, value) = self.dense.swap_remove(pos);
+        if let Some(&(moved, _)) = self.dense.get(pos) {
+            self.sparse[moved.index()] = pos as u32;
+        }
+        Some(value)
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    /// Is the map empty?
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    /// Get an iterator over the entries, in no particular order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (Idx, &V)> {
+        self.dense.iter().map(|(idx, v)| (*idx, v))
+    }
+
+    /// Get an iterator over the keys, in no particular order.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = Idx> + '_ {
+        self.dense.iter().map(|(idx, _)| *idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::Value;
+
+    #[test]
+    fn entity_set_algebra() {
+        let v = |i| Value::new(i);
+        let mut a: EntitySet<Value> = vec![v(1), v(70), v(200)].into_iter().collect();
+        let b: EntitySet<Value> = vec![v(70), v(3)].into_iter().collect();
+        assert_eq!(a.len(), 3);
+        assert!(a.contains(v(200)) && !a.contains(v(3)));
+        assert!(a.union_with(&b));
+        assert!(!a.union_with(&b));
+        assert!(b.is_subset(&a));
+        assert!(a.difference_with(&b));
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![v(1), v(200)]);
+        assert!(a.intersect_with(&b));
+        assert!(a.is_empty());
+    }
+
+    #[test]
+    fn entity_set_eq_ignores_capacity() {
+        let hash = |set: &EntitySet<Value>| {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            set.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut a = EntitySet::new();
+        a.insert(Value::new(100));
+        a.remove(Value::new(100));
+        assert!(a.is_empty());
+        assert_eq!(a, EntitySet::new());
+        assert_eq!(hash(&a), hash(&EntitySet::new()));
+
+        a.insert(Value::new(3));
+        let b: EntitySet<Value> = std::iter::once(Value::new(3)).collect();
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+    }
+
+    #[test]
+    fn entity_vec_iterates_in_creation_order() {
+        let mut vec: EntityVec<Value, char> = EntityVec::default();
+        let ids = "wasm".chars().map(|c| vec.push(c)).collect::<Vec<_>>();
+        assert_eq!(vec.iter().collect::<Vec<_>>(), ids);
+        assert_eq!(vec.values().collect::<String>(), "wasm");
+        let entries = vec.entries().map(|(id, &c)| (id, c)).collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            ids.iter().copied().zip("wasm".chars()).collect::<Vec<_>>()
+        );
+        for (id, c) in vec.entries_mut() {
+            *c = if id.index() % 2 == 0 { '-' } else { *c };
+        }
+        assert_eq!(vec.values().rev().collect::<String>(), "m-a-");
+    }
+
+    #[test]
+    fn sparse_map_remove() {
+        let v = |i| Value::new(i);
+        let mut map = SparseMap::new();
+        map.insert(v(1000), 'a');
+        map.insert(v(5), 'b');
+        map.insert(v(42), 'c');
+        assert_eq!(map.remove(v(1000)), Some('a'));
+        assert_eq!(map.get(v(42)), Some(&'c'));
+        assert_eq!(map.get(v(1000)), None);
+        assert_eq!(map.insert(v(5), 'd'), Some('b'));
+        assert_eq!(map.len(), 2);
+        map.clear();
+        assert!(!map.contains_key(v(5)));
+    }
+}
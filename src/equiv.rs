@@ -0,0 +1,423 @@
+//! Equivalence checking between two function bodies.
+//!
+//! Transforms that replace one body with another (deduplication,
+//! outlining, or any optimization) can be checked with `check()`. It
+//! first compares the bodies structurally after renumbering (see
+//! `canonicalize::renumber()`), which proves them equivalent if they
+//! match. Otherwise it runs both in the interpreter on the same
+//! inputs: every argument list, if there are few enough for the
+//! budget, or else boundary values and random ones. Agreement on every
+//! argument list is still not proof, as the state below is fixed.
+//!
+//! Both bodies run against the state of the same module, starting
+//! from its initial memories, tables, and globals each time, and calls
+//! to imports return zeroes. Two runs agree if they return the same
+//! values (any NaN matching any other) or both trap, and leave the
+//! same memories and globals and make the same import calls.
+
+use crate::entity::EntityRef;
+use crate::interp::{ConstVal, ImportCall, ImportMode, InterpContext, InterpResult};
+use crate::ir::{BlockOrigin, Func, FunctionBody, Module, Type};
+use crate::passes::canonicalize;
+use crate::testgen::Rng;
+use anyhow::{bail, Result};
+use std::hash::{Hash, Hasher};
+
+/// How much work `check()` may do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    /// The most inputs to run each body on.
+    pub inputs: usize,
+    /// The fuel for each run; see `InterpContext::fuel`.
+    pub fuel: u64,
+    /// The seed for random inputs.
+    pub seed: u64,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            inputs: 1000,
+            fuel: 100_000,
+            seed: 0,
+        }
+    }
+}
+
+/// The observable effect of one run of a body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// The returned values, or `None` if the body trapped.
+    pub results: Option<Vec<ConstVal>>,
+    /// The values of the globals afterward.
+    pub globals: Vec<ConstVal>,
+    /// A hash of the contents of the memories afterward.
+    pub memory_hash: u64,
+    /// The calls made to imported functions, in order.
+    pub imports: Vec<ImportCall>,
+}
+
+/// What `check()` found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Equivalence {
+    /// The bodies are identical after renumbering.
+    Structural,
+    /// The bodies agree on every possible argument list, of which
+    /// there are `inputs`. Only the arguments vary: globals, memories
+    /// and tables start in their initial state, and imports return
+    /// zeroes, so bodies that differ only for other states or import
+    /// results also agree.
+    AllArgs { inputs: usize },
+    /// The bodies agree on this many inputs, which is evidence but
+    /// not proof.
+    Sampled { inputs: usize },
+    /// The bodies disagree on `args`.
+    Counterexample {
+        args: Vec<ConstVal>,
+        a: Outcome,
+        b: Outcome,
+    },
+    /// Nothing could be concluded, for the given reason.
+    Unknown(String),
+}
+
+impl Equivalence {
+    /// Whether the bodies were proven equivalent in every state.
+    /// Only structural identity proves this; agreement on all
+    /// arguments (`AllArgs`) covers only the initial state.
+    pub fn is_proven(&self) -> bool {
+        matches!(self, Equivalence::Structural)
+    }
+}
+
+impl std::fmt::Display for Equivalence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Equivalence::Structural => write!(f, "equivalent: structurally identical"),
+            Equivalence::AllArgs { inputs } => write!(
+                f,
+                "equivalent from the initial state: agree on all {} inputs",
+                inputs
+            ),
+            Equivalence::Sampled { inputs } => {
+                write!(f, "probably equivalent: agree on {} inputs", inputs)
+            }
+            Equivalence::Counterexample { args, a, b } => {
+                writeln!(f, "not equivalent: differ on args {:?}", args)?;
+                writeln!(f, "  a: {:?}", a)?;
+                write!(f, "  b: {:?}", b)
+            }
+            Equivalence::Unknown(reason) => write!(f, "unknown: {}", reason),
+        }
+    }
+}
+
+/// Check whether bodies `a` and `b` are equivalent in the context of
+/// `module`, within `budget`. The bodies must have the same parameter
+/// and return types, and any functions they call must be expanded.
+pub fn check(
+    module: &Module,
+    a: &FunctionBody,
+    b: &FunctionBody,
+    budget: Budget,
+) -> Result<Equivalence> {
+    let params = param_types(a);
+    if params != param_types(b) || a.rets != b.rets {
+        bail!("Bodies have different signatures");
+    }
+
+    let (mut a_norm, mut b_norm) = (canonicalize::renumber(a), canonicalize::renumber(b));
+    // Where blocks came from does not affect what they do.
+    for block in a_norm.blocks.values_mut().chain(b_norm.blocks.values_mut()) {
+        block.origin = BlockOrigin::Unknown;
+    }
+    if a_norm.structural_hash() == b_norm.structural_hash()
+        && a_norm.display("", None).to_string() == b_norm.display("", None).to_string()
+    {
+        return Ok(Equivalence::Structural);
+    }
+
+    if let Some(ty) = params.iter().find(|ty| bits(**ty).is_none()) {
+        return Ok(Equivalence::Unknown(format!(
+            "cannot generate arguments of type {}",
+            ty
+        )));
+    }
+    let total_bits = params.iter().map(|&ty| bits(ty).unwrap()).sum::<u32>();
+    let exhaustive = total_bits < usize::BITS && (1usize << total_bits) <= budget.inputs;
+    let count = if exhaustive {
+        1usize << total_bits
+    } else {
+        budget.inputs
+    };
+
+    let initial = InterpContext::new(module)?;
+    let mut rng = Rng::new(budget.seed);
+    let mut agreed = 0;
+    for i in 0..count {
+        let args = if exhaustive {
+            enumerated_args(&params, i as u64)
+        } else if i == 0 {
+            params.iter().map(|&ty| const_of_bits(ty, 0)).collect()
+        } else {
+            params.iter().map(|&ty| random_arg(&mut rng, ty)).collect()
+        };
+        let (a_out, b_out) = match (
+            run(module, &initial, a, &args, budget.fuel),
+            run(module, &initial, b, &args, budget.fuel),
+        ) {
+            (Some(a_out), Some(b_out)) => (a_out, b_out),
+            // Out of fuel: this input tells us nothing.
+            _ => continue,
+        };
+        if a_out != b_out {
+            return Ok(Equivalence::Counterexample {
+                args,
+                a: a_out,
+                b: b_out,
+            });
+        }
+        agreed += 1;
+    }
+
+    Ok(if agreed == 0 {
+        Equivalence::Unknown("ran out of fuel on every input".to_owned())
+    } else if exhaustive && agreed == count {
+        Equivalence::AllArgs { inputs: agreed }
+    } else {
+        Equivalence::Sampled { inputs: agreed }
+    })
+}
+
+fn param_types(body: &FunctionBody) -> Vec<Type> {
+    body.blocks[body.entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect()
+}
+
+/// The width of a type the checker can generate values of.
+fn bits(ty: Type) -> Option<u32> {
+    match ty {
+        Type::I32 | Type::F32 => Some(32),
+        Type::I64 | Type::F64 => Some(64),
+        _ => None,
+    }
+}
+
+fn const_of_bits(ty: Type, bits: u64) -> ConstVal {
+    match ty {
+        Type::I32 => ConstVal::I32(bits as u32),
+        Type::I64 => ConstVal::I64(bits),
+        Type::F32 => ConstVal::F32(bits as u32),
+        Type::F64 => ConstVal::F64(bits),
+        _ => unreachable!(),
+    }
+}
+
+/// The `index`th input, taking each parameter's bits in turn from
+/// the low bits of `index`.
+fn enumerated_args(params: &[Type], mut index: u64) -> Vec<ConstVal> {
+    params
+        .iter()
+        .map(|&ty| {
+            let width = bits(ty).unwrap();
+            let value = if width == 64 {
+                index
+            } else {
+                index & ((1 << width) - 1)
+            };
+            index = index.checked_shr(width).unwrap_or(0);
+            const_of_bits(ty, value)
+        })
+        .collect()
+}
+
+/// A random argument, half the time a boundary value of its type.
+fn random_arg(rng: &mut Rng, ty: Type) -> ConstVal {
+    const I32_EDGES: &[u64] = &[0, 1, 2, 0x7fff_ffff, 0x8000_0000, 0xffff_ffff];
+    const I64_EDGES: &[u64] = &[0, 1, 2, i64::MAX as u64, i64::MIN as u64, u64::MAX];
+    // 0.0, -0.0, 1.0, -1.0, +inf, -inf, NaN.
+    const F32_EDGES: &[u64] = &[
+        0,
+        0x8000_0000,
+        0x3f80_0000,
+        0xbf80_0000,
+        0x7f80_0000,
+        0xff80_0000,
+        0x7fc0_0000,
+    ];
+    const F64_EDGES: &[u64] = &[
+        0,
+        0x8000_0000_0000_0000,
+        0x3ff0_0000_0000_0000,
+        0xbff0_0000_0000_0000,
+        0x7ff0_0000_0000_0000,
+        0xfff0_0000_0000_0000,
+        0x7ff8_0000_0000_0000,
+    ];
+    let edges = match ty {
+        Type::I32 => I32_EDGES,
+        Type::I64 => I64_EDGES,
+        Type::F32 => F32_EDGES,
+        Type::F64 => F64_EDGES,
+        _ => unreachable!(),
+    };
+    let bits = if rng.one_in(2) {
+        *rng.choose(edges)
+    } else {
+        rng.next_u64()
+    };
+    const_of_bits(ty, bits)
+}
+
+/// Replace any NaN with the canonical NaN, since Wasm does not
+/// determine NaN payloads.
+fn canonical_nan(value: ConstVal) -> ConstVal {
+    match value {
+        ConstVal::F32(bits) if f32::from_bits(bits).is_nan() => ConstVal::F32(0x7fc0_0000),
+        ConstVal::F64(bits) if f64::from_bits(bits).is_nan() => {
+            ConstVal::F64(0x7ff8_0000_0000_0000)
+        }
+        other => other,
+    }
+}
+
+/// Run `body` on `args` from the state in `initial`, or return `None`
+/// if it runs out of fuel.
+fn run(
+    module: &Module,
+    initial: &InterpContext,
+    body: &FunctionBody,
+    args: &[ConstVal],
+    fuel: u64,
+) -> Option<Outcome> {
+    let mut ctx = InterpContext {
+        memories: initial.memories.clone(),
+        tables: initial.tables.clone(),
+        globals: initial.globals.clone(),
+        fuel,
+        import_mode: ImportMode::Record {
+            defaults: Default::default(),
+        },
+        ..InterpContext::empty()
+    };
+    let results = match ctx.call_body(module, Func::invalid(), body, args) {
+        InterpResult::Ok(values) => Some(values.into_iter().map(canonical_nan).collect()),
+        InterpResult::Trap(..) => None,
+        InterpResult::OutOfFuel => return None,
+    };
+    let globals = module
+        .globals
+        .iter()
+        .map(|global| canonical_nan(ctx.globals[global]))
+        .collect();
+    let mut hasher = fxhash::FxHasher64::default();
+    for memory in module.memories.iter() {
+        memory.index().hash(&mut hasher);
+        ctx.memories[memory].data.hash(&mut hasher);
+    }
+    Some(Outcome {
+        results,
+        globals,
+        memory_hash: hasher.finish(),
+        imports: ctx.import_log,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FrontendOptions;
+
+    fn bodies(wat: &str) -> (Module<'static>, FunctionBody, FunctionBody) {
+        let bytes = wat::parse_str(wat).unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let module = module.without_orig_bytes();
+        let body = |i| module.funcs[Func::new(i)].body().unwrap().clone();
+        let (a, b) = (body(0), body(1));
+        (module, a, b)
+    }
+
+    #[test]
+    fn structural_and_sampled() {
+        let (module, a, b) = bodies(
+            r#"(module
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 2
+                  i32.mul)
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 1
+                  i32.shl)
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 2
+                  i32.mul))"#,
+        );
+        // A copy elsewhere in the module matches, whatever its offset.
+        let copy = module.funcs[Func::new(2)].body().unwrap();
+        assert_eq!(
+            check(&module, &a, copy, Budget::default()).unwrap(),
+            Equivalence::Structural
+        );
+        let result = check(&module, &a, &b, Budget::default()).unwrap();
+        assert_eq!(result, Equivalence::Sampled { inputs: 1000 });
+        assert!(!result.is_proven());
+    }
+
+    #[test]
+    fn counterexample_and_all_args() {
+        let (module, a, b) = bodies(
+            r#"(module
+                (global $g (mut i32) (i32.const 0))
+                (func (param i64) (result i64)
+                  local.get 0
+                  i64.const 1
+                  i64.add)
+                (func (param i64) (result i64)
+                  i32.const 1
+                  global.set $g
+                  local.get 0
+                  i64.const 1
+                  i64.add))"#,
+        );
+        match check(&module, &a, &b, Budget::default()).unwrap() {
+            Equivalence::Counterexample { args, a, b } => {
+                assert_eq!(args, vec![ConstVal::I64(0)]);
+                assert_eq!(a.results, b.results);
+                assert_ne!(a.globals, b.globals);
+            }
+            other => panic!("unexpected: {}", other),
+        }
+
+        // With no parameters there is a single input to try.
+        let (module, a, b) = bodies(
+            r#"(module
+                (func (result i32) i32.const 7)
+                (func (result i32) i32.const 3 i32.const 4 i32.add))"#,
+        );
+        assert_eq!(
+            check(&module, &a, &b, Budget::default()).unwrap(),
+            Equivalence::AllArgs { inputs: 1 }
+        );
+    }
+
+    #[test]
+    fn all_args_is_not_proof() {
+        // The bodies differ whenever the imported global is nonzero,
+        // but the checker only ever sees it as zero.
+        let (module, a, b) = bodies(
+            r#"(module
+                (import "env" "g" (global $g i32))
+                (func (result i32) global.get $g)
+                (func (result i32) i32.const 0))"#,
+        );
+        let result = check(&module, &a, &b, Budget::default()).unwrap();
+        assert_eq!(result, Equivalence::AllArgs { inputs: 1 });
+        assert!(!result.is_proven());
+    }
+}
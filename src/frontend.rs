@@ -2,6 +2,7 @@
 
 #![allow(dead_code)]
 
+use crate::diagnostics::{Diagnostics, Severity};
 use crate::entity::EntityRef;
 use crate::errors::FrontendError;
 use crate::ir::*;
@@ -12,19 +13,153 @@ use anyhow::{bail, Result};
 use fxhash::{FxHashMap, FxHashSet};
 use log::trace;
 use std::convert::TryFrom;
+use std::sync::Arc;
 use wasmparser::{BlockType, DataKind, ExternalKind, KnownCustom, Name, Parser, Payload, TypeRef};
 
+/// The most bytes a function's body size takes as a LEB128: a low PC
+/// this far before the body may still be the function's.
+const BODY_SIZE_SLACK: u64 = 5;
+
 /// Options to control the Wasm-to-bytecode translation process.
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct FrontendOptions {
     /// Preserve DWARF debug-info. Otherwise, it is discarded if
     /// present.
     pub debug: bool,
+    /// Tolerate functions that use unsupported features: expanding
+    /// one leaves it un-expanded and records it in
+    /// `Module::unsupported_funcs`, rather than failing, so that the
+    /// rest of the module can still be analyzed and transformed.
+    /// Such functions are emitted as their original bytecode.
+    pub lenient: bool,
+    /// How to translate statically-unreachable code.
+    pub unreachable_code: UnreachableCode,
+    /// Size each function body's containers from the length of its
+    /// bytecode before parsing, so that each is allocated about once
+    /// rather than grown repeatedly. This trades some over-allocation
+    /// for less allocator traffic when expanding many functions; see
+    /// the `parse-preallocate` case in `benches/phases.rs`.
+    ///
+    /// This stands in for a per-body bump arena: a body's containers
+    /// are `Vec`s, which cannot use a custom allocator on stable Rust,
+    /// and they grow throughout optimization, which an arena freed
+    /// only with the body would not reclaim.
+    pub preallocate: bool,
+    /// Record, in `FunctionBody::wasm_offsets`, the byte offset in
+    /// the module of the Wasm instruction each operator comes from.
+    /// Engines report traps and profiles by these offsets.
+    pub wasm_offsets: bool,
+    /// Where to report sections skipped while parsing, functions left
+    /// un-expanded, and the like. The module keeps these options, so
+    /// this also receives diagnostics from later work on the module.
+    pub diagnostics: Diagnostics,
+    /// Hooks that may lower operators themselves as function bodies
+    /// are parsed, tried in order for each operator; see `LowerHook`.
+    pub lower_hooks: Vec<Arc<dyn LowerHook>>,
+}
+
+/// How the frontend translates statically-unreachable Wasm code: the
+/// code after an unconditional branch, `return`, or `unreachable`, up
+/// to the end of the enclosing block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnreachableCode {
+    /// Skip unreachable operators, keeping only the blocks needed to
+    /// follow the control structure around them. These blocks have
+    /// no predecessors and no terminator.
+    #[default]
+    Elide,
+    /// Skip unreachable operators, and remove all blocks that are
+    /// unreachable from the entry block. This renumbers the body's
+    /// blocks and values.
+    Drop,
+    /// Translate unreachable code as if it were reachable, into
+    /// blocks with no predecessors. Operands it pops from the
+    /// polymorphic stack, and locals it reads before setting, are
+    /// zero constants.
+    Keep,
+    /// Skip unreachable operators, and give the blocks that remain
+    /// without a terminator an explicit `unreachable` one.
+    Trap,
+}
+
+impl std::str::FromStr for UnreachableCode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<UnreachableCode> {
+        match s {
+            "elide" => Ok(UnreachableCode::Elide),
+            "drop" => Ok(UnreachableCode::Drop),
+            "keep" => Ok(UnreachableCode::Keep),
+            "trap" => Ok(UnreachableCode::Trap),
+            _ => bail!("Unknown unreachable-code handling: {}", s),
+        }
+    }
+}
+
+/// A hook that lowers chosen operators to the embedder's own IR idiom
+/// at parse time, in place of the frontend's translation: for
+/// example, a call to an imported "builtin" function that should
+/// become an inline sequence of operators.
+///
+/// Hooks see each operator of reachable code after conversion to an
+/// `Operator`, with its operands. A hook lowers straight-line code
+/// only: it may add values to the current block, but not blocks or
+/// branches.
+pub trait LowerHook: std::fmt::Debug + Send + Sync {
+    /// Lower `op`, applied to `args`, by adding values through `ctx`,
+    /// and return the values that stand for its results; or return
+    /// `None` to leave the operator to the next hook, or to the
+    /// frontend. The results must have the operator's result types.
+    fn lower(
+        &self,
+        op: &Operator,
+        args: &[Value],
+        ctx: &mut LowerContext,
+    ) -> Result<Option<Vec<Value>>>;
+}
+
+/// Where a `LowerHook` adds its values: the end of the block being
+/// built, in the function being parsed.
+pub struct LowerContext<'a, 'b> {
+    module: &'b Module<'a>,
+    body: &'b mut FunctionBody,
+    block: Block,
+    loc: SourceLoc,
+    offset: u32,
+}
+
+impl<'a, 'b> LowerContext<'a, 'b> {
+    /// The module being parsed.
+    pub fn module(&self) -> &Module<'a> {
+        self.module
+    }
+
+    /// The function body being built.
+    pub fn body(&mut self) -> &mut FunctionBody {
+        self.body
+    }
+
+    /// The block being built.
+    pub fn block(&self) -> Block {
+        self.block
+    }
+
+    /// Append an operator to the block being built, with the source
+    /// location (and Wasm offset, if recorded) of the operator being
+    /// lowered.
+    pub fn add_op(&mut self, op: Operator, args: &[Value], tys: &[Type]) -> Value {
+        let value = self.body.add_op(self.block, op, args, tys);
+        self.body.source_locs[value] = self.loc;
+        if self.module.frontend_options.wasm_offsets {
+            self.body.wasm_offsets[value] = Some(self.offset);
+        }
+        value
+    }
 }
 
 /// Convert the given bytecode to a `Module`.
 pub(crate) fn wasm_to_ir<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Result<Module<'a>> {
     let mut module = Module::with_orig_bytes(bytes);
+    module.frontend_options = options.clone();
     let parser = Parser::new(0);
     let mut next_func = 0;
     let mut dwarf = gimli::Dwarf::default();
@@ -45,13 +180,91 @@ pub(crate) fn wasm_to_ir<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Resu
         gimli::RangeLists::new(extra_sections.debug_ranges, extra_sections.debug_rnglists);
 
     if options.debug {
-        let debug_map = DebugMap::from_dwarf(dwarf, &mut module.debug, extra_sections.code_offset)?;
+        let code_offset = extra_sections.code_offset;
+        for (pc, local, name) in dwarf_local_names(&dwarf)? {
+            // A function's low PC may point at its body's size rather
+            // than the body itself.
+            let func = module.funcs.entries().find_map(|(func, decl)| match decl {
+                FuncDecl::Lazy(_, _, body) => {
+                    let start = (body.range().start as u64).saturating_sub(code_offset as u64);
+                    let end = body.range().end as u64 - code_offset as u64;
+                    (start <= pc + BODY_SIZE_SLACK && pc < end).then_some(func)
+                }
+                _ => None,
+            });
+            if let Some(func) = func {
+                module
+                    .debug
+                    .local_names
+                    .entry(func)
+                    .or_default()
+                    .entry(local)
+                    .or_insert(name);
+            }
+        }
+        let debug_map = DebugMap::from_dwarf(dwarf, &mut module.debug, code_offset)?;
         module.debug_map = debug_map;
     }
 
     Ok(module)
 }
 
+/// Parse a global's initializer: a single constant, or an extended
+/// constant expression.
+fn parse_global_init<'a>(
+    init_expr: &wasmparser::ConstExpr<'a>,
+) -> Result<(Option<u64>, Option<InitExpr>)> {
+    if let Ok(value) = parse_init_expr(init_expr) {
+        return Ok((value, None));
+    }
+    let mut stack: Vec<InitExpr> = vec![];
+    for op in init_expr.get_operators_reader() {
+        let expr = match op? {
+            wasmparser::Operator::I32Const { value } => {
+                InitExpr::Const(Type::I32, value as u32 as u64)
+            }
+            wasmparser::Operator::I64Const { value } => InitExpr::Const(Type::I64, value as u64),
+            wasmparser::Operator::F32Const { value } => {
+                InitExpr::Const(Type::F32, value.bits() as u64)
+            }
+            wasmparser::Operator::F64Const { value } => InitExpr::Const(Type::F64, value.bits()),
+            wasmparser::Operator::GlobalGet { global_index } => {
+                InitExpr::GlobalGet(Global::from(global_index))
+            }
+            wasmparser::Operator::End => break,
+            op => {
+                let op = match op {
+                    wasmparser::Operator::I32Add => InitOp::I32Add,
+                    wasmparser::Operator::I32Sub => InitOp::I32Sub,
+                    wasmparser::Operator::I32Mul => InitOp::I32Mul,
+                    wasmparser::Operator::I64Add => InitOp::I64Add,
+                    wasmparser::Operator::I64Sub => InitOp::I64Sub,
+                    wasmparser::Operator::I64Mul => InitOp::I64Mul,
+                    op => bail!(FrontendError::UnsupportedFeature(format!(
+                        "Unsupported global initializer operator: {:?}",
+                        op
+                    ))),
+                };
+                let b = stack.pop();
+                let a = stack.pop();
+                match (a, b) {
+                    (Some(a), Some(b)) => InitExpr::Binary(op, Box::new(a), Box::new(b)),
+                    _ => bail!(FrontendError::UnsupportedFeature(
+                        "Malformed global initializer".to_owned()
+                    )),
+                }
+            }
+        };
+        stack.push(expr);
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(init), true) => Ok((None, Some(init))),
+        _ => bail!(FrontendError::UnsupportedFeature(
+            "Malformed global initializer".to_owned()
+        )),
+    }
+}
+
 fn parse_init_expr<'a>(init_expr: &wasmparser::ConstExpr<'a>) -> Result<Option<u64>> {
     let operators = init_expr
         .get_operators_reader()
@@ -130,6 +343,7 @@ fn handle_payload<'a>(
                             ty,
                             value: None,
                             mutable,
+                            init: None,
                         });
                         ImportKind::Global(global)
                     }
@@ -169,11 +383,12 @@ fn handle_payload<'a>(
                 let global = global?;
                 let mutable = global.ty.mutable;
                 let ty = global.ty.content_type.into();
-                let init_expr = parse_init_expr(&global.init_expr)?;
+                let (value, init) = parse_global_init(&global.init_expr)?;
                 module.globals.push(GlobalData {
                     ty,
-                    value: init_expr,
+                    value,
                     mutable,
+                    init,
                 });
             }
         }
@@ -194,7 +409,7 @@ fn handle_payload<'a>(
                 module.funcs.push(FuncDecl::Body(
                     sig_idx,
                     "".to_owned(),
-                    FunctionBody::default(),
+                    FunctionBody::default().into(),
                 ));
             }
         }
@@ -267,6 +482,19 @@ fn handle_payload<'a>(
                                         .set_name(name.name);
                                 }
                             }
+                            Name::Local(names) => {
+                                for naming in names {
+                                    let naming = naming?;
+                                    let func = Func::new(naming.index as usize);
+                                    for name in naming.names {
+                                        let name = name?;
+                                        module.debug.local_names.entry(func).or_default().insert(
+                                            Local::new(name.index as usize),
+                                            name.name.to_owned(),
+                                        );
+                                    }
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -415,7 +643,12 @@ fn handle_payload<'a>(
             module.start_func = Some(Func::from(func));
         }
         payload => {
-            log::warn!("Skipping section: {:?}", payload);
+            module.frontend_options.diagnostics.report(
+                Severity::Warning,
+                "frontend",
+                None,
+                format!("skipped unsupported section: {:?}", payload),
+            );
         }
     }
 
@@ -501,7 +734,15 @@ pub(crate) fn parse_body<'a>(
         module.signatures[my_sig]
     );
 
-    let mut builder = FunctionBodyBuilder::new(module, my_sig, &mut ret);
+    if module.frontend_options.preallocate {
+        // Bytecode averages a few bytes per value, and tens of bytes
+        // per block.
+        let len = body.range().len();
+        ret.reserve(len / 3, len / 32);
+    }
+
+    let offset = u32::try_from(body.range().start)?;
+    let mut builder = FunctionBodyBuilder::new(module, my_sig, &mut ret, offset);
     let entry = Block::new(0);
     builder.body.entry = entry;
     builder.locals.seal_block_preds(entry, &mut builder.body);
@@ -514,6 +755,11 @@ pub(crate) fn parse_body<'a>(
         trace!("defining local {} to value {}", local_idx, value);
         builder.locals.declare(local_idx, arg_ty);
         builder.locals.set(local_idx, value);
+        builder.body.mark_value_as_local(value, local_idx);
+        builder
+            .body
+            .debug_values
+            .record(entry, None, local_idx, value);
     }
 
     let n_args = module.signatures[my_sig].params.len();
@@ -522,10 +768,18 @@ pub(crate) fn parse_body<'a>(
         builder.locals.declare(local_idx, *local_ty);
     }
 
+    let mode = module.frontend_options.unreachable_code;
     let ops = body.get_operators_reader()?;
     for item in ops.into_iter_with_offsets() {
         let (op, offset) = item?;
         let loc = debug_locs.get_loc(offset);
+        builder.cur_offset = u32::try_from(offset)?;
+        if !builder.reachable
+            && mode == UnreachableCode::Keep
+            && !matches!(op, wasmparser::Operator::End | wasmparser::Operator::Else)
+        {
+            builder.start_dead_block();
+        }
         if builder.reachable {
             builder.handle_op(op, loc)?;
         } else {
@@ -545,6 +799,18 @@ pub(crate) fn parse_body<'a>(
         debug_assert!(!matches!(value, &ValueDef::Placeholder(_)));
     }
 
+    match mode {
+        UnreachableCode::Elide | UnreachableCode::Keep => {}
+        UnreachableCode::Drop => ret = crate::passes::canonicalize::renumber(&ret),
+        UnreachableCode::Trap => {
+            for def in ret.blocks.values_mut() {
+                if let Terminator::None = def.terminator {
+                    def.terminator = Terminator::Unreachable;
+                }
+            }
+        }
+    }
+
     trace!("Final function body:{:?}", ret);
 
     Ok(ret)
@@ -661,6 +927,7 @@ impl LocalTracker {
 
             let placeholder = body.add_placeholder(ty);
             body.mark_value_as_local(placeholder, local);
+            body.debug_values.record(at_block, None, local, placeholder);
             if at_block == self.cur_block {
                 self.in_cur_block.insert(local, placeholder);
             } else {
@@ -682,6 +949,7 @@ impl LocalTracker {
 
             let placeholder = body.add_placeholder(ty);
             body.mark_value_as_local(placeholder, local);
+            body.debug_values.record(at_block, None, local, placeholder);
             if at_block == self.cur_block {
                 self.in_cur_block.insert(local, placeholder);
             } else {
@@ -718,7 +986,10 @@ impl LocalTracker {
             Type::I64 => body.add_op(at_block, Operator::I64Const { value: 0 }, &[], &[ty]),
             Type::F32 => body.add_op(at_block, Operator::F32Const { value: 0 }, &[], &[ty]),
             Type::F64 => body.add_op(at_block, Operator::F64Const { value: 0 }, &[], &[ty]),
-            Type::V128 => body.add_op(at_block, Operator::V128Const { value: 0 }, &[], &[ty]),
+            Type::V128 => {
+                let value = body.imm_pool.intern(0);
+                body.add_op(at_block, Operator::V128Const { value }, &[], &[ty])
+            }
             _ => todo!("unsupported type: {:?}", ty),
         };
         log::trace!(
@@ -820,6 +1091,9 @@ struct FunctionBodyBuilder<'a, 'b> {
     reachable: bool,
     ctrl_stack: Vec<Frame>,
     op_stack: Vec<(Type, Value)>,
+    /// The byte offset in the module of the operator being
+    /// translated, recorded as the origin of the blocks it creates.
+    cur_offset: u32,
 }
 
 /// A frame in the Wasm control stack, mapping to IR entities for
@@ -928,8 +1202,16 @@ impl Frame {
 }
 
 impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
-    fn new(module: &'b Module<'a>, my_sig: Signature, body: &'b mut FunctionBody) -> Self {
-        body.blocks.push(BlockDef::default());
+    fn new(
+        module: &'b Module<'a>,
+        my_sig: Signature,
+        body: &'b mut FunctionBody,
+        offset: u32,
+    ) -> Self {
+        body.blocks.push(BlockDef {
+            origin: BlockOrigin::FromWasmOffset(offset),
+            ..BlockDef::default()
+        });
         let mut ret = Self {
             module,
             my_sig,
@@ -939,11 +1221,12 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             cur_block: Block::new(0),
             reachable: true,
             locals: LocalTracker::default(),
+            cur_offset: offset,
         };
 
         // Push initial implicit Block.
         let results = module.signatures[my_sig].returns.to_vec();
-        let out = ret.body.add_block();
+        let out = ret.add_block();
         ret.add_block_params(out, &results[..]);
         ret.ctrl_stack.push(Frame::Block {
             start_depth: 0,
@@ -971,6 +1254,65 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         self.op_stack.pop().unwrap().1
     }
 
+    fn set_local(&mut self, local: Local, value: Value) {
+        self.locals.set(local, value);
+        let after = self.body.blocks[self.cur_block].insts.last().copied();
+        self.body
+            .debug_values
+            .record(self.cur_block, after, local, value);
+    }
+
+    /// The number of operands the innermost frame has on the stack.
+    fn frame_depth(&self) -> usize {
+        let start = self
+            .ctrl_stack
+            .last()
+            .map_or(0, |frame| frame.start_depth());
+        self.op_stack.len() - start
+    }
+
+    /// Make sure the innermost frame has operands of types `tys` on
+    /// top of the stack. In unreachable code, the stack is
+    /// polymorphic: operands below the frame's own are zero
+    /// constants.
+    fn fill_operands(&mut self, tys: &[Type]) {
+        let depth = self.frame_depth();
+        if depth >= tys.len() {
+            return;
+        }
+        let start = self.op_stack.len() - depth;
+        let missing = tys[..tys.len() - depth]
+            .iter()
+            .map(|&ty| {
+                let value = self
+                    .locals
+                    .create_default_value(self.body, ty, self.cur_block);
+                (ty, value)
+            })
+            .collect::<Vec<_>>();
+        self.op_stack.splice(start..start, missing);
+    }
+
+    /// Add a block that originates from the current operator.
+    fn add_block(&mut self) -> Block {
+        self.body
+            .add_block_with_origin(BlockOrigin::FromWasmOffset(self.cur_offset))
+    }
+
+    /// Continue translating unreachable code in a new block with no
+    /// predecessors (see `UnreachableCode::Keep`).
+    fn start_dead_block(&mut self) {
+        debug_assert!(!self.reachable);
+        let block = self.add_block();
+        self.locals.seal_block_preds(block, self.body);
+        self.locals.finish_block(false);
+        self.locals.start_block(block);
+        self.cur_block = block;
+        self.reachable = true;
+        let start = self.op_stack.len() - self.frame_depth();
+        self.op_stack.truncate(start);
+    }
+
     fn block_results(&mut self, tys: &[Type], start_depth: usize, at_block: Block) -> Vec<Value> {
         if self.op_stack.len() < start_depth + tys.len() {
             tys.iter()
@@ -1005,19 +1347,31 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 let local_index = Local::from(*local_index);
                 let ty = self.body.locals[local_index];
                 let value = self.locals.get(&mut self.body, local_index);
+                // A blockparam carrying the local takes the location
+                // of its first read.
+                if self.body.source_locs[value] == SourceLoc::invalid()
+                    && matches!(
+                        self.body.values[value],
+                        ValueDef::Placeholder(..) | ValueDef::BlockParam(..)
+                    )
+                {
+                    self.body.source_locs[value] = loc;
+                }
                 self.op_stack.push((ty, value));
             }
 
             wasmparser::Operator::LocalSet { local_index } => {
                 let local_index = Local::from(*local_index);
+                self.fill_operands(&[self.body.locals[local_index]]);
                 let (_, value) = self.op_stack.pop().unwrap();
-                self.locals.set(local_index, value);
+                self.set_local(local_index, value);
             }
 
             wasmparser::Operator::LocalTee { local_index } => {
                 let local_index = Local::from(*local_index);
+                self.fill_operands(&[self.body.locals[local_index]]);
                 let (_ty, value) = *self.op_stack.last().unwrap();
-                self.locals.set(local_index, value);
+                self.set_local(local_index, value);
             }
 
             wasmparser::Operator::Call { .. }
@@ -1051,6 +1405,8 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             | wasmparser::Operator::I64Store32 { .. }
             | wasmparser::Operator::MemorySize { .. }
             | wasmparser::Operator::MemoryGrow { .. }
+            | wasmparser::Operator::MemoryCopy { .. }
+            | wasmparser::Operator::MemoryFill { .. }
             | wasmparser::Operator::I32Const { .. }
             | wasmparser::Operator::I64Const { .. }
             | wasmparser::Operator::F32Const { .. }
@@ -1217,8 +1573,6 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             | wasmparser::Operator::V128Store16Lane { .. }
             | wasmparser::Operator::V128Store32Lane { .. }
             | wasmparser::Operator::V128Store64Lane { .. }
-            | wasmparser::Operator::V128Const { .. }
-            | wasmparser::Operator::I8x16Shuffle { .. }
             | wasmparser::Operator::I8x16ExtractLaneS { .. }
             | wasmparser::Operator::I8x16ExtractLaneU { .. }
             | wasmparser::Operator::I8x16ReplaceLane { .. }
@@ -1435,17 +1789,38 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             | wasmparser::Operator::RefIsNull
             | wasmparser::Operator::RefNull { .. }
             | wasmparser::Operator::RefFunc { .. } => {
-                self.emit(Operator::try_from(&op).unwrap(), loc)?
+                // Lane indices come straight from the input, and only
+                // these can fail to convert.
+                let ir_op = Operator::try_from(&op).map_err(|()| {
+                    FrontendError::Internal(format!("Lane index out of range in {:?}", op))
+                })?;
+                self.emit(ir_op, loc)?
+            }
+
+            // The immediates of these go in the body's pool.
+            wasmparser::Operator::V128Const { value } => {
+                let value = self.body.imm_pool.intern(value.i128() as u128);
+                self.emit(Operator::V128Const { value }, loc)?
+            }
+            wasmparser::Operator::I8x16Shuffle { lanes } => {
+                let lanes = self.body.imm_pool.intern_lanes(*lanes);
+                self.emit(Operator::I8x16Shuffle { lanes }, loc)?
             }
 
             wasmparser::Operator::Nop => {}
 
             wasmparser::Operator::Drop => {
+                self.fill_operands(&[Type::I32]);
                 let _ = self.pop_1();
             }
 
             wasmparser::Operator::Br { relative_depth }
             | wasmparser::Operator::BrIf { relative_depth } => {
+                let mut operands = self.relative_frame(*relative_depth).br_args().to_vec();
+                if let wasmparser::Operator::BrIf { .. } = &op {
+                    operands.push(Type::I32);
+                }
+                self.fill_operands(&operands);
                 let cond = match &op {
                     wasmparser::Operator::Br { .. } => None,
                     wasmparser::Operator::BrIf { .. } => Some(self.pop_1()),
@@ -1466,7 +1841,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                         self.reachable = false;
                     }
                     Some(cond) => {
-                        let cont = self.body.add_block();
+                        let cont = self.add_block();
                         // Get the args off the stack but leave for the fallthrough.
                         let args = self.op_stack[self.op_stack.len() - frame.br_args().len()..]
                             .iter()
@@ -1482,6 +1857,9 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::BrTable { targets } => {
+                let mut operands = self.relative_frame(targets.default()).br_args().to_vec();
+                operands.push(Type::I32);
+                self.fill_operands(&operands);
                 // Get the selector index.
                 let index = self.pop_1();
                 // Get the signature of the default frame; this tells
@@ -1509,6 +1887,8 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::Return => {
+                let returns = self.module.signatures[self.my_sig].returns.clone();
+                self.fill_operands(&returns);
                 let retvals = self.pop_n(self.module.signatures[self.my_sig].returns.len());
                 self.emit_ret(&retvals[..]);
                 self.reachable = false;
@@ -1669,7 +2049,10 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
 
             wasmparser::Operator::Block { blockty } => {
                 let (params, results) = self.block_params_and_results(*blockty);
-                let out = self.body.add_block();
+                if self.reachable {
+                    self.fill_operands(&params);
+                }
+                let out = self.add_block();
                 self.add_block_params(out, &results[..]);
                 let start_depth = if self.reachable {
                     self.op_stack.len() - params.len()
@@ -1687,9 +2070,10 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
 
             wasmparser::Operator::Loop { blockty } => {
                 let (params, results) = self.block_params_and_results(*blockty);
-                let header = self.body.add_block();
+                let header = self.add_block();
                 self.add_block_params(header, &params[..]);
                 let initial_args = if self.reachable {
+                    self.fill_operands(&params);
                     self.pop_n(params.len())
                 } else {
                     vec![Value::invalid(); params.len()]
@@ -1700,7 +2084,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 self.locals.finish_block(self.reachable);
                 self.locals.start_block(header);
                 self.push_block_params(params.len());
-                let out = self.body.add_block();
+                let out = self.add_block();
                 self.add_block_params(out, &results[..]);
                 self.ctrl_stack.push(Frame::Loop {
                     start_depth,
@@ -1713,11 +2097,14 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
 
             wasmparser::Operator::If { blockty } => {
                 let (params, results) = self.block_params_and_results(*blockty);
-                let if_true = self.body.add_block();
-                let if_false = self.body.add_block();
-                let join = self.body.add_block();
+                let if_true = self.add_block();
+                let if_false = self.add_block();
+                let join = self.add_block();
                 self.add_block_params(join, &results[..]);
                 let (cond, param_values) = if self.reachable {
+                    let mut operands = params.clone();
+                    operands.push(Type::I32);
+                    self.fill_operands(&operands);
                     let cond = self.pop_1();
                     let param_values = self.op_stack[self.op_stack.len() - params.len()..].to_vec();
                     (cond, param_values)
@@ -1956,10 +2343,69 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         }
     }
 
+    /// Offer `op` to the module's lowering hooks, with its `n_inputs`
+    /// operands on top of the operand stack. If one lowers it, replace
+    /// the operands with its results and return `true`.
+    fn lower_with_hooks(
+        &mut self,
+        op: &Operator,
+        n_inputs: usize,
+        outputs: &[Type],
+        loc: SourceLoc,
+    ) -> Result<bool> {
+        let module = self.module;
+        if module.frontend_options.lower_hooks.is_empty() {
+            return Ok(false);
+        }
+        let depth = self.op_stack.len() - n_inputs;
+        let args: Vec<Value> = self.op_stack[depth..].iter().map(|&(_, v)| v).collect();
+        for hook in &module.frontend_options.lower_hooks {
+            let mut ctx = LowerContext {
+                module,
+                body: self.body,
+                block: self.cur_block,
+                loc,
+                offset: self.cur_offset,
+            };
+            let Some(results) = hook.lower(op, &args, &mut ctx)? else {
+                continue;
+            };
+            let tys: Vec<Option<Type>> = results
+                .iter()
+                .map(|&v| self.body.values[self.body.resolve_alias(v)].ty(&self.body.type_pool))
+                .collect();
+            if tys.len() != outputs.len()
+                || tys.iter().zip(outputs).any(|(&ty, &out)| ty != Some(out))
+            {
+                bail!(FrontendError::Internal(format!(
+                    "Lowering hook {:?} gave results {:?} of types {:?} for {:?}, expected {:?}",
+                    hook, results, tys, op, outputs
+                )));
+            }
+            log::trace!(" -> lowered by hook {:?} to {:?}", hook, results);
+            self.op_stack.truncate(depth);
+            self.op_stack.extend(outputs.iter().copied().zip(results));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
     fn emit(&mut self, op: Operator, loc: SourceLoc) -> Result<()> {
+        if let Operator::Select = op {
+            // The operand type comes from the stack; in unreachable
+            // code, it may not be there.
+            if self.frame_depth() < 2 {
+                self.fill_operands(&[Type::I32, Type::I32, Type::I32]);
+            }
+        }
         let inputs = op_inputs(self.module, &self.op_stack[..], &op)?;
+        self.fill_operands(&inputs);
         let outputs = op_outputs(self.module, &self.op_stack[..], &op)?;
 
+        if self.lower_with_hooks(&op, inputs.len(), &outputs, loc)? {
+            return Ok(());
+        }
+
         log::trace!(
             "emit into block {:?}: op {:?} inputs {:?}",
             self.cur_block,
@@ -1973,7 +2419,12 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         let args = &mut self.body.arg_pool[input_operands];
         for (i, &input) in inputs.into_iter().enumerate().rev() {
             let (stack_top_ty, stack_top) = self.op_stack.pop().unwrap();
-            assert_eq!(stack_top_ty, input);
+            assert!(
+                stack_top_ty.is_subtype_of(input),
+                "operand of type {:?} where {:?} is expected",
+                stack_top_ty,
+                input
+            );
             args[i] = stack_top;
         }
         log::trace!(" -> operands: {:?}", input_operands);
@@ -1994,6 +2445,9 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             self.body.append_to_block(self.cur_block, value);
         }
         self.body.source_locs[value] = loc;
+        if self.module.frontend_options.wasm_offsets {
+            self.body.wasm_offsets[value] = Some(self.cur_offset);
+        }
 
         if n_outputs == 1 {
             let output_ty = outputs[0];
@@ -2014,3 +2468,115 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testgen::wat_module;
+    use crate::{ConstVal, InterpContext};
+
+    #[test]
+    fn unreachable_code_modes() {
+        // A block that branches out with a constant, followed by an
+        // `i32.add` whose operands come from the polymorphic stack.
+        let wat = r#"(module
+            (func (result i32)
+              (block (result i32)
+                i32.const 1
+                br 0
+                i32.add)))"#;
+        for mode in [
+            UnreachableCode::Elide,
+            UnreachableCode::Drop,
+            UnreachableCode::Keep,
+            UnreachableCode::Trap,
+        ] {
+            let options = FrontendOptions {
+                unreachable_code: mode,
+                ..FrontendOptions::default()
+            };
+            let mut module = wat_module(wat, &options);
+            module.expand_all_funcs().unwrap();
+            let body = module.funcs[Func::new(0)].body().unwrap();
+            let has_add = body
+                .values
+                .values()
+                .any(|def| matches!(def, ValueDef::Operator(Operator::I32Add, ..)));
+            assert_eq!(has_add, mode == UnreachableCode::Keep, "{:?}", mode);
+            let unterminated = body
+                .blocks
+                .values()
+                .filter(|block| matches!(block.terminator, Terminator::None))
+                .count();
+            match mode {
+                UnreachableCode::Drop | UnreachableCode::Trap => assert_eq!(unterminated, 0),
+                _ => {}
+            }
+            if mode == UnreachableCode::Drop {
+                assert!(body
+                    .blocks
+                    .entries()
+                    .all(|(block, def)| block == body.entry || !def.preds.is_empty()));
+            }
+            let produced = module.to_wasm_bytes().unwrap();
+            wasmparser::Validator::new()
+                .validate_all(&produced)
+                .unwrap();
+        }
+    }
+
+    /// Lowers calls to `builtin.double` to an `i32.add`.
+    #[derive(Debug)]
+    struct Double;
+
+    impl LowerHook for Double {
+        fn lower(
+            &self,
+            op: &Operator,
+            args: &[Value],
+            ctx: &mut LowerContext,
+        ) -> Result<Option<Vec<Value>>> {
+            let &Operator::Call { function_index } = op else {
+                return Ok(None);
+            };
+            let is_double = ctx.module().imports.iter().any(|import| {
+                import.kind == ImportKind::Func(function_index)
+                    && import.module == "builtin"
+                    && import.name == "double"
+            });
+            if !is_double {
+                return Ok(None);
+            }
+            let sum = ctx.add_op(Operator::I32Add, &[args[0], args[0]], &[Type::I32]);
+            Ok(Some(vec![sum]))
+        }
+    }
+
+    #[test]
+    fn lower_hooks_replace_builtin_calls() {
+        let options = FrontendOptions {
+            lower_hooks: vec![Arc::new(Double)],
+            ..FrontendOptions::default()
+        };
+        let mut module = wat_module(
+            r#"(module
+                (import "builtin" "double" (func $double (param i32) (result i32)))
+                (func (param i32) (result i32)
+                  local.get 0
+                  call $double
+                  i32.const 1
+                  i32.add))"#,
+            &options,
+        );
+        module.expand_all_funcs().unwrap();
+        let f = Func::new(1);
+        let body = module.funcs[f].body().unwrap();
+        assert!(!body
+            .values
+            .values()
+            .any(|def| matches!(def, ValueDef::Operator(Operator::Call { .. }, ..))));
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, f, &[ConstVal::I32(20)]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(41)]);
+    }
+}
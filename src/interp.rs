@@ -3,15 +3,29 @@
 use crate::entity::{EntityRef, PerEntity};
 use crate::ir::*;
 use crate::ops::Operator;
+use crate::passes::pgo::Profile;
 use smallvec::{smallvec, SmallVec};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+mod heap;
+pub use heap::*;
+mod symbolic;
+pub use symbolic::*;
+mod taint;
+pub use taint::*;
 
 /// How large do we allow a Wasm memory to be when interpreting? Limit
 /// the size somewhat (apply an implementation limit) so we do not
 /// have unreasonably large state.
 const MAX_PAGES: usize = 2048; // 2048 * 64KiB = 128MiB
 
+/// How many elements do we allow a table to have when interpreting?
+/// As for memories, an implementation limit.
+const MAX_TABLE_ELEMENTS: usize = 1 << 20;
+
 /// Context for the IR interpreter. Corresponds roughly to Wasm module
 /// state.
 pub struct InterpContext {
@@ -23,6 +37,91 @@ pub struct InterpContext {
     pub globals: PerEntity<Global, ConstVal>,
     /// Fuel remaining: allows deterministic stopping of execution.
     pub fuel: u64,
+    /// If set, execution counts are added to this profile.
+    pub profile: Option<Profile>,
+    /// The call stack at the last trap, innermost frame first.
+    pub backtrace: Vec<BacktraceFrame>,
+    /// Why the last call trapped, if it did.
+    pub trap_code: Option<TrapCode>,
+    /// How calls to imported functions are handled.
+    pub import_mode: ImportMode,
+    /// Calls to imported functions: appended to when recording, and
+    /// read from when replaying.
+    pub import_log: Vec<ImportCall>,
+    /// If set, taint is tracked while interpreting.
+    pub taint: Option<TaintTracker>,
+    /// If set, symbolic values and path constraints are tracked while
+    /// interpreting.
+    pub symbolic: Option<SymbolicTracker>,
+    /// If set, calls to the heap monitor imports are aggregated here.
+    pub heap: Option<HeapStats>,
+}
+
+/// How the interpreter handles calls to imported functions.
+#[derive(Clone, Debug, Default)]
+pub enum ImportMode {
+    /// Panic: the interpreter knows no host functions.
+    #[default]
+    Panic,
+    /// Append each call to `InterpContext::import_log`, returning the
+    /// results given for the import's name in `defaults`, or else
+    /// zeroes of its result types.
+    Record {
+        defaults: HashMap<String, Vec<ConstVal>>,
+    },
+    /// Return the results of the calls in `InterpContext::import_log`
+    /// in order, starting at index `next`. A call that does not match
+    /// the next logged call's import and args, or that runs past the
+    /// end of the log, traps.
+    Replay { next: usize },
+}
+
+/// One call to an imported function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportCall {
+    pub module: String,
+    pub name: String,
+    pub args: Vec<ConstVal>,
+    pub results: Vec<ConstVal>,
+}
+
+/// Why execution trapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrapCode {
+    /// An `unreachable` operator or terminator, or a block without a
+    /// terminator.
+    Unreachable,
+    /// A memory access out of bounds.
+    MemoryOutOfBounds,
+    /// A table access, or the index of a `call_indirect`, out of
+    /// bounds.
+    TableOutOfBounds,
+    /// A `call_indirect` or `call_ref` of a null reference.
+    NullReference,
+    /// A `call_indirect` of a function whose signature is not the
+    /// expected one.
+    SignatureMismatch,
+    /// A call to an import that does not match the next call in the
+    /// import log, when replaying.
+    ReplayMismatch,
+    /// Any other trapping operator, such as an integer division by
+    /// zero.
+    Operator,
+}
+
+/// One frame of the call stack at a trap.
+#[derive(Clone, Debug)]
+pub struct BacktraceFrame {
+    pub func: Func,
+    pub block: Block,
+    /// The index in `block` of the trapping instruction, or in outer
+    /// frames, of the call; `u32::MAX` for the terminator.
+    pub inst: u32,
+    /// The source location of that instruction, if known.
+    pub loc: SourceLoc,
+    /// The original locals whose values are known at that point, with
+    /// their names, if any.
+    pub locals: Vec<(Local, Option<String>, ConstVal)>,
 }
 
 /// The state of one interpreter memory.
@@ -35,7 +134,9 @@ pub struct InterpMemory {
 /// The state of one interpreter table.
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct InterpTable {
+    /// The elements; null references are `Func::invalid()`.
     pub elements: Vec<Func>,
+    pub max_elements: usize,
 }
 
 /// One stack frame in the interpreted execution context.
@@ -44,6 +145,10 @@ pub struct InterpStackFrame {
     func: Func,
     cur_block: Block,
     values: HashMap<Value, SmallVec<[ConstVal; 2]>>,
+    /// The taints of tainted values, if tracking taint.
+    taints: HashMap<Value, Vec<Taint>>,
+    /// The symbolic expressions of symbolic values, if tracking them.
+    syms: HashMap<Value, Vec<Option<Rc<SymExpr>>>>,
 }
 
 /// The result of an interpreter session.
@@ -58,12 +163,27 @@ pub enum InterpResult {
 }
 
 /// A constant concrete value during interpretation.
+///
+/// Numbers are stored as bits. The `From` and `TryFrom` impls convert
+/// to and from the Rust types of the same width, for host functions
+/// and embedders; floats convert bit-for-bit, so NaN payloads are
+/// kept.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub enum ConstVal {
     I32(u32),
     I64(u64),
     F32(u32),
     F64(u64),
+    /// A 128-bit vector, lane 0 in the low bits.
+    V128(u128),
+    /// A function reference; a null reference is `Func::invalid()`.
+    Ref(Func),
+    /// An external reference: a handle chosen by the host, which the
+    /// interpreter passes around without looking into; `None` is the
+    /// null reference. The IR has no `externref` type, so these only
+    /// flow through values typed as function references.
+    ExternRef(Option<u32>),
+    /// Not a known value.
     #[default]
     None,
 }
@@ -106,20 +226,26 @@ impl InterpContext {
 
         let mut tables = PerEntity::default();
         for (table, data) in module.tables.entries() {
+            let mut elements = data.func_elements.clone().unwrap_or_default();
+            if elements.len() < data.initial as usize {
+                elements.resize(data.initial as usize, Func::invalid());
+            }
+            let max_elements = data
+                .max
+                .map_or(MAX_TABLE_ELEMENTS, |max| max as usize)
+                .min(MAX_TABLE_ELEMENTS);
             let interp_table = InterpTable {
-                elements: data.func_elements.clone().unwrap_or(vec![]),
+                elements,
+                max_elements,
             };
             tables[table] = interp_table;
         }
 
         let mut globals = PerEntity::default();
         for (global, data) in module.globals.entries() {
-            globals[global] = match data.ty {
-                Type::I32 => ConstVal::I32(data.value.unwrap_or(0) as u32),
-                Type::I64 => ConstVal::I64(data.value.unwrap_or(0)),
-                Type::F32 => ConstVal::F32(data.value.unwrap_or(0) as u32),
-                Type::F64 => ConstVal::F64(data.value.unwrap_or(0)),
-                _ => unimplemented!(),
+            globals[global] = match &data.init {
+                Some(init) => eval_init(init, &globals),
+                None => const_of_type(data.ty, data.value.unwrap_or(0)),
             };
         }
 
@@ -127,25 +253,80 @@ impl InterpContext {
             memories,
             tables,
             globals,
-            fuel: u64::MAX,
+            ..InterpContext::empty()
         })
     }
 
+    /// Construct an interpreter context with no memories, tables, or
+    /// globals and unlimited fuel, without instantiating any module.
+    /// Loads and stores trap, and globals read as `ConstVal::None`
+    /// unless set first.
+    pub fn empty() -> Self {
+        InterpContext {
+            memories: PerEntity::default(),
+            tables: PerEntity::default(),
+            globals: PerEntity::default(),
+            fuel: u64::MAX,
+            profile: None,
+            backtrace: vec![],
+            trap_code: None,
+            import_mode: ImportMode::default(),
+            import_log: vec![],
+            taint: None,
+            symbolic: None,
+            heap: None,
+        }
+    }
+
     /// Call the given function with the given args, running the
     /// interpreter until fuel is exhausted or the function returns.
     pub fn call(&mut self, module: &Module<'_>, func: Func, args: &[ConstVal]) -> InterpResult {
+        self.trap_code = None;
+        if let Some(taint) = &mut self.taint {
+            taint.transfer.clear();
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.start(args);
+        }
+        self.call_inner(module, func, args)
+    }
+
+    fn call_inner(&mut self, module: &Module<'_>, func: Func, args: &[ConstVal]) -> InterpResult {
         let body = match &module.funcs[func] {
             FuncDecl::Lazy(..) => panic!("Un-expanded function"),
             FuncDecl::Compiled(..) => panic!("Already-compiled function"),
             FuncDecl::Import(..) => {
                 let import = &module.imports[func.index()];
                 assert_eq!(import.kind, ImportKind::Func(func));
-                return self.call_import(&import.name[..], args);
+                return self.call_import(module, func, import, args);
             }
             FuncDecl::Body(_, _, body) => body,
             FuncDecl::None => panic!("FuncDecl::None in call()"),
         };
+        self.call_body(module, func, body, args)
+    }
 
+    /// Trap at instruction `inst_idx` of the current block of `frame`.
+    fn trap(
+        &mut self,
+        body: &FunctionBody,
+        frame: &InterpStackFrame,
+        inst_idx: usize,
+        code: TrapCode,
+    ) -> InterpResult {
+        self.trap_code = Some(code);
+        self.backtrace.clear();
+        self.push_frame(body, frame, inst_idx as u32);
+        InterpResult::Trap(frame.func, frame.cur_block, inst_idx as u32)
+    }
+
+    pub(crate) fn call_body(
+        &mut self,
+        module: &Module<'_>,
+        func: Func,
+        body: &FunctionBody,
+        args: &[ConstVal],
+    ) -> InterpResult {
         log::trace!(
             "Interp: entering func {}:\n{}\n",
             func,
@@ -157,18 +338,41 @@ impl InterpContext {
             func,
             cur_block: body.entry,
             values: HashMap::new(),
+            taints: HashMap::new(),
+            syms: HashMap::new(),
         };
 
         for (&arg, &(_, blockparam)) in args.iter().zip(body.blocks[body.entry].params.iter()) {
             log::trace!("Entry block param {} gets arg value {:?}", blockparam, arg);
             frame.values.insert(blockparam, smallvec![arg]);
         }
+        if let Some(taint) = &mut self.taint {
+            let taints = std::mem::take(&mut taint.transfer);
+            for (taint, &(_, blockparam)) in taints.into_iter().zip(&body.blocks[body.entry].params)
+            {
+                frame.set_taints(blockparam, vec![taint]);
+            }
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            let syms = std::mem::take(&mut symbolic.transfer);
+            for (sym, &(_, blockparam)) in syms.into_iter().zip(&body.blocks[body.entry].params) {
+                frame.set_syms(blockparam, vec![sym]);
+            }
+        }
 
+        if let Some(profile) = &mut self.profile {
+            profile.record_call(func);
+        }
+        let mut prev_block = None;
         loop {
             self.fuel -= 1;
             if self.fuel == 0 {
                 return InterpResult::OutOfFuel;
             }
+            if let Some(profile) = &mut self.profile {
+                profile.record_block(func, prev_block, frame.cur_block);
+            }
+            prev_block = Some(frame.cur_block);
 
             log::trace!("Interpreting block {}", frame.cur_block);
             for (inst_idx, &inst) in body.blocks[frame.cur_block].insts.iter().enumerate() {
@@ -177,10 +381,15 @@ impl InterpContext {
                     &ValueDef::Alias(_) => smallvec![],
                     &ValueDef::PickOutput(val, idx, _) => {
                         let val = body.resolve_alias(val);
+                        let taint = frame.taint(val, idx as usize);
+                        frame.set_taints(inst, vec![taint]);
+                        let sym = frame.sym(val, idx as usize);
+                        frame.set_syms(inst, vec![sym]);
                         smallvec![frame.values.get(&val).unwrap()[idx as usize]]
                     }
                     &ValueDef::Operator(Operator::Call { function_index }, args, _) => {
-                        let args = body.arg_pool[args]
+                        let arg_values = &body.arg_pool[args];
+                        let args = arg_values
                             .iter()
                             .map(|&arg| {
                                 let arg = body.resolve_alias(arg);
@@ -189,14 +398,31 @@ impl InterpContext {
                                 multivalue[0]
                             })
                             .collect::<Vec<_>>();
-                        let result = self.call(module, function_index, &args[..]);
+                        let callee = function_index;
+                        self.enter_call(module, body, &frame, inst, callee, arg_values);
+                        let result = self.call_inner(module, callee, &args[..]);
                         match result {
-                            InterpResult::Ok(vals) => vals,
+                            InterpResult::Ok(vals) => {
+                                self.exit_call(module, &mut frame, inst, callee, &vals[..]);
+                                vals
+                            }
+                            InterpResult::Trap(..) => {
+                                self.push_frame(body, &frame, inst_idx as u32);
+                                return result;
+                            }
                             _ => return result,
                         }
                     }
-                    &ValueDef::Operator(Operator::CallIndirect { table_index, .. }, args, _) => {
-                        let args = body.arg_pool[args]
+                    &ValueDef::Operator(
+                        Operator::CallIndirect {
+                            table_index,
+                            sig_index,
+                        },
+                        args,
+                        _,
+                    ) => {
+                        let arg_values = &body.arg_pool[args];
+                        let args = arg_values
                             .iter()
                             .map(|&arg| {
                                 let arg = body.resolve_alias(arg);
@@ -206,15 +432,73 @@ impl InterpContext {
                             })
                             .collect::<Vec<_>>();
                         let idx = args.last().unwrap().as_u32().unwrap() as usize;
-                        let func = self.tables[table_index].elements[idx];
-                        let result = self.call(module, func, &args[..args.len() - 1]);
+                        let func = match self.tables[table_index].elements.get(idx) {
+                            Some(&func) => func,
+                            None => {
+                                return self.trap(
+                                    body,
+                                    &frame,
+                                    inst_idx,
+                                    TrapCode::TableOutOfBounds,
+                                )
+                            }
+                        };
+                        if !func.is_valid() {
+                            return self.trap(body, &frame, inst_idx, TrapCode::NullReference);
+                        }
+                        if module.signatures[module.funcs[func].sig()]
+                            != module.signatures[sig_index]
+                        {
+                            return self.trap(body, &frame, inst_idx, TrapCode::SignatureMismatch);
+                        }
+                        let arg_values = &arg_values[..arg_values.len() - 1];
+                        self.enter_call(module, body, &frame, inst, func, arg_values);
+                        let result = self.call_inner(module, func, &args[..args.len() - 1]);
                         match result {
-                            InterpResult::Ok(vals) => vals,
+                            InterpResult::Ok(vals) => {
+                                self.exit_call(module, &mut frame, inst, func, &vals[..]);
+                                vals
+                            }
+                            InterpResult::Trap(..) => {
+                                self.push_frame(body, &frame, inst_idx as u32);
+                                return result;
+                            }
+                            _ => return result,
+                        }
+                    }
+                    &ValueDef::Operator(Operator::CallRef { .. }, args, _) => {
+                        let arg_values = &body.arg_pool[args];
+                        let args = arg_values
+                            .iter()
+                            .map(|&arg| {
+                                let arg = body.resolve_alias(arg);
+                                let multivalue = frame.values.get(&arg).unwrap();
+                                assert_eq!(multivalue.len(), 1);
+                                multivalue[0]
+                            })
+                            .collect::<Vec<_>>();
+                        let func = match args.last() {
+                            Some(&ConstVal::Ref(func)) if func.is_valid() => func,
+                            _ => return self.trap(body, &frame, inst_idx, TrapCode::NullReference),
+                        };
+                        let arg_values = &arg_values[..arg_values.len() - 1];
+                        self.enter_call(module, body, &frame, inst, func, arg_values);
+                        let result = self.call_inner(module, func, &args[..args.len() - 1]);
+                        match result {
+                            InterpResult::Ok(vals) => {
+                                self.exit_call(module, &mut frame, inst, func, &vals[..]);
+                                vals
+                            }
+                            InterpResult::Trap(..) => {
+                                self.push_frame(body, &frame, inst_idx as u32);
+                                return result;
+                            }
                             _ => return result,
                         }
                     }
                     &ValueDef::Operator(ref op, args, _) => {
-                        let args = body.arg_pool[args]
+                        let arg_values = &body.arg_pool[args];
+                        let args = arg_values
                             .iter()
                             .map(|&arg| {
                                 let arg = body.resolve_alias(arg);
@@ -227,17 +511,50 @@ impl InterpContext {
                                 multivalue[0]
                             })
                             .collect::<Vec<_>>();
-                        let result = match const_eval(op, &args[..], Some(self)) {
+                        let result = match op {
+                            Operator::V128Const { value } => {
+                                Some(ConstVal::V128(body.imm_pool[*value]))
+                            }
+                            _ => const_eval(op, &args[..], Some(self)),
+                        };
+                        let result = match result {
                             Some(result) => result,
                             None => {
                                 log::trace!("const_eval failed on {:?} args {:?}", op, args);
-                                return InterpResult::Trap(
-                                    frame.func,
-                                    frame.cur_block,
-                                    inst_idx as u32,
-                                );
+                                let code = match op {
+                                    Operator::Unreachable => TrapCode::Unreachable,
+                                    Operator::MemoryFill { .. } | Operator::MemoryCopy { .. } => {
+                                        TrapCode::MemoryOutOfBounds
+                                    }
+                                    op if op.memory_access().is_some() => {
+                                        TrapCode::MemoryOutOfBounds
+                                    }
+                                    _ => self.trap_code.unwrap_or(TrapCode::Operator),
+                                };
+                                return self.trap(body, &frame, inst_idx, code);
                             }
                         };
+                        if let Some(taint) = &mut self.taint {
+                            let arg_values = arg_values
+                                .iter()
+                                .map(|&arg| body.resolve_alias(arg))
+                                .collect::<Vec<_>>();
+                            let arg_taints = arg_values
+                                .iter()
+                                .map(|&arg| frame.taint(arg, 0))
+                                .collect::<Vec<_>>();
+                            let result_taint =
+                                taint.op(frame.func, inst, op, &arg_values, &args, &arg_taints);
+                            frame.set_taints(inst, vec![result_taint]);
+                        }
+                        if let Some(symbolic) = &self.symbolic {
+                            let arg_syms = arg_values
+                                .iter()
+                                .map(|&arg| frame.sym(body.resolve_alias(arg), 0))
+                                .collect::<Vec<_>>();
+                            let sym = symbolic.op(op, &arg_syms, &args, result);
+                            frame.set_syms(inst, vec![sym]);
+                        }
                         smallvec![result]
                     }
                     &ValueDef::None | &ValueDef::Placeholder(..) | &ValueDef::BlockParam(..) => {
@@ -250,11 +567,11 @@ impl InterpContext {
             }
 
             match &body.blocks[frame.cur_block].terminator {
-                &Terminator::None => {
-                    return InterpResult::Trap(frame.func, frame.cur_block, u32::MAX)
-                }
-                &Terminator::Unreachable => {
-                    return InterpResult::Trap(frame.func, frame.cur_block, u32::MAX)
+                &Terminator::None | &Terminator::Unreachable => {
+                    self.trap_code = Some(TrapCode::Unreachable);
+                    self.backtrace.clear();
+                    self.push_frame(body, &frame, u32::MAX);
+                    return InterpResult::Trap(frame.func, frame.cur_block, u32::MAX);
                 }
                 &Terminator::Br { ref target } => {
                     frame.apply_target(body, target);
@@ -265,8 +582,13 @@ impl InterpContext {
                     ref if_false,
                 } => {
                     let cond = body.resolve_alias(cond);
-                    let cond = frame.values.get(&cond).unwrap();
-                    let cond = cond[0].as_u32().unwrap() != 0;
+                    let cond_val = frame.values.get(&cond).unwrap()[0];
+                    if let Some(symbolic) = &mut self.symbolic {
+                        let expr = frame.sym(cond, 0);
+                        let block = frame.cur_block;
+                        symbolic.branch(func, block, BranchKind::Cond, expr, cond_val);
+                    }
+                    let cond = cond_val.as_u32().unwrap() != 0;
                     if cond {
                         frame.apply_target(body, if_true);
                     } else {
@@ -279,8 +601,13 @@ impl InterpContext {
                     ref default,
                 } => {
                     let value = body.resolve_alias(value);
-                    let value = frame.values.get(&value).unwrap();
-                    let value = value[0].as_u32().unwrap() as usize;
+                    let val = frame.values.get(&value).unwrap()[0];
+                    if let Some(symbolic) = &mut self.symbolic {
+                        let expr = frame.sym(value, 0);
+                        let kind = BranchKind::Select(targets.len());
+                        symbolic.branch(func, frame.cur_block, kind, expr, val);
+                    }
+                    let value = val.as_u32().unwrap() as usize;
                     if value < targets.len() {
                         frame.apply_target(body, &targets[value]);
                     } else {
@@ -288,6 +615,18 @@ impl InterpContext {
                     }
                 }
                 &Terminator::Return { ref values } => {
+                    if let Some(taint) = &mut self.taint {
+                        taint.transfer = values
+                            .iter()
+                            .map(|&value| frame.taint(body.resolve_alias(value), 0))
+                            .collect();
+                    }
+                    if let Some(symbolic) = &mut self.symbolic {
+                        symbolic.transfer = values
+                            .iter()
+                            .map(|&value| frame.sym(body.resolve_alias(value), 0))
+                            .collect();
+                    }
                     let values = values
                         .iter()
                         .map(|&value| {
@@ -302,12 +641,210 @@ impl InterpContext {
         }
     }
 
-    fn call_import(&mut self, name: &str, args: &[ConstVal]) -> InterpResult {
-        panic!("Unknown import: {} with args: {:?}", name, args);
+    /// Write the current state of the module's defined memories,
+    /// tables, and globals back into `module` as its initial state.
+    /// Imported entities are left alone, since their state belongs to
+    /// the embedder. Each memory gets a single segment holding its
+    /// whole image; `data_segments::compact()` removes the zeroes.
+    pub fn snapshot_into(&self, module: &mut Module<'_>) {
+        let imported = module
+            .imports
+            .iter()
+            .map(|import| import.kind.clone())
+            .collect::<HashSet<_>>();
+
+        for (memory, data) in module.memories.entries_mut() {
+            if imported.contains(&ImportKind::Memory(memory)) {
+                continue;
+            }
+            let image = &self.memories[memory].data;
+            data.initial_pages = image.len() / WASM_PAGE;
+            data.segments = vec![MemorySegment {
+                offset: 0,
+                data: image.clone(),
+            }];
+        }
+        for (table, data) in module.tables.entries_mut() {
+            if imported.contains(&ImportKind::Table(table)) {
+                continue;
+            }
+            let elements = &self.tables[table].elements;
+            data.initial = std::cmp::max(data.initial, elements.len() as u64);
+            data.func_elements = Some(elements.clone());
+        }
+        for (global, data) in module.globals.entries_mut() {
+            if imported.contains(&ImportKind::Global(global)) {
+                continue;
+            }
+            if let Some(bits) = self.globals[global].bits() {
+                data.value = Some(bits);
+                data.init = None;
+            }
+        }
+    }
+
+    /// Pass the taints and symbolic values of a call's args, if
+    /// tracking them.
+    fn enter_call(
+        &mut self,
+        module: &Module<'_>,
+        body: &FunctionBody,
+        frame: &InterpStackFrame,
+        inst: Value,
+        callee: Func,
+        args: &[Value],
+    ) {
+        if let Some(taint) = &mut self.taint {
+            let args = args
+                .iter()
+                .map(|&arg| body.resolve_alias(arg))
+                .collect::<Vec<_>>();
+            let arg_taints = args.iter().map(|&arg| frame.taint(arg, 0)).collect();
+            taint.enter_call(module, frame.func, inst, callee, &args, arg_taints);
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.transfer = match &module.funcs[callee] {
+                FuncDecl::Import(..) => vec![],
+                _ => args
+                    .iter()
+                    .map(|&arg| frame.sym(body.resolve_alias(arg), 0))
+                    .collect(),
+            };
+        }
+    }
+
+    /// Take the taints and symbolic values of a call's results
+    /// `vals`, if tracking them.
+    fn exit_call(
+        &mut self,
+        module: &Module<'_>,
+        frame: &mut InterpStackFrame,
+        inst: Value,
+        callee: Func,
+        vals: &[ConstVal],
+    ) {
+        if let Some(taint) = &mut self.taint {
+            let taints = taint.exit_call(module, frame.func, inst, callee, vals.len());
+            frame.set_taints(inst, taints);
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            let syms = symbolic.exit_call(module, frame.func, inst, callee, vals);
+            frame.set_syms(inst, syms);
+        }
+    }
+
+    /// Add `frame`, stopped at instruction `inst` of its block, to the
+    /// backtrace.
+    fn push_frame(&mut self, body: &FunctionBody, frame: &InterpStackFrame, inst: u32) {
+        let block = frame.cur_block;
+        let (pos, loc) = match body.blocks[block].insts.get(inst as usize) {
+            Some(&value) => (inst as usize, body.source_locs[value]),
+            None => (body.blocks[block].insts.len(), SourceLoc::invalid()),
+        };
+        let locals = body
+            .debug_values
+            .ranges(body)
+            .into_iter()
+            .filter(|range| range.block == block && range.insts.contains(&pos))
+            .filter_map(|range| {
+                let value = frame.values.get(&range.value)?;
+                let name = body.local_names.get(&range.local).cloned();
+                Some((range.local, name, *value.first()?))
+            })
+            .collect();
+        self.backtrace.push(BacktraceFrame {
+            func: frame.func,
+            block,
+            inst,
+            loc,
+            locals,
+        });
+    }
+
+    fn call_import(
+        &mut self,
+        module: &Module<'_>,
+        func: Func,
+        import: &Import,
+        args: &[ConstVal],
+    ) -> InterpResult {
+        if let Some(heap) = &mut self.heap {
+            if import.module == heap.monitor && heap.record(&import.name, args) {
+                return InterpResult::Ok(smallvec![]);
+            }
+        }
+        match &mut self.import_mode {
+            ImportMode::Panic => {
+                panic!("Unknown import: {} with args: {:?}", import.name, args);
+            }
+            ImportMode::Record { defaults } => {
+                let results = match defaults.get(&import.name) {
+                    Some(results) => results.clone(),
+                    None => module.signatures[module.funcs[func].sig()]
+                        .returns
+                        .iter()
+                        .map(|&ty| const_of_type(ty, 0))
+                        .collect(),
+                };
+                self.import_log.push(ImportCall {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                    args: args.to_vec(),
+                    results: results.clone(),
+                });
+                InterpResult::Ok(results.into_iter().collect())
+            }
+            ImportMode::Replay { next } => match self.import_log.get(*next) {
+                Some(call)
+                    if call.module == import.module
+                        && call.name == import.name
+                        && call.args == args =>
+                {
+                    *next += 1;
+                    InterpResult::Ok(call.results.iter().cloned().collect())
+                }
+                _ => {
+                    log::trace!("replay mismatch at {}: {:?}", next, import);
+                    self.trap_code = Some(TrapCode::ReplayMismatch);
+                    self.backtrace.clear();
+                    InterpResult::Trap(func, Block::invalid(), u32::MAX)
+                }
+            },
+        }
     }
 }
 
 impl InterpStackFrame {
+    /// The taint of output `idx` of `value`.
+    fn taint(&self, value: Value, idx: usize) -> Taint {
+        self.taints
+            .get(&value)
+            .and_then(|taints| taints.get(idx))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_taints(&mut self, value: Value, taints: Vec<Taint>) {
+        if taints.iter().all(|taint| taint.is_empty()) {
+            self.taints.remove(&value);
+        } else {
+            self.taints.insert(value, taints);
+        }
+    }
+
+    /// The symbolic value of output `idx` of `value`, if any.
+    fn sym(&self, value: Value, idx: usize) -> Option<Rc<SymExpr>> {
+        self.syms.get(&value)?.get(idx)?.clone()
+    }
+
+    fn set_syms(&mut self, value: Value, syms: Vec<Option<Rc<SymExpr>>>) {
+        if syms.iter().all(|sym| sym.is_none()) {
+            self.syms.remove(&value);
+        } else {
+            self.syms.insert(value, syms);
+        }
+    }
+
     fn apply_target(&mut self, body: &FunctionBody, target: &BlockTarget) {
         // Collect blockparam args.
         let args = target
@@ -327,11 +864,140 @@ impl InterpStackFrame {
             log::trace!("setting blockparam {} to {:?}", param, arg);
             self.values.insert(param, arg);
         }
+        let taints = target
+            .args
+            .iter()
+            .map(|&arg| self.taints.get(&body.resolve_alias(arg)).cloned())
+            .collect::<Vec<_>>();
+        for (taints, &(_, param)) in taints
+            .into_iter()
+            .zip(body.blocks[target.block].params.iter())
+        {
+            self.set_taints(param, taints.unwrap_or_default());
+        }
+        let syms = target
+            .args
+            .iter()
+            .map(|&arg| self.syms.get(&body.resolve_alias(arg)).cloned())
+            .collect::<Vec<_>>();
+        for (syms, &(_, param)) in syms
+            .into_iter()
+            .zip(body.blocks[target.block].params.iter())
+        {
+            self.set_syms(param, syms.unwrap_or_default());
+        }
         // Set current block.
         self.cur_block = target.block;
     }
 }
 
+/// The state `eval_function()` runs against: memories, tables,
+/// globals, and fuel. `InterpContext::empty()` gives a state for
+/// functions that touch none of these.
+pub type InterpState = InterpContext;
+
+/// Execute the single function `func` of `module` with `args`, against
+/// `state`, without instantiating the module. `func` may be un-expanded,
+/// in which case a copy of its body is parsed; functions it calls must
+/// be expanded. Mismatched arguments and functions without a body are
+/// errors, rather than panics, so that passes can use this as a
+/// constant-evaluation oracle; traps and fuel exhaustion are reported
+/// in the result.
+pub fn eval_function(
+    module: &Module<'_>,
+    func: Func,
+    args: &[ConstVal],
+    state: &mut InterpState,
+) -> anyhow::Result<InterpResult> {
+    match &module.funcs[func] {
+        FuncDecl::Compiled(..) => anyhow::bail!("{} is already compiled", func),
+        FuncDecl::None => anyhow::bail!("{} has no declaration", func),
+        _ => {}
+    }
+    let params = &module.signatures[module.funcs[func].sig()].params;
+    if params.len() != args.len() {
+        anyhow::bail!(
+            "{} takes {} arguments but was given {}",
+            func,
+            params.len(),
+            args.len()
+        );
+    }
+    for (i, (&ty, &arg)) in params.iter().zip(args).enumerate() {
+        let matches = matches!(
+            (ty, arg),
+            (Type::I32, ConstVal::I32(_))
+                | (Type::I64, ConstVal::I64(_))
+                | (Type::F32, ConstVal::F32(_))
+                | (Type::F64, ConstVal::F64(_))
+        );
+        if !matches {
+            anyhow::bail!(
+                "Argument {} of {} is {:?}, not of type {}",
+                i,
+                func,
+                arg,
+                ty
+            );
+        }
+    }
+
+    if let Some(taint) = &mut state.taint {
+        taint.transfer.clear();
+    }
+    if let Some(symbolic) = &mut state.symbolic {
+        symbolic.start(args);
+    }
+    Ok(match &module.funcs[func] {
+        FuncDecl::Lazy(..) => {
+            let body = module.clone_and_expand_body(func)?;
+            state.call_body(module, func, &body, args)
+        }
+        _ => state.call_inner(module, func, args),
+    })
+}
+
+fn const_of_type(ty: Type, bits: u64) -> ConstVal {
+    match ty {
+        Type::I32 => ConstVal::I32(bits as u32),
+        Type::I64 => ConstVal::I64(bits),
+        Type::F32 => ConstVal::F32(bits as u32),
+        Type::F64 => ConstVal::F64(bits),
+        Type::V128 => ConstVal::V128(bits as u128),
+        Type::FuncRef | Type::TypedFuncRef(..) => ConstVal::Ref(Func::invalid()),
+    }
+}
+
+/// Evaluate a global initializer, given the values of the globals
+/// before it.
+fn eval_init(init: &InitExpr, globals: &PerEntity<Global, ConstVal>) -> ConstVal {
+    match init {
+        &InitExpr::Const(ty, bits) => const_of_type(ty, bits),
+        &InitExpr::GlobalGet(global) => globals[global],
+        InitExpr::Binary(op, a, b) => match (op, eval_init(a, globals), eval_init(b, globals)) {
+            (InitOp::I32Add, ConstVal::I32(a), ConstVal::I32(b)) => {
+                ConstVal::I32(a.wrapping_add(b))
+            }
+            (InitOp::I32Sub, ConstVal::I32(a), ConstVal::I32(b)) => {
+                ConstVal::I32(a.wrapping_sub(b))
+            }
+            (InitOp::I32Mul, ConstVal::I32(a), ConstVal::I32(b)) => {
+                ConstVal::I32(a.wrapping_mul(b))
+            }
+            (InitOp::I64Add, ConstVal::I64(a), ConstVal::I64(b)) => {
+                ConstVal::I64(a.wrapping_add(b))
+            }
+            (InitOp::I64Sub, ConstVal::I64(a), ConstVal::I64(b)) => {
+                ConstVal::I64(a.wrapping_sub(b))
+            }
+            (InitOp::I64Mul, ConstVal::I64(a), ConstVal::I64(b)) => {
+                ConstVal::I64(a.wrapping_mul(b))
+            }
+            _ => ConstVal::None,
+        },
+    }
+}
+
 impl ConstVal {
     pub fn as_u32(self) -> Option<u32> {
         match self {
@@ -340,6 +1006,34 @@ impl ConstVal {
         }
     }
 
+    /// The value's bits, zero-extended to 64 bits, as stored in
+    /// `GlobalData::value`.
+    pub fn bits(self) -> Option<u64> {
+        match self {
+            Self::I32(x) | Self::F32(x) => Some(x as u64),
+            Self::I64(x) | Self::F64(x) => Some(x),
+            Self::V128(..) | Self::Ref(..) | Self::ExternRef(..) | Self::None => None,
+        }
+    }
+
+    /// The IR type of the value, if it has one. References have none,
+    /// as a reference value does not determine its static type.
+    pub fn ty(self) -> Option<Type> {
+        match self {
+            Self::I32(..) => Some(Type::I32),
+            Self::I64(..) => Some(Type::I64),
+            Self::F32(..) => Some(Type::F32),
+            Self::F64(..) => Some(Type::F64),
+            Self::V128(..) => Some(Type::V128),
+            Self::Ref(..) | Self::ExternRef(..) | Self::None => None,
+        }
+    }
+
+    /// Is this a null reference?
+    pub fn is_null(self) -> bool {
+        matches!(self, Self::ExternRef(None)) || self == Self::Ref(Func::invalid())
+    }
+
     pub fn meet(a: Option<ConstVal>, b: Option<ConstVal>) -> Option<ConstVal> {
         match (a, b) {
             (None, None) => None,
@@ -350,8 +1044,71 @@ impl ConstVal {
     }
 }
 
+macro_rules! const_val_conversions {
+    ($($rust:ty => $variant:ident($bits:ty), $to:expr, $from:expr;)*) => {
+        $(
+            impl From<$rust> for ConstVal {
+                fn from(value: $rust) -> ConstVal {
+                    let to: fn($rust) -> $bits = $to;
+                    ConstVal::$variant(to(value))
+                }
+            }
+
+            impl TryFrom<ConstVal> for $rust {
+                type Error = anyhow::Error;
+                fn try_from(value: ConstVal) -> anyhow::Result<$rust> {
+                    let from: fn($bits) -> $rust = $from;
+                    match value {
+                        ConstVal::$variant(bits) => Ok(from(bits)),
+                        other => anyhow::bail!(
+                            "Expected {} value, got {:?}",
+                            stringify!($variant),
+                            other
+                        ),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+const_val_conversions! {
+    u32 => I32(u32), |x| x, |x| x;
+    i32 => I32(u32), |x| x as u32, |x| x as i32;
+    u64 => I64(u64), |x| x, |x| x;
+    i64 => I64(u64), |x| x as u64, |x| x as i64;
+    f32 => F32(u32), f32::to_bits, f32::from_bits;
+    f64 => F64(u64), f64::to_bits, f64::from_bits;
+    u128 => V128(u128), |x| x, |x| x;
+}
+
+impl From<Func> for ConstVal {
+    fn from(func: Func) -> ConstVal {
+        ConstVal::Ref(func)
+    }
+}
+
+impl TryFrom<ConstVal> for Func {
+    type Error = anyhow::Error;
+    /// A function reference; null is `Func::invalid()`.
+    fn try_from(value: ConstVal) -> anyhow::Result<Func> {
+        match value {
+            ConstVal::Ref(func) => Ok(func),
+            other => anyhow::bail!("Expected function reference, got {:?}", other),
+        }
+    }
+}
+
+/// Does `a` truncate to an integer in `[min, limit)`, so that a
+/// trapping conversion with those bounds does not trap?
+fn trunc_in_range(a: f64, min: f64, limit: f64) -> bool {
+    let a = a.trunc();
+    a >= min && a < limit
+}
+
 /// Constant-evaluate the given operator with the given arguments,
-/// returning a constant result if possible to know.
+/// returning a constant result if possible to know. This does not
+/// evaluate `v128.const`, whose value is in the body's `imm_pool`.
 pub fn const_eval(
     op: &Operator,
     vals: &[ConstVal],
@@ -362,6 +1119,19 @@ pub fn const_eval(
         (Operator::I64Const { value }, []) => Some(ConstVal::I64(*value)),
         (Operator::F32Const { value }, []) => Some(ConstVal::F32(*value)),
         (Operator::F64Const { value }, []) => Some(ConstVal::F64(*value)),
+        (Operator::V128Not, [ConstVal::V128(a)]) => Some(ConstVal::V128(!a)),
+        (Operator::V128And, [ConstVal::V128(a), ConstVal::V128(b)]) => Some(ConstVal::V128(a & b)),
+        (Operator::V128AndNot, [ConstVal::V128(a), ConstVal::V128(b)]) => {
+            Some(ConstVal::V128(a & !b))
+        }
+        (Operator::V128Or, [ConstVal::V128(a), ConstVal::V128(b)]) => Some(ConstVal::V128(a | b)),
+        (Operator::V128Xor, [ConstVal::V128(a), ConstVal::V128(b)]) => Some(ConstVal::V128(a ^ b)),
+        (Operator::V128Bitselect, [ConstVal::V128(a), ConstVal::V128(b), ConstVal::V128(c)]) => {
+            Some(ConstVal::V128((a & c) | (b & !c)))
+        }
+        (Operator::V128AnyTrue, [ConstVal::V128(a)]) => {
+            Some(ConstVal::I32(if *a != 0 { 1 } else { 0 }))
+        }
         (Operator::I32Eqz, [ConstVal::I32(a)]) => Some(ConstVal::I32(if *a == 0 { 1 } else { 0 })),
         (Operator::I32Eq, [ConstVal::I32(a), ConstVal::I32(b)]) => {
             Some(ConstVal::I32(if a == b { 1 } else { 0 }))
@@ -705,7 +1475,7 @@ pub fn const_eval(
 
         (Operator::I32TruncF32S, [ConstVal::F32(a)]) => {
             let a = f32::from_bits(*a);
-            if a >= (i32::MIN as f32) && a <= (i32::MAX as f32) {
+            if trunc_in_range(a as f64, -2147483648.0, 2147483648.0) {
                 Some(ConstVal::I32(a as i32 as u32))
             } else {
                 None
@@ -713,7 +1483,7 @@ pub fn const_eval(
         }
         (Operator::I32TruncF32U, [ConstVal::F32(a)]) => {
             let a = f32::from_bits(*a);
-            if a >= 0.0 && a <= (u32::MAX as f32) {
+            if trunc_in_range(a as f64, 0.0, 4294967296.0) {
                 Some(ConstVal::I32(a as u32))
             } else {
                 None
@@ -721,7 +1491,7 @@ pub fn const_eval(
         }
         (Operator::I32TruncF64S, [ConstVal::F64(a)]) => {
             let a = f64::from_bits(*a);
-            if a >= (i32::MIN as f64) && a <= (i32::MAX as f64) {
+            if trunc_in_range(a, -2147483648.0, 2147483648.0) {
                 Some(ConstVal::I32(a as i32 as u32))
             } else {
                 None
@@ -729,7 +1499,7 @@ pub fn const_eval(
         }
         (Operator::I32TruncF64U, [ConstVal::F64(a)]) => {
             let a = f64::from_bits(*a);
-            if a >= 0.0 && a <= (u32::MAX as f64) {
+            if trunc_in_range(a, 0.0, 4294967296.0) {
                 Some(ConstVal::I32(a as u32))
             } else {
                 None
@@ -738,7 +1508,7 @@ pub fn const_eval(
 
         (Operator::I64TruncF32S, [ConstVal::F32(a)]) => {
             let a = f32::from_bits(*a);
-            if a >= (i64::MIN as f32) && a <= (i64::MAX as f32) {
+            if trunc_in_range(a as f64, -9223372036854775808.0, 9223372036854775808.0) {
                 Some(ConstVal::I64(a as i64 as u64))
             } else {
                 None
@@ -746,7 +1516,7 @@ pub fn const_eval(
         }
         (Operator::I64TruncF32U, [ConstVal::F32(a)]) => {
             let a = f32::from_bits(*a);
-            if a >= 0.0 && a <= (u64::MAX as f32) {
+            if trunc_in_range(a as f64, 0.0, 18446744073709551616.0) {
                 Some(ConstVal::I64(a as u64))
             } else {
                 None
@@ -754,7 +1524,7 @@ pub fn const_eval(
         }
         (Operator::I64TruncF64S, [ConstVal::F64(a)]) => {
             let a = f64::from_bits(*a);
-            if a >= (i64::MIN as f64) && a <= (i64::MAX as f64) {
+            if trunc_in_range(a, -9223372036854775808.0, 9223372036854775808.0) {
                 Some(ConstVal::I64(a as i64 as u64))
             } else {
                 None
@@ -762,7 +1532,7 @@ pub fn const_eval(
         }
         (Operator::I64TruncF64U, [ConstVal::F64(a)]) => {
             let a = f64::from_bits(*a);
-            if a >= 0.0 && a <= (u64::MAX as f64) {
+            if trunc_in_range(a, 0.0, 18446744073709551616.0) {
                 Some(ConstVal::I64(a as u64))
             } else {
                 None
@@ -902,9 +1672,48 @@ pub fn const_eval(
             ConstVal::None
         }),
 
-        (Operator::TableGet { .. }, _)
-        | (Operator::TableSet { .. }, _)
-        | (Operator::TableGrow { .. }, _) => None,
+        (Operator::TableGet { table_index }, [ConstVal::I32(idx)]) => {
+            ctx.and_then(
+                |global| match global.tables[*table_index].elements.get(*idx as usize) {
+                    Some(&func) => Some(ConstVal::Ref(func)),
+                    None => {
+                        global.trap_code = Some(TrapCode::TableOutOfBounds);
+                        None
+                    }
+                },
+            )
+        }
+        (Operator::TableSet { table_index }, [ConstVal::I32(idx), ConstVal::Ref(func)]) => ctx
+            .and_then(
+                |global| match global.tables[*table_index].elements.get_mut(*idx as usize) {
+                    Some(elt) => {
+                        *elt = *func;
+                        Some(ConstVal::None)
+                    }
+                    None => {
+                        global.trap_code = Some(TrapCode::TableOutOfBounds);
+                        None
+                    }
+                },
+            ),
+        (Operator::TableGrow { table_index }, [ConstVal::Ref(init), ConstVal::I32(delta)]) => ctx
+            .map(|global| {
+                let table = &mut global.tables[*table_index];
+                let old = table.elements.len();
+                match old.checked_add(*delta as usize) {
+                    Some(new) if new <= table.max_elements => {
+                        table.elements.resize(new, *init);
+                        ConstVal::I32(old as u32)
+                    }
+                    _ => ConstVal::I32(u32::MAX),
+                }
+            }),
+
+        (Operator::RefNull { .. }, []) => Some(ConstVal::Ref(Func::invalid())),
+        (Operator::RefFunc { func_index }, []) => Some(ConstVal::Ref(*func_index)),
+        (Operator::RefIsNull, [value @ (ConstVal::Ref(..) | ConstVal::ExternRef(..))]) => {
+            Some(ConstVal::I32(if value.is_null() { 1 } else { 0 }))
+        }
 
         (Operator::TableSize { table_index }, []) => {
             ctx.map(|global| ConstVal::I32(global.tables[*table_index].elements.len() as u32))
@@ -914,15 +1723,42 @@ pub fn const_eval(
             ctx.map(|global| ConstVal::I32((global.memories[*mem].data.len() / WASM_PAGE) as u32))
         }
 
-        (Operator::MemoryGrow { mem }, [ConstVal::I32(amount)]) => ctx.and_then(|global| {
+        (Operator::MemoryGrow { mem }, [ConstVal::I32(amount)]) => ctx.map(|global| {
             let cur_pages = global.memories[*mem].data.len() / WASM_PAGE;
             let new_pages = cur_pages + (*amount as usize);
             if new_pages > global.memories[*mem].max_pages || new_pages > MAX_PAGES {
-                None
+                // A failed grow returns -1 rather than trapping.
+                ConstVal::I32(u32::MAX)
             } else {
                 global.memories[*mem].data.resize(new_pages * WASM_PAGE, 0);
-                Some(ConstVal::I32(cur_pages as u32))
+                ConstVal::I32(cur_pages as u32)
+            }
+        }),
+
+        (
+            Operator::MemoryCopy { dst_mem, src_mem },
+            [ConstVal::I32(dst), ConstVal::I32(src), ConstVal::I32(len)],
+        ) => ctx.and_then(|global| {
+            let (dst, src, len) = (*dst as usize, *src as usize, *len as usize);
+            if src.checked_add(len)? > global.memories[*src_mem].data.len()
+                || dst.checked_add(len)? > global.memories[*dst_mem].data.len()
+            {
+                return None;
             }
+            let bytes = global.memories[*src_mem].data[src..src + len].to_vec();
+            global.memories[*dst_mem].data[dst..dst + len].copy_from_slice(&bytes);
+            Some(ConstVal::None)
+        }),
+        (
+            Operator::MemoryFill { mem },
+            [ConstVal::I32(dst), ConstVal::I32(val), ConstVal::I32(len)],
+        ) => ctx.and_then(|global| {
+            let (dst, len) = (*dst as usize, *len as usize);
+            if dst.checked_add(len)? > global.memories[*mem].data.len() {
+                return None;
+            }
+            global.memories[*mem].data[dst..dst + len].fill(*val as u8);
+            Some(ConstVal::None)
         }),
 
         (Operator::Nop, []) => Some(ConstVal::None),
@@ -938,6 +1774,16 @@ pub fn const_eval(
                 addr,
             )))
         }),
+        (Operator::V128Load { memory }, [ConstVal::I32(addr)]) => ctx.and_then(|global| {
+            let addr = addr.checked_add(memory.offset)?;
+            if addr.checked_add(16)? > global.memories[memory.memory].data.len() as u32 {
+                return None;
+            }
+            Some(ConstVal::V128(read_u128(
+                &global.memories[memory.memory],
+                addr,
+            )))
+        }),
         (Operator::I64Load { memory }, [ConstVal::I32(addr)]) => ctx.and_then(|global| {
             let addr = addr.checked_add(memory.offset)?;
             if addr.checked_add(8)? > global.memories[memory.memory].data.len() as u32 {
@@ -1067,6 +1913,15 @@ pub fn const_eval(
                 write_u32(&mut global.memories[memory.memory], addr, *data);
                 Some(ConstVal::None)
             }),
+        (Operator::V128Store { memory }, [ConstVal::I32(addr), ConstVal::V128(data)]) => ctx
+            .and_then(|global| {
+                let addr = addr.checked_add(memory.offset)?;
+                if addr.checked_add(16)? > global.memories[memory.memory].data.len() as u32 {
+                    return None;
+                }
+                write_u128(&mut global.memories[memory.memory], addr, *data);
+                Some(ConstVal::None)
+            }),
         (Operator::I64Store { memory }, [ConstVal::I32(addr), ConstVal::I64(data)]) => ctx
             .and_then(|global| {
                 let addr = addr.checked_add(memory.offset)?;
@@ -1167,6 +2022,12 @@ pub(crate) fn read_u64(mem: &InterpMemory, addr: u32) -> u64 {
     u64::from_le_bytes(mem.data[addr..(addr + 8)].try_into().unwrap())
 }
 
+pub(crate) fn read_u128(mem: &InterpMemory, addr: u32) -> u128 {
+    use std::convert::TryInto;
+    let addr = addr as usize;
+    u128::from_le_bytes(mem.data[addr..(addr + 16)].try_into().unwrap())
+}
+
 pub(crate) fn write_u8(mem: &mut InterpMemory, addr: u32, data: u8) {
     let addr = addr as usize;
     mem.data[addr] = data;
@@ -1187,6 +2048,11 @@ pub(crate) fn write_u64(mem: &mut InterpMemory, addr: u32, data: u64) {
     mem.data[addr..(addr + 8)].copy_from_slice(&data.to_le_bytes()[..]);
 }
 
+pub(crate) fn write_u128(mem: &mut InterpMemory, addr: u32, data: u128) {
+    let addr = addr as usize;
+    mem.data[addr..(addr + 16)].copy_from_slice(&data.to_le_bytes()[..]);
+}
+
 // Min/max implementations with proper handling for negative-zero (as
 // distinct from positive-zero): see
 // https://github.com/wasmi-labs/wasmi/blob/6d3729c17e6d8bcabb8cd7fed0f6278f17f94e06/crates/core/src/value.rs#L575.
@@ -1251,3 +2117,250 @@ fn f64_max(a: f64, b: f64) -> f64 {
         f64::NAN
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testgen::wat_module;
+    use crate::FrontendOptions;
+
+    #[test]
+    fn tables_and_funcrefs() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (type $t (func (result i32)))
+                (type $u (func (param i32) (result i32)))
+                (table 2 4 funcref)
+                (elem (i32.const 0) func $one)
+                (elem declare func $two $id)
+                (func $one (type $t) i32.const 1)
+                (func $two (type $t) i32.const 2)
+                (func $id (type $u) local.get 0)
+                (func (export "call") (param i32) (result i32)
+                  local.get 0
+                  call_indirect (type $t))
+                (func (export "set_two") (param i32)
+                  local.get 0
+                  ref.func $two
+                  table.set)
+                (func (export "set_id") (param i32)
+                  local.get 0
+                  ref.func $id
+                  table.set)
+                (func (export "grow") (param i32) (result i32)
+                  ref.null $t
+                  local.get 0
+                  table.grow)
+                (func (export "size") (result i32)
+                  table.size)
+                (func (export "is_null") (param i32) (result i32)
+                  local.get 0
+                  table.get
+                  ref.is_null)
+                (func (export "get") (param i32) (result funcref)
+                  local.get 0
+                  table.get)
+                (func (export "call_ref") (param i32) (result i32)
+                  local.get 0
+                  ref.func $id
+                  call_ref $u)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let export = |name: &str| {
+            module
+                .exports
+                .iter()
+                .find_map(|export| match export.kind {
+                    ExportKind::Func(func) if export.name == name => Some(func),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let mut call = |name: &str, args: &[ConstVal]| {
+            let result = ctx.call(&module, export(name), args);
+            (result, ctx.trap_code)
+        };
+        let ok = |(result, _): (InterpResult, Option<TrapCode>)| result.ok().unwrap().to_vec();
+        let trap = |(result, code): (InterpResult, Option<TrapCode>)| {
+            assert!(matches!(result, InterpResult::Trap(..)));
+            code.unwrap()
+        };
+
+        // The table starts with `$one` and a null.
+        assert_eq!(ok(call("call", &[ConstVal::I32(0)])), [ConstVal::I32(1)]);
+        assert_eq!(
+            trap(call("call", &[ConstVal::I32(1)])),
+            TrapCode::NullReference
+        );
+        assert_eq!(
+            trap(call("call", &[ConstVal::I32(2)])),
+            TrapCode::TableOutOfBounds
+        );
+        assert_eq!(ok(call("is_null", &[ConstVal::I32(1)])), [ConstVal::I32(1)]);
+
+        // `call_indirect` sees elements set at runtime.
+        ok(call("set_two", &[ConstVal::I32(1)]));
+        assert_eq!(ok(call("call", &[ConstVal::I32(1)])), [ConstVal::I32(2)]);
+        assert_eq!(ok(call("is_null", &[ConstVal::I32(1)])), [ConstVal::I32(0)]);
+        ok(call("set_id", &[ConstVal::I32(0)]));
+        assert_eq!(
+            trap(call("call", &[ConstVal::I32(0)])),
+            TrapCode::SignatureMismatch
+        );
+        assert_eq!(
+            trap(call("set_id", &[ConstVal::I32(2)])),
+            TrapCode::TableOutOfBounds
+        );
+
+        // Growth up to the maximum, with null elements.
+        assert_eq!(ok(call("grow", &[ConstVal::I32(1)])), [ConstVal::I32(2)]);
+        assert_eq!(ok(call("size", &[])), [ConstVal::I32(3)]);
+        assert_eq!(ok(call("is_null", &[ConstVal::I32(2)])), [ConstVal::I32(1)]);
+        assert_eq!(
+            ok(call("grow", &[ConstVal::I32(2)])),
+            [ConstVal::I32(u32::MAX)]
+        );
+        assert_eq!(ok(call("size", &[])), [ConstVal::I32(3)]);
+
+        // `ref.func` refers to the function itself, and `call_ref`
+        // calls it.
+        let two = Func::from(1u32);
+        assert_eq!(ok(call("get", &[ConstVal::I32(1)])), [ConstVal::Ref(two)]);
+        assert_eq!(
+            ok(call("call_ref", &[ConstVal::I32(7)])),
+            [ConstVal::I32(7)]
+        );
+    }
+
+    #[test]
+    fn value_conversions_and_v128() {
+        assert_eq!(ConstVal::from(-1i32), ConstVal::I32(u32::MAX));
+        assert_eq!(i64::try_from(ConstVal::from(-2i64)).unwrap(), -2);
+        let nan = f32::from_bits(0x7fc0_1234);
+        let value = ConstVal::from(nan);
+        assert_eq!(value.ty(), Some(Type::F32));
+        assert_eq!(f32::try_from(value).unwrap().to_bits(), 0x7fc0_1234);
+        assert!(u32::try_from(ConstVal::from(1.0f64)).is_err());
+        assert!(ConstVal::from(Func::invalid()).is_null());
+        assert!(ConstVal::ExternRef(None).is_null());
+        assert!(!ConstVal::ExternRef(Some(0)).is_null());
+
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory 1)
+                (func (export "f") (result i32)
+                  i32.const 16
+                  v128.const i32x4 1 2 3 4
+                  v128.store
+                  i32.const 16
+                  v128.load
+                  v128.const i32x4 1 2 3 4
+                  v128.xor
+                  v128.any_true)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let f = match module.exports[0].kind {
+            ExportKind::Func(f) => f,
+            _ => unreachable!(),
+        };
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, f, &[]).ok().unwrap();
+        assert_eq!(&result[..], [ConstVal::I32(0)]);
+        assert_eq!(
+            read_u128(&ctx.memories[Memory::from(0u32)], 16),
+            0x4_0000_0003_0000_0002_0000_0001
+        );
+    }
+
+    #[test]
+    fn records_and_replays_imports() {
+        let mut module = wat_module(
+            r#"(module
+                (import "env" "get" (func $get (param i32) (result i32)))
+                (func (param i32) (result i32)
+                  local.get 0
+                  call $get
+                  local.get 0
+                  call $get
+                  i32.add))"#,
+            &FrontendOptions::default(),
+        );
+        module.expand_all_funcs().unwrap();
+        let (get, f) = (Func::new(0), Func::new(1));
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        ctx.import_mode = ImportMode::Record {
+            defaults: Default::default(),
+        };
+        let result = ctx.call(&module, f, &[ConstVal::I32(5)]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(0)]);
+        let call = ImportCall {
+            module: "env".to_owned(),
+            name: "get".to_owned(),
+            args: vec![ConstVal::I32(5)],
+            results: vec![ConstVal::I32(0)],
+        };
+        assert_eq!(ctx.import_log, vec![call.clone(), call]);
+
+        for call in &mut ctx.import_log {
+            call.results = vec![ConstVal::I32(21)];
+        }
+        ctx.import_mode = ImportMode::Replay { next: 0 };
+        let result = ctx.call(&module, f, &[ConstVal::I32(5)]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(42)]);
+
+        // The log is used up, and other args would not match it anyway.
+        let result = ctx.call(&module, f, &[ConstVal::I32(6)]);
+        assert!(matches!(result, InterpResult::Trap(func, ..) if func == get));
+        assert_eq!(ctx.backtrace.len(), 1);
+        assert_eq!(ctx.backtrace[0].func, f);
+    }
+
+    #[test]
+    fn eval_function_runs_one_func() {
+        let module = wat_module(
+            r#"(module
+                (memory 1)
+                (data (i32.const 0) "\2a")
+                (func $f (param i32 i32) (result i32)
+                  local.get 0
+                  local.get 1
+                  i32.mul
+                  i32.const 1
+                  i32.add)
+                (func $load (result i32)
+                  i32.const 0
+                  i32.load8_u))"#,
+            &FrontendOptions::default(),
+        );
+        let (f, load) = (Func::new(0), Func::new(1));
+
+        // Un-expanded bodies are parsed on the fly, and no memory is
+        // needed for a function that uses none.
+        let mut state = InterpState::empty();
+        let args = [ConstVal::I32(6), ConstVal::I32(7)];
+        let result = eval_function(&module, f, &args, &mut state).unwrap();
+        assert_eq!(&result.ok().unwrap()[..], &[ConstVal::I32(43)]);
+        assert!(matches!(module.funcs[f], FuncDecl::Lazy(..)));
+
+        // Without an instantiated memory the load traps; with one it
+        // sees the data segment.
+        let result = eval_function(&module, load, &[], &mut state).unwrap();
+        assert!(matches!(result, InterpResult::Trap(..)));
+        let mut state = InterpContext::new(&module).unwrap();
+        let result = eval_function(&module, load, &[], &mut state).unwrap();
+        assert_eq!(&result.ok().unwrap()[..], &[ConstVal::I32(42)]);
+
+        // Bad arguments are errors rather than panics.
+        assert!(eval_function(&module, f, &[ConstVal::I32(1)], &mut state).is_err());
+        let args = [ConstVal::I32(1), ConstVal::I64(2)];
+        assert!(eval_function(&module, f, &args, &mut state).is_err());
+    }
+}
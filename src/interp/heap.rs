@@ -0,0 +1,117 @@
+//! Heap statistics for the interpreter: aggregating the calls that
+//! `passes::instrument::heap()` adds to its monitor imports.
+
+use super::ConstVal;
+use std::collections::BTreeMap;
+
+/// Heap statistics: set as `InterpContext::heap` to handle calls to
+/// the monitor imports by updating these, rather than per the import
+/// mode.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The module of the monitor imports.
+    pub monitor: String,
+    /// Calls to `memory.grow`, and how many of them failed.
+    pub grows: u64,
+    pub failed_grows: u64,
+    /// Pages added by successful grows, per memory index.
+    pub pages_grown: BTreeMap<u32, u64>,
+    /// Calls to the allocator, and how many of them returned null.
+    pub allocs: u64,
+    pub failed_allocs: u64,
+    /// Calls to the deallocator with a non-null address.
+    pub frees: u64,
+    /// Frees of addresses that were not allocated.
+    pub bad_frees: u64,
+    /// Bytes allocated in all.
+    pub bytes_allocated: u64,
+    /// The largest allocation, in bytes.
+    pub largest_alloc: u32,
+    /// Live allocations: the size at each address.
+    pub live: BTreeMap<u32, u32>,
+    /// The bytes of the live allocations, now and at most.
+    pub live_bytes: u64,
+    pub peak_live_bytes: u64,
+}
+
+impl HeapStats {
+    /// Statistics for monitor imports from `monitor`.
+    pub fn new(monitor: &str) -> HeapStats {
+        HeapStats {
+            monitor: monitor.to_owned(),
+            ..HeapStats::default()
+        }
+    }
+
+    /// Record a call to the monitor import `name`. Returns whether it
+    /// is one.
+    pub(crate) fn record(&mut self, name: &str, args: &[ConstVal]) -> bool {
+        let arg = |i: usize| match args.get(i) {
+            Some(&ConstVal::I32(value)) => value,
+            _ => 0,
+        };
+        match name {
+            "grow" => {
+                self.grows += 1;
+                match arg(2) {
+                    u32::MAX => self.failed_grows += 1,
+                    _ => *self.pages_grown.entry(arg(0)).or_default() += arg(1) as u64,
+                }
+            }
+            "alloc" => {
+                let (size, address) = (arg(0), arg(1));
+                self.allocs += 1;
+                if address == 0 {
+                    self.failed_allocs += 1;
+                    return true;
+                }
+                self.bytes_allocated += size as u64;
+                self.largest_alloc = self.largest_alloc.max(size);
+                if let Some(old) = self.live.insert(address, size) {
+                    // Allocated again without a free that we saw.
+                    self.live_bytes -= old as u64;
+                }
+                self.live_bytes += size as u64;
+                self.peak_live_bytes = self.peak_live_bytes.max(self.live_bytes);
+            }
+            "free" => match arg(0) {
+                0 => {}
+                address => {
+                    self.frees += 1;
+                    match self.live.remove(&address) {
+                        Some(size) => self.live_bytes -= size as u64,
+                        None => self.bad_frees += 1,
+                    }
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl std::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "memory.grow: {} calls, {} failed",
+            self.grows, self.failed_grows
+        )?;
+        for (memory, pages) in &self.pages_grown {
+            writeln!(f, "  memory {}: grew {} pages", memory, pages)?;
+        }
+        writeln!(
+            f,
+            "alloc: {} calls, {} failed, {} bytes, largest {}",
+            self.allocs, self.failed_allocs, self.bytes_allocated, self.largest_alloc
+        )?;
+        writeln!(f, "free: {} calls, {} bad", self.frees, self.bad_frees)?;
+        writeln!(
+            f,
+            "live: {} allocations, {} bytes, peak {} bytes",
+            self.live.len(),
+            self.live_bytes,
+            self.peak_live_bytes
+        )
+    }
+}
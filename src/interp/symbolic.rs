@@ -0,0 +1,421 @@
+//! Concolic execution for the interpreter: symbolic expressions for
+//! integer values alongside their concrete values, and the path
+//! constraints of the branches they decide.
+
+use super::{const_eval, ConstVal, ImportMode, InterpContext, InterpResult};
+use crate::entity::EntityRef;
+use crate::ir::{Block, Func, FuncDecl, Module, Value};
+use crate::ops::Operator;
+use crate::SideEffect;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+/// A symbolic expression over the inputs of a run.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SymExpr {
+    /// An input, by index into `SymbolicTracker::inputs`.
+    Input(usize),
+    /// A concrete value.
+    Const(ConstVal),
+    /// An operator applied to operands.
+    Op(Operator, Vec<Rc<SymExpr>>),
+}
+
+impl SymExpr {
+    /// Evaluate the expression with the given input values, or `None`
+    /// if it traps.
+    pub fn eval(&self, inputs: &[ConstVal]) -> Option<ConstVal> {
+        match self {
+            SymExpr::Input(idx) => inputs.get(*idx).cloned(),
+            SymExpr::Const(val) => Some(*val),
+            SymExpr::Op(op, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(inputs))
+                    .collect::<Option<Vec<_>>>()?;
+                const_eval(op, &args[..], None)
+            }
+        }
+    }
+}
+
+impl Display for SymExpr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SymExpr::Input(idx) => write!(f, "in{}", idx),
+            SymExpr::Const(val) => write!(f, "{:?}", val),
+            SymExpr::Op(op, args) => {
+                write!(f, "({}", op)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Where an input came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymInputKind {
+    /// An argument of the top-level call.
+    Arg(usize),
+    /// A result of a call to the import `name`, made by the call
+    /// instruction `inst` in `func`.
+    Import {
+        name: String,
+        func: Func,
+        inst: Value,
+    },
+}
+
+/// An input of a run, with the concrete value it had.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymInput {
+    pub kind: SymInputKind,
+    pub value: ConstVal,
+}
+
+/// The kind of a branch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    /// A conditional branch, taken on a nonzero condition.
+    Cond,
+    /// A select among the given number of targets, with any larger
+    /// index taking the default.
+    Select(usize),
+}
+
+/// A branch decided by a symbolic value, with the way it went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathConstraint {
+    pub func: Func,
+    /// The block whose terminator branched.
+    pub block: Block,
+    pub kind: BranchKind,
+    /// The branch's condition or index.
+    pub expr: Rc<SymExpr>,
+    /// The concrete value of `expr` on this path.
+    pub value: ConstVal,
+}
+
+impl PathConstraint {
+    /// Whether the branch goes the same way with the given input
+    /// values, or `None` if `expr` traps.
+    pub fn holds(&self, inputs: &[ConstVal]) -> Option<bool> {
+        let value = self.expr.eval(inputs)?.as_u32()?;
+        let recorded = self.value.as_u32()?;
+        Some(match self.kind {
+            BranchKind::Cond => (value != 0) == (recorded != 0),
+            BranchKind::Select(n) => {
+                std::cmp::min(value as usize, n) == std::cmp::min(recorded as usize, n)
+            }
+        })
+    }
+}
+
+/// The concolic engine: set as `InterpContext::symbolic` to track
+/// symbolic values and collect path constraints while interpreting.
+///
+/// Only integer operators without side-effects other than trapping
+/// produce symbolic results; any other operator falls back to its
+/// concrete result. Memory, globals and tables hold only concrete
+/// values.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolicTracker {
+    /// Whether the args of the top-level call are inputs.
+    pub symbolic_args: bool,
+    /// Imports (by name) whose results are inputs.
+    pub symbolic_imports: HashSet<String>,
+    /// The inputs, in the order they were introduced.
+    pub inputs: Vec<SymInput>,
+    /// The branches decided by symbolic values, in execution order.
+    pub constraints: Vec<PathConstraint>,
+    /// The symbolic values of the args of the call being entered, or
+    /// of the results of the call that just returned.
+    pub(crate) transfer: Vec<Option<Rc<SymExpr>>>,
+}
+
+impl SymbolicTracker {
+    fn input(&mut self, kind: SymInputKind, value: ConstVal) -> Rc<SymExpr> {
+        self.inputs.push(SymInput { kind, value });
+        Rc::new(SymExpr::Input(self.inputs.len() - 1))
+    }
+
+    /// Set up the args of a top-level call.
+    pub(crate) fn start(&mut self, args: &[ConstVal]) {
+        self.transfer = if self.symbolic_args {
+            args.iter()
+                .enumerate()
+                .map(|(i, &arg)| Some(self.input(SymInputKind::Arg(i), arg)))
+                .collect()
+        } else {
+            vec![]
+        };
+    }
+
+    /// The symbolic values of the results of the last top-level call.
+    pub fn results(&self) -> &[Option<Rc<SymExpr>>] {
+        &self.transfer[..]
+    }
+
+    /// The symbolic result of `op` with operands `args`, whose
+    /// concrete values are `arg_vals` and result is `result`.
+    pub(crate) fn op(
+        &self,
+        op: &Operator,
+        args: &[Option<Rc<SymExpr>>],
+        arg_vals: &[ConstVal],
+        result: ConstVal,
+    ) -> Option<Rc<SymExpr>> {
+        let is_int = |val: &ConstVal| matches!(val, ConstVal::I32(_) | ConstVal::I64(_));
+        if args.iter().all(|arg| arg.is_none())
+            || !op.effects().iter().all(|&e| e == SideEffect::Trap)
+            || !arg_vals.iter().all(is_int)
+            || !is_int(&result)
+        {
+            return None;
+        }
+        let args = args
+            .iter()
+            .zip(arg_vals)
+            .map(|(arg, &val)| arg.clone().unwrap_or_else(|| Rc::new(SymExpr::Const(val))))
+            .collect();
+        Some(Rc::new(SymExpr::Op(*op, args)))
+    }
+
+    /// Record a branch of `kind` at the end of `block` in `func`,
+    /// decided by `expr` with concrete value `value`.
+    pub(crate) fn branch(
+        &mut self,
+        func: Func,
+        block: Block,
+        kind: BranchKind,
+        expr: Option<Rc<SymExpr>>,
+        value: ConstVal,
+    ) {
+        if let Some(expr) = expr {
+            self.constraints.push(PathConstraint {
+                func,
+                block,
+                kind,
+                expr,
+                value,
+            });
+        }
+    }
+
+    /// The symbolic values of the results `vals` of the call by `inst`
+    /// in `func` to `callee`, after it returns.
+    pub(crate) fn exit_call(
+        &mut self,
+        module: &Module<'_>,
+        func: Func,
+        inst: Value,
+        callee: Func,
+        vals: &[ConstVal],
+    ) -> Vec<Option<Rc<SymExpr>>> {
+        match &module.funcs[callee] {
+            FuncDecl::Import(..) => {
+                let name = &module.imports[callee.index()].name;
+                if !self.symbolic_imports.contains(name) {
+                    return vec![None; vals.len()];
+                }
+                vals.iter()
+                    .map(|&val| {
+                        let kind = SymInputKind::Import {
+                            name: name.clone(),
+                            func,
+                            inst,
+                        };
+                        Some(self.input(kind, val))
+                    })
+                    .collect()
+            }
+            _ => std::mem::take(&mut self.transfer),
+        }
+    }
+}
+
+/// A solver for path constraints.
+pub trait Solver {
+    /// Find values for `inputs` under which every constraint in
+    /// `prefix` holds and `flip` does not, or `None` if there are none
+    /// or the solver gives up.
+    fn solve(
+        &mut self,
+        inputs: &[SymInput],
+        prefix: &[PathConstraint],
+        flip: &PathConstraint,
+    ) -> Option<Vec<ConstVal>>;
+}
+
+/// Options for `explore()`.
+#[derive(Clone, Debug)]
+pub struct ExploreOptions {
+    /// The maximum number of runs.
+    pub max_runs: usize,
+    /// Interpreter fuel for each run.
+    pub fuel: u64,
+}
+
+impl Default for ExploreOptions {
+    fn default() -> Self {
+        ExploreOptions {
+            max_runs: 100,
+            fuel: 1_000_000,
+        }
+    }
+}
+
+/// One run of `explore()`.
+#[derive(Clone, Debug)]
+pub struct ExploreRun {
+    pub args: Vec<ConstVal>,
+    pub result: InterpResult,
+    pub constraints: Vec<PathConstraint>,
+}
+
+/// Explore the paths through `func` by concolic execution, starting
+/// from `args`: after each run, ask `solver` for args that flip each
+/// branch not yet flipped on the way to it (generational search).
+/// Only the args are inputs; each run starts from a fresh
+/// `InterpContext` that records calls to imports.
+pub fn explore(
+    module: &Module<'_>,
+    func: Func,
+    args: &[ConstVal],
+    solver: &mut dyn Solver,
+    options: &ExploreOptions,
+) -> anyhow::Result<Vec<ExploreRun>> {
+    let mut runs = vec![];
+    let mut seen = HashSet::new();
+    let mut worklist = vec![(args.to_vec(), 0)];
+    seen.insert(args.to_vec());
+    while let Some((args, bound)) = worklist.pop() {
+        if runs.len() == options.max_runs {
+            break;
+        }
+        let mut ctx = InterpContext::new(module)?;
+        ctx.fuel = options.fuel;
+        ctx.import_mode = ImportMode::Record {
+            defaults: Default::default(),
+        };
+        ctx.symbolic = Some(SymbolicTracker {
+            symbolic_args: true,
+            ..SymbolicTracker::default()
+        });
+        let result = ctx.call(module, func, &args[..]);
+        let tracker = ctx.symbolic.take().unwrap();
+        for i in bound..tracker.constraints.len() {
+            let (prefix, rest) = tracker.constraints.split_at(i);
+            if let Some(new_args) = solver.solve(&tracker.inputs, prefix, &rest[0]) {
+                if seen.insert(new_args.clone()) {
+                    worklist.push((new_args, i + 1));
+                }
+            }
+        }
+        log::trace!(
+            "explore: args {:?} gave {:?} with {} constraints",
+            args,
+            result,
+            tracker.constraints.len()
+        );
+        runs.push(ExploreRun {
+            args,
+            result,
+            constraints: tracker.constraints,
+        });
+    }
+    Ok(runs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, FunctionBody, SignatureData, Terminator, Type};
+
+    /// Tries the values 0 to 999 for the first input.
+    struct BruteForce;
+
+    impl Solver for BruteForce {
+        fn solve(
+            &mut self,
+            _: &[SymInput],
+            prefix: &[PathConstraint],
+            flip: &PathConstraint,
+        ) -> Option<Vec<ConstVal>> {
+            (0..1000).map(|x| vec![ConstVal::I32(x)]).find(|inputs| {
+                prefix.iter().all(|c| c.holds(inputs) == Some(true))
+                    && flip.holds(inputs) == Some(false)
+            })
+        }
+    }
+
+    #[test]
+    fn explore_flips_branches() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        // f(x) = if x + 5 == 12 { 1 } else { 0 }
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let five = body.add_op(entry, Operator::I32Const { value: 5 }, &[], &[Type::I32]);
+        let sum = body.add_op(entry, Operator::I32Add, &[x, five], &[Type::I32]);
+        let twelve = body.add_op(entry, Operator::I32Const { value: 12 }, &[], &[Type::I32]);
+        let cond = body.add_op(entry, Operator::I32Eq, &[sum, twelve], &[Type::I32]);
+        let ret = |body: &mut FunctionBody, value| {
+            let block = body.add_block();
+            let value = body.add_op(block, Operator::I32Const { value }, &[], &[Type::I32]);
+            body.set_terminator(
+                block,
+                Terminator::Return {
+                    values: vec![value],
+                },
+            );
+            BlockTarget {
+                block,
+                args: vec![],
+            }
+        };
+        let if_true = ret(&mut body, 1);
+        let if_false = ret(&mut body, 0);
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            },
+        );
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let runs = explore(
+            &module,
+            f,
+            &[ConstVal::I32(0)],
+            &mut BruteForce,
+            &ExploreOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[1].args, vec![ConstVal::I32(7)]);
+        let results = runs
+            .iter()
+            .map(|run| run.result.clone().ok().unwrap()[0])
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![ConstVal::I32(0), ConstVal::I32(1)]);
+        let constraint = &runs[0].constraints[0];
+        assert_eq!(constraint.value, ConstVal::I32(0));
+        assert_eq!(
+            constraint.expr.to_string(),
+            "(i32eq (i32add in0 I32(5)) I32(12))"
+        );
+    }
+}
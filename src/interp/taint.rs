@@ -0,0 +1,465 @@
+//! Taint tracking for the interpreter: which values and memory bytes
+//! derive from selected inputs, and which of them reach selected
+//! sinks.
+
+use super::ConstVal;
+use crate::entity::EntityRef;
+use crate::ir::{Func, FuncDecl, Global, Memory, Module, Value};
+use crate::ops::Operator;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// A set of taint labels, each an index into `TaintTracker::sources`.
+pub type Taint = BTreeSet<usize>;
+
+/// Where a taint label came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaintSource {
+    /// The results of a call to the import `name`, made by the call
+    /// instruction `inst` in `func`.
+    Import {
+        name: String,
+        func: Func,
+        inst: Value,
+    },
+    /// A memory range marked with `TaintTracker::taint_memory()`.
+    Memory {
+        memory: Memory,
+        start: u32,
+        len: u32,
+    },
+}
+
+/// A kind of sink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaintSink {
+    /// The stored value of a store.
+    Store,
+    /// An argument of a call to the import with the given name.
+    Import(String),
+}
+
+/// A tainted value reaching a sink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaintFlow {
+    pub sink: TaintSink,
+    /// The function containing the sink instruction.
+    pub func: Func,
+    /// The sink instruction: the store or call.
+    pub inst: Value,
+    /// The tainted operand of `inst`.
+    pub value: Value,
+    /// The labels of `value`'s taint.
+    pub taint: Taint,
+}
+
+/// The taint engine: set as `InterpContext::taint` to track taint
+/// while interpreting.
+///
+/// Taint propagates from operands to results of every operator, through
+/// blockparams, calls and returns, and through memory and globals.
+/// It does not propagate through control flow: a value computed under
+/// a tainted branch condition is not itself tainted.
+#[derive(Clone, Debug, Default)]
+pub struct TaintTracker {
+    /// Imports (by name) whose results are tainted.
+    pub source_imports: HashSet<String>,
+    /// Imports (by name) whose tainted arguments are reported.
+    pub sink_imports: HashSet<String>,
+    /// Whether tainted values stored to memory are reported.
+    pub sink_stores: bool,
+    /// The source of each label.
+    pub sources: Vec<TaintSource>,
+    /// Tainted values that reached sinks, in execution order.
+    pub flows: Vec<TaintFlow>,
+    /// The taint of each tainted memory byte.
+    memory: HashMap<(Memory, u32), Taint>,
+    /// The taint of each tainted global.
+    globals: HashMap<Global, Taint>,
+    /// The taints of the args of the call being entered, or of the
+    /// results of the call that just returned.
+    pub(crate) transfer: Vec<Taint>,
+}
+
+impl TaintTracker {
+    /// Taint `len` bytes of `memory` starting at `start` with a new
+    /// label, and return the label.
+    pub fn taint_memory(&mut self, memory: Memory, start: u32, len: u32) -> usize {
+        let label = self.sources.len();
+        self.sources
+            .push(TaintSource::Memory { memory, start, len });
+        for addr in start..start.saturating_add(len) {
+            self.memory.entry((memory, addr)).or_default().insert(label);
+        }
+        label
+    }
+
+    /// The taint of `len` bytes of `memory` starting at `start`.
+    pub fn memory_taint(&self, memory: Memory, start: u32, len: u32) -> Taint {
+        (start..start.saturating_add(len))
+            .filter_map(|addr| self.memory.get(&(memory, addr)))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    fn set_byte_taint(&mut self, memory: Memory, addr: u32, taint: Taint) {
+        if taint.is_empty() {
+            self.memory.remove(&(memory, addr));
+        } else {
+            self.memory.insert((memory, addr), taint);
+        }
+    }
+
+    /// The taints of the results of the last top-level call.
+    pub fn result_taints(&self) -> &[Taint] {
+        &self.transfer[..]
+    }
+
+    /// Propagate taint through `op`, executed by `inst` in `func`
+    /// with operands `args`, and return the taint of its result.
+    pub(crate) fn op(
+        &mut self,
+        func: Func,
+        inst: Value,
+        op: &Operator,
+        args: &[Value],
+        arg_vals: &[ConstVal],
+        arg_taints: &[Taint],
+    ) -> Taint {
+        if let Some((memory, len)) = op.memory_access() {
+            let addr = arg_vals[0].as_u32().unwrap().wrapping_add(memory.offset);
+            if op.is_load() {
+                return self.memory_taint(memory.memory, addr, len as u32);
+            }
+            let taint = &arg_taints[1];
+            if self.sink_stores && !taint.is_empty() {
+                self.flows.push(TaintFlow {
+                    sink: TaintSink::Store,
+                    func,
+                    inst,
+                    value: args[1],
+                    taint: taint.clone(),
+                });
+            }
+            let taint = taint.clone();
+            for addr in addr..addr.saturating_add(len as u32) {
+                self.set_byte_taint(memory.memory, addr, taint.clone());
+            }
+            return Taint::default();
+        }
+        match op {
+            Operator::MemoryCopy { dst_mem, src_mem } => {
+                let dst = arg_vals[0].as_u32().unwrap();
+                let src = arg_vals[1].as_u32().unwrap();
+                let len = arg_vals[2].as_u32().unwrap();
+                // Read every source byte first, as the ranges may
+                // overlap.
+                let taints = (src..src + len)
+                    .map(|addr| self.memory.get(&(*src_mem, addr)).cloned())
+                    .collect::<Vec<_>>();
+                for (addr, taint) in (dst..dst + len).zip(taints) {
+                    self.set_byte_taint(*dst_mem, addr, taint.unwrap_or_default());
+                }
+                Taint::default()
+            }
+            Operator::MemoryFill { mem } => {
+                let dst = arg_vals[0].as_u32().unwrap();
+                let len = arg_vals[2].as_u32().unwrap();
+                for addr in dst..dst + len {
+                    self.set_byte_taint(*mem, addr, arg_taints[1].clone());
+                }
+                Taint::default()
+            }
+            Operator::GlobalGet { global_index } => {
+                self.globals.get(global_index).cloned().unwrap_or_default()
+            }
+            Operator::GlobalSet { global_index } => {
+                if arg_taints[0].is_empty() {
+                    self.globals.remove(global_index);
+                } else {
+                    self.globals.insert(*global_index, arg_taints[0].clone());
+                }
+                Taint::default()
+            }
+            _ => arg_taints.iter().flatten().cloned().collect(),
+        }
+    }
+
+    /// Record a call by `inst` in `func` to `callee` with operands
+    /// `args`, before it is made.
+    pub(crate) fn enter_call(
+        &mut self,
+        module: &Module<'_>,
+        func: Func,
+        inst: Value,
+        callee: Func,
+        args: &[Value],
+        arg_taints: Vec<Taint>,
+    ) {
+        match &module.funcs[callee] {
+            FuncDecl::Import(..) => {
+                let name = &module.imports[callee.index()].name;
+                if self.sink_imports.contains(name) {
+                    for (&value, taint) in args.iter().zip(arg_taints) {
+                        if !taint.is_empty() {
+                            self.flows.push(TaintFlow {
+                                sink: TaintSink::Import(name.clone()),
+                                func,
+                                inst,
+                                value,
+                                taint,
+                            });
+                        }
+                    }
+                }
+                self.transfer.clear();
+            }
+            _ => self.transfer = arg_taints,
+        }
+    }
+
+    /// The taints of the `n` results of the call by `inst` in `func`
+    /// to `callee`, after it returns.
+    pub(crate) fn exit_call(
+        &mut self,
+        module: &Module<'_>,
+        func: Func,
+        inst: Value,
+        callee: Func,
+        n: usize,
+    ) -> Vec<Taint> {
+        match &module.funcs[callee] {
+            FuncDecl::Import(..) => {
+                let name = &module.imports[callee.index()].name;
+                if !self.source_imports.contains(name) {
+                    return vec![Taint::default(); n];
+                }
+                let label = self.sources.len();
+                self.sources.push(TaintSource::Import {
+                    name: name.clone(),
+                    func,
+                    inst,
+                });
+                vec![Taint::from([label]); n]
+            }
+            _ => std::mem::take(&mut self.transfer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{
+        BlockTarget, FunctionBody, Import, ImportKind, MemoryData, SignatureData, Terminator, Type,
+    };
+    use crate::{ImportMode, InterpContext, MemoryArg};
+
+    #[test]
+    fn taint_reaches_sinks() {
+        let mut module = Module::empty();
+        let memory = module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![],
+        });
+        let source_sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        let sink_sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![],
+        });
+        let double_sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let import = |module: &mut Module, sig, name: &str| {
+            let func = module.funcs.push(FuncDecl::Import(sig, name.to_owned()));
+            module.imports.push(Import {
+                module: "env".to_owned(),
+                name: name.to_owned(),
+                kind: ImportKind::Func(func),
+            });
+            func
+        };
+        let input = import(&mut module, source_sig, "input");
+        let send = import(&mut module, sink_sig, "send");
+
+        let mut body = FunctionBody::new(&module, double_sig);
+        let x = body.blocks[body.entry].params[0].1;
+        let doubled = body.add_op(body.entry, Operator::I32Add, &[x, x], &[Type::I32]);
+        body.set_terminator(
+            body.entry,
+            Terminator::Return {
+                values: vec![doubled],
+            },
+        );
+        let double =
+            module
+                .funcs
+                .push(FuncDecl::Body(double_sig, "double".to_owned(), body.into()));
+
+        // f() { a = input(); mem[4] = a; send(double(mem[4])); send(3); send(mem[100]) }
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let next = body.add_block();
+        let arg = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory,
+        };
+        let call = |func| Operator::Call {
+            function_index: func,
+        };
+        let a = body.add_op(entry, call(input), &[], &[Type::I32]);
+        let four = body.add_op(entry, Operator::I32Const { value: 4 }, &[], &[Type::I32]);
+        let store = body.add_op(entry, Operator::I32Store { memory: arg }, &[four, a], &[]);
+        let loaded = body.add_op(
+            entry,
+            Operator::I32Load { memory: arg },
+            &[four],
+            &[Type::I32],
+        );
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: next,
+                    args: vec![loaded],
+                },
+            },
+        );
+        let p = body.add_blockparam(next, Type::I32);
+        let d = body.add_op(next, call(double), &[p], &[Type::I32]);
+        let send_d = body.add_op(next, call(send), &[d], &[]);
+        let three = body.add_op(next, Operator::I32Const { value: 3 }, &[], &[Type::I32]);
+        body.add_op(next, call(send), &[three], &[]);
+        let hundred = body.add_op(next, Operator::I32Const { value: 100 }, &[], &[Type::I32]);
+        let m = body.add_op(
+            next,
+            Operator::I32Load { memory: arg },
+            &[hundred],
+            &[Type::I32],
+        );
+        let send_m = body.add_op(next, call(send), &[m], &[]);
+        body.set_terminator(next, Terminator::Return { values: vec![] });
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        ctx.import_mode = ImportMode::Record {
+            defaults: Default::default(),
+        };
+        let mut tracker = TaintTracker {
+            source_imports: HashSet::from(["input".to_owned()]),
+            sink_imports: HashSet::from(["send".to_owned()]),
+            sink_stores: true,
+            ..TaintTracker::default()
+        };
+        assert_eq!(tracker.taint_memory(memory, 100, 4), 0);
+        ctx.taint = Some(tracker);
+        ctx.call(&module, f, &[]).ok().unwrap();
+
+        let tracker = ctx.taint.unwrap();
+        assert_eq!(
+            tracker.sources[1],
+            TaintSource::Import {
+                name: "input".to_owned(),
+                func: f,
+                inst: a,
+            }
+        );
+        let flow = |sink, inst, value, label| TaintFlow {
+            sink,
+            func: f,
+            inst,
+            value,
+            taint: Taint::from([label]),
+        };
+        let send_sink = TaintSink::Import("send".to_owned());
+        assert_eq!(
+            tracker.flows,
+            vec![
+                flow(TaintSink::Store, store, a, 1),
+                flow(send_sink.clone(), send_d, d, 1),
+                flow(send_sink, send_m, m, 0),
+            ]
+        );
+        assert_eq!(tracker.memory_taint(memory, 0, 8), Taint::from([1]));
+    }
+
+    /// Run the one function defined by `body`, after `env.input`
+    /// (taint label 0) and with one memory, and return the tracker.
+    fn run(body: &str) -> TaintTracker {
+        let wat = format!(
+            r#"(module
+                (import "env" "input" (func $input (result i32)))
+                (memory 1)
+                (func {}))"#,
+            body
+        );
+        let bytes = wat::parse_str(wat).unwrap();
+        let mut module =
+            Module::from_wasm_bytes(&bytes, &crate::FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let mut ctx = InterpContext::new(&module).unwrap();
+        ctx.import_mode = ImportMode::Record {
+            defaults: Default::default(),
+        };
+        ctx.taint = Some(TaintTracker {
+            source_imports: HashSet::from(["input".to_owned()]),
+            ..TaintTracker::default()
+        });
+        ctx.call(&module, Func::new(1), &[]).ok().unwrap();
+        ctx.taint.unwrap()
+    }
+
+    #[test]
+    fn memory_copy_moves_taint() {
+        // Bytes 4..8 are tainted, then bytes 0..8 are copied to 6..14.
+        let tracker = run(r#"
+            i32.const 4
+            call $input
+            i32.store
+            i32.const 6
+            i32.const 0
+            i32.const 8
+            memory.copy"#);
+        let memory = Memory::new(0);
+        let taint = |start, len| tracker.memory_taint(memory, start, len);
+        assert!(taint(0, 4).is_empty());
+        assert_eq!(taint(4, 2), Taint::from([0]));
+        // The copy overwrites tainted bytes 6..8 with untainted ones.
+        assert!(taint(6, 4).is_empty());
+        assert_eq!(taint(10, 4), Taint::from([0]));
+        assert!(taint(14, 4).is_empty());
+    }
+
+    #[test]
+    fn memory_fill_sets_taint() {
+        // Bytes 0..8 are filled with a tainted value, then bytes 2..4
+        // with an untainted one.
+        let tracker = run(r#"
+            i32.const 0
+            call $input
+            i32.const 8
+            memory.fill
+            i32.const 2
+            i32.const 0
+            i32.const 2
+            memory.fill"#);
+        let memory = Memory::new(0);
+        let taint = |start, len| tracker.memory_taint(memory, start, len);
+        assert_eq!(taint(0, 2), Taint::from([0]));
+        assert!(taint(2, 2).is_empty());
+        assert_eq!(taint(4, 4), Taint::from([0]));
+        assert!(taint(8, 4).is_empty());
+    }
+}
@@ -61,6 +61,47 @@ impl From<wasmparser::RefType> for Type {
     }
 }
 
+impl Type {
+    /// Is this a reference type?
+    pub fn is_ref(self) -> bool {
+        matches!(self, Type::FuncRef | Type::TypedFuncRef(..))
+    }
+
+    /// Can a value of this type be used where `other` is expected?
+    /// Types are subtypes of themselves; a typed function reference
+    /// is a subtype of `funcref`, and a non-nullable one is a subtype
+    /// of the nullable reference to the same signature.
+    pub fn is_subtype_of(self, other: Type) -> bool {
+        match (self, other) {
+            _ if self == other => true,
+            (Type::TypedFuncRef(..), Type::FuncRef) => true,
+            (Type::TypedFuncRef(nullable, sig), Type::TypedFuncRef(other_nullable, other_sig)) => {
+                sig == other_sig && (other_nullable || !nullable)
+            }
+            _ => false,
+        }
+    }
+
+    /// The least upper bound of two types: the most specific type of
+    /// which both are subtypes, or `None` if they have no common
+    /// supertype.
+    pub fn lub(self, other: Type) -> Option<Type> {
+        match (self, other) {
+            _ if self.is_subtype_of(other) => Some(other),
+            _ if other.is_subtype_of(self) => Some(self),
+            (Type::TypedFuncRef(nullable, sig), Type::TypedFuncRef(other_nullable, other_sig))
+                if sig == other_sig =>
+            {
+                Some(Type::TypedFuncRef(nullable || other_nullable, sig))
+            }
+            (Type::FuncRef | Type::TypedFuncRef(..), Type::FuncRef | Type::TypedFuncRef(..)) => {
+                Some(Type::FuncRef)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -128,6 +169,8 @@ declare_entity!(Block, "block");
 declare_entity!(Local, "local");
 // An SSA value in one function body.
 declare_entity!(Value, "v");
+// A 128-bit immediate in one function body's `imm_pool`.
+declare_entity!(V128Imm, "imm");
 
 mod module;
 pub use module::*;
@@ -139,3 +182,9 @@ mod display;
 pub use display::*;
 mod debug;
 pub use debug::*;
+mod typecheck;
+pub use typecheck::*;
+mod builder;
+pub use builder::*;
+mod heap_size;
+pub use heap_size::HeapSize;
@@ -0,0 +1,131 @@
+//! A cursor-style helper for emitting code into a function body.
+
+use super::{Block, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::{Func, Operator, Signature, Table};
+
+/// Appends instructions to a `FunctionBody` at a current block.
+///
+/// The builder starts at the body's entry block. Each method that
+/// emits an instruction appends it to the current block; a terminator
+/// ends the block, and `switch_to_block()` moves to another one.
+pub struct FunctionBuilder<'b> {
+    /// The body being built.
+    pub body: &'b mut FunctionBody,
+    block: Block,
+    params: Vec<Value>,
+}
+
+impl<'b> FunctionBuilder<'b> {
+    /// Create a builder positioned at the entry of `body`.
+    pub fn new(body: &'b mut FunctionBody) -> FunctionBuilder<'b> {
+        let block = body.entry;
+        let params = body.blocks[block]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .collect();
+        FunctionBuilder {
+            body,
+            block,
+            params,
+        }
+    }
+
+    /// The function's parameters.
+    pub fn params(&self) -> &[Value] {
+        &self.params[..]
+    }
+
+    /// The block instructions are appended to.
+    pub fn current_block(&self) -> Block {
+        self.block
+    }
+
+    /// Create a new, empty block.
+    pub fn add_block(&mut self) -> Block {
+        self.body.add_block()
+    }
+
+    /// Append further instructions to `block`.
+    pub fn switch_to_block(&mut self, block: Block) {
+        self.block = block;
+    }
+
+    /// Append an operator with the given arguments and result types.
+    pub fn op(&mut self, op: Operator, args: &[Value], tys: &[Type]) -> Value {
+        self.body.add_op(self.block, op, args, tys)
+    }
+
+    /// Append a call to `func`, whose results have types `rets`, and
+    /// return its results, one value each.
+    pub fn call(&mut self, func: Func, args: &[Value], rets: &[Type]) -> Vec<Value> {
+        let call = Operator::Call {
+            function_index: func,
+        };
+        self.call_op(call, args, rets)
+    }
+
+    /// Append an indirect call through `table`, with signature `sig`,
+    /// to the function at `index`, and return its results, one value
+    /// each.
+    pub fn call_indirect(
+        &mut self,
+        sig: Signature,
+        table: Table,
+        index: Value,
+        args: &[Value],
+        rets: &[Type],
+    ) -> Vec<Value> {
+        let call = Operator::CallIndirect {
+            sig_index: sig,
+            table_index: table,
+        };
+        let args = args
+            .iter()
+            .copied()
+            .chain(std::iter::once(index))
+            .collect::<Vec<_>>();
+        self.call_op(call, &args, rets)
+    }
+
+    fn call_op(&mut self, call: Operator, args: &[Value], rets: &[Type]) -> Vec<Value> {
+        let call = self.op(call, args, rets);
+        match rets.len() {
+            0 => vec![],
+            1 => vec![call],
+            _ => rets
+                .iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let pick = self
+                        .body
+                        .add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    self.body.append_to_block(self.block, pick);
+                    pick
+                })
+                .collect(),
+        }
+    }
+
+    /// End the current block with `terminator`.
+    pub fn terminate(&mut self, terminator: Terminator) {
+        self.body.set_terminator(self.block, terminator);
+    }
+
+    /// End the current block by returning `values`.
+    pub fn ret(&mut self, values: &[Value]) {
+        self.terminate(Terminator::Return {
+            values: values.to_vec(),
+        });
+    }
+
+    /// End the current block by branching to `block` with `args`.
+    pub fn br(&mut self, block: Block, args: &[Value]) {
+        self.terminate(Terminator::Br {
+            target: BlockTarget {
+                block,
+                args: args.to_vec(),
+            },
+        });
+    }
+}
@@ -1,10 +1,15 @@
-//! Debug info (currently, source-location maps).
+//! Debug info: source-location maps, and the SSA values that hold
+//! the original Wasm locals.
 
+use super::heap_size::{btree_map_size, entity_vec_size, hash_map_size, string_size, vec_size};
+use super::{Block, Func, FunctionBody, Local, Value};
+use crate::cfg::CFGInfo;
 use crate::declare_entity;
-use crate::entity::EntityVec;
+use crate::entity::{EntityRef, EntityVec, PerEntity};
 use addr2line::gimli;
 use std::collections::hash_map::Entry as HashEntry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
 
 declare_entity!(SourceFile, "file");
 declare_entity!(SourceLoc, "loc");
@@ -19,6 +24,10 @@ pub struct Debug {
     /// by a `SourceLoc` entity index.
     pub source_locs: EntityVec<SourceLoc, SourceLocData>,
     source_loc_dedup: HashMap<SourceLocData, SourceLoc>,
+    /// Original names of functions' locals, from the name section or
+    /// DWARF. Expanding a function body copies its names into
+    /// `FunctionBody::local_names`.
+    pub local_names: BTreeMap<Func, BTreeMap<Local, String>>,
 }
 
 /// A "source location": a filename (interned to an ID), a line, and a
@@ -53,6 +62,26 @@ impl Debug {
             HashEntry::Occupied(o) => *o.get(),
         }
     }
+
+    /// The number of bytes of heap memory the interning tables and
+    /// local names hold.
+    pub fn heap_size(&self) -> usize {
+        let names = |names: &BTreeMap<Local, String>| {
+            btree_map_size(names) + names.values().map(string_size).sum::<usize>()
+        };
+        entity_vec_size(&self.source_files)
+            + self.source_files.values().map(string_size).sum::<usize>()
+            + hash_map_size(&self.source_file_dedup)
+            + self
+                .source_file_dedup
+                .keys()
+                .map(string_size)
+                .sum::<usize>()
+            + entity_vec_size(&self.source_locs)
+            + hash_map_size(&self.source_loc_dedup)
+            + btree_map_size(&self.local_names)
+            + self.local_names.values().map(names).sum::<usize>()
+    }
 }
 
 /// A map from ranges of offsets in the original Wasm file to source
@@ -67,6 +96,11 @@ pub struct DebugMap {
 }
 
 impl DebugMap {
+    /// The number of bytes of heap memory the map holds.
+    pub fn heap_size(&self) -> usize {
+        vec_size(&self.tuples)
+    }
+
     pub(crate) fn from_dwarf<R: gimli::Reader>(
         dwarf: gimli::Dwarf<R>,
         debug: &mut Debug,
@@ -99,3 +133,282 @@ impl DebugMap {
         })
     }
 }
+
+/// Find the names of Wasm locals in DWARF: the parameters and
+/// variables whose location is a local (`DW_OP_WASM_location 0x00
+/// <index>`). Each is returned with the low PC of its function,
+/// relative to the code section.
+pub(crate) fn dwarf_local_names<R: gimli::Reader>(
+    dwarf: &gimli::Dwarf<R>,
+) -> anyhow::Result<Vec<(u64, Local, String)>> {
+    let mut names = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        // The low PCs of the enclosing functions, with their depths.
+        let mut funcs: Vec<(isize, u64)> = vec![];
+        let mut depth = 0;
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            while funcs.last().is_some_and(|&(d, _)| d >= depth) {
+                funcs.pop();
+            }
+            match entry.tag() {
+                gimli::DW_TAG_subprogram => {
+                    if let Some(low_pc) = entry.attr_value(gimli::DW_AT_low_pc)? {
+                        if let Some(pc) = dwarf.attr_address(&unit, low_pc)? {
+                            funcs.push((depth, pc));
+                        }
+                    }
+                }
+                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                    let pc = match funcs.last() {
+                        Some(&(_, pc)) => pc,
+                        None => continue,
+                    };
+                    let name = match entry.attr_value(gimli::DW_AT_name)? {
+                        Some(name) => dwarf.attr_string(&unit, name)?,
+                        None => continue,
+                    };
+                    let mut expr = match entry.attr_value(gimli::DW_AT_location)? {
+                        Some(gimli::AttributeValue::Exprloc(expr)) => expr.0,
+                        _ => continue,
+                    };
+                    if expr.read_u8()? == gimli::DW_OP_WASM_location.0 && expr.read_u8()? == 0 {
+                        let index = expr.read_uleb128()?;
+                        let name = name.to_string_lossy()?.into_owned();
+                        names.push((pc, Local::new(index as usize), name));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The SSA values that hold a function's original Wasm locals, for
+/// emitting variable locations in debug info.
+///
+/// The map is a list of entries in program order, each saying that
+/// from some point on, a local holds a value. A local keeps its value
+/// until the next entry for it, including across blocks; where a
+/// block's predecessors disagree on it, its value is unknown until
+/// the next entry. `ranges()` resolves the map against a body.
+///
+/// Entries refer to instructions rather than positions, so they stay
+/// meaningful as passes transform the body: an entry placed after an
+/// instruction moves with it to another block, aliases are resolved,
+/// and a value that is no longer computed makes its local's value
+/// unknown. Passes that renumber blocks or values must update the map
+/// with `renumber()`.
+#[derive(Clone, Debug, Default)]
+pub struct DebugValueMap {
+    entries: Vec<DebugValueEntry>,
+}
+
+/// One entry in a `DebugValueMap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugValueEntry {
+    /// The block the entry was recorded in. If `after` is no longer
+    /// in any block, the entry applies here, at the point of the
+    /// previous entry for this block.
+    pub block: Block,
+    /// The instruction after which the local holds the value, or
+    /// `None` for the start of `block`.
+    pub after: Option<Value>,
+    /// The original local.
+    pub local: Local,
+    /// The value it holds.
+    pub value: Value,
+}
+
+/// A range of a block over which a local holds a value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugValueRange {
+    pub block: Block,
+    /// Indices of the block's instructions over which the local holds
+    /// the value; index `insts.len()` stands for the terminator.
+    pub insts: Range<usize>,
+    pub local: Local,
+    pub value: Value,
+}
+
+impl DebugValueMap {
+    /// Record that `local` holds `value` from just after `after` in
+    /// `block`, or from its start.
+    pub fn record(&mut self, block: Block, after: Option<Value>, local: Local, value: Value) {
+        self.entries.push(DebugValueEntry {
+            block,
+            after,
+            local,
+            value,
+        });
+    }
+
+    /// The entries, in program order.
+    pub fn entries(&self) -> &[DebugValueEntry] {
+        &self.entries[..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of bytes of heap memory the map holds.
+    pub fn heap_size(&self) -> usize {
+        vec_size(&self.entries)
+    }
+
+    /// Rewrite the map for a body whose blocks and values have been
+    /// renumbered. Entries in blocks that map to `Block::invalid()`
+    /// are dropped, and values that map to `Value::invalid()` become
+    /// unknown.
+    pub fn renumber(
+        &self,
+        blocks: &PerEntity<Block, Block>,
+        values: impl Fn(Value) -> Value,
+    ) -> DebugValueMap {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| blocks[entry.block].is_valid())
+            .map(|entry| DebugValueEntry {
+                block: blocks[entry.block],
+                after: entry.after.map(&values).filter(|v| v.is_valid()),
+                local: entry.local,
+                value: values(entry.value),
+            })
+            .collect();
+        DebugValueMap { entries }
+    }
+
+    /// Rewrite the values the locals hold with `values`.
+    pub fn map_values(mut self, values: impl Fn(Value) -> Value) -> DebugValueMap {
+        for entry in &mut self.entries {
+            entry.value = values(entry.value);
+        }
+        self
+    }
+
+    /// Resolve the map against `body`: for each block reachable from
+    /// the entry, the ranges over which each local holds a known value.
+    pub fn ranges(&self, body: &FunctionBody) -> Vec<DebugValueRange> {
+        let cfg = CFGInfo::new(body);
+
+        // Where each placed value is, and the entries of each block at
+        // their instruction positions (see `DebugValueRange`).
+        let mut placed: HashMap<Value, (Block, usize)> = HashMap::new();
+        for &block in cfg.rpo.values() {
+            let def = &body.blocks[block];
+            for &(_, param) in &def.params {
+                placed.insert(param, (block, 0));
+            }
+            for (i, &inst) in def.insts.iter().enumerate() {
+                placed.insert(inst, (block, i + 1));
+            }
+        }
+        let mut last_pos: HashMap<Block, usize> = HashMap::new();
+        let mut block_entries: PerEntity<Block, Vec<(usize, Local, Option<Value>)>> =
+            PerEntity::default();
+        for entry in &self.entries {
+            let (block, pos) = match entry.after {
+                None => (entry.block, 0),
+                Some(after) => match placed.get(&after) {
+                    Some(&place) => place,
+                    None => (
+                        entry.block,
+                        last_pos.get(&entry.block).copied().unwrap_or(0),
+                    ),
+                },
+            };
+            last_pos.insert(block, pos);
+            let value = body.resolve_alias(entry.value);
+            let value = match placed.contains_key(&value) {
+                true => Some(value),
+                false => None,
+            };
+            block_entries[block].push((pos, entry.local, value));
+        }
+        for &block in cfg.rpo.values() {
+            block_entries[block].sort_by_key(|&(pos, ..)| pos);
+        }
+
+        // Propagate the locals' values forward to a fixpoint. A block
+        // with no state yet has not been reached.
+        type State = BTreeMap<Local, Value>;
+        let apply = |state: &mut State, entries: &[(usize, Local, Option<Value>)]| {
+            for &(_, local, value) in entries {
+                match value {
+                    Some(value) => state.insert(local, value),
+                    None => state.remove(&local),
+                };
+            }
+        };
+        let mut block_in: PerEntity<Block, Option<State>> = PerEntity::default();
+        let mut block_out: PerEntity<Block, Option<State>> = PerEntity::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in cfg.rpo.values() {
+                let state = match block == body.entry {
+                    true => State::new(),
+                    false => {
+                        let mut preds = cfg.preds[block]
+                            .iter()
+                            .filter_map(|&pred| block_out[pred].as_ref());
+                        let mut state = match preds.next() {
+                            Some(state) => state.clone(),
+                            None => continue,
+                        };
+                        for other in preds {
+                            state.retain(|local, value| other.get(local) == Some(value));
+                        }
+                        state
+                    }
+                };
+                if block_in[block].as_ref() == Some(&state) {
+                    continue;
+                }
+                let mut out = state.clone();
+                apply(&mut out, &block_entries[block]);
+                block_in[block] = Some(state);
+                block_out[block] = Some(out);
+                changed = true;
+            }
+        }
+
+        let mut ranges = vec![];
+        for &block in cfg.rpo.values() {
+            let mut open: BTreeMap<Local, (usize, Value)> = block_in[block]
+                .iter()
+                .flatten()
+                .map(|(&local, &value)| (local, (0, value)))
+                .collect();
+            let end = body.blocks[block].insts.len() + 1;
+            let mut close = |local: Local, start: usize, pos: usize, value: Value| {
+                if pos > start {
+                    ranges.push(DebugValueRange {
+                        block,
+                        insts: start..pos,
+                        local,
+                        value,
+                    });
+                }
+            };
+            for &(pos, local, value) in &block_entries[block] {
+                if let Some((start, old)) = open.remove(&local) {
+                    close(local, start, pos, old);
+                }
+                if let Some(value) = value {
+                    open.insert(local, (pos, value));
+                }
+            }
+            for (local, (start, value)) in open {
+                close(local, start, end, value);
+            }
+        }
+        ranges
+    }
+}
@@ -1,7 +1,8 @@
 //! Displaying IR.
 
-use super::{Func, FuncDecl, FunctionBody, Module, SourceLoc, ValueDef};
+use super::{Func, FuncDecl, FunctionBody, Module, SourceLoc, Value, ValueDef};
 use crate::entity::EntityRef;
+use crate::Operator;
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter, Result as FmtResult};
 
@@ -98,7 +99,7 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                         "{}    {} = {} {} # {} ",
                         self.indent,
                         value,
-                        op,
+                        self.op(op),
                         self.body.arg_pool[*args]
                             .iter()
                             .map(|arg| format!("{}", arg))
@@ -144,7 +145,7 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                 self.indent,
                 block_id,
                 block_params.join(", "),
-                block.desc
+                block.origin
             )?;
 
             if let Some(decorator) = self.decorator {
@@ -158,7 +159,7 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                 block
                     .preds
                     .iter()
-                    .map(|pred| format!("{} ({})", pred, self.body.blocks[*pred].desc))
+                    .map(|pred| format!("{} ({})", pred, self.body.blocks[*pred].origin))
                     .collect::<Vec<_>>()
                     .join(", ")
             )?;
@@ -169,18 +170,18 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                 block
                     .succs
                     .iter()
-                    .map(|succ| format!("{} ({})", succ, self.body.blocks[*succ].desc))
+                    .map(|succ| format!("{} ({})", succ, self.body.blocks[*succ].origin))
                     .collect::<Vec<_>>()
                     .join(", ")
             )?;
             for (_, param) in &block.params {
-                if let Some(local) = self.body.value_locals[*param] {
-                    writeln!(f, "{}    # {}: {}", self.indent, param, local)?;
+                if let Some(note) = self.local_note(*param) {
+                    writeln!(f, "{}    # {}: {}", self.indent, param, note)?;
                 }
             }
             for &inst in &block.insts {
-                if let Some(local) = self.body.value_locals[inst] {
-                    writeln!(f, "{}    # {}: {}", self.indent, inst, local)?;
+                if let Some(note) = self.local_note(inst) {
+                    writeln!(f, "{}    # {}: {}", self.indent, inst, note)?;
                 }
                 match &self.body.values[inst] {
                     ValueDef::Operator(op, args, tys) => {
@@ -192,23 +193,16 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                             .iter()
                             .map(|&ty| format!("{}", ty))
                             .collect::<Vec<_>>();
-                        let loc = if self.body.source_locs[inst] != SourceLoc::invalid()
-                            && self.module.is_some()
-                        {
-                            let module = self.module.as_ref().unwrap();
-                            let loc = self.body.source_locs[inst];
-                            let data = &module.debug.source_locs[loc];
-                            let filename = &module.debug.source_files[data.file];
-                            format!("@{} {}:{}:{}", loc, filename, data.line, data.col)
-                        } else {
-                            "".to_owned()
-                        };
+                        let mut loc = self.loc(inst);
+                        if let Some(offset) = self.body.wasm_offsets[inst] {
+                            loc = format!("wasm@{:#x} {}", offset, loc);
+                        }
                         write!(
                             f,
                             "{}    {} = {} {} # {} {} ",
                             self.indent,
                             inst,
-                            op,
+                            self.op(op),
                             args.join(", "),
                             tys.join(", "),
                             loc,
@@ -221,9 +215,12 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
                     ValueDef::PickOutput(val, idx, ty) => {
                         writeln!(f, "{}    {} = {}.{} # {}", self.indent, inst, val, idx, ty)?;
                     }
-                    ValueDef::Alias(val) => {
-                        writeln!(f, "{}    {} = {}", self.indent, inst, val)?;
-                    }
+                    ValueDef::Alias(val) => match self.loc(inst) {
+                        loc if loc.is_empty() => {
+                            writeln!(f, "{}    {} = {}", self.indent, inst, val)?;
+                        }
+                        loc => writeln!(f, "{}    {} = {} # {}", self.indent, inst, val, loc)?,
+                    },
                     _ => unreachable!(),
                 }
             }
@@ -242,6 +239,50 @@ impl<'a, PD: PrintDecorator> Display for FunctionBodyDisplay<'a, PD> {
     }
 }
 
+impl<'a, PD: PrintDecorator> FunctionBodyDisplay<'a, PD> {
+    /// `op`, with the values of its pooled immediates.
+    fn op(&self, op: &Operator) -> String {
+        match *op {
+            Operator::V128Const { value } => {
+                format!("v128const<{}>", self.body.imm_pool[value])
+            }
+            Operator::I8x16Shuffle { lanes } => {
+                format!("i8x16shuffle<{:?}>", self.body.imm_pool.lanes(lanes))
+            }
+            _ => op.to_string(),
+        }
+    }
+
+    /// The source location of `value`, if it has one and there is a
+    /// module to look it up in.
+    fn loc(&self, value: Value) -> String {
+        let loc = self.body.source_locs[value];
+        match self.module {
+            Some(module) if loc != SourceLoc::invalid() => {
+                let data = &module.debug.source_locs[loc];
+                let filename = &module.debug.source_files[data.file];
+                format!("@{} {}:{}:{}", loc, filename, data.line, data.col)
+            }
+            _ => "".to_owned(),
+        }
+    }
+
+    /// The local that `value` carries, with the local's original name
+    /// and `value`'s location, if any.
+    fn local_note(&self, value: Value) -> Option<String> {
+        let local = self.body.value_locals[value]?;
+        let mut note = local.to_string();
+        if let Some(name) = self.body.local_names.get(&local) {
+            note += &format!(" \"{}\"", name);
+        }
+        let loc = self.loc(value);
+        if !loc.is_empty() {
+            note += &format!(" {}", loc);
+        }
+        Some(note)
+    }
+}
+
 pub struct ModuleDisplay<'a, PD: PrintDecorator> {
     pub(crate) module: &'a Module<'a>,
     pub(crate) decorators: Option<Box<dyn Fn(Func) -> PD>>,
@@ -270,11 +311,14 @@ impl<'a, PD: PrintDecorator> Display for ModuleDisplay<'a, PD> {
             writeln!(f, "  {}: {}", sig, sig_str)?;
         }
         for (global, global_data) in self.module.globals.entries() {
-            writeln!(
-                f,
-                "  {}: {:?} # {}",
-                global, global_data.value, global_data.ty
-            )?;
+            match &global_data.init {
+                Some(init) => writeln!(f, "  {}: {:?} # {}", global, init, global_data.ty)?,
+                None => writeln!(
+                    f,
+                    "  {}: {:?} # {}",
+                    global, global_data.value, global_data.ty
+                )?,
+            }
         }
         for (table, table_data) in self.module.tables.entries() {
             writeln!(f, "  {}: {}", table, table_data.ty)?;
@@ -1,18 +1,24 @@
 use super::{
-    Block, FunctionBodyDisplay, Local, Module, NOPPrintDecorator, PrintDecorator, Signature, Type,
-    Value, ValueDef,
+    Block, Func, FunctionBodyDisplay, Local, Module, NOPPrintDecorator, PrintDecorator, Signature,
+    Type, Value, ValueDef,
 };
-use crate::backend::WasmFuncBackend;
-use crate::cfg::CFGInfo;
+use crate::backend::frame::FrameInfo;
+use crate::backend::{BackendOptions, EmitInfo, WasmFuncBackend};
+use crate::cfg::{BlockOrder, CFGInfo};
 use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::frontend::parse_body;
-use crate::ir::SourceLoc;
+use crate::ir::{DebugValueMap, SourceLoc};
 use crate::passes::basic_opt::OptOptions;
-use crate::pool::{ListPool, ListRef};
+use crate::passes::maxssa::CutBlocks;
+use crate::passes::source_locs::LocChecker;
+use crate::pool::{ImmPool, ListPool, ListRef};
 use crate::Operator;
 use anyhow::Result;
 use fxhash::FxHashMap;
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// A declaration of a function: there is one `FuncDecl` per `Func`
 /// index.
@@ -20,6 +26,13 @@ use std::collections::HashSet;
 /// `FuncDecl` represents the various forms in which we can hold a
 /// function body: not yet parsed, parsed into full IR, recompiled
 /// into new bytecode, or an import (none of the above).
+///
+/// An IR body is held behind an `Arc` and is copy-on-write: cloning a
+/// `FuncDecl` (or taking `Module::shared_body()`) shares the body,
+/// and mutating it through `body_mut()` copies it first if it is
+/// shared. So analyses can hold snapshots of some bodies, on any
+/// thread, while the module's owner mutates other bodies or even the
+/// same ones; a snapshot never observes later mutations.
 #[derive(Clone, Debug, Default)]
 pub enum FuncDecl<'a> {
     /// An imported function.
@@ -27,7 +40,7 @@ pub enum FuncDecl<'a> {
     /// An un-expanded body that can be lazily expanded if needed.
     Lazy(Signature, String, wasmparser::FunctionBody<'a>),
     /// A modified or new function body that requires compilation.
-    Body(Signature, String, FunctionBody),
+    Body(Signature, String, Arc<FunctionBody>),
     /// A compiled function body (was IR, has been collapsed back to bytecode).
     Compiled(Signature, String, Vec<u8>),
     /// A placeholder.
@@ -53,7 +66,7 @@ impl<'a> FuncDecl<'a> {
         match self {
             FuncDecl::Lazy(sig, name, body) => {
                 let body = parse_body(module, *sig, body)?;
-                *self = FuncDecl::Body(*sig, name.clone(), body);
+                *self = FuncDecl::Body(*sig, name.clone(), Arc::new(body));
                 Ok(())
             }
             _ => Ok(()),
@@ -64,7 +77,7 @@ impl<'a> FuncDecl<'a> {
     pub fn optimize(&mut self, opts: &OptOptions) {
         match self {
             FuncDecl::Body(_, _, body) => {
-                body.optimize(opts);
+                Arc::make_mut(body).optimize(opts);
             }
             _ => {}
         }
@@ -81,15 +94,33 @@ impl<'a> FuncDecl<'a> {
     /// predecessor edges) cut-blocks in a max-SSA transform
     /// beforehand will ensure that simply connecting blockparams
     /// appropriately will reconnect all SSA.
+    ///
+    /// `passes::maxssa::CutBlocks` computes the usual cut-sets.
     pub fn convert_to_max_ssa(&mut self, cut_blocks: Option<HashSet<Block>>) {
         match self {
             FuncDecl::Body(_, _, body) => {
-                body.convert_to_max_ssa(cut_blocks);
+                Arc::make_mut(body).convert_to_max_ssa(cut_blocks);
             }
             _ => {}
         }
     }
 
+    /// Compute a hash of the function's code: the structural hash of
+    /// an expanded body (see `FunctionBody::structural_hash()`), a
+    /// hash of the bytecode of a lazy or compiled one, or zero for an
+    /// import.
+    pub fn content_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = fxhash::FxHasher64::default();
+        match self {
+            FuncDecl::Body(_, _, body) => return body.structural_hash(),
+            FuncDecl::Lazy(_, _, body) => body.as_bytes().hash(&mut hasher),
+            FuncDecl::Compiled(_, _, bytes) => bytes.hash(&mut hasher),
+            FuncDecl::Import(..) | FuncDecl::None => return 0,
+        }
+        hasher.finish()
+    }
+
     /// Return the function body, if it exists.
     pub fn body(&self) -> Option<&FunctionBody> {
         match self {
@@ -98,10 +129,11 @@ impl<'a> FuncDecl<'a> {
         }
     }
 
-    /// Return the function body, if it exists, in mutable form.
+    /// Return the function body, if it exists, in mutable form. This
+    /// copies the body first if it is shared.
     pub fn body_mut(&mut self) -> Option<&mut FunctionBody> {
         match self {
-            FuncDecl::Body(_, _, body) => Some(body),
+            FuncDecl::Body(_, _, body) => Some(Arc::make_mut(body)),
             _ => None,
         }
     }
@@ -157,7 +189,9 @@ pub struct FunctionBody {
     pub locals: EntityVec<Local, Type>,
     /// Entry block.
     pub entry: Block,
-    /// Block bodies.
+    /// Block bodies, in creation order, which is also their iteration
+    /// order. This includes unreachable blocks and says nothing about
+    /// control flow; `rpo()` gives a control-flow order.
     pub blocks: EntityVec<Block, BlockDef>,
     /// Value definitions, indexed by `Value`.
     pub values: EntityVec<Value, ValueDef>,
@@ -167,12 +201,74 @@ pub struct FunctionBody {
     pub single_type_dedup: FxHashMap<Type, ListRef<Type>>,
     /// Pool of values for ValueDefs' arg lists.
     pub arg_pool: ListPool<Value>,
+    /// Pool of the 128-bit immediates of SIMD operators.
+    pub imm_pool: ImmPool,
     /// Blocks in which values are computed. Each may be `Block::invalid()` if not placed.
     pub value_blocks: PerEntity<Value, Block>,
     /// Wasm locals that values correspond to, if any.
     pub value_locals: PerEntity<Value, Option<Local>>,
     /// Debug source locations of each value.
+    ///
+    /// Passes that create instructions must give them a location:
+    /// an instruction that replaces or clones another takes its
+    /// location (`copy_loc()`), and one that combines several takes
+    /// their merged location (`merge_locs()`). Only constants, which
+    /// carry no meaningful position, may be left without one.
+    /// `passes::source_locs::LocChecker` flags violations.
     pub source_locs: PerEntity<Value, SourceLoc>,
+    /// The byte offset in the original module of the Wasm instruction
+    /// each operator came from, if `FrontendOptions::wasm_offsets` was
+    /// set. Unlike `source_locs`, these refer to the bytecode rather
+    /// than to source code. They move with `source_locs` through
+    /// `copy_loc()` and `merge_locs()`.
+    pub wasm_offsets: PerEntity<Value, Option<u32>>,
+    /// The values that hold the original Wasm locals, for debug info.
+    /// Unlike `value_locals`, this survives transforms; see
+    /// `DebugValueMap`.
+    pub debug_values: DebugValueMap,
+    /// Original names of locals, from the name section or DWARF.
+    pub local_names: BTreeMap<Local, String>,
+    /// Generations and the cached block order; see `generation()`
+    /// and `compute_rpo()`.
+    pub(crate) tracking: Tracking,
+}
+
+/// The generations of a body and of its CFG, and a `BlockOrder`
+/// cached for the entry block it was computed from. A generation of
+/// zero means the body has changed since a generation was last
+/// handed out; a fresh one is then taken from a global counter, so
+/// that no two versions of any bodies share one.
+#[derive(Debug, Default)]
+pub(crate) struct Tracking {
+    body: AtomicU64,
+    cfg: AtomicU64,
+    order: Mutex<Option<(Block, Arc<BlockOrder>)>>,
+}
+
+impl Clone for Tracking {
+    fn clone(&self) -> Self {
+        Tracking {
+            body: AtomicU64::new(self.body.load(Ordering::Relaxed)),
+            cfg: AtomicU64::new(self.cfg.load(Ordering::Relaxed)),
+            order: Mutex::new(self.order.lock().unwrap().clone()),
+        }
+    }
+}
+
+/// The current generation in `counter`, taking a fresh one if it
+/// has been reset.
+fn current_generation(counter: &AtomicU64) -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    match counter.load(Ordering::Relaxed) {
+        0 => {
+            let fresh = NEXT.fetch_add(1, Ordering::Relaxed);
+            match counter.compare_exchange(0, fresh, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => fresh,
+                Err(current) => current,
+            }
+        }
+        generation => generation,
+    }
 }
 
 impl FunctionBody {
@@ -202,18 +298,45 @@ impl FunctionBody {
             values,
             type_pool: ListPool::default(),
             arg_pool: ListPool::default(),
+            imm_pool: ImmPool::default(),
             single_type_dedup: FxHashMap::default(),
             value_blocks,
             value_locals: PerEntity::default(),
             source_locs: PerEntity::default(),
+            wasm_offsets: PerEntity::default(),
+            debug_values: DebugValueMap::default(),
+            local_names: BTreeMap::default(),
+            tracking: Tracking::default(),
         }
     }
 
     /// Optimize this function given the options in `opts`.
     pub fn optimize(&mut self, opts: &OptOptions) {
-        let cfg = crate::cfg::CFGInfo::new(self);
-        crate::passes::basic_opt::basic_opt(self, &cfg, opts);
-        crate::passes::empty_blocks::run(self);
+        self.optimize_func(None, opts);
+    }
+
+    /// Optimize this function, which is `func` named `name` if known,
+    /// given the options in `opts`. The function's identity selects
+    /// it for `opts.pass_debug`.
+    pub(crate) fn optimize_func(&mut self, func: Option<(Func, &str)>, opts: &OptOptions) {
+        let checker = cfg!(debug_assertions).then(|| LocChecker::new(self));
+        let mut seq = 0;
+        let mut after = |body: &FunctionBody, pass: &str| {
+            if let Some(debug) = &opts.pass_debug {
+                debug.after_pass(func, seq, pass, body);
+            }
+            seq += 1;
+        };
+        after(self, "input");
+        for pass in opts.pipeline() {
+            pass.run(self, opts);
+            after(self, pass.name());
+        }
+        if let Some(checker) = checker {
+            for value in checker.check(self) {
+                log::warn!("optimize: new value {} has no source location", value);
+            }
+        }
     }
 
     /// Perform a maximal-SSA transform on this function. See comments
@@ -221,15 +344,53 @@ impl FunctionBody {
     pub fn convert_to_max_ssa(&mut self, cut_blocks: Option<HashSet<Block>>) {
         let cfg = crate::cfg::CFGInfo::new(self);
         crate::passes::maxssa::run(self, cut_blocks, &cfg);
+        self.mark_changed();
+    }
+
+    /// Perform a maximal-SSA transform on this function, cutting at
+    /// the blocks `cut_blocks` selects.
+    pub fn convert_to_max_ssa_with(&mut self, cut_blocks: &CutBlocks) {
+        let cfg = crate::cfg::CFGInfo::new(self);
+        let cut_blocks = cut_blocks.blocks(self, &cfg);
+        crate::passes::maxssa::run(self, cut_blocks, &cfg);
+        self.mark_changed();
+    }
+
+    /// Remove the blockparams that dominance makes unnecessary,
+    /// undoing a maximal-SSA transform. See
+    /// `passes::maxssa::minimize_blockparams()`.
+    pub fn minimize_blockparams(&mut self) -> usize {
+        crate::passes::maxssa::minimize_blockparams(self)
+    }
+
+    /// Reserve capacity for `values` more values and `blocks` more
+    /// blocks, with their operands and per-value state, so that
+    /// building a body of known size allocates each container once.
+    pub fn reserve(&mut self, values: usize, blocks: usize) {
+        self.values.reserve(values);
+        self.blocks.reserve(blocks);
+        let total = self.values.len() + values;
+        self.value_blocks.reserve(total);
+        self.source_locs.reserve(total);
+        self.arg_pool.reserve(2 * values);
     }
 
     /// Add a new, empty block and return its ID.
     pub fn add_block(&mut self) -> Block {
+        self.mark_values_changed();
         let id = self.blocks.push(BlockDef::default());
         log::trace!("add_block: block {}", id);
         id
     }
 
+    /// Add a new, empty block with the given origin and return its
+    /// ID.
+    pub fn add_block_with_origin(&mut self, origin: BlockOrigin) -> Block {
+        let id = self.add_block();
+        self.blocks[id].origin = origin;
+        id
+    }
+
     /// Convenience: intern a single type as a
     /// result-type-list. Caches and deduplicates to minimize
     /// type-pool growth.
@@ -246,6 +407,7 @@ impl FunctionBody {
     /// they can be (re)computed in bulk with
     /// `FunctionBody::recompute_edges()` if necessary.
     pub fn add_edge(&mut self, from: Block, to: Block) {
+        self.mark_changed();
         let succ_pos = self.blocks[from].succs.len();
         let pred_pos = self.blocks[to].preds.len();
         self.blocks[from].succs.push(to);
@@ -260,6 +422,7 @@ impl FunctionBody {
     /// intermediate block with an unconditional branch and carrying
     /// through all blockparams.
     pub fn split_edge(&mut self, from: Block, to: Block, succ_idx: usize) -> Block {
+        self.mark_changed();
         assert_eq!(self.blocks[from].succs[succ_idx], to);
         let pred_idx = self.blocks[from].pos_in_succ_pred[succ_idx];
         assert_eq!(self.blocks[to].preds[pred_idx], from);
@@ -307,6 +470,7 @@ impl FunctionBody {
     /// after building a function body or mutating its CFG and prior
     /// to analyses.
     pub fn recompute_edges(&mut self) {
+        self.mark_changed();
         for block in self.blocks.values_mut() {
             block.preds.clear();
             block.succs.clear();
@@ -323,9 +487,83 @@ impl FunctionBody {
         }
     }
 
+    /// The reachable blocks in reverse postorder: depth-first from
+    /// the entry, visiting each block's successors in the order its
+    /// terminator lists them. Every block comes before the blocks it
+    /// dominates, and the order depends only on the control-flow
+    /// graph, not on block numbering. The edges must be up to date
+    /// (see `recompute_edges()`).
+    pub fn rpo(&self) -> Vec<Block> {
+        self.compute_rpo().rpo.values().copied().collect()
+    }
+
+    /// The reachable blocks in reverse postorder, as `rpo()`, with the
+    /// position of each. The result is cached on the body until its
+    /// CFG changes, so that passes run one after another share it.
+    ///
+    /// Changes are seen as `cfg_generation()` sees them.
+    pub fn compute_rpo(&self) -> Arc<BlockOrder> {
+        let mut cached = self.tracking.order.lock().unwrap();
+        match &*cached {
+            Some((entry, order)) if *entry == self.entry => order.clone(),
+            _ => {
+                let order = Arc::new(BlockOrder::compute(self));
+                *cached = Some((self.entry, order.clone()));
+                order
+            }
+        }
+    }
+
+    /// The generation of this body: a number that changes whenever
+    /// the body may have changed, and that no other body or version
+    /// of this one shares, except a clone until either changes.
+    /// Analyses record it to tell whether they are still valid; see
+    /// `cfg::Analysis`.
+    ///
+    /// Changes are seen through the methods that edit the body
+    /// (`add_block()`, `add_value()`, `add_op()`, `append_to_block()`,
+    /// `add_blockparam()`, `set_alias()`, `set_terminator()`, the
+    /// edge methods, and the passes run through `optimize()`). Code
+    /// that edits the fields directly must call `mark_changed()`.
+    pub fn generation(&self) -> u64 {
+        current_generation(&self.tracking.body)
+    }
+
+    /// The generation of this body's CFG, which changes only when the
+    /// edges may have: through `add_edge()`, `split_edge()`,
+    /// `set_terminator()`, `recompute_edges()`, or `mark_changed()`.
+    /// The block order also changes with `entry`.
+    pub fn cfg_generation(&self) -> u64 {
+        current_generation(&self.tracking.cfg)
+    }
+
+    /// Record that the body, including its CFG, may have changed.
+    pub fn mark_changed(&mut self) {
+        *self.tracking.body.get_mut() = 0;
+        *self.tracking.cfg.get_mut() = 0;
+        *self.tracking.order.get_mut().unwrap() = None;
+    }
+
+    /// Record that the body may have changed, but not its CFG.
+    fn mark_values_changed(&mut self) {
+        *self.tracking.body.get_mut() = 0;
+    }
+
+    /// The instructions of the reachable blocks, with their blocks,
+    /// in the order of `rpo()` and then of each block's `insts`.
+    pub fn rpo_insts(&self) -> impl Iterator<Item = (Block, Value)> + '_ {
+        self.rpo().into_iter().flat_map(move |block| {
+            self.blocks[block]
+                .insts
+                .iter()
+                .map(move |&inst| (block, inst))
+        })
+    }
+
     /// Add a new value node to the function (not yet in any block)
     /// and return its SSA value number.
     pub fn add_value(&mut self, value: ValueDef) -> Value {
+        self.mark_values_changed();
         log::trace!("add_value: def {:?}", value);
         let value = self.values.push(value);
         log::trace!(" -> {}", value);
@@ -350,8 +588,23 @@ impl FunctionBody {
         value
     }
 
+    /// Return `op`, an operator of the body `from`, with its
+    /// immediates interned in this body's pool, for copying it here.
+    pub fn import_op(&mut self, from: &FunctionBody, op: Operator) -> Operator {
+        match op {
+            Operator::V128Const { value } => Operator::V128Const {
+                value: self.imm_pool.intern(from.imm_pool[value]),
+            },
+            Operator::I8x16Shuffle { lanes } => Operator::I8x16Shuffle {
+                lanes: self.imm_pool.intern(from.imm_pool[lanes]),
+            },
+            op => op,
+        }
+    }
+
     /// Make one value an alias to another. Panics on cycles.
     pub fn set_alias(&mut self, value: Value, to: Value) {
+        self.mark_values_changed();
         log::trace!("set_alias: value {:?} to {:?}", value, to);
         // Resolve the `to` value through all existing aliases.
         let to = self.resolve_and_update_alias(to);
@@ -394,9 +647,59 @@ impl FunctionBody {
         to
     }
 
+    /// Give `to` the source location of `from`, for an instruction
+    /// that replaces or is cloned from another.
+    pub fn copy_loc(&mut self, from: Value, to: Value) {
+        self.source_locs[to] = self.source_locs[from];
+        self.wasm_offsets[to] = self.wasm_offsets[from];
+    }
+
+    /// Give `into` a location merged from those of `from`, for an
+    /// instruction that combines several others. A single location
+    /// cannot describe several source positions, so this picks the
+    /// first valid one; callers should list the primary value first.
+    pub fn merge_locs(&mut self, into: Value, from: &[Value]) {
+        if let Some(loc) = from
+            .iter()
+            .map(|&value| self.source_locs[value])
+            .find(|loc| loc.is_valid())
+        {
+            self.source_locs[into] = loc;
+        }
+        if let Some(offset) = from.iter().find_map(|&value| self.wasm_offsets[value]) {
+            self.wasm_offsets[into] = Some(offset);
+        }
+    }
+
+    /// The values that came from the Wasm instruction at `offset` in
+    /// the original module, as recorded in `wasm_offsets`: for
+    /// example, those that may have caused an engine-reported trap.
+    pub fn values_at_wasm_offset(&self, offset: u32) -> impl Iterator<Item = Value> + '_ {
+        self.values
+            .iter()
+            .filter(move |&value| self.wasm_offsets[value] == Some(offset))
+    }
+
+    /// Compact the argument and type pools, reclaiming the storage
+    /// of lists that are no longer referenced by any value.
+    pub fn defrag_pools(&mut self) {
+        let mut args = vec![];
+        let mut tys = vec![];
+        for def in self.values.values_mut() {
+            if let ValueDef::Operator(_, a, t) = def {
+                args.push(a);
+                tys.push(t);
+            }
+        }
+        tys.extend(self.single_type_dedup.values_mut());
+        self.arg_pool.defrag(args);
+        self.type_pool.defrag(tys);
+    }
+
     /// Add a new blockparam to the given block, returning its SSA
     /// value number.
     pub fn add_blockparam(&mut self, block: Block, ty: Type) -> Value {
+        self.mark_values_changed();
         let index = self.blocks[block].params.len();
         let value = self.add_value(ValueDef::BlockParam(block, index as u32, ty));
         self.blocks[block].params.push((ty, value));
@@ -407,13 +710,61 @@ impl FunctionBody {
     /// Add a new `Placeholder` value that can be replaced with an
     /// actual definition later. Useful in some algorithms that
     /// follow or resolve cycles.
+    ///
+    /// This is also the supported way to build forward references:
+    /// create a placeholder, use it freely, then define it with
+    /// `replace_placeholder()` once its definition is known. Call
+    /// `check_no_placeholders()` when construction is complete.
     pub fn add_placeholder(&mut self, ty: Type) -> Value {
         self.add_value(ValueDef::Placeholder(ty))
     }
 
+    /// Replace a `Placeholder` value with its actual definition,
+    /// which must have the placeholder's type. Existing uses of the
+    /// value now refer to the new definition.
+    ///
+    /// If `def` is an operator, the value must still be placed in a
+    /// block with `append_to_block()`; an `Alias` needs no placement.
+    /// Panics if `value` is not a placeholder or the types differ.
+    pub fn replace_placeholder(&mut self, value: Value, def: ValueDef) {
+        self.mark_values_changed();
+        let ty = match &self.values[value] {
+            &ValueDef::Placeholder(ty) => ty,
+            other => panic!("{} is not a placeholder: {:?}", value, other),
+        };
+        let def_ty = match &def {
+            &ValueDef::Alias(to) => self.values[self.resolve_alias(to)].ty(&self.type_pool),
+            def => def.ty(&self.type_pool),
+        };
+        assert_eq!(
+            def_ty,
+            Some(ty),
+            "replacement for placeholder {} has the wrong type",
+            value
+        );
+        self.values[value] = def;
+    }
+
+    /// Check that no placeholders remain. Call this when external
+    /// construction of a body is complete; later passes and the
+    /// backend do not expect to see placeholders.
+    pub fn check_no_placeholders(&self) -> Result<()> {
+        let remaining = self
+            .values
+            .entries()
+            .filter(|(_, def)| matches!(def, ValueDef::Placeholder(_)))
+            .map(|(value, _)| value)
+            .collect::<Vec<_>>();
+        if !remaining.is_empty() {
+            anyhow::bail!("Placeholders were never replaced: {:?}", remaining);
+        }
+        Ok(())
+    }
+
     /// Convert a `Placeholder` value into a blockparam on the given
     /// block.
     pub fn replace_placeholder_with_blockparam(&mut self, block: Block, value: Value) {
+        self.mark_values_changed();
         let index = self.blocks[block].params.len();
         let ty = match &self.values[value] {
             &ValueDef::Placeholder(ty) => ty,
@@ -431,6 +782,7 @@ impl FunctionBody {
 
     /// Append a value to the instruction list in a block.
     pub fn append_to_block(&mut self, block: Block, value: Value) {
+        self.mark_values_changed();
         self.blocks[block].insts.push(value);
         self.value_blocks[value] = block;
     }
@@ -544,6 +896,13 @@ impl FunctionBody {
             }
             let mut visit_use = |u: Value, i: Option<usize>, inst: Option<Value>| {
                 let u = self.resolve_alias(u);
+                if let ValueDef::Placeholder(_) = &self.values[u] {
+                    bad.push(format!(
+                        "Use of arg {} at {:?} in {} illegal: unreplaced placeholder",
+                        u, inst, block
+                    ));
+                    return;
+                }
                 if block_inst[u].is_none() {
                     bad.push(format!(
                         "Use of arg {} at {:?} in {} illegal: not defined",
@@ -587,6 +946,14 @@ impl FunctionBody {
                 visit_use(u, Some(terminator_idx), None);
             });
         }
+        // Verify immediates that operators' types do not constrain.
+        for (inst, def) in self.values.entries() {
+            if let ValueDef::Operator(op, ..) = def {
+                if let Err(e) = op.check_immediates() {
+                    bad.push(format!("Bad immediate in {}: {}", inst, e));
+                }
+            }
+        }
         if bad.len() > 0 {
             anyhow::bail!(
                 "Body is:\n{}\nError(s) in SSA: {:?}",
@@ -598,6 +965,92 @@ impl FunctionBody {
         Ok(())
     }
 
+    /// Compute a hash of this function's structure: its reachable
+    /// blocks, their parameters, instructions and terminators, and
+    /// the dataflow between them. The hash depends only on the order
+    /// of blocks in a reverse-postorder walk and of instructions
+    /// within blocks, not on the numbering of blocks and values, so
+    /// functions that differ only by renumbering, aliases, or
+    /// unreachable blocks hash equally. Debug information is not
+    /// included. Module-level entities referenced by operators (e.g.
+    /// callees) are hashed by index.
+    ///
+    /// The hash is stable across runs of the same build of this
+    /// crate, but not across versions.
+    pub fn structural_hash(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = fxhash::FxHasher64::default();
+        self.hash_structure(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Feed this function's structure, as hashed by
+    /// `structural_hash()`, to `hasher`.
+    pub(crate) fn hash_structure<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        use std::hash::Hash;
+        let cfg = CFGInfo::new(self);
+        let mut block_nums: FxHashMap<Block, usize> = FxHashMap::default();
+        for (i, &block) in cfg.rpo.values().enumerate() {
+            block_nums.insert(block, i);
+        }
+        let block_num = |block: Block| block_nums.get(&block).copied().unwrap_or(usize::MAX);
+        let mut value_nums: FxHashMap<Value, usize> = FxHashMap::default();
+        let value_num = |value_nums: &FxHashMap<Value, usize>, value: Value| {
+            value_nums
+                .get(&self.resolve_alias(value))
+                .copied()
+                .unwrap_or(usize::MAX)
+        };
+
+        self.rets.hash(hasher);
+        for &block in cfg.rpo.values() {
+            let def = &self.blocks[block];
+            for &(ty, param) in &def.params {
+                ty.hash(hasher);
+                let next = value_nums.len();
+                value_nums.insert(param, next);
+            }
+            for &inst in &def.insts {
+                match &self.values[inst] {
+                    // Hash as if `passes::resolve_aliases` had run.
+                    ValueDef::Alias(..) => continue,
+                    ValueDef::Operator(op, args, tys) => {
+                        0u8.hash(hasher);
+                        op.hash(hasher);
+                        // Immediate handles depend on the order of
+                        // interning, so hash their values as well.
+                        if let Operator::V128Const { value: imm }
+                        | Operator::I8x16Shuffle { lanes: imm } = op
+                        {
+                            self.imm_pool[*imm].hash(hasher);
+                        }
+                        for &arg in &self.arg_pool[*args] {
+                            value_num(&value_nums, arg).hash(hasher);
+                        }
+                        self.type_pool[*tys].hash(hasher);
+                    }
+                    ValueDef::PickOutput(value, index, ty) => {
+                        1u8.hash(hasher);
+                        value_num(&value_nums, *value).hash(hasher);
+                        index.hash(hasher);
+                        ty.hash(hasher);
+                    }
+                    _ => 2u8.hash(hasher),
+                }
+                let next = value_nums.len();
+                value_nums.insert(inst, next);
+            }
+            std::mem::discriminant(&def.terminator).hash(hasher);
+            def.terminator.visit_uses(|value| {
+                value_num(&value_nums, value).hash(hasher);
+            });
+            def.terminator.visit_targets(|target| {
+                block_num(target.block).hash(hasher);
+                target.args.len().hash(hasher);
+            });
+        }
+    }
+
     /// Verify that the CFG of this function is reducible. (This is
     /// not necessary to produce Wasm, as the backend can turn
     /// irreducible control flow into reducible control flow via the
@@ -629,11 +1082,32 @@ impl FunctionBody {
     pub fn compile(&self) -> Result<wasm_encoder::Function> {
         WasmFuncBackend::compile(self)
     }
+
+    /// Compile this function to Wasm bytecode with the given backend
+    /// options.
+    pub fn compile_with_options(&self, options: &BackendOptions) -> Result<wasm_encoder::Function> {
+        WasmFuncBackend::compile_with_options(self, options)
+    }
+
+    /// Compile this function, also returning where the code for each
+    /// value went and the body as compiled (see `EmitInfo`).
+    pub fn compile_with_emit_info(
+        &self,
+        options: &BackendOptions,
+    ) -> Result<(wasm_encoder::Function, EmitInfo, Cow<'_, FunctionBody>)> {
+        WasmFuncBackend::compile_with_emit_info(self, options)
+    }
+
+    /// Compute the operand-stack height and locals that `compile()`'s
+    /// output uses.
+    pub fn frame_info(&self) -> Result<FrameInfo> {
+        WasmFuncBackend::frame_info(self)
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct BlockDef {
-    /// Instructions in this block.
+    /// Instructions in this block, in execution order.
     pub insts: Vec<Value>,
     /// Terminator: branch or return.
     pub terminator: Terminator,
@@ -647,8 +1121,43 @@ pub struct BlockDef {
     pub pos_in_pred_succ: Vec<usize>,
     /// Type and Value for each blockparam.
     pub params: Vec<(Type, Value)>,
-    /// Descriptive name for the block, if any.
-    pub desc: String,
+    /// Where the block came from, shown by the printer.
+    pub origin: BlockOrigin,
+    /// If the terminator is a conditional branch, whether it is
+    /// likely (`Some(true)`) or unlikely (`Some(false)`) to go to
+    /// its `if_true` target. The backend emits this as a Wasm branch
+    /// hint.
+    pub branch_hint: Option<bool>,
+}
+
+/// The provenance of a block: what created it. The frontend records
+/// the Wasm instruction each block comes from, passes that synthesize
+/// blocks name themselves, and tools may label blocks. Passes that
+/// copy a block copy its origin.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BlockOrigin {
+    /// Nothing is known about where the block came from.
+    #[default]
+    Unknown,
+    /// Created for the Wasm instruction (or, for the entry block and
+    /// the function's implicit outer block, the function body) at
+    /// this byte offset in the original module.
+    FromWasmOffset(u32),
+    /// Created by the named pass.
+    SyntheticFor(&'static str),
+    /// Labeled by a user of this crate.
+    UserLabel(String),
+}
+
+impl std::fmt::Display for BlockOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockOrigin::Unknown => Ok(()),
+            BlockOrigin::FromWasmOffset(offset) => write!(f, "wasm@{:#x}", offset),
+            BlockOrigin::SyntheticFor(pass) => write!(f, "synthetic:{}", pass),
+            BlockOrigin::UserLabel(label) => write!(f, "label:{}", label),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -881,3 +1390,167 @@ impl Terminator {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, SignatureData, Terminator};
+
+    #[test]
+    fn rpo_follows_control_flow() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        // Blocks are created in an order unrelated to control flow,
+        // and one is unreachable.
+        let join = body.add_block();
+        let dead = body.add_block();
+        let right = body.add_block();
+        let left = body.add_block();
+        let target = |block| BlockTarget {
+            block,
+            args: vec![],
+        };
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: x,
+                if_true: target(left),
+                if_false: target(right),
+            },
+        );
+        let one = body.add_op(left, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        body.set_terminator(
+            left,
+            Terminator::Br {
+                target: target(join),
+            },
+        );
+        body.set_terminator(
+            right,
+            Terminator::Br {
+                target: target(join),
+            },
+        );
+        body.set_terminator(
+            dead,
+            Terminator::Br {
+                target: target(join),
+            },
+        );
+        body.set_terminator(join, Terminator::Return { values: vec![] });
+
+        assert_eq!(
+            body.blocks.iter().collect::<Vec<_>>(),
+            vec![entry, join, dead, right, left]
+        );
+        assert_eq!(body.rpo(), vec![entry, right, left, join]);
+        assert_eq!(body.rpo(), CFGInfo::new(&body).rpo.into_vec());
+        assert_eq!(body.rpo_insts().collect::<Vec<_>>(), vec![(left, one)]);
+
+        // The order is cached until the edges change.
+        let order = body.compute_rpo();
+        assert!(Arc::ptr_eq(&order, &body.compute_rpo()));
+        assert!(!order.is_reachable(dead));
+        let generation = body.cfg_generation();
+        body.blocks[entry].terminator = Terminator::Br {
+            target: target(dead),
+        };
+        body.recompute_edges();
+        assert!(body.cfg_generation() > generation);
+        assert_eq!(body.rpo(), vec![entry, dead, join]);
+    }
+
+    #[test]
+    fn forward_reference_via_placeholder() {
+        // f(x) = (x + 1) computed after its use is built.
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let exit = body.add_block();
+        let later = body.add_placeholder(Type::I32);
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: exit,
+                    args: vec![],
+                },
+            },
+        );
+        body.set_terminator(
+            exit,
+            Terminator::Return {
+                values: vec![later],
+            },
+        );
+        assert!(body.check_no_placeholders().is_err());
+        assert!(body.validate().is_err());
+
+        let one = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let args = body.arg_pool.double(x, one);
+        let tys = body.single_type_list(Type::I32);
+        body.replace_placeholder(later, ValueDef::Operator(Operator::I32Add, args, tys));
+        body.append_to_block(entry, later);
+        body.check_no_placeholders().unwrap();
+        body.validate().unwrap();
+    }
+
+    #[test]
+    fn structural_hash_ignores_numbering() {
+        let module = crate::testgen::gen_module(1, &Default::default());
+        let body = module.funcs.values().next().unwrap().body().unwrap();
+        let hash = body.structural_hash();
+
+        // Unplaced values and unreachable blocks do not matter.
+        let mut renumbered = body.clone();
+        renumbered.add_value(ValueDef::Placeholder(Type::I32));
+        let dead = renumbered.add_block();
+        renumbered.add_op(dead, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        renumbered.set_terminator(dead, Terminator::Unreachable);
+        assert_eq!(renumbered.structural_hash(), hash);
+
+        let mut changed = body.clone();
+        let entry = changed.entry;
+        changed.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        assert_ne!(changed.structural_hash(), hash);
+    }
+
+    #[test]
+    fn v128_immediates_are_per_body() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let body = |value: u128| {
+            let mut body = FunctionBody::new(&module, sig);
+            let value = body.imm_pool.intern(value);
+            let op = Operator::V128Const { value };
+            body.add_op(body.entry, op, &[], &[Type::V128]);
+            body.set_terminator(body.entry, Terminator::Return { values: vec![] });
+            (body, op)
+        };
+        let (a, op_a) = body(5);
+        let (mut b, op_b) = body(6);
+
+        // The handles coincide, but the values behind them do not.
+        assert_eq!(op_a, op_b);
+        assert_ne!(a.structural_hash(), b.structural_hash());
+
+        match b.import_op(&a, op_a) {
+            Operator::V128Const { value } => assert_eq!(b.imm_pool[value], 5),
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+}
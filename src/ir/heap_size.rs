@@ -0,0 +1,231 @@
+//! Estimates of the heap memory held by modules and function bodies,
+//! broken down by component.
+//!
+//! The estimates count the allocated capacity of each container, so
+//! they include slack that `shrink_to_fit` would release, but not the
+//! allocator's own overhead. Hash maps are counted by bucket and
+//! B-tree maps by entry, which is close enough for budgeting.
+
+use super::{BlockDef, BlockOrigin, BlockTarget, FuncDecl, FunctionBody, Module, Terminator};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+
+/// Bytes of heap memory held, by component.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapSize {
+    /// Value definitions and the per-value side tables (blocks,
+    /// locals, and source locations).
+    pub values: usize,
+    /// The argument- and type-list pools, and the immediate pool.
+    pub pools: usize,
+    /// Block definitions: instruction lists, edges, parameters, and
+    /// terminators.
+    pub blocks: usize,
+    /// Debug info: source-location tables, local names, and the
+    /// values that hold each local.
+    pub debug: usize,
+    /// Compiled function bytecode not yet emitted.
+    pub code: usize,
+    /// Memory and table initializers.
+    pub data: usize,
+    /// Everything else: function bodies' fixed parts, locals,
+    /// signatures, imports, exports, and the like.
+    pub other: usize,
+}
+
+impl HeapSize {
+    /// The total over all components.
+    pub fn total(&self) -> usize {
+        self.values + self.pools + self.blocks + self.debug + self.code + self.data + self.other
+    }
+}
+
+impl std::ops::AddAssign for HeapSize {
+    fn add_assign(&mut self, rhs: HeapSize) {
+        self.values += rhs.values;
+        self.pools += rhs.pools;
+        self.blocks += rhs.blocks;
+        self.debug += rhs.debug;
+        self.code += rhs.code;
+        self.data += rhs.data;
+        self.other += rhs.other;
+    }
+}
+
+pub(crate) fn vec_size<T>(vec: &Vec<T>) -> usize {
+    vec.capacity() * size_of::<T>()
+}
+
+pub(crate) fn string_size(string: &String) -> usize {
+    string.capacity()
+}
+
+pub(crate) fn entity_vec_size<Idx: EntityRef, T: Clone + Debug>(vec: &EntityVec<Idx, T>) -> usize {
+    vec.capacity() * size_of::<T>()
+}
+
+pub(crate) fn per_entity_size<Idx: EntityRef, T: Clone + Debug + Default>(
+    map: &PerEntity<Idx, T>,
+) -> usize {
+    map.capacity() * size_of::<T>()
+}
+
+pub(crate) fn hash_map_size<K, V, S: BuildHasher>(map: &HashMap<K, V, S>) -> usize {
+    // One control byte per bucket besides the entry itself.
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+pub(crate) fn btree_map_size<K, V>(map: &BTreeMap<K, V>) -> usize {
+    map.len() * size_of::<(K, V)>()
+}
+
+fn target_size(target: &BlockTarget) -> usize {
+    vec_size(&target.args)
+}
+
+impl BlockDef {
+    /// The number of bytes of heap memory the block holds.
+    pub fn heap_size(&self) -> usize {
+        let terminator = match &self.terminator {
+            Terminator::Br { target } => target_size(target),
+            Terminator::CondBr {
+                if_true, if_false, ..
+            } => target_size(if_true) + target_size(if_false),
+            Terminator::Select {
+                targets, default, ..
+            } => {
+                vec_size(targets)
+                    + targets.iter().map(target_size).sum::<usize>()
+                    + target_size(default)
+            }
+            Terminator::Return { values } => vec_size(values),
+            Terminator::Unreachable | Terminator::None => 0,
+        };
+        vec_size(&self.insts)
+            + vec_size(&self.succs)
+            + vec_size(&self.pos_in_succ_pred)
+            + vec_size(&self.preds)
+            + vec_size(&self.pos_in_pred_succ)
+            + vec_size(&self.params)
+            + self.origin.heap_size()
+            + terminator
+    }
+}
+
+impl BlockOrigin {
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            BlockOrigin::UserLabel(label) => string_size(label),
+            _ => 0,
+        }
+    }
+}
+
+impl FunctionBody {
+    /// Estimate the heap memory this body holds, including the body
+    /// itself (which always lives behind an `Arc` in a `FuncDecl`).
+    pub fn heap_size(&self) -> HeapSize {
+        HeapSize {
+            values: entity_vec_size(&self.values)
+                + per_entity_size(&self.value_blocks)
+                + per_entity_size(&self.value_locals)
+                + per_entity_size(&self.source_locs)
+                + per_entity_size(&self.wasm_offsets),
+            pools: self.type_pool.heap_size()
+                + self.arg_pool.heap_size()
+                + self.imm_pool.heap_size()
+                + hash_map_size(&self.single_type_dedup),
+            blocks: entity_vec_size(&self.blocks)
+                + self
+                    .blocks
+                    .values()
+                    .map(|block| block.heap_size())
+                    .sum::<usize>(),
+            debug: self.debug_values.heap_size()
+                + btree_map_size(&self.local_names)
+                + self.local_names.values().map(string_size).sum::<usize>(),
+            other: size_of::<FunctionBody>() + vec_size(&self.rets) + entity_vec_size(&self.locals),
+            ..HeapSize::default()
+        }
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Estimate the heap memory this module holds. Un-expanded lazy
+    /// bodies and custom sections borrow the original bytes and are
+    /// not counted; expanded bodies shared with a clone of this
+    /// module are counted in full here and in the clone.
+    pub fn heap_size_estimate(&self) -> HeapSize {
+        let mut size = HeapSize {
+            debug: self.debug.heap_size() + self.debug_map.heap_size(),
+            data: self
+                .memories
+                .values()
+                .map(|memory| {
+                    vec_size(&memory.segments)
+                        + memory
+                            .segments
+                            .iter()
+                            .map(|segment| vec_size(&segment.data))
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+                + self
+                    .tables
+                    .values()
+                    .filter_map(|table| table.func_elements.as_ref())
+                    .map(vec_size)
+                    .sum::<usize>(),
+            other: entity_vec_size(&self.funcs)
+                + entity_vec_size(&self.signatures)
+                + self
+                    .signatures
+                    .values()
+                    .map(|sig| vec_size(&sig.params) + vec_size(&sig.returns))
+                    .sum::<usize>()
+                + entity_vec_size(&self.globals)
+                + entity_vec_size(&self.tables)
+                + entity_vec_size(&self.memories)
+                + vec_size(&self.imports)
+                + self
+                    .imports
+                    .iter()
+                    .map(|import| string_size(&import.module) + string_size(&import.name))
+                    .sum::<usize>()
+                + vec_size(&self.exports)
+                + self
+                    .exports
+                    .iter()
+                    .map(|export| string_size(&export.name))
+                    .sum::<usize>()
+                + btree_map_size(&self.custom_sections)
+                + btree_map_size(&self.unsupported_funcs)
+                + self
+                    .unsupported_funcs
+                    .values()
+                    .map(string_size)
+                    .sum::<usize>(),
+            ..HeapSize::default()
+        };
+        for decl in self.funcs.values() {
+            match decl {
+                FuncDecl::Import(_, name) | FuncDecl::Lazy(_, name, _) => {
+                    size.other += string_size(name);
+                }
+                FuncDecl::Body(_, name, body) => {
+                    size.other += string_size(name);
+                    size += body.heap_size();
+                }
+                FuncDecl::Compiled(_, name, bytes) => {
+                    size.other += string_size(name);
+                    size.code += vec_size(bytes);
+                }
+                FuncDecl::None => {}
+            }
+        }
+        size
+    }
+}
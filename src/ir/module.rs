@@ -2,13 +2,28 @@ use super::{
     Func, FuncDecl, Global, Memory, ModuleDisplay, NOPPrintDecorator, PrintDecorator, Signature,
     Table, Type,
 };
+use crate::backend::cache::CompileCache;
+use crate::backend::BackendOptions;
+use crate::diagnostics::Severity;
 use crate::entity::{EntityRef, EntityVec};
-use crate::ir::{Debug, DebugMap, FunctionBody};
+use crate::errors::FrontendError;
+use crate::ir::{Debug, DebugMap, FunctionBody, FunctionBuilder, Terminator};
+use crate::passes::basic_opt::OptOptions;
+use crate::passes::effects::{EffectSummaries, EffectSummary};
+use crate::passes::features::FeatureSet;
+use crate::passes::host_surface::HostSurface;
+use crate::passes::import_shims::ImportAdapter;
+use crate::passes::intrinsics::Intrinsic;
+use crate::passes::opcodes::OpcodeHistogram;
+use crate::passes::overrides::{FuncOverride, FuncOverrides};
+use crate::passes::specialize::{SpecializeOptions, SpecializeReport};
+use crate::passes::trampolines::TrampolineSpec;
 use crate::{backend, frontend};
 use anyhow::Result;
 use std::collections::BTreeMap;
+use std::sync::Arc;
 
-pub use crate::frontend::FrontendOptions;
+pub use crate::frontend::{FrontendOptions, LowerContext, LowerHook, UnreachableCode};
 
 /// A Wasm module, represented as a collection of IR entities.
 ///
@@ -28,6 +43,11 @@ pub use crate::frontend::FrontendOptions;
 ///   append new function bodies to `funcs`.
 /// - Compile the IR to a new Wasm module with
 ///   `Module::to_wasm_bytes()`.
+///
+/// `Module` and `FunctionBody` are `Send` and `Sync`. Function bodies
+/// are copy-on-write (see `FuncDecl`), so one thread may mutate a
+/// module while others analyze snapshots of its bodies taken with
+/// `Module::shared_body()`; each snapshot stays as it was when taken.
 #[derive(Clone, Debug)]
 pub struct Module<'a> {
     /// The original Wasm module this module was parsed from, if
@@ -36,7 +56,9 @@ pub struct Module<'a> {
     pub orig_bytes: Option<&'a [u8]>,
     /// The functions in this module: imports, un-expanded ("lazily
     /// parsed") functions, functions as IR, or IR compiled into new
-    /// bytecode.
+    /// bytecode. They are in function index order, as in the Wasm
+    /// function index space (imports first), and iterate in that
+    /// order; see `EntityVec`.
     pub funcs: EntityVec<Func, FuncDecl<'a>>,
     /// Type signatures, referred to by `funcs`, `imports` and
     /// `exports`.
@@ -61,6 +83,22 @@ pub struct Module<'a> {
     pub debug_map: DebugMap,
     /// Other custom sections retained for re-serialization.
     pub custom_sections: BTreeMap<String, &'a [u8]>,
+    /// Per-function overrides of the module-wide optimization
+    /// pipeline; see `Module::optimize()`.
+    pub func_overrides: FuncOverrides,
+    /// The options this module was parsed with, which also govern
+    /// the expansion of its lazy function bodies.
+    pub frontend_options: FrontendOptions,
+    /// Functions left un-expanded because they use unsupported
+    /// features, with the reason.
+    pub unsupported_funcs: BTreeMap<Func, String>,
+    /// Blobs placed by `Module::add_rodata()`, by contents, with
+    /// their memory and address, so that identical blobs share one
+    /// copy.
+    pub rodata: BTreeMap<Vec<u8>, (Memory, u64)>,
+    /// Imported functions declared to have known semantics; see
+    /// `Module::declare_intrinsic()`.
+    pub intrinsics: BTreeMap<Func, Intrinsic>,
 }
 
 /// A function signature definition.
@@ -124,6 +162,48 @@ pub struct GlobalData {
     pub value: Option<u64>,
     /// Whether this global variable is mutable.
     pub mutable: bool,
+    /// A computed initializer, if the initial value is not a single
+    /// constant. Overrides `value` if present.
+    pub init: Option<InitExpr>,
+}
+
+/// A computed global initializer: an extended constant expression,
+/// as a tree.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InitExpr {
+    /// A constant of the given type, as a bundle of 64 bits. Only
+    /// numeric types (`i32`, `i64`, `f32` and `f64`) can be emitted;
+    /// others are errors.
+    Const(Type, u64),
+    /// The value of another global.
+    GlobalGet(Global),
+    /// An arithmetic operator applied to two initializers.
+    Binary(InitOp, Box<InitExpr>, Box<InitExpr>),
+}
+
+/// The arithmetic operators allowed in extended constant expressions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InitOp {
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I64Add,
+    I64Sub,
+    I64Mul,
+}
+
+impl InitExpr {
+    /// Call `f` on each global this initializer reads.
+    pub fn visit_globals<F: FnMut(Global)>(&self, f: &mut F) {
+        match self {
+            InitExpr::Const(..) => {}
+            InitExpr::GlobalGet(global) => f(*global),
+            InitExpr::Binary(_, a, b) => {
+                a.visit_globals(f);
+                b.visit_globals(f);
+            }
+        }
+    }
 }
 
 impl From<&wasmparser::FuncType> for SignatureData {
@@ -149,7 +229,7 @@ impl From<wasmparser::FuncType> for SignatureData {
 }
 
 /// A module import definition.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct Import {
     /// The name of the module the import comes from.
     pub module: String,
@@ -162,7 +242,7 @@ pub struct Import {
 
 /// The kind of of a Wasm import, including the specific entity index
 /// that the import corresponds to.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ImportKind {
     /// An import of a table.
     Table(Table),
@@ -187,7 +267,7 @@ impl std::fmt::Display for ImportKind {
 }
 
 /// A module export definition.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash)]
 pub struct Export {
     /// The name of this export.
     pub name: String,
@@ -197,7 +277,7 @@ pub struct Export {
 
 /// The kind of a Wasm export, including the specific entity index
 /// that this export directive exports.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ExportKind {
     /// An export of a table.
     Table(Table),
@@ -221,6 +301,15 @@ impl std::fmt::Display for ExportKind {
     }
 }
 
+// Checked at compile time: see the `Module` docs.
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_module_send_sync() {
+        assert_send_sync::<Module<'_>>();
+        assert_send_sync::<FunctionBody>();
+    }
+};
+
 impl<'a> Module<'a> {
     /// Create a new empty Wasm module, ready for entities to be added.
     pub fn empty() -> Module<'static> {
@@ -237,6 +326,11 @@ impl<'a> Module<'a> {
             debug: Debug::default(),
             debug_map: DebugMap::default(),
             custom_sections: BTreeMap::default(),
+            func_overrides: FuncOverrides::default(),
+            frontend_options: FrontendOptions::default(),
+            unsupported_funcs: BTreeMap::default(),
+            rodata: BTreeMap::default(),
+            intrinsics: BTreeMap::default(),
         }
     }
 
@@ -256,14 +350,33 @@ impl<'a> Module<'a> {
     ///   recompiled into new bytecode. The bytecode should be
     ///   equivalent, but will not literally be the same bytecode as the
     ///   original module.
+    /// - Functions in `unsupported_funcs` keep a copy of their original
+    ///   bytecode as `FuncDecl::Compiled`.
     pub fn without_orig_bytes(self) -> Module<'static> {
+        for name in self.custom_sections.keys() {
+            self.frontend_options.diagnostics.report(
+                Severity::Warning,
+                "frontend",
+                None,
+                format!("dropped custom section `{}`", name),
+            );
+        }
+        let unsupported_funcs = self.unsupported_funcs;
         Module {
             orig_bytes: None,
             funcs: EntityVec::from(
                 self.funcs
                     .into_vec()
                     .into_iter()
-                    .map(|decl| decl.without_orig_bytes())
+                    .enumerate()
+                    .map(|(i, decl)| match decl {
+                        FuncDecl::Lazy(sig, name, body)
+                            if unsupported_funcs.contains_key(&Func::new(i)) =>
+                        {
+                            FuncDecl::Compiled(sig, name, body.as_bytes().to_vec())
+                        }
+                        decl => decl.without_orig_bytes(),
+                    })
                     .collect::<Vec<_>>(),
             ),
             signatures: self.signatures,
@@ -276,12 +389,361 @@ impl<'a> Module<'a> {
             debug: self.debug,
             debug_map: self.debug_map,
             custom_sections: BTreeMap::default(),
+            func_overrides: self.func_overrides,
+            frontend_options: self.frontend_options,
+            unsupported_funcs,
+            rodata: self.rodata,
+            intrinsics: self.intrinsics,
         }
     }
 
+    /// Compute a fingerprint of the module's contents: its
+    /// signatures, globals, tables, memories, imports, exports, start
+    /// function, and function bodies (by `structural_hash()`, or by
+    /// bytecode for bodies that are not expanded). Function names,
+    /// debug info, and custom sections are not included.
+    ///
+    /// Two modules with equal fingerprints are almost certainly
+    /// equivalent; note that an unexpanded body and its expansion
+    /// fingerprint differently.
+    pub fn fingerprint(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = fxhash::FxHasher64::default();
+        for sig in self.signatures.values() {
+            sig.hash(&mut hasher);
+        }
+        for global in self.globals.values() {
+            global.hash(&mut hasher);
+        }
+        for table in self.tables.values() {
+            table.hash(&mut hasher);
+        }
+        for memory in self.memories.values() {
+            memory.hash(&mut hasher);
+        }
+        self.imports.hash(&mut hasher);
+        self.exports.hash(&mut hasher);
+        self.start_func.hash(&mut hasher);
+        for decl in self.funcs.values() {
+            if let FuncDecl::None = decl {
+                continue;
+            }
+            decl.sig().hash(&mut hasher);
+            decl.content_hash().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Put the module in canonical form, so that its output is stable
+    /// across transform histories. All function bodies must be
+    /// expanded. See `passes::canonicalize`.
+    pub fn canonicalize(&mut self) -> Result<()> {
+        crate::passes::canonicalize::run(self)
+    }
+
+    /// Rename imports: `f` maps an import's module and name to new
+    /// ones, or returns `None` to leave it. Returns the number of
+    /// imports renamed.
+    pub fn remap_imports<F: FnMut(&str, &str) -> Option<(String, String)>>(
+        &mut self,
+        f: F,
+    ) -> usize {
+        crate::passes::import_shims::remap(self, f)
+    }
+
+    /// Add an imported function with signature `sig`, renumbering
+    /// defined functions to make room for it. See
+    /// `passes::import_shims::add_func_import()`.
+    pub fn add_func_import(&mut self, module: &str, name: &str, sig: Signature) -> Result<Func> {
+        crate::passes::import_shims::add_func_import(self, module, name, sig)
+    }
+
+    /// Change the signature of the imported function `func` to `sig`,
+    /// generating an adapter function with the old signature that the
+    /// rest of the module calls instead. The adapter is inferred with
+    /// `ImportAdapter::infer()`; see `passes::import_shims::retarget()`
+    /// to supply one. Returns the adapter function.
+    pub fn retarget_import(&mut self, func: Func, sig: Signature) -> Result<Func> {
+        let old = self.funcs[func].sig();
+        let adapter = ImportAdapter::infer(self, old, sig)
+            .ok_or_else(|| anyhow::anyhow!("No adapter from {} to {} for {}", old, sig, func))?;
+        crate::passes::import_shims::retarget(self, func, &adapter)
+    }
+
+    /// Add a function with signature `from_sig` that calls
+    /// `spec.target`, whose signature must be `to_sig`, converting
+    /// arguments and results as `spec` describes. See
+    /// `passes::trampolines`. Returns the new function.
+    pub fn make_trampoline(
+        &mut self,
+        from_sig: Signature,
+        to_sig: Signature,
+        spec: &TrampolineSpec,
+    ) -> Result<Func> {
+        crate::passes::trampolines::make(self, from_sig, to_sig, spec)
+    }
+
+    /// Place a read-only blob in the main memory, where the guest
+    /// will not otherwise use it, and return the memory and address.
+    /// Adding the same contents again returns the same address. See
+    /// `passes::rodata`.
+    pub fn add_rodata(&mut self, data: &[u8]) -> Result<(Memory, u64)> {
+        crate::passes::rodata::add(self, data)
+    }
+
+    /// Declare the function imported as `module_name`.`name` an
+    /// intrinsic with the given semantics, so that effect summaries,
+    /// and the optimizer given them, treat calls to it accordingly.
+    /// Returns the imported function. See `passes::intrinsics`.
+    pub fn declare_intrinsic(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        intrinsic: Intrinsic,
+    ) -> Result<Func> {
+        crate::passes::intrinsics::declare(self, module_name, name, intrinsic)
+    }
+
+    /// Specialize the module to fixed values of some of its imports,
+    /// removing the code that other host configurations would need.
+    /// See `passes::specialize`.
+    pub fn specialize(&mut self, options: &SpecializeOptions) -> Result<SpecializeReport> {
+        crate::passes::specialize::specialize(self, options)
+    }
+
+    /// Lower computed global initializers that cannot be emitted as
+    /// constant expressions into writes from a start function, in
+    /// dependency order. Returns the number of globals lowered. See
+    /// `passes::global_inits`.
+    pub fn lower_complex_initializers(&mut self) -> Result<usize> {
+        crate::passes::global_inits::lower(self, false)
+    }
+
+    /// Compute effect summaries for all functions, bottom-up over
+    /// the call graph. Only expanded bodies are analyzed; imports
+    /// other than intrinsics, and lazy bodies, are assumed to do
+    /// anything. The result can be
+    /// passed to the optimizer in `OptOptions::effects`.
+    pub fn effect_summaries(&self) -> EffectSummaries {
+        EffectSummaries::compute(self)
+    }
+
+    /// The effect summary of a single function. This analyzes the
+    /// whole module; use `effect_summaries()` to query many functions.
+    pub fn effect_summary(&self, func: Func) -> EffectSummary {
+        self.effect_summaries().get(func)
+    }
+
+    /// Detect which post-MVP proposals this module uses. Lazy bodies
+    /// are scanned as bytecode, without expanding them.
+    pub fn detect_features(&self) -> Result<FeatureSet> {
+        FeatureSet::compute(self)
+    }
+
+    /// Count how often each operator occurs, streaming bodies that are
+    /// not yet expanded rather than expanding them. See
+    /// `passes::opcodes`.
+    pub fn opcode_histogram(&self) -> Result<OpcodeHistogram> {
+        OpcodeHistogram::compute(self)
+    }
+
+    /// Summarize what the module needs from and provides to its host.
+    /// See `passes::host_surface`.
+    pub fn host_surface(&self) -> HostSurface {
+        crate::passes::host_surface::describe(self)
+    }
+
+    /// Verify module-level consistency: that every entity reference
+    /// is in range, imports and exports agree with the declarations
+    /// they name, table contents match the tables' types, the start
+    /// function takes and returns nothing, memory limits are sane,
+    /// and every function body matches its signature. This
+    /// complements `FunctionBody::validate()`, which checks the
+    /// inside of each body.
+    pub fn verify(&self) -> Result<()> {
+        let mut bad = vec![];
+        let sig_ok = |sig: Signature| sig.is_valid() && sig.index() < self.signatures.len();
+        let func_ok = |func: Func| func.is_valid() && func.index() < self.funcs.len();
+
+        for (func, decl) in self.funcs.entries() {
+            if let FuncDecl::None = decl {
+                bad.push(format!("{} has no declaration", func));
+                continue;
+            }
+            let sig = decl.sig();
+            if !sig_ok(sig) {
+                bad.push(format!("{} has out-of-range signature {}", func, sig));
+                continue;
+            }
+            if let FuncDecl::Body(_, _, body) = decl {
+                let sig_data = &self.signatures[sig];
+                let params = body.blocks[body.entry]
+                    .params
+                    .iter()
+                    .map(|&(ty, _)| ty)
+                    .collect::<Vec<_>>();
+                if params != sig_data.params || body.n_params != sig_data.params.len() {
+                    bad.push(format!(
+                        "{} body params {:?} do not match signature {} params {:?}",
+                        func, params, sig, sig_data.params
+                    ));
+                }
+                if body.rets != sig_data.returns {
+                    bad.push(format!(
+                        "{} body returns {:?} do not match signature {} returns {:?}",
+                        func, body.rets, sig, sig_data.returns
+                    ));
+                }
+            }
+        }
+
+        let mut imported_funcs = vec![false; self.funcs.len()];
+        for import in &self.imports {
+            let ok = match import.kind {
+                ImportKind::Func(func) => {
+                    let ok = func_ok(func) && matches!(self.funcs[func], FuncDecl::Import(..));
+                    if ok {
+                        imported_funcs[func.index()] = true;
+                    }
+                    ok
+                }
+                ImportKind::Table(table) => table.index() < self.tables.len(),
+                ImportKind::Global(global) => global.index() < self.globals.len(),
+                ImportKind::Memory(memory) => memory.index() < self.memories.len(),
+            };
+            if !ok {
+                bad.push(format!(
+                    "Import \"{}\".\"{}\" names invalid or mismatched {}",
+                    import.module, import.name, import.kind
+                ));
+            }
+        }
+        for (func, decl) in self.funcs.entries() {
+            if let FuncDecl::Import(..) = decl {
+                if !imported_funcs[func.index()] {
+                    bad.push(format!(
+                        "{} is declared as an import but not imported",
+                        func
+                    ));
+                }
+            }
+        }
+
+        let mut export_names = std::collections::HashSet::new();
+        for export in &self.exports {
+            let ok = match export.kind {
+                ExportKind::Func(func) => func_ok(func),
+                ExportKind::Table(table) => table.index() < self.tables.len(),
+                ExportKind::Global(global) => global.index() < self.globals.len(),
+                ExportKind::Memory(memory) => memory.index() < self.memories.len(),
+            };
+            if !ok {
+                bad.push(format!(
+                    "Export \"{}\" names invalid {}",
+                    export.name, export.kind
+                ));
+            }
+            if !export_names.insert(&export.name) {
+                bad.push(format!("Duplicate export name \"{}\"", export.name));
+            }
+        }
+
+        for (table, data) in self.tables.entries() {
+            if data.max.map(|max| max < data.initial).unwrap_or(false) {
+                bad.push(format!("{} has maximum below its initial size", table));
+            }
+            let elements = match &data.func_elements {
+                Some(elements) => elements,
+                None => continue,
+            };
+            let (nullable, sig) = match data.ty {
+                Type::FuncRef => (true, None),
+                Type::TypedFuncRef(nullable, sig) => (nullable, Some(sig)),
+                ty => {
+                    bad.push(format!("{} of type {} has function elements", table, ty));
+                    continue;
+                }
+            };
+            for (i, &elem) in elements.iter().enumerate() {
+                if elem.is_invalid() {
+                    if !nullable {
+                        bad.push(format!("{} has null at {} but is non-nullable", table, i));
+                    }
+                } else if !func_ok(elem) || matches!(self.funcs[elem], FuncDecl::None) {
+                    bad.push(format!("{} element {} is invalid {}", table, i, elem));
+                } else if sig.map(|sig| sig as usize != self.funcs[elem].sig().index())
+                    == Some(true)
+                {
+                    bad.push(format!(
+                        "{} element {} ({}) does not match table type {}",
+                        table, i, elem, data.ty
+                    ));
+                }
+            }
+        }
+
+        for (memory, data) in self.memories.entries() {
+            const MAX_PAGES: usize = 0x1_0000;
+            if data.initial_pages > MAX_PAGES || data.maximum_pages.unwrap_or(0) > MAX_PAGES {
+                bad.push(format!("{} exceeds {} pages", memory, MAX_PAGES));
+            }
+            if data.maximum_pages.map(|max| max < data.initial_pages) == Some(true) {
+                bad.push(format!("{} has maximum below its initial size", memory));
+            }
+            let size = data.initial_pages.saturating_mul(WASM_PAGE);
+            for segment in &data.segments {
+                if segment.offset.saturating_add(segment.data.len()) > size {
+                    bad.push(format!(
+                        "{} has a data segment at {:#x} beyond its initial size",
+                        memory, segment.offset
+                    ));
+                }
+            }
+        }
+
+        if let Some(start) = self.start_func {
+            if !func_ok(start) || matches!(self.funcs[start], FuncDecl::None) {
+                bad.push(format!("Start function {} is invalid", start));
+            } else if let Some(sig_data) = self.signatures.get(self.funcs[start].sig()) {
+                if !sig_data.params.is_empty() || !sig_data.returns.is_empty() {
+                    bad.push(format!("Start function {} must have type [] -> []", start));
+                }
+            }
+        }
+
+        if !bad.is_empty() {
+            anyhow::bail!("Error(s) in module: {:?}", bad);
+        }
+        Ok(())
+    }
+
     /// Compile the module to Wasm bytecode.
+    ///
+    /// The output is byte-deterministic: the same module compiled
+    /// with the same options (here, the defaults) by the same version
+    /// of this crate always produces the same bytes, on any machine
+    /// and with any number of threads. Use `canonicalize()` first to
+    /// also make equivalent modules produce the same bytes.
     pub fn to_wasm_bytes(&self) -> Result<Vec<u8>> {
-        backend::compile(self)
+        backend::compile(self, None, &BackendOptions::default())
+    }
+
+    /// Compile the module to Wasm bytecode, reusing the compiled
+    /// forms of function bodies found in `cache` and adding those
+    /// compiled anew.
+    pub fn to_wasm_bytes_with_cache(&self, cache: &dyn CompileCache) -> Result<Vec<u8>> {
+        backend::compile(self, Some(cache), &BackendOptions::default())
+    }
+
+    /// Compile the module to Wasm bytecode with the given backend
+    /// options, optionally using `cache` as in
+    /// `to_wasm_bytes_with_cache()`.
+    pub fn to_wasm_bytes_with_options(
+        &self,
+        options: &BackendOptions,
+        cache: Option<&dyn CompileCache>,
+    ) -> Result<Vec<u8>> {
+        backend::compile(self, cache, options)
     }
 
     /// Perform some work on each function body with IR.
@@ -293,14 +755,156 @@ impl<'a> Module<'a> {
         }
     }
 
+    /// Take a snapshot of the body of `func`, if it has IR, that can
+    /// be sent to other threads. Mutating the body in this module
+    /// afterward copies it rather than affecting the snapshot.
+    pub fn shared_body(&self, func: Func) -> Option<Arc<FunctionBody>> {
+        match &self.funcs[func] {
+            FuncDecl::Body(_, _, body) => Some(body.clone()),
+            _ => None,
+        }
+    }
+
+    /// Replace the definition of `func` with `body`, keeping its
+    /// index, signature, and name, so that exports, table entries,
+    /// and callers now reach the new body. Returns the old
+    /// declaration, which a tool may push back as a new function
+    /// (after renaming it with `FuncDecl::set_name()`) and call from
+    /// the replacement. `Func::new(module.funcs.len())` is the index
+    /// it will get.
+    ///
+    /// Panics if `func` is an import, or if `body` does not have the
+    /// function's signature.
+    pub fn replace_func(&mut self, func: Func, body: FunctionBody) -> FuncDecl<'a> {
+        let (sig, name) = match &self.funcs[func] {
+            FuncDecl::Import(..) | FuncDecl::None => {
+                panic!("Cannot replace {}: not a defined function", func)
+            }
+            decl => (decl.sig(), decl.name().to_owned()),
+        };
+        let sig_data = &self.signatures[sig];
+        let params = body.blocks[body.entry]
+            .params
+            .iter()
+            .map(|&(ty, _)| ty)
+            .collect::<Vec<_>>();
+        assert!(
+            params == sig_data.params && body.rets == sig_data.returns,
+            "Replacement body for {} does not have signature {}",
+            func,
+            sig
+        );
+        std::mem::replace(
+            &mut self.funcs[func],
+            FuncDecl::Body(sig, name, body.into()),
+        )
+    }
+
+    /// Interpose a wrapper on `func`. The original definition moves
+    /// to a new function, renamed with an `_original` suffix, and
+    /// `func` becomes a wrapper with the same signature, so exports,
+    /// table entries, and callers reach the wrapper. `build_wrapper`
+    /// is called with a builder at the wrapper's entry and the moved
+    /// original's index; it emits any logic before and after calling
+    /// the original, and must terminate every block it creates.
+    /// Returns the moved original.
+    ///
+    /// Imported functions cannot be wrapped this way; see
+    /// `passes::import_shims::retarget()`.
+    pub fn wrap_func<F: FnOnce(&mut FunctionBuilder, Func)>(
+        &mut self,
+        func: Func,
+        build_wrapper: F,
+    ) -> Result<Func> {
+        if let FuncDecl::Import(..) | FuncDecl::None = &self.funcs[func] {
+            anyhow::bail!("Cannot wrap {}: not a defined function", func);
+        }
+        let sig = self.funcs[func].sig();
+        let original = Func::new(self.funcs.len());
+        let mut body = FunctionBody::new(self, sig);
+        build_wrapper(&mut FunctionBuilder::new(&mut body), original);
+        if let Some((block, _)) = body
+            .blocks
+            .entries()
+            .find(|(_, def)| def.terminator == Terminator::None)
+        {
+            anyhow::bail!("Wrapper for {} does not terminate {}", func, block);
+        }
+        body.validate()?;
+
+        let mut old = self.replace_func(func, body);
+        let name = format!("{}_original", old.name());
+        old.set_name(&name);
+        Ok(self.funcs.push(old))
+    }
+
+    /// Perform some work on each function body with IR, passing the
+    /// override from `func_overrides` that applies to it, if any.
+    pub fn per_func_body_with_overrides<F: Fn(&mut FunctionBody, Option<&FuncOverride>)>(
+        &mut self,
+        f: F,
+    ) {
+        for (func, decl) in self.funcs.entries_mut() {
+            if let FuncDecl::Body(_, name, body) = decl {
+                f(Arc::make_mut(body), self.func_overrides.get(func, name));
+            }
+        }
+    }
+
+    /// Optimize each function body with IR with `opts`, or as
+    /// `func_overrides` says for it.
+    pub fn optimize(&mut self, opts: &OptOptions) {
+        for (func, decl) in self.funcs.entries_mut() {
+            if let FuncDecl::Body(_, name, body) = decl {
+                if let Some(opts) = self.func_overrides.opt_options(func, name, opts) {
+                    Arc::make_mut(body).optimize_func(Some((func, name)), opts);
+                }
+            }
+        }
+    }
+
+    /// Convert each function body with IR to maximal SSA if `max_ssa`
+    /// is set, or as `func_overrides` says for it.
+    pub fn convert_to_max_ssa(&mut self, max_ssa: bool) {
+        for (func, decl) in self.funcs.entries_mut() {
+            if let FuncDecl::Body(_, name, body) = decl {
+                if self.func_overrides.max_ssa(func, name, max_ssa) {
+                    Arc::make_mut(body).convert_to_max_ssa(None);
+                }
+            }
+        }
+    }
+
     /// Expand a function body, parsing its lazy reference to original
-    /// bytecode into IR if needed.
+    /// bytecode into IR if needed. If the module was parsed with
+    /// `FrontendOptions::lenient`, a function that uses unsupported
+    /// features stays un-expanded and is recorded in
+    /// `unsupported_funcs`.
     pub fn expand_func<'b>(&'b mut self, id: Func) -> Result<&'b mut FuncDecl<'a>> {
         if let FuncDecl::Lazy(..) = self.funcs[id] {
+            if self.unsupported_funcs.contains_key(&id) {
+                return Ok(&mut self.funcs[id]);
+            }
             // End the borrow. This is cheap (a slice copy).
             let mut func = self.funcs[id].clone();
-            func.parse(self)?;
-            self.funcs[id] = func;
+            match self.parse_func(id, &mut func) {
+                Ok(()) => self.funcs[id] = func,
+                Err(e) if self.frontend_options.lenient => {
+                    match e.downcast_ref::<FrontendError>() {
+                        Some(FrontendError::UnsupportedFeature(reason)) => {
+                            self.frontend_options.diagnostics.report(
+                                Severity::Warning,
+                                "frontend",
+                                Some(id),
+                                format!("left un-expanded: {}", reason),
+                            );
+                            self.unsupported_funcs.insert(id, reason.clone());
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
         }
         Ok(&mut self.funcs[id])
     }
@@ -311,13 +915,23 @@ impl<'a> Module<'a> {
     /// original function (which itself must remain as well).
     pub fn clone_and_expand_body(&self, id: Func) -> Result<FunctionBody> {
         let mut body = self.funcs[id].clone();
-        body.parse(self)?;
+        self.parse_func(id, &mut body)?;
         Ok(match body {
-            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Body(_, _, body) => Arc::unwrap_or_clone(body),
             _ => unreachable!(),
         })
     }
 
+    /// Parse `decl`, the declaration of `id`, giving the body its
+    /// locals' names.
+    fn parse_func(&self, id: Func, decl: &mut FuncDecl<'a>) -> Result<()> {
+        decl.parse(self)?;
+        if let (FuncDecl::Body(_, _, body), Some(names)) = (decl, self.debug.local_names.get(&id)) {
+            Arc::make_mut(body).local_names = names.clone();
+        }
+        Ok(())
+    }
+
     /// For all functions that are lazy references to initial
     /// bytecode, expand them into IR.
     pub fn expand_all_funcs(&mut self) -> Result<()> {
@@ -328,6 +942,23 @@ impl<'a> Module<'a> {
         Ok(())
     }
 
+    /// Call `f` with each function that is still a lazy reference to
+    /// original bytecode and a reader over its operators, without
+    /// expanding it into IR. This is much cheaper than expansion for
+    /// whole-module scans such as counting opcodes. Stops at the
+    /// first error `f` returns.
+    pub fn visit_lazy_funcs<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Func, wasmparser::OperatorsReader<'a>) -> Result<()>,
+    {
+        for (id, decl) in self.funcs.entries() {
+            if let FuncDecl::Lazy(_, _, body) = decl {
+                f(id, body.get_operators_reader()?)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Return a wrapper that implements Display on this module,
     /// pretty-printing it as textual IR.
     pub fn display<'b>(&'b self) -> ModuleDisplay<'b, impl PrintDecorator>
@@ -373,6 +1004,11 @@ impl<'a> Module<'a> {
             debug: Debug::default(),
             debug_map: DebugMap::default(),
             custom_sections: BTreeMap::default(),
+            func_overrides: FuncOverrides::default(),
+            frontend_options: FrontendOptions::default(),
+            unsupported_funcs: BTreeMap::default(),
+            rodata: BTreeMap::default(),
+            intrinsics: BTreeMap::default(),
         }
     }
 }
@@ -386,4 +1022,505 @@ mod test {
         let module = Module::empty();
         let _ = module.to_wasm_bytes().unwrap();
     }
+
+    #[test]
+    fn debug_values_track_locals() {
+        use crate::ir::{DebugValueRange, Local, ValueDef};
+        use crate::passes::canonicalize;
+        use crate::Operator;
+        use wasm_encoder::{CodeSection, FunctionSection, Instruction, TypeSection, ValType};
+
+        // local1 = local0 + 1; return local1
+        let mut types = TypeSection::new();
+        types.function([ValType::I32], [ValType::I32]);
+        let mut funcs = FunctionSection::new();
+        funcs.function(0);
+        let mut code = CodeSection::new();
+        let mut func = wasm_encoder::Function::new([(1, ValType::I32)]);
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::I32Const(1));
+        func.instruction(&Instruction::I32Add);
+        func.instruction(&Instruction::LocalSet(1));
+        func.instruction(&Instruction::LocalGet(1));
+        func.instruction(&Instruction::End);
+        code.function(&func);
+        let mut encoder = wasm_encoder::Module::new();
+        encoder.section(&types).section(&funcs).section(&code);
+        let bytes = encoder.finish();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let check = |body: &FunctionBody| {
+            let param = body.blocks[body.entry].params[0].1;
+            let (add_pos, add) = body.blocks[body.entry]
+                .insts
+                .iter()
+                .enumerate()
+                .find(|&(_, &inst)| {
+                    matches!(body.values[inst], ValueDef::Operator(Operator::I32Add, ..))
+                })
+                .map(|(i, &inst)| (i, inst))
+                .unwrap();
+            let end = body.blocks[body.entry].insts.len() + 1;
+            let ranges = body.debug_values.ranges(body);
+            assert!(ranges.contains(&DebugValueRange {
+                block: body.entry,
+                insts: 0..end,
+                local: Local::new(0),
+                value: param,
+            }));
+            assert!(ranges.contains(&DebugValueRange {
+                block: body.entry,
+                insts: add_pos + 1..end,
+                local: Local::new(1),
+                value: add,
+            }));
+        };
+        let body = module.funcs[Func::new(0)].body_mut().unwrap();
+        check(body);
+        body.optimize(&OptOptions::default());
+        check(body);
+        check(&canonicalize::renumber(body));
+    }
+
+    #[test]
+    fn local_names_in_display_and_backtrace() {
+        use crate::ir::Local;
+        use crate::{ConstVal, InterpContext, InterpResult};
+        use wasm_encoder::{
+            CodeSection, FunctionSection, IndirectNameMap, Instruction, NameMap, NameSection,
+            TypeSection, ValType,
+        };
+
+        // sum = x + 1; unreachable
+        let mut types = TypeSection::new();
+        types.function([ValType::I32], []);
+        let mut funcs = FunctionSection::new();
+        funcs.function(0);
+        let mut code = CodeSection::new();
+        let mut func = wasm_encoder::Function::new([(1, ValType::I32)]);
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::I32Const(1));
+        func.instruction(&Instruction::I32Add);
+        func.instruction(&Instruction::LocalSet(1));
+        func.instruction(&Instruction::Unreachable);
+        func.instruction(&Instruction::End);
+        code.function(&func);
+        let mut local_names = NameMap::new();
+        local_names.append(0, "x");
+        local_names.append(1, "sum");
+        let mut locals = IndirectNameMap::new();
+        locals.append(0, &local_names);
+        let mut names = NameSection::new();
+        names.locals(&locals);
+        let mut encoder = wasm_encoder::Module::new();
+        encoder
+            .section(&types)
+            .section(&funcs)
+            .section(&code)
+            .section(&names);
+        let bytes = encoder.finish();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let func = Func::new(0);
+        let body = module.funcs[func].body().unwrap();
+        assert_eq!(body.local_names[&Local::new(1)], "sum");
+        let text = body.display("", Some(&module)).to_string();
+        assert!(text.contains("local0 \"x\""), "{}", text);
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, func, &[ConstVal::I32(41)]);
+        assert!(matches!(result, InterpResult::Trap(..)));
+        assert_eq!(ctx.backtrace.len(), 1);
+        let locals = &ctx.backtrace[0].locals;
+        assert!(locals.contains(&(Local::new(0), Some("x".to_owned()), ConstVal::I32(41))));
+        assert!(locals.contains(&(Local::new(1), Some("sum".to_owned()), ConstVal::I32(42))));
+    }
+
+    #[test]
+    fn lenient_keeps_unsupported_funcs() {
+        use wasm_encoder::{CodeSection, FunctionSection, Instruction, TypeSection};
+
+        // Function 0 tail-calls function 1, which returns a constant.
+        let mut types = TypeSection::new();
+        types.function([], [wasm_encoder::ValType::I32]);
+        let mut funcs = FunctionSection::new();
+        funcs.function(0);
+        funcs.function(0);
+        let mut code = CodeSection::new();
+        let mut tail = wasm_encoder::Function::new([]);
+        tail.instruction(&Instruction::ReturnCall(1));
+        tail.instruction(&Instruction::End);
+        code.function(&tail);
+        let mut leaf = wasm_encoder::Function::new([]);
+        leaf.instruction(&Instruction::I32Const(42));
+        leaf.instruction(&Instruction::End);
+        code.function(&leaf);
+        let mut encoder = wasm_encoder::Module::new();
+        encoder.section(&types).section(&funcs).section(&code);
+        let bytes = encoder.finish();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        assert!(module.expand_all_funcs().is_err());
+
+        let options = FrontendOptions {
+            lenient: true,
+            ..FrontendOptions::default()
+        };
+        let mut module = Module::from_wasm_bytes(&bytes, &options).unwrap();
+        module.expand_all_funcs().unwrap();
+        assert!(matches!(module.funcs[Func::new(0)], FuncDecl::Lazy(..)));
+        assert!(matches!(module.funcs[Func::new(1)], FuncDecl::Body(..)));
+        assert_eq!(
+            module.unsupported_funcs.keys().copied().collect::<Vec<_>>(),
+            vec![Func::new(0)]
+        );
+        let module = module.without_orig_bytes();
+        assert!(matches!(module.funcs[Func::new(0)], FuncDecl::Compiled(..)));
+        let produced = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new()
+            .validate_all(&produced)
+            .unwrap();
+    }
+
+    #[test]
+    fn without_orig_bytes_keeps_overrides() {
+        use crate::passes::overrides::FuncMatcher;
+
+        let mut module = crate::testgen::wat_module("(module (func))", &FrontendOptions::default());
+        module.expand_all_funcs().unwrap();
+        let f = Func::new(0);
+        module.func_overrides.add(
+            FuncMatcher::Func(f),
+            FuncOverride {
+                no_opt: true,
+                ..FuncOverride::default()
+            },
+        );
+        let module = module.without_orig_bytes();
+        let options = OptOptions::default();
+        assert!(module
+            .func_overrides
+            .opt_options(f, "f", &options)
+            .is_none());
+    }
+
+    #[test]
+    fn replace_func_keeps_references() {
+        use crate::{Operator, Terminator, Type};
+
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let identity = |module: &Module| {
+            let mut body = FunctionBody::new(module, sig);
+            let x = body.blocks[body.entry].params[0].1;
+            body.set_terminator(body.entry, Terminator::Return { values: vec![x] });
+            body
+        };
+        let body = identity(&module);
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "f".to_owned(),
+            kind: ExportKind::Func(f),
+        });
+
+        // The replacement calls the original, renamed, and adds one.
+        let original = Func::new(module.funcs.len());
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let call = Operator::Call {
+            function_index: original,
+        };
+        let y = body.add_op(entry, call, &[x], &[Type::I32]);
+        let one = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let sum = body.add_op(entry, Operator::I32Add, &[y, one], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![sum] });
+
+        let mut old = module.replace_func(f, body);
+        old.set_name("f_original");
+        assert_eq!(module.funcs.push(old), original);
+        assert_eq!(module.funcs[f].name(), "f");
+        assert_eq!(module.exports[0].kind, ExportKind::Func(f));
+
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut ctx = crate::InterpContext::new(&module).unwrap();
+        let result = ctx
+            .call(&module, f, &[crate::ConstVal::I32(41)])
+            .ok()
+            .unwrap();
+        assert_eq!(&result[..], &[crate::ConstVal::I32(42)]);
+    }
+
+    #[test]
+    fn verify_catches_inconsistencies() {
+        let mut module = crate::testgen::gen_module(0, &Default::default());
+        module.verify().unwrap();
+
+        let func = Func::new(0);
+        module.start_func = Some(func);
+        module.exports.push(Export {
+            name: "f0".to_owned(),
+            kind: ExportKind::Memory(Memory::new(5)),
+        });
+        module.memories[Memory::new(0)].maximum_pages = Some(0);
+        let err = module.verify().unwrap_err().to_string();
+        assert!(err.contains("names invalid"));
+        assert!(err.contains("Duplicate export name"));
+        assert!(err.contains("maximum below"));
+    }
+
+    #[test]
+    fn wrap_func_interposes() {
+        use crate::{ConstVal, InterpContext, Operator, Type};
+
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let x = body.blocks[body.entry].params[0].1;
+        body.set_terminator(body.entry, Terminator::Return { values: vec![x] });
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "f".to_owned(),
+            kind: ExportKind::Func(f),
+        });
+
+        // The wrapper doubles the argument before the call and adds
+        // one after it.
+        let original = module
+            .wrap_func(f, |b, original| {
+                let x = b.params()[0];
+                let doubled = b.op(Operator::I32Add, &[x, x], &[Type::I32]);
+                let y = b.call(original, &[doubled], &[Type::I32])[0];
+                let one = b.op(Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+                let sum = b.op(Operator::I32Add, &[y, one], &[Type::I32]);
+                b.ret(&[sum]);
+            })
+            .unwrap();
+        assert_eq!(module.funcs[original].name(), "f_original");
+        assert_eq!(module.exports[0].kind, ExportKind::Func(f));
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, f, &[ConstVal::I32(20)]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(41)]);
+
+        // A wrapper must terminate its blocks.
+        assert!(module.wrap_func(f, |_, _| {}).is_err());
+    }
+
+    #[test]
+    fn shared_bodies_are_snapshots() {
+        use crate::{Operator, Type};
+
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut funcs = vec![];
+        for i in 0..4 {
+            let mut body = FunctionBody::new(&module, sig);
+            let x = body.blocks[body.entry].params[0].1;
+            body.set_terminator(body.entry, Terminator::Return { values: vec![x] });
+            funcs.push(
+                module
+                    .funcs
+                    .push(FuncDecl::Body(sig, format!("f{}", i), body.into())),
+            );
+        }
+        let snapshots = funcs
+            .iter()
+            .map(|&func| module.shared_body(func).unwrap())
+            .collect::<Vec<_>>();
+        let hashes = std::thread::scope(|scope| {
+            let handles = snapshots
+                .iter()
+                .map(|body| scope.spawn(move || body.structural_hash()))
+                .collect::<Vec<_>>();
+            // Meanwhile, mutate every body in the module.
+            for &func in &funcs {
+                let body = module.funcs[func].body_mut().unwrap();
+                body.add_op(
+                    body.entry,
+                    Operator::I32Const { value: 1 },
+                    &[],
+                    &[Type::I32],
+                );
+            }
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for (i, &func) in funcs.iter().enumerate() {
+            assert_eq!(snapshots[i].blocks[snapshots[i].entry].insts.len(), 0);
+            assert_eq!(hashes[i], snapshots[i].structural_hash());
+            let body = module.funcs[func].body().unwrap();
+            assert_eq!(body.blocks[body.entry].insts.len(), 1);
+            assert_ne!(hashes[i], body.structural_hash());
+        }
+    }
+
+    #[test]
+    fn heap_size_counts_bodies() {
+        use crate::testgen::{gen_module, GenOptions};
+        use wasm_encoder::Encode;
+
+        let mut module = gen_module(1, &GenOptions::default());
+        let expanded = module.heap_size_estimate();
+        assert!(expanded.values > 0 && expanded.pools > 0 && expanded.blocks > 0);
+        let body_sizes = module
+            .funcs
+            .values()
+            .filter_map(|decl| decl.body())
+            .map(|body| body.heap_size().total())
+            .sum::<usize>();
+        assert!(expanded.total() > body_sizes);
+
+        // Compiling the bodies trades IR for (much smaller) bytecode.
+        for decl in module.funcs.values_mut() {
+            if let FuncDecl::Body(sig, name, body) = decl {
+                let mut bytes = vec![];
+                body.compile().unwrap().encode(&mut bytes);
+                *decl = FuncDecl::Compiled(*sig, name.clone(), bytes);
+            }
+        }
+        let compiled = module.heap_size_estimate();
+        assert_eq!(compiled.values + compiled.pools + compiled.blocks, 0);
+        assert!(compiled.code > 0 && compiled.total() < expanded.total());
+    }
+
+    #[test]
+    fn visit_lazy_funcs_streams_operators() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 1
+                  i32.add)
+                (func (result i32)
+                  i32.const 2))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_func(Func::new(1)).unwrap();
+
+        let mut seen = vec![];
+        module
+            .visit_lazy_funcs(|func, ops| {
+                for op in ops {
+                    seen.push((func, format!("{:?}", op?)));
+                }
+                Ok(())
+            })
+            .unwrap();
+        let f0 = Func::new(0);
+        assert_eq!(
+            seen,
+            vec![
+                (f0, "LocalGet { local_index: 0 }".to_owned()),
+                (f0, "I32Const { value: 1 }".to_owned()),
+                (f0, "I32Add".to_owned()),
+                (f0, "End".to_owned()),
+            ]
+        );
+        assert!(matches!(module.funcs[f0], FuncDecl::Lazy(..)));
+    }
+
+    #[test]
+    fn block_origins() {
+        use crate::ir::BlockOrigin;
+
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (param i32)
+                  block
+                    local.get 0
+                    br_if 0
+                  end))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let body = module.funcs[Func::new(0)].body_mut().unwrap();
+
+        // Every block created for the `block` and `br_if` points at
+        // its opcode; the entry points at the function body.
+        let mut opcodes = vec![];
+        for block in body.blocks.values() {
+            match block.origin {
+                BlockOrigin::FromWasmOffset(offset) => opcodes.push(bytes[offset as usize]),
+                ref other => panic!("unexpected origin {:?}", other),
+            }
+        }
+        assert!(opcodes.contains(&0x02), "{:?}", opcodes);
+        assert!(opcodes.contains(&0x0d), "{:?}", opcodes);
+
+        let entry = body.entry;
+        body.blocks[entry].origin = BlockOrigin::UserLabel("start".to_owned());
+        let text = body.display("", None).to_string();
+        assert!(text.contains("# label:start"), "{}", text);
+        assert!(text.contains("# wasm@0x"), "{}", text);
+    }
+
+    #[test]
+    fn wasm_offsets_point_at_instructions() {
+        use crate::{Operator, ValueDef};
+
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (param i32 i32) (result i32)
+                  local.get 0
+                  local.get 1
+                  i32.div_u))"#,
+        )
+        .unwrap();
+        let options = FrontendOptions {
+            wasm_offsets: true,
+            ..FrontendOptions::default()
+        };
+        let mut module = Module::from_wasm_bytes(&bytes, &options).unwrap();
+        module.expand_all_funcs().unwrap();
+        let body = module.funcs[Func::new(0)].body().unwrap();
+        let div = body
+            .values
+            .iter()
+            .find(|&value| {
+                matches!(
+                    body.values[value],
+                    ValueDef::Operator(Operator::I32DivU, ..)
+                )
+            })
+            .unwrap();
+        let offset = body.wasm_offsets[div].unwrap();
+        assert_eq!(bytes[offset as usize], 0x6e); // i32.div_u
+        assert_eq!(
+            body.values_at_wasm_offset(offset).collect::<Vec<_>>(),
+            vec![div]
+        );
+        let text = body.display("", None).to_string();
+        assert!(text.contains(&format!("wasm@{:#x}", offset)), "{}", text);
+
+        // The option is off by default.
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let body = module.funcs[Func::new(0)].body().unwrap();
+        assert!(body
+            .values
+            .iter()
+            .all(|value| body.wasm_offsets[value].is_none()));
+    }
 }
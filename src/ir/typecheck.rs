@@ -0,0 +1,310 @@
+//! Type checking of function bodies.
+
+use super::{Block, FunctionBody, Module, Type, Value, ValueDef};
+use crate::entity::PerEntity;
+use crate::op_traits::{op_inputs, op_outputs};
+use crate::Operator;
+
+/// A type error found by `FunctionBody::typecheck()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeError {
+    /// The block in which the error occurs.
+    pub block: Block,
+    /// The value whose definition is ill-typed, or `None` if the
+    /// error is in the block's terminator.
+    pub value: Option<Value>,
+    /// The operator involved, if any.
+    pub op: Option<Operator>,
+    /// What is being checked, e.g. "argument 1" or "result types".
+    pub what: String,
+    /// The expected type(s).
+    pub expected: Vec<Type>,
+    /// The type(s) actually found.
+    pub actual: Vec<Type>,
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "in {}", self.block)?;
+        match self.value {
+            Some(value) => write!(f, ", {}", value)?,
+            None => write!(f, ", terminator")?,
+        }
+        if let Some(op) = &self.op {
+            write!(f, " ({})", op)?;
+        }
+        write!(
+            f,
+            ": {} expected {:?}, found {:?}",
+            self.what, self.expected, self.actual
+        )
+    }
+}
+
+/// The result of type-checking a function body.
+#[derive(Clone, Debug, Default)]
+pub struct TypeCheck {
+    /// The type(s) inferred for each value. For operators, these are
+    /// computed from the operator's signature and arguments rather
+    /// than taken from the value's declared result types.
+    pub types: PerEntity<Value, Vec<Type>>,
+    /// All type errors found.
+    pub errors: Vec<TypeError>,
+}
+
+impl TypeCheck {
+    /// Return the inferred types if there were no errors, or an error
+    /// listing all of them.
+    pub fn into_result(self) -> anyhow::Result<PerEntity<Value, Vec<Type>>> {
+        if self.errors.is_empty() {
+            Ok(self.types)
+        } else {
+            let errors = self
+                .errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>();
+            anyhow::bail!("Type error(s): {}", errors.join("; "))
+        }
+    }
+}
+
+impl FunctionBody {
+    /// Infer the type of every value from the signatures of the
+    /// operators that compute it, and check that every operand,
+    /// declared result type, branch argument, and return value
+    /// agrees. Reference types may be used where a supertype is
+    /// expected (see `Type::is_subtype_of()`). Only reachable blocks
+    /// are checked; `validate()` checks everything else about SSA
+    /// form.
+    pub fn typecheck(&self, module: &Module) -> TypeCheck {
+        let mut check = TypeCheck::default();
+        let cfg = crate::cfg::CFGInfo::new(self);
+        for &block in cfg.rpo.values() {
+            let def = &self.blocks[block];
+            for &(ty, param) in &def.params {
+                check.types[param] = vec![ty];
+            }
+            for &inst in &def.insts {
+                let types = self.typecheck_inst(module, block, inst, &mut check);
+                check.types[inst] = types;
+            }
+            self.typecheck_terminator(block, &mut check);
+        }
+        check
+    }
+
+    /// The single type of `value` as inferred so far, or an error
+    /// description if it has none.
+    fn operand_type(&self, check: &TypeCheck, value: Value) -> Result<Type, Vec<Type>> {
+        let value = self.resolve_alias(value);
+        match &check.types[value][..] {
+            &[ty] => Ok(ty),
+            tys => Err(tys.to_vec()),
+        }
+    }
+
+    fn typecheck_inst(
+        &self,
+        module: &Module,
+        block: Block,
+        inst: Value,
+        check: &mut TypeCheck,
+    ) -> Vec<Type> {
+        let error = |check: &mut TypeCheck, op, what: String, expected, actual| {
+            check.errors.push(TypeError {
+                block,
+                value: Some(inst),
+                op,
+                what,
+                expected,
+                actual,
+            });
+        };
+        match &self.values[inst] {
+            ValueDef::Operator(op, args, tys) => {
+                let declared = self.type_pool[*tys].to_vec();
+                let mut stack = vec![];
+                for (i, &arg) in self.arg_pool[*args].iter().enumerate() {
+                    match self.operand_type(check, arg) {
+                        Ok(ty) => stack.push((ty, arg)),
+                        Err(tys) => {
+                            error(check, Some(*op), format!("argument {}", i), vec![], tys);
+                            return declared;
+                        }
+                    }
+                }
+                let arg_tys = stack.iter().map(|&(ty, _)| ty).collect::<Vec<_>>();
+                // `select` takes its type from its operands.
+                if matches!(op, Operator::Select) && stack.len() != 3 {
+                    error(check, Some(*op), "arguments".to_owned(), vec![], arg_tys);
+                    return declared;
+                }
+                let inputs = match op_inputs(module, &stack[..], op) {
+                    Ok(inputs) => inputs,
+                    Err(e) => {
+                        error(check, Some(*op), e.to_string(), vec![], arg_tys);
+                        return declared;
+                    }
+                };
+                if inputs.len() != arg_tys.len() {
+                    error(
+                        check,
+                        Some(*op),
+                        "arguments".to_owned(),
+                        inputs.to_vec(),
+                        arg_tys.clone(),
+                    );
+                } else {
+                    for (i, (&expected, &actual)) in inputs.iter().zip(arg_tys.iter()).enumerate() {
+                        if !actual.is_subtype_of(expected) {
+                            error(
+                                check,
+                                Some(*op),
+                                format!("argument {}", i),
+                                vec![expected],
+                                vec![actual],
+                            );
+                        }
+                    }
+                }
+                if let Operator::Select = op {
+                    if arg_tys[0] != arg_tys[1] {
+                        error(
+                            check,
+                            Some(*op),
+                            "operands".to_owned(),
+                            vec![arg_tys[0], arg_tys[0]],
+                            arg_tys[..2].to_vec(),
+                        );
+                    }
+                }
+                let outputs = match op_outputs(module, &stack[..], op) {
+                    Ok(outputs) => outputs.to_vec(),
+                    Err(_) => return declared,
+                };
+                let matches = outputs.len() == declared.len()
+                    && outputs
+                        .iter()
+                        .zip(declared.iter())
+                        .all(|(&inferred, &declared)| inferred.is_subtype_of(declared));
+                if !matches {
+                    error(
+                        check,
+                        Some(*op),
+                        "result types".to_owned(),
+                        outputs.clone(),
+                        declared,
+                    );
+                }
+                outputs
+            }
+            &ValueDef::PickOutput(value, index, ty) => {
+                let tys = &check.types[self.resolve_alias(value)];
+                match tys.get(index as usize) {
+                    Some(&actual) if actual.is_subtype_of(ty) => {}
+                    _ => {
+                        let tys = tys.clone();
+                        error(check, None, format!("output {}", index), vec![ty], tys);
+                    }
+                }
+                vec![ty]
+            }
+            ValueDef::BlockParam(_, _, ty) => vec![*ty],
+            def => {
+                let tys = def.tys(&self.type_pool).to_vec();
+                error(check, None, format!("definition {:?}", def), vec![], tys);
+                vec![]
+            }
+        }
+    }
+
+    fn typecheck_terminator(&self, block: Block, check: &mut TypeCheck) {
+        let expect = |check: &mut TypeCheck, what: String, expected: &[Type], values: &[Value]| {
+            let actual = values
+                .iter()
+                .map(|&value| self.operand_type(check, value).ok())
+                .collect::<Vec<_>>();
+            let ok = expected.len() == values.len()
+                && expected.iter().zip(actual.iter()).all(
+                    |(&expected, actual)| matches!(actual, Some(ty) if ty.is_subtype_of(expected)),
+                );
+            if !ok {
+                check.errors.push(TypeError {
+                    block,
+                    value: None,
+                    op: None,
+                    what,
+                    expected: expected.to_vec(),
+                    actual: actual.into_iter().flatten().collect(),
+                });
+            }
+        };
+        let terminator = &self.blocks[block].terminator;
+        terminator.visit_targets(|target| {
+            let params = self.blocks[target.block]
+                .params
+                .iter()
+                .map(|&(ty, _)| ty)
+                .collect::<Vec<_>>();
+            expect(
+                check,
+                format!("arguments to {}", target.block),
+                &params,
+                &target.args,
+            );
+        });
+        match terminator {
+            super::Terminator::CondBr { cond, .. } => {
+                expect(check, "condition".to_owned(), &[Type::I32], &[*cond])
+            }
+            super::Terminator::Select { value, .. } => {
+                expect(check, "selector".to_owned(), &[Type::I32], &[*value])
+            }
+            super::Terminator::Return { values } => {
+                expect(check, "return values".to_owned(), &self.rets, values)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{SignatureData, Terminator};
+
+    #[test]
+    fn reports_mismatches() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I64],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        // i32.add of an i64, declared to produce an f32.
+        let bad = body.add_op(entry, Operator::I32Add, &[x, x], &[Type::F32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![bad] });
+
+        let check = body.typecheck(&module);
+        let whats = check
+            .errors
+            .iter()
+            .map(|e| e.what.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(whats, vec!["argument 0", "argument 1", "result types"]);
+        assert_eq!(check.types[bad], vec![Type::I32]);
+        assert!(check.into_result().is_err());
+
+        let module = crate::testgen::gen_module(5, &Default::default());
+        for decl in module.funcs.values() {
+            decl.body()
+                .unwrap()
+                .typecheck(&module)
+                .into_result()
+                .unwrap();
+        }
+    }
+}
@@ -20,8 +20,11 @@ pub use wasmparser;
 pub use wasm_encoder;
 
 mod backend;
+pub mod bisect;
 pub mod cfg;
+pub mod diagnostics;
 pub mod entity;
+pub mod equiv;
 mod errors;
 mod frontend;
 mod ir;
@@ -30,16 +33,33 @@ mod ops;
 pub mod passes;
 pub mod pool;
 mod scoped_map;
+pub mod testgen;
 
+pub use backend::cache::{CompileCache, FsCompileCache};
+pub use backend::frame::FrameInfo;
+pub use backend::offset_map::{OffsetMap, OffsetMapEntry, OffsetMapLoc};
+pub use backend::stack_map::{StackMap, StackMapRecord};
+pub use backend::{BackendOptions, EmitInfo};
+pub use diagnostics::{Diagnostic, DiagnosticSink, Diagnostics, Severity};
 pub use errors::*;
 pub use ir::*;
-pub use op_traits::SideEffect;
-pub use ops::{Ieee32, Ieee64, MemoryArg, Operator};
+pub use op_traits::{ImmediateKind, OpInfo, SideEffect, TypeRule};
+pub use ops::{Ieee32, Ieee64, LaneIdx, MemoryArg, Operator};
 
 mod interp;
 pub use interp::*;
 
-pub use passes::basic_opt::OptOptions;
+pub use passes::atomics::AtomicWaits;
+pub use passes::basic_opt::{OptLevel, OptOptions};
+pub use passes::cost::{CostModel, DefaultCostModel, SizeCostModel};
+pub use passes::downgrade::TargetFeatures;
+pub use passes::effects::{EffectSummaries, EffectSummary};
+pub use passes::features::{Feature, FeatureSet};
+pub use passes::intrinsics::Intrinsic;
+pub use passes::switch::SwitchLowering;
 
 #[cfg(feature = "fuzzing")]
 pub mod fuzzing;
+
+#[cfg(feature = "bench")]
+pub mod bench;
@@ -2,7 +2,7 @@
 
 use crate::entity::EntityRef;
 use crate::ir::{Module, Type, Value};
-use crate::{MemoryArg, Operator};
+use crate::{LaneIdx, MemoryArg, Operator};
 use anyhow::Result;
 use std::borrow::Cow;
 
@@ -219,7 +219,7 @@ pub fn op_inputs(
             Ok(vec![Type::I32, module.tables[*table_index].ty].into())
         }
         Operator::TableGrow { table_index } => {
-            Ok(vec![Type::I32, module.tables[*table_index].ty].into())
+            Ok(vec![module.tables[*table_index].ty, Type::I32].into())
         }
         Operator::TableSize { .. } => Ok(Cow::Borrowed(&[])),
         Operator::MemorySize { .. } => Ok(Cow::Borrowed(&[])),
@@ -691,7 +691,7 @@ pub fn op_outputs(
         Operator::I64ReinterpretF64 => Ok(Cow::Borrowed(&[Type::I64])),
         Operator::TableGet { table_index } => Ok(vec![module.tables[*table_index].ty].into()),
         Operator::TableSet { .. } => Ok(Cow::Borrowed(&[])),
-        Operator::TableGrow { .. } => Ok(Cow::Borrowed(&[])),
+        Operator::TableGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::TableSize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::MemorySize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::MemoryGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
@@ -699,7 +699,7 @@ pub fn op_outputs(
         Operator::MemoryFill { .. } => Ok(Cow::Borrowed(&[])),
 
         Operator::V128Load { .. } => Ok(Cow::Borrowed(&[Type::V128])),
-        Operator::V128Load8x8S { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+        Operator::V128Load8x8S { .. } => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::V128Load8x8U { .. } => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::V128Load16x4S { .. } => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::V128Load16x4U { .. } => Ok(Cow::Borrowed(&[Type::V128])),
@@ -812,7 +812,7 @@ pub fn op_outputs(
         Operator::I8x16Neg => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I8x16Popcnt => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I8x16AllTrue => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::I8x16Bitmask => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I8x16Bitmask => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::I8x16NarrowI16x8S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I8x16NarrowI16x8U => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I8x16Shl => Ok(Cow::Borrowed(&[Type::V128])),
@@ -836,16 +836,16 @@ pub fn op_outputs(
         Operator::I16x8Neg => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8Q15MulrSatS => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8AllTrue => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::I16x8Bitmask => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I16x8Bitmask => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::I16x8NarrowI32x4S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8NarrowI32x4U => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8ExtendLowI8x16S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8ExtendHighI8x16S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8ExtendLowI8x16U => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8ExtendHighI8x16U => Ok(Cow::Borrowed(&[Type::V128])),
-        Operator::I16x8Shl => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I16x8ShrS => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I16x8ShrU => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
+        Operator::I16x8Shl => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I16x8ShrS => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I16x8ShrU => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8Add => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8AddSatS => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I16x8AddSatU => Ok(Cow::Borrowed(&[Type::V128])),
@@ -868,14 +868,14 @@ pub fn op_outputs(
         Operator::I32x4Abs => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4Neg => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4AllTrue => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::I32x4Bitmask => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I32x4Bitmask => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::I32x4ExtendLowI16x8S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4ExtendHighI16x8S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4ExtendLowI16x8U => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4ExtendHighI16x8U => Ok(Cow::Borrowed(&[Type::V128])),
-        Operator::I32x4Shl => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I32x4ShrS => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I32x4ShrU => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
+        Operator::I32x4Shl => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I32x4ShrS => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I32x4ShrU => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4Add => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4Sub => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I32x4Mul => Ok(Cow::Borrowed(&[Type::V128])),
@@ -892,14 +892,14 @@ pub fn op_outputs(
         Operator::I64x2Abs => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2Neg => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2AllTrue => Ok(Cow::Borrowed(&[Type::I32])),
-        Operator::I64x2Bitmask => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I64x2Bitmask => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::I64x2ExtendLowI32x4S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2ExtendHighI32x4S => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2ExtendLowI32x4U => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2ExtendHighI32x4U => Ok(Cow::Borrowed(&[Type::V128])),
-        Operator::I64x2Shl => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I64x2ShrS => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
-        Operator::I64x2ShrU => Ok(Cow::Borrowed(&[Type::V128, Type::I32])),
+        Operator::I64x2Shl => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I64x2ShrS => Ok(Cow::Borrowed(&[Type::V128])),
+        Operator::I64x2ShrU => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2Add => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2Sub => Ok(Cow::Borrowed(&[Type::V128])),
         Operator::I64x2Mul => Ok(Cow::Borrowed(&[Type::V128])),
@@ -1462,6 +1462,17 @@ impl Operator {
         }
     }
 
+    /// Can control leave the operator by unwinding, rather than by
+    /// returning or trapping?
+    ///
+    /// The IR has no exception-handling operators, so nothing can
+    /// catch an unwind within a function body; but any call may
+    /// unwind, for example when an import raises a host exception
+    /// that propagates through Wasm frames.
+    pub fn may_unwind(&self) -> bool {
+        self.is_call() || matches!(self, Operator::CallRef { .. })
+    }
+
     /// Does the operator access (read or write) memory?
     pub fn accesses_memory(&self) -> bool {
         self.effects().iter().any(|e| match e {
@@ -1599,10 +1610,313 @@ impl Operator {
         }
     }
 
+    /// Return the `MemoryArg` of an ordinary load or store together
+    /// with the number of bytes it accesses, starting at the dynamic
+    /// address plus the static offset.
+    pub fn memory_access(&self) -> Option<(MemoryArg, usize)> {
+        match self {
+            Operator::I32Load8S { memory }
+            | Operator::I32Load8U { memory }
+            | Operator::I64Load8S { memory }
+            | Operator::I64Load8U { memory }
+            | Operator::V128Load8Splat { memory }
+            | Operator::V128Load8Lane { memory, .. }
+            | Operator::I32Store8 { memory }
+            | Operator::I64Store8 { memory }
+            | Operator::V128Store8Lane { memory, .. } => Some((*memory, 1)),
+            Operator::I32Load16S { memory }
+            | Operator::I32Load16U { memory }
+            | Operator::I64Load16S { memory }
+            | Operator::I64Load16U { memory }
+            | Operator::V128Load16Splat { memory }
+            | Operator::V128Load16Lane { memory, .. }
+            | Operator::I32Store16 { memory }
+            | Operator::I64Store16 { memory }
+            | Operator::V128Store16Lane { memory, .. } => Some((*memory, 2)),
+            Operator::I32Load { memory }
+            | Operator::F32Load { memory }
+            | Operator::I64Load32S { memory }
+            | Operator::I64Load32U { memory }
+            | Operator::V128Load32Splat { memory }
+            | Operator::V128Load32Zero { memory }
+            | Operator::V128Load32Lane { memory, .. }
+            | Operator::I32Store { memory }
+            | Operator::F32Store { memory }
+            | Operator::I64Store32 { memory }
+            | Operator::V128Store32Lane { memory, .. } => Some((*memory, 4)),
+            Operator::I64Load { memory }
+            | Operator::F64Load { memory }
+            | Operator::V128Load8x8S { memory }
+            | Operator::V128Load8x8U { memory }
+            | Operator::V128Load16x4S { memory }
+            | Operator::V128Load16x4U { memory }
+            | Operator::V128Load32x2S { memory }
+            | Operator::V128Load32x2U { memory }
+            | Operator::V128Load64Splat { memory }
+            | Operator::V128Load64Zero { memory }
+            | Operator::V128Load64Lane { memory, .. }
+            | Operator::I64Store { memory }
+            | Operator::F64Store { memory }
+            | Operator::V128Store64Lane { memory, .. } => Some((*memory, 8)),
+            Operator::V128Load { memory } | Operator::V128Store { memory } => Some((*memory, 16)),
+            _ => None,
+        }
+    }
+
+    /// The number of lanes the operator's `lane` immediate selects
+    /// among, if it has one.
+    pub fn lane_count(&self) -> Option<u8> {
+        match self {
+            Operator::I8x16ExtractLaneS { .. }
+            | Operator::I8x16ExtractLaneU { .. }
+            | Operator::I8x16ReplaceLane { .. }
+            | Operator::V128Load8Lane { .. }
+            | Operator::V128Store8Lane { .. } => Some(16),
+            Operator::I16x8ExtractLaneS { .. }
+            | Operator::I16x8ExtractLaneU { .. }
+            | Operator::I16x8ReplaceLane { .. }
+            | Operator::V128Load16Lane { .. }
+            | Operator::V128Store16Lane { .. } => Some(8),
+            Operator::I32x4ExtractLane { .. }
+            | Operator::I32x4ReplaceLane { .. }
+            | Operator::F32x4ExtractLane { .. }
+            | Operator::F32x4ReplaceLane { .. }
+            | Operator::V128Load32Lane { .. }
+            | Operator::V128Store32Lane { .. } => Some(4),
+            Operator::I64x2ExtractLane { .. }
+            | Operator::I64x2ReplaceLane { .. }
+            | Operator::F64x2ExtractLane { .. }
+            | Operator::F64x2ReplaceLane { .. }
+            | Operator::V128Load64Lane { .. }
+            | Operator::V128Store64Lane { .. } => Some(2),
+            _ => None,
+        }
+    }
+
+    /// The operator's `lane` immediate, if it has one.
+    pub fn lane(&self) -> Option<LaneIdx> {
+        match self {
+            Operator::I8x16ExtractLaneS { lane }
+            | Operator::I8x16ExtractLaneU { lane }
+            | Operator::I8x16ReplaceLane { lane }
+            | Operator::I16x8ExtractLaneS { lane }
+            | Operator::I16x8ExtractLaneU { lane }
+            | Operator::I16x8ReplaceLane { lane }
+            | Operator::I32x4ExtractLane { lane }
+            | Operator::I32x4ReplaceLane { lane }
+            | Operator::I64x2ExtractLane { lane }
+            | Operator::I64x2ReplaceLane { lane }
+            | Operator::F32x4ExtractLane { lane }
+            | Operator::F32x4ReplaceLane { lane }
+            | Operator::F64x2ExtractLane { lane }
+            | Operator::F64x2ReplaceLane { lane }
+            | Operator::V128Load8Lane { lane, .. }
+            | Operator::V128Load16Lane { lane, .. }
+            | Operator::V128Load32Lane { lane, .. }
+            | Operator::V128Load64Lane { lane, .. }
+            | Operator::V128Store8Lane { lane, .. }
+            | Operator::V128Store16Lane { lane, .. }
+            | Operator::V128Store32Lane { lane, .. }
+            | Operator::V128Store64Lane { lane, .. } => Some(*lane),
+            _ => None,
+        }
+    }
+
+    /// Check the immediates that the types alone do not constrain: a
+    /// lane index must be below the operator's lane count, and an
+    /// alignment must not exceed the width of the access.
+    pub fn check_immediates(&self) -> Result<()> {
+        if let (Some(lane), Some(count)) = (self.lane(), self.lane_count()) {
+            if lane.get() >= count {
+                anyhow::bail!("{}: lane {} out of range for {} lanes", self, lane, count);
+            }
+        }
+        if let Some((memory, width)) = self.memory_access() {
+            if 1usize
+                .checked_shl(memory.align)
+                .is_none_or(|align| align > width)
+            {
+                anyhow::bail!(
+                    "{}: alignment 2^{} exceeds access width {}",
+                    self,
+                    memory.align,
+                    width
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Is the operator capable of trapping?
     pub fn can_trap(&self) -> bool {
         self.effects().contains(&SideEffect::Trap)
     }
+
+    /// The kinds of immediates that this operator carries, in field
+    /// order.
+    pub fn immediates(&self) -> &'static [ImmediateKind] {
+        use ImmediateKind::*;
+        match self {
+            Operator::Call { .. } | Operator::RefFunc { .. } => &[Func],
+            Operator::CallIndirect { .. } => &[Signature, Table],
+            Operator::CallRef { .. } | Operator::RefNull { .. } => &[Signature],
+            Operator::TypedSelect { .. } => &[Type],
+            Operator::GlobalGet { .. } | Operator::GlobalSet { .. } => &[Global],
+            Operator::TableGet { .. }
+            | Operator::TableSet { .. }
+            | Operator::TableGrow { .. }
+            | Operator::TableSize { .. } => &[Table],
+            Operator::MemorySize { .. }
+            | Operator::MemoryGrow { .. }
+            | Operator::MemoryFill { .. } => &[Memory],
+            Operator::MemoryCopy { .. } => &[Memory, Memory],
+            Operator::I32Const { .. }
+            | Operator::I64Const { .. }
+            | Operator::F32Const { .. }
+            | Operator::F64Const { .. }
+            | Operator::V128Const { .. } => &[Const],
+            Operator::I8x16Shuffle { .. } => &[Lanes],
+            Operator::V128Load8Lane { .. }
+            | Operator::V128Load16Lane { .. }
+            | Operator::V128Load32Lane { .. }
+            | Operator::V128Load64Lane { .. }
+            | Operator::V128Store8Lane { .. }
+            | Operator::V128Store16Lane { .. }
+            | Operator::V128Store32Lane { .. }
+            | Operator::V128Store64Lane { .. } => &[MemArg, Lane],
+            Operator::I8x16ExtractLaneS { .. }
+            | Operator::I8x16ExtractLaneU { .. }
+            | Operator::I8x16ReplaceLane { .. }
+            | Operator::I16x8ExtractLaneS { .. }
+            | Operator::I16x8ExtractLaneU { .. }
+            | Operator::I16x8ReplaceLane { .. }
+            | Operator::I32x4ExtractLane { .. }
+            | Operator::I32x4ReplaceLane { .. }
+            | Operator::I64x2ExtractLane { .. }
+            | Operator::I64x2ReplaceLane { .. }
+            | Operator::F32x4ExtractLane { .. }
+            | Operator::F32x4ReplaceLane { .. }
+            | Operator::F64x2ExtractLane { .. }
+            | Operator::F64x2ReplaceLane { .. } => &[Lane],
+            _ if self.memory_access().is_some() => &[MemArg],
+            _ => &[],
+        }
+    }
+
+    /// Collect the facts about this operator in one place. See
+    /// `OpInfo`.
+    pub fn info(&self) -> OpInfo {
+        let module_inputs = matches!(
+            self,
+            Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::CallRef { .. }
+                | Operator::GlobalSet { .. }
+                | Operator::TableSet { .. }
+                | Operator::TableGrow { .. }
+        );
+        let module_outputs = matches!(
+            self,
+            Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::CallRef { .. }
+                | Operator::GlobalGet { .. }
+                | Operator::TableGet { .. }
+                | Operator::RefFunc { .. }
+        );
+        // Operators whose types depend on neither the module nor
+        // their operands never consult either, so an empty module and
+        // operand stack suffice.
+        let empty = Module::empty();
+        let inputs = if module_inputs {
+            TypeRule::Module
+        } else if matches!(self, Operator::Select | Operator::RefIsNull) {
+            TypeRule::Operand
+        } else {
+            TypeRule::Fixed(op_inputs(&empty, &[], self).unwrap())
+        };
+        let outputs = if module_outputs {
+            TypeRule::Module
+        } else if matches!(self, Operator::Select) {
+            TypeRule::Operand
+        } else {
+            TypeRule::Fixed(op_outputs(&empty, &[], self).unwrap())
+        };
+        OpInfo {
+            inputs,
+            outputs,
+            effects: self.effects(),
+            is_pure: self.is_pure(),
+            can_trap: self.can_trap(),
+            immediates: self.immediates(),
+        }
+    }
+}
+
+/// How an operator's input or output types are determined.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TypeRule {
+    /// Always exactly these types.
+    Fixed(Cow<'static, [Type]>),
+    /// Given by a module entity that the operator names: a function
+    /// or signature, a global, or a table.
+    Module,
+    /// Given by the types of the operator's operands.
+    Operand,
+}
+
+/// A kind of immediate (static operand) carried by an operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImmediateKind {
+    /// A function index.
+    Func,
+    /// A signature index.
+    Signature,
+    /// A global index.
+    Global,
+    /// A table index.
+    Table,
+    /// A memory index.
+    Memory,
+    /// A `MemoryArg`: memory index, alignment, and offset.
+    MemArg,
+    /// A SIMD lane index.
+    Lane,
+    /// A SIMD shuffle's lane indices.
+    Lanes,
+    /// A constant value.
+    Const,
+    /// A value type.
+    Type,
+}
+
+/// Facts about an operator: the single source of truth consulted by
+/// the frontend, verifiers, optimizations, and the interpreter, and
+/// available to external tools.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpInfo {
+    /// The types of the operator's inputs.
+    pub inputs: TypeRule,
+    /// The types of the operator's results.
+    pub outputs: TypeRule,
+    /// The side-effects the operator may have.
+    pub effects: &'static [SideEffect],
+    /// Whether the operator is pure (has no effects at all).
+    pub is_pure: bool,
+    /// Whether the operator may trap.
+    pub can_trap: bool,
+    /// The kinds of the operator's immediates, in field order.
+    pub immediates: &'static [ImmediateKind],
+}
+
+impl OpInfo {
+    /// The operator's number of inputs, if it is fixed.
+    pub fn arity(&self) -> Option<usize> {
+        match &self.inputs {
+            TypeRule::Fixed(tys) => Some(tys.len()),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for Operator {
@@ -1845,8 +2159,10 @@ impl std::fmt::Display for Operator {
                 write!(f, "v128store64lane<{}, {}>", memory, lane)?
             }
 
+            // The immediates are in the body's pool; `FunctionBody`'s
+            // display shows their values instead.
             Operator::V128Const { value } => write!(f, "v128const<{}>", value)?,
-            Operator::I8x16Shuffle { lanes } => write!(f, "i8x16shuffle<{:?}>", lanes)?,
+            Operator::I8x16Shuffle { lanes } => write!(f, "i8x16shuffle<{}>", lanes)?,
             Operator::I8x16ExtractLaneS { lane } => write!(f, "i8x16extractlanes<{}>", lane)?,
             Operator::I8x16ExtractLaneU { lane } => write!(f, "i8x16extractlaneu<{}>", lane)?,
             Operator::I8x16ReplaceLane { lane } => write!(f, "i8x16replacelane<{}>", lane)?,
@@ -2100,3 +2416,41 @@ pub(crate) fn op_rematerialize(op: &Operator) -> bool {
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Func, Memory};
+
+    #[test]
+    fn op_info() {
+        let add = Operator::I32Add.info();
+        assert_eq!(
+            add.inputs,
+            TypeRule::Fixed(Cow::Borrowed(&[Type::I32, Type::I32]))
+        );
+        assert_eq!(add.arity(), Some(2));
+        assert!(add.is_pure && !add.can_trap);
+
+        let call = Operator::Call {
+            function_index: Func::new(0),
+        }
+        .info();
+        assert_eq!(call.inputs, TypeRule::Module);
+        assert_eq!(call.immediates, &[ImmediateKind::Func]);
+
+        let load = Operator::I64Load32U {
+            memory: MemoryArg {
+                align: 2,
+                offset: 0,
+                memory: Memory::new(0),
+            },
+        }
+        .info();
+        assert_eq!(load.outputs, TypeRule::Fixed(Cow::Borrowed(&[Type::I64])));
+        assert_eq!(load.immediates, &[ImmediateKind::MemArg]);
+        assert!(load.can_trap);
+
+        assert_eq!(Operator::Select.info().outputs, TypeRule::Operand);
+    }
+}
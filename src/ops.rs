@@ -14,7 +14,7 @@
 //! accesses to Wasm locals (these become the SSA dataflow itself) and
 //! control flow (these become `Terminator` instructions).
 
-use crate::{entity::EntityRef, Func, Global, Memory, Signature, Table, Type};
+use crate::{entity::EntityRef, Func, Global, Memory, Signature, Table, Type, V128Imm};
 use std::convert::TryFrom;
 pub use wasmparser::{Ieee32, Ieee64};
 
@@ -27,6 +27,27 @@ pub struct MemoryArg {
     pub memory: Memory,
 }
 
+impl MemoryArg {
+    /// The largest alignment, as a power of two, of any access: that
+    /// of a 16-byte `v128` access.
+    pub const MAX_ALIGN: u32 = 4;
+
+    /// Create a memory argument for an access to `memory` at `offset`
+    /// with alignment `2^align`, or `None` if the alignment exceeds
+    /// that of any access. `Operator::check_immediates()` also checks
+    /// it against the width of a particular access.
+    pub fn new(memory: Memory, align: u32, offset: u32) -> Option<MemoryArg> {
+        if align > MemoryArg::MAX_ALIGN {
+            return None;
+        }
+        Some(MemoryArg {
+            align,
+            offset,
+            memory,
+        })
+    }
+}
+
 impl std::fmt::Display for MemoryArg {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -37,6 +58,54 @@ impl std::fmt::Display for MemoryArg {
     }
 }
 
+/// A SIMD lane index: the lane of a `v128` that an operator reads or
+/// writes. A `v128` has at most 16 lanes; `Operator::lane_count()`
+/// gives the bound for a particular operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LaneIdx(u8);
+
+impl LaneIdx {
+    /// The most lanes of any `v128` shape: those of `i8x16`.
+    pub const MAX_LANES: u8 = 16;
+
+    /// Create a lane index, or `None` if `lane` is out of range for
+    /// every shape.
+    pub fn new(lane: u8) -> Option<LaneIdx> {
+        if lane < LaneIdx::MAX_LANES {
+            Some(LaneIdx(lane))
+        } else {
+            None
+        }
+    }
+
+    /// The lane number.
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for LaneIdx {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<LaneIdx> for u8 {
+    fn from(lane: LaneIdx) -> u8 {
+        lane.0
+    }
+}
+
+/// Fails if `lane` is out of range for every shape, as for
+/// `LaneIdx::new()`.
+impl TryFrom<u8> for LaneIdx {
+    type Error = ();
+
+    fn try_from(lane: u8) -> Result<LaneIdx, ()> {
+        LaneIdx::new(lane).ok_or(())
+    }
+}
+
 /// An operator in the IR, consuming arguments and producing results
 /// when executed.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -365,86 +434,88 @@ pub enum Operator {
     },
     V128Load8Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Load16Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Load32Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Load64Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Store8Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Store16Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Store32Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
     V128Store64Lane {
         memory: MemoryArg,
-        lane: u8,
+        lane: LaneIdx,
     },
 
     V128Const {
-        value: u128,
+        /// The value, in the body's `imm_pool`.
+        value: V128Imm,
     },
 
     I8x16Shuffle {
-        lanes: [u8; 16],
+        /// The lane bytes, in the body's `imm_pool`.
+        lanes: V128Imm,
     },
 
     I8x16ExtractLaneS {
-        lane: u8,
+        lane: LaneIdx,
     },
     I8x16ExtractLaneU {
-        lane: u8,
+        lane: LaneIdx,
     },
     I8x16ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     I16x8ExtractLaneS {
-        lane: u8,
+        lane: LaneIdx,
     },
     I16x8ExtractLaneU {
-        lane: u8,
+        lane: LaneIdx,
     },
     I16x8ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     I32x4ExtractLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     I32x4ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     I64x2ExtractLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     I64x2ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     F32x4ExtractLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     F32x4ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     F64x2ExtractLane {
-        lane: u8,
+        lane: LaneIdx,
     },
     F64x2ReplaceLane {
-        lane: u8,
+        lane: LaneIdx,
     },
 
     I8x16Swizzle,
@@ -670,7 +741,81 @@ pub enum Operator {
 
 #[test]
 fn op_size() {
-    assert_eq!(std::mem::size_of::<Operator>(), 32);
+    assert_eq!(std::mem::size_of::<Operator>(), 16);
+    assert_eq!(std::mem::size_of::<crate::ir::ValueDef>(), 32);
+}
+
+#[test]
+fn checked_immediates() {
+    assert_eq!(LaneIdx::new(15).map(LaneIdx::get), Some(15));
+    assert_eq!(LaneIdx::new(16), None);
+    assert_eq!(LaneIdx::try_from(3).map(u8::from), Ok(3));
+    assert_eq!(LaneIdx::try_from(16), Err(()));
+    assert!(MemoryArg::new(Memory::new(0), 5, 0).is_none());
+
+    let lane = LaneIdx::new(3).unwrap();
+    assert!(Operator::I32x4ExtractLane { lane }
+        .check_immediates()
+        .is_ok());
+    assert!(Operator::I64x2ExtractLane { lane }
+        .check_immediates()
+        .is_err());
+    assert_eq!(
+        Operator::I64x2ExtractLane { lane }.to_string(),
+        "i64x2extractlane<3>"
+    );
+
+    let memory = MemoryArg::new(Memory::new(0), 3, 8).unwrap();
+    assert!(Operator::I64Load { memory }.check_immediates().is_ok());
+    assert!(Operator::I32Load { memory }.check_immediates().is_err());
+    let err = Operator::V128Load8Lane { memory, lane }
+        .check_immediates()
+        .unwrap_err();
+    assert!(err.to_string().contains("alignment"), "{}", err);
+}
+
+#[test]
+fn bad_lanes_are_errors() {
+    let wat = r#"(module (func (param v128) (result i32)
+        local.get 0
+        i32x4.extract_lane 3))"#;
+    let bytes = wat::parse_str(wat).unwrap();
+    // `i32x4.extract_lane` is 0xfd 27, followed by the lane. Lanes out
+    // of range for the shape, or for every shape, are errors.
+    let at = bytes.windows(3).position(|w| w == [0xfd, 27, 3]).unwrap() + 2;
+    for lane in [4, 16] {
+        let mut bytes = bytes.clone();
+        bytes[at] = lane;
+        let mut module = crate::Module::from_wasm_bytes(&bytes, &Default::default()).unwrap();
+        let err = module.expand_all_funcs().unwrap_err();
+        assert!(err.to_string().contains("lane index"), "{}", err);
+    }
+}
+
+#[test]
+fn v128_imms_round_trip() {
+    let wat = r#"(module (func (export "f") (result v128)
+        v128.const i64x2 1 2
+        v128.const i64x2 3 4
+        i8x16.shuffle 0 1 2 3 4 5 6 7 24 25 26 27 28 29 30 31))"#;
+    let bytes = wat::parse_str(wat).unwrap();
+    let print = |bytes: &[u8]| {
+        let mut module = crate::Module::from_wasm_bytes(bytes, &Default::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let body = module.funcs[Func::new(0)].body().unwrap();
+        let text = body.display("", None).to_string();
+        (module.to_wasm_bytes().unwrap(), text)
+    };
+    // The immediates survive compiling and re-parsing.
+    let (bytes, _) = print(&bytes[..]);
+    let (_, text) = print(&bytes[..]);
+    let value = (2u128 << 64) | 1;
+    assert!(text.contains(&format!("v128const<{}>", value)), "{}", text);
+    assert!(
+        text.contains("i8x16shuffle<[0, 1, 2, 3, 4, 5, 6, 7, 24,"),
+        "{}",
+        text
+    );
 }
 
 impl<'a, 'b> std::convert::TryFrom<&'b wasmparser::Operator<'a>> for Operator {
@@ -991,99 +1136,93 @@ impl<'a, 'b> std::convert::TryFrom<&'b wasmparser::Operator<'a>> for Operator {
             }),
             &wasmparser::Operator::V128Load8Lane { memarg, lane } => Ok(Operator::V128Load8Lane {
                 memory: memarg.into(),
-                lane,
+                lane: LaneIdx::try_from(lane)?,
             }),
             &wasmparser::Operator::V128Load16Lane { memarg, lane } => {
                 Ok(Operator::V128Load16Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Load32Lane { memarg, lane } => {
                 Ok(Operator::V128Load32Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Load64Lane { memarg, lane } => {
                 Ok(Operator::V128Load64Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Store8Lane { memarg, lane } => {
                 Ok(Operator::V128Store8Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Store16Lane { memarg, lane } => {
                 Ok(Operator::V128Store16Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Store32Lane { memarg, lane } => {
                 Ok(Operator::V128Store32Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
             &wasmparser::Operator::V128Store64Lane { memarg, lane } => {
                 Ok(Operator::V128Store64Lane {
                     memory: memarg.into(),
-                    lane,
+                    lane: LaneIdx::try_from(lane)?,
                 })
             }
 
-            &wasmparser::Operator::V128Const { value } => Ok(Operator::V128Const {
-                value: value.i128() as u128,
+            &wasmparser::Operator::I8x16ExtractLaneS { lane } => Ok(Operator::I8x16ExtractLaneS {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I8x16ExtractLaneU { lane } => Ok(Operator::I8x16ExtractLaneU {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I8x16ReplaceLane { lane } => Ok(Operator::I8x16ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I16x8ExtractLaneS { lane } => Ok(Operator::I16x8ExtractLaneS {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I16x8ExtractLaneU { lane } => Ok(Operator::I16x8ExtractLaneU {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I16x8ReplaceLane { lane } => Ok(Operator::I16x8ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I32x4ExtractLane { lane } => Ok(Operator::I32x4ExtractLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I32x4ReplaceLane { lane } => Ok(Operator::I32x4ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I64x2ExtractLane { lane } => Ok(Operator::I64x2ExtractLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::I64x2ReplaceLane { lane } => Ok(Operator::I64x2ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::F32x4ExtractLane { lane } => Ok(Operator::F32x4ExtractLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::F32x4ReplaceLane { lane } => Ok(Operator::F32x4ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::F64x2ExtractLane { lane } => Ok(Operator::F64x2ExtractLane {
+                lane: LaneIdx::try_from(lane)?,
+            }),
+            &wasmparser::Operator::F64x2ReplaceLane { lane } => Ok(Operator::F64x2ReplaceLane {
+                lane: LaneIdx::try_from(lane)?,
             }),
-
-            &wasmparser::Operator::I8x16Shuffle { lanes } => Ok(Operator::I8x16Shuffle { lanes }),
-
-            &wasmparser::Operator::I8x16ExtractLaneS { lane } => {
-                Ok(Operator::I8x16ExtractLaneS { lane })
-            }
-            &wasmparser::Operator::I8x16ExtractLaneU { lane } => {
-                Ok(Operator::I8x16ExtractLaneU { lane })
-            }
-            &wasmparser::Operator::I8x16ReplaceLane { lane } => {
-                Ok(Operator::I8x16ReplaceLane { lane })
-            }
-            &wasmparser::Operator::I16x8ExtractLaneS { lane } => {
-                Ok(Operator::I16x8ExtractLaneS { lane })
-            }
-            &wasmparser::Operator::I16x8ExtractLaneU { lane } => {
-                Ok(Operator::I16x8ExtractLaneU { lane })
-            }
-            &wasmparser::Operator::I16x8ReplaceLane { lane } => {
-                Ok(Operator::I16x8ReplaceLane { lane })
-            }
-            &wasmparser::Operator::I32x4ExtractLane { lane } => {
-                Ok(Operator::I32x4ExtractLane { lane })
-            }
-            &wasmparser::Operator::I32x4ReplaceLane { lane } => {
-                Ok(Operator::I32x4ReplaceLane { lane })
-            }
-            &wasmparser::Operator::I64x2ExtractLane { lane } => {
-                Ok(Operator::I64x2ExtractLane { lane })
-            }
-            &wasmparser::Operator::I64x2ReplaceLane { lane } => {
-                Ok(Operator::I64x2ReplaceLane { lane })
-            }
-            &wasmparser::Operator::F32x4ExtractLane { lane } => {
-                Ok(Operator::F32x4ExtractLane { lane })
-            }
-            &wasmparser::Operator::F32x4ReplaceLane { lane } => {
-                Ok(Operator::F32x4ReplaceLane { lane })
-            }
-            &wasmparser::Operator::F64x2ExtractLane { lane } => {
-                Ok(Operator::F64x2ExtractLane { lane })
-            }
-            &wasmparser::Operator::F64x2ReplaceLane { lane } => {
-                Ok(Operator::F64x2ReplaceLane { lane })
-            }
 
             &wasmparser::Operator::I8x16Swizzle => Ok(Operator::I8x16Swizzle),
             &wasmparser::Operator::I8x16Splat => Ok(Operator::I8x16Splat),
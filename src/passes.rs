@@ -1,7 +1,53 @@
 //! Passes.
 
+pub mod atomics;
 pub mod basic_opt;
+pub(crate) mod bytecode;
+pub mod canonicalize;
+pub mod cost;
+pub mod data_segments;
 pub mod dom_pass;
+pub mod downgrade;
+pub mod effects;
 pub mod empty_blocks;
+pub mod extract;
+pub mod features;
+pub mod func_order;
+pub mod global_inits;
+pub mod host_surface;
+pub mod import_shims;
+pub mod index_assign;
+pub mod instrument;
+pub mod intrinsics;
+pub mod link;
 pub mod maxssa;
+pub mod memory_ssa;
+pub mod narrow_types;
+pub mod null_checks;
+pub mod nullability;
+pub mod opcodes;
+pub mod overrides;
+pub mod pass_debug;
+pub mod pgo;
+pub mod pipeline;
+pub mod preinit;
+pub mod ranges;
+pub mod reassociate;
+pub mod relaxed_simd;
+pub(crate) mod remap;
 pub mod resolve_aliases;
+pub mod rodata;
+pub mod scalarize;
+pub mod select;
+pub mod shrink_memory;
+pub mod signatures;
+pub mod source_locs;
+pub mod specialize;
+pub mod split;
+pub mod split_funcs;
+pub mod stack_usage;
+pub mod strings;
+pub mod switch;
+pub mod tables;
+pub mod terminator_stats;
+pub mod trampolines;
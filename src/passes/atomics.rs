@@ -0,0 +1,497 @@
+//! Atomics-to-nonatomics lowering, for single-threaded targets.
+//!
+//! The IR has no atomic operators, so this works on bytecode, before
+//! expansion: each un-expanded function that uses atomics is rewritten
+//! with plain accesses in their place and then expanded from the
+//! rewritten code. With one thread, an atomic access is just an
+//! access; only its alignment check is lost. Read-modify-writes become
+//! a load, the operation, and a store, through scratch locals. Fences
+//! disappear. Waits and notifies, which only mean something between
+//! threads, either trap or return at once, as `AtomicWaits` selects.
+//!
+//! This is only sound if no other thread can access the module's
+//! memory: `lower()` fails if a shared memory is imported or exported.
+//! (The frontend does not keep whether a memory is shared, so the
+//! backend emits every memory unshared.)
+
+use super::bytecode::{expand_rewritten, rewrite_body};
+use crate::ir::{FuncDecl, Module};
+use crate::Func;
+use anyhow::{bail, Result};
+use wasm_encoder::{Instruction, ValType};
+use wasmparser::{MemArg, Operator as O};
+
+/// What atomic waits and notifies become.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AtomicWaits {
+    /// Trap: with one thread, a wait could only deadlock.
+    #[default]
+    Trap,
+    /// Return at once: a wait as if it timed out, or as "not-equal"
+    /// if the value differs; a notify as if no thread was waiting.
+    NoOp,
+}
+
+/// The width of an atomic access.
+#[derive(Clone, Copy, Debug)]
+enum Width {
+    I32,
+    I32_8,
+    I32_16,
+    I64,
+    I64_8,
+    I64_16,
+    I64_32,
+}
+
+impl Width {
+    fn is_i64(self) -> bool {
+        matches!(
+            self,
+            Width::I64 | Width::I64_8 | Width::I64_16 | Width::I64_32
+        )
+    }
+
+    /// The mask of the bits accessed, for a narrow access.
+    fn mask(self) -> Option<u64> {
+        match self {
+            Width::I32_8 | Width::I64_8 => Some(0xff),
+            Width::I32_16 | Width::I64_16 => Some(0xffff),
+            Width::I64_32 => Some(0xffff_ffff),
+            Width::I32 | Width::I64 => None,
+        }
+    }
+
+    fn load(self, m: wasm_encoder::MemArg) -> Instruction<'static> {
+        match self {
+            Width::I32 => Instruction::I32Load(m),
+            Width::I32_8 => Instruction::I32Load8U(m),
+            Width::I32_16 => Instruction::I32Load16U(m),
+            Width::I64 => Instruction::I64Load(m),
+            Width::I64_8 => Instruction::I64Load8U(m),
+            Width::I64_16 => Instruction::I64Load16U(m),
+            Width::I64_32 => Instruction::I64Load32U(m),
+        }
+    }
+
+    fn store(self, m: wasm_encoder::MemArg) -> Instruction<'static> {
+        match self {
+            Width::I32 => Instruction::I32Store(m),
+            Width::I32_8 => Instruction::I32Store8(m),
+            Width::I32_16 => Instruction::I32Store16(m),
+            Width::I64 => Instruction::I64Store(m),
+            Width::I64_8 => Instruction::I64Store8(m),
+            Width::I64_16 => Instruction::I64Store16(m),
+            Width::I64_32 => Instruction::I64Store32(m),
+        }
+    }
+}
+
+/// A read-modify-write operation.
+#[derive(Clone, Copy, Debug)]
+enum Rmw {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+    Cmpxchg,
+}
+
+/// An atomic operator, classified.
+#[derive(Clone, Copy, Debug)]
+enum Atomic {
+    Fence,
+    Load(Width, MemArg),
+    Store(Width, MemArg),
+    Rmw(Rmw, Width, MemArg),
+    Notify(MemArg),
+    Wait(Width, MemArg),
+}
+
+fn classify(op: &O) -> Option<Atomic> {
+    use Atomic::{Load, Rmw as R, Store};
+    use Rmw::*;
+    use Width::*;
+    Some(match *op {
+        O::AtomicFence => Atomic::Fence,
+        O::MemoryAtomicNotify { memarg } => Atomic::Notify(memarg),
+        O::MemoryAtomicWait32 { memarg } => Atomic::Wait(I32, memarg),
+        O::MemoryAtomicWait64 { memarg } => Atomic::Wait(I64, memarg),
+
+        O::I32AtomicLoad { memarg } => Load(I32, memarg),
+        O::I64AtomicLoad { memarg } => Load(I64, memarg),
+        O::I32AtomicLoad8U { memarg } => Load(I32_8, memarg),
+        O::I32AtomicLoad16U { memarg } => Load(I32_16, memarg),
+        O::I64AtomicLoad8U { memarg } => Load(I64_8, memarg),
+        O::I64AtomicLoad16U { memarg } => Load(I64_16, memarg),
+        O::I64AtomicLoad32U { memarg } => Load(I64_32, memarg),
+
+        O::I32AtomicStore { memarg } => Store(I32, memarg),
+        O::I64AtomicStore { memarg } => Store(I64, memarg),
+        O::I32AtomicStore8 { memarg } => Store(I32_8, memarg),
+        O::I32AtomicStore16 { memarg } => Store(I32_16, memarg),
+        O::I64AtomicStore8 { memarg } => Store(I64_8, memarg),
+        O::I64AtomicStore16 { memarg } => Store(I64_16, memarg),
+        O::I64AtomicStore32 { memarg } => Store(I64_32, memarg),
+
+        O::I32AtomicRmwAdd { memarg } => R(Add, I32, memarg),
+        O::I64AtomicRmwAdd { memarg } => R(Add, I64, memarg),
+        O::I32AtomicRmw8AddU { memarg } => R(Add, I32_8, memarg),
+        O::I32AtomicRmw16AddU { memarg } => R(Add, I32_16, memarg),
+        O::I64AtomicRmw8AddU { memarg } => R(Add, I64_8, memarg),
+        O::I64AtomicRmw16AddU { memarg } => R(Add, I64_16, memarg),
+        O::I64AtomicRmw32AddU { memarg } => R(Add, I64_32, memarg),
+
+        O::I32AtomicRmwSub { memarg } => R(Sub, I32, memarg),
+        O::I64AtomicRmwSub { memarg } => R(Sub, I64, memarg),
+        O::I32AtomicRmw8SubU { memarg } => R(Sub, I32_8, memarg),
+        O::I32AtomicRmw16SubU { memarg } => R(Sub, I32_16, memarg),
+        O::I64AtomicRmw8SubU { memarg } => R(Sub, I64_8, memarg),
+        O::I64AtomicRmw16SubU { memarg } => R(Sub, I64_16, memarg),
+        O::I64AtomicRmw32SubU { memarg } => R(Sub, I64_32, memarg),
+
+        O::I32AtomicRmwAnd { memarg } => R(And, I32, memarg),
+        O::I64AtomicRmwAnd { memarg } => R(And, I64, memarg),
+        O::I32AtomicRmw8AndU { memarg } => R(And, I32_8, memarg),
+        O::I32AtomicRmw16AndU { memarg } => R(And, I32_16, memarg),
+        O::I64AtomicRmw8AndU { memarg } => R(And, I64_8, memarg),
+        O::I64AtomicRmw16AndU { memarg } => R(And, I64_16, memarg),
+        O::I64AtomicRmw32AndU { memarg } => R(And, I64_32, memarg),
+
+        O::I32AtomicRmwOr { memarg } => R(Or, I32, memarg),
+        O::I64AtomicRmwOr { memarg } => R(Or, I64, memarg),
+        O::I32AtomicRmw8OrU { memarg } => R(Or, I32_8, memarg),
+        O::I32AtomicRmw16OrU { memarg } => R(Or, I32_16, memarg),
+        O::I64AtomicRmw8OrU { memarg } => R(Or, I64_8, memarg),
+        O::I64AtomicRmw16OrU { memarg } => R(Or, I64_16, memarg),
+        O::I64AtomicRmw32OrU { memarg } => R(Or, I64_32, memarg),
+
+        O::I32AtomicRmwXor { memarg } => R(Xor, I32, memarg),
+        O::I64AtomicRmwXor { memarg } => R(Xor, I64, memarg),
+        O::I32AtomicRmw8XorU { memarg } => R(Xor, I32_8, memarg),
+        O::I32AtomicRmw16XorU { memarg } => R(Xor, I32_16, memarg),
+        O::I64AtomicRmw8XorU { memarg } => R(Xor, I64_8, memarg),
+        O::I64AtomicRmw16XorU { memarg } => R(Xor, I64_16, memarg),
+        O::I64AtomicRmw32XorU { memarg } => R(Xor, I64_32, memarg),
+
+        O::I32AtomicRmwXchg { memarg } => R(Xchg, I32, memarg),
+        O::I64AtomicRmwXchg { memarg } => R(Xchg, I64, memarg),
+        O::I32AtomicRmw8XchgU { memarg } => R(Xchg, I32_8, memarg),
+        O::I32AtomicRmw16XchgU { memarg } => R(Xchg, I32_16, memarg),
+        O::I64AtomicRmw8XchgU { memarg } => R(Xchg, I64_8, memarg),
+        O::I64AtomicRmw16XchgU { memarg } => R(Xchg, I64_16, memarg),
+        O::I64AtomicRmw32XchgU { memarg } => R(Xchg, I64_32, memarg),
+
+        O::I32AtomicRmwCmpxchg { memarg } => R(Cmpxchg, I32, memarg),
+        O::I64AtomicRmwCmpxchg { memarg } => R(Cmpxchg, I64, memarg),
+        O::I32AtomicRmw8CmpxchgU { memarg } => R(Cmpxchg, I32_8, memarg),
+        O::I32AtomicRmw16CmpxchgU { memarg } => R(Cmpxchg, I32_16, memarg),
+        O::I64AtomicRmw8CmpxchgU { memarg } => R(Cmpxchg, I64_8, memarg),
+        O::I64AtomicRmw16CmpxchgU { memarg } => R(Cmpxchg, I64_16, memarg),
+        O::I64AtomicRmw32CmpxchgU { memarg } => R(Cmpxchg, I64_32, memarg),
+
+        _ => return None,
+    })
+}
+
+/// The scratch locals of a rewritten function: an address, and a
+/// value, an old value and an expected value of each integer type.
+struct Scratch {
+    addr: u32,
+    i32s: [u32; 3],
+    i64s: [u32; 3],
+}
+
+impl Scratch {
+    /// The value, old-value and expected-value locals for `width`.
+    fn locals(&self, width: Width) -> [u32; 3] {
+        match width.is_i64() {
+            true => self.i64s,
+            false => self.i32s,
+        }
+    }
+}
+
+/// The instructions replacing `atomic`.
+fn lower_op(atomic: Atomic, waits: AtomicWaits, scratch: &Scratch) -> Vec<Instruction<'static>> {
+    use Instruction as I;
+    let m = |memarg: MemArg| wasm_encoder::MemArg {
+        offset: memarg.offset,
+        align: memarg.align as u32,
+        memory_index: memarg.memory,
+    };
+    match atomic {
+        Atomic::Fence => vec![],
+        Atomic::Load(width, memarg) => vec![width.load(m(memarg))],
+        Atomic::Store(width, memarg) => vec![width.store(m(memarg))],
+        Atomic::Notify(_) | Atomic::Wait(..) if waits == AtomicWaits::Trap => {
+            vec![I::Unreachable]
+        }
+        // [addr, count] -> 0, after the bounds check of a load.
+        Atomic::Notify(memarg) => vec![I::Drop, I::I32Load(m(memarg)), I::Drop, I::I32Const(0)],
+        // [addr, expected, timeout] -> 1 ("not-equal") or 2
+        // ("timed-out").
+        Atomic::Wait(width, memarg) => {
+            let [_, _, expected] = scratch.locals(width);
+            let (load, eq) = match width.is_i64() {
+                true => (I::I64Load(m(memarg)), I::I64Eq),
+                false => (I::I32Load(m(memarg)), I::I32Eq),
+            };
+            vec![
+                I::Drop,
+                I::LocalSet(expected),
+                load,
+                I::LocalGet(expected),
+                eq,
+                I::I32Const(1),
+                I::I32Add,
+            ]
+        }
+        // [addr, value] -> old, storing `old <op> value`; with
+        // `Cmpxchg`, [addr, expected, value] -> old, storing `value`
+        // if `old` is `expected` wrapped to the width accessed.
+        Atomic::Rmw(rmw, width, memarg) => {
+            let [value, old, expected] = scratch.locals(width);
+            let addr = scratch.addr;
+            let is_i64 = width.is_i64();
+            let mut insts = vec![I::LocalSet(value)];
+            if let Rmw::Cmpxchg = rmw {
+                insts.push(I::LocalSet(expected));
+            }
+            insts.extend([
+                I::LocalTee(addr),
+                width.load(m(memarg)),
+                I::LocalSet(old),
+                I::LocalGet(addr),
+            ]);
+            let op = match (rmw, is_i64) {
+                (Rmw::Add, false) => Some(I::I32Add),
+                (Rmw::Sub, false) => Some(I::I32Sub),
+                (Rmw::And, false) => Some(I::I32And),
+                (Rmw::Or, false) => Some(I::I32Or),
+                (Rmw::Xor, false) => Some(I::I32Xor),
+                (Rmw::Add, true) => Some(I::I64Add),
+                (Rmw::Sub, true) => Some(I::I64Sub),
+                (Rmw::And, true) => Some(I::I64And),
+                (Rmw::Or, true) => Some(I::I64Or),
+                (Rmw::Xor, true) => Some(I::I64Xor),
+                (Rmw::Xchg | Rmw::Cmpxchg, _) => None,
+            };
+            match (rmw, op) {
+                (Rmw::Cmpxchg, _) => {
+                    insts.extend([
+                        I::LocalGet(value),
+                        I::LocalGet(old),
+                        I::LocalGet(old),
+                        I::LocalGet(expected),
+                    ]);
+                    match (width.mask(), is_i64) {
+                        (Some(mask), false) => insts.extend([I::I32Const(mask as i32), I::I32And]),
+                        (Some(mask), true) => insts.extend([I::I64Const(mask as i64), I::I64And]),
+                        (None, _) => {}
+                    }
+                    insts.extend([if is_i64 { I::I64Eq } else { I::I32Eq }, I::Select]);
+                }
+                (_, Some(op)) => insts.extend([I::LocalGet(old), I::LocalGet(value), op]),
+                (_, None) => insts.push(I::LocalGet(value)),
+            }
+            insts.extend([width.store(m(memarg)), I::LocalGet(old)]);
+            insts
+        }
+    }
+}
+
+/// Rewrite the body `body` of `func` without atomics, if it has any.
+fn lower_body(
+    module: &Module,
+    func: Func,
+    body: &wasmparser::FunctionBody,
+    waits: AtomicWaits,
+) -> Result<Option<Vec<u8>>> {
+    let scratch = [(4, ValType::I32), (3, ValType::I64)];
+    rewrite_body(module, func, body, &scratch, classify, |atomic, next| {
+        let scratch = Scratch {
+            addr: next,
+            i32s: [next + 1, next + 2, next + 3],
+            i64s: [next + 4, next + 5, next + 6],
+        };
+        lower_op(atomic, waits, &scratch)
+    })
+}
+
+/// Fail if a shared memory is imported or exported, so that other
+/// threads may access it.
+fn check_single_threaded(module: &Module) -> Result<()> {
+    let Some(bytes) = module.orig_bytes else {
+        return Ok(());
+    };
+    let mut shared = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        match payload? {
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if let wasmparser::TypeRef::Memory(ty) = import.ty {
+                        if ty.shared {
+                            bail!(
+                                "Cannot lower atomics: shared memory {}.{} is imported",
+                                import.module,
+                                import.name
+                            );
+                        }
+                        shared.push(false);
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(reader) => {
+                for ty in reader {
+                    shared.push(ty?.shared);
+                }
+            }
+            wasmparser::Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == wasmparser::ExternalKind::Memory
+                        && shared.get(export.index as usize) == Some(&true)
+                    {
+                        bail!(
+                            "Cannot lower atomics: shared memory is exported as {}",
+                            export.name
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite the un-expanded functions of `module` that use atomics
+/// without them, expanding them. Returns how many there were.
+pub fn lower(module: &mut Module, waits: AtomicWaits) -> Result<usize> {
+    let mut lowered = vec![];
+    for (func, decl) in module.funcs.entries() {
+        if let FuncDecl::Lazy(_, _, body) = decl {
+            if let Some(code) = lower_body(module, func, body, waits)? {
+                lowered.push((func, body.range().start, code));
+            }
+        }
+    }
+    if lowered.is_empty() {
+        return Ok(0);
+    }
+    check_single_threaded(module)?;
+
+    let count = lowered.len();
+    for (func, offset, code) in lowered {
+        expand_rewritten(module, func, offset, &code)?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::passes::downgrade::{downgrade, TargetFeatures};
+    use crate::{ConstVal, ExportKind, FrontendOptions, InterpContext};
+
+    #[test]
+    fn atomics_become_plain_accesses() {
+        let wat = |memory: &str| {
+            wat::parse_str(format!(
+                r#"(module
+                    {}
+                    (func (export "rmw") (param i32) (result i64)
+                      i32.const 8
+                      i32.const 0x1ff
+                      i32.atomic.store
+                      ;; old byte 0xff; stores 0xff + 0x02 wrapped = 0x01
+                      i32.const 8
+                      i32.const 2
+                      i32.atomic.rmw8.add_u
+                      ;; cmpxchg against 0x101, wrapped to 0x01: stores 0x7f
+                      i32.const 8
+                      i32.const 0x101
+                      i32.const 0x7f
+                      i32.atomic.rmw8.cmpxchg_u
+                      i32.add
+                      atomic.fence
+                      i32.const 8
+                      i32.atomic.load
+                      i32.add
+                      i64.extend_i32_u
+                      i32.const 16
+                      local.get 0
+                      i64.extend_i32_u
+                      i64.atomic.rmw.xchg
+                      i64.add
+                      i32.const 16
+                      i64.atomic.load32_u
+                      i64.add)
+                    (func (export "wait") (param i32) (result i32)
+                      i32.const 0
+                      local.get 0
+                      i64.const -1
+                      memory.atomic.wait32
+                      i32.const 0
+                      i32.const 1
+                      memory.atomic.notify
+                      i32.add))"#,
+                memory
+            ))
+            .unwrap()
+        };
+        let bytes = wat("(memory 1 1 shared)");
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let call = |module: &Module, name: &str, arg: u32| {
+            let func = module
+                .exports
+                .iter()
+                .find_map(|export| match export.kind {
+                    ExportKind::Func(func) if export.name == name => Some(func),
+                    _ => None,
+                })
+                .unwrap();
+            let mut ctx = InterpContext::new(module).unwrap();
+            ctx.call(module, func, &[ConstVal::I32(arg)])
+                .ok()
+                .ok()
+                .map(|result| result.to_vec())
+        };
+
+        let mut trapping = module.clone();
+        downgrade(&mut trapping, &TargetFeatures::mvp()).unwrap();
+        assert_eq!(call(&trapping, "wait", 0), None);
+
+        let target = TargetFeatures {
+            atomic_waits: AtomicWaits::NoOp,
+            ..TargetFeatures::mvp()
+        };
+        let report = downgrade(&mut module, &target).unwrap();
+        assert_eq!(report.atomics, 2);
+        // The old bytes 0xff and 0x01, the word 0x17f, then the
+        // xchg's old 0 and new 5.
+        assert_eq!(
+            call(&module, "rmw", 5),
+            Some(vec![ConstVal::I64(0xff + 0x01 + 0x17f + 5)])
+        );
+        // Memory is zero: "timed-out" (2), and no waiters woken.
+        assert_eq!(call(&module, "wait", 0), Some(vec![ConstVal::I32(2)]));
+        assert_eq!(call(&module, "wait", 1), Some(vec![ConstVal::I32(1)]));
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new_with_features(crate::FeatureSet::default().wasm_features())
+            .validate_all(&bytes)
+            .unwrap();
+
+        let bytes = wat("(memory (export \"mem\") 1 1 shared)");
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let err = downgrade(&mut module, &target).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Cannot lower atomics: shared memory is exported as mem"
+        );
+    }
+}
@@ -1,19 +1,66 @@
-//! Basic optimizations: GVN and constant-propagation/folding.
+//! Basic optimizations: GVN, constant-propagation/folding, and
+//! folding of address arithmetic into load/store offsets.
+//!
+//! Trap semantics: with `OptOptions::preserve_traps` set (the
+//! default), no pass run by `FunctionBody::optimize` removes,
+//! duplicates, or speculates an operator that may trap, or moves one
+//! across another side effect. An operator with constant inputs is
+//! only folded if evaluating it does not trap, and address folding
+//! keeps every effective address (and so every out-of-bounds trap)
+//! unchanged. With the flag clear, operators whose only side effect
+//! is a possible trap (division, float-to-int truncation, and the
+//! like) are treated as pure: they are deduplicated by GVN and may be
+//! executed speculatively, so a trap can occur on a path that would
+//! not have trapped before.
 
 use crate::cfg::CFGInfo;
 use crate::interp::{const_eval, ConstVal};
 use crate::ir::*;
+use crate::passes::cost::{CostModel, DefaultCostModel, SizeCostModel};
 use crate::passes::dom_pass::{dom_pass, DomtreePass};
-use crate::pool::ListRef;
+use crate::passes::effects::EffectSummaries;
+use crate::passes::pass_debug::PassDebug;
+use crate::passes::ranges::RangeAnalysis;
+use crate::passes::switch::SwitchLowering;
+use crate::pool::{ImmPool, ListRef};
 use crate::scoped_map::ScopedMap;
-use crate::Operator;
+use crate::{Operator, SideEffect};
 use smallvec::{smallvec, SmallVec};
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 pub struct OptOptions {
     pub gvn: bool,
     pub cprop: bool,
     pub redundant_blockparams: bool,
+    /// Fold constant address offsets into load/store immediates when
+    /// range analysis shows that the address add cannot wrap.
+    pub fold_offsets: bool,
+    /// Policy for converting between `br_table`s and compare trees.
+    pub switch_lowering: SwitchLowering,
+    /// Flatten small if/else diamonds whose arms only compute pure
+    /// values into `select`s.
+    pub form_selects: bool,
+    /// Reassociate integer add/mul trees to fold their constants, and
+    /// put commutative operands and compares in canonical form.
+    pub reassociate: bool,
+    /// Never remove, duplicate, or speculate potentially-trapping
+    /// operators. See the module documentation for details.
+    pub preserve_traps: bool,
+    /// Effect summaries of the module's functions (see
+    /// `Module::effect_summaries()`). When present, calls to pure
+    /// functions are treated like pure operators, and calls to
+    /// intrinsics that return a constant are folded to it.
+    pub effects: Option<Arc<EffectSummaries>>,
+    /// Replace `ref.is_null` checks whose outcome is known from
+    /// nullability analysis with constants.
+    pub null_checks: bool,
+    /// Operator costs consulted by passes that trade size against
+    /// speed.
+    pub cost_model: Arc<dyn CostModel>,
+    /// Dump and verify IR between passes; by default, as configured
+    /// by the environment (see `passes::pass_debug`).
+    pub pass_debug: Option<Arc<PassDebug>>,
 }
 
 impl std::default::Default for OptOptions {
@@ -22,15 +69,108 @@ impl std::default::Default for OptOptions {
             gvn: true,
             cprop: true,
             redundant_blockparams: true,
+            fold_offsets: true,
+            switch_lowering: SwitchLowering::default(),
+            form_selects: false,
+            reassociate: true,
+            preserve_traps: true,
+            effects: None,
+            null_checks: true,
+            cost_model: Arc::new(DefaultCostModel),
+            pass_debug: PassDebug::from_env().map(Arc::new),
+        }
+    }
+}
+
+/// Optimization level presets; see `OptOptions::level()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Cheap cleanups only: GVN, constant propagation, and removal of
+    /// redundant block parameters.
+    O1,
+    /// The default optimizations.
+    O2,
+    /// Everything in `O2`, plus select formation and raising compare
+    /// chains into `br_table`s.
+    O3,
+    /// Optimize for size: everything in `O2`, plus select formation
+    /// and switch lowering chosen per switch.
+    Os,
+    /// Optimize aggressively for size: as `Os`, but with a cost model
+    /// that counts only bytes.
+    Oz,
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<OptLevel> {
+        match s {
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            "z" => Ok(OptLevel::Oz),
+            _ => anyhow::bail!("Unknown optimization level: {}", s),
+        }
+    }
+}
+
+impl OptOptions {
+    /// The preset options for `level`.
+    pub fn level(level: OptLevel) -> OptOptions {
+        let default = OptOptions::default();
+        match level {
+            OptLevel::O1 => OptOptions {
+                fold_offsets: false,
+                reassociate: false,
+                null_checks: false,
+                ..default
+            },
+            OptLevel::O2 => default,
+            OptLevel::O3 => OptOptions {
+                form_selects: true,
+                switch_lowering: SwitchLowering::Tables,
+                ..default
+            },
+            OptLevel::Os => OptOptions {
+                form_selects: true,
+                switch_lowering: SwitchLowering::Auto,
+                ..default
+            },
+            OptLevel::Oz => OptOptions {
+                cost_model: Arc::new(SizeCostModel),
+                ..OptOptions::level(OptLevel::Os)
+            },
+        }
+    }
+
+    /// May `op` be treated as pure, i.e., deduplicated, folded, or
+    /// executed speculatively?
+    pub(crate) fn treat_as_pure(&self, op: &Operator) -> bool {
+        if op.is_pure() || (!self.preserve_traps && op.effects() == [SideEffect::Trap]) {
+            return true;
+        }
+        match (op, &self.effects) {
+            (&Operator::Call { function_index }, Some(effects)) => {
+                let summary = effects.get(function_index);
+                summary.is_pure() || (!self.preserve_traps && summary.is_pure_except_trap())
+            }
+            _ => false,
         }
     }
 }
 
 pub(crate) fn basic_opt(body: &mut FunctionBody, cfg: &CFGInfo, options: &OptOptions) {
     loop {
+        let ranges = if options.fold_offsets {
+            Some(RangeAnalysis::new(body, cfg))
+        } else {
+            None
+        };
         let mut pass = BasicOptPass {
             map: ScopedMap::default(),
             cfg,
+            ranges: ranges.as_ref(),
             options,
             changed: false,
         };
@@ -43,8 +183,9 @@ pub(crate) fn basic_opt(body: &mut FunctionBody, cfg: &CFGInfo, options: &OptOpt
 
 #[derive(Debug)]
 struct BasicOptPass<'a> {
-    map: ScopedMap<ValueDef, Value>,
+    map: ScopedMap<(ValueDef, SmallVec<[Value; 4]>), Value>,
     cfg: &'a CFGInfo,
+    ranges: Option<&'a RangeAnalysis>,
     options: &'a OptOptions,
     changed: bool,
 }
@@ -60,14 +201,27 @@ impl<'a> DomtreePass for BasicOptPass<'a> {
     }
 }
 
-fn value_is_pure(value: Value, body: &FunctionBody) -> bool {
+fn value_is_pure(value: Value, body: &FunctionBody, options: &OptOptions) -> bool {
     match body.values[value] {
-        ValueDef::Operator(op, ..) if op.is_pure() => true,
+        ValueDef::Operator(op, ..) if options.treat_as_pure(&op) => true,
         _ => false,
     }
 }
 
-fn value_is_const(value: Value, body: &FunctionBody) -> ConstVal {
+/// The key under which GVN looks up a value: its definition with the
+/// argument list taken by content rather than by pool reference, so
+/// that operators built separately over the same arguments match.
+fn gvn_key(value: &ValueDef, body: &FunctionBody) -> (ValueDef, SmallVec<[Value; 4]>) {
+    match value {
+        &ValueDef::Operator(op, args, tys) => (
+            ValueDef::Operator(op, ListRef::default(), tys),
+            body.arg_pool[args].iter().copied().collect(),
+        ),
+        value => (value.clone(), smallvec![]),
+    }
+}
+
+pub(crate) fn value_is_const(value: Value, body: &FunctionBody) -> ConstVal {
     match body.values[value] {
         ValueDef::Operator(Operator::I32Const { value }, _, _) => ConstVal::I32(value),
         ValueDef::Operator(Operator::I64Const { value }, _, _) => ConstVal::I64(value),
@@ -77,12 +231,15 @@ fn value_is_const(value: Value, body: &FunctionBody) -> ConstVal {
     }
 }
 
-fn const_op(val: ConstVal) -> Operator {
+pub(crate) fn const_op(imm_pool: &mut ImmPool, val: ConstVal) -> Operator {
     match val {
         ConstVal::I32(value) => Operator::I32Const { value },
         ConstVal::I64(value) => Operator::I64Const { value },
         ConstVal::F32(value) => Operator::F32Const { value },
         ConstVal::F64(value) => Operator::F64Const { value },
+        ConstVal::V128(value) => Operator::V128Const {
+            value: imm_pool.intern(value),
+        },
         _ => unreachable!(),
     }
 }
@@ -145,8 +302,8 @@ impl<'a> BasicOptPass<'a> {
                     // blockparam and rewrite it as a new constant
                     // operator.
                     let ty = body.type_pool.single(ty);
-                    body.values[blockparam] =
-                        ValueDef::Operator(const_op(const_val), ListRef::default(), ty);
+                    let op = const_op(&mut body.imm_pool, const_val);
+                    body.values[blockparam] = ValueDef::Operator(op, ListRef::default(), ty);
                     const_insts_to_insert.push(blockparam);
                     blockparams_to_remove.push(i);
                 }
@@ -176,7 +333,7 @@ impl<'a> BasicOptPass<'a> {
         while i < body.blocks[block].insts.len() {
             let inst = body.blocks[block].insts[i];
             i += 1;
-            if value_is_pure(inst, body) {
+            if value_is_pure(inst, body, self.options) {
                 let mut value = body.values[inst].clone();
 
                 // Resolve aliases in the arg lists.
@@ -210,6 +367,15 @@ impl<'a> BasicOptPass<'a> {
                             | Operator::F32Const { .. }
                             | Operator::F64Const { .. }
                             | Operator::V128Const { .. } => None,
+                            &Operator::Call { function_index } => self
+                                .options
+                                .effects
+                                .as_ref()
+                                .and_then(|effects| effects.constant_results(function_index))
+                                .and_then(|values| match values {
+                                    &[value] => Some(value),
+                                    _ => None,
+                                }),
                             _ => const_eval(op, &arg_values[..], None),
                         };
                         match const_val {
@@ -257,16 +423,65 @@ impl<'a> BasicOptPass<'a> {
                 if self.options.gvn {
                     // GVN: look for already-existing copies of this
                     // value.
-                    if let Some(value) = self.map.get(&value) {
+                    let key = gvn_key(&value, body);
+                    if let Some(value) = self.map.get(&key) {
                         body.set_alias(inst, *value);
                         i -= 1;
                         body.blocks[block].insts.remove(i);
                         self.changed = true;
                         continue;
                     }
-                    self.map.insert(value, inst);
+                    self.map.insert(key, inst);
                 }
+            } else if let Some(ranges) = self.ranges {
+                self.fold_offset(inst, block, body, ranges);
             }
         }
     }
+
+    /// Rewrite a load or store of `x + c` into an access of `x` with
+    /// `c` added to its static offset. This is only valid if `x + c`
+    /// does not wrap, because the offset addition never does.
+    fn fold_offset(
+        &mut self,
+        inst: Value,
+        block: Block,
+        body: &mut FunctionBody,
+        ranges: &RangeAnalysis,
+    ) {
+        let (mut op, args, tys) = match body.values[inst] {
+            ValueDef::Operator(op, args, tys) => (op, args, tys),
+            _ => return,
+        };
+        let memarg = match op.memory_access() {
+            Some((memarg, _)) => memarg,
+            None => return,
+        };
+        let addr = body.resolve_alias(body.arg_pool[args][0]);
+        let (base, offset) = match body.values[addr] {
+            ValueDef::Operator(Operator::I32Add, add_args, _) => {
+                let lhs = body.resolve_alias(body.arg_pool[add_args][0]);
+                let rhs = body.resolve_alias(body.arg_pool[add_args][1]);
+                match (value_is_const(lhs, body), value_is_const(rhs, body)) {
+                    (_, ConstVal::I32(c)) => (lhs, c),
+                    (ConstVal::I32(c), _) => (rhs, c),
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+        let new_offset = match memarg.offset.checked_add(offset) {
+            Some(new_offset) => new_offset,
+            None => return,
+        };
+        match ranges.range_at(base, block) {
+            Some(range) if range.max + offset as u64 <= u32::MAX as u64 => {}
+            _ => return,
+        }
+
+        op.update_memory_arg(|memarg| memarg.offset = new_offset);
+        body.values[inst] = ValueDef::Operator(op, args, tys);
+        body.arg_pool[args][0] = base;
+        self.changed = true;
+    }
 }
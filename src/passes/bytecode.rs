@@ -0,0 +1,87 @@
+//! Rewriting of un-expanded function bodies, for lowering operators
+//! the IR does not have: the bytecode is spliced, and the function is
+//! then expanded from the rewritten code.
+
+use crate::frontend::parse_body;
+use crate::ir::{FuncDecl, Module};
+use crate::Func;
+use anyhow::Result;
+use std::sync::Arc;
+use wasm_encoder::{Encode, Instruction, ValType};
+use wasmparser::Operator;
+
+/// Rewrite `body`, the un-expanded body of `func`, replacing each
+/// operator that `classify` recognizes with the instructions `lower`
+/// gives for it, if there are any. The rewritten body has the
+/// `scratch` locals appended; `lower` gets the index of the first.
+pub(crate) fn rewrite_body<T>(
+    module: &Module,
+    func: Func,
+    body: &wasmparser::FunctionBody,
+    scratch: &[(u32, ValType)],
+    classify: impl Fn(&Operator) -> Option<T>,
+    mut lower: impl FnMut(T, u32) -> Vec<Instruction<'static>>,
+) -> Result<Option<Vec<u8>>> {
+    let mut ops = body.get_operators_reader()?;
+    let ops_start = ops.original_position();
+    let mut found = vec![];
+    while !ops.eof() {
+        let offset = ops.original_position();
+        if let Some(op) = classify(&ops.read()?) {
+            found.push((offset, ops.original_position(), op));
+        }
+    }
+    if found.is_empty() {
+        return Ok(None);
+    }
+
+    // Append the scratch locals after the existing ones.
+    let base = body.range().start;
+    let bytes = body.as_bytes();
+    let mut locals = body.get_locals_reader()?;
+    let mut next = module.signatures[module.funcs[func].sig()].params.len() as u32;
+    let groups = locals.get_count();
+    let entries_start = locals.original_position();
+    for _ in 0..groups {
+        next += locals.read()?.0;
+    }
+    let mut out = vec![];
+    (groups + scratch.len() as u32).encode(&mut out);
+    out.extend_from_slice(&bytes[entries_start - base..ops_start - base]);
+    for &(count, ty) in scratch {
+        count.encode(&mut out);
+        ty.encode(&mut out);
+    }
+
+    // Copy the code, replacing each operator found.
+    let mut copied = ops_start;
+    for (start, end, op) in found {
+        out.extend_from_slice(&bytes[copied - base..start - base]);
+        for inst in lower(op, next) {
+            inst.encode(&mut out);
+        }
+        copied = end;
+    }
+    out.extend_from_slice(&bytes[copied - base..]);
+    Ok(Some(out))
+}
+
+/// Expand `func` from `code`, a rewritten body that was at `offset`.
+pub(crate) fn expand_rewritten(
+    module: &mut Module,
+    func: Func,
+    offset: usize,
+    code: &[u8],
+) -> Result<()> {
+    let reader = wasmparser::BinaryReader::new(code, offset, wasmparser::WasmFeatures::all());
+    let mut body = wasmparser::FunctionBody::new(reader);
+    let sig = module.funcs[func].sig();
+    let mut body = parse_body(module, sig, &mut body)?;
+    if let Some(names) = module.debug.local_names.get(&func) {
+        body.local_names = names.clone();
+    }
+    let name = module.funcs[func].name().to_owned();
+    module.funcs[func] = FuncDecl::Body(sig, name, Arc::new(body));
+    module.unsupported_funcs.remove(&func);
+    Ok(())
+}
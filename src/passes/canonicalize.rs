@@ -0,0 +1,217 @@
+//! Canonicalization: putting a module in a stable, diff-friendly
+//! form.
+//!
+//! The backend's output is byte-deterministic for a given module and
+//! backend options, but two modules that mean the same thing can
+//! still differ in incidental ways: the order of their exports, the
+//! numbering of blocks and values left behind by earlier transforms,
+//! or unused and duplicate signatures. This pass removes those
+//! differences, so that builds are reproducible and their outputs
+//! comparable across machines and transform histories.
+//!
+//! Sections are always emitted in the standard order, with custom
+//! sections last and sorted by name; there is nothing to normalize
+//! there.
+
+use crate::cfg::CFGInfo;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{Block, BlockDef, FunctionBody, Module, Value, ValueDef};
+use crate::passes::signatures;
+use anyhow::{bail, Result};
+
+/// Canonicalize `module`: sort exports by name, renumber every
+/// function body (see `renumber()`), and minimize the type section
+/// (see `signatures::minimize()`). Fails if any function body is not
+/// expanded, as the type section then cannot be minimized.
+pub fn run(module: &mut Module) -> Result<()> {
+    module.exports.sort_by(|a, b| a.name.cmp(&b.name));
+    module.per_func_body(|body| *body = renumber(body));
+    if signatures::minimize(module).is_none() {
+        bail!("Cannot canonicalize signatures: not all function bodies are expanded");
+    }
+    Ok(())
+}
+
+/// Renumber `body` in a canonical order: blocks in reverse postorder,
+/// and values in order of definition, each block's parameters before
+/// its instructions. Aliases are resolved and unreachable blocks
+/// dropped, so bodies with equal structural hashes (see
+/// `FunctionBody::structural_hash()`) renumber to equal bodies.
+pub fn renumber(body: &FunctionBody) -> FunctionBody {
+    let cfg = CFGInfo::new(body);
+    let mut new = FunctionBody {
+        n_params: body.n_params,
+        rets: body.rets.clone(),
+        locals: body.locals.clone(),
+        local_names: body.local_names.clone(),
+        ..FunctionBody::default()
+    };
+
+    // Number blocks and values first, since block parameters may be
+    // used before they are defined in this order.
+    let mut block_map: PerEntity<Block, Block> = PerEntity::default();
+    let mut value_map: PerEntity<Value, Value> = PerEntity::default();
+    for &block in cfg.rpo.values() {
+        block_map[block] = new.blocks.push(BlockDef {
+            origin: body.blocks[block].origin.clone(),
+            branch_hint: body.blocks[block].branch_hint,
+            ..BlockDef::default()
+        });
+        let def = &body.blocks[block];
+        for &value in def.params.iter().map(|(_, value)| value).chain(&def.insts) {
+            value_map[value] = new.values.push(ValueDef::None);
+        }
+    }
+    new.entry = block_map[body.entry];
+    let map_value = |value: Value| value_map[body.resolve_alias(value)];
+
+    for &block in cfg.rpo.values() {
+        let def = &body.blocks[block];
+        let new_block = block_map[block];
+        for &(ty, param) in &def.params {
+            let new_param = value_map[param];
+            let index = new.blocks[new_block].params.len() as u32;
+            new.values[new_param] = ValueDef::BlockParam(new_block, index, ty);
+            new.value_blocks[new_param] = new_block;
+            new.blocks[new_block].params.push((ty, new_param));
+        }
+        for &inst in &def.insts {
+            let new_inst = value_map[inst];
+            new.values[new_inst] = match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => {
+                    let args = new
+                        .arg_pool
+                        .from_iter(body.arg_pool[*args].iter().map(|&arg| map_value(arg)));
+                    let tys = match &body.type_pool[*tys] {
+                        &[ty] => new.single_type_list(ty),
+                        tys => new.type_pool.from_iter(tys.iter().copied()),
+                    };
+                    ValueDef::Operator(new.import_op(body, *op), args, tys)
+                }
+                ValueDef::PickOutput(value, index, ty) => {
+                    ValueDef::PickOutput(map_value(*value), *index, *ty)
+                }
+                other => other.clone(),
+            };
+            new.value_blocks[new_inst] = new_block;
+            new.blocks[new_block].insts.push(new_inst);
+        }
+        let mut terminator = def.terminator.clone();
+        terminator.update_targets(|target| target.block = block_map[target.block]);
+        // This includes the targets' arguments.
+        terminator.update_uses(|value| *value = map_value(*value));
+        new.blocks[new_block].terminator = terminator;
+    }
+
+    for (old, _) in body.values.entries() {
+        let new_value = value_map[old];
+        if new_value.is_valid() {
+            new.value_locals[new_value] = body.value_locals[old];
+            new.source_locs[new_value] = body.source_locs[old];
+            new.wasm_offsets[new_value] = body.wasm_offsets[old];
+        }
+    }
+    new.debug_values = body
+        .debug_values
+        .renumber(&block_map, |value| value_map[body.resolve_alias(value)]);
+    new.recompute_edges();
+    new
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Export, ExportKind, FuncDecl, SignatureData, Terminator, Type};
+    use crate::Operator;
+
+    fn module(reversed: bool) -> Module<'static> {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        // An unreachable block, and a value aliased to another, which
+        // canonicalization removes.
+        if reversed {
+            body.add_block();
+        }
+        let exit = body.add_block();
+        let param = body.add_blockparam(exit, Type::I32);
+        let one = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let sum = body.add_op(entry, Operator::I32Add, &[x, one], &[Type::I32]);
+        let arg = if reversed {
+            let alias = body.add_value(ValueDef::Alias(sum));
+            body.value_blocks[alias] = entry;
+            alias
+        } else {
+            sum
+        };
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: crate::ir::BlockTarget {
+                    block: exit,
+                    args: vec![arg],
+                },
+            },
+        );
+        body.set_terminator(
+            exit,
+            Terminator::Return {
+                values: vec![param],
+            },
+        );
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        let mut names = vec!["b", "a"];
+        if reversed {
+            names.reverse();
+        }
+        for name in names {
+            module.exports.push(Export {
+                name: name.to_owned(),
+                kind: ExportKind::Func(f),
+            });
+        }
+        module
+    }
+
+    #[test]
+    fn equivalent_modules_compile_identically() {
+        let (mut a, mut b) = (module(false), module(true));
+        assert_ne!(a.to_wasm_bytes().unwrap(), b.to_wasm_bytes().unwrap());
+        run(&mut a).unwrap();
+        run(&mut b).unwrap();
+        let bytes = a.to_wasm_bytes().unwrap();
+        assert_eq!(bytes, b.to_wasm_bytes().unwrap());
+        assert_eq!(bytes, a.to_wasm_bytes().unwrap());
+        assert_eq!(a.exports[0].name, "a");
+
+        let body = b.funcs[crate::Func::new(0)].body().unwrap();
+        body.validate().unwrap();
+        assert_eq!(body.blocks.len(), 2);
+        assert_eq!(
+            format!("{}", body.display("", None)),
+            format!(
+                "{}",
+                a.funcs[crate::Func::new(0)]
+                    .body()
+                    .unwrap()
+                    .display("", None)
+            )
+        );
+    }
+
+    #[test]
+    fn lazy_bodies_are_errors() {
+        let bytes = wat::parse_str("(module (func))").unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &Default::default()).unwrap();
+        assert!(run(&mut module).is_err());
+        module.expand_all_funcs().unwrap();
+        run(&mut module).unwrap();
+    }
+}
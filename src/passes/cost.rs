@@ -0,0 +1,214 @@
+//! Operator cost model.
+//!
+//! Transforms that trade code size against speed (select formation,
+//! rematerialization, and the like) need some estimate of what an
+//! operator costs. `CostModel` gives each operator a size cost and a
+//! latency cost; passes consult the model in `OptOptions::cost_model`
+//! rather than hard-coding their own tables, so an embedder whose
+//! engine has unusual cost characteristics can supply its own.
+
+use crate::ir::{Block, FunctionBody, Module, Type, ValueDef};
+use crate::op_traits::{op_inputs, op_outputs};
+use crate::{ImmediateKind, Operator};
+
+/// Size and latency costs of operators.
+pub trait CostModel: std::fmt::Debug + Send + Sync {
+    /// The cost of the operator in code size, in (approximate)
+    /// encoded bytes.
+    fn size(&self, op: &Operator) -> u32;
+
+    /// The cost of executing the operator, in (approximate) cycles
+    /// of a simple in-order machine.
+    fn latency(&self, op: &Operator) -> u32;
+
+    /// A name identifying this model in compilation-cache keys. Models
+    /// that may give any operator different costs must have different
+    /// names, and a model's name must change whenever its costs do.
+    fn cache_id(&self) -> &str;
+
+    /// The total size of the operators in `block`.
+    fn block_size(&self, body: &FunctionBody, block: Block) -> u32 {
+        block_cost(body, block, |op| self.size(op))
+    }
+
+    /// The total latency of the operators in `block`, executed in
+    /// sequence.
+    fn block_latency(&self, body: &FunctionBody, block: Block) -> u32 {
+        block_cost(body, block, |op| self.latency(op))
+    }
+}
+
+fn block_cost<F: Fn(&Operator) -> u32>(body: &FunctionBody, block: Block, f: F) -> u32 {
+    body.blocks[block]
+        .insts
+        .iter()
+        .map(|&inst| match &body.values[inst] {
+            ValueDef::Operator(op, ..) => f(op),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The cost model used unless an embedder provides another: sizes
+/// are those of the standard binary encoding with small indices, and
+/// latencies follow typical native lowerings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCostModel;
+
+/// Does `op` operate on SIMD vectors?
+fn is_simd(op: &Operator) -> bool {
+    if op.is_call() || matches!(op, Operator::CallRef { .. }) {
+        return false;
+    }
+    let empty = Module::empty();
+    let has_v128 = |tys: &[Type]| tys.contains(&Type::V128);
+    op_inputs(&empty, &[], op).is_ok_and(|tys| has_v128(&tys))
+        || op_outputs(&empty, &[], op).is_ok_and(|tys| has_v128(&tys))
+}
+
+/// The length of `value` in signed LEB128.
+fn sleb_len(value: i64) -> u32 {
+    // Significant bits, plus a sign bit.
+    let redundant = if value < 0 {
+        value.leading_ones()
+    } else {
+        value.leading_zeros()
+    };
+    (64 - redundant + 1).div_ceil(7)
+}
+
+impl CostModel for DefaultCostModel {
+    fn size(&self, op: &Operator) -> u32 {
+        let opcode = if is_simd(op) {
+            3
+        } else {
+            match op {
+                Operator::I32TruncSatF32S
+                | Operator::I32TruncSatF32U
+                | Operator::I32TruncSatF64S
+                | Operator::I32TruncSatF64U
+                | Operator::I64TruncSatF32S
+                | Operator::I64TruncSatF32U
+                | Operator::I64TruncSatF64S
+                | Operator::I64TruncSatF64U
+                | Operator::MemoryCopy { .. }
+                | Operator::MemoryFill { .. }
+                | Operator::TableGrow { .. }
+                | Operator::TableSize { .. } => 2,
+                _ => 1,
+            }
+        };
+        let immediates: u32 = op
+            .immediates()
+            .iter()
+            .map(|kind| match kind {
+                ImmediateKind::MemArg => 2,
+                ImmediateKind::Lanes => 16,
+                ImmediateKind::Const => match op {
+                    Operator::I32Const { value } => sleb_len(*value as i32 as i64),
+                    Operator::I64Const { value } => sleb_len(*value as i64),
+                    Operator::F32Const { .. } => 4,
+                    Operator::F64Const { .. } => 8,
+                    _ => 16,
+                },
+                _ => 1,
+            })
+            .sum();
+        opcode + immediates
+    }
+
+    fn latency(&self, op: &Operator) -> u32 {
+        match op {
+            Operator::Nop
+            | Operator::I32Const { .. }
+            | Operator::I64Const { .. }
+            | Operator::F32Const { .. }
+            | Operator::F64Const { .. }
+            | Operator::V128Const { .. } => 0,
+
+            Operator::I32DivS
+            | Operator::I32DivU
+            | Operator::I32RemS
+            | Operator::I32RemU
+            | Operator::I64DivS
+            | Operator::I64DivU
+            | Operator::I64RemS
+            | Operator::I64RemU => 25,
+            Operator::F32Div | Operator::F64Div | Operator::F32Sqrt | Operator::F64Sqrt => 15,
+            Operator::F32x4Div | Operator::F64x2Div | Operator::F32x4Sqrt | Operator::F64x2Sqrt => {
+                15
+            }
+            Operator::I32Mul | Operator::I64Mul => 3,
+
+            Operator::Call { .. } => 5,
+            Operator::CallIndirect { .. } | Operator::CallRef { .. } => 10,
+            Operator::MemoryGrow { .. } | Operator::TableGrow { .. } => 100,
+            Operator::MemoryCopy { .. } | Operator::MemoryFill { .. } => 50,
+
+            _ if op.is_load() => 4,
+            _ if op.accesses_memory() => 2,
+            _ if is_simd(op) => 2,
+            _ => match op_outputs(&Module::empty(), &[], op).as_deref() {
+                // Scalar floating-point arithmetic.
+                Ok([Type::F32]) | Ok([Type::F64]) => 4,
+                _ => 1,
+            },
+        }
+    }
+
+    fn cache_id(&self) -> &str {
+        "default"
+    }
+}
+
+/// A cost model for optimizing purely for size: latency is taken to
+/// be the size, so passes that weigh latency weigh bytes instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeCostModel;
+
+impl CostModel for SizeCostModel {
+    fn size(&self, op: &Operator) -> u32 {
+        DefaultCostModel.size(op)
+    }
+
+    fn latency(&self, op: &Operator) -> u32 {
+        DefaultCostModel.size(op)
+    }
+
+    fn cache_id(&self) -> &str {
+        "size"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Memory, MemoryArg};
+
+    #[test]
+    fn default_costs() {
+        let model = DefaultCostModel;
+        assert_eq!(model.size(&Operator::I32Add), 1);
+        assert_eq!(model.size(&Operator::I32Const { value: 5 }), 2);
+        assert_eq!(model.size(&Operator::I32Const { value: 64 }), 3);
+        assert_eq!(model.size(&Operator::I32Const { value: 0xffff_ffc0 }), 2);
+        assert_eq!(model.size(&Operator::I32Const { value: u32::MAX }), 2);
+        assert_eq!(model.size(&Operator::I64Const { value: 1 << 40 }), 7);
+        assert_eq!(model.size(&Operator::I32x4Add), 3);
+        let memory = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory: Memory::from(0),
+        };
+        assert_eq!(model.size(&Operator::I32Load { memory }), 3);
+
+        assert!(model.latency(&Operator::I32DivU) > model.latency(&Operator::I32Mul));
+        assert!(model.latency(&Operator::I32Mul) > model.latency(&Operator::I32Add));
+        assert!(model.latency(&Operator::F64Add) > model.latency(&Operator::I64Add));
+        assert_eq!(model.latency(&Operator::I32Const { value: 1 }), 0);
+
+        // The size model counts bytes even for latency.
+        assert_eq!(SizeCostModel.latency(&Operator::I32DivU), 1);
+        assert_eq!(SizeCostModel.latency(&Operator::I32Const { value: 64 }), 3);
+    }
+}
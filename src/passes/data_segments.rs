@@ -0,0 +1,465 @@
+//! Data-segment analysis and compaction.
+//!
+//! Toolchains often emit data segments that overlap, duplicate one
+//! another, or carry long runs of zero bytes (e.g., `.bss`-like
+//! regions that were materialized into `.data`). Because Wasm
+//! memories that are defined (not imported) by a module start out
+//! zero-filled, such zero runs are redundant. This module provides
+//! an analysis that reports these properties, together with the
+//! ranges that the module's code may write at runtime, and a
+//! transform that rewrites each memory's segment list into a minimal
+//! equivalent one.
+
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, ImportKind, Memory, MemorySegment, Module, Value, ValueDef, WASM_PAGE};
+use crate::{ExportKind, Operator};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Options for data-segment compaction.
+#[derive(Clone, Debug)]
+pub struct CompactOptions {
+    /// Runs of zero bytes at least this long are removed from
+    /// segments (splitting the segment in two), and gaps shorter than
+    /// this between two segments are filled with zeroes so that the
+    /// segments can be merged. This trades a few bytes of data for the
+    /// per-segment encoding overhead.
+    pub min_zero_run: usize,
+}
+
+impl std::default::Default for CompactOptions {
+    fn default() -> Self {
+        CompactOptions { min_zero_run: 8 }
+    }
+}
+
+/// Analysis results for the data segments of one memory.
+#[derive(Clone, Debug, Default)]
+pub struct DataSegmentReport {
+    /// The memory these segments initialize.
+    pub memory: Memory,
+    /// Number of data segments.
+    pub segments: usize,
+    /// Total number of bytes across all segments.
+    pub total_bytes: usize,
+    /// Pairs of segment indices `(earlier, later)` whose ranges
+    /// overlap. The later segment's data wins in the overlap.
+    pub overlapping: Vec<(usize, usize)>,
+    /// Pairs of segment indices `(earlier, later)` with identical
+    /// contents, regardless of offset.
+    pub duplicates: Vec<(usize, usize)>,
+    /// Address ranges inside segments consisting only of zero bytes,
+    /// of at least the length given by `CompactOptions::min_zero_run`.
+    pub zero_ranges: Vec<Range<usize>>,
+    /// Initialized address ranges that code may write at runtime.
+    pub writable: Vec<Range<usize>>,
+    /// Initialized address ranges that are never written at runtime.
+    pub read_only: Vec<Range<usize>>,
+}
+
+/// Analyze the data segments of every memory in the module.
+///
+/// The writable/read-only classification is conservative: it relies
+/// on store addresses being constants in the IR, and treats the whole
+/// memory as writable if the memory is imported or exported, if any
+/// function body is not expanded to IR, or if any write to the memory
+/// has a non-constant address.
+pub fn analyze(module: &Module, options: &CompactOptions) -> Vec<DataSegmentReport> {
+    let writes = collect_writes(module);
+    module
+        .memories
+        .entries()
+        .map(|(memory, mem_data)| {
+            let segments = &mem_data.segments;
+            let mut report = DataSegmentReport {
+                memory,
+                segments: segments.len(),
+                total_bytes: segments.iter().map(|seg| seg.data.len()).sum(),
+                ..Default::default()
+            };
+
+            for (i, a) in segments.iter().enumerate() {
+                for (j, b) in segments.iter().enumerate().skip(i + 1) {
+                    if a.offset < b.offset + b.data.len() && b.offset < a.offset + a.data.len() {
+                        report.overlapping.push((i, j));
+                    }
+                    if !a.data.is_empty() && a.data == b.data {
+                        report.duplicates.push((i, j));
+                    }
+                }
+                for run in zero_runs(&a.data[..], options.min_zero_run) {
+                    report
+                        .zero_ranges
+                        .push((a.offset + run.start)..(a.offset + run.end));
+                }
+            }
+
+            let initialized = flatten(segments)
+                .into_iter()
+                .map(|(start, data)| start..(start + data.len()))
+                .collect::<Vec<_>>();
+            match writes.get(&memory) {
+                None => report.read_only = initialized,
+                Some(None) => report.writable = initialized,
+                Some(Some(written)) => {
+                    let written = normalize(written.clone());
+                    report.writable = intersect(&initialized, &written);
+                    report.read_only = subtract(&initialized, &written);
+                }
+            }
+
+            report
+        })
+        .collect()
+}
+
+/// Rewrite the data segments of every memory into an equivalent,
+/// minimal set: overlapping and adjacent segments are merged, ranges
+/// that are shadowed by later segments are dropped, and (for memories
+/// defined by this module) zero runs are removed.
+///
+/// Memories with a segment that extends beyond the memory's initial
+/// size are left untouched, because such a segment traps at
+/// instantiation and compaction could remove the trap.
+///
+/// Returns the number of segment bytes saved.
+pub fn compact(module: &mut Module, options: &CompactOptions) -> usize {
+    let imported = imported_memories(module);
+    let mut saved = 0;
+    for (memory, mem_data) in module.memories.entries_mut() {
+        let limit = mem_data.initial_pages.saturating_mul(WASM_PAGE);
+        if mem_data.segments.iter().any(|seg| {
+            seg.offset
+                .checked_add(seg.data.len())
+                .map(|end| end > limit)
+                .unwrap_or(true)
+        }) {
+            log::debug!(
+                "data_segments: {} has out-of-bounds segments; not compacting",
+                memory
+            );
+            continue;
+        }
+
+        let before: usize = mem_data.segments.iter().map(|seg| seg.data.len()).sum();
+        let mut runs = flatten(&mem_data.segments);
+        if !imported.contains(&memory) {
+            runs = drop_zeros(runs, options.min_zero_run);
+        }
+        let segments = runs
+            .into_iter()
+            .map(|(offset, data)| MemorySegment { offset, data })
+            .collect::<Vec<_>>();
+        let after: usize = segments.iter().map(|seg| seg.data.len()).sum();
+        log::debug!(
+            "data_segments: {}: {} segments ({} bytes) -> {} segments ({} bytes)",
+            memory,
+            mem_data.segments.len(),
+            before,
+            segments.len(),
+            after
+        );
+        saved += before.saturating_sub(after);
+        mem_data.segments = segments;
+    }
+    saved
+}
+
+fn imported_memories(module: &Module) -> Vec<Memory> {
+    module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Memory(mem) => Some(mem),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Apply the segments in order to an initially-unset image, and
+/// return the resulting initialized contents as sorted, disjoint,
+/// non-adjacent runs `(start, data)`.
+pub(crate) fn flatten(segments: &[MemorySegment]) -> Vec<(usize, Vec<u8>)> {
+    let mut runs: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    for seg in segments {
+        if seg.data.is_empty() {
+            continue;
+        }
+        let start = seg.offset;
+        let end = seg.offset + seg.data.len();
+        // Find all existing runs that overlap `[start, end)`; split
+        // off any parts outside of the new segment.
+        let overlapping = runs
+            .range(..end)
+            .rev()
+            .take_while(|(&run_start, run)| run_start + run.len() > start)
+            .map(|(&run_start, _)| run_start)
+            .collect::<Vec<_>>();
+        for run_start in overlapping {
+            let run = runs.remove(&run_start).unwrap();
+            let run_end = run_start + run.len();
+            if run_start < start {
+                runs.insert(run_start, run[..(start - run_start)].to_vec());
+            }
+            if run_end > end {
+                runs.insert(end, run[(end - run_start)..].to_vec());
+            }
+        }
+        runs.insert(start, seg.data.clone());
+    }
+
+    // Coalesce runs that touch.
+    let mut result: Vec<(usize, Vec<u8>)> = vec![];
+    for (start, data) in runs {
+        match result.last_mut() {
+            Some((last_start, last_data)) if *last_start + last_data.len() == start => {
+                last_data.extend_from_slice(&data[..]);
+            }
+            _ => result.push((start, data)),
+        }
+    }
+    result
+}
+
+/// Remove leading and trailing zeroes and long zero runs from each
+/// run, and merge runs separated by short gaps. Only valid when
+/// uninitialized memory is known to be zero.
+fn drop_zeros(runs: Vec<(usize, Vec<u8>)>, min_zero_run: usize) -> Vec<(usize, Vec<u8>)> {
+    let min_zero_run = std::cmp::max(min_zero_run, 1);
+    let mut pieces: Vec<(usize, Vec<u8>)> = vec![];
+    for (start, data) in runs {
+        let mut piece_start = 0;
+        for zeros in zero_runs(&data[..], 1) {
+            let at_edge = zeros.start == 0 || zeros.end == data.len();
+            if at_edge || zeros.len() >= min_zero_run {
+                if zeros.start > piece_start {
+                    pieces.push((start + piece_start, data[piece_start..zeros.start].to_vec()));
+                }
+                piece_start = zeros.end;
+            }
+        }
+        if piece_start < data.len() {
+            pieces.push((start + piece_start, data[piece_start..].to_vec()));
+        }
+    }
+
+    let mut result: Vec<(usize, Vec<u8>)> = vec![];
+    for (start, data) in pieces {
+        match result.last_mut() {
+            Some((last_start, last_data))
+                if *last_start + last_data.len() + min_zero_run > start =>
+            {
+                let gap = start - (*last_start + last_data.len());
+                last_data.resize(last_data.len() + gap, 0);
+                last_data.extend_from_slice(&data[..]);
+            }
+            _ => result.push((start, data)),
+        }
+    }
+    result
+}
+
+/// Find all maximal runs of zero bytes of at least `min_len` bytes.
+fn zero_runs(data: &[u8], min_len: usize) -> Vec<Range<usize>> {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && data[i] == 0 {
+            i += 1;
+        }
+        if i - start >= std::cmp::max(min_len, 1) {
+            runs.push(start..i);
+        }
+    }
+    runs
+}
+
+/// For each memory that may be written, the ranges written, or `None`
+/// if the written addresses are not statically known. Memories that
+/// are never written do not appear in the map.
+fn collect_writes(module: &Module) -> BTreeMap<Memory, Option<Vec<Range<usize>>>> {
+    let mut writes: BTreeMap<Memory, Option<Vec<Range<usize>>>> = BTreeMap::new();
+    let unknown = |writes: &mut BTreeMap<Memory, Option<Vec<Range<usize>>>>, mem: Memory| {
+        writes.insert(mem, None);
+    };
+
+    for import in &module.imports {
+        if let ImportKind::Memory(mem) = import.kind {
+            unknown(&mut writes, mem);
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Memory(mem) = export.kind {
+            unknown(&mut writes, mem);
+        }
+    }
+
+    let all_expanded = module
+        .funcs
+        .values()
+        .all(|decl| matches!(decl, FuncDecl::Body(..) | FuncDecl::Import(..)));
+    if !all_expanded {
+        for mem in module.memories.iter() {
+            unknown(&mut writes, mem);
+        }
+        return writes;
+    }
+
+    for decl in module.funcs.values() {
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        let const_arg = |value: Value| -> Option<usize> {
+            match &body.values[body.resolve_alias(value)] {
+                ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value as usize),
+                _ => None,
+            }
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                let (op, args) = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+                    _ => continue,
+                };
+                let (mem, range) = match op {
+                    Operator::MemoryCopy { dst_mem, .. } => {
+                        let range = const_arg(args[0])
+                            .zip(const_arg(args[2]))
+                            .map(|(dst, len)| dst..(dst + len));
+                        (*dst_mem, range)
+                    }
+                    Operator::MemoryFill { mem } => {
+                        let range = const_arg(args[0])
+                            .zip(const_arg(args[2]))
+                            .map(|(dst, len)| dst..(dst + len));
+                        (*mem, range)
+                    }
+                    _ => match op.memory_access() {
+                        Some((memarg, width)) if op.is_store() => {
+                            let range = const_arg(args[0]).map(|addr| {
+                                let start = addr + memarg.offset as usize;
+                                start..(start + width)
+                            });
+                            (memarg.memory, range)
+                        }
+                        _ => continue,
+                    },
+                };
+                match (writes.get_mut(&mem), range) {
+                    (Some(None), _) => {}
+                    (Some(Some(ranges)), Some(range)) => ranges.push(range),
+                    (None, Some(range)) => {
+                        writes.insert(mem, Some(vec![range]));
+                    }
+                    (_, None) => unknown(&mut writes, mem),
+                }
+            }
+        }
+    }
+
+    // Memory indices that are out of range (e.g., in a malformed
+    // module) are ignored.
+    writes.retain(|mem, _| mem.index() < module.memories.len());
+    writes
+}
+
+/// Sort and merge a list of ranges into disjoint, non-adjacent ranges.
+fn normalize(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.retain(|r| r.start < r.end);
+    ranges.sort_by_key(|r| r.start);
+    let mut result: Vec<Range<usize>> = vec![];
+    for r in ranges {
+        match result.last_mut() {
+            Some(last) if last.end >= r.start => last.end = std::cmp::max(last.end, r.end),
+            _ => result.push(r),
+        }
+    }
+    result
+}
+
+/// Intersect two normalized range lists.
+fn intersect(a: &[Range<usize>], b: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = std::cmp::max(a[i].start, b[j].start);
+        let end = std::cmp::min(a[i].end, b[j].end);
+        if start < end {
+            result.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+/// Subtract normalized range list `b` from normalized range list `a`.
+fn subtract(a: &[Range<usize>], b: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut result = vec![];
+    for r in a {
+        let mut start = r.start;
+        for cut in b {
+            if cut.end <= start || cut.start >= r.end {
+                continue;
+            }
+            if cut.start > start {
+                result.push(start..cut.start);
+            }
+            start = std::cmp::max(start, cut.end);
+        }
+        if start < r.end {
+            result.push(start..r.end);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::MemoryData;
+
+    fn seg(offset: usize, data: &[u8]) -> MemorySegment {
+        MemorySegment {
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn flatten_overlapping() {
+        let runs = flatten(&[seg(0, &[1, 1, 1, 1]), seg(2, &[2, 2, 2]), seg(1, &[3])]);
+        assert_eq!(runs, vec![(0, vec![1, 3, 2, 2, 2])]);
+    }
+
+    #[test]
+    fn compact_drops_zeros() {
+        let mut module = Module::empty();
+        let mut data = vec![5, 0, 0];
+        data.resize(data.len() + 32, 0);
+        data.extend(&[6, 7]);
+        module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![seg(16, &[0, 0, 1, 2]), seg(18, &data[..])],
+        });
+        let opts = CompactOptions::default();
+        let report = analyze(&module, &opts);
+        assert_eq!(report[0].overlapping, vec![(0, 1)]);
+        assert_eq!(report[0].read_only.len(), 1);
+
+        compact(&mut module, &opts);
+        let segments = &module.memories[Memory::new(0)].segments;
+        assert_eq!(segments, &vec![seg(18, &[5]), seg(18 + 35, &[6, 7])]);
+        let _ = module.to_wasm_bytes().unwrap();
+    }
+}
@@ -0,0 +1,1144 @@
+//! Feature downgrades and upgrades: lowering newer features into older
+//! code for engines without them, and raising older code into newer
+//! features for engines with them.
+//!
+//! Given the features a target engine supports, `downgrade()` lowers
+//! what it can of the rest:
+//!
+//! - Relaxed-SIMD operators become strict ones; see
+//!   `passes::relaxed_simd`.
+//! - Atomic accesses become plain ones, for single-threaded targets;
+//!   see `passes::atomics`.
+//! - SIMD code becomes scalar code, each `v128` a pair of `i64`s; see
+//!   `passes::scalarize`. This comes first, as it emits operators the
+//!   lowerings below may lower in turn.
+//! - Sign-extension operators become a shift left and an arithmetic
+//!   shift right.
+//! - Saturating float-to-int conversions become trapping ones,
+//!   guarded by a range check: an out-of-range or NaN input is
+//!   replaced by zero before the conversion, and the result by the
+//!   saturated value after it, both with `select`.
+//! - `memory.copy` and `memory.fill` become byte loops, after a bounds
+//!   check of the whole range so that, as with the bulk operators, an
+//!   out-of-bounds operation traps before writing anything. A copy
+//!   runs backward when the destination is above the source, so
+//!   overlapping copies behave as `memmove`.
+//! - Functions returning several values return the first, and store
+//!   the others in scratch globals (one per result position and
+//!   type), which callers read right after the call. Imported and
+//!   exported functions keep their signatures, so multi-value ones
+//!   cannot be lowered.
+//!
+//! Other features cannot be lowered; `downgrade()` fails if the module
+//! still uses any the target lacks.
+//!
+//! Conversely, `upgrade()` raises the guarded conversions back into
+//! saturating ones when the target supports them, as when a module
+//! built for old engines is recompiled for new ones. It recognizes
+//! the sequence `downgrade()` emits, including after optimization has
+//! shared its constants.
+//!
+//! Setting `BackendOptions::target_features` runs both on a copy of
+//! the module before compiling.
+
+use super::atomics::{self, AtomicWaits};
+use super::features::{Feature, FeatureSet};
+use super::relaxed_simd;
+use super::scalarize::scalarize;
+use crate::ir::{
+    Block, BlockOrigin, BlockTarget, ExportKind, FuncDecl, FunctionBody, GlobalData, ImportKind,
+    Module, Terminator, Type, Value, ValueDef,
+};
+use crate::{Global, MemoryArg, Operator, Signature};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The features a target engine supports, beyond the MVP.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetFeatures {
+    pub features: FeatureSet,
+    /// What atomic waits and notifies become without threads.
+    pub atomic_waits: AtomicWaits,
+}
+
+impl TargetFeatures {
+    /// A target supporting only the MVP.
+    pub fn mvp() -> TargetFeatures {
+        TargetFeatures::default()
+    }
+
+    /// A target supporting `features`.
+    pub fn new(features: impl IntoIterator<Item = Feature>) -> TargetFeatures {
+        let mut set = FeatureSet::default();
+        for feature in features {
+            set.insert(feature);
+        }
+        TargetFeatures {
+            features: set,
+            ..TargetFeatures::default()
+        }
+    }
+
+    /// Does the target support `feature`?
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+/// What `downgrade()` lowered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DowngradeReport {
+    /// Functions whose relaxed-SIMD operators became strict ones.
+    pub relaxed_simd: usize,
+    /// Functions whose atomics became plain accesses.
+    pub atomics: usize,
+    /// Functions whose SIMD code became scalar code.
+    pub simd_funcs: usize,
+    pub sign_extensions: usize,
+    pub saturating_conversions: usize,
+    pub bulk_memory_ops: usize,
+    /// Signatures whose extra results now go through globals.
+    pub multi_value_sigs: usize,
+}
+
+/// Lower the features of `module` that `target` lacks, as described
+/// in the module documentation.
+pub fn downgrade(module: &mut Module, target: &TargetFeatures) -> Result<DowngradeReport> {
+    let mut report = DowngradeReport::default();
+    // Relaxed SIMD and atomics are lowered in bytecode, which can then
+    // be expanded.
+    if !target.supports(Feature::RelaxedSimd) {
+        report.relaxed_simd = relaxed_simd::determinize(module)?;
+    }
+    if !target.supports(Feature::Threads) {
+        report.atomics = atomics::lower(module, target.atomic_waits)?;
+    }
+    module.expand_all_funcs()?;
+    for (func, decl) in module.funcs.entries() {
+        if !matches!(decl, FuncDecl::Body(..) | FuncDecl::Import(..)) {
+            bail!("Cannot lower features of {}: it has no IR", func);
+        }
+    }
+
+    if !target.supports(Feature::Simd) {
+        report.simd_funcs = scalarize(module)?;
+    }
+    if !target.supports(Feature::SignExtension) {
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                report.sign_extensions += lower_sign_extension(body);
+            }
+        }
+    }
+    if !target.supports(Feature::SaturatingFloatToInt) {
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                report.saturating_conversions += lower_saturating_conversions(body);
+            }
+        }
+    }
+    if !target.supports(Feature::BulkMemory) {
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                report.bulk_memory_ops += lower_bulk_memory(body);
+            }
+        }
+    }
+    if !target.supports(Feature::MultiValue) {
+        report.multi_value_sigs = lower_multi_value(module)?;
+    }
+
+    let missing = module
+        .detect_features()?
+        .iter()
+        .filter(|&feature| !target.supports(feature))
+        .map(|feature| feature.name())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "Module uses features the target lacks that cannot be lowered: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(report)
+}
+
+/// What `upgrade()` raised.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpgradeReport {
+    pub saturating_conversions: usize,
+}
+
+/// Raise code in `module` into the features `target` supports, as
+/// described in the module documentation.
+pub fn upgrade(module: &mut Module, target: &TargetFeatures) -> Result<UpgradeReport> {
+    let mut report = UpgradeReport::default();
+    if target.supports(Feature::SaturatingFloatToInt) {
+        module.expand_all_funcs()?;
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                report.saturating_conversions += raise_saturating_conversions(body);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Take the instructions of `block` out, to be appended again in
+/// order with replacements.
+fn take_insts(body: &mut FunctionBody, block: Block) -> Vec<Value> {
+    std::mem::take(&mut body.blocks[block].insts)
+}
+
+/// Add an operator at the end of `block`, with the location of `loc`.
+fn add_op_at(
+    body: &mut FunctionBody,
+    block: Block,
+    loc: Value,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let tys = match ty {
+        Some(ty) => vec![ty],
+        None => vec![],
+    };
+    let value = body.add_op(block, op, args, &tys);
+    body.copy_loc(loc, value);
+    value
+}
+
+fn lower_sign_extension(body: &mut FunctionBody) -> usize {
+    let mut count = 0;
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        for inst in take_insts(body, block) {
+            let (shl, shr, ty, shift) = match &body.values[inst] {
+                ValueDef::Operator(op, ..) => match op {
+                    Operator::I32Extend8S => (Operator::I32Shl, Operator::I32ShrS, Type::I32, 24),
+                    Operator::I32Extend16S => (Operator::I32Shl, Operator::I32ShrS, Type::I32, 16),
+                    Operator::I64Extend8S => (Operator::I64Shl, Operator::I64ShrS, Type::I64, 56),
+                    Operator::I64Extend16S => (Operator::I64Shl, Operator::I64ShrS, Type::I64, 48),
+                    Operator::I64Extend32S => (Operator::I64Shl, Operator::I64ShrS, Type::I64, 32),
+                    _ => {
+                        body.append_to_block(block, inst);
+                        continue;
+                    }
+                },
+                _ => {
+                    body.append_to_block(block, inst);
+                    continue;
+                }
+            };
+            let (x, tys) = match &body.values[inst] {
+                ValueDef::Operator(_, args, tys) => (body.arg_pool[*args][0], *tys),
+                _ => unreachable!(),
+            };
+            let shift = match ty {
+                Type::I32 => Operator::I32Const { value: shift },
+                _ => Operator::I64Const {
+                    value: shift as u64,
+                },
+            };
+            let shift = add_op_at(body, block, inst, shift, &[], Some(ty));
+            let shifted = add_op_at(body, block, inst, shl, &[x, shift], Some(ty));
+            let args = body.arg_pool.from_iter([shifted, shift].iter().copied());
+            body.values[inst] = ValueDef::Operator(shr, args, tys);
+            body.append_to_block(block, inst);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// A saturating float-to-int conversion and its trapping counterpart.
+#[derive(Clone, Copy)]
+struct SatConversion {
+    sat: Operator,
+    trunc: Operator,
+    float: Type,
+    int: Type,
+    signed: bool,
+}
+
+const SAT_CONVERSIONS: [SatConversion; 8] = {
+    const fn conv(
+        sat: Operator,
+        trunc: Operator,
+        float: Type,
+        int: Type,
+        signed: bool,
+    ) -> SatConversion {
+        SatConversion {
+            sat,
+            trunc,
+            float,
+            int,
+            signed,
+        }
+    }
+    use Operator::*;
+    use Type::{F32, F64, I32, I64};
+    [
+        conv(I32TruncSatF32S, I32TruncF32S, F32, I32, true),
+        conv(I32TruncSatF32U, I32TruncF32U, F32, I32, false),
+        conv(I32TruncSatF64S, I32TruncF64S, F64, I32, true),
+        conv(I32TruncSatF64U, I32TruncF64U, F64, I32, false),
+        conv(I64TruncSatF32S, I64TruncF32S, F32, I64, true),
+        conv(I64TruncSatF32U, I64TruncF32U, F32, I64, false),
+        conv(I64TruncSatF64S, I64TruncF64S, F64, I64, true),
+        conv(I64TruncSatF64U, I64TruncF64U, F64, I64, false),
+    ]
+};
+
+impl SatConversion {
+    fn bits(&self) -> u32 {
+        match self.int {
+            Type::I32 => 32,
+            _ => 64,
+        }
+    }
+
+    /// The lower bound of inputs that convert without trapping, and
+    /// whether it is itself one.
+    fn lo(&self) -> (f64, bool) {
+        match (self.signed, self.float, self.bits()) {
+            (false, ..) => (-1.0, false),
+            // -2^31 - 1 is exact only as an f64.
+            (true, Type::F64, 32) => (-2147483649.0, false),
+            (true, _, bits) => (-(2f64.powi(bits as i32 - 1)), true),
+        }
+    }
+
+    /// The (exclusive) upper bound of inputs that convert without
+    /// trapping.
+    fn hi(&self) -> f64 {
+        match self.signed {
+            true => 2f64.powi(self.bits() as i32 - 1),
+            false => 2f64.powi(self.bits() as i32),
+        }
+    }
+
+    fn min(&self) -> u64 {
+        match self.signed {
+            true => 1 << (self.bits() - 1),
+            false => 0,
+        }
+    }
+
+    fn max(&self) -> u64 {
+        match self.signed {
+            true => (1 << (self.bits() - 1)) - 1,
+            false => u64::MAX >> (64 - self.bits()),
+        }
+    }
+
+    fn float_const(&self, value: f64) -> Operator {
+        match self.float {
+            Type::F32 => Operator::F32Const {
+                value: (value as f32).to_bits(),
+            },
+            _ => Operator::F64Const {
+                value: value.to_bits(),
+            },
+        }
+    }
+
+    fn int_const(&self, value: u64) -> Operator {
+        match self.int {
+            Type::I32 => Operator::I32Const {
+                value: value as u32,
+            },
+            _ => Operator::I64Const { value },
+        }
+    }
+
+    /// The comparisons `>=`, `>` and `<` of the float type.
+    fn cmps(&self) -> (Operator, Operator, Operator) {
+        match self.float {
+            Type::F32 => (Operator::F32Ge, Operator::F32Gt, Operator::F32Lt),
+            _ => (Operator::F64Ge, Operator::F64Gt, Operator::F64Lt),
+        }
+    }
+}
+
+fn lower_saturating_conversions(body: &mut FunctionBody) -> usize {
+    let mut count = 0;
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        for inst in take_insts(body, block) {
+            let (conv, x, tys) = match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => {
+                    match SAT_CONVERSIONS.iter().find(|conv| conv.sat == *op) {
+                        Some(conv) => (*conv, body.arg_pool[*args][0], *tys),
+                        None => {
+                            body.append_to_block(block, inst);
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    body.append_to_block(block, inst);
+                    continue;
+                }
+            };
+            let (float, int) = (Some(conv.float), Some(conv.int));
+            let (ge, gt, lt) = conv.cmps();
+            let mut op = |op, args: &[Value], ty| add_op_at(body, block, inst, op, args, ty);
+
+            // x in range ? trunc(x) : x > 0 ? max : x < 0 ? min : 0,
+            // with zero in place of x for trunc when out of range.
+            let (lo, inclusive) = conv.lo();
+            let lo = op(conv.float_const(lo), &[], float);
+            let lo_ok = op(if inclusive { ge } else { gt }, &[x, lo], Some(Type::I32));
+            let hi = op(conv.float_const(conv.hi()), &[], float);
+            let hi_ok = op(lt, &[x, hi], Some(Type::I32));
+            let in_range = op(Operator::I32And, &[lo_ok, hi_ok], Some(Type::I32));
+            let zero = op(conv.float_const(0.0), &[], float);
+            let safe = op(Operator::Select, &[x, zero, in_range], float);
+            let truncated = op(conv.trunc, &[safe], int);
+            let max = op(conv.int_const(conv.max()), &[], int);
+            let min = op(conv.int_const(conv.min()), &[], int);
+            let int_zero = op(conv.int_const(0), &[], int);
+            let neg = op(lt, &[x, zero], Some(Type::I32));
+            let low = op(Operator::Select, &[min, int_zero, neg], int);
+            let pos = op(gt, &[x, zero], Some(Type::I32));
+            let saturated = op(Operator::Select, &[max, low, pos], int);
+            let args = body
+                .arg_pool
+                .from_iter([truncated, saturated, in_range].iter().copied());
+            body.values[inst] = ValueDef::Operator(Operator::Select, args, tys);
+            body.append_to_block(block, inst);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// The operator and arguments of `value`, through aliases.
+fn op_of(body: &FunctionBody, value: Value) -> Option<(Operator, Vec<Value>)> {
+    match &body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(op, args, _) => Some((
+            *op,
+            body.arg_pool[*args]
+                .iter()
+                .map(|&arg| body.resolve_alias(arg))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Is `value` the constant `expected`?
+fn is_const(body: &FunctionBody, value: Value, expected: Operator) -> bool {
+    matches!(op_of(body, value), Some((op, args)) if args.is_empty() && op == expected)
+}
+
+/// Is `value` the comparison `x <op> <constant>`?
+fn is_cmp(body: &FunctionBody, value: Value, op: Operator, x: Value, rhs: Operator) -> bool {
+    match op_of(body, value) {
+        Some((cmp, args)) => cmp == op && args[0] == x && is_const(body, args[1], rhs),
+        None => false,
+    }
+}
+
+/// If `value` is the guarded conversion `lower_saturating_conversions`
+/// emits, its conversion and input.
+fn match_guarded_conversion(body: &FunctionBody, value: Value) -> Option<(SatConversion, Value)> {
+    let (op, args) = op_of(body, value)?;
+    if op != Operator::Select {
+        return None;
+    }
+    let (truncated, saturated, in_range) = (args[0], args[1], args[2]);
+    let (trunc, trunc_args) = op_of(body, truncated)?;
+    let conv = *SAT_CONVERSIONS.iter().find(|conv| conv.trunc == trunc)?;
+    let (ge, gt, lt) = conv.cmps();
+    let zero = conv.float_const(0.0);
+
+    let (select, safe_args) = op_of(body, trunc_args[0])?;
+    let x = safe_args[0];
+    if select != Operator::Select || safe_args[2] != in_range || !is_const(body, safe_args[1], zero)
+    {
+        return None;
+    }
+
+    let (and, mut checks) = op_of(body, in_range)?;
+    if and != Operator::I32And {
+        return None;
+    }
+    let (lo, inclusive) = conv.lo();
+    let lo_ok = |value| {
+        is_cmp(
+            body,
+            value,
+            if inclusive { ge } else { gt },
+            x,
+            conv.float_const(lo),
+        )
+    };
+    if !lo_ok(checks[0]) {
+        checks.swap(0, 1);
+    }
+    if !lo_ok(checks[0]) || !is_cmp(body, checks[1], lt, x, conv.float_const(conv.hi())) {
+        return None;
+    }
+
+    let (select, sat_args) = op_of(body, saturated)?;
+    if select != Operator::Select
+        || !is_const(body, sat_args[0], conv.int_const(conv.max()))
+        || !is_cmp(body, sat_args[2], gt, x, zero)
+    {
+        return None;
+    }
+    // With an unsigned conversion, `x < 0 ? 0 : 0` may be folded.
+    let low_ok = match op_of(body, sat_args[1])? {
+        (Operator::Select, low_args) => {
+            is_const(body, low_args[0], conv.int_const(conv.min()))
+                && is_const(body, low_args[1], conv.int_const(0))
+                && is_cmp(body, low_args[2], lt, x, zero)
+        }
+        (op, low_args) => conv.min() == 0 && low_args.is_empty() && op == conv.int_const(0),
+    };
+    match low_ok {
+        true => Some((conv, x)),
+        false => None,
+    }
+}
+
+fn raise_saturating_conversions(body: &mut FunctionBody) -> usize {
+    let mut count = 0;
+    let mut guards = HashSet::new();
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        for i in 0..body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            if let Some((conv, x)) = match_guarded_conversion(body, inst) {
+                // Everything the select computes from `x`.
+                let mut stack = op_of(body, inst).unwrap().1;
+                while let Some(value) = stack.pop() {
+                    if value != x && guards.insert(value) {
+                        stack.extend(op_of(body, value).map_or(vec![], |(_, args)| args));
+                    }
+                }
+                let tys = body.single_type_list(conv.int);
+                let args = body.arg_pool.single(x);
+                body.values[inst] = ValueDef::Operator(conv.sat, args, tys);
+                count += 1;
+            }
+        }
+    }
+
+    // Remove the guards left unused, including the trapping
+    // conversions, which the backend would otherwise still emit.
+    loop {
+        let mut uses: HashMap<Value, usize> = HashMap::new();
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                body.values[inst].visit_uses(&body.arg_pool, |arg| {
+                    *uses.entry(body.resolve_alias(arg)).or_default() += 1;
+                });
+            }
+            block.terminator.visit_uses(|value| {
+                *uses.entry(body.resolve_alias(value)).or_default() += 1;
+            });
+        }
+        let dead = guards
+            .iter()
+            .copied()
+            .filter(|value| !uses.contains_key(value))
+            .collect::<HashSet<_>>();
+        if dead.is_empty() {
+            break;
+        }
+        for &value in &dead {
+            let block = body.value_blocks[value];
+            body.blocks[block].insts.retain(|&inst| inst != value);
+            body.values[value] = ValueDef::None;
+            guards.remove(&value);
+        }
+    }
+    count
+}
+
+/// The first `memory.copy` or `memory.fill` in `body`.
+fn find_bulk_op(body: &FunctionBody) -> Option<(Block, usize)> {
+    body.blocks.entries().find_map(|(block, def)| {
+        def.insts
+            .iter()
+            .position(|&inst| {
+                matches!(
+                    body.values[inst],
+                    ValueDef::Operator(
+                        Operator::MemoryCopy { .. } | Operator::MemoryFill { .. },
+                        ..
+                    )
+                )
+            })
+            .map(|index| (block, index))
+    })
+}
+
+fn lower_bulk_memory(body: &mut FunctionBody) -> usize {
+    let mut count = 0;
+    while let Some((block, index)) = find_bulk_op(body) {
+        let inst = body.blocks[block].insts[index];
+        let (op, args) = match &body.values[inst] {
+            ValueDef::Operator(op, args, _) => (*op, body.arg_pool[*args].to_vec()),
+            _ => unreachable!(),
+        };
+
+        // Split the block after the operator, which has no results.
+        let origin = BlockOrigin::SyntheticFor("downgrade");
+        let cont = body.add_block_with_origin(origin.clone());
+        let rest = body.blocks[block].insts.split_off(index + 1);
+        body.blocks[block].insts.pop();
+        for &value in &rest {
+            body.value_blocks[value] = cont;
+        }
+        body.blocks[cont].insts = rest;
+        body.blocks[cont].terminator = std::mem::take(&mut body.blocks[block].terminator);
+        body.blocks[cont].branch_hint = body.blocks[block].branch_hint.take();
+        body.values[inst] = ValueDef::None;
+
+        let (dst, src_or_val, len) = (args[0], args[1], args[2]);
+        let (dst_mem, src_mem) = match op {
+            Operator::MemoryCopy { dst_mem, src_mem } => (dst_mem, Some(src_mem)),
+            Operator::MemoryFill { mem } => (mem, None),
+            _ => unreachable!(),
+        };
+        let op = |body: &mut FunctionBody, block, op, args: &[Value], ty| {
+            add_op_at(body, block, inst, op, args, ty)
+        };
+
+        // Trap unless `[addr, addr + len)` is within `mem`, computing
+        // in 64 bits to avoid overflow.
+        let len64 = op(
+            body,
+            block,
+            Operator::I64ExtendI32U,
+            &[len],
+            Some(Type::I64),
+        );
+        let page_shift = op(
+            body,
+            block,
+            Operator::I64Const { value: 16 },
+            &[],
+            Some(Type::I64),
+        );
+        let mut out_of_bounds = vec![];
+        for (addr, mem) in std::iter::once((dst, dst_mem)).chain(src_mem.map(|m| (src_or_val, m))) {
+            let pages = op(
+                body,
+                block,
+                Operator::MemorySize { mem },
+                &[],
+                Some(Type::I32),
+            );
+            let pages = op(
+                body,
+                block,
+                Operator::I64ExtendI32U,
+                &[pages],
+                Some(Type::I64),
+            );
+            let size = op(
+                body,
+                block,
+                Operator::I64Shl,
+                &[pages, page_shift],
+                Some(Type::I64),
+            );
+            let addr = op(
+                body,
+                block,
+                Operator::I64ExtendI32U,
+                &[addr],
+                Some(Type::I64),
+            );
+            let end = op(
+                body,
+                block,
+                Operator::I64Add,
+                &[addr, len64],
+                Some(Type::I64),
+            );
+            out_of_bounds.push(op(
+                body,
+                block,
+                Operator::I64GtU,
+                &[end, size],
+                Some(Type::I32),
+            ));
+        }
+        let out_of_bounds = match out_of_bounds[..] {
+            [a] => a,
+            [a, b] => op(body, block, Operator::I32Or, &[a, b], Some(Type::I32)),
+            _ => unreachable!(),
+        };
+        let trap = body.add_block_with_origin(origin.clone());
+        body.blocks[trap].terminator = Terminator::Unreachable;
+        let in_bounds = body.add_block_with_origin(origin.clone());
+        body.blocks[block].terminator = Terminator::CondBr {
+            cond: out_of_bounds,
+            if_true: BlockTarget {
+                block: trap,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: in_bounds,
+                args: vec![],
+            },
+        };
+
+        // Byte loops, each with a counter as a blockparam of its
+        // header.
+        let load = |mem| Operator::I32Load8U {
+            memory: MemoryArg::new(mem, 0, 0).unwrap(),
+        };
+        let store = Operator::I32Store8 {
+            memory: MemoryArg::new(dst_mem, 0, 0).unwrap(),
+        };
+        let br = |block, args: Vec<Value>| Terminator::Br {
+            target: BlockTarget { block, args },
+        };
+        let zero = op(
+            body,
+            in_bounds,
+            Operator::I32Const { value: 0 },
+            &[],
+            Some(Type::I32),
+        );
+        let one = op(
+            body,
+            in_bounds,
+            Operator::I32Const { value: 1 },
+            &[],
+            Some(Type::I32),
+        );
+        let forward = body.add_block_with_origin(origin.clone());
+        let i = body.add_blockparam(forward, Type::I32);
+        let forward_body = body.add_block_with_origin(origin.clone());
+        let more = op(body, forward, Operator::I32LtU, &[i, len], Some(Type::I32));
+        body.blocks[forward].terminator = Terminator::CondBr {
+            cond: more,
+            if_true: BlockTarget {
+                block: forward_body,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: cont,
+                args: vec![],
+            },
+        };
+        let to = op(
+            body,
+            forward_body,
+            Operator::I32Add,
+            &[dst, i],
+            Some(Type::I32),
+        );
+        let byte = match src_mem {
+            Some(src_mem) => {
+                let from = op(
+                    body,
+                    forward_body,
+                    Operator::I32Add,
+                    &[src_or_val, i],
+                    Some(Type::I32),
+                );
+                op(body, forward_body, load(src_mem), &[from], Some(Type::I32))
+            }
+            None => src_or_val,
+        };
+        op(body, forward_body, store, &[to, byte], None);
+        let next = op(
+            body,
+            forward_body,
+            Operator::I32Add,
+            &[i, one],
+            Some(Type::I32),
+        );
+        body.blocks[forward_body].terminator = br(forward, vec![next]);
+
+        match src_mem {
+            // A copy to a higher address runs backward, from the end.
+            Some(src_mem) if dst_mem == src_mem => {
+                let backward = body.add_block_with_origin(origin.clone());
+                let i = body.add_blockparam(backward, Type::I32);
+                let backward_body = body.add_block_with_origin(origin.clone());
+                body.blocks[backward].terminator = Terminator::CondBr {
+                    cond: i,
+                    if_true: BlockTarget {
+                        block: backward_body,
+                        args: vec![],
+                    },
+                    if_false: BlockTarget {
+                        block: cont,
+                        args: vec![],
+                    },
+                };
+                let prev = op(
+                    body,
+                    backward_body,
+                    Operator::I32Sub,
+                    &[i, one],
+                    Some(Type::I32),
+                );
+                let from = op(
+                    body,
+                    backward_body,
+                    Operator::I32Add,
+                    &[src_or_val, prev],
+                    Some(Type::I32),
+                );
+                let byte = op(body, backward_body, load(src_mem), &[from], Some(Type::I32));
+                let to = op(
+                    body,
+                    backward_body,
+                    Operator::I32Add,
+                    &[dst, prev],
+                    Some(Type::I32),
+                );
+                op(body, backward_body, store, &[to, byte], None);
+                body.blocks[backward_body].terminator = br(backward, vec![prev]);
+
+                let up = op(
+                    body,
+                    in_bounds,
+                    Operator::I32GtU,
+                    &[dst, src_or_val],
+                    Some(Type::I32),
+                );
+                body.blocks[in_bounds].terminator = Terminator::CondBr {
+                    cond: up,
+                    if_true: BlockTarget {
+                        block: backward,
+                        args: vec![len],
+                    },
+                    if_false: BlockTarget {
+                        block: forward,
+                        args: vec![zero],
+                    },
+                };
+            }
+            _ => body.blocks[in_bounds].terminator = br(forward, vec![zero]),
+        }
+        body.recompute_edges();
+        count += 1;
+    }
+    count
+}
+
+fn lower_multi_value(module: &mut Module) -> Result<usize> {
+    let multi = module
+        .signatures
+        .entries()
+        .filter(|(_, data)| data.returns.len() > 1)
+        .map(|(sig, _)| sig)
+        .collect::<HashSet<Signature>>();
+    if multi.is_empty() {
+        return Ok(0);
+    }
+    for import in &module.imports {
+        if let ImportKind::Func(func) = import.kind {
+            if multi.contains(&module.funcs[func].sig()) {
+                bail!(
+                    "Cannot lower the multi-value results of import {}.{}",
+                    import.module,
+                    import.name
+                );
+            }
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Func(func) = export.kind {
+            if multi.contains(&module.funcs[func].sig()) {
+                bail!(
+                    "Cannot lower the multi-value results of export {}",
+                    export.name
+                );
+            }
+        }
+    }
+
+    // One scratch global per extra result position and type.
+    let mut scratch: BTreeMap<(usize, Type), Global> = BTreeMap::new();
+    for &sig in &multi {
+        for (i, &ty) in module.signatures[sig].returns.iter().enumerate().skip(1) {
+            if !matches!(
+                ty,
+                Type::I32 | Type::I64 | Type::F32 | Type::F64 | Type::TypedFuncRef(true, _)
+            ) {
+                bail!("Cannot lower a multi-value result of type {}", ty);
+            }
+            let globals = &mut module.globals;
+            scratch.entry((i, ty)).or_insert_with(|| {
+                globals.push(GlobalData {
+                    ty,
+                    value: Some(0),
+                    mutable: true,
+                    init: None,
+                })
+            });
+        }
+    }
+
+    for decl in module.funcs.values_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+
+        // Returns store the extra results.
+        if body.rets.len() > 1 {
+            let rets = body.rets.clone();
+            for block in body.blocks.iter().collect::<Vec<_>>() {
+                let values = match &body.blocks[block].terminator {
+                    Terminator::Return { values } => values.clone(),
+                    _ => continue,
+                };
+                for (i, &value) in values.iter().enumerate().skip(1) {
+                    let global_index = scratch[&(i, rets[i])];
+                    body.add_op(block, Operator::GlobalSet { global_index }, &[value], &[]);
+                }
+                body.blocks[block].terminator = Terminator::Return {
+                    values: vec![values[0]],
+                };
+            }
+            body.rets.truncate(1);
+        }
+
+        // Calls read them right after.
+        let mut picks: HashMap<Value, Vec<(Value, usize, Type)>> = HashMap::new();
+        for (value, def) in body.values.entries() {
+            if let &ValueDef::PickOutput(call, i, ty) = def {
+                picks.entry(call).or_default().push((value, i as usize, ty));
+            }
+        }
+        let is_multi_call = |body: &FunctionBody, value: Value| match &body.values[value] {
+            ValueDef::Operator(
+                Operator::Call { .. } | Operator::CallIndirect { .. } | Operator::CallRef { .. },
+                _,
+                tys,
+            ) => tys.len() > 1,
+            _ => false,
+        };
+        let moved = picks
+            .iter()
+            .filter(|(&call, _)| is_multi_call(body, call))
+            .flat_map(|(_, picks)| picks.iter().map(|&(pick, ..)| pick))
+            .collect::<HashSet<_>>();
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            for inst in take_insts(body, block) {
+                if moved.contains(&inst) {
+                    continue;
+                }
+                if !is_multi_call(body, inst) {
+                    body.append_to_block(block, inst);
+                    continue;
+                }
+                if let ValueDef::Operator(_, _, tys) = &body.values[inst] {
+                    let first = body.type_pool[*tys][0];
+                    let single = body.single_type_list(first);
+                    if let ValueDef::Operator(_, _, tys) = &mut body.values[inst] {
+                        *tys = single;
+                    }
+                }
+                body.append_to_block(block, inst);
+                for &(pick, i, ty) in picks.get(&inst).map_or(&[][..], |picks| &picks[..]) {
+                    if i == 0 {
+                        body.values[pick] = ValueDef::Alias(inst);
+                        continue;
+                    }
+                    let global_index = scratch[&(i, ty)];
+                    body.values[pick] = ValueDef::Operator(
+                        Operator::GlobalGet { global_index },
+                        Default::default(),
+                        body.single_type_list(ty),
+                    );
+                    body.append_to_block(block, pick);
+                }
+            }
+        }
+    }
+
+    for &sig in &multi {
+        module.signatures[sig].returns.truncate(1);
+    }
+    Ok(multi.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+    use crate::{ConstVal, FrontendOptions, InterpContext};
+
+    #[test]
+    fn lowered_module_runs_the_same_on_mvp() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory 1)
+                (data (i32.const 0) "abcdefgh")
+                (func $pair (param i32) (result i32 i64)
+                  local.get 0
+                  local.get 0
+                  i64.extend_i32_u)
+                (func (export "run") (param i32) (result i32)
+                  ;; memmove "abcdefgh" up by 2, then fill 2 bytes.
+                  i32.const 2
+                  i32.const 0
+                  i32.const 8
+                  memory.copy
+                  i32.const 0
+                  local.get 0
+                  i32.const 2
+                  memory.fill
+                  ;; Sign-extend the byte at 9 ('g' + the fill pattern).
+                  i32.const 9
+                  i32.load8_u
+                  local.get 0
+                  i32.add
+                  i32.extend8_s
+                  call $pair
+                  i32.wrap_i64
+                  i32.add)
+                (func (export "oob") (result i32)
+                  i32.const 65530
+                  i32.const 0
+                  i32.const 8
+                  memory.copy
+                  i32.const 0
+                  i32.load8_u)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let run = |module: &Module, name: &str, args: &[ConstVal]| {
+            let func = module
+                .exports
+                .iter()
+                .find_map(|export| match export.kind {
+                    ExportKind::Func(func) if export.name == name => Some(func),
+                    _ => None,
+                })
+                .unwrap();
+            let mut ctx = InterpContext::new(module).unwrap();
+            let result = ctx.call(module, func, args).ok().ok().map(|r| r.to_vec());
+            let memory = ctx.memories[crate::Memory::new(0)].data[..12].to_vec();
+            (result, memory)
+        };
+        let before = run(&module, "run", &[ConstVal::I32(0x7e)]);
+        let oob_before = run(&module, "oob", &[]);
+        assert_eq!(&before.1[..10], b"~~abcdefgh");
+        assert!(oob_before.0.is_none());
+
+        let mvp = FeatureSet::default().wasm_features();
+        let options = crate::BackendOptions {
+            target_features: Some(TargetFeatures::mvp()),
+            ..crate::BackendOptions::default()
+        };
+        let bytes = module.to_wasm_bytes_with_options(&options, None).unwrap();
+        wasmparser::Validator::new_with_features(mvp)
+            .validate_all(&bytes)
+            .unwrap();
+        assert!(wasmparser::Validator::new_with_features(mvp)
+            .validate_all(&module.to_wasm_bytes().unwrap())
+            .is_err());
+
+        let report = downgrade(&mut module, &TargetFeatures::mvp()).unwrap();
+        assert_eq!(
+            report,
+            DowngradeReport {
+                relaxed_simd: 0,
+                atomics: 0,
+                simd_funcs: 0,
+                sign_extensions: 1,
+                saturating_conversions: 0,
+                bulk_memory_ops: 3,
+                multi_value_sigs: 1,
+            }
+        );
+        assert!(module.detect_features().unwrap().is_empty());
+        assert_eq!(run(&module, "run", &[ConstVal::I32(0x7e)]), before);
+        // The whole copy traps before writing anything.
+        assert_eq!(run(&module, "oob", &[]), oob_before);
+    }
+
+    #[test]
+    fn saturating_conversions_round_trip() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (export "i32_f32_s") (param f32) (result i32) local.get 0 i32.trunc_sat_f32_s)
+                (func (export "i32_f32_u") (param f32) (result i32) local.get 0 i32.trunc_sat_f32_u)
+                (func (export "i32_f64_s") (param f64) (result i32) local.get 0 i32.trunc_sat_f64_s)
+                (func (export "i32_f64_u") (param f64) (result i32) local.get 0 i32.trunc_sat_f64_u)
+                (func (export "i64_f32_s") (param f32) (result i64) local.get 0 i64.trunc_sat_f32_s)
+                (func (export "i64_f32_u") (param f32) (result i64) local.get 0 i64.trunc_sat_f32_u)
+                (func (export "i64_f64_s") (param f64) (result i64) local.get 0 i64.trunc_sat_f64_s)
+                (func (export "i64_f64_u") (param f64) (result i64) local.get 0 i64.trunc_sat_f64_u))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let inputs = [
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            0.0,
+            -0.9,
+            -1.0,
+            3.7,
+            -3.7,
+            2147483647.9,
+            2147483648.0,
+            -2147483648.9,
+            -2147483649.0,
+            4294967295.9,
+            4294967296.0,
+            9.2e18,
+            -9.3e18,
+            1.8e19,
+            1.9e19,
+        ];
+        let results = |module: &Module| {
+            let mut results = vec![];
+            for export in &module.exports {
+                let func = match export.kind {
+                    ExportKind::Func(func) => func,
+                    _ => unreachable!(),
+                };
+                for &input in &inputs {
+                    let arg = match export.name.contains("f32") {
+                        true => ConstVal::F32((input as f32).to_bits()),
+                        false => ConstVal::F64(input.to_bits()),
+                    };
+                    let mut ctx = InterpContext::new(module).unwrap();
+                    results.push(ctx.call(module, func, &[arg]).ok().unwrap().to_vec());
+                }
+            }
+            results
+        };
+        let expected = results(&module);
+
+        let report = downgrade(&mut module, &TargetFeatures::mvp()).unwrap();
+        assert_eq!(report.saturating_conversions, 8);
+        assert!(module.detect_features().unwrap().is_empty());
+        assert_eq!(results(&module), expected);
+
+        module.optimize(&crate::OptOptions::default());
+        let target = TargetFeatures::new([Feature::SaturatingFloatToInt]);
+        let report = upgrade(&mut module, &target).unwrap();
+        assert_eq!(report.saturating_conversions, 8);
+        module.optimize(&crate::OptOptions::default());
+        assert_eq!(results(&module), expected);
+        for decl in module.funcs.values() {
+            let body = decl.body().unwrap();
+            let ops = body
+                .blocks
+                .values()
+                .map(|block| block.insts.len())
+                .sum::<usize>();
+            assert_eq!(ops, 1, "{}", body.display("", None));
+        }
+    }
+}
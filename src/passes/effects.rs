@@ -0,0 +1,357 @@
+//! Per-function effect summaries.
+//!
+//! A `call` is opaque to the intraprocedural passes: its operator
+//! effects are `All`, so it is never deduplicated or removed. An
+//! `EffectSummary` describes what a callee can actually do, computed
+//! bottom-up over the direct call graph, so that a call to a function
+//! that only computes a value from its arguments can be treated like
+//! any other pure operator.
+
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::interp::ConstVal;
+use crate::ir::{FuncDecl, Module, Terminator, ValueDef};
+use crate::passes::intrinsics::Intrinsic;
+use crate::{Func, Operator, SideEffect};
+use std::collections::BTreeMap;
+
+/// What a function may do when called, including everything its
+/// callees may do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EffectSummary {
+    /// May read a memory.
+    pub reads_mem: bool,
+    /// May write or grow a memory.
+    pub writes_mem: bool,
+    /// May read a global.
+    pub reads_globals: bool,
+    /// May write a global.
+    pub writes_globals: bool,
+    /// May read a table.
+    pub reads_tables: bool,
+    /// May write or grow a table.
+    pub writes_tables: bool,
+    /// May trap.
+    pub may_trap: bool,
+    /// May not return at all: contains a loop or is recursive.
+    pub may_diverge: bool,
+    /// Makes an indirect call (`call_indirect` or `call_ref`).
+    pub calls_indirect: bool,
+}
+
+impl EffectSummary {
+    /// The summary of a function about which nothing is known, e.g.
+    /// an import.
+    pub fn unknown() -> EffectSummary {
+        EffectSummary {
+            reads_mem: true,
+            writes_mem: true,
+            reads_globals: true,
+            writes_globals: true,
+            reads_tables: true,
+            writes_tables: true,
+            may_trap: true,
+            may_diverge: true,
+            calls_indirect: true,
+        }
+    }
+
+    /// Does the function have no observable effect other than its
+    /// return values? A call to a pure function may be deduplicated,
+    /// removed if unused, or moved freely.
+    pub fn is_pure(&self) -> bool {
+        *self == EffectSummary::default()
+    }
+
+    /// Is the only effect of the function a possible trap?
+    pub fn is_pure_except_trap(&self) -> bool {
+        EffectSummary {
+            may_trap: false,
+            ..*self
+        }
+        .is_pure()
+    }
+
+    /// Does the function write no state (memory, globals, or tables)?
+    pub fn is_read_only(&self) -> bool {
+        !self.writes_mem && !self.writes_globals && !self.writes_tables && !self.calls_indirect
+    }
+
+    /// Add the effects in `other` to this summary.
+    pub fn union(&mut self, other: &EffectSummary) {
+        self.reads_mem |= other.reads_mem;
+        self.writes_mem |= other.writes_mem;
+        self.reads_globals |= other.reads_globals;
+        self.writes_globals |= other.writes_globals;
+        self.reads_tables |= other.reads_tables;
+        self.writes_tables |= other.writes_tables;
+        self.may_trap |= other.may_trap;
+        self.may_diverge |= other.may_diverge;
+        self.calls_indirect |= other.calls_indirect;
+    }
+
+    /// Add the effects of a single non-call operator.
+    fn add_op(&mut self, op: &Operator) {
+        for effect in op.effects() {
+            match effect {
+                SideEffect::Trap => self.may_trap = true,
+                SideEffect::ReadMem => self.reads_mem = true,
+                SideEffect::WriteMem => self.writes_mem = true,
+                SideEffect::ReadGlobal => self.reads_globals = true,
+                SideEffect::WriteGlobal => self.writes_globals = true,
+                SideEffect::ReadTable => self.reads_tables = true,
+                SideEffect::WriteTable => self.writes_tables = true,
+                // Locals are not visible outside the function.
+                SideEffect::ReadLocal | SideEffect::WriteLocal => {}
+                SideEffect::All => self.union(&EffectSummary::unknown()),
+            }
+        }
+    }
+}
+
+/// Effect summaries for every function in a module.
+#[derive(Clone, Debug, Default)]
+pub struct EffectSummaries {
+    summaries: Vec<EffectSummary>,
+    /// The results of intrinsics that return constants.
+    constants: BTreeMap<Func, Vec<ConstVal>>,
+}
+
+impl EffectSummaries {
+    /// Compute summaries for all functions in `module`. Imports and
+    /// bodies that are not expanded to IR are summarized as
+    /// `EffectSummary::unknown()`, except for intrinsics, which are
+    /// summarized by their declared semantics. Functions are visited in
+    /// bottom-up order over the strongly-connected components of the
+    /// direct call graph; all members of a recursive cycle share one
+    /// summary.
+    pub fn compute(module: &Module) -> EffectSummaries {
+        let n = module.funcs.len();
+        let mut local = vec![EffectSummary::unknown(); n];
+        let mut callees: Vec<Vec<usize>> = vec![vec![]; n];
+        for (func, decl) in module.funcs.entries() {
+            let body = match decl {
+                FuncDecl::Body(_, _, body) => body,
+                FuncDecl::Import(..) => {
+                    if let Some(intrinsic) = module.intrinsics.get(&func) {
+                        local[func.index()] = intrinsic.summary();
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let summary = &mut local[func.index()];
+            *summary = EffectSummary::default();
+            let cfg = CFGInfo::new(body);
+            for &block in cfg.rpo.values() {
+                let pos = cfg.rpo_pos[block];
+                body.blocks[block].terminator.visit_targets(|target| {
+                    if cfg.rpo_pos[target.block] <= pos {
+                        summary.may_diverge = true;
+                    }
+                });
+                if let Terminator::Unreachable = body.blocks[block].terminator {
+                    summary.may_trap = true;
+                }
+                for &inst in &body.blocks[block].insts {
+                    let op = match &body.values[inst] {
+                        ValueDef::Operator(op, ..) => op,
+                        _ => continue,
+                    };
+                    match op {
+                        &Operator::Call { function_index } => {
+                            callees[func.index()].push(function_index.index());
+                        }
+                        Operator::CallIndirect { .. } | Operator::CallRef { .. } => {
+                            summary.union(&EffectSummary::unknown());
+                        }
+                        op => summary.add_op(op),
+                    }
+                }
+            }
+        }
+
+        let mut summaries = local.clone();
+        for scc in sccs(&callees) {
+            let mut summary = EffectSummary::default();
+            for &f in &scc {
+                summary.union(&local[f]);
+                for &callee in &callees[f] {
+                    if scc.contains(&callee) {
+                        summary.may_diverge = true;
+                    } else {
+                        let callee = summaries
+                            .get(callee)
+                            .copied()
+                            .unwrap_or_else(EffectSummary::unknown);
+                        summary.union(&callee);
+                    }
+                }
+            }
+            for &f in &scc {
+                summaries[f] = summary;
+            }
+        }
+        let constants = module
+            .intrinsics
+            .iter()
+            .filter_map(|(&func, intrinsic)| match intrinsic {
+                Intrinsic::Constant(values) => Some((func, values.clone())),
+                _ => None,
+            })
+            .collect();
+        EffectSummaries {
+            summaries,
+            constants,
+        }
+    }
+
+    /// The results of `func`, if it is an intrinsic that always
+    /// returns the same constants.
+    pub fn constant_results(&self, func: Func) -> Option<&[ConstVal]> {
+        self.constants.get(&func).map(|values| &values[..])
+    }
+
+    /// The summary of `func`. Functions outside the module are
+    /// `EffectSummary::unknown()`.
+    pub fn get(&self, func: Func) -> EffectSummary {
+        func.is_valid()
+            .then(|| self.summaries.get(func.index()).copied())
+            .flatten()
+            .unwrap_or_else(EffectSummary::unknown)
+    }
+}
+
+/// Tarjan's algorithm, iteratively. Returns the strongly-connected
+/// components of the graph with the given adjacency lists, each
+/// component after all components it has edges to.
+pub(crate) fn sccs(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let n = edges.len();
+    let mut index = vec![usize::MAX; n];
+    let mut lowlink = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = vec![];
+    let mut next_index = 0;
+    let mut result = vec![];
+    for root in 0..n {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        // Each frame is a node and the position of the next edge to
+        // visit.
+        let mut frames = vec![(root, 0)];
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        while let Some(&mut (node, ref mut edge)) = frames.last_mut() {
+            if let Some(&succ) = edges[node].get(*edge) {
+                *edge += 1;
+                if succ >= n {
+                    continue;
+                }
+                if index[succ] == usize::MAX {
+                    index[succ] = next_index;
+                    lowlink[succ] = next_index;
+                    next_index += 1;
+                    stack.push(succ);
+                    on_stack[succ] = true;
+                    frames.push((succ, 0));
+                } else if on_stack[succ] {
+                    lowlink[node] = lowlink[node].min(index[succ]);
+                }
+                continue;
+            }
+            frames.pop();
+            if let Some(&(parent, _)) = frames.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[node]);
+            }
+            if lowlink[node] == index[node] {
+                let mut scc = vec![];
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack[member] = false;
+                    scc.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                result.push(scc);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, SignatureData, Type};
+    use crate::OptOptions;
+    use std::sync::Arc;
+
+    #[test]
+    fn pure_calls_are_deduplicated() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let f = module.funcs.push(FuncDecl::None);
+        let g = module.funcs.push(FuncDecl::None);
+        let h = module.funcs.push(FuncDecl::None);
+
+        // f(x) = x + 1: pure.
+        let mut body = FunctionBody::new(&module, sig);
+        let x = body.blocks[body.entry].params[0].1;
+        let one = body.add_op(
+            body.entry,
+            Operator::I32Const { value: 1 },
+            &[],
+            &[Type::I32],
+        );
+        let sum = body.add_op(body.entry, Operator::I32Add, &[x, one], &[Type::I32]);
+        body.set_terminator(body.entry, Terminator::Return { values: vec![sum] });
+        module.funcs[f] = FuncDecl::Body(sig, "f".to_owned(), body.into());
+
+        // g(x) = f(x) + f(x), and calls itself: recursive.
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let call = Operator::Call { function_index: f };
+        let a = body.add_op(entry, call, &[x], &[Type::I32]);
+        let b = body.add_op(entry, call, &[x], &[Type::I32]);
+        let sum = body.add_op(entry, Operator::I32Add, &[a, b], &[Type::I32]);
+        let rec = Operator::Call { function_index: g };
+        let r = body.add_op(entry, rec, &[sum], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![r] });
+        module.funcs[g] = FuncDecl::Body(sig, "g".to_owned(), body.into());
+
+        // h(x) = f(x): pure through the call.
+        let mut body = FunctionBody::new(&module, sig);
+        let x = body.blocks[body.entry].params[0].1;
+        let a = body.add_op(body.entry, call, &[x], &[Type::I32]);
+        body.set_terminator(body.entry, Terminator::Return { values: vec![a] });
+        module.funcs[h] = FuncDecl::Body(sig, "h".to_owned(), body.into());
+
+        assert!(module.effect_summary(f).is_pure());
+        assert!(module.effect_summary(g).may_diverge);
+        assert!(!module.effect_summary(g).writes_mem);
+        assert!(module.effect_summary(h).is_pure());
+        assert!(!module.effect_summary(Func::invalid()).is_pure());
+
+        let opts = OptOptions {
+            effects: Some(Arc::new(module.effect_summaries())),
+            ..OptOptions::default()
+        };
+        let mut body = module.funcs[g].body().unwrap().clone();
+        body.optimize(&opts);
+        let calls = body.blocks[body.entry]
+            .insts
+            .iter()
+            .filter(|&&inst| matches!(body.values[inst], ValueDef::Operator(op, ..) if op == call))
+            .count();
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,251 @@
+//! Extraction of one function into a standalone module.
+//!
+//! A backend bug found in a large module is much easier to share and
+//! debug once the module is cut down to the function that shows it.
+//! `extract_func()` keeps a function and everything it transitively
+//! calls or references (through `call`, `ref.func`, and the elements
+//! of tables it uses), plus the globals and tables those functions
+//! use, and renumbers them densely. All memories and their data
+//! segments are kept as they are.
+//!
+//! The result has no imports, so that it can be instantiated on its
+//! own: imported functions become bodies that trap, and imported
+//! globals, tables, and memories become definitions (globals start at
+//! zero). The root function is its only export, and there is no start
+//! function.
+
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{Export, ExportKind, FuncDecl, FunctionBody, Module, Terminator, ValueDef};
+use crate::passes::remap::EntityMap;
+use crate::{Func, Global, Operator, Table};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The functions, globals, and tables a set of functions uses.
+#[derive(Default)]
+struct Uses {
+    funcs: BTreeSet<Func>,
+    globals: BTreeSet<Global>,
+    tables: BTreeSet<Table>,
+}
+
+/// Extract `root` and its dependencies from `module` into a new
+/// module, as described in the module documentation. The root is
+/// exported under its name, or as `func<N>` if it has none.
+pub fn extract_func(module: &Module, root: Func) -> Result<Module<'static>> {
+    match module.funcs.get(root) {
+        Some(FuncDecl::Import(..)) => bail!("Cannot extract imported function {}", root),
+        Some(FuncDecl::None) | None => bail!("No function {} to extract", root),
+        _ => {}
+    }
+
+    // Find the functions, globals, and tables the root needs.
+    let mut uses = Uses::default();
+    let mut bodies = BTreeMap::new();
+    let mut worklist = vec![root];
+    uses.funcs.insert(root);
+    while let Some(func) = worklist.pop() {
+        if let FuncDecl::Import(..) = &module.funcs[func] {
+            continue;
+        }
+        let body = module.clone_and_expand_body(func)?;
+        let (mut new_funcs, mut new_tables) = (vec![], vec![]);
+        for value in body.values.values() {
+            let op = match value {
+                ValueDef::Operator(op, ..) => op,
+                _ => continue,
+            };
+            match op {
+                Operator::Call { function_index }
+                | Operator::RefFunc {
+                    func_index: function_index,
+                } => new_funcs.push(*function_index),
+                Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                    uses.globals.insert(*global_index);
+                }
+                Operator::CallIndirect { table_index, .. }
+                | Operator::TableGet { table_index }
+                | Operator::TableSet { table_index }
+                | Operator::TableGrow { table_index }
+                | Operator::TableSize { table_index } => new_tables.push(*table_index),
+                _ => {}
+            }
+        }
+        for table in new_tables {
+            if uses.tables.insert(table) {
+                let elements = module.tables[table].func_elements.iter();
+                new_funcs.extend(elements.flatten().filter(|f| f.is_valid()));
+            }
+        }
+        for func in new_funcs {
+            if uses.funcs.insert(func) {
+                worklist.push(func);
+            }
+        }
+        bodies.insert(func, body);
+    }
+    // Globals may be initialized from other globals.
+    let mut globals = uses.globals.iter().copied().collect::<Vec<_>>();
+    while let Some(global) = globals.pop() {
+        if let Some(init) = &module.globals[global].init {
+            init.visit_globals(&mut |g| {
+                if uses.globals.insert(g) {
+                    globals.push(g);
+                }
+            });
+        }
+    }
+
+    let mut map = EntityMap::default();
+    renumber(&uses.funcs, &mut map.funcs);
+    renumber(&uses.globals, &mut map.globals);
+    renumber(&uses.tables, &mut map.tables);
+    for memory in module.memories.iter() {
+        map.memories[memory] = memory;
+    }
+
+    let mut out = Module::empty();
+    out.signatures = module.signatures.clone();
+    out.memories = module.memories.clone();
+    out.debug = module.debug.clone();
+    for &global in &uses.globals {
+        let mut data = module.globals[global].clone();
+        data.value = data.value.or(Some(0));
+        if let Some(init) = &mut data.init {
+            map.init(init);
+        }
+        out.globals.push(data);
+    }
+    for &table in &uses.tables {
+        let mut data = module.tables[table].clone();
+        map.table(&mut data);
+        out.tables.push(data);
+    }
+    for &func in &uses.funcs {
+        let decl = &module.funcs[func];
+        let (sig, name) = (decl.sig(), decl.name().to_owned());
+        let body = match bodies.remove(&func) {
+            Some(mut body) => {
+                map.body(&mut body);
+                body
+            }
+            None => {
+                let mut stub = FunctionBody::new(module, sig);
+                stub.set_terminator(stub.entry, Terminator::Unreachable);
+                stub
+            }
+        };
+        out.funcs.push(FuncDecl::Body(sig, name, body.into()));
+    }
+    let name = match module.funcs[root].name() {
+        "" => root.to_string(),
+        name => name.to_owned(),
+    };
+    out.exports.push(Export {
+        name,
+        kind: ExportKind::Func(map.funcs[root]),
+    });
+    Ok(out)
+}
+
+/// Assign dense new indices to a set of entities, in their original
+/// order.
+fn renumber<T: EntityRef + Ord + Default + std::fmt::Debug>(
+    set: &BTreeSet<T>,
+    map: &mut PerEntity<T, T>,
+) {
+    for (i, &old) in set.iter().enumerate() {
+        map[old] = T::new(i);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{GlobalData, Import, ImportKind, SignatureData, Type};
+    use crate::{ConstVal, InterpContext};
+
+    #[test]
+    fn keeps_callees_and_stubs_imports() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        let host = module.funcs.push(FuncDecl::Import(sig, "host".to_owned()));
+        module.imports.push(Import {
+            module: "env".to_owned(),
+            name: "host".to_owned(),
+            kind: ImportKind::Func(host),
+        });
+        let unused = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(1),
+            mutable: false,
+            init: None,
+        });
+        let used = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(41),
+            mutable: false,
+            init: None,
+        });
+        let add_func = |module: &mut Module, name: &str, op: Operator| {
+            let mut body = FunctionBody::new(module, sig);
+            let value = body.add_op(body.entry, op, &[], &[Type::I32]);
+            body.set_terminator(
+                body.entry,
+                Terminator::Return {
+                    values: vec![value],
+                },
+            );
+            module
+                .funcs
+                .push(FuncDecl::Body(sig, name.to_owned(), body.into()))
+        };
+        let callee = add_func(
+            &mut module,
+            "callee",
+            Operator::GlobalGet { global_index: used },
+        );
+        add_func(
+            &mut module,
+            "dead",
+            Operator::GlobalGet {
+                global_index: unused,
+            },
+        );
+        let calls_host = add_func(
+            &mut module,
+            "calls_host",
+            Operator::Call {
+                function_index: host,
+            },
+        );
+        let root = add_func(
+            &mut module,
+            "root",
+            Operator::Call {
+                function_index: callee,
+            },
+        );
+
+        let out = extract_func(&module, root).unwrap();
+        assert!(out.imports.is_empty());
+        assert_eq!(out.funcs.len(), 2);
+        assert_eq!(out.globals.len(), 1);
+        let root = Func::new(1);
+        assert_eq!(out.funcs[root].name(), "root");
+        let bytes = out.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut ctx = InterpContext::new(&out).unwrap();
+        let result = ctx.call(&out, root, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(41)]);
+
+        let out = extract_func(&module, calls_host).unwrap();
+        assert!(out.imports.is_empty());
+        assert_eq!(out.funcs[Func::new(0)].name(), "host");
+        let bytes = out.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+    }
+}
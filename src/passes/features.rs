@@ -0,0 +1,403 @@
+//! Feature-usage detection.
+//!
+//! Reports which post-MVP proposals a module actually uses, as
+//! opposed to which ones its producer enabled, so that a deployer can
+//! check it against an engine's support. Un-expanded and compiled
+//! bodies are scanned as bytecode without building IR; expanded
+//! bodies are scanned as IR, in which some proposals (those the
+//! frontend does not support) cannot appear.
+
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, FunctionBody, InitExpr, Module, Type, ValueDef};
+use crate::Operator;
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+/// A post-MVP Wasm proposal that a module may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    SignExtension,
+    SaturatingFloatToInt,
+    MultiValue,
+    BulkMemory,
+    ReferenceTypes,
+    Simd,
+    RelaxedSimd,
+    Threads,
+    SharedEverythingThreads,
+    TailCall,
+    MultiMemory,
+    Exceptions,
+    ExtendedConst,
+    FunctionReferences,
+    MemoryControl,
+    Gc,
+}
+
+impl Feature {
+    /// The proposal's name, as wasmparser and most engines spell it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::SignExtension => "sign_extension",
+            Feature::SaturatingFloatToInt => "saturating_float_to_int",
+            Feature::MultiValue => "multi_value",
+            Feature::BulkMemory => "bulk_memory",
+            Feature::ReferenceTypes => "reference_types",
+            Feature::Simd => "simd",
+            Feature::RelaxedSimd => "relaxed_simd",
+            Feature::Threads => "threads",
+            Feature::SharedEverythingThreads => "shared_everything_threads",
+            Feature::TailCall => "tail_call",
+            Feature::MultiMemory => "multi_memory",
+            Feature::Exceptions => "exceptions",
+            Feature::ExtendedConst => "extended_const",
+            Feature::FunctionReferences => "function_references",
+            Feature::MemoryControl => "memory_control",
+            Feature::Gc => "gc",
+        }
+    }
+
+    /// The corresponding wasmparser feature flag.
+    pub fn wasm_features(self) -> wasmparser::WasmFeatures {
+        use wasmparser::WasmFeatures as F;
+        match self {
+            Feature::SignExtension => F::SIGN_EXTENSION,
+            Feature::SaturatingFloatToInt => F::SATURATING_FLOAT_TO_INT,
+            Feature::MultiValue => F::MULTI_VALUE,
+            Feature::BulkMemory => F::BULK_MEMORY,
+            Feature::ReferenceTypes => F::REFERENCE_TYPES,
+            Feature::Simd => F::SIMD,
+            Feature::RelaxedSimd => F::RELAXED_SIMD,
+            Feature::Threads => F::THREADS,
+            Feature::SharedEverythingThreads => F::SHARED_EVERYTHING_THREADS,
+            Feature::TailCall => F::TAIL_CALL,
+            Feature::MultiMemory => F::MULTI_MEMORY,
+            Feature::Exceptions => F::EXCEPTIONS,
+            Feature::ExtendedConst => F::EXTENDED_CONST,
+            Feature::FunctionReferences => F::FUNCTION_REFERENCES,
+            Feature::MemoryControl => F::MEMORY_CONTROL,
+            Feature::Gc => F::GC,
+        }
+    }
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The set of proposals a module uses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeatureSet(BTreeSet<Feature>);
+
+impl FeatureSet {
+    /// Add `feature` to the set.
+    pub fn insert(&mut self, feature: Feature) {
+        self.0.insert(feature);
+    }
+
+    /// Does the module use `feature`?
+    pub fn contains(&self, feature: Feature) -> bool {
+        self.0.contains(&feature)
+    }
+
+    /// Does the module use only MVP features?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The features used, in a fixed order.
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// The wasmparser flags for the features used, on top of the
+    /// MVP. A validator configured with these accepts the module.
+    pub fn wasm_features(&self) -> wasmparser::WasmFeatures {
+        self.iter().fold(
+            wasmparser::WasmFeatures::MUTABLE_GLOBAL | wasmparser::WasmFeatures::FLOATS,
+            |features, feature| features | feature.wasm_features(),
+        )
+    }
+
+    /// Compute the features `module` uses.
+    pub fn compute(module: &Module) -> Result<FeatureSet> {
+        let mut set = FeatureSet::default();
+        set.scan_module(module);
+        for decl in module.funcs.values() {
+            match decl {
+                FuncDecl::Lazy(_, _, body) => set.scan_code(module, body)?,
+                FuncDecl::Body(_, _, body) => set.scan_body(body),
+                FuncDecl::Compiled(_, _, bytes) => {
+                    let reader = wasmparser::BinaryReader::new(
+                        &bytes[..],
+                        0,
+                        wasmparser::WasmFeatures::all(),
+                    );
+                    set.scan_code(module, &wasmparser::FunctionBody::new(reader))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(set)
+    }
+
+    /// Scan the locals and operators of a body in bytecode.
+    fn scan_code(&mut self, module: &Module, body: &wasmparser::FunctionBody) -> Result<()> {
+        for local in body.get_locals_reader()? {
+            let (_, ty) = local?;
+            self.scan_type(ty.into());
+        }
+        self.scan_operators(module, body.get_operators_reader()?)
+    }
+
+    /// Scan the type of a value (not of a table element).
+    fn scan_type(&mut self, ty: Type) {
+        match ty {
+            Type::V128 => self.insert(Feature::Simd),
+            Type::FuncRef => self.insert(Feature::ReferenceTypes),
+            Type::TypedFuncRef(..) => self.insert(Feature::FunctionReferences),
+            _ => {}
+        }
+    }
+
+    fn scan_module(&mut self, module: &Module) {
+        for sig in module.signatures.values() {
+            if sig.returns.len() > 1 {
+                self.insert(Feature::MultiValue);
+            }
+            for &ty in sig.params.iter().chain(sig.returns.iter()) {
+                self.scan_type(ty);
+            }
+        }
+        for global in module.globals.values() {
+            self.scan_type(global.ty);
+            if let Some(InitExpr::Binary(..)) = global.init {
+                self.insert(Feature::ExtendedConst);
+            }
+        }
+        // Tables of `funcref` are MVP; only reference values are not.
+        for table in module.tables.values() {
+            if table.ty != Type::FuncRef {
+                self.scan_type(table.ty);
+            }
+        }
+        if module.tables.len() > 1 {
+            self.insert(Feature::ReferenceTypes);
+        }
+        if module.memories.len() > 1 {
+            self.insert(Feature::MultiMemory);
+        }
+    }
+
+    fn scan_operators(&mut self, module: &Module, ops: wasmparser::OperatorsReader) -> Result<()> {
+        for op in ops {
+            let op = op?;
+            if let Some(feature) = operator_feature(&op) {
+                self.insert(feature);
+            }
+            match op {
+                wasmparser::Operator::Block { blockty }
+                | wasmparser::Operator::Loop { blockty }
+                | wasmparser::Operator::If { blockty } => match blockty {
+                    wasmparser::BlockType::Empty => {}
+                    wasmparser::BlockType::Type(ty) => self.scan_type(ty.into()),
+                    // Only blocks with parameters or several results
+                    // need a signature.
+                    wasmparser::BlockType::FuncType(sig) => {
+                        self.insert(Feature::MultiValue);
+                        let sig = &module.signatures[crate::Signature::new(sig as usize)];
+                        for &ty in sig.params.iter().chain(sig.returns.iter()) {
+                            self.scan_type(ty);
+                        }
+                    }
+                },
+                wasmparser::Operator::CallIndirect { table_index, .. } if table_index != 0 => {
+                    self.insert(Feature::ReferenceTypes)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_body(&mut self, body: &FunctionBody) {
+        for &ty in body.locals.values() {
+            self.scan_type(ty);
+        }
+        for def in body.values.values() {
+            match def {
+                ValueDef::BlockParam(_, _, ty) | ValueDef::PickOutput(_, _, ty) => {
+                    self.scan_type(*ty)
+                }
+                ValueDef::Operator(op, _, tys) => {
+                    for &ty in &body.type_pool[*tys] {
+                        self.scan_type(ty);
+                    }
+                    if let Some(feature) = ir_operator_feature(op) {
+                        self.insert(feature);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names = self.iter().map(Feature::name).collect::<Vec<_>>();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// The proposal an IR operator belongs to, beyond what its types
+/// imply. (SIMD operators all produce or consume `v128` values, so
+/// they are caught by type instead.)
+fn ir_operator_feature(op: &Operator) -> Option<Feature> {
+    match op {
+        Operator::I32Extend8S
+        | Operator::I32Extend16S
+        | Operator::I64Extend8S
+        | Operator::I64Extend16S
+        | Operator::I64Extend32S => Some(Feature::SignExtension),
+        Operator::I32TruncSatF32S
+        | Operator::I32TruncSatF32U
+        | Operator::I32TruncSatF64S
+        | Operator::I32TruncSatF64U
+        | Operator::I64TruncSatF32S
+        | Operator::I64TruncSatF32U
+        | Operator::I64TruncSatF64S
+        | Operator::I64TruncSatF64U => Some(Feature::SaturatingFloatToInt),
+        Operator::MemoryCopy { .. } | Operator::MemoryFill { .. } => Some(Feature::BulkMemory),
+        Operator::TableGet { .. }
+        | Operator::TableSet { .. }
+        | Operator::TableGrow { .. }
+        | Operator::TableSize { .. }
+        | Operator::RefIsNull
+        | Operator::RefNull { .. }
+        | Operator::RefFunc { .. } => Some(Feature::ReferenceTypes),
+        Operator::CallIndirect { table_index, .. } if table_index.index() != 0 => {
+            Some(Feature::ReferenceTypes)
+        }
+        Operator::CallRef { .. } => Some(Feature::FunctionReferences),
+        _ => None,
+    }
+}
+
+macro_rules! proposal_feature {
+    (mvp) => {
+        None
+    };
+    (sign_extension) => {
+        Some(Feature::SignExtension)
+    };
+    (saturating_float_to_int) => {
+        Some(Feature::SaturatingFloatToInt)
+    };
+    (bulk_memory) => {
+        Some(Feature::BulkMemory)
+    };
+    (reference_types) => {
+        Some(Feature::ReferenceTypes)
+    };
+    (simd) => {
+        Some(Feature::Simd)
+    };
+    (relaxed_simd) => {
+        Some(Feature::RelaxedSimd)
+    };
+    (threads) => {
+        Some(Feature::Threads)
+    };
+    (shared_everything_threads) => {
+        Some(Feature::SharedEverythingThreads)
+    };
+    (tail_call) => {
+        Some(Feature::TailCall)
+    };
+    (exceptions) => {
+        Some(Feature::Exceptions)
+    };
+    (function_references) => {
+        Some(Feature::FunctionReferences)
+    };
+    (memory_control) => {
+        Some(Feature::MemoryControl)
+    };
+    (gc) => {
+        Some(Feature::Gc)
+    };
+}
+
+macro_rules! define_operator_feature {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        /// The proposal a bytecode operator belongs to, from
+        /// wasmparser's own classification.
+        fn operator_feature(op: &wasmparser::Operator) -> Option<Feature> {
+            match op {
+                $(wasmparser::Operator::$op { .. } => proposal_feature!($proposal),)*
+            }
+        }
+    };
+}
+
+wasmparser::for_each_operator!(define_operator_feature);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FrontendOptions;
+
+    #[test]
+    fn detects_lazy_and_expanded_features() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory 1)
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.extend8_s)
+                (func (param v128) (result v128)
+                  local.get 0
+                  return_call 1)
+                (func (param i32 i32) (result i32)
+                  local.get 0
+                  local.get 1
+                  i32.const 4
+                  memory.copy
+                  i32.const 0))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let lazy = module.detect_features().unwrap();
+        assert_eq!(
+            lazy.to_string(),
+            "sign_extension, bulk_memory, simd, tail_call"
+        );
+        wasmparser::Validator::new_with_features(lazy.wasm_features())
+            .validate_all(&bytes)
+            .unwrap();
+
+        // The frontend does not support tail calls, so that body stays
+        // lazy; the others are now scanned as IR, with the same result.
+        module.frontend_options.lenient = true;
+        module.expand_all_funcs().unwrap();
+        assert_eq!(module.detect_features().unwrap(), lazy);
+    }
+
+    #[test]
+    fn funcref_values_need_reference_types() {
+        let detect = |wat: &str| {
+            let bytes = wat::parse_str(wat).unwrap();
+            let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+            let lazy = module.detect_features().unwrap();
+            module.expand_all_funcs().unwrap();
+            assert_eq!(module.detect_features().unwrap(), lazy);
+            lazy.to_string()
+        };
+        assert_eq!(detect("(module (table 1 funcref))"), "");
+        assert_eq!(detect("(module (func (param funcref)))"), "reference_types");
+        assert_eq!(detect("(module (func (local funcref)))"), "reference_types");
+    }
+}
@@ -0,0 +1,222 @@
+//! Function ordering.
+//!
+//! The order of functions in the index space matters twice over: a
+//! `call` immediate is a LEB128 index, so functions called from many
+//! sites are cheaper at low indices, and engines that compile or tier
+//! up in index order (or stream the code section) reach the code they
+//! need sooner if it comes first. `reorder_funcs()` renumbers the
+//! functions with bodies by one of several strategies; imports always
+//! stay first.
+
+use crate::entity::EntityRef;
+use crate::ir::{ExportKind, FuncDecl, FunctionBody, Module, ValueDef};
+use crate::passes::pgo::Profile;
+use crate::passes::remap;
+use crate::{Func, Operator};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+/// A strategy for ordering functions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FuncOrder {
+    /// Keep the current order.
+    #[default]
+    Original,
+    /// Place each function just after its first caller, depth-first
+    /// from the exports and start function, so that callers and
+    /// callees are close together.
+    CallGraph,
+    /// Order by decreasing number of static call sites, which
+    /// minimizes the size of `call` immediates.
+    MostCalled,
+    /// Order by decreasing call count in a profile, hottest first.
+    HotFirst,
+}
+
+impl std::str::FromStr for FuncOrder {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<FuncOrder> {
+        match s {
+            "original" => Ok(FuncOrder::Original),
+            "call-graph" => Ok(FuncOrder::CallGraph),
+            "most-called" => Ok(FuncOrder::MostCalled),
+            "hot-first" => Ok(FuncOrder::HotFirst),
+            _ => bail!("Unknown function order: {}", s),
+        }
+    }
+}
+
+/// Reorder the functions with bodies in `module` by `order`, and
+/// return how many moved. `HotFirst` requires a `profile` of this
+/// module. Ties keep their current relative order, and bodies are
+/// expanded first.
+pub fn reorder_funcs(
+    module: &mut Module,
+    order: FuncOrder,
+    profile: Option<&Profile>,
+) -> Result<usize> {
+    module.expand_all_funcs()?;
+    let mut funcs = module
+        .funcs
+        .entries()
+        .filter(|(_, decl)| !matches!(decl, FuncDecl::Import(..)))
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    match order {
+        FuncOrder::Original => {}
+        FuncOrder::CallGraph => funcs = call_graph_order(module, &funcs),
+        FuncOrder::MostCalled => {
+            let mut sites: BTreeMap<Func, usize> = BTreeMap::new();
+            for decl in module.funcs.values() {
+                if let Some(body) = decl.body() {
+                    for callee in callees(body) {
+                        *sites.entry(callee).or_default() += 1;
+                    }
+                }
+            }
+            // A stable sort keeps ties in index order.
+            funcs.sort_by_key(|func| std::cmp::Reverse(sites.get(func).copied().unwrap_or(0)));
+        }
+        FuncOrder::HotFirst => {
+            let profile = match profile {
+                Some(profile) => profile,
+                None => bail!("Hot-first function order requires a profile"),
+            };
+            let calls = |func: &Func| profile.funcs.get(func).map(|counts| counts.calls);
+            funcs.sort_by_key(|func| std::cmp::Reverse(calls(func)));
+        }
+    }
+
+    let first = module.funcs.len() - funcs.len();
+    let moved = funcs
+        .iter()
+        .enumerate()
+        .filter(|&(i, func)| func.index() != first + i)
+        .count();
+    if moved > 0 {
+        remap::reorder_funcs(module, &funcs)?;
+    }
+    Ok(moved)
+}
+
+/// The direct callees of `body`, once per call site, in block and
+/// instruction order.
+fn callees(body: &FunctionBody) -> impl Iterator<Item = Func> + '_ {
+    body.blocks
+        .values()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(move |&inst| match body.values[inst] {
+            ValueDef::Operator(Operator::Call { function_index }, ..) => Some(function_index),
+            _ => None,
+        })
+}
+
+/// Order `funcs` depth-first over the call graph, from the exported
+/// functions, then the start function, then any left over in index
+/// order. Each function is placed before its callees, in the order it
+/// first calls them.
+fn call_graph_order(module: &Module, funcs: &[Func]) -> Vec<Func> {
+    let exports = module
+        .exports
+        .iter()
+        .filter_map(|export| match export.kind {
+            ExportKind::Func(func) => Some(func),
+            _ => None,
+        });
+    let roots = exports
+        .chain(module.start_func)
+        .chain(funcs.iter().copied())
+        .collect::<Vec<_>>();
+
+    let mut placed = vec![false; module.funcs.len()];
+    let mut order = vec![];
+    let mut stack = vec![];
+    for root in roots {
+        stack.push(root);
+        while let Some(func) = stack.pop() {
+            if placed[func.index()] {
+                continue;
+            }
+            placed[func.index()] = true;
+            let body = match module.funcs[func].body() {
+                Some(body) => body,
+                None => continue,
+            };
+            order.push(func);
+            let mut callees = callees(body).collect::<Vec<_>>();
+            // Push in reverse so that the first callee is visited
+            // first.
+            callees.reverse();
+            stack.extend(callees.into_iter().filter(|callee| !placed[callee.index()]));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FrontendOptions;
+
+    fn names<'a>(module: &'a Module) -> Vec<&'a str> {
+        module
+            .funcs
+            .values()
+            .map(|decl| decl.name())
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn strategies() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (import "env" "imp" (func $imp))
+                (func $leaf)
+                (func $helper call $leaf)
+                (func $main (export "main")
+                  call $helper
+                  call $leaf
+                  call $other)
+                (func $other call $leaf))"#,
+        )
+        .unwrap();
+        let parse = || Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+
+        let mut module = parse();
+        assert_eq!(
+            reorder_funcs(&mut module, FuncOrder::Original, None).unwrap(),
+            0
+        );
+
+        let mut module = parse();
+        reorder_funcs(&mut module, FuncOrder::CallGraph, None).unwrap();
+        assert_eq!(
+            names(&module),
+            vec!["imp", "main", "helper", "leaf", "other"]
+        );
+        let produced = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new()
+            .validate_all(&produced)
+            .unwrap();
+
+        let mut module = parse();
+        reorder_funcs(&mut module, FuncOrder::MostCalled, None).unwrap();
+        assert_eq!(
+            names(&module),
+            vec!["imp", "leaf", "helper", "other", "main"]
+        );
+
+        let mut module = parse();
+        assert!(reorder_funcs(&mut module, FuncOrder::HotFirst, None).is_err());
+        let mut profile = Profile::default();
+        for _ in 0..3 {
+            profile.record_call(Func::new(4));
+        }
+        profile.record_call(Func::new(3));
+        reorder_funcs(&mut module, FuncOrder::HotFirst, Some(&profile)).unwrap();
+        assert_eq!(
+            names(&module),
+            vec!["imp", "other", "main", "leaf", "helper"]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Global initializers: dependency order, and lowering computed
+//! initializers into the start function.
+//!
+//! A constant expression may only read imported immutable globals.
+//! Linking modules together, or pre-initializing one, can produce
+//! initializers that read defined globals (or that use extended
+//! constant expressions a consumer does not support). These cannot be
+//! emitted as-is; instead, `lower()` zero-initializes such globals,
+//! makes them mutable, and writes their values from a start function,
+//! in an order where every global is written before it is read.
+
+use crate::ir::{ExportKind, FuncDecl, FunctionBody, Global, ImportKind, InitExpr, InitOp};
+use crate::ir::{Module, Terminator, Type, Value};
+use crate::passes::signatures;
+use crate::{Block, Operator};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// For each global with a computed initializer, the globals it reads.
+pub fn dependencies(module: &Module) -> BTreeMap<Global, BTreeSet<Global>> {
+    module
+        .globals
+        .entries()
+        .filter_map(|(global, data)| {
+            let init = data.init.as_ref()?;
+            let mut deps = BTreeSet::new();
+            init.visit_globals(&mut |dep| {
+                deps.insert(dep);
+            });
+            Some((global, deps))
+        })
+        .collect()
+}
+
+/// The globals with computed initializers, ordered so that each comes
+/// after the others it reads. Fails if initializers depend on each
+/// other cyclically.
+pub fn order(module: &Module) -> Result<Vec<Global>> {
+    let deps = dependencies(module);
+    let mut order = vec![];
+    // Absent: unvisited; 1: on the stack; 2: done.
+    let mut state: BTreeMap<Global, u8> = BTreeMap::new();
+    for &root in deps.keys() {
+        if state.contains_key(&root) {
+            continue;
+        }
+        state.insert(root, 1);
+        let mut stack = vec![(root, deps[&root].iter())];
+        while let Some((global, iter)) = stack.last_mut() {
+            match iter.next() {
+                Some(&dep) if deps.contains_key(&dep) => match state.get(&dep) {
+                    None => {
+                        state.insert(dep, 1);
+                        stack.push((dep, deps[&dep].iter()));
+                    }
+                    Some(1) => bail!(
+                        "Initializers of {} and {} depend on each other",
+                        global,
+                        dep
+                    ),
+                    _ => {}
+                },
+                Some(_) => {}
+                None => {
+                    let global = *global;
+                    state.insert(global, 2);
+                    order.push(global);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Can `init` be emitted as a constant expression, reading only
+/// imported immutable globals?
+fn expressible(module: &Module, init: &InitExpr) -> bool {
+    let mut ok = true;
+    init.visit_globals(&mut |global| {
+        let imported = module
+            .imports
+            .iter()
+            .any(|import| import.kind == ImportKind::Global(global));
+        ok &= imported && !module.globals[global].mutable;
+    });
+    ok
+}
+
+/// Lower computed initializers into a start function: those that
+/// cannot be emitted as constant expressions, or all of them if `all`
+/// is set (for consumers without extended constant expressions). A
+/// lowered global becomes mutable and starts at zero, so exported
+/// globals cannot be lowered; this is an error. Any existing start
+/// function is called after the writes. Returns the number of globals
+/// lowered.
+pub fn lower(module: &mut Module, all: bool) -> Result<usize> {
+    let lowered = order(module)?
+        .into_iter()
+        .filter(|&global| {
+            let init = module.globals[global].init.as_ref().unwrap();
+            all || !expressible(module, init)
+        })
+        .collect::<Vec<_>>();
+    if lowered.is_empty() {
+        return Ok(0);
+    }
+    // Making an exported global mutable would change the type of the
+    // export, and hosts importing it as immutable would fail to link.
+    for export in &module.exports {
+        if let ExportKind::Global(global) = export.kind {
+            if lowered.contains(&global) {
+                bail!(
+                    "Cannot lower the initializer of {}: it is exported as {}",
+                    global,
+                    export.name
+                );
+            }
+        }
+    }
+
+    let sig = signatures::intern(module, &[], &[]);
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    // Emit every write before changing any global, so that an
+    // initializer that cannot be lowered leaves the globals as they
+    // were.
+    for &global in &lowered {
+        let init = module.globals[global].init.as_ref().unwrap();
+        let value = emit(&mut body, entry, module, init)?;
+        let set = Operator::GlobalSet {
+            global_index: global,
+        };
+        body.add_op(entry, set, &[value], &[]);
+    }
+    for &global in &lowered {
+        module.globals[global].init = None;
+        module.globals[global].value = Some(0);
+        module.globals[global].mutable = true;
+    }
+    if let Some(start) = module.start_func {
+        let call = Operator::Call {
+            function_index: start,
+        };
+        body.add_op(entry, call, &[], &[]);
+    }
+    body.set_terminator(entry, Terminator::Return { values: vec![] });
+    let start = module
+        .funcs
+        .push(FuncDecl::Body(sig, "global_init".to_owned(), body.into()));
+    module.start_func = Some(start);
+    log::debug!(
+        "global_inits: lowered {} initializers into {}",
+        lowered.len(),
+        start
+    );
+    Ok(lowered.len())
+}
+
+fn emit(body: &mut FunctionBody, block: Block, module: &Module, init: &InitExpr) -> Result<Value> {
+    Ok(match init {
+        &InitExpr::Const(ty, bits) => {
+            let op = match ty {
+                Type::I32 => Operator::I32Const { value: bits as u32 },
+                Type::I64 => Operator::I64Const { value: bits },
+                Type::F32 => Operator::F32Const { value: bits as u32 },
+                Type::F64 => Operator::F64Const { value: bits },
+                _ => bail!("Cannot lower a constant initializer of type {}", ty),
+            };
+            body.add_op(block, op, &[], &[ty])
+        }
+        &InitExpr::GlobalGet(global) => {
+            let op = Operator::GlobalGet {
+                global_index: global,
+            };
+            body.add_op(block, op, &[], &[module.globals[global].ty])
+        }
+        InitExpr::Binary(op, a, b) => {
+            let a = emit(body, block, module, a)?;
+            let b = emit(body, block, module, b)?;
+            let (op, ty) = match op {
+                InitOp::I32Add => (Operator::I32Add, Type::I32),
+                InitOp::I32Sub => (Operator::I32Sub, Type::I32),
+                InitOp::I32Mul => (Operator::I32Mul, Type::I32),
+                InitOp::I64Add => (Operator::I64Add, Type::I64),
+                InitOp::I64Sub => (Operator::I64Sub, Type::I64),
+                InitOp::I64Mul => (Operator::I64Mul, Type::I64),
+            };
+            body.add_op(block, op, &[a, b], &[ty])
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+    use crate::ir::{Export, ExportKind, GlobalData, Import, SignatureData};
+    use crate::{ConstVal, InterpContext};
+
+    fn global(module: &mut Module, init: Option<InitExpr>) -> Global {
+        module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: None,
+            mutable: false,
+            init,
+        })
+    }
+
+    fn binary(op: InitOp, a: InitExpr, b: InitExpr) -> Option<InitExpr> {
+        Some(InitExpr::Binary(op, Box::new(a), Box::new(b)))
+    }
+
+    #[test]
+    fn lowers_in_dependency_order() {
+        let mut module = Module::empty();
+        let g0 = global(&mut module, None);
+        module.imports.push(Import {
+            module: "env".to_owned(),
+            name: "g".to_owned(),
+            kind: ImportKind::Global(g0),
+        });
+        let c = |value: u32| InitExpr::Const(Type::I32, value as u64);
+        // g1 = g0 + 1 is a valid extended constant expression; g2 =
+        // g3 * 2 and g3 = g1 - 3 read defined globals, and g2 reads
+        // one defined after it.
+        let g1 = global(
+            &mut module,
+            binary(InitOp::I32Add, InitExpr::GlobalGet(g0), c(1)),
+        );
+        let g2 = Global::new(2);
+        let g3 = Global::new(3);
+        global(
+            &mut module,
+            binary(InitOp::I32Mul, InitExpr::GlobalGet(g3), c(2)),
+        );
+        global(
+            &mut module,
+            binary(InitOp::I32Sub, InitExpr::GlobalGet(g1), c(3)),
+        );
+        assert_eq!(order(&module).unwrap(), vec![g1, g3, g2]);
+
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let get = Operator::GlobalGet { global_index: g2 };
+        let value = body.add_op(body.entry, get, &[], &[Type::I32]);
+        body.set_terminator(
+            body.entry,
+            Terminator::Return {
+                values: vec![value],
+            },
+        );
+        let read = module
+            .funcs
+            .push(FuncDecl::Body(sig, "read".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "read".to_owned(),
+            kind: ExportKind::Func(read),
+        });
+
+        assert_eq!(lower(&mut module, false).unwrap(), 2);
+        assert!(module.globals[g1].init.is_some());
+        assert!(module.globals[g2].mutable && module.globals[g3].mutable);
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        // The imported global is zero in the interpreter.
+        let mut ctx = InterpContext::new(&module).unwrap();
+        ctx.call(&module, module.start_func.unwrap(), &[])
+            .ok()
+            .unwrap();
+        let result = ctx.call(&module, read, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(-4i32 as u32)]);
+
+        // An exported global cannot become mutable.
+        module.globals[g2].init = binary(InitOp::I32Mul, InitExpr::GlobalGet(g3), c(2));
+        module.globals[g3].init = binary(InitOp::I32Sub, InitExpr::GlobalGet(g1), c(3));
+        module.exports.push(Export {
+            name: "g3".to_owned(),
+            kind: ExportKind::Global(g3),
+        });
+        let err = lower(&mut module, false).unwrap_err();
+        assert!(err.to_string().contains("exported as g3"), "{}", err);
+
+        // Cycles cannot be ordered.
+        module.globals[g2].init = Some(InitExpr::GlobalGet(g3));
+        module.globals[g3].init = Some(InitExpr::GlobalGet(g2));
+        assert!(order(&module).is_err());
+    }
+
+    #[test]
+    fn non_numeric_constants_are_errors() {
+        let mut module = Module::empty();
+        let g0 = global(&mut module, None);
+        let v128 = InitExpr::Const(Type::V128, 0);
+        let g1 = global(
+            &mut module,
+            binary(InitOp::I32Add, InitExpr::GlobalGet(g0), v128),
+        );
+        let err = module.to_wasm_bytes().unwrap_err();
+        assert!(err.to_string().contains("v128"), "{}", err);
+        let err = lower(&mut module, true).unwrap_err();
+        assert!(err.to_string().contains("v128"), "{}", err);
+        assert!(module.globals[g1].init.is_some() && !module.globals[g1].mutable);
+    }
+}
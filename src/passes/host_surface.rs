@@ -0,0 +1,433 @@
+//! Host-surface summaries: everything a module needs from its host
+//! and provides to it.
+//!
+//! `describe()` lists a module's imports and exports with their
+//! types, its memories and tables with their limits, and what its
+//! start function does at instantiation: which imports it may call,
+//! directly or through other functions, and so which the host must
+//! have ready before the module's exports are available.
+//! `HostSurface::to_json()` writes the summary as JSON, for
+//! documentation and binding generators.
+
+use super::pgo::json_string;
+use crate::entity::EntityRef;
+use crate::ir::{ExportKind, FuncDecl, ImportKind, Module, Type, ValueDef};
+use crate::{Func, Operator};
+use std::collections::BTreeSet;
+
+/// The type of an imported or exported entity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityDesc {
+    Func {
+        params: Vec<Type>,
+        returns: Vec<Type>,
+    },
+    Table {
+        ty: Type,
+        initial: u64,
+        max: Option<u64>,
+    },
+    Global {
+        ty: Type,
+        mutable: bool,
+    },
+    Memory {
+        initial_pages: usize,
+        max_pages: Option<usize>,
+    },
+}
+
+/// Something the module needs from its host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostImport {
+    pub module: String,
+    pub name: String,
+    pub desc: EntityDesc,
+}
+
+/// Something the module provides to its host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostExport {
+    pub name: String,
+    pub desc: EntityDesc,
+}
+
+/// One memory or table, with how the host sees it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageDesc {
+    /// The memory's or table's type and limits.
+    pub desc: EntityDesc,
+    /// The module and name it is imported as, if imported.
+    pub import: Option<(String, String)>,
+    /// The names it is exported as.
+    pub exports: Vec<String>,
+}
+
+/// What the start function does at instantiation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StartDesc {
+    pub func: Func,
+    pub name: String,
+    /// The imported functions it may call, directly or transitively,
+    /// as module and name.
+    pub calls_imports: Vec<(String, String)>,
+    /// Whether it may make calls that cannot be followed: indirect
+    /// calls, or calls from functions that are not expanded. Any
+    /// import may then be called.
+    pub unknown_calls: bool,
+}
+
+/// A module's host surface. See the module documentation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostSurface {
+    pub imports: Vec<HostImport>,
+    pub exports: Vec<HostExport>,
+    pub memories: Vec<StorageDesc>,
+    pub tables: Vec<StorageDesc>,
+    pub start: Option<StartDesc>,
+}
+
+fn import_desc(module: &Module, kind: &ImportKind) -> EntityDesc {
+    match *kind {
+        ImportKind::Func(func) => func_desc(module, func),
+        ImportKind::Table(table) => export_desc(module, &ExportKind::Table(table)),
+        ImportKind::Global(global) => export_desc(module, &ExportKind::Global(global)),
+        ImportKind::Memory(memory) => export_desc(module, &ExportKind::Memory(memory)),
+    }
+}
+
+fn func_desc(module: &Module, func: Func) -> EntityDesc {
+    let sig = &module.signatures[module.funcs[func].sig()];
+    EntityDesc::Func {
+        params: sig.params.clone(),
+        returns: sig.returns.clone(),
+    }
+}
+
+fn export_desc(module: &Module, kind: &ExportKind) -> EntityDesc {
+    match *kind {
+        ExportKind::Func(func) => func_desc(module, func),
+        ExportKind::Table(table) => {
+            let data = &module.tables[table];
+            EntityDesc::Table {
+                ty: data.ty,
+                initial: data.initial,
+                max: data.max,
+            }
+        }
+        ExportKind::Global(global) => {
+            let data = &module.globals[global];
+            EntityDesc::Global {
+                ty: data.ty,
+                mutable: data.mutable,
+            }
+        }
+        ExportKind::Memory(memory) => {
+            let data = &module.memories[memory];
+            EntityDesc::Memory {
+                initial_pages: data.initial_pages,
+                max_pages: data.maximum_pages,
+            }
+        }
+    }
+}
+
+/// Follow the calls from `start`.
+fn describe_start(module: &Module, start: Func) -> StartDesc {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![start];
+    let mut imports = BTreeSet::new();
+    let mut unknown_calls = false;
+    while let Some(func) = stack.pop() {
+        if !seen.insert(func) {
+            continue;
+        }
+        let body = match &module.funcs[func] {
+            FuncDecl::Import(..) => {
+                imports.insert(func);
+                continue;
+            }
+            decl => match decl.body() {
+                Some(body) => body,
+                None => {
+                    unknown_calls = true;
+                    continue;
+                }
+            },
+        };
+        for value in body.values.values() {
+            match value {
+                ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                    stack.push(*function_index);
+                }
+                ValueDef::Operator(
+                    Operator::CallIndirect { .. } | Operator::CallRef { .. },
+                    ..,
+                ) => unknown_calls = true,
+                _ => {}
+            }
+        }
+    }
+    let calls_imports = module
+        .imports
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Func(func) if imports.contains(&func)))
+        .map(|import| (import.module.clone(), import.name.clone()))
+        .collect();
+    StartDesc {
+        func: start,
+        name: module.funcs[start].name().to_owned(),
+        calls_imports,
+        unknown_calls,
+    }
+}
+
+/// Describe the host surface of `module`. The start function's calls
+/// are followed only through expanded function bodies.
+pub fn describe(module: &Module) -> HostSurface {
+    let imports = module
+        .imports
+        .iter()
+        .map(|import| HostImport {
+            module: import.module.clone(),
+            name: import.name.clone(),
+            desc: import_desc(module, &import.kind),
+        })
+        .collect();
+    let exports = module
+        .exports
+        .iter()
+        .map(|export| HostExport {
+            name: export.name.clone(),
+            desc: export_desc(module, &export.kind),
+        })
+        .collect();
+    let storage = |kind: ExportKind| StorageDesc {
+        desc: export_desc(module, &kind),
+        import: module.imports.iter().find_map(|import| {
+            let same = match (&import.kind, &kind) {
+                (ImportKind::Memory(a), ExportKind::Memory(b)) => a == b,
+                (ImportKind::Table(a), ExportKind::Table(b)) => a == b,
+                _ => false,
+            };
+            same.then(|| (import.module.clone(), import.name.clone()))
+        }),
+        exports: module
+            .exports
+            .iter()
+            .filter(|export| export.kind == kind)
+            .map(|export| export.name.clone())
+            .collect(),
+    };
+    HostSurface {
+        imports,
+        exports,
+        memories: module
+            .memories
+            .iter()
+            .map(|memory| storage(ExportKind::Memory(memory)))
+            .collect(),
+        tables: module
+            .tables
+            .iter()
+            .map(|table| storage(ExportKind::Table(table)))
+            .collect(),
+        start: module.start_func.map(|start| describe_start(module, start)),
+    }
+}
+
+fn json_types(tys: &[Type]) -> String {
+    let tys = tys
+        .iter()
+        .map(|ty| json_string(&ty.to_string()))
+        .collect::<Vec<_>>();
+    format!("[{}]", tys.join(", "))
+}
+
+fn json_max<T: std::fmt::Display>(max: Option<T>) -> String {
+    max.map_or("null".to_owned(), |max| max.to_string())
+}
+
+impl EntityDesc {
+    fn to_json(&self) -> String {
+        match self {
+            EntityDesc::Func { params, returns } => format!(
+                "\"kind\": \"func\", \"params\": {}, \"results\": {}",
+                json_types(params),
+                json_types(returns)
+            ),
+            EntityDesc::Table { ty, initial, max } => format!(
+                "\"kind\": \"table\", \"element\": {}, \"initial\": {}, \"max\": {}",
+                json_string(&ty.to_string()),
+                initial,
+                json_max(*max)
+            ),
+            EntityDesc::Global { ty, mutable } => format!(
+                "\"kind\": \"global\", \"type\": {}, \"mutable\": {}",
+                json_string(&ty.to_string()),
+                mutable
+            ),
+            EntityDesc::Memory {
+                initial_pages,
+                max_pages,
+            } => format!(
+                "\"kind\": \"memory\", \"initial_pages\": {}, \"max_pages\": {}",
+                initial_pages,
+                json_max(*max_pages)
+            ),
+        }
+    }
+}
+
+impl HostSurface {
+    /// Serialize the summary as JSON.
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<String>| {
+            if items.is_empty() {
+                "[]".to_owned()
+            } else {
+                format!("[\n    {}\n  ]", items.join(",\n    "))
+            }
+        };
+        let imports = self
+            .imports
+            .iter()
+            .map(|import| {
+                format!(
+                    "{{\"module\": {}, \"name\": {}, {}}}",
+                    json_string(&import.module),
+                    json_string(&import.name),
+                    import.desc.to_json()
+                )
+            })
+            .collect();
+        let exports = self
+            .exports
+            .iter()
+            .map(|export| {
+                format!(
+                    "{{\"name\": {}, {}}}",
+                    json_string(&export.name),
+                    export.desc.to_json()
+                )
+            })
+            .collect();
+        let storage = |items: &[StorageDesc]| {
+            items
+                .iter()
+                .map(|item| {
+                    let import = match &item.import {
+                        Some((module, name)) => format!(
+                            "{{\"module\": {}, \"name\": {}}}",
+                            json_string(module),
+                            json_string(name)
+                        ),
+                        None => "null".to_owned(),
+                    };
+                    let exports = item
+                        .exports
+                        .iter()
+                        .map(|name| json_string(name))
+                        .collect::<Vec<_>>();
+                    format!(
+                        "{{{}, \"import\": {}, \"exports\": [{}]}}",
+                        item.desc.to_json(),
+                        import,
+                        exports.join(", ")
+                    )
+                })
+                .collect()
+        };
+        let start = match &self.start {
+            Some(start) => {
+                let calls = start
+                    .calls_imports
+                    .iter()
+                    .map(|(module, name)| {
+                        format!(
+                            "{{\"module\": {}, \"name\": {}}}",
+                            json_string(module),
+                            json_string(name)
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"func\": {}, \"name\": {}, \"calls_imports\": [{}], \"unknown_calls\": {}}}",
+                    start.func.index(),
+                    json_string(&start.name),
+                    calls.join(", "),
+                    start.unknown_calls
+                )
+            }
+            None => "null".to_owned(),
+        };
+        format!(
+            "{{\n  \"imports\": {},\n  \"exports\": {},\n  \"memories\": {},\n  \"tables\": {},\n  \"start\": {}\n}}\n",
+            list(imports),
+            list(exports),
+            list(storage(&self.memories)),
+            list(storage(&self.tables)),
+            start
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FrontendOptions;
+
+    #[test]
+    fn describes_imports_exports_and_start() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (import "env" "log" (func $log (param i32)))
+                (import "env" "now" (func $now (result i64)))
+                (import "env" "memory" (memory 1 2))
+                (table 1 funcref)
+                (global $g (export "g") (mut i32) (i32.const 0))
+                (func $init
+                  i32.const 1
+                  call $log)
+                (func (export "run") (result i64)
+                  call $now)
+                (export "mem" (memory 0))
+                (start $init)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let surface = describe(&module);
+        assert_eq!(surface.imports.len(), 3);
+        assert_eq!(
+            surface.imports[1].desc,
+            EntityDesc::Func {
+                params: vec![],
+                returns: vec![Type::I64]
+            }
+        );
+        assert_eq!(
+            surface.memories[0].import,
+            Some(("env".to_owned(), "memory".to_owned()))
+        );
+        assert_eq!(surface.memories[0].exports, vec!["mem".to_owned()]);
+        // Only `log` must be ready at instantiation.
+        let start = surface.start.as_ref().unwrap();
+        assert_eq!(
+            start.calls_imports,
+            vec![("env".to_owned(), "log".to_owned())]
+        );
+        assert!(!start.unknown_calls);
+
+        let json = surface.to_json();
+        assert!(json.contains(
+            r#"{"module": "env", "name": "now", "kind": "func", "params": [], "results": ["i64"]}"#
+        ));
+        assert!(json.contains(r#"{"name": "g", "kind": "global", "type": "i32", "mutable": true}"#));
+        assert!(json.contains(
+            r#"{"kind": "table", "element": "funcref", "initial": 1, "max": null, "import": null, "exports": []}"#
+        ));
+        assert!(json.contains(r#""calls_imports": [{"module": "env", "name": "log"}]"#));
+    }
+}
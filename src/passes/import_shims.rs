@@ -0,0 +1,301 @@
+//! Import renaming and shimming.
+//!
+//! Migrating a module between versions of a host API usually means
+//! renaming its imports, and sometimes calling a host function whose
+//! signature differs slightly from the one the module was built
+//! against: the same arguments in another order, or extra results
+//! that the module does not use. `remap()` renames imports, and
+//! `retarget()` changes an imported function's signature, generating
+//! an adapter function with the old signature that the rest of the
+//! module calls instead.
+
+use crate::entity::EntityRef;
+use crate::ir::{ExportKind, FuncDecl, Import, ImportKind, Module, Type, ValueDef};
+use crate::passes::trampolines::{self, Source, TrampolineSpec};
+use crate::{Func, Operator, Signature};
+use anyhow::{bail, Result};
+
+/// How an adapter with an import's old signature calls the import
+/// with its new signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportAdapter {
+    /// The import's new signature.
+    pub sig: Signature,
+    /// For each parameter of the new signature, the index of the old
+    /// parameter passed in it.
+    pub args: Vec<usize>,
+    /// For each result of the old signature, the index of the new
+    /// result returned in it. New results not listed are dropped.
+    pub results: Vec<usize>,
+}
+
+impl ImportAdapter {
+    /// Infer an adapter from `old` to `new` in `module`, if the new
+    /// parameters are a reordering of the old ones and the old results
+    /// are a subsequence, by type, of the new ones. Parameters of the
+    /// same type keep their relative order.
+    pub fn infer(module: &Module, old: Signature, new: Signature) -> Option<ImportAdapter> {
+        let (old_data, new_data) = (&module.signatures[old], &module.signatures[new]);
+        if old_data.params.len() != new_data.params.len() {
+            return None;
+        }
+        let args = match_in_order(&new_data.params, &old_data.params)?;
+        let results = match_in_order(&old_data.returns, &new_data.returns)?;
+        Some(ImportAdapter {
+            sig: new,
+            args,
+            results,
+        })
+    }
+}
+
+/// For each of `wanted`, the index of the first not yet taken element
+/// of `available` with the same type.
+fn match_in_order(wanted: &[Type], available: &[Type]) -> Option<Vec<usize>> {
+    let mut taken = vec![false; available.len()];
+    wanted
+        .iter()
+        .map(|ty| {
+            let i = (0..available.len()).find(|&i| !taken[i] && available[i] == *ty)?;
+            taken[i] = true;
+            Some(i)
+        })
+        .collect()
+}
+
+/// Rename imports: `f` is called with each import's module and name,
+/// and returns its new module and name, or `None` to leave it.
+/// Returns the number of imports renamed.
+pub fn remap<F: FnMut(&str, &str) -> Option<(String, String)>>(
+    module: &mut Module,
+    mut f: F,
+) -> usize {
+    let mut renamed = 0;
+    for import in &mut module.imports {
+        if let Some((new_module, new_name)) = f(&import.module, &import.name) {
+            log::debug!(
+                "import_shims: {}.{} -> {}.{}",
+                import.module,
+                import.name,
+                new_module,
+                new_name
+            );
+            import.module = new_module;
+            import.name = new_name;
+            renamed += 1;
+        }
+    }
+    renamed
+}
+
+/// Change the signature of the imported function `func` as `adapter`
+/// describes. A new function with the old signature adapts calls to
+/// the new one, and every reference to `func` elsewhere in the module
+/// (calls, `ref.func`s, table elements, exports, and the start
+/// function) is redirected to it. Returns the adapter function.
+///
+/// All function bodies must be expanded (see
+/// `Module::expand_all_funcs()`), since calls in bytecode cannot be
+/// redirected.
+pub fn retarget(module: &mut Module, func: Func, adapter: &ImportAdapter) -> Result<Func> {
+    let (old_sig, name) = match &module.funcs[func] {
+        FuncDecl::Import(sig, name) => (*sig, name.clone()),
+        _ => bail!("{} is not an imported function", func),
+    };
+    if module
+        .funcs
+        .values()
+        .any(|decl| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Compiled(..)))
+    {
+        bail!(
+            "Cannot retarget {}: not all function bodies are expanded",
+            func
+        );
+    }
+    // Build the adapter.
+    let spec = TrampolineSpec {
+        target: func,
+        args: adapter.args.iter().map(|&i| Source::forward(i)).collect(),
+        results: adapter
+            .results
+            .iter()
+            .map(|&i| Source::forward(i))
+            .collect(),
+    };
+    let body = trampolines::body(module, old_sig, adapter.sig, &spec)?;
+
+    // Redirect references to the import.
+    let shim = Func::new(module.funcs.len());
+    update_func_refs(module, |f| if f == func { shim } else { f });
+    debug_assert!(module
+        .imports
+        .iter()
+        .any(|import| import.kind == ImportKind::Func(func)));
+
+    module.funcs[func] = FuncDecl::Import(adapter.sig, name.clone());
+    let shim_name = format!("{}_adapter", name);
+    Ok(module
+        .funcs
+        .push(FuncDecl::Body(old_sig, shim_name, body.into())))
+}
+
+/// Rewrite every reference to a function in `module` (calls and
+/// `ref.func`s in expanded bodies, table elements, exports, and the
+/// start function) with `f`. Function import entries are left alone.
+pub fn update_func_refs<F: FnMut(Func) -> Func>(module: &mut Module, mut f: F) {
+    for decl in module.funcs.values_mut() {
+        if let Some(body) = decl.body_mut() {
+            for value in body.values.values_mut() {
+                match value {
+                    ValueDef::Operator(Operator::Call { function_index }, ..)
+                    | ValueDef::Operator(
+                        Operator::RefFunc {
+                            func_index: function_index,
+                        },
+                        ..,
+                    ) => {
+                        *function_index = f(*function_index);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    for table in module.tables.values_mut() {
+        for elt in table.func_elements.iter_mut().flatten() {
+            if elt.is_valid() {
+                *elt = f(*elt);
+            }
+        }
+    }
+    for export in &mut module.exports {
+        if let ExportKind::Func(func) = &mut export.kind {
+            *func = f(*func);
+        }
+    }
+    if let Some(start) = &mut module.start_func {
+        *start = f(*start);
+    }
+}
+
+/// Add an imported function `module_name`.`name` with signature
+/// `sig`. Function imports must precede defined functions, so the new
+/// import takes the index after the existing imports, and every
+/// defined function moves up by one; references to them are updated
+/// as in `update_func_refs()`. Returns the new import.
+///
+/// All function bodies must be expanded (see
+/// `Module::expand_all_funcs()`), since calls in bytecode cannot be
+/// renumbered.
+pub fn add_func_import(
+    module: &mut Module,
+    module_name: &str,
+    name: &str,
+    sig: Signature,
+) -> Result<Func> {
+    if module
+        .funcs
+        .values()
+        .any(|decl| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Compiled(..)))
+    {
+        bail!("Cannot add an import: not all function bodies are expanded");
+    }
+    let at = module
+        .funcs
+        .values()
+        .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count();
+    let import = Func::new(at);
+    update_func_refs(module, |f| {
+        if f.index() >= at {
+            Func::new(f.index() + 1)
+        } else {
+            f
+        }
+    });
+    let mut funcs = std::mem::take(&mut module.funcs).into_vec();
+    funcs.insert(at, FuncDecl::Import(sig, name.to_owned()));
+    for decl in funcs {
+        module.funcs.push(decl);
+    }
+    module.imports.push(Import {
+        module: module_name.to_owned(),
+        name: name.to_owned(),
+        kind: ImportKind::Func(import),
+    });
+    Ok(import)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, SignatureData, Terminator};
+
+    #[test]
+    fn reordered_args_and_dropped_result() {
+        let mut module = Module::empty();
+        let old = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::F64],
+            returns: vec![Type::I32],
+        });
+        let new = module.signatures.push(SignatureData {
+            params: vec![Type::F64, Type::I32],
+            returns: vec![Type::I64, Type::I32],
+        });
+        let host = module.funcs.push(FuncDecl::Import(old, "f".to_owned()));
+        module.imports.push(Import {
+            module: "env".to_owned(),
+            name: "f".to_owned(),
+            kind: ImportKind::Func(host),
+        });
+        let mut body = FunctionBody::new(&module, old);
+        let entry = body.entry;
+        let args = body.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>();
+        let call = Operator::Call {
+            function_index: host,
+        };
+        let r = body.add_op(entry, call, &args, &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![r] });
+        let caller = module
+            .funcs
+            .push(FuncDecl::Body(old, "g".to_owned(), body.into()));
+        module.exports.push(crate::ir::Export {
+            name: "f".to_owned(),
+            kind: ExportKind::Func(host),
+        });
+
+        let renamed = remap(&mut module, |m, n| {
+            (m == "env").then(|| ("env2".to_owned(), format!("{}_v2", n)))
+        });
+        assert_eq!(renamed, 1);
+        assert_eq!(module.imports[0].module, "env2");
+        assert_eq!(module.imports[0].name, "f_v2");
+
+        let adapter = ImportAdapter::infer(&module, old, new).unwrap();
+        assert_eq!(adapter.args, vec![1, 0]);
+        assert_eq!(adapter.results, vec![1]);
+        let shim = retarget(&mut module, host, &adapter).unwrap();
+        assert_eq!(module.funcs[host].sig(), new);
+        assert_eq!(module.funcs[shim].sig(), old);
+        assert_eq!(module.exports[0].kind, ExportKind::Func(shim));
+        let body = module.funcs[caller].body().unwrap();
+        assert!(matches!(
+            body.values[r],
+            ValueDef::Operator(Operator::Call { function_index }, ..) if function_index == shim
+        ));
+        module.funcs[shim].body().unwrap().validate().unwrap();
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        // No adapter can supply a missing argument.
+        let fewer = module.signatures.push(SignatureData {
+            params: vec![Type::F64, Type::F64],
+            returns: vec![Type::I32],
+        });
+        assert_eq!(ImportAdapter::infer(&module, old, fewer), None);
+    }
+}
@@ -0,0 +1,300 @@
+//! LEB-size-aware index assignment.
+//!
+//! Every reference to a function, global, or signature encodes its
+//! index as a LEB128 number, one byte per seven bits, so indices
+//! below 128 take one byte and those below 16384 two. This pass gives
+//! the most referenced entities in each index space the smallest
+//! indices, counting references from code, tables, exports, the start
+//! function, global initializers, and types.
+//!
+//! Imports keep their indices, since they must precede definitions.
+//! Globals read by another global's initializer, and signatures
+//! referred to by another signature's typed function references, stay
+//! before their users. An index space is left alone if the new order
+//! would not be smaller.
+
+use crate::entity::EntityRef;
+use crate::ir::{ExportKind, FuncDecl, ImportKind, Module, Type, ValueDef};
+use crate::passes::{remap, signatures};
+use crate::{Func, Global, Operator, Signature};
+use anyhow::{bail, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// What `assign_indices()` changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexReport {
+    /// Functions that moved in the function index space.
+    pub funcs_moved: usize,
+    /// Globals that moved in the global index space.
+    pub globals_moved: usize,
+    /// Signatures that moved in the type index space.
+    pub signatures_moved: usize,
+    /// The estimated reduction in encoded size, in bytes.
+    pub bytes_saved: usize,
+}
+
+impl std::fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "moved {} functions", self.funcs_moved)?;
+        writeln!(f, "moved {} globals", self.globals_moved)?;
+        writeln!(f, "moved {} signatures", self.signatures_moved)?;
+        writeln!(f, "saved about {} bytes", self.bytes_saved)
+    }
+}
+
+/// Reassign function, global, and signature indices in `module` to
+/// minimize the size of their encodings, as described in the module
+/// documentation. Function bodies are expanded first; bodies that
+/// cannot be (see `FrontendOptions::lenient`) would keep stale
+/// indices, so they are an error.
+pub fn assign_indices(module: &mut Module) -> Result<IndexReport> {
+    module.expand_all_funcs()?;
+    if let Some((func, _)) = module
+        .funcs
+        .entries()
+        .find(|(_, decl)| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Compiled(..)))
+    {
+        bail!("Cannot renumber references in un-expanded body of {}", func);
+    }
+
+    let mut report = IndexReport::default();
+
+    let fixed = module
+        .funcs
+        .values()
+        .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count();
+    let counts = func_refs(module);
+    let deps = vec![vec![]; counts.len()];
+    if let Some((order, saved)) = assign(fixed, &counts, &deps) {
+        report.funcs_moved = moved(fixed, &order);
+        report.bytes_saved += saved;
+        let order = order.into_iter().map(Func::new).collect::<Vec<_>>();
+        remap::reorder_funcs(module, &order)?;
+    }
+
+    let fixed = module
+        .imports
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Global(_)))
+        .count();
+    let (counts, deps) = global_refs(module);
+    if let Some((order, saved)) = assign(fixed, &counts, &deps) {
+        report.globals_moved = moved(fixed, &order);
+        report.bytes_saved += saved;
+        let order = order.into_iter().map(Global::new).collect::<Vec<_>>();
+        remap::reorder_globals(module, &order)?;
+    }
+
+    let (counts, deps) = signature_refs(module);
+    if let Some((order, saved)) = assign(0, &counts, &deps) {
+        report.signatures_moved = moved(0, &order);
+        report.bytes_saved += saved;
+        let order = order.into_iter().map(Signature::new).collect::<Vec<_>>();
+        signatures::reorder(module, &order);
+    }
+
+    Ok(report)
+}
+
+/// The number of bytes in the LEB128 encoding of `index`.
+fn leb_len(index: usize) -> usize {
+    let bits = (usize::BITS - index.leading_zeros()).max(1) as usize;
+    bits.div_ceil(7)
+}
+
+fn moved(fixed: usize, order: &[usize]) -> usize {
+    order
+        .iter()
+        .enumerate()
+        .filter(|&(i, &old)| old != fixed + i)
+        .count()
+}
+
+/// Order the entities from `fixed` on by decreasing reference count,
+/// keeping each after its `deps` and ties in their current order.
+/// Returns the order and the bytes it saves, or `None` if it saves
+/// nothing.
+fn assign(fixed: usize, counts: &[usize], deps: &[Vec<usize>]) -> Option<(Vec<usize>, usize)> {
+    let mut pending = vec![0; counts.len()];
+    let mut users = vec![vec![]; counts.len()];
+    for (i, deps) in deps.iter().enumerate().skip(fixed) {
+        let mut deps = deps
+            .iter()
+            .copied()
+            .filter(|&dep| dep >= fixed && dep != i)
+            .collect::<Vec<_>>();
+        deps.sort();
+        deps.dedup();
+        pending[i] = deps.len();
+        for dep in deps {
+            users[dep].push(i);
+        }
+    }
+
+    let mut ready = (fixed..counts.len())
+        .filter(|&i| pending[i] == 0)
+        .map(|i| (counts[i], Reverse(i)))
+        .collect::<BinaryHeap<_>>();
+    let mut order = vec![];
+    while let Some((_, Reverse(i))) = ready.pop() {
+        order.push(i);
+        for &user in &users[i] {
+            pending[user] -= 1;
+            if pending[user] == 0 {
+                ready.push((counts[user], Reverse(user)));
+            }
+        }
+    }
+    debug_assert_eq!(fixed + order.len(), counts.len());
+
+    let before = (fixed..counts.len())
+        .map(|i| counts[i] * leb_len(i))
+        .sum::<usize>();
+    let after = order
+        .iter()
+        .enumerate()
+        .map(|(i, &old)| counts[old] * leb_len(fixed + i))
+        .sum::<usize>();
+    match before.checked_sub(after) {
+        Some(saved) if saved > 0 => Some((order, saved)),
+        _ => None,
+    }
+}
+
+fn func_refs(module: &Module) -> Vec<usize> {
+    let mut counts = vec![0; module.funcs.len()];
+    for decl in module.funcs.values() {
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                match body.values[inst] {
+                    ValueDef::Operator(Operator::Call { function_index }, ..)
+                    | ValueDef::Operator(
+                        Operator::RefFunc {
+                            func_index: function_index,
+                        },
+                        ..,
+                    ) => counts[function_index.index()] += 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+    for table in module.tables.values() {
+        for func in table.func_elements.iter().flatten() {
+            if func.is_valid() {
+                counts[func.index()] += 1;
+            }
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Func(func) = export.kind {
+            counts[func.index()] += 1;
+        }
+    }
+    if let Some(func) = module.start_func {
+        counts[func.index()] += 1;
+    }
+    counts
+}
+
+fn global_refs(module: &Module) -> (Vec<usize>, Vec<Vec<usize>>) {
+    let mut counts = vec![0; module.globals.len()];
+    let mut deps = vec![vec![]; module.globals.len()];
+    for decl in module.funcs.values() {
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                match body.values[inst] {
+                    ValueDef::Operator(Operator::GlobalGet { global_index }, ..)
+                    | ValueDef::Operator(Operator::GlobalSet { global_index }, ..) => {
+                        counts[global_index.index()] += 1
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    for (global, data) in module.globals.entries() {
+        if let Some(init) = &data.init {
+            init.visit_globals(&mut |read| {
+                counts[read.index()] += 1;
+                deps[global.index()].push(read.index());
+            });
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Global(global) = export.kind {
+            counts[global.index()] += 1;
+        }
+    }
+    (counts, deps)
+}
+
+fn signature_refs(module: &mut Module) -> (Vec<usize>, Vec<Vec<usize>>) {
+    let mut counts = vec![0; module.signatures.len()];
+    signatures::visit_refs(module, &mut |sig| counts[sig.index()] += 1);
+    let mut deps = vec![vec![]; module.signatures.len()];
+    for (sig, data) in module.signatures.entries() {
+        for &ty in data.params.iter().chain(data.returns.iter()) {
+            if let Type::TypedFuncRef(_, index) = ty {
+                counts[index as usize] += 1;
+                deps[sig.index()].push(index as usize);
+            }
+        }
+    }
+    (counts, deps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FrontendOptions;
+
+    #[test]
+    fn most_referenced_get_small_indices() {
+        // 200 functions and globals; the last of each is used from
+        // many places.
+        let mut wat = String::from("(module\n");
+        for i in 0..200 {
+            wat += &format!("(global $g{} (mut i32) (i32.const {}))\n", i, i);
+        }
+        wat += "(func $main (export \"main\")\n";
+        for _ in 0..20 {
+            wat += "call $f199 global.get $g199 global.set $g199\n";
+        }
+        wat += ")\n";
+        for i in 0..200 {
+            wat += &format!("(func $f{})\n", i);
+        }
+        wat += ")";
+        let bytes = wat::parse_str(&wat).unwrap();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let before = module.to_wasm_bytes().unwrap();
+        let report = assign_indices(&mut module).unwrap();
+        assert!(report.funcs_moved > 0 && report.globals_moved > 0);
+        assert_eq!(report.signatures_moved, 0);
+        // Each moved index shrinks from two bytes to one, 20 times
+        // for the function and 40 for the global.
+        assert!(report.bytes_saved >= 60, "{}", report);
+        assert_eq!(module.funcs[Func::new(0)].name(), "f199");
+        assert_eq!(module.funcs[Func::new(1)].name(), "main");
+
+        let after = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&after).unwrap();
+        assert!(after.len() < before.len());
+
+        // A second run finds nothing left to gain.
+        assert_eq!(assign_indices(&mut module).unwrap(), IndexReport::default());
+    }
+}
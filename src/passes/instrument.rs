@@ -0,0 +1,602 @@
+//! Instrumentation: coverage counters, gas metering, call tracing,
+//! memory-access checking, and heap monitoring.
+//!
+//! Each transform inserts code into every expanded function body.
+//! Coverage counts block executions in memory; the others call a
+//! host function, imported under a configurable name, that the
+//! embedder implements. All of them require every function body to
+//! be expanded, since adding an import renumbers functions.
+//!
+//! The interpreter implements the heap monitor itself: see
+//! `HeapStats` and `heap_stats()`.
+
+use crate::entity::EntityRef;
+use crate::ir::{Block, ExportKind, FunctionBody, Module, Terminator, Type, Value, ValueDef};
+use crate::passes::cost::{CostModel, DefaultCostModel};
+use crate::passes::signatures;
+use crate::{Func, HeapStats, InterpContext, InterpResult, Memory, MemoryArg, Operator};
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+/// Options for coverage instrumentation.
+#[derive(Clone, Debug)]
+pub struct CoverageOptions {
+    /// The memory holding the counters.
+    pub memory: Memory,
+    /// The address of the first counter. Each counter is an `i32`;
+    /// the embedder must keep the range free.
+    pub base: u32,
+}
+
+/// Options for gas metering.
+#[derive(Clone, Debug)]
+pub struct GasOptions {
+    /// The module and name of the imported `(i64) -> ()` function
+    /// charged at the start of each block.
+    pub import: (String, String),
+    /// The cost of each operator, per `CostModel::latency()`. Each
+    /// block also costs one unit, so that empty loops are metered.
+    pub cost_model: Arc<dyn CostModel>,
+}
+
+impl Default for GasOptions {
+    fn default() -> Self {
+        GasOptions {
+            import: ("env".to_owned(), "gas".to_owned()),
+            cost_model: Arc::new(DefaultCostModel),
+        }
+    }
+}
+
+/// Options for call tracing.
+#[derive(Clone, Debug)]
+pub struct TraceOptions {
+    /// The module and name of the imported `(i32) -> ()` function
+    /// called on entry to each function with its index.
+    pub import: (String, String),
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            import: ("env".to_owned(), "trace".to_owned()),
+        }
+    }
+}
+
+/// Options for memory-access checking.
+#[derive(Clone, Debug)]
+pub struct MemcheckOptions {
+    /// The module and name of the imported function called before
+    /// each load and store, with signature `(i32 address, i32 offset,
+    /// i32 size, i32 is_store) -> ()`. The effective address is the
+    /// address plus the static offset.
+    pub import: (String, String),
+}
+
+impl Default for MemcheckOptions {
+    fn default() -> Self {
+        MemcheckOptions {
+            import: ("env".to_owned(), "memcheck".to_owned()),
+        }
+    }
+}
+
+/// Options for heap monitoring.
+#[derive(Clone, Debug)]
+pub struct HeapOptions {
+    /// The module of the imported monitor functions:
+    ///
+    /// - `grow`, `(i32 memory, i32 delta, i32 result) -> ()`, called
+    ///   after each `memory.grow` with the memory's index, the pages
+    ///   requested, and the old size in pages or -1 on failure.
+    /// - `alloc`, `(i32 size, i32 address) -> ()`, called as the
+    ///   allocator returns.
+    /// - `free`, `(i32 address) -> ()`, called on entry to the
+    ///   deallocator.
+    pub monitor: String,
+    /// The exported allocator, `(i32 size) -> i32`, and deallocator,
+    /// `(i32 address) -> ()`, to monitor, if any.
+    pub allocator: Option<(String, String)>,
+}
+
+impl Default for HeapOptions {
+    fn default() -> Self {
+        HeapOptions {
+            monitor: "heap_monitor".to_owned(),
+            allocator: None,
+        }
+    }
+}
+
+/// The monitor imports `heap()` added.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapImports {
+    pub grow: Func,
+    /// The allocation and free monitors, if monitoring an allocator.
+    pub alloc: Option<(Func, Func)>,
+}
+
+/// Add a counter to every block, incremented each time the block
+/// runs. Returns the function and block of each counter, in order.
+pub fn coverage(module: &mut Module, options: &CoverageOptions) -> Result<Vec<(Func, Block)>> {
+    if module.memories.get(options.memory).is_none() {
+        bail!("No memory {} for coverage counters", options.memory);
+    }
+    let mut counters = vec![];
+    for (func, decl) in module.funcs.entries_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.iter() {
+            let offset = options
+                .base
+                .checked_add(counters.len() as u32 * 4)
+                .ok_or_else(|| anyhow::anyhow!("Coverage counters overflow memory"))?;
+            let memory = MemoryArg {
+                align: 2,
+                offset,
+                memory: options.memory,
+            };
+            let mut at = Inserter::new(body, block, 0);
+            let zero = at.op(Operator::I32Const { value: 0 }, &[], &[Type::I32]);
+            let count = at.op(Operator::I32Load { memory }, &[zero], &[Type::I32]);
+            let one = at.op(Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+            let count = at.op(Operator::I32Add, &[count, one], &[Type::I32]);
+            at.op(Operator::I32Store { memory }, &[zero, count], &[]);
+            counters.push((func, block));
+        }
+    }
+    Ok(counters)
+}
+
+/// Charge each block's cost to an imported gas function at the start
+/// of the block. Returns the import.
+pub fn gas(module: &mut Module, options: &GasOptions) -> Result<Func> {
+    let sig = signatures::intern(module, &[Type::I64], &[]);
+    let (import_module, name) = &options.import;
+    let gas = module.add_func_import(import_module, name, sig)?;
+    for decl in module.funcs.values_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.iter() {
+            let cost = 1 + options.cost_model.block_latency(body, block) as u64;
+            let mut at = Inserter::new(body, block, 0);
+            let cost = at.op(Operator::I64Const { value: cost }, &[], &[Type::I64]);
+            at.op(
+                Operator::Call {
+                    function_index: gas,
+                },
+                &[cost],
+                &[],
+            );
+        }
+    }
+    Ok(gas)
+}
+
+/// Call an imported trace function on entry to every function, with
+/// the function's index (after the import is added). Returns the
+/// import.
+pub fn trace(module: &mut Module, options: &TraceOptions) -> Result<Func> {
+    let sig = signatures::intern(module, &[Type::I32], &[]);
+    let (import_module, name) = &options.import;
+    let trace = module.add_func_import(import_module, name, sig)?;
+    for (func, decl) in module.funcs.entries_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        let entry = body.entry;
+        let mut at = Inserter::new(body, entry, 0);
+        let index = func.index() as u32;
+        let index = at.op(Operator::I32Const { value: index }, &[], &[Type::I32]);
+        at.op(
+            Operator::Call {
+                function_index: trace,
+            },
+            &[index],
+            &[],
+        );
+    }
+    Ok(trace)
+}
+
+/// Call an imported check function before every ordinary load and
+/// store. Returns the import.
+pub fn memcheck(module: &mut Module, options: &MemcheckOptions) -> Result<Func> {
+    let sig = signatures::intern(module, &[Type::I32; 4], &[]);
+    let (import_module, name) = &options.import;
+    let check = module.add_func_import(import_module, name, sig)?;
+    for decl in module.funcs.values_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.iter() {
+            let mut i = 0;
+            while i < body.blocks[block].insts.len() {
+                let inst = body.blocks[block].insts[i];
+                let (op, addr) = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) if op.memory_access().is_some() => {
+                        (*op, body.arg_pool[*args][0])
+                    }
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let (memory, size) = op.memory_access().unwrap();
+                let mut at = Inserter::new(body, block, i);
+                let offset = at.op(
+                    Operator::I32Const {
+                        value: memory.offset,
+                    },
+                    &[],
+                    &[Type::I32],
+                );
+                let size = at.op(Operator::I32Const { value: size as u32 }, &[], &[Type::I32]);
+                let is_store = op.is_store() as u32;
+                let is_store = at.op(Operator::I32Const { value: is_store }, &[], &[Type::I32]);
+                at.op(
+                    Operator::Call {
+                        function_index: check,
+                    },
+                    &[addr, offset, size, is_store],
+                    &[],
+                );
+                i = at.pos + 1;
+            }
+        }
+    }
+    Ok(check)
+}
+
+/// Call imported monitor functions after every `memory.grow` and,
+/// optionally, as an exported allocator returns and as a deallocator
+/// is entered. Monitoring the functions themselves, rather than calls
+/// to their exports, also sees the module's own allocations. Returns
+/// the imports.
+pub fn heap(module: &mut Module, options: &HeapOptions) -> Result<HeapImports> {
+    let monitor = options.monitor.as_str();
+    let sig = signatures::intern(module, &[Type::I32; 3], &[]);
+    let grow = module.add_func_import(monitor, "grow", sig)?;
+    let alloc = match &options.allocator {
+        Some((malloc, free)) => {
+            let sig = signatures::intern(module, &[Type::I32; 2], &[]);
+            let alloc = module.add_func_import(monitor, "alloc", sig)?;
+            let sig = signatures::intern(module, &[Type::I32], &[]);
+            let free_monitor = module.add_func_import(monitor, "free", sig)?;
+            let malloc = allocator_export(module, malloc, &[Type::I32])?;
+            let free = allocator_export(module, free, &[])?;
+            Some((malloc, alloc, free, free_monitor))
+        }
+        None => None,
+    };
+
+    for decl in module.funcs.values_mut() {
+        let body = match decl.body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        for block in body.blocks.iter() {
+            let mut i = 0;
+            while i < body.blocks[block].insts.len() {
+                let inst = body.blocks[block].insts[i];
+                let (mem, delta) = match &body.values[inst] {
+                    ValueDef::Operator(Operator::MemoryGrow { mem }, args, _) => {
+                        (*mem, body.arg_pool[*args][0])
+                    }
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let mut at = Inserter::new(body, block, i + 1);
+                let index = mem.index() as u32;
+                let index = at.op(Operator::I32Const { value: index }, &[], &[Type::I32]);
+                at.op(
+                    Operator::Call {
+                        function_index: grow,
+                    },
+                    &[index, delta, inst],
+                    &[],
+                );
+                i = at.pos;
+            }
+        }
+    }
+
+    if let Some((malloc, alloc, free, free_monitor)) = alloc {
+        let body = module.funcs[malloc].body_mut().unwrap();
+        let size = body.blocks[body.entry].params[0].1;
+        for block in body.blocks.iter() {
+            if let Terminator::Return { values } = &body.blocks[block].terminator {
+                let address = values[0];
+                body.add_op(
+                    block,
+                    Operator::Call {
+                        function_index: alloc,
+                    },
+                    &[size, address],
+                    &[],
+                );
+            }
+        }
+        let body = module.funcs[free].body_mut().unwrap();
+        let entry = body.entry;
+        let address = body.blocks[entry].params[0].1;
+        Inserter::new(body, entry, 0).op(
+            Operator::Call {
+                function_index: free_monitor,
+            },
+            &[address],
+            &[],
+        );
+    }
+    Ok(HeapImports {
+        grow,
+        alloc: alloc.map(|(_, alloc, _, free_monitor)| (alloc, free_monitor)),
+    })
+}
+
+/// The function exported as `name`, which must have a body and take
+/// an `i32` and return `returns`.
+fn allocator_export(module: &Module, name: &str, returns: &[Type]) -> Result<Func> {
+    let func = module
+        .exports
+        .iter()
+        .find_map(|export| match export.kind {
+            ExportKind::Func(func) if export.name == name => Some(func),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No exported function named '{}'", name))?;
+    let sig = &module.signatures[module.funcs[func].sig()];
+    if sig.params != [Type::I32] || sig.returns != returns {
+        bail!("Exported function '{}' has the wrong signature", name);
+    }
+    if module.funcs[func].body().is_none() {
+        bail!("Exported function '{}' has no body", name);
+    }
+    Ok(func)
+}
+
+/// Add heap monitoring to `module` and run its start function and
+/// then the exports in `invoke`, which take no parameters, in the
+/// interpreter, gathering statistics from the monitor.
+pub fn heap_stats(
+    module: &mut Module,
+    options: &HeapOptions,
+    invoke: &[String],
+    fuel: Option<u64>,
+) -> Result<HeapStats> {
+    module.expand_all_funcs()?;
+    heap(module, options)?;
+    let mut funcs = vec![];
+    for name in invoke {
+        let func = module
+            .exports
+            .iter()
+            .find_map(|export| match &export.kind {
+                ExportKind::Func(func) if &export.name == name => Some(*func),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No exported function named '{}'", name))?;
+        if !module.signatures[module.funcs[func].sig()]
+            .params
+            .is_empty()
+        {
+            bail!("Function '{}' takes parameters", name);
+        }
+        funcs.push(func);
+    }
+
+    let mut ctx = InterpContext::new(module)?;
+    ctx.heap = Some(HeapStats::new(&options.monitor));
+    if let Some(fuel) = fuel {
+        ctx.fuel = fuel;
+    }
+    for func in module.start_func.into_iter().chain(funcs) {
+        match ctx.call(module, func, &[]) {
+            InterpResult::Ok(_) => {}
+            InterpResult::Trap(func, block, inst) => {
+                bail!("Trapped in {} at {} instruction {}", func, block, inst)
+            }
+            InterpResult::OutOfFuel => bail!("Ran out of fuel"),
+        }
+    }
+    Ok(ctx.heap.take().unwrap())
+}
+
+/// Inserts operators into a block at a position, advancing past
+/// each.
+struct Inserter<'b> {
+    body: &'b mut FunctionBody,
+    block: Block,
+    pos: usize,
+}
+
+impl<'b> Inserter<'b> {
+    fn new(body: &'b mut FunctionBody, block: Block, pos: usize) -> Inserter<'b> {
+        Inserter { body, block, pos }
+    }
+
+    fn op(&mut self, op: Operator, args: &[Value], tys: &[Type]) -> Value {
+        let value = self.body.add_op(self.block, op, args, tys);
+        let insts = &mut self.body.blocks[self.block].insts;
+        insts.pop();
+        insts.insert(self.pos, value);
+        self.pos += 1;
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Export, ExportKind, FuncDecl, MemoryData, SignatureData, Terminator};
+    use crate::{ConstVal, InterpContext};
+
+    fn module() -> (Module<'static>, Func) {
+        let mut module = Module::empty();
+        module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![],
+        });
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let memory = MemoryArg {
+            align: 2,
+            offset: 8,
+            memory: Memory::new(0),
+        };
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let p = body.blocks[entry].params[0].1;
+        let value = body.add_op(entry, Operator::I32Load { memory }, &[p], &[Type::I32]);
+        body.set_terminator(
+            entry,
+            Terminator::Return {
+                values: vec![value],
+            },
+        );
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "f".to_owned(),
+            kind: ExportKind::Func(f),
+        });
+        (module, f)
+    }
+
+    #[test]
+    fn coverage_counts_blocks() {
+        let (mut module, f) = module();
+        let options = CoverageOptions {
+            memory: Memory::new(0),
+            base: 0x100,
+        };
+        let counters = coverage(&mut module, &options).unwrap();
+        assert_eq!(counters, vec![(f, Block::new(0))]);
+        module.funcs[f].body().unwrap().validate().unwrap();
+
+        // Read the counter back through the function itself.
+        let mut ctx = InterpContext::new(&module).unwrap();
+        for _ in 0..3 {
+            ctx.call(&module, f, &[ConstVal::I32(0x100 - 8)])
+                .ok()
+                .unwrap();
+        }
+        let result = ctx
+            .call(&module, f, &[ConstVal::I32(0x100 - 8)])
+            .ok()
+            .unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(4)]);
+    }
+
+    #[test]
+    fn imports_are_added_before_bodies() {
+        let (mut module, f) = module();
+        let gas = gas(&mut module, &GasOptions::default()).unwrap();
+        let trace = trace(&mut module, &TraceOptions::default()).unwrap();
+        let check = memcheck(&mut module, &MemcheckOptions::default()).unwrap();
+        assert_eq!((gas.index(), trace.index(), check.index()), (0, 1, 2));
+        let f = Func::new(f.index() + 3);
+        assert_eq!(module.exports[0].kind, ExportKind::Func(f));
+        let body = module.funcs[f].body().unwrap();
+        body.validate().unwrap();
+        let calls = body
+            .values
+            .values()
+            .filter_map(|def| match def {
+                ValueDef::Operator(Operator::Call { function_index }, ..) => Some(*function_index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec![gas, trace, check]);
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+    }
+
+    #[test]
+    fn heap_stats_aggregate_monitor_calls() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory 1 2)
+                (global $top (mut i32) (i32.const 1024))
+                (func $malloc (export "malloc") (param i32) (result i32)
+                  (local i32)
+                  global.get $top
+                  local.set 1
+                  global.get $top
+                  local.get 0
+                  i32.add
+                  global.set $top
+                  ;; Grow a page if the allocation ends past memory.
+                  global.get $top
+                  memory.size
+                  i32.const 16
+                  i32.shl
+                  i32.gt_u
+                  if
+                    i32.const 1
+                    memory.grow
+                    drop
+                  end
+                  local.get 1)
+                (func $free (export "free") (param i32))
+                (func (export "run")
+                  i32.const 100
+                  call $malloc
+                  i32.const 70000
+                  call $malloc
+                  drop
+                  call $free
+                  i32.const 0
+                  call $free
+                  i32.const 1234
+                  call $free
+                  i32.const 100
+                  memory.grow
+                  drop))"#,
+        )
+        .unwrap();
+        let options = HeapOptions {
+            allocator: Some(("malloc".to_owned(), "free".to_owned())),
+            ..HeapOptions::default()
+        };
+
+        let mut module = Module::from_wasm_bytes(&bytes, &Default::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let imports = heap(&mut module, &options).unwrap();
+        assert_eq!(
+            imports,
+            HeapImports {
+                grow: Func::new(0),
+                alloc: Some((Func::new(1), Func::new(2))),
+            }
+        );
+        let instrumented = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new()
+            .validate_all(&instrumented)
+            .unwrap();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &Default::default()).unwrap();
+        let stats = heap_stats(&mut module, &options, &["run".to_owned()], None).unwrap();
+        assert_eq!((stats.grows, stats.failed_grows), (2, 1));
+        assert_eq!(stats.pages_grown.into_iter().collect::<Vec<_>>(), [(0, 1)]);
+        assert_eq!((stats.allocs, stats.failed_allocs), (2, 0));
+        assert_eq!((stats.bytes_allocated, stats.largest_alloc), (70100, 70000));
+        // The free of 0 is ignored; 1234 was never allocated.
+        assert_eq!((stats.frees, stats.bad_frees), (2, 1));
+        assert_eq!(stats.live.into_iter().collect::<Vec<_>>(), [(1124, 70000)]);
+        assert_eq!((stats.live_bytes, stats.peak_live_bytes), (70000, 70100));
+    }
+}
@@ -0,0 +1,178 @@
+//! Intrinsics: imported functions with known semantics.
+//!
+//! An import is opaque: nothing is known of what a call to it does,
+//! so the optimizer keeps every such call where it is. An embedder
+//! that provides an import with a simple meaning can declare it an
+//! intrinsic on the `Module` (see `Module::declare_intrinsic()`).
+//! Effect summaries then describe the import by that meaning rather
+//! than as `EffectSummary::unknown()`, and so does everything that
+//! calls it. Given those summaries in `OptOptions::effects`, GVN
+//! deduplicates calls to a pure intrinsic, select formation executes
+//! them speculatively, and constant propagation folds calls to one
+//! that returns a constant.
+
+use crate::interp::ConstVal;
+use crate::ir::{Func, ImportKind, Module};
+use crate::passes::effects::EffectSummary;
+use crate::Type;
+use anyhow::{bail, Result};
+
+/// What an intrinsic does when called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Intrinsic {
+    /// Computes its results from its arguments alone, with no other
+    /// effect, and never traps.
+    Pure,
+    /// Always returns these values, with no other effect.
+    Constant(Vec<ConstVal>),
+    /// Copies memory like `memory.copy`: it may read and write memory,
+    /// and may trap, but has no other effect.
+    MemCopy,
+    /// Has exactly the effects described.
+    Effects(EffectSummary),
+}
+
+impl Intrinsic {
+    /// The effects of a call to the intrinsic.
+    pub fn summary(&self) -> EffectSummary {
+        match self {
+            Intrinsic::Pure | Intrinsic::Constant(..) => EffectSummary::default(),
+            Intrinsic::MemCopy => EffectSummary {
+                reads_mem: true,
+                writes_mem: true,
+                may_trap: true,
+                ..EffectSummary::default()
+            },
+            Intrinsic::Effects(summary) => *summary,
+        }
+    }
+}
+
+/// Declare the function imported as `module_name`.`name` an intrinsic.
+/// Returns the imported function.
+pub fn declare(
+    module: &mut Module,
+    module_name: &str,
+    name: &str,
+    intrinsic: Intrinsic,
+) -> Result<Func> {
+    let Some(func) = module.imports.iter().find_map(|import| match import.kind {
+        ImportKind::Func(func) if import.module == module_name && import.name == name => Some(func),
+        _ => None,
+    }) else {
+        bail!("No function is imported as {}.{}", module_name, name);
+    };
+    if let Intrinsic::Constant(values) = &intrinsic {
+        let sig = module.funcs[func].sig();
+        let types: Vec<Option<Type>> = values.iter().map(|value| value.ty()).collect();
+        let returns: Vec<Option<Type>> = module.signatures[sig]
+            .returns
+            .iter()
+            .map(|&ty| Some(ty))
+            .collect();
+        if types != returns {
+            bail!(
+                "Constant results {:?} do not match the results {:?} of {}.{}",
+                values,
+                module.signatures[sig].returns,
+                module_name,
+                name
+            );
+        }
+    }
+    module.intrinsics.insert(func, intrinsic);
+    Ok(func)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, FunctionBody, Import, SignatureData, Terminator, Value, ValueDef};
+    use crate::passes::basic_opt::OptOptions;
+    use crate::Operator;
+    use std::sync::Arc;
+
+    #[test]
+    fn intrinsic_calls_optimize_like_operators() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let import = |module: &mut Module, name: &str| {
+            let func = module
+                .funcs
+                .push(FuncDecl::Import(sig, format!("env.{}", name)));
+            module.imports.push(Import {
+                module: "env".to_owned(),
+                name: name.to_owned(),
+                kind: ImportKind::Func(func),
+            });
+            func
+        };
+        let hash = import(&mut module, "hash");
+        let answer = import(&mut module, "answer");
+        let log = import(&mut module, "log");
+        assert!(declare(&mut module, "env", "missing", Intrinsic::Pure).is_err());
+        assert!(declare(&mut module, "env", "answer", Intrinsic::Constant(vec![])).is_err());
+        declare(&mut module, "env", "hash", Intrinsic::Pure).unwrap();
+        declare(
+            &mut module,
+            "env",
+            "answer",
+            Intrinsic::Constant(vec![ConstVal::I32(42)]),
+        )
+        .unwrap();
+
+        // hash(x) + hash(x) + answer(x) + log(x) + log(x)
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let call = |body: &mut FunctionBody, func: Func| {
+            body.add_op(
+                entry,
+                Operator::Call {
+                    function_index: func,
+                },
+                &[x],
+                &[Type::I32],
+            )
+        };
+        let calls: Vec<Value> = [hash, hash, answer, log, log]
+            .iter()
+            .map(|&func| call(&mut body, func))
+            .collect();
+        let sum = calls[1..].iter().fold(calls[0], |sum, &value| {
+            body.add_op(entry, Operator::I32Add, &[sum, value], &[Type::I32])
+        });
+        body.set_terminator(entry, Terminator::Return { values: vec![sum] });
+        let f = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let summaries = module.effect_summaries();
+        assert!(summaries.get(hash).is_pure());
+        assert!(!summaries.get(log).is_pure());
+        let options = OptOptions {
+            effects: Some(Arc::new(summaries)),
+            ..OptOptions::default()
+        };
+        module.optimize(&options);
+
+        let body = module.funcs[f].body().unwrap();
+        let count = |func: Func| {
+            body.blocks[entry]
+                .insts
+                .iter()
+                .filter(|&&inst| {
+                    matches!(
+                        body.values[inst],
+                        ValueDef::Operator(Operator::Call { function_index }, ..)
+                            if function_index == func
+                    )
+                })
+                .count()
+        };
+        assert_eq!((count(hash), count(answer), count(log)), (1, 0, 2));
+    }
+}
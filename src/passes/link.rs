@@ -0,0 +1,847 @@
+//! Merging two modules into one.
+//!
+//! `merge()` combines every entity of two modules into a single
+//! module: the first module's entities come first in each index
+//! space, then the second's, except that imports of both come before
+//! any definition, as Wasm requires. Each module is given a name, and
+//! with `MergeOptions::resolve_imports`, an import that one module
+//! makes from the other's name is linked to the other's export of the
+//! same name and kind, so that the merged module no longer imports
+//! it. Signatures are deduplicated afterward.
+//!
+//! Exports of both modules are kept, and must not clash. If both
+//! modules have a start function, the merged module gets a new one
+//! that calls both in order. Global initializers that read a linked
+//! import are lowered into the start function (see
+//! `passes::global_inits`), as constant expressions may only read
+//! imported globals.
+//!
+//! `check_compat()` checks, without merging, that one module's
+//! exports satisfy another's imports, by the rules Wasm applies at
+//! instantiation; `merge()` applies the same rules to the imports it
+//! links.
+
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{
+    Export, ExportKind, FuncDecl, FunctionBody, FunctionBuilder, Import, ImportKind, Module,
+    SignatureData, Type,
+};
+use crate::passes::remap::EntityMap;
+use crate::passes::signatures;
+use crate::{Func, Global, Memory, Signature, Table};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashSet};
+
+/// Options for merging modules.
+#[derive(Clone, Debug)]
+pub struct MergeOptions {
+    /// The names of the two modules, as other modules import from
+    /// them.
+    pub names: [String; 2],
+    /// Link imports from one module's name to the other's exports.
+    pub resolve_imports: bool,
+}
+
+/// The seams between merged modules.
+#[derive(Clone, Debug, Default)]
+pub struct MergeManifest {
+    /// Imports linked to an export of the other module: the import's
+    /// module and name, and the merged entity it now refers to.
+    pub resolved: Vec<(String, String, ExportKind)>,
+    /// Imports that remain, as the module and name imported.
+    pub imports: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for MergeManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (module, name, kind) in &self.resolved {
+            writeln!(f, "resolved {}.{} -> {}", module, name, kind)?;
+        }
+        for (module, name) in &self.imports {
+            writeln!(f, "import {}.{}", module, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// An import that a provider module's exports do not satisfy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The module and name imported.
+    pub module: String,
+    pub name: String,
+    pub kind: MismatchKind,
+}
+
+/// How an import and the export of its name differ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// Nothing is exported under the name.
+    Missing,
+    /// An entity of another kind is exported under the name.
+    Kind {
+        import: &'static str,
+        export: &'static str,
+    },
+    /// The function's signatures differ.
+    Signature {
+        import: SignatureData,
+        export: SignatureData,
+    },
+    /// The global's type or mutability differ.
+    Global {
+        import: (Type, bool),
+        export: (Type, bool),
+    },
+    /// The table's element types differ.
+    TableType { import: Type, export: Type },
+    /// The table's or memory's limits, as `(initial, max)`, are not
+    /// within the import's: the export must be at least as large
+    /// initially, and if the import has a maximum, must have one no
+    /// larger.
+    Limits {
+        import: (u64, Option<u64>),
+        export: (u64, Option<u64>),
+    },
+}
+
+fn kind_name(kind: &ImportKind) -> &'static str {
+    match kind {
+        ImportKind::Func(..) => "function",
+        ImportKind::Table(..) => "table",
+        ImportKind::Global(..) => "global",
+        ImportKind::Memory(..) => "memory",
+    }
+}
+
+fn export_kind_name(kind: &ExportKind) -> &'static str {
+    match kind {
+        ExportKind::Func(..) => "function",
+        ExportKind::Table(..) => "table",
+        ExportKind::Global(..) => "global",
+        ExportKind::Memory(..) => "memory",
+    }
+}
+
+struct DisplaySig<'a>(&'a SignatureData);
+
+impl std::fmt::Display for DisplaySig<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let list = |tys: &[Type]| {
+            tys.iter()
+                .map(|ty| ty.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(
+            f,
+            "({}) -> ({})",
+            list(&self.0.params),
+            list(&self.0.returns)
+        )
+    }
+}
+
+struct DisplayLimits((u64, Option<u64>));
+
+impl std::fmt::Display for DisplayLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            (initial, Some(max)) => write!(f, "{}..={}", initial, max),
+            (initial, None) => write!(f, "{}.. (no maximum)", initial),
+        }
+    }
+}
+
+impl std::fmt::Display for MismatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mutability = |mutable: bool| if mutable { "mutable" } else { "immutable" };
+        match self {
+            MismatchKind::Missing => write!(f, "not exported"),
+            MismatchKind::Kind { import, export } => {
+                write!(f, "imported as a {} but exported as a {}", import, export)
+            }
+            MismatchKind::Signature { import, export } => write!(
+                f,
+                "imported with signature {} but exported with {}",
+                DisplaySig(import),
+                DisplaySig(export)
+            ),
+            MismatchKind::Global { import, export } => write!(
+                f,
+                "imported as {} {} but exported as {} {}",
+                mutability(import.1),
+                import.0,
+                mutability(export.1),
+                export.0
+            ),
+            MismatchKind::TableType { import, export } => write!(
+                f,
+                "imported with element type {} but exported with {}",
+                import, export
+            ),
+            MismatchKind::Limits { import, export } => write!(
+                f,
+                "imported with limits {} but exported with {}",
+                DisplayLimits(*import),
+                DisplayLimits(*export)
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "import {}.{}: {}", self.module, self.name, self.kind)
+    }
+}
+
+/// Why the `export` limits are not within the `import` limits, if
+/// they are not.
+fn limits_mismatch(import: (u64, Option<u64>), export: (u64, Option<u64>)) -> Option<MismatchKind> {
+    let fits = export.0 >= import.0
+        && match (import.1, export.1) {
+            (None, _) => true,
+            (Some(max), Some(export_max)) => export_max <= max,
+            (Some(_), None) => false,
+        };
+    if fits {
+        None
+    } else {
+        Some(MismatchKind::Limits { import, export })
+    }
+}
+
+/// An index space that imports and exports can refer to.
+trait Space: EntityRef + Ord + Default + std::fmt::Debug {
+    fn count(module: &Module) -> usize;
+    fn from_import(kind: &ImportKind) -> Option<Self>;
+    fn from_export(kind: &ExportKind) -> Option<Self>;
+    fn to_export(self) -> ExportKind;
+    /// Why an import of `import` in `a` cannot be linked to `export`
+    /// in `b`, if it cannot.
+    fn mismatch(a: &Module, import: Self, b: &Module, export: Self) -> Option<MismatchKind>;
+}
+
+impl Space for Func {
+    fn count(module: &Module) -> usize {
+        module.funcs.len()
+    }
+    fn from_import(kind: &ImportKind) -> Option<Func> {
+        match kind {
+            ImportKind::Func(func) => Some(*func),
+            _ => None,
+        }
+    }
+    fn from_export(kind: &ExportKind) -> Option<Func> {
+        match kind {
+            ExportKind::Func(func) => Some(*func),
+            _ => None,
+        }
+    }
+    fn to_export(self) -> ExportKind {
+        ExportKind::Func(self)
+    }
+    fn mismatch(a: &Module, import: Func, b: &Module, export: Func) -> Option<MismatchKind> {
+        let import = &a.signatures[a.funcs[import].sig()];
+        let export = &b.signatures[b.funcs[export].sig()];
+        if import == export {
+            None
+        } else {
+            Some(MismatchKind::Signature {
+                import: import.clone(),
+                export: export.clone(),
+            })
+        }
+    }
+}
+
+impl Space for Table {
+    fn count(module: &Module) -> usize {
+        module.tables.len()
+    }
+    fn from_import(kind: &ImportKind) -> Option<Table> {
+        match kind {
+            ImportKind::Table(table) => Some(*table),
+            _ => None,
+        }
+    }
+    fn from_export(kind: &ExportKind) -> Option<Table> {
+        match kind {
+            ExportKind::Table(table) => Some(*table),
+            _ => None,
+        }
+    }
+    fn to_export(self) -> ExportKind {
+        ExportKind::Table(self)
+    }
+    fn mismatch(a: &Module, import: Table, b: &Module, export: Table) -> Option<MismatchKind> {
+        let (import, export) = (&a.tables[import], &b.tables[export]);
+        if import.ty != export.ty {
+            return Some(MismatchKind::TableType {
+                import: import.ty,
+                export: export.ty,
+            });
+        }
+        limits_mismatch((import.initial, import.max), (export.initial, export.max))
+    }
+}
+
+impl Space for Global {
+    fn count(module: &Module) -> usize {
+        module.globals.len()
+    }
+    fn from_import(kind: &ImportKind) -> Option<Global> {
+        match kind {
+            ImportKind::Global(global) => Some(*global),
+            _ => None,
+        }
+    }
+    fn from_export(kind: &ExportKind) -> Option<Global> {
+        match kind {
+            ExportKind::Global(global) => Some(*global),
+            _ => None,
+        }
+    }
+    fn to_export(self) -> ExportKind {
+        ExportKind::Global(self)
+    }
+    fn mismatch(a: &Module, import: Global, b: &Module, export: Global) -> Option<MismatchKind> {
+        let (import, export) = (&a.globals[import], &b.globals[export]);
+        if import.ty == export.ty && import.mutable == export.mutable {
+            None
+        } else {
+            Some(MismatchKind::Global {
+                import: (import.ty, import.mutable),
+                export: (export.ty, export.mutable),
+            })
+        }
+    }
+}
+
+impl Space for Memory {
+    fn count(module: &Module) -> usize {
+        module.memories.len()
+    }
+    fn from_import(kind: &ImportKind) -> Option<Memory> {
+        match kind {
+            ImportKind::Memory(memory) => Some(*memory),
+            _ => None,
+        }
+    }
+    fn from_export(kind: &ExportKind) -> Option<Memory> {
+        match kind {
+            ExportKind::Memory(memory) => Some(*memory),
+            _ => None,
+        }
+    }
+    fn to_export(self) -> ExportKind {
+        ExportKind::Memory(self)
+    }
+    fn mismatch(a: &Module, import: Memory, b: &Module, export: Memory) -> Option<MismatchKind> {
+        let limits = |data: &crate::ir::MemoryData| {
+            (
+                data.initial_pages as u64,
+                data.maximum_pages.map(|max| max as u64),
+            )
+        };
+        limits_mismatch(limits(&a.memories[import]), limits(&b.memories[export]))
+    }
+}
+
+/// Lay out one index space of the merged module. Fills in `maps`
+/// from each input's indices to merged ones, and returns the merged
+/// order as `(input, old index)` pairs. Adds the imports it links to
+/// `linked`, and records them (and what they now refer to) in
+/// `manifest`.
+fn layout<T: Space>(
+    inputs: &[Module; 2],
+    options: &MergeOptions,
+    maps: [&mut PerEntity<T, T>; 2],
+    linked: &mut [HashSet<ImportKind>; 2],
+    manifest: &mut MergeManifest,
+) -> Result<Vec<(usize, T)>> {
+    // Find the imports to link, and their targets.
+    let mut links: [BTreeMap<T, (usize, T)>; 2] = Default::default();
+    let mut imported: [HashSet<T>; 2] = Default::default();
+    for side in 0..2 {
+        let (module, other) = (&inputs[side], &inputs[1 - side]);
+        for import in &module.imports {
+            let entity = match T::from_import(&import.kind) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            imported[side].insert(entity);
+            if !options.resolve_imports || import.module != options.names[1 - side] {
+                continue;
+            }
+            let target = other
+                .exports
+                .iter()
+                .find(|export| export.name == import.name)
+                .and_then(|export| T::from_export(&export.kind));
+            if let Some(target) = target {
+                if let Some(kind) = T::mismatch(module, entity, other, target) {
+                    bail!(
+                        "{}",
+                        Mismatch {
+                            module: import.module.clone(),
+                            name: import.name.clone(),
+                            kind,
+                        }
+                    );
+                }
+                links[side].insert(entity, (1 - side, target));
+            }
+        }
+    }
+
+    // Unlinked imports first, then definitions.
+    let mut order = vec![];
+    for side in 0..2 {
+        for import in &inputs[side].imports {
+            if let Some(entity) = T::from_import(&import.kind) {
+                if !links[side].contains_key(&entity) {
+                    order.push((side, entity));
+                }
+            }
+        }
+    }
+    for side in 0..2 {
+        for i in 0..T::count(&inputs[side]) {
+            let entity = T::new(i);
+            if !imported[side].contains(&entity) {
+                order.push((side, entity));
+            }
+        }
+    }
+    let [map0, map1] = maps;
+    let maps = [map0, map1];
+    for (i, &(side, entity)) in order.iter().enumerate() {
+        maps[side][entity] = T::new(i);
+    }
+
+    // Point linked imports at their (possibly also linked) targets.
+    for side in 0..2 {
+        for import in &inputs[side].imports {
+            let entity = match T::from_import(&import.kind) {
+                Some(entity) if links[side].contains_key(&entity) => entity,
+                _ => continue,
+            };
+            let mut target = links[side][&entity];
+            let mut steps = 0;
+            while let Some(&next) = links[target.0].get(&target.1) {
+                steps += 1;
+                if steps > links[0].len() + links[1].len() {
+                    bail!("Import {}.{} links to itself", import.module, import.name);
+                }
+                target = next;
+            }
+            let merged = maps[target.0][target.1];
+            maps[side][entity] = merged;
+            linked[side].insert(import.kind.clone());
+            manifest.resolved.push((
+                import.module.clone(),
+                import.name.clone(),
+                merged.to_export(),
+            ));
+        }
+    }
+    Ok(order)
+}
+
+/// The imports of `consumer` that the exports of `provider` do not
+/// satisfy, in import order. Every import is checked against the
+/// export of its name, whatever module it names; callers linking only
+/// some import modules to `provider` should filter the result.
+pub fn check_compat(provider: &Module, consumer: &Module) -> Vec<Mismatch> {
+    consumer
+        .imports
+        .iter()
+        .filter_map(|import| {
+            let kind = match provider
+                .exports
+                .iter()
+                .find(|export| export.name == import.name)
+            {
+                None => Some(MismatchKind::Missing),
+                Some(export) => match (&import.kind, &export.kind) {
+                    (&ImportKind::Func(a), &ExportKind::Func(b)) => {
+                        Func::mismatch(consumer, a, provider, b)
+                    }
+                    (&ImportKind::Table(a), &ExportKind::Table(b)) => {
+                        Table::mismatch(consumer, a, provider, b)
+                    }
+                    (&ImportKind::Global(a), &ExportKind::Global(b)) => {
+                        Global::mismatch(consumer, a, provider, b)
+                    }
+                    (&ImportKind::Memory(a), &ExportKind::Memory(b)) => {
+                        Memory::mismatch(consumer, a, provider, b)
+                    }
+                    (import, export) => Some(MismatchKind::Kind {
+                        import: kind_name(import),
+                        export: export_kind_name(export),
+                    }),
+                },
+            };
+            kind.map(|kind| Mismatch {
+                module: import.module.clone(),
+                name: import.name.clone(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+/// Merge `a` and `b` into one module, as described in the module
+/// documentation.
+pub fn merge(
+    a: &Module,
+    b: &Module,
+    options: &MergeOptions,
+) -> Result<(Module<'static>, MergeManifest)> {
+    let mut inputs = [a.clone(), b.clone()];
+    for input in &mut inputs {
+        input.expand_all_funcs()?;
+    }
+    // The second module's signatures follow the first's, so that
+    // its list holds all of them.
+    let offset = inputs[0].signatures.len();
+    let shift = |sig: &mut Signature| *sig = Signature::new(sig.index() + offset);
+    signatures::visit_refs(&mut inputs[1], &mut { shift });
+    let mut sigs = inputs[0].signatures.clone();
+    for mut data in std::mem::take(&mut inputs[1].signatures).into_vec() {
+        for ty in data.params.iter_mut().chain(data.returns.iter_mut()) {
+            if let Type::TypedFuncRef(_, index) = ty {
+                *index += offset as u32;
+            }
+        }
+        sigs.push(data);
+    }
+    inputs[1].signatures = sigs;
+
+    let mut manifest = MergeManifest::default();
+    let mut linked = Default::default();
+    let [mut map0, mut map1] = [EntityMap::default(), EntityMap::default()];
+    let funcs = layout(
+        &inputs,
+        options,
+        [&mut map0.funcs, &mut map1.funcs],
+        &mut linked,
+        &mut manifest,
+    )?;
+    let tables = layout(
+        &inputs,
+        options,
+        [&mut map0.tables, &mut map1.tables],
+        &mut linked,
+        &mut manifest,
+    )?;
+    let globals = layout(
+        &inputs,
+        options,
+        [&mut map0.globals, &mut map1.globals],
+        &mut linked,
+        &mut manifest,
+    )?;
+    let memories = layout(
+        &inputs,
+        options,
+        [&mut map0.memories, &mut map1.memories],
+        &mut linked,
+        &mut manifest,
+    )?;
+    let maps = [map0, map1];
+
+    let mut out = Module::empty();
+    out.signatures = inputs[1].signatures.clone();
+    out.debug = inputs[0].debug.clone();
+    for (side, func) in funcs {
+        let decl = match &inputs[side].funcs[func] {
+            FuncDecl::Import(sig, name) => FuncDecl::Import(*sig, name.clone()),
+            FuncDecl::Body(sig, name, body) => {
+                let mut body = FunctionBody::clone(body);
+                maps[side].body(&mut body);
+                if side == 1 {
+                    reintern_locs(&mut body, &inputs[1], &mut out);
+                }
+                FuncDecl::Body(*sig, name.clone(), body.into())
+            }
+            decl => bail!("Unexpected function {} in merge: {:?}", func, decl),
+        };
+        out.funcs.push(decl);
+    }
+    for (side, table) in tables {
+        let mut data = inputs[side].tables[table].clone();
+        maps[side].table(&mut data);
+        out.tables.push(data);
+    }
+    for (side, global) in globals {
+        let mut data = inputs[side].globals[global].clone();
+        if let Some(init) = &mut data.init {
+            maps[side].init(init);
+        }
+        out.globals.push(data);
+    }
+    for (side, memory) in memories {
+        out.memories.push(inputs[side].memories[memory].clone());
+    }
+
+    // Only unlinked imports remain. Each index space lists them in
+    // the order `layout()` placed them.
+    for side in 0..2 {
+        for import in &inputs[side].imports {
+            if linked[side].contains(&import.kind) {
+                continue;
+            }
+            let mut kind = import.kind.clone();
+            maps[side].import(&mut kind);
+            manifest
+                .imports
+                .push((import.module.clone(), import.name.clone()));
+            out.imports.push(Import {
+                module: import.module.clone(),
+                name: import.name.clone(),
+                kind,
+            });
+        }
+    }
+
+    let mut names = HashSet::new();
+    for side in 0..2 {
+        for export in &inputs[side].exports {
+            if !names.insert(export.name.clone()) {
+                bail!("Both modules export {}", export.name);
+            }
+            let mut kind = export.kind.clone();
+            maps[side].export(&mut kind);
+            out.exports.push(Export {
+                name: export.name.clone(),
+                kind,
+            });
+        }
+    }
+
+    let starts = (0..2)
+        .filter_map(|side| inputs[side].start_func.map(|f| maps[side].funcs[f]))
+        .collect::<Vec<_>>();
+    out.start_func = match &starts[..] {
+        [] => None,
+        &[start] => Some(start),
+        _ => {
+            let sig = signatures::intern(&mut out, &[], &[]);
+            let mut body = FunctionBody::new(&out, sig);
+            let mut builder = FunctionBuilder::new(&mut body);
+            for &start in &starts {
+                builder.call(start, &[], &[]);
+            }
+            builder.ret(&[]);
+            Some(
+                out.funcs
+                    .push(FuncDecl::Body(sig, "merged_start".to_owned(), body.into())),
+            )
+        }
+    };
+
+    // A global that read an import now linked to a definition may
+    // no longer be initialized by a constant expression.
+    out.lower_complex_initializers()?;
+
+    signatures::minimize(&mut out);
+    Ok((out, manifest))
+}
+
+/// Move the source locations of a body from `from`'s debug info into
+/// `to`'s.
+fn reintern_locs(body: &mut FunctionBody, from: &Module, to: &mut Module) {
+    for value in body.values.iter() {
+        let loc = body.source_locs[value];
+        if !loc.is_valid() {
+            continue;
+        }
+        let data = from.debug.source_locs[loc];
+        let file = to.debug.intern_file(&from.debug.source_files[data.file]);
+        body.source_locs[value] = to.debug.intern_loc(file, data.line, data.col);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{SignatureData, Terminator, ValueDef};
+    use crate::{ConstVal, InterpContext, Operator};
+
+    #[test]
+    fn resolves_imports_between_modules() {
+        let i2i = SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        };
+
+        // `a` calls `b.add1`, and also imports `env.log`.
+        let mut a = Module::empty();
+        let sig = a.signatures.push(i2i.clone());
+        let log = a.funcs.push(FuncDecl::Import(sig, "log".to_owned()));
+        let add1 = a.funcs.push(FuncDecl::Import(sig, "add1".to_owned()));
+        for (module, name, func) in [("env", "log", log), ("b", "add1", add1)] {
+            a.imports.push(Import {
+                module: module.to_owned(),
+                name: name.to_owned(),
+                kind: ImportKind::Func(func),
+            });
+        }
+        let mut body = FunctionBody::new(&a, sig);
+        let mut builder = FunctionBuilder::new(&mut body);
+        let x = builder.params()[0];
+        let y = builder.call(add1, &[x], &[Type::I32]);
+        builder.ret(&y);
+        let main = a
+            .funcs
+            .push(FuncDecl::Body(sig, "main".to_owned(), body.into()));
+        a.exports.push(Export {
+            name: "main".to_owned(),
+            kind: ExportKind::Func(main),
+        });
+
+        let mut b = Module::empty();
+        b.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let sig = b.signatures.push(i2i);
+        let mut body = FunctionBody::new(&b, sig);
+        let x = body.blocks[body.entry].params[0].1;
+        let one = body.add_op(
+            body.entry,
+            Operator::I32Const { value: 1 },
+            &[],
+            &[Type::I32],
+        );
+        let sum = body.add_op(body.entry, Operator::I32Add, &[x, one], &[Type::I32]);
+        body.set_terminator(body.entry, Terminator::Return { values: vec![sum] });
+        let add1 = b
+            .funcs
+            .push(FuncDecl::Body(sig, "add1".to_owned(), body.into()));
+        b.exports.push(Export {
+            name: "add1".to_owned(),
+            kind: ExportKind::Func(add1),
+        });
+
+        let options = MergeOptions {
+            names: ["a".to_owned(), "b".to_owned()],
+            resolve_imports: true,
+        };
+        let (merged, manifest) = merge(&a, &b, &options).unwrap();
+        assert_eq!(manifest.imports, vec![("env".to_owned(), "log".to_owned())]);
+        assert_eq!(manifest.resolved.len(), 1);
+        assert_eq!(merged.imports.len(), 1);
+        assert_eq!(merged.signatures.len(), 1);
+        let bytes = merged.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        // `main` now calls `add1` directly.
+        let main = Func::new(1);
+        let calls = merged.funcs[main]
+            .body()
+            .unwrap()
+            .values
+            .values()
+            .any(|def| {
+                matches!(
+                    def,
+                    ValueDef::Operator(Operator::Call { function_index }, ..)
+                        if *function_index == Func::new(2)
+                )
+            });
+        assert!(calls);
+        let mut ctx = InterpContext::new(&merged).unwrap();
+        let result = ctx.call(&merged, main, &[ConstVal::I32(41)]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(42)]);
+    }
+
+    #[test]
+    fn lowers_initializers_reading_linked_globals() {
+        let a = wat::parse_str(
+            r#"(module
+                (global (export "base") i32 (i32.const 5)))"#,
+        )
+        .unwrap();
+        let b = wat::parse_str(
+            r#"(module
+                (import "a" "base" (global i32))
+                (global $copy i32 (global.get 0))
+                (func (export "read") (result i32)
+                  global.get $copy))"#,
+        )
+        .unwrap();
+        let a = Module::from_wasm_bytes(&a[..], &Default::default()).unwrap();
+        let b = Module::from_wasm_bytes(&b[..], &Default::default()).unwrap();
+        let options = MergeOptions {
+            names: ["a".to_owned(), "b".to_owned()],
+            resolve_imports: true,
+        };
+        let (merged, _) = merge(&a, &b, &options).unwrap();
+        assert!(merged.imports.is_empty());
+        let bytes = merged.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        let read = merged
+            .exports
+            .iter()
+            .find_map(|export| match export.kind {
+                ExportKind::Func(func) if export.name == "read" => Some(func),
+                _ => None,
+            })
+            .unwrap();
+        let mut ctx = InterpContext::new(&merged).unwrap();
+        ctx.call(&merged, merged.start_func.unwrap(), &[])
+            .ok()
+            .unwrap();
+        let result = ctx.call(&merged, read, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(5)]);
+    }
+
+    #[test]
+    fn check_compat_reports_mismatches() {
+        let provider = wat::parse_str(
+            r#"(module
+                (func (export "f") (param i32) (result i32) local.get 0)
+                (func (export "g") (param i32) (result i32) local.get 0)
+                (global (export "counter") (mut i32) (i32.const 0))
+                (memory (export "memory") 3 4)
+                (table (export "table") 2 funcref)
+              )"#,
+        )
+        .unwrap();
+        let consumer = wat::parse_str(
+            r#"(module
+                (import "env" "f" (func (param i32) (result i32)))
+                (import "env" "g" (func (param i64) (result i32)))
+                (import "env" "counter" (global i32))
+                (import "env" "memory" (memory 2 8))
+                (import "env" "table" (table 1 10 funcref))
+                (import "env" "missing" (func))
+                (import "env" "f" (global i32))
+              )"#,
+        )
+        .unwrap();
+        let provider = Module::from_wasm_bytes(&provider[..], &Default::default()).unwrap();
+        let consumer = Module::from_wasm_bytes(&consumer[..], &Default::default()).unwrap();
+        // `env.f` as a function matches, and so does the memory: at
+        // least 2 pages initially, and at most 8.
+        let mismatches = check_compat(&provider, &consumer)
+            .iter()
+            .map(|mismatch| mismatch.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mismatches,
+            [
+                "import env.g: imported with signature (i64) -> (i32) but exported with (i32) -> (i32)",
+                "import env.counter: imported as immutable i32 but exported as mutable i32",
+                "import env.table: imported with limits 1..=10 but exported with 2.. (no maximum)",
+                "import env.missing: not exported",
+                "import env.f: imported as a global but exported as a function",
+            ]
+        );
+    }
+}
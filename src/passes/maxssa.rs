@@ -3,16 +3,153 @@
 //! through blockparams. This makes some other transforms easier
 //! because it removes the need to worry about adding blockparams when
 //! mutating the CFG (all possible blockparams are already there!).
+//!
+//! The conversion can be limited to a set of "cut blocks"; see
+//! `CutBlocks` for the usual choices. `minimize_blockparams()` goes
+//! the other way, removing every blockparam that dominance makes
+//! unnecessary, so that tools can move between the two forms.
 
 use crate::cfg::CFGInfo;
 use crate::entity::PerEntity;
 use crate::ir::{Block, FunctionBody, Value, ValueDef};
 use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 
 pub(crate) fn run(body: &mut FunctionBody, cut_blocks: Option<HashSet<Block>>, cfg: &CFGInfo) {
     MaxSSAPass::new(cut_blocks).run(body, cfg);
 }
 
+/// A predicate selecting blocks of a function body.
+pub type BlockPredicate = Arc<dyn Fn(&FunctionBody, &CFGInfo, Block) -> bool + Send + Sync>;
+
+/// The blocks at which a max-SSA conversion passes every live value
+/// through blockparams.
+#[derive(Clone)]
+pub enum CutBlocks {
+    /// Every block: no value is used outside the block that defines
+    /// it.
+    All,
+    /// The header of each loop (the target of each edge that goes
+    /// backward in reverse postorder): values live around a loop are
+    /// carried explicitly, while straight-line code keeps using
+    /// dominating values directly.
+    LoopHeaders,
+    /// The blocks for which the predicate returns true.
+    Predicate(BlockPredicate),
+}
+
+impl std::fmt::Debug for CutBlocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CutBlocks::All => write!(f, "All"),
+            CutBlocks::LoopHeaders => write!(f, "LoopHeaders"),
+            CutBlocks::Predicate(..) => write!(f, "Predicate(..)"),
+        }
+    }
+}
+
+impl CutBlocks {
+    /// The cut set for `body`, in the form `FunctionBody::convert_to_max_ssa()`
+    /// takes.
+    pub fn blocks(&self, body: &FunctionBody, cfg: &CFGInfo) -> Option<HashSet<Block>> {
+        match self {
+            CutBlocks::All => None,
+            CutBlocks::LoopHeaders => Some(loop_headers(body, cfg)),
+            CutBlocks::Predicate(pred) => Some(
+                body.blocks
+                    .iter()
+                    .filter(|&block| pred(body, cfg, block))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The targets of edges that go backward in reverse postorder,
+/// including the entries of irreducible loops.
+pub fn loop_headers(body: &FunctionBody, cfg: &CFGInfo) -> HashSet<Block> {
+    let mut headers = HashSet::new();
+    for (pos, &block) in cfg.rpo.entries() {
+        for &succ in &body.blocks[block].succs {
+            if cfg.rpo_pos[succ].is_some_and(|succ_pos| succ_pos <= pos) {
+                headers.insert(succ);
+            }
+        }
+    }
+    headers
+}
+
+/// Remove every blockparam whose inputs, other than the blockparam
+/// itself, are all one value defined in a block that strictly
+/// dominates the param's block, making the param an alias of that
+/// value. Repeats until no more can be removed, so that the chains of
+/// params a max-SSA conversion threads through loops collapse.
+/// Returns the number of params removed.
+pub fn minimize_blockparams(body: &mut FunctionBody) -> usize {
+    let cfg = CFGInfo::new(body);
+    let mut removed = 0;
+    loop {
+        let mut changed = false;
+        for &block in cfg.rpo.values() {
+            if block == body.entry {
+                continue;
+            }
+            let mut to_remove = vec![];
+            for (i, &(_, param)) in body.blocks[block].params.iter().enumerate() {
+                let mut input = None;
+                let mut unique = true;
+                for (&pred, &pos) in cfg.preds[block].iter().zip(cfg.pred_pos[block].iter()) {
+                    let arg = body.blocks[pred]
+                        .terminator
+                        .visit_target(pos, |target| target.args[i]);
+                    let arg = body.resolve_alias(arg);
+                    if arg != param && *input.get_or_insert(arg) != arg {
+                        unique = false;
+                        break;
+                    }
+                }
+                let Some(input) = input.filter(|_| unique) else {
+                    continue;
+                };
+                let def = cfg.def_block[input];
+                if def != block && cfg.dominates(def, block) {
+                    body.values[param] = ValueDef::Alias(input);
+                    to_remove.push(i);
+                }
+            }
+            if to_remove.is_empty() {
+                continue;
+            }
+            let keep = |i: usize| !to_remove.contains(&i);
+            let params = std::mem::take(&mut body.blocks[block].params);
+            body.blocks[block].params = params
+                .into_iter()
+                .enumerate()
+                .filter(|&(i, _)| keep(i))
+                .map(|(_, param)| param)
+                .collect();
+            for (&pred, &pos) in cfg.preds[block].iter().zip(cfg.pred_pos[block].iter()) {
+                body.blocks[pred].terminator.update_target(pos, |target| {
+                    let args = std::mem::take(&mut target.args);
+                    target.args = args
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(i, _)| keep(i))
+                        .map(|(_, arg)| arg)
+                        .collect();
+                });
+            }
+            removed += to_remove.len();
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+    body.mark_changed();
+    removed
+}
+
 struct MaxSSAPass {
     /// Blocks at which all live values must cross through blockparams
     /// (or if None, then all blocks).
@@ -214,3 +351,92 @@ fn iter_all_same<Item: PartialEq + Eq + Copy, I: Iterator<Item = Item>>(iter: I)
     }
     item
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interp::{ConstVal, InterpContext};
+    use crate::ir::{BlockTarget, FuncDecl, Module, SignatureData, Terminator, Type};
+    use crate::Operator;
+
+    #[test]
+    fn loop_header_cuts_and_minimize() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        // entry: br head(0)
+        // head(i): next = i + x; br_if next < 100, head(next), exit
+        // exit: return next * x
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let (head, exit) = (body.add_block(), body.add_block());
+        let zero = body.add_op(entry, Operator::I32Const { value: 0 }, &[], &[Type::I32]);
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: head,
+                    args: vec![zero],
+                },
+            },
+        );
+        let i = body.add_blockparam(head, Type::I32);
+        let next = body.add_op(head, Operator::I32Add, &[i, x], &[Type::I32]);
+        let limit = body.add_op(head, Operator::I32Const { value: 100 }, &[], &[Type::I32]);
+        let more = body.add_op(head, Operator::I32LtS, &[next, limit], &[Type::I32]);
+        body.set_terminator(
+            head,
+            Terminator::CondBr {
+                cond: more,
+                if_true: BlockTarget {
+                    block: head,
+                    args: vec![next],
+                },
+                if_false: BlockTarget {
+                    block: exit,
+                    args: vec![],
+                },
+            },
+        );
+        let product = body.add_op(exit, Operator::I32Mul, &[next, x], &[Type::I32]);
+        body.set_terminator(
+            exit,
+            Terminator::Return {
+                values: vec![product],
+            },
+        );
+
+        let run = |module: &mut Module, body: &FunctionBody| {
+            body.validate().unwrap();
+            let func = module
+                .funcs
+                .push(FuncDecl::Body(sig, "f".to_owned(), body.clone().into()));
+            let mut ctx = InterpContext::new(module).unwrap();
+            ctx.call(module, func, &[ConstVal::I32(7)]).ok().unwrap()
+        };
+        let params = |body: &FunctionBody| {
+            (
+                body.blocks[head].params.len(),
+                body.blocks[exit].params.len(),
+            )
+        };
+        let expected = run(&mut module, &body);
+
+        let mut loops = body.clone();
+        loops.convert_to_max_ssa_with(&CutBlocks::LoopHeaders);
+        assert_eq!(params(&loops), (2, 0));
+        assert_eq!(run(&mut module, &loops), expected);
+
+        let mut all = body.clone();
+        all.convert_to_max_ssa_with(&CutBlocks::All);
+        assert_eq!(params(&all), (2, 2));
+        assert_eq!(run(&mut module, &all), expected);
+
+        assert_eq!(all.minimize_blockparams(), 3);
+        assert_eq!(params(&all), (1, 0));
+        assert_eq!(run(&mut module, &all), expected);
+    }
+}
@@ -0,0 +1,213 @@
+//! Memory SSA: a lightweight memory-dependence analysis.
+//!
+//! Linear memory is treated as a single SSA value that is threaded
+//! through each function body. Every instruction that may write a
+//! memory (a store, `memory.grow`, a bulk-memory operator, or a call)
+//! defines a new memory state; every instruction that may read one
+//! uses the state reaching it. Where control flow merges different
+//! states, the merge block has a memory phi.
+//!
+//! All memories share one state, so a store to one memory clobbers
+//! loads from every other. This is conservative but keeps the
+//! analysis cheap enough to run before any load/store transform.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::{Block, FunctionBody, Value, ValueDef};
+use crate::{Operator, SideEffect};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// A version of the memory state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemState {
+    /// The state on entry to the function.
+    Entry,
+    /// The merge of different states at the start of a block.
+    Phi(Block),
+    /// The state after the given instruction writes memory.
+    Def(Value),
+}
+
+/// The memory state reaching every memory access in a function body.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySSA {
+    /// The state used (or clobbered) by each instruction that may
+    /// access memory.
+    before: FxHashMap<Value, MemState>,
+    /// The instructions that may write memory.
+    writes: FxHashSet<Value>,
+    /// The state at the start of each reachable block.
+    entry: PerEntity<Block, Option<MemState>>,
+    /// The state at the end of each reachable block.
+    exit: PerEntity<Block, Option<MemState>>,
+}
+
+/// Does `op` possibly read, and possibly write, memory?
+fn mem_access(op: &Operator) -> (bool, bool) {
+    let effects = op.effects();
+    let any = |e: SideEffect| effects.iter().any(|&x| x == e || x == SideEffect::All);
+    (any(SideEffect::ReadMem), any(SideEffect::WriteMem))
+}
+
+impl MemorySSA {
+    /// Compute memory states for `body`. Unreachable blocks are not
+    /// analyzed.
+    pub fn new(body: &FunctionBody, cfg: &CFGInfo) -> MemorySSA {
+        let mut result = MemorySSA::default();
+        // Iterate to a fixpoint: on the first pass, states flowing
+        // around back edges are not yet known and are ignored.
+        loop {
+            let mut changed = false;
+            for &block in cfg.rpo.values() {
+                let mut incoming = cfg.preds[block]
+                    .iter()
+                    .filter_map(|&pred| result.exit[pred])
+                    .collect::<Vec<_>>();
+                if block == body.entry {
+                    incoming.push(MemState::Entry);
+                }
+                let mut state = match incoming.split_first() {
+                    Some((&first, rest)) if rest.iter().all(|&s| s == first) => first,
+                    Some(_) => MemState::Phi(block),
+                    None => continue,
+                };
+                result.entry[block] = Some(state);
+                for &inst in &body.blocks[block].insts {
+                    let (reads, writes) = match &body.values[inst] {
+                        ValueDef::Operator(op, ..) => mem_access(op),
+                        _ => continue,
+                    };
+                    if reads || writes {
+                        result.before.insert(inst, state);
+                    }
+                    if writes {
+                        result.writes.insert(inst);
+                        state = MemState::Def(inst);
+                    }
+                }
+                if result.exit[block] != Some(state) {
+                    result.exit[block] = Some(state);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        result
+    }
+
+    /// The memory state that `inst` reads or overwrites, or `None` if
+    /// it does not access memory (or is unreachable).
+    pub fn state_before(&self, inst: Value) -> Option<MemState> {
+        self.before.get(&inst).copied()
+    }
+
+    /// The memory state after `inst`, or `None` if it does not access
+    /// memory (or is unreachable).
+    pub fn state_after(&self, inst: Value) -> Option<MemState> {
+        if self.writes.contains(&inst) {
+            Some(MemState::Def(inst))
+        } else {
+            self.state_before(inst)
+        }
+    }
+
+    /// The state at the start of `block`.
+    pub fn entry_state(&self, block: Block) -> Option<MemState> {
+        self.entry[block]
+    }
+
+    /// The state at the end of `block`.
+    pub fn exit_state(&self, block: Block) -> Option<MemState> {
+        self.exit[block]
+    }
+
+    /// The single instruction whose memory write the access `load`
+    /// observes, if there is one: the last store (or call, etc.)
+    /// before it on every path. Returns `None` if the reaching state
+    /// is the function entry or a merge of several writes.
+    pub fn last_store_reaching(&self, load: Value) -> Option<Value> {
+        match self.state_before(load)? {
+            MemState::Def(store) => Some(store),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, Module, SignatureData, Terminator, Type};
+    use crate::{Memory, MemoryArg};
+
+    #[test]
+    fn states_through_diamond() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let memory = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory: Memory::from(0),
+        };
+        let load = Operator::I32Load { memory };
+        let store = Operator::I32Store { memory };
+
+        let l0 = body.add_op(entry, load, &[x], &[Type::I32]);
+        let s0 = body.add_op(entry, store, &[x, l0], &[]);
+        let l1 = body.add_op(entry, load, &[x], &[Type::I32]);
+        let (left, right, join) = (body.add_block(), body.add_block(), body.add_block());
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: x,
+                if_true: BlockTarget {
+                    block: left,
+                    args: vec![],
+                },
+                if_false: BlockTarget {
+                    block: right,
+                    args: vec![],
+                },
+            },
+        );
+        let s1 = body.add_op(left, store, &[x, x], &[]);
+        body.set_terminator(
+            left,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: vec![],
+                },
+            },
+        );
+        body.set_terminator(
+            right,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: vec![],
+                },
+            },
+        );
+        let l2 = body.add_op(join, load, &[x], &[Type::I32]);
+        body.set_terminator(join, Terminator::Return { values: vec![l2] });
+
+        let cfg = CFGInfo::new(&body);
+        let mssa = MemorySSA::new(&body, &cfg);
+        assert_eq!(mssa.state_before(l0), Some(MemState::Entry));
+        assert_eq!(mssa.last_store_reaching(l1), Some(s0));
+        assert_eq!(mssa.state_before(s1), Some(MemState::Def(s0)));
+        assert_eq!(mssa.state_after(s1), Some(MemState::Def(s1)));
+        assert_eq!(mssa.exit_state(right), Some(MemState::Def(s0)));
+        assert_eq!(mssa.state_before(l2), Some(MemState::Phi(join)));
+        assert_eq!(mssa.last_store_reaching(l2), None);
+        assert_eq!(mssa.state_before(x), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Blockparam type narrowing.
+//!
+//! A blockparam's declared type must be a supertype of every value
+//! passed to it, but it may be more general than needed: the frontend
+//! gives each one the type of a Wasm block result or local, and
+//! transforms that merge values keep the widest type at hand. This
+//! pass narrows each param's reference type to the least upper bound
+//! of its inputs, so that later uses see the more precise signature
+//! and casts and checks against it can be folded.
+//!
+//! Nullability is never narrowed: the backend keeps blockparams in
+//! locals, and a local of non-nullable type must be set before every
+//! read, which the backend does not guarantee. `passes::nullability`
+//! derives non-nullness from the inputs instead.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::{FunctionBody, Type, Value, ValueDef};
+
+/// The least upper bound of the types of the inputs to each blockparam
+/// of a reachable block other than the entry, ignoring inputs of the
+/// param itself. A param has no entry if its inputs have no common
+/// supertype.
+pub fn input_lubs(body: &FunctionBody, cfg: &CFGInfo) -> PerEntity<Value, Option<Type>> {
+    let mut lubs = PerEntity::default();
+    for &block in cfg.rpo.values() {
+        if block == body.entry {
+            continue;
+        }
+        for (i, &(_, param)) in body.blocks[block].params.iter().enumerate() {
+            let mut lub: Option<Option<Type>> = None;
+            for (&pred, &pos) in cfg.preds[block].iter().zip(cfg.pred_pos[block].iter()) {
+                let arg = body.blocks[pred]
+                    .terminator
+                    .visit_target(pos, |target| target.args[i]);
+                let arg = body.resolve_alias(arg);
+                if arg == param {
+                    continue;
+                }
+                let ty = body.values[arg].ty(&body.type_pool);
+                lub = Some(match lub {
+                    None => ty,
+                    Some(lub) => lub.zip(ty).and_then(|(a, b)| a.lub(b)),
+                });
+            }
+            lubs[param] = lub.flatten();
+        }
+    }
+    lubs
+}
+
+/// Narrow blockparam types as described in the module documentation,
+/// repeating until no more can be narrowed, as params feed other
+/// params. Returns the number of times a param was narrowed.
+pub fn run(body: &mut FunctionBody) -> usize {
+    let cfg = CFGInfo::new(body);
+    let mut narrowed = 0;
+    loop {
+        let lubs = input_lubs(body, &cfg);
+        let mut changed = false;
+        for &block in cfg.rpo.values() {
+            for i in 0..body.blocks[block].params.len() {
+                let (ty, param) = body.blocks[block].params[i];
+                let new_ty = match (lubs[param], ty) {
+                    (Some(Type::TypedFuncRef(false, sig)), Type::FuncRef)
+                    | (Some(Type::TypedFuncRef(false, sig)), Type::TypedFuncRef(true, _)) => {
+                        Type::TypedFuncRef(true, sig)
+                    }
+                    (Some(lub), _) => lub,
+                    (None, _) => continue,
+                };
+                if new_ty == ty || !new_ty.is_subtype_of(ty) {
+                    continue;
+                }
+                body.blocks[block].params[i].0 = new_ty;
+                if let ValueDef::BlockParam(_, _, param_ty) = &mut body.values[param] {
+                    *param_ty = new_ty;
+                }
+                narrowed += 1;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    body.mark_changed();
+    narrowed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, Module, SignatureData, Terminator};
+    use crate::Operator;
+
+    #[test]
+    fn params_narrow_to_input_lub() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::TypedFuncRef(true, 0)],
+            returns: vec![Type::FuncRef],
+        });
+        // entry(c, f): br_if c, join(f), other
+        // other: g = ref.null; br join(g)
+        // join(p: funcref): return p
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let c = body.blocks[entry].params[0].1;
+        let f = body.blocks[entry].params[1].1;
+        let (other, join) = (body.add_block(), body.add_block());
+        let p = body.add_blockparam(join, Type::FuncRef);
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: c,
+                if_true: BlockTarget {
+                    block: join,
+                    args: vec![f],
+                },
+                if_false: BlockTarget {
+                    block: other,
+                    args: vec![],
+                },
+            },
+        );
+        let g = body.add_op(
+            other,
+            Operator::RefNull { sig_index: sig },
+            &[],
+            &[Type::TypedFuncRef(true, 0)],
+        );
+        body.set_terminator(
+            other,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: vec![g],
+                },
+            },
+        );
+        body.set_terminator(join, Terminator::Return { values: vec![p] });
+
+        let cfg = CFGInfo::new(&body);
+        assert_eq!(
+            input_lubs(&body, &cfg)[p],
+            Some(Type::TypedFuncRef(true, 0))
+        );
+        assert_eq!(run(&mut body), 1);
+        assert_eq!(body.blocks[join].params[0].0, Type::TypedFuncRef(true, 0));
+        assert_eq!(
+            body.values[p],
+            ValueDef::BlockParam(join, 0, Type::TypedFuncRef(true, 0))
+        );
+        assert!(body.typecheck(&module).errors.is_empty());
+        assert_eq!(run(&mut body), 0);
+    }
+}
@@ -0,0 +1,102 @@
+//! Null-check elimination.
+//!
+//! Replaces `ref.is_null` of a reference whose nullness is known at
+//! that point (see `NullabilityAnalysis`) with a constant, so that the
+//! check and any branch on it can be folded away. A reference tested
+//! once is thereby known in every block the test dominates.
+//!
+//! The IR has no `ref.as_non_null` or cast operators; `ref.is_null`
+//! is the only null check there is to remove.
+
+use crate::cfg::CFGInfo;
+use crate::ir::{FunctionBody, Type, ValueDef};
+use crate::passes::nullability::{NullabilityAnalysis, Nullness};
+use crate::pool::ListRef;
+use crate::Operator;
+
+/// Fold redundant null checks in `body`. Returns the number of checks
+/// removed.
+pub fn run(body: &mut FunctionBody, cfg: &CFGInfo) -> usize {
+    let analysis = NullabilityAnalysis::new(body, cfg);
+    let mut folded = 0;
+    for &block in cfg.rpo.values() {
+        for i in 0..body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            let tested = match &body.values[inst] {
+                ValueDef::Operator(Operator::RefIsNull, args, _) => body.arg_pool[*args][0],
+                _ => continue,
+            };
+            let value = match analysis.nullness_at(body, tested, block) {
+                Some(Nullness::Null) => 1,
+                Some(Nullness::NonNull) => 0,
+                _ => continue,
+            };
+            body.values[inst] = ValueDef::Operator(
+                Operator::I32Const { value },
+                ListRef::default(),
+                body.single_type_list(Type::I32),
+            );
+            folded += 1;
+        }
+    }
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, Module, SignatureData, Terminator};
+
+    #[test]
+    fn repeated_check_is_folded() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::FuncRef],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let arg = body.blocks[entry].params[0].1;
+        let first = body.add_op(entry, Operator::RefIsNull, &[arg], &[Type::I32]);
+        let (yes, no) = (body.add_block(), body.add_block());
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: first,
+                if_true: BlockTarget {
+                    block: yes,
+                    args: vec![],
+                },
+                if_false: BlockTarget {
+                    block: no,
+                    args: vec![],
+                },
+            },
+        );
+        body.set_terminator(
+            yes,
+            Terminator::Return {
+                values: vec![first],
+            },
+        );
+        let second = body.add_op(no, Operator::RefIsNull, &[arg], &[Type::I32]);
+        body.set_terminator(
+            no,
+            Terminator::Return {
+                values: vec![second],
+            },
+        );
+
+        let cfg = CFGInfo::new(&body);
+        assert_eq!(run(&mut body, &cfg), 1);
+        assert!(matches!(
+            body.values[first],
+            ValueDef::Operator(Operator::RefIsNull, ..)
+        ));
+        assert!(matches!(
+            body.values[second],
+            ValueDef::Operator(Operator::I32Const { value: 0 }, ..)
+        ));
+        body.validate().unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Nullability analysis for reference values.
+//!
+//! Determines, for every reference-typed value in a function body,
+//! whether it is provably null, provably non-null, or unknown. Facts
+//! come from `ref.null` and `ref.func`, from non-nullable typed
+//! references, from agreement of all inputs to a blockparam, and, at
+//! a particular point, from dominating branches on `ref.is_null`.
+//! These facts let transforms drop redundant null checks.
+//!
+//! The IR has no GC allocation operators, so there is no escape
+//! analysis yet: every reference is a function reference, and those
+//! are never allocated by the function itself.
+
+use crate::cfg::CFGInfo;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{Block, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::Operator;
+use smallvec::SmallVec;
+
+/// What is known about whether a reference is null.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Nullness {
+    /// The reference is always null.
+    Null,
+    /// The reference is never null.
+    NonNull,
+    /// The reference may or may not be null.
+    Unknown,
+}
+
+impl Nullness {
+    /// The nullness of a value that may be either `self` or `other`.
+    pub fn join(self, other: Nullness) -> Nullness {
+        if self == other {
+            self
+        } else {
+            Nullness::Unknown
+        }
+    }
+}
+
+/// Nullness of every reference value in a function body.
+#[derive(Clone, Debug)]
+pub struct NullabilityAnalysis {
+    /// The nullness of each reference value, independent of program
+    /// point.
+    facts: PerEntity<Value, Option<Nullness>>,
+    /// Facts that hold throughout a block and the blocks it
+    /// dominates, learned from the branch that is the block's only
+    /// way in.
+    refined: PerEntity<Block, SmallVec<[(Value, Nullness); 1]>>,
+    /// Immediate dominator of each block.
+    idom: PerEntity<Block, Block>,
+}
+
+impl NullabilityAnalysis {
+    /// Compute nullness for all reference values in `body`.
+    pub fn new(body: &FunctionBody, cfg: &CFGInfo) -> NullabilityAnalysis {
+        let mut analysis = NullabilityAnalysis {
+            facts: PerEntity::default(),
+            refined: PerEntity::default(),
+            idom: cfg.domtree.clone(),
+        };
+
+        // Iterate to a fixpoint, optimistically ignoring blockparam
+        // inputs that have not been computed yet (along backedges).
+        loop {
+            let mut changed = false;
+            for &block in cfg.rpo.values() {
+                for (i, &(ty, param)) in body.blocks[block].params.iter().enumerate() {
+                    if !ty.is_ref() {
+                        continue;
+                    }
+                    let mut fact = match ty {
+                        Type::TypedFuncRef(false, _) => Some(Nullness::NonNull),
+                        _ if block == body.entry => Some(Nullness::Unknown),
+                        _ => None,
+                    };
+                    if fact != Some(Nullness::NonNull) {
+                        for (&pred, &pos) in cfg.preds[block].iter().zip(cfg.pred_pos[block].iter())
+                        {
+                            let input = body.blocks[pred]
+                                .terminator
+                                .visit_target(pos, |target| target.args[i]);
+                            if let Some(input) = analysis.facts[body.resolve_alias(input)] {
+                                fact = Some(fact.map_or(input, |fact| fact.join(input)));
+                            }
+                        }
+                    }
+                    changed |= analysis.facts[param] != fact;
+                    analysis.facts[param] = fact;
+                }
+                for &inst in &body.blocks[block].insts {
+                    let fact = analysis.compute(body, inst);
+                    changed |= analysis.facts[inst] != fact;
+                    analysis.facts[inst] = fact;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for &block in cfg.rpo.values() {
+            if let Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } = &body.blocks[block].terminator
+            {
+                let tested = match &body.values[body.resolve_alias(*cond)] {
+                    ValueDef::Operator(Operator::RefIsNull, args, _) => {
+                        body.resolve_alias(body.arg_pool[*args][0])
+                    }
+                    _ => continue,
+                };
+                if if_true.block == if_false.block {
+                    continue;
+                }
+                for (target, fact) in [
+                    (if_true.block, Nullness::Null),
+                    (if_false.block, Nullness::NonNull),
+                ] {
+                    if cfg.preds[target].len() == 1 && target != body.entry {
+                        analysis.refined[target].push((tested, fact));
+                    }
+                }
+            }
+        }
+
+        analysis
+    }
+
+    /// The nullness of `value` anywhere it is defined, or `None` if it
+    /// is not a reference (or is unreachable). `value` must not be an
+    /// alias.
+    pub fn nullness_of(&self, value: Value) -> Option<Nullness> {
+        self.facts[value]
+    }
+
+    /// The nullness of `value` at a use in `block`, refined by
+    /// dominating `ref.is_null` branches.
+    pub fn nullness_at(&self, body: &FunctionBody, value: Value, block: Block) -> Option<Nullness> {
+        let value = body.resolve_alias(value);
+        let fact = self.facts[value]?;
+        if fact != Nullness::Unknown {
+            return Some(fact);
+        }
+        let mut block = block;
+        while block.is_valid() {
+            if let Some(&(_, fact)) = self.refined[block].iter().find(|(v, _)| *v == value) {
+                return Some(fact);
+            }
+            let parent = self.idom[block];
+            if parent == block {
+                break;
+            }
+            block = parent;
+        }
+        Some(fact)
+    }
+
+    /// Is `value` provably non-null at a use in `block`?
+    pub fn is_non_null_at(&self, body: &FunctionBody, value: Value, block: Block) -> bool {
+        self.nullness_at(body, value, block) == Some(Nullness::NonNull)
+    }
+
+    fn compute(&self, body: &FunctionBody, inst: Value) -> Option<Nullness> {
+        let (op, args, ty) = match &body.values[inst] {
+            ValueDef::Operator(op, args, tys) if tys.len() == 1 => {
+                (op, &body.arg_pool[*args], body.type_pool[*tys][0])
+            }
+            _ => return None,
+        };
+        if !ty.is_ref() {
+            return None;
+        }
+        Some(match op {
+            Operator::RefNull { .. } => Nullness::Null,
+            Operator::RefFunc { .. } => Nullness::NonNull,
+            _ if matches!(ty, Type::TypedFuncRef(false, _)) => Nullness::NonNull,
+            Operator::Select | Operator::TypedSelect { .. } => {
+                let fact = |v: Value| self.facts[body.resolve_alias(v)];
+                match (fact(args[0]), fact(args[1])) {
+                    (Some(a), Some(b)) => a.join(b),
+                    (Some(a), None) | (None, Some(a)) => a,
+                    (None, None) => Nullness::Unknown,
+                }
+            }
+            _ => Nullness::Unknown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{BlockTarget, Module, SignatureData};
+    use crate::{Func, Signature};
+
+    #[test]
+    fn nullness_through_params_and_branches() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::FuncRef],
+            returns: vec![Type::FuncRef],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let arg = body.blocks[entry].params[0].1;
+        let null = Operator::RefNull {
+            sig_index: Signature::invalid(),
+        };
+        let null = body.add_op(entry, null, &[], &[Type::FuncRef]);
+        let func = Operator::RefFunc {
+            func_index: Func::new(0),
+        };
+        let func = body.add_op(entry, func, &[], &[Type::FuncRef]);
+        let is_null = body.add_op(entry, Operator::RefIsNull, &[arg], &[Type::I32]);
+
+        // Both arms pass `func` to `join`; only one passes `null`.
+        let (yes, no, join) = (body.add_block(), body.add_block(), body.add_block());
+        let p = body.add_blockparam(join, Type::FuncRef);
+        let q = body.add_blockparam(join, Type::FuncRef);
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: is_null,
+                if_true: BlockTarget {
+                    block: yes,
+                    args: vec![],
+                },
+                if_false: BlockTarget {
+                    block: no,
+                    args: vec![],
+                },
+            },
+        );
+        body.set_terminator(
+            yes,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: vec![func, null],
+                },
+            },
+        );
+        body.set_terminator(
+            no,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: vec![func, arg],
+                },
+            },
+        );
+        body.set_terminator(join, Terminator::Return { values: vec![p] });
+
+        let cfg = CFGInfo::new(&body);
+        let analysis = NullabilityAnalysis::new(&body, &cfg);
+        assert_eq!(analysis.nullness_of(null), Some(Nullness::Null));
+        assert_eq!(analysis.nullness_of(p), Some(Nullness::NonNull));
+        assert_eq!(analysis.nullness_of(q), Some(Nullness::Unknown));
+        assert_eq!(analysis.nullness_of(is_null), None);
+        assert_eq!(
+            analysis.nullness_at(&body, arg, entry),
+            Some(Nullness::Unknown)
+        );
+        assert_eq!(analysis.nullness_at(&body, arg, yes), Some(Nullness::Null));
+        assert!(analysis.is_non_null_at(&body, arg, no));
+        assert!(!analysis.is_non_null_at(&body, arg, join));
+    }
+}
@@ -0,0 +1,167 @@
+//! Opcode histograms: how often each operator occurs in a module.
+//!
+//! Functions still in bytecode form (lazy or compiled) are streamed
+//! with wasmparser's operator reader, without expanding them, so a
+//! histogram of a freshly parsed module is cheap. Expanded bodies are
+//! counted by their IR operators instead, which leave out what the IR
+//! has no operator for: locals, structured control flow, and `drop`.
+//! Both are keyed by operator name as in wasmparser's `Operator`
+//! (`I32Add`, `LocalGet`), which the IR's operator names follow.
+
+use crate::ir::{FuncDecl, Module, ValueDef};
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// How often each operator occurs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpcodeHistogram {
+    /// The count of each operator, by name.
+    pub counts: BTreeMap<String, u64>,
+    /// How many functions were scanned as bytecode.
+    pub bytecode_funcs: usize,
+    /// How many functions were scanned as IR.
+    pub ir_funcs: usize,
+}
+
+impl OpcodeHistogram {
+    /// Count the operators of every function body in `module`.
+    pub fn compute(module: &Module) -> Result<OpcodeHistogram> {
+        let mut histogram = OpcodeHistogram::default();
+        module.visit_lazy_funcs(|_, ops| histogram.add_bytecode(ops))?;
+        for decl in module.funcs.values() {
+            match decl {
+                FuncDecl::Body(_, _, body) => {
+                    histogram.ir_funcs += 1;
+                    for block in body.blocks.values() {
+                        for &inst in &block.insts {
+                            if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                                let name = format!("{:?}", op);
+                                let len = name
+                                    .find(|c: char| !c.is_ascii_alphanumeric())
+                                    .unwrap_or(name.len());
+                                histogram.add(&name[..len], 1);
+                            }
+                        }
+                    }
+                }
+                FuncDecl::Compiled(_, _, bytes) => {
+                    let reader = wasmparser::BinaryReader::new(
+                        &bytes[..],
+                        0,
+                        wasmparser::WasmFeatures::all(),
+                    );
+                    let ops = wasmparser::FunctionBody::new(reader).get_operators_reader()?;
+                    histogram.add_bytecode(ops)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(histogram)
+    }
+
+    fn add_bytecode(&mut self, ops: wasmparser::OperatorsReader) -> Result<()> {
+        self.bytecode_funcs += 1;
+        for op in ops {
+            self.add(operator_name(&op?), 1);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, count: u64) {
+        match self.counts.get_mut(name) {
+            Some(total) => *total += count,
+            None => {
+                self.counts.insert(name.to_owned(), count);
+            }
+        }
+    }
+
+    /// Add the counts of `other`, as when summing over a corpus.
+    pub fn merge(&mut self, other: &OpcodeHistogram) {
+        for (name, &count) in &other.counts {
+            self.add(name, count);
+        }
+        self.bytecode_funcs += other.bytecode_funcs;
+        self.ir_funcs += other.ir_funcs;
+    }
+
+    /// The number of operators counted.
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The operators, most frequent first; equally frequent ones by
+    /// name.
+    pub fn by_frequency(&self) -> Vec<(&str, u64)> {
+        let mut counts = self
+            .counts
+            .iter()
+            .map(|(name, &count)| (&name[..], count))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+}
+
+macro_rules! define_operator_name {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        /// The name of a bytecode operator.
+        fn operator_name(op: &wasmparser::Operator) -> &'static str {
+            match op {
+                $(wasmparser::Operator::$op { .. } => stringify!($op),)*
+            }
+        }
+    };
+}
+
+wasmparser::for_each_operator!(define_operator_name);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+    use crate::{FrontendOptions, Func};
+
+    #[test]
+    fn counts_lazy_and_expanded_bodies() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 1
+                  i32.add
+                  i32.const 2
+                  i32.add)
+                (func (param i32) (result i32)
+                  local.get 0
+                  i32.const 3
+                  i32.mul))"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let lazy = module.opcode_histogram().unwrap();
+        assert_eq!(lazy.bytecode_funcs, 2);
+        assert_eq!(
+            lazy.by_frequency(),
+            vec![
+                ("I32Const", 3),
+                ("End", 2),
+                ("I32Add", 2),
+                ("LocalGet", 2),
+                ("I32Mul", 1),
+            ]
+        );
+
+        // As IR, the second function has no `local.get` or `end`.
+        module.expand_func(Func::new(1)).unwrap();
+        let mixed = module.opcode_histogram().unwrap();
+        assert_eq!((mixed.bytecode_funcs, mixed.ir_funcs), (1, 1));
+        assert_eq!(mixed.counts["I32Mul"], 1);
+        assert_eq!(mixed.counts["LocalGet"], 1);
+        assert_eq!(mixed.total(), 8);
+
+        let mut corpus = lazy.clone();
+        corpus.merge(&lazy);
+        assert_eq!(corpus.total(), 2 * lazy.total());
+    }
+}
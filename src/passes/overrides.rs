@@ -0,0 +1,142 @@
+//! Per-function overrides of module-wide optimization settings.
+//!
+//! When one function miscompiles, or needs special treatment, it is
+//! useful to change how the pipeline handles just that function:
+//! skip optimizing it, optimize it with other options, or force it
+//! into (or out of) maximal SSA. `FuncOverrides`, attached to a
+//! `Module`, matches functions by index or by name pattern, and the
+//! module-wide pipeline entry points (`Module::optimize()`,
+//! `Module::convert_to_max_ssa()`, and
+//! `Module::per_func_body_with_overrides()`) consult it.
+
+use crate::ir::Func;
+use crate::passes::basic_opt::OptOptions;
+
+/// Which functions an override applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FuncMatcher {
+    /// One function, by index.
+    Func(Func),
+    /// Functions whose names match a pattern, in which `*` matches
+    /// any run of characters and `?` any one character.
+    Name(String),
+}
+
+impl FuncMatcher {
+    /// Does this matcher match the function `func` named `name`?
+    pub fn matches(&self, func: Func, name: &str) -> bool {
+        match self {
+            FuncMatcher::Func(f) => *f == func,
+            FuncMatcher::Name(pattern) => glob_match(pattern.as_bytes(), name.as_bytes()),
+        }
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((&c, rest)) => match name.split_first() {
+            Some((&n, name)) if c == b'?' || c == n => glob_match(rest, name),
+            _ => false,
+        },
+    }
+}
+
+/// Settings that replace the module-wide ones for some functions.
+#[derive(Clone, Debug, Default)]
+pub struct FuncOverride {
+    /// Do not optimize these functions at all.
+    pub no_opt: bool,
+    /// Optimize these functions with these options instead.
+    pub opt_options: Option<OptOptions>,
+    /// Convert these functions to maximal SSA (`Some(true)`), or
+    /// never (`Some(false)`), whatever the module-wide setting.
+    pub max_ssa: Option<bool>,
+}
+
+/// An ordered list of per-function overrides. The first rule that
+/// matches a function applies to it.
+#[derive(Clone, Debug, Default)]
+pub struct FuncOverrides {
+    rules: Vec<(FuncMatcher, FuncOverride)>,
+}
+
+impl FuncOverrides {
+    /// Add a rule, after those already present.
+    pub fn add(&mut self, matcher: FuncMatcher, over: FuncOverride) {
+        self.rules.push((matcher, over));
+    }
+
+    /// Are there no rules?
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The override for the function `func` named `name`, if any.
+    pub fn get(&self, func: Func, name: &str) -> Option<&FuncOverride> {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(func, name))
+            .map(|(_, over)| over)
+    }
+
+    /// The options to optimize `func` with, given the module-wide
+    /// `options`, or `None` if it is not to be optimized.
+    pub fn opt_options<'o>(
+        &'o self,
+        func: Func,
+        name: &str,
+        options: &'o OptOptions,
+    ) -> Option<&'o OptOptions> {
+        match self.get(func, name) {
+            Some(over) if over.no_opt => None,
+            Some(over) => Some(over.opt_options.as_ref().unwrap_or(options)),
+            None => Some(options),
+        }
+    }
+
+    /// Whether to convert `func` to maximal SSA, given the
+    /// module-wide setting `max_ssa`.
+    pub fn max_ssa(&self, func: Func, name: &str, max_ssa: bool) -> bool {
+        self.get(func, name)
+            .and_then(|over| over.max_ssa)
+            .unwrap_or(max_ssa)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let mut overrides = FuncOverrides::default();
+        let (f0, f1, f2) = (Func::new(0), Func::new(1), Func::new(2));
+        overrides.add(
+            FuncMatcher::Func(f0),
+            FuncOverride {
+                max_ssa: Some(true),
+                ..FuncOverride::default()
+            },
+        );
+        overrides.add(
+            FuncMatcher::Name("hot_*_v?".to_owned()),
+            FuncOverride {
+                no_opt: true,
+                max_ssa: Some(false),
+                ..FuncOverride::default()
+            },
+        );
+        let options = OptOptions::default();
+        assert!(overrides.opt_options(f0, "hot_loop_v2", &options).is_some());
+        assert!(overrides.opt_options(f1, "hot_loop_v2", &options).is_none());
+        assert!(overrides
+            .opt_options(f1, "hot_loop_v10", &options)
+            .is_some());
+        assert!(overrides.max_ssa(f0, "", false));
+        assert!(!overrides.max_ssa(f1, "hot__v1", true));
+        assert!(overrides.max_ssa(f2, "cold", true));
+    }
+}
@@ -0,0 +1,154 @@
+//! Dumping and verifying IR between optimization passes.
+//!
+//! Finding which pass miscompiles a function is much easier with the
+//! function's IR in hand after every pass. A `PassDebug` in
+//! `OptOptions::pass_debug` makes `FunctionBody::optimize()` write
+//! the IR of selected functions to a directory after each pass, one
+//! file per pass, and optionally validate it, panicking with the
+//! pass's name at the first invalid result.
+//!
+//! It can also be configured from the environment, so that a tool
+//! built on waffle can be debugged without changing it:
+//!
+//! - `WAFFLE_PASS_DUMP=<dir>` enables dumping into `<dir>`;
+//! - `WAFFLE_PASS_DUMP_FUNCS=<pattern>,...` restricts it to functions
+//!   whose names match one of the patterns (see `FuncMatcher::Name`);
+//! - `WAFFLE_PASS_VERIFY=1` validates the IR after each pass.
+
+use crate::ir::{Func, FunctionBody};
+use crate::passes::overrides::FuncMatcher;
+use std::path::PathBuf;
+
+/// Where and for which functions to dump IR between passes.
+#[derive(Clone, Debug, Default)]
+pub struct PassDebug {
+    /// The directory to write dumps to, if any. Files are named
+    /// `<func>.<seq>.<pass>.waffle`, where `<seq>` numbers the passes
+    /// run on the function, starting with `00.input` for its IR
+    /// before optimization.
+    pub dump_dir: Option<PathBuf>,
+    /// The functions to dump. Empty means all.
+    pub funcs: Vec<FuncMatcher>,
+    /// Validate the IR after each pass.
+    pub verify: bool,
+}
+
+impl PassDebug {
+    /// The configuration given by the environment, if any (see the
+    /// module documentation).
+    pub fn from_env() -> Option<PassDebug> {
+        let dump_dir = std::env::var_os("WAFFLE_PASS_DUMP").map(PathBuf::from);
+        let verify = std::env::var("WAFFLE_PASS_VERIFY").is_ok_and(|v| v == "1");
+        if dump_dir.is_none() && !verify {
+            return None;
+        }
+        let funcs = std::env::var("WAFFLE_PASS_DUMP_FUNCS")
+            .map(|patterns| {
+                patterns
+                    .split(',')
+                    .filter(|p| !p.is_empty())
+                    .map(|p| FuncMatcher::Name(p.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(PassDebug {
+            dump_dir,
+            funcs,
+            verify,
+        })
+    }
+
+    fn selects(&self, func: Option<(Func, &str)>) -> bool {
+        self.funcs.is_empty()
+            || func.is_some_and(|(func, name)| self.funcs.iter().any(|m| m.matches(func, name)))
+    }
+
+    /// Called after the `seq`th pass, `pass`, has run on `body`, which
+    /// is the function `func` if known.
+    pub(crate) fn after_pass(
+        &self,
+        func: Option<(Func, &str)>,
+        seq: usize,
+        pass: &str,
+        body: &FunctionBody,
+    ) {
+        if self.verify {
+            if let Err(e) = body.validate() {
+                let func = func.map_or("<body>".to_owned(), |(f, _)| f.to_string());
+                panic!("IR of {} invalid after pass {}: {}", func, pass, e);
+            }
+        }
+        let dir = match &self.dump_dir {
+            Some(dir) if self.selects(func) => dir,
+            _ => return,
+        };
+        let func = func.map_or("body".to_owned(), |(f, _)| f.to_string());
+        let path = dir.join(format!("{}.{:02}.{}.waffle", func, seq, pass));
+        let text = format!("{}", body.display("", None));
+        if let Err(e) = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, text)) {
+            log::warn!("pass_debug: could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, Module, SignatureData, Terminator, Type};
+    use crate::passes::basic_opt::OptOptions;
+    use crate::Operator;
+    use std::sync::Arc;
+
+    #[test]
+    fn dumps_selected_functions_after_each_pass() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        for name in ["kept", "skipped"] {
+            let mut body = FunctionBody::new(&module, sig);
+            let one = body.add_op(
+                body.entry,
+                Operator::I32Const { value: 1 },
+                &[],
+                &[Type::I32],
+            );
+            let two = body.add_op(body.entry, Operator::I32Add, &[one, one], &[Type::I32]);
+            body.set_terminator(body.entry, Terminator::Return { values: vec![two] });
+            module
+                .funcs
+                .push(FuncDecl::Body(sig, name.to_owned(), body.into()));
+        }
+
+        let dir = std::env::temp_dir().join(format!("waffle-pass-debug-{}", std::process::id()));
+        let debug = PassDebug {
+            dump_dir: Some(dir.clone()),
+            funcs: vec![FuncMatcher::Name("k*".to_owned())],
+            verify: true,
+        };
+        let options = OptOptions {
+            pass_debug: Some(Arc::new(debug)),
+            ..OptOptions::default()
+        };
+        module.optimize(&options);
+
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                "func0.00.input.waffle",
+                "func0.01.reassociate.waffle",
+                "func0.02.null_checks.waffle",
+                "func0.03.basic_opt.waffle",
+                "func0.04.switch.waffle",
+                "func0.05.empty_blocks.waffle",
+            ]
+        );
+    }
+}
@@ -0,0 +1,800 @@
+//! Profile-guided optimization.
+//!
+//! A `Profile` records how often each function was called, each block
+//! ran, and each CFG edge was taken, as observed by the interpreter
+//! (see `InterpContext::profile`) running a module on representative
+//! inputs. `collect()` gathers one by invoking exports, and profiles
+//! are stored as JSON so that collection and optimization can happen
+//! separately. `pgo()` then uses a profile to
+//!
+//! - inline small functions into hot call sites,
+//! - hint conditional branches that strongly prefer one direction, and
+//! - lay out functions hottest-first, so that engines that compile
+//!   or tier up in index order reach the hot code first.
+//!
+//! Profiles refer to functions and blocks by index, so they apply only
+//! to the module they were collected from, parsed the same way.
+
+use crate::entity::{EntityRef, PerEntity};
+use crate::interp::{InterpContext, InterpResult};
+use crate::ir::{
+    Block, BlockOrigin, BlockTarget, ExportKind, FunctionBody, Module, Terminator, Value, ValueDef,
+};
+use crate::passes::func_order::{reorder_funcs, FuncOrder};
+use crate::{Func, Operator};
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+
+/// Execution counts for a module.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Counts for each function that ran.
+    pub funcs: BTreeMap<Func, FuncProfile>,
+}
+
+/// Execution counts for one function.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FuncProfile {
+    /// The function's name, used to check that a profile matches the
+    /// module it is applied to.
+    pub name: String,
+    /// How many times the function was called.
+    pub calls: u64,
+    /// How many times each block ran.
+    pub blocks: BTreeMap<Block, u64>,
+    /// How many times each edge `(from, to)` was taken.
+    pub edges: BTreeMap<(Block, Block), u64>,
+}
+
+impl Profile {
+    pub(crate) fn record_call(&mut self, func: Func) {
+        self.funcs.entry(func).or_default().calls += 1;
+    }
+
+    pub(crate) fn record_block(&mut self, func: Func, from: Option<Block>, block: Block) {
+        let counts = self.funcs.entry(func).or_default();
+        *counts.blocks.entry(block).or_default() += 1;
+        if let Some(from) = from {
+            *counts.edges.entry((from, block)).or_default() += 1;
+        }
+    }
+
+    /// How many times `block` in `func` ran.
+    pub fn block_count(&self, func: Func, block: Block) -> u64 {
+        self.funcs
+            .get(&func)
+            .and_then(|counts| counts.blocks.get(&block))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Serialize the profile as JSON.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"funcs\": [");
+        for (i, (func, counts)) in self.funcs.iter().enumerate() {
+            let blocks = counts
+                .blocks
+                .iter()
+                .map(|(block, count)| format!("[{}, {}]", block.index(), count))
+                .collect::<Vec<_>>();
+            let edges = counts
+                .edges
+                .iter()
+                .map(|((from, to), count)| format!("[{}, {}, {}]", from.index(), to.index(), count))
+                .collect::<Vec<_>>();
+            out += if i == 0 { "\n" } else { ",\n" };
+            out += &format!(
+                "    {{\"func\": {}, \"name\": {}, \"calls\": {}, \"blocks\": [{}], \"edges\": [{}]}}",
+                func.index(),
+                json_string(&counts.name),
+                counts.calls,
+                blocks.join(", "),
+                edges.join(", ")
+            );
+        }
+        out += "\n  ]\n}\n";
+        out
+    }
+
+    /// Parse a profile written by `to_json()`.
+    pub fn from_json(text: &str) -> Result<Profile> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let json = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != text.len() {
+            bail!("Trailing data after profile at byte {}", parser.pos);
+        }
+
+        let mut profile = Profile::default();
+        for entry in json.field("funcs")?.array()? {
+            let func = Func::new(entry.field("func")?.number()? as usize);
+            let mut counts = FuncProfile {
+                name: entry.field("name")?.string()?.to_owned(),
+                calls: entry.field("calls")?.number()?,
+                ..FuncProfile::default()
+            };
+            for block in entry.field("blocks")?.array()? {
+                match block.array()? {
+                    [block, count] => {
+                        let block = Block::new(block.number()? as usize);
+                        counts.blocks.insert(block, count.number()?);
+                    }
+                    _ => bail!("Block count must be [block, count]"),
+                }
+            }
+            for edge in entry.field("edges")?.array()? {
+                match edge.array()? {
+                    [from, to, count] => {
+                        let from = Block::new(from.number()? as usize);
+                        let to = Block::new(to.number()? as usize);
+                        counts.edges.insert((from, to), count.number()?);
+                    }
+                    _ => bail!("Edge count must be [from, to, count]"),
+                }
+            }
+            profile.funcs.insert(func, counts);
+        }
+        Ok(profile)
+    }
+}
+
+/// Collect a profile by running `module`'s start function, if any,
+/// and then each of the exports in `invoke`, with no arguments, in
+/// the interpreter. Since the interpreter has no host functions, the
+/// code that runs must not call imports.
+pub fn collect(module: &mut Module, invoke: &[String], fuel: Option<u64>) -> Result<Profile> {
+    let mut funcs = vec![];
+    for name in invoke {
+        let func = module
+            .exports
+            .iter()
+            .find_map(|export| match &export.kind {
+                ExportKind::Func(func) if &export.name == name => Some(*func),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No exported function named '{}'", name))?;
+        if !module.signatures[module.funcs[func].sig()]
+            .params
+            .is_empty()
+        {
+            bail!("Function '{}' takes parameters", name);
+        }
+        funcs.push(func);
+    }
+    module.expand_all_funcs()?;
+
+    let mut ctx = InterpContext::new(module)?;
+    ctx.profile = Some(Profile::default());
+    if let Some(fuel) = fuel {
+        ctx.fuel = fuel;
+    }
+    for func in module.start_func.into_iter().chain(funcs) {
+        match ctx.call(module, func, &[]) {
+            InterpResult::Ok(_) => {}
+            InterpResult::Trap(func, block, inst) => {
+                bail!("Trapped in {} at {} instruction {}", func, block, inst)
+            }
+            InterpResult::OutOfFuel => bail!("Ran out of fuel"),
+        }
+    }
+
+    let mut profile = ctx.profile.take().unwrap();
+    for (func, counts) in profile.funcs.iter_mut() {
+        counts.name = module.funcs[*func].name().to_owned();
+    }
+    Ok(profile)
+}
+
+/// Options for profile-guided optimization.
+#[derive(Clone, Debug)]
+pub struct PgoOptions {
+    /// Inline calls that ran at least `inline_threshold` times.
+    pub inline: bool,
+    /// The minimum count of a call site for inlining.
+    pub inline_threshold: u64,
+    /// The largest callee to inline, in instructions.
+    pub max_inline_size: usize,
+    /// Hint conditional branches that go one way at least
+    /// `hint_percent` percent of the time.
+    pub branch_hints: bool,
+    /// The minimum bias of a hinted branch, in percent.
+    pub hint_percent: u64,
+    /// Order functions by decreasing call count.
+    pub layout: bool,
+}
+
+impl Default for PgoOptions {
+    fn default() -> Self {
+        PgoOptions {
+            inline: true,
+            inline_threshold: 100,
+            max_inline_size: 32,
+            branch_hints: true,
+            hint_percent: 90,
+            layout: true,
+        }
+    }
+}
+
+/// What `pgo()` changed.
+#[derive(Clone, Debug, Default)]
+pub struct PgoReport {
+    /// Call sites inlined, as `(caller, callee)`.
+    pub inlined: Vec<(Func, Func)>,
+    /// Conditional branches given a hint.
+    pub hinted: usize,
+    /// Functions that moved in the function index space.
+    pub moved: usize,
+}
+
+impl std::fmt::Display for PgoReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (caller, callee) in &self.inlined {
+            writeln!(f, "inlined {} into {}", callee, caller)?;
+        }
+        writeln!(f, "hinted {} branches", self.hinted)?;
+        writeln!(f, "moved {} functions", self.moved)
+    }
+}
+
+/// Optimize `module` using `profile`, as described in the module
+/// documentation. Inlining and layout renumber values, blocks, and
+/// functions, so the profile no longer applies afterwards.
+pub fn pgo(module: &mut Module, profile: &Profile, options: &PgoOptions) -> Result<PgoReport> {
+    module.expand_all_funcs()?;
+    for (&func, counts) in &profile.funcs {
+        match module.funcs.get(func) {
+            Some(decl) if decl.name() == counts.name => {}
+            _ => bail!(
+                "Profile does not match module: no function {} named '{}'",
+                func,
+                counts.name
+            ),
+        }
+    }
+
+    let mut report = PgoReport::default();
+    // Hints go first, so that inlined callees carry theirs along.
+    if options.branch_hints {
+        for (&func, counts) in &profile.funcs {
+            if let Some(body) = module.funcs[func].body_mut() {
+                report.hinted += hint_branches(body, counts, options.hint_percent);
+            }
+        }
+    }
+    if options.inline {
+        report.inlined = inline_hot_calls(module, profile, options);
+    }
+    if options.layout {
+        report.moved = reorder_funcs(module, FuncOrder::HotFirst, Some(profile))?;
+    }
+    Ok(report)
+}
+
+/// Set the branch hint of every conditional branch whose profiled
+/// edge counts favor one target by at least `percent` percent.
+fn hint_branches(body: &mut FunctionBody, counts: &FuncProfile, percent: u64) -> usize {
+    let mut hinted = 0;
+    for (block, def) in body.blocks.entries_mut() {
+        let (if_true, if_false) = match &def.terminator {
+            Terminator::CondBr {
+                if_true, if_false, ..
+            } if if_true.block != if_false.block => (if_true.block, if_false.block),
+            _ => continue,
+        };
+        let taken = |to| counts.edges.get(&(block, to)).copied().unwrap_or(0);
+        let (t, f) = (taken(if_true), taken(if_false));
+        let total = t + f;
+        if total == 0 {
+            continue;
+        }
+        def.branch_hint = if t * 100 >= total * percent {
+            Some(true)
+        } else if f * 100 >= total * percent {
+            Some(false)
+        } else {
+            None
+        };
+        hinted += def.branch_hint.is_some() as usize;
+    }
+    hinted
+}
+
+/// Inline small callees into call sites that ran often enough, using
+/// the callees' bodies from before any inlining.
+fn inline_hot_calls(
+    module: &mut Module,
+    profile: &Profile,
+    options: &PgoOptions,
+) -> Vec<(Func, Func)> {
+    let callees = module
+        .funcs
+        .entries()
+        .filter_map(|(func, decl)| {
+            let body = decl.body()?;
+            let size = body
+                .blocks
+                .values()
+                .map(|def| def.insts.len())
+                .sum::<usize>();
+            (size <= options.max_inline_size).then(|| (func, body.clone()))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let mut inlined = vec![];
+    for &caller in profile.funcs.keys() {
+        let body = match module.funcs[caller].body_mut() {
+            Some(body) => body,
+            None => continue,
+        };
+        // Find the sites first, since inlining adds blocks.
+        let mut sites = vec![];
+        for (block, def) in body.blocks.entries() {
+            if profile.block_count(caller, block) < options.inline_threshold {
+                continue;
+            }
+            for &inst in &def.insts {
+                if let ValueDef::Operator(Operator::Call { function_index }, ..) = body.values[inst]
+                {
+                    if function_index != caller && callees.contains_key(&function_index) {
+                        sites.push((inst, function_index));
+                    }
+                }
+            }
+        }
+        for (inst, callee) in sites {
+            // Earlier inlining may have moved the call to a
+            // continuation block.
+            let block = body.value_blocks[inst];
+            let index = body.blocks[block]
+                .insts
+                .iter()
+                .position(|&i| i == inst)
+                .unwrap();
+            inline_call(body, block, index, &callees[&callee]);
+            inlined.push((caller, callee));
+        }
+    }
+    inlined
+}
+
+/// Replace the call at `index` in `block` with a copy of `callee`'s
+/// body. The rest of the block moves to a new continuation block that
+/// receives the callee's return values as blockparams.
+fn inline_call(body: &mut FunctionBody, block: Block, index: usize, callee: &FunctionBody) {
+    let call = body.blocks[block].insts[index];
+    let (args, rets) = match &body.values[call] {
+        ValueDef::Operator(Operator::Call { .. }, args, tys) => {
+            (body.arg_pool[*args].to_vec(), body.type_pool[*tys].to_vec())
+        }
+        _ => unreachable!(),
+    };
+
+    // Split the block after the call.
+    let cont = body.add_block_with_origin(BlockOrigin::SyntheticFor("pgo"));
+    let rest = body.blocks[block].insts.split_off(index + 1);
+    body.blocks[block].insts.pop();
+    for &inst in &rest {
+        body.value_blocks[inst] = cont;
+    }
+    body.blocks[cont].insts = rest;
+    body.blocks[cont].terminator = std::mem::take(&mut body.blocks[block].terminator);
+    body.blocks[cont].branch_hint = body.blocks[block].branch_hint.take();
+    let results = rets
+        .iter()
+        .map(|&ty| body.add_blockparam(cont, ty))
+        .collect::<Vec<_>>();
+    match &results[..] {
+        [result] => body.set_alias(call, *result),
+        _ => {
+            for value in body.values.values_mut() {
+                if let ValueDef::PickOutput(of, i, _) = *value {
+                    if of == call {
+                        *value = ValueDef::Alias(results[i as usize]);
+                    }
+                }
+            }
+            let values = &body.values;
+            body.blocks[cont]
+                .insts
+                .retain(|&inst| !matches!(values[inst], ValueDef::Alias(..)));
+            body.values[call] = ValueDef::None;
+        }
+    }
+
+    // Copy the callee's blocks and values.
+    let mut blocks: PerEntity<Block, Block> = PerEntity::default();
+    for (b, def) in callee.blocks.entries() {
+        let new = body.add_block();
+        body.blocks[new].origin = def.origin.clone();
+        body.blocks[new].branch_hint = def.branch_hint;
+        blocks[b] = new;
+    }
+    let mut values: PerEntity<Value, Value> = PerEntity::default();
+    for v in callee.values.iter() {
+        values[v] = body.add_value(ValueDef::None);
+    }
+    for (v, def) in callee.values.entries() {
+        let def = match def {
+            ValueDef::BlockParam(b, i, ty) => ValueDef::BlockParam(blocks[*b], *i, *ty),
+            ValueDef::Operator(op, args, tys) => {
+                let args = callee.arg_pool[*args]
+                    .iter()
+                    .map(|&arg| values[arg])
+                    .collect::<Vec<_>>();
+                let args = body.arg_pool.from_iter(args.into_iter());
+                let tys = body
+                    .type_pool
+                    .from_iter(callee.type_pool[*tys].iter().copied());
+                ValueDef::Operator(body.import_op(callee, *op), args, tys)
+            }
+            ValueDef::PickOutput(of, i, ty) => ValueDef::PickOutput(values[*of], *i, *ty),
+            ValueDef::Alias(to) => ValueDef::Alias(values[*to]),
+            ValueDef::Placeholder(..) | ValueDef::None => ValueDef::None,
+        };
+        body.values[values[v]] = def;
+        body.source_locs[values[v]] = callee.source_locs[v];
+        body.wasm_offsets[values[v]] = callee.wasm_offsets[v];
+    }
+    for (b, def) in callee.blocks.entries() {
+        let new = blocks[b];
+        body.blocks[new].params = def
+            .params
+            .iter()
+            .map(|&(ty, param)| (ty, values[param]))
+            .collect();
+        body.blocks[new].insts = def.insts.iter().map(|&inst| values[inst]).collect();
+        for &(_, param) in &body.blocks[new].params.clone() {
+            body.value_blocks[param] = new;
+        }
+        for &inst in &body.blocks[new].insts.clone() {
+            body.value_blocks[inst] = new;
+        }
+        let mut terminator = def.terminator.clone();
+        terminator.update_uses(|value| *value = values[*value]);
+        terminator.update_targets(|target| target.block = blocks[target.block]);
+        if let Terminator::Return { values } = terminator {
+            terminator = Terminator::Br {
+                target: BlockTarget {
+                    block: cont,
+                    args: values,
+                },
+            };
+        }
+        body.blocks[new].terminator = terminator;
+    }
+
+    body.blocks[block].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: blocks[callee.entry],
+            args,
+        },
+    };
+    body.recompute_edges();
+}
+
+/// Quote a string for JSON.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The subset of JSON that profiles use: non-negative integers,
+/// strings, arrays, and objects.
+#[derive(Clone, Debug)]
+enum Json {
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Result<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| anyhow!("Missing field '{}' in profile", name)),
+            _ => bail!("Expected an object with field '{}' in profile", name),
+        }
+    }
+
+    fn array(&self) -> Result<&[Json]> {
+        match self {
+            Json::Array(elements) => Ok(&elements[..]),
+            _ => bail!("Expected an array in profile"),
+        }
+    }
+
+    fn number(&self) -> Result<u64> {
+        match self {
+            Json::Number(n) => Ok(*n),
+            _ => bail!("Expected a number in profile"),
+        }
+    }
+
+    fn string(&self) -> Result<&str> {
+        match self {
+            Json::String(s) => Ok(&s[..]),
+            _ => bail!("Expected a string in profile"),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<u8> {
+        self.skip_ws();
+        self.text
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| anyhow!("Unexpected end of profile"))
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek()? != c {
+            bail!("Expected '{}' at byte {} of profile", c as char, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Parse a comma-separated sequence of items up to `close`.
+    fn sequence<T>(
+        &mut self,
+        close: u8,
+        mut item: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let mut items = vec![];
+        if self.peek()? == close {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            match self.peek()? {
+                b',' => self.pos += 1,
+                c if c == close => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                _ => bail!(
+                    "Expected ',' or '{}' at byte {} of profile",
+                    close as char,
+                    self.pos
+                ),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek()? {
+            b'{' => {
+                self.pos += 1;
+                let fields = self.sequence(b'}', |parser| {
+                    let key = parser.string()?;
+                    parser.expect(b':')?;
+                    Ok((key, parser.value()?))
+                })?;
+                Ok(Json::Object(fields))
+            }
+            b'[' => {
+                self.pos += 1;
+                Ok(Json::Array(self.sequence(b']', |parser| parser.value())?))
+            }
+            b'"' => Ok(Json::String(self.string()?)),
+            b'0'..=b'9' => {
+                let start = self.pos;
+                while self.pos < self.text.len() && self.text[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let digits = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+                Ok(Json::Number(digits.parse()?))
+            }
+            _ => bail!("Unexpected character at byte {} of profile", self.pos),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let c = *self
+                .text
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("Unterminated string in profile"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self
+                        .text
+                        .get(self.pos)
+                        .ok_or_else(|| anyhow!("Unterminated string in profile"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => bytes.push(escape),
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'u' => {
+                            let hex = self
+                                .text
+                                .get(self.pos..self.pos + 4)
+                                .ok_or_else(|| anyhow!("Bad escape in profile"))?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex)?, 16)?;
+                            let c = char::from_u32(code)
+                                .ok_or_else(|| anyhow!("Bad escape in profile"))?;
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            self.pos += 4;
+                        }
+                        _ => bail!("Bad escape at byte {} of profile", self.pos),
+                    }
+                }
+                c => bytes.push(c),
+            }
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Export, FuncDecl, FunctionBuilder, SignatureData, Type};
+
+    /// `main` loops ten times, calling `inc` on each iteration. `inc`
+    /// comes second in the index space.
+    fn module() -> (Module<'static>, Func, Func) {
+        let mut module = Module::empty();
+        let inc_sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let main_sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+
+        let inc = Func::new(1);
+        let mut body = FunctionBody::new(&module, main_sig);
+        let entry = body.entry;
+        let header = body.add_block();
+        let exit = body.add_block();
+        let zero = body.add_op(entry, Operator::I32Const { value: 0 }, &[], &[Type::I32]);
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: header,
+                    args: vec![zero],
+                },
+            },
+        );
+        let i = body.add_blockparam(header, Type::I32);
+        let next = body.add_op(
+            header,
+            Operator::Call {
+                function_index: inc,
+            },
+            &[i],
+            &[Type::I32],
+        );
+        let ten = body.add_op(header, Operator::I32Const { value: 10 }, &[], &[Type::I32]);
+        let more = body.add_op(header, Operator::I32LtU, &[next, ten], &[Type::I32]);
+        body.set_terminator(
+            header,
+            Terminator::CondBr {
+                cond: more,
+                if_true: BlockTarget {
+                    block: header,
+                    args: vec![next],
+                },
+                if_false: BlockTarget {
+                    block: exit,
+                    args: vec![],
+                },
+            },
+        );
+        body.set_terminator(exit, Terminator::Return { values: vec![next] });
+        let main = module
+            .funcs
+            .push(FuncDecl::Body(main_sig, "main".to_owned(), body.into()));
+        let mut body = FunctionBody::new(&module, inc_sig);
+        let mut builder = FunctionBuilder::new(&mut body);
+        let x = builder.params()[0];
+        let one = builder.op(Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let sum = builder.op(Operator::I32Add, &[x, one], &[Type::I32]);
+        builder.ret(&[sum]);
+        let pushed = module
+            .funcs
+            .push(FuncDecl::Body(inc_sig, "inc".to_owned(), body.into()));
+
+        assert_eq!(pushed, inc);
+        module.exports.push(Export {
+            name: "main".to_owned(),
+            kind: ExportKind::Func(main),
+        });
+        (module, inc, main)
+    }
+
+    #[test]
+    fn profile_round_trips_through_json() {
+        let (mut module, inc, main) = module();
+        let profile = collect(&mut module, &["main".to_owned()], None).unwrap();
+        assert_eq!(profile.funcs[&inc].calls, 10);
+        assert_eq!(profile.funcs[&main].calls, 1);
+        assert_eq!(profile.block_count(main, Block::new(1)), 10);
+        assert_eq!(profile.funcs[&inc].name, "inc");
+        assert_eq!(
+            profile.funcs[&main].edges[&(Block::new(1), Block::new(1))],
+            9
+        );
+        let json = profile.to_json();
+        assert_eq!(Profile::from_json(&json).unwrap(), profile);
+        assert!(Profile::from_json("{\"funcs\": [1,]}").is_err());
+    }
+
+    #[test]
+    fn pgo_inlines_hints_and_reorders() {
+        let (mut module, inc, main) = module();
+        let profile = collect(&mut module, &["main".to_owned()], None).unwrap();
+        let options = PgoOptions {
+            inline_threshold: 5,
+            ..PgoOptions::default()
+        };
+        let report = pgo(&mut module, &profile, &options).unwrap();
+        assert_eq!(report.inlined, vec![(main, inc)]);
+        assert_eq!(report.hinted, 1);
+        // `inc` is called ten times, so it moves first.
+        assert_eq!(report.moved, 2);
+        let main = Func::new(1);
+        assert_eq!(module.funcs[main].name(), "main");
+        assert_eq!(module.exports[0].kind, ExportKind::Func(main));
+        let body = module.funcs[main].body().unwrap();
+        body.validate().unwrap();
+        assert!(!body
+            .values
+            .values()
+            .any(|def| matches!(def, ValueDef::Operator(Operator::Call { .. }, ..))));
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, main, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[crate::ConstVal::I32(10)]);
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let hints = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .filter_map(|payload| match payload.unwrap() {
+                wasmparser::Payload::CustomSection(section) => Some(section.name().to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(hints.contains(&"metadata.code.branch_hint".to_owned()));
+    }
+}
@@ -0,0 +1,151 @@
+//! The optimization pipeline as a list of passes.
+//!
+//! `FunctionBody::optimize()` runs the passes that `OptOptions`
+//! enables, in a fixed order. Tools that need to run part of the
+//! pipeline (such as `bisect`) can get that order from
+//! `OptOptions::pipeline()` and run the passes one at a time.
+
+use crate::cfg::CFGInfo;
+use crate::ir::FunctionBody;
+use crate::passes::basic_opt::OptOptions;
+use crate::passes::maxssa::CutBlocks;
+
+/// One pass of the optimization pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// Reassociation and canonicalization of operands.
+    Reassociate,
+    /// Folding of null checks with known outcomes.
+    NullChecks,
+    /// GVN, constant propagation, and address folding.
+    BasicOpt,
+    /// Switch lowering and raising.
+    Switch,
+    /// Select formation.
+    Select,
+    /// Removal of empty blocks.
+    EmptyBlocks,
+    /// Conversion to maximal SSA.
+    MaxSsa,
+    /// Conversion to maximal SSA at loop headers only.
+    MaxSsaLoops,
+    /// Removal of blockparams that dominance makes unnecessary.
+    MinimizeBlockparams,
+    /// Narrowing of blockparam reference types to their inputs.
+    NarrowTypes,
+}
+
+impl Pass {
+    /// The pass's name, as used in dumps and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Reassociate => "reassociate",
+            Pass::NullChecks => "null_checks",
+            Pass::BasicOpt => "basic_opt",
+            Pass::Switch => "switch",
+            Pass::Select => "select",
+            Pass::EmptyBlocks => "empty_blocks",
+            Pass::MaxSsa => "max_ssa",
+            Pass::MaxSsaLoops => "max_ssa_loops",
+            Pass::MinimizeBlockparams => "minimize_blockparams",
+            Pass::NarrowTypes => "narrow_types",
+        }
+    }
+
+    /// Run the pass on `body` with `opts`.
+    pub fn run(self, body: &mut FunctionBody, opts: &OptOptions) {
+        match self {
+            Pass::Reassociate => crate::passes::reassociate::run(body),
+            Pass::NullChecks => {
+                let cfg = CFGInfo::new(body);
+                crate::passes::null_checks::run(body, &cfg);
+            }
+            Pass::BasicOpt => {
+                let cfg = CFGInfo::new(body);
+                crate::passes::basic_opt::basic_opt(body, &cfg, opts);
+            }
+            Pass::Switch => crate::passes::switch::run(body, opts.switch_lowering),
+            Pass::Select => {
+                crate::passes::select::form_selects(body, opts);
+            }
+            Pass::EmptyBlocks => crate::passes::empty_blocks::run(body),
+            Pass::MaxSsa => body.convert_to_max_ssa(None),
+            Pass::MaxSsaLoops => body.convert_to_max_ssa_with(&CutBlocks::LoopHeaders),
+            Pass::MinimizeBlockparams => {
+                body.minimize_blockparams();
+            }
+            Pass::NarrowTypes => {
+                crate::passes::narrow_types::run(body);
+            }
+        }
+        // Passes edit the body's fields directly.
+        body.mark_changed();
+    }
+}
+
+impl std::str::FromStr for Pass {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Pass> {
+        [
+            Pass::Reassociate,
+            Pass::NullChecks,
+            Pass::BasicOpt,
+            Pass::Switch,
+            Pass::Select,
+            Pass::EmptyBlocks,
+            Pass::MaxSsa,
+            Pass::MaxSsaLoops,
+            Pass::MinimizeBlockparams,
+            Pass::NarrowTypes,
+        ]
+        .iter()
+        .copied()
+        .find(|pass| pass.name() == s)
+        .ok_or_else(|| anyhow::anyhow!("Unknown pass: {}", s))
+    }
+}
+
+impl std::fmt::Display for Pass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl OptOptions {
+    /// The passes `FunctionBody::optimize()` runs with these options,
+    /// in order.
+    pub fn pipeline(&self) -> Vec<Pass> {
+        let mut passes = vec![];
+        if self.reassociate {
+            passes.push(Pass::Reassociate);
+        }
+        if self.null_checks {
+            passes.push(Pass::NullChecks);
+        }
+        passes.push(Pass::BasicOpt);
+        passes.push(Pass::Switch);
+        if self.form_selects {
+            passes.push(Pass::Select);
+        }
+        passes.push(Pass::EmptyBlocks);
+        passes
+    }
+}
+
+/// A list of passes and the options to run them with.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    /// The passes, in order.
+    pub passes: Vec<Pass>,
+    /// The options the passes consult.
+    pub options: OptOptions,
+}
+
+impl From<OptOptions> for Pipeline {
+    fn from(options: OptOptions) -> Pipeline {
+        Pipeline {
+            passes: options.pipeline(),
+            options,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Pre-initialization: running a module's initialization ahead of
+//! time.
+//!
+//! Many guests spend their startup building data structures that do
+//! not depend on the embedder (parsing configuration baked into the
+//! binary, filling lookup tables, running static constructors).
+//! `preinit()` runs the start function and then an exported
+//! initialization function in the interpreter, and writes the
+//! resulting memories, tables, and globals back into the module as its
+//! initial state, in the manner of Wizer. The start function is
+//! removed, since its effects are now part of the snapshot.
+//!
+//! The interpreter has no host functions, so initialization must not
+//! call imports; it also cannot see the state of imported memories,
+//! tables, or globals, which are left as they are.
+
+use crate::interp::{InterpContext, InterpResult};
+use crate::ir::{ExportKind, Module};
+use crate::passes::data_segments::{self, CompactOptions};
+use anyhow::{anyhow, bail, Result};
+
+/// Options for pre-initialization.
+#[derive(Clone, Debug, Default)]
+pub struct PreinitOptions {
+    /// The interpreter fuel (roughly, blocks executed) available to
+    /// initialization, or unlimited if `None`.
+    pub fuel: Option<u64>,
+    /// Keep the initialization function's export. By default it is
+    /// removed, since running it again would repeat initialization.
+    pub keep_init_export: bool,
+}
+
+/// Run `module`'s start function and then the function exported as
+/// `init`, with no arguments, and make the resulting state the
+/// module's initial state.
+pub fn preinit(module: &mut Module, init: &str, options: &PreinitOptions) -> Result<()> {
+    let init_func = module
+        .exports
+        .iter()
+        .find_map(|export| match &export.kind {
+            ExportKind::Func(func) if export.name == init => Some(*func),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No exported function named '{}'", init))?;
+    if !module.signatures[module.funcs[init_func].sig()]
+        .params
+        .is_empty()
+    {
+        bail!("Initialization function '{}' takes parameters", init);
+    }
+    module.expand_all_funcs()?;
+
+    let mut ctx = InterpContext::new(module)?;
+    if let Some(fuel) = options.fuel {
+        ctx.fuel = fuel;
+    }
+    for func in module.start_func.into_iter().chain(Some(init_func)) {
+        match ctx.call(module, func, &[]) {
+            InterpResult::Ok(_) => {}
+            InterpResult::Trap(func, block, inst) => {
+                bail!(
+                    "Initialization trapped in {} at {} instruction {}",
+                    func,
+                    block,
+                    inst
+                )
+            }
+            InterpResult::OutOfFuel => bail!("Initialization ran out of fuel"),
+        }
+    }
+
+    ctx.snapshot_into(module);
+    data_segments::compact(module, &CompactOptions::default());
+    module.start_func = None;
+    if !options.keep_init_export {
+        module
+            .exports
+            .retain(|export| !(export.name == init && export.kind == ExportKind::Func(init_func)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{
+        Export, FuncDecl, FunctionBody, FunctionBuilder, GlobalData, MemoryData, SignatureData,
+        Type,
+    };
+    use crate::{MemoryArg, Operator};
+
+    #[test]
+    fn snapshot_replaces_initial_state() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let memory = module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![],
+        });
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(0),
+            mutable: true,
+            init: None,
+        });
+
+        // `init` grows memory by a page, stores 42 at 0x10000 + 4, and
+        // sets the global to 7.
+        let mut body = FunctionBody::new(&module, sig);
+        let mut builder = FunctionBuilder::new(&mut body);
+        let one = builder.op(Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        builder.op(Operator::MemoryGrow { mem: memory }, &[one], &[Type::I32]);
+        let addr = builder.op(Operator::I32Const { value: 0x10000 }, &[], &[Type::I32]);
+        let value = builder.op(Operator::I32Const { value: 42 }, &[], &[Type::I32]);
+        let memarg = MemoryArg {
+            align: 2,
+            offset: 4,
+            memory,
+        };
+        builder.op(Operator::I32Store { memory: memarg }, &[addr, value], &[]);
+        let seven = builder.op(Operator::I32Const { value: 7 }, &[], &[Type::I32]);
+        builder.op(
+            Operator::GlobalSet {
+                global_index: global,
+            },
+            &[seven],
+            &[],
+        );
+        builder.ret(&[]);
+        let init = module
+            .funcs
+            .push(FuncDecl::Body(sig, "init".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "init".to_owned(),
+            kind: ExportKind::Func(init),
+        });
+
+        preinit(&mut module, "init", &PreinitOptions::default()).unwrap();
+        assert!(module.exports.is_empty());
+        assert_eq!(module.globals[global].value, Some(7));
+        let data = &module.memories[memory];
+        assert_eq!(data.initial_pages, 2);
+        assert_eq!(data.segments.len(), 1);
+        assert_eq!(data.segments[0].offset, 0x10004);
+        assert_eq!(data.segments[0].data, vec![42]);
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        let options = PreinitOptions {
+            fuel: Some(1),
+            ..PreinitOptions::default()
+        };
+        module.exports.push(Export {
+            name: "init".to_owned(),
+            kind: ExportKind::Func(init),
+        });
+        assert!(preinit(&mut module, "init", &options).is_err());
+    }
+}
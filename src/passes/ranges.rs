@@ -0,0 +1,545 @@
+//! Integer value-range and alignment analysis.
+//!
+//! Computes, for every integer-typed value in a function body, a
+//! conservative unsigned interval together with a power of two that
+//! is known to divide the value. Ranges come from constants, masks,
+//! shifts, zero-extending loads and the like, and are refined at a
+//! particular point by the comparisons on branches that dominate it.
+//! These facts let transforms prove that address arithmetic cannot
+//! wrap, e.g. to fold an add into a load's static offset or to drop a
+//! bounds check.
+
+use crate::cfg::CFGInfo;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{Block, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::Operator;
+use smallvec::{smallvec, SmallVec};
+
+/// A conservative range of unsigned integer values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueRange {
+    /// The smallest value, interpreted as unsigned.
+    pub min: u64,
+    /// The largest value, interpreted as unsigned.
+    pub max: u64,
+    /// A power of two that is known to divide the value.
+    pub align: u64,
+}
+
+const MAX_ALIGN: u64 = 1 << 63;
+
+impl ValueRange {
+    /// The range of all values of the given integer width.
+    pub fn full(bits: u32) -> ValueRange {
+        ValueRange {
+            min: 0,
+            max: limit(bits),
+            align: 1,
+        }
+    }
+
+    /// The range containing exactly one value.
+    pub fn constant(value: u64) -> ValueRange {
+        ValueRange {
+            min: value,
+            max: value,
+            align: align_of(value),
+        }
+    }
+
+    /// Construct a range from bounds, with no alignment knowledge.
+    pub fn new(min: u64, max: u64) -> ValueRange {
+        ValueRange { min, max, align: 1 }
+    }
+
+    /// If this range contains exactly one value, return it.
+    pub fn as_constant(&self) -> Option<u64> {
+        if self.min == self.max {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    /// Does the range contain `value`?
+    pub fn contains(&self, value: u64) -> bool {
+        self.min <= value && value <= self.max && value & (self.align - 1) == 0
+    }
+
+    /// The smallest range containing both ranges.
+    pub fn union(&self, other: &ValueRange) -> ValueRange {
+        ValueRange {
+            min: std::cmp::min(self.min, other.min),
+            max: std::cmp::max(self.max, other.max),
+            align: std::cmp::min(self.align, other.align),
+        }
+    }
+
+    /// The range of values in both ranges. If the ranges are disjoint
+    /// (i.e., the program point is unreachable), `self` is returned
+    /// unchanged.
+    pub fn intersect(&self, other: &ValueRange) -> ValueRange {
+        let min = std::cmp::max(self.min, other.min);
+        let max = std::cmp::min(self.max, other.max);
+        if min > max {
+            return *self;
+        }
+        ValueRange {
+            min,
+            max,
+            align: std::cmp::max(self.align, other.align),
+        }
+    }
+}
+
+fn limit(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn align_of(value: u64) -> u64 {
+    if value == 0 {
+        MAX_ALIGN
+    } else {
+        1 << value.trailing_zeros()
+    }
+}
+
+fn int_bits(ty: Type) -> Option<u32> {
+    match ty {
+        Type::I32 => Some(32),
+        Type::I64 => Some(64),
+        _ => None,
+    }
+}
+
+/// Value-range facts for one function body.
+#[derive(Clone, Debug)]
+pub struct RangeAnalysis {
+    /// The range of each integer value, independent of program point.
+    ranges: PerEntity<Value, Option<ValueRange>>,
+    /// Facts that hold throughout a block and the blocks it
+    /// dominates, learned from the branch that is the block's only
+    /// way in.
+    facts: PerEntity<Block, SmallVec<[(Value, ValueRange); 2]>>,
+    /// Immediate dominator of each block.
+    idom: PerEntity<Block, Block>,
+    /// The value each alias (at the time of analysis) resolves to.
+    aliases: PerEntity<Value, Option<Value>>,
+}
+
+impl RangeAnalysis {
+    /// Compute ranges for all values in `body`.
+    pub fn new(body: &FunctionBody, cfg: &CFGInfo) -> RangeAnalysis {
+        let mut analysis = RangeAnalysis {
+            ranges: PerEntity::default(),
+            facts: PerEntity::default(),
+            idom: cfg.domtree.clone(),
+            aliases: PerEntity::default(),
+        };
+
+        // Blocks are visited in RPO, so every operand has been seen
+        // before its use except for blockparam inputs along
+        // backedges, which are treated as unknown.
+        for &block in cfg.rpo.values() {
+            for (i, &(ty, param)) in body.blocks[block].params.iter().enumerate() {
+                let bits = match int_bits(ty) {
+                    Some(bits) => bits,
+                    None => continue,
+                };
+                let mut range: Option<ValueRange> = None;
+                for (&pred, &pos) in cfg.preds[block].iter().zip(cfg.pred_pos[block].iter()) {
+                    let input = body.blocks[pred]
+                        .terminator
+                        .visit_target(pos, |target| target.args[i]);
+                    let input = analysis
+                        .lookup(body, input)
+                        .unwrap_or(ValueRange::full(bits));
+                    range = Some(match range {
+                        Some(range) => range.union(&input),
+                        None => input,
+                    });
+                }
+                analysis.ranges[param] = Some(range.unwrap_or(ValueRange::full(bits)));
+            }
+            for &inst in &body.blocks[block].insts {
+                analysis.ranges[inst] = analysis.compute(body, inst);
+            }
+        }
+
+        for &block in cfg.rpo.values() {
+            if let Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } = &body.blocks[block].terminator
+            {
+                if if_true.block == if_false.block {
+                    continue;
+                }
+                for (target, taken) in [(if_true.block, true), (if_false.block, false)] {
+                    if cfg.preds[target].len() == 1 && target != body.entry {
+                        let facts = analysis.branch_facts(body, *cond, taken);
+                        analysis.facts[target].extend(facts);
+                    }
+                }
+            }
+        }
+
+        for (value, def) in body.values.entries() {
+            if let ValueDef::Alias(_) = def {
+                let resolved = body.resolve_alias(value);
+                analysis.aliases[value] = Some(resolved);
+                analysis.ranges[value] = analysis.ranges[resolved];
+            }
+        }
+
+        analysis
+    }
+
+    /// The range of `value` anywhere it is defined, or `None` if it is
+    /// not an integer (or is unreachable).
+    pub fn range_of(&self, value: Value) -> Option<ValueRange> {
+        self.ranges[value]
+    }
+
+    /// The range of `value` at a use in `block`, refined by the
+    /// conditions of branches dominating `block`.
+    pub fn range_at(&self, value: Value, block: Block) -> Option<ValueRange> {
+        let value = self.aliases[value].unwrap_or(value);
+        let mut range = self.ranges[value]?;
+        let mut block = block;
+        while block.is_valid() {
+            for (fact_value, fact) in &self.facts[block] {
+                if *fact_value == value {
+                    range = range.intersect(fact);
+                }
+            }
+            let parent = self.idom[block];
+            if parent == block {
+                break;
+            }
+            block = parent;
+        }
+        Some(range)
+    }
+
+    fn lookup(&self, body: &FunctionBody, value: Value) -> Option<ValueRange> {
+        let value = body.resolve_alias(value);
+        self.ranges[value]
+    }
+
+    fn compute(&self, body: &FunctionBody, inst: Value) -> Option<ValueRange> {
+        let (op, args, tys) = match &body.values[inst] {
+            ValueDef::Operator(op, args, tys) if tys.len() == 1 => {
+                (op, &body.arg_pool[*args], body.type_pool[*tys][0])
+            }
+            _ => return None,
+        };
+        let bits = int_bits(tys)?;
+        let full = ValueRange::full(bits);
+        let lim = limit(bits);
+        let arg = |i: usize| self.lookup(body, args[i]).unwrap_or(full);
+        let const_arg = |i: usize| self.lookup(body, args[i]).and_then(|r| r.as_constant());
+
+        let range = match op {
+            Operator::I32Const { value } => ValueRange::constant(*value as u64),
+            Operator::I64Const { value } => ValueRange::constant(*value),
+
+            Operator::I32And | Operator::I64And => {
+                let (a, b) = (arg(0), arg(1));
+                ValueRange {
+                    min: 0,
+                    max: std::cmp::min(a.max, b.max),
+                    align: std::cmp::max(a.align, b.align),
+                }
+            }
+            Operator::I32Or | Operator::I64Or => {
+                let (a, b) = (arg(0), arg(1));
+                let max = std::cmp::max(a.max, b.max);
+                ValueRange {
+                    min: std::cmp::max(a.min, b.min),
+                    max: if max == 0 {
+                        0
+                    } else {
+                        limit(64 - max.leading_zeros())
+                    },
+                    align: std::cmp::min(a.align, b.align),
+                }
+            }
+            Operator::I32Add | Operator::I64Add => {
+                let (a, b) = (arg(0), arg(1));
+                let align = std::cmp::min(a.align, b.align);
+                match a.max.checked_add(b.max).filter(|&max| max <= lim) {
+                    Some(max) => ValueRange {
+                        min: a.min + b.min,
+                        max,
+                        align,
+                    },
+                    None => ValueRange { align, ..full },
+                }
+            }
+            Operator::I32Sub | Operator::I64Sub => {
+                let (a, b) = (arg(0), arg(1));
+                let align = std::cmp::min(a.align, b.align);
+                if a.min >= b.max {
+                    ValueRange {
+                        min: a.min - b.max,
+                        max: a.max - b.min,
+                        align,
+                    }
+                } else {
+                    ValueRange { align, ..full }
+                }
+            }
+            Operator::I32Mul | Operator::I64Mul => {
+                let (a, b) = (arg(0), arg(1));
+                let align = std::cmp::min(a.align.saturating_mul(b.align), MAX_ALIGN);
+                match a.max.checked_mul(b.max).filter(|&max| max <= lim) {
+                    Some(max) => ValueRange {
+                        min: a.min * b.min,
+                        max,
+                        align,
+                    },
+                    None => full,
+                }
+            }
+            Operator::I32Shl | Operator::I64Shl => match const_arg(1) {
+                Some(k) => {
+                    let k = (k % bits as u64) as u32;
+                    let a = arg(0);
+                    let align = std::cmp::min(a.align.saturating_mul(1 << k), MAX_ALIGN);
+                    if a.max.leading_zeros() >= k + (64 - bits) {
+                        ValueRange {
+                            min: a.min << k,
+                            max: a.max << k,
+                            align,
+                        }
+                    } else {
+                        ValueRange { align, ..full }
+                    }
+                }
+                None => full,
+            },
+            Operator::I32ShrU | Operator::I64ShrU => match const_arg(1) {
+                Some(k) => {
+                    let k = (k % bits as u64) as u32;
+                    let a = arg(0);
+                    ValueRange {
+                        min: a.min >> k,
+                        max: a.max >> k,
+                        align: std::cmp::max(a.align >> k, 1),
+                    }
+                }
+                None => ValueRange::new(0, arg(0).max),
+            },
+            Operator::I32DivU | Operator::I64DivU => {
+                let (a, b) = (arg(0), arg(1));
+                ValueRange::new(
+                    a.min / std::cmp::max(b.max, 1),
+                    a.max / std::cmp::max(b.min, 1),
+                )
+            }
+            Operator::I32RemU | Operator::I64RemU => {
+                let (a, b) = (arg(0), arg(1));
+                ValueRange::new(0, std::cmp::min(a.max, b.max.saturating_sub(1)))
+            }
+
+            Operator::I32Eqz
+            | Operator::I32Eq
+            | Operator::I32Ne
+            | Operator::I32LtS
+            | Operator::I32LtU
+            | Operator::I32GtS
+            | Operator::I32GtU
+            | Operator::I32LeS
+            | Operator::I32LeU
+            | Operator::I32GeS
+            | Operator::I32GeU
+            | Operator::I64Eqz
+            | Operator::I64Eq
+            | Operator::I64Ne
+            | Operator::I64LtS
+            | Operator::I64LtU
+            | Operator::I64GtS
+            | Operator::I64GtU
+            | Operator::I64LeS
+            | Operator::I64LeU
+            | Operator::I64GeS
+            | Operator::I64GeU
+            | Operator::F32Eq
+            | Operator::F32Ne
+            | Operator::F32Lt
+            | Operator::F32Gt
+            | Operator::F32Le
+            | Operator::F32Ge
+            | Operator::F64Eq
+            | Operator::F64Ne
+            | Operator::F64Lt
+            | Operator::F64Gt
+            | Operator::F64Le
+            | Operator::F64Ge => ValueRange::new(0, 1),
+
+            Operator::I32Clz
+            | Operator::I32Ctz
+            | Operator::I32Popcnt
+            | Operator::I64Clz
+            | Operator::I64Ctz
+            | Operator::I64Popcnt => ValueRange::new(0, bits as u64),
+
+            Operator::I32Load8U { .. } | Operator::I64Load8U { .. } => ValueRange::new(0, 0xff),
+            Operator::I32Load16U { .. } | Operator::I64Load16U { .. } => ValueRange::new(0, 0xffff),
+            Operator::I64Load32U { .. } => ValueRange::new(0, 0xffff_ffff),
+            Operator::MemorySize { .. } => ValueRange::new(0, 0x1_0000),
+
+            Operator::I64ExtendI32U => self.lookup(body, args[0]).unwrap_or(ValueRange::full(32)),
+            Operator::I32WrapI64 => match self.lookup(body, args[0]) {
+                Some(a) if a.max <= lim => a,
+                Some(a) => ValueRange {
+                    align: std::cmp::min(a.align, 1 << 31),
+                    ..full
+                },
+                None => full,
+            },
+            Operator::Select | Operator::TypedSelect { .. } => arg(0).union(&arg(1)),
+
+            _ => full,
+        };
+        Some(range)
+    }
+
+    /// Facts about the operands of `cond` that hold when a branch on
+    /// it is (or is not) taken.
+    fn branch_facts(
+        &self,
+        body: &FunctionBody,
+        cond: Value,
+        taken: bool,
+    ) -> SmallVec<[(Value, ValueRange); 2]> {
+        let cond = body.resolve_alias(cond);
+        let mut facts: SmallVec<[(Value, ValueRange); 2]> = smallvec![];
+        facts.push((
+            cond,
+            if taken {
+                ValueRange::new(1, u64::MAX)
+            } else {
+                ValueRange::constant(0)
+            },
+        ));
+
+        let (op, args) = match &body.values[cond] {
+            ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+            _ => return facts,
+        };
+        let bits = match op {
+            Operator::I32Eqz
+            | Operator::I32Eq
+            | Operator::I32Ne
+            | Operator::I32LtU
+            | Operator::I32GtU
+            | Operator::I32LeU
+            | Operator::I32GeU => 32,
+            Operator::I64Eqz
+            | Operator::I64Eq
+            | Operator::I64Ne
+            | Operator::I64LtU
+            | Operator::I64GtU
+            | Operator::I64LeU
+            | Operator::I64GeU => 64,
+            _ => return facts,
+        };
+        let lim = limit(bits);
+        let full = ValueRange::full(bits);
+        let range = |v: Value| self.lookup(body, v).unwrap_or(full);
+        let a = body.resolve_alias(args[0]);
+
+        if let Operator::I32Eqz | Operator::I64Eqz = op {
+            facts.push((
+                a,
+                if taken {
+                    ValueRange::constant(0)
+                } else {
+                    ValueRange::new(1, lim)
+                },
+            ));
+            return facts;
+        }
+
+        let b = body.resolve_alias(args[1]);
+        let (ra, rb) = (range(a), range(b));
+        // Normalize to "lhs < rhs" or "lhs <= rhs" being true.
+        let (lhs, rhs, rl, rr, strict) = match (op, taken) {
+            (Operator::I32LtU | Operator::I64LtU, true) => (a, b, ra, rb, true),
+            (Operator::I32LtU | Operator::I64LtU, false) => (b, a, rb, ra, false),
+            (Operator::I32GtU | Operator::I64GtU, true) => (b, a, rb, ra, true),
+            (Operator::I32GtU | Operator::I64GtU, false) => (a, b, ra, rb, false),
+            (Operator::I32LeU | Operator::I64LeU, true) => (a, b, ra, rb, false),
+            (Operator::I32LeU | Operator::I64LeU, false) => (b, a, rb, ra, true),
+            (Operator::I32GeU | Operator::I64GeU, true) => (b, a, rb, ra, false),
+            (Operator::I32GeU | Operator::I64GeU, false) => (a, b, ra, rb, true),
+            (Operator::I32Eq | Operator::I64Eq, true)
+            | (Operator::I32Ne | Operator::I64Ne, false) => {
+                let both = ra.intersect(&rb);
+                facts.push((a, both));
+                facts.push((b, both));
+                return facts;
+            }
+            _ => return facts,
+        };
+        let gap = if strict { 1 } else { 0 };
+        if rr.max >= gap {
+            facts.push((lhs, ValueRange::new(0, rr.max - gap)));
+        }
+        if rl.min <= lim - gap {
+            facts.push((rhs, ValueRange::new(rl.min + gap, lim)));
+        }
+        facts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Memory, Module, SignatureData};
+    use crate::{MemoryArg, OptOptions};
+
+    #[test]
+    fn fold_masked_offset() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let mask = body.add_op(entry, Operator::I32Const { value: 0xff }, &[], &[Type::I32]);
+        let masked = body.add_op(entry, Operator::I32And, &[x, mask], &[Type::I32]);
+        let c = body.add_op(entry, Operator::I32Const { value: 16 }, &[], &[Type::I32]);
+        let addr = body.add_op(entry, Operator::I32Add, &[masked, c], &[Type::I32]);
+        let memory = MemoryArg {
+            align: 2,
+            offset: 4,
+            memory: Memory::new(0),
+        };
+        let load = body.add_op(entry, Operator::I32Load { memory }, &[addr], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![load] });
+
+        let cfg = CFGInfo::new(&body);
+        let ranges = RangeAnalysis::new(&body, &cfg);
+        assert_eq!(ranges.range_of(x), Some(ValueRange::full(32)));
+        assert_eq!(ranges.range_of(addr), Some(ValueRange::new(16, 0xff + 16)));
+
+        body.optimize(&OptOptions::default());
+        match body.values[load] {
+            ValueDef::Operator(Operator::I32Load { memory }, args, _) => {
+                assert_eq!(memory.offset, 20);
+                assert_eq!(body.arg_pool[args][0], masked);
+            }
+            _ => panic!("load was rewritten"),
+        }
+    }
+}
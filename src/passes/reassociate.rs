@@ -0,0 +1,486 @@
+//! Reassociation and algebraic simplification.
+//!
+//! Integer addition and multiplication are associative and
+//! commutative (they wrap), so a tree of them can be flattened into
+//! its leaves, all constant leaves folded into one, and the tree
+//! rebuilt in a canonical order: `(x + 1) + (y + 3)` becomes
+//! `(x + y) + 4`. Other commutative operators have their operands
+//! put in a canonical order, and compares are canonicalized by
+//! swapping operands (flipping the predicate) and by absorbing a
+//! negating `eqz`, so that equivalent expressions meet in GVN.
+
+use crate::entity::EntityRef;
+use crate::ir::{Block, FunctionBody, Type, Value, ValueDef};
+use crate::Operator;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chain {
+    Add,
+    Mul,
+}
+
+impl Chain {
+    fn identity(self) -> u64 {
+        match self {
+            Chain::Add => 0,
+            Chain::Mul => 1,
+        }
+    }
+
+    fn combine(self, a: u64, b: u64) -> u64 {
+        match self {
+            Chain::Add => a.wrapping_add(b),
+            Chain::Mul => a.wrapping_mul(b),
+        }
+    }
+
+    fn op(self, ty: Type) -> Operator {
+        match (self, ty) {
+            (Chain::Add, Type::I32) => Operator::I32Add,
+            (Chain::Add, _) => Operator::I64Add,
+            (Chain::Mul, Type::I32) => Operator::I32Mul,
+            (Chain::Mul, _) => Operator::I64Mul,
+        }
+    }
+}
+
+fn chain_of(op: &Operator) -> Option<(Chain, Type)> {
+    match op {
+        Operator::I32Add | Operator::I32Sub => Some((Chain::Add, Type::I32)),
+        Operator::I64Add | Operator::I64Sub => Some((Chain::Add, Type::I64)),
+        Operator::I32Mul => Some((Chain::Mul, Type::I32)),
+        Operator::I64Mul => Some((Chain::Mul, Type::I64)),
+        _ => None,
+    }
+}
+
+fn is_commutative(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Add
+            | Operator::I32Mul
+            | Operator::I32And
+            | Operator::I32Or
+            | Operator::I32Xor
+            | Operator::I32Eq
+            | Operator::I32Ne
+            | Operator::I64Add
+            | Operator::I64Mul
+            | Operator::I64And
+            | Operator::I64Or
+            | Operator::I64Xor
+            | Operator::I64Eq
+            | Operator::I64Ne
+    )
+}
+
+/// The predicate that gives the same result with operands swapped.
+fn swapped_compare(op: &Operator) -> Option<Operator> {
+    Some(match op {
+        Operator::I32LtS => Operator::I32GtS,
+        Operator::I32LtU => Operator::I32GtU,
+        Operator::I32GtS => Operator::I32LtS,
+        Operator::I32GtU => Operator::I32LtU,
+        Operator::I32LeS => Operator::I32GeS,
+        Operator::I32LeU => Operator::I32GeU,
+        Operator::I32GeS => Operator::I32LeS,
+        Operator::I32GeU => Operator::I32LeU,
+        Operator::I64LtS => Operator::I64GtS,
+        Operator::I64LtU => Operator::I64GtU,
+        Operator::I64GtS => Operator::I64LtS,
+        Operator::I64GtU => Operator::I64LtU,
+        Operator::I64LeS => Operator::I64GeS,
+        Operator::I64LeU => Operator::I64GeU,
+        Operator::I64GeS => Operator::I64LeS,
+        Operator::I64GeU => Operator::I64LeU,
+        _ => return None,
+    })
+}
+
+/// The predicate that gives the opposite result on the same
+/// operands. Only integer compares qualify; float compares are not
+/// complementary in the presence of NaN.
+fn inverted_compare(op: &Operator) -> Option<Operator> {
+    Some(match op {
+        Operator::I32Eq => Operator::I32Ne,
+        Operator::I32Ne => Operator::I32Eq,
+        Operator::I32LtS => Operator::I32GeS,
+        Operator::I32LtU => Operator::I32GeU,
+        Operator::I32GtS => Operator::I32LeS,
+        Operator::I32GtU => Operator::I32LeU,
+        Operator::I32LeS => Operator::I32GtS,
+        Operator::I32LeU => Operator::I32GtU,
+        Operator::I32GeS => Operator::I32LtS,
+        Operator::I32GeU => Operator::I32LtU,
+        Operator::I64Eq => Operator::I64Ne,
+        Operator::I64Ne => Operator::I64Eq,
+        Operator::I64LtS => Operator::I64GeS,
+        Operator::I64LtU => Operator::I64GeU,
+        Operator::I64GtS => Operator::I64LeS,
+        Operator::I64GtU => Operator::I64LeU,
+        Operator::I64LeS => Operator::I64GtS,
+        Operator::I64LeU => Operator::I64GtU,
+        Operator::I64GeS => Operator::I64LtS,
+        Operator::I64GeU => Operator::I64LtU,
+        _ => return None,
+    })
+}
+
+fn const_of(body: &FunctionBody, value: Value) -> Option<u64> {
+    match &body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value as u64),
+        ValueDef::Operator(Operator::I64Const { value }, _, _) => Some(*value),
+        _ => None,
+    }
+}
+
+fn const_op(ty: Type, value: u64) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Const {
+            value: value as u32,
+        },
+        _ => Operator::I64Const { value },
+    }
+}
+
+/// Use counts, and the single user (if an instruction) of each
+/// value used exactly once.
+struct Uses {
+    count: HashMap<Value, usize>,
+    user: HashMap<Value, Value>,
+}
+
+impl Uses {
+    fn compute(body: &FunctionBody) -> Uses {
+        let mut uses = Uses {
+            count: HashMap::new(),
+            user: HashMap::new(),
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                match &body.values[inst] {
+                    ValueDef::Operator(_, args, _) => {
+                        for &arg in &body.arg_pool[*args] {
+                            uses.add(body.resolve_alias(arg), inst);
+                        }
+                    }
+                    ValueDef::PickOutput(value, ..) => uses.add(body.resolve_alias(*value), inst),
+                    _ => {}
+                }
+            }
+            block.terminator.visit_uses(|value| {
+                uses.add(body.resolve_alias(value), Value::invalid());
+            });
+        }
+        uses
+    }
+
+    fn add(&mut self, value: Value, user: Value) {
+        *self.count.entry(value).or_insert(0) += 1;
+        self.user.insert(value, user);
+    }
+
+    /// The user of `value`, if it is used exactly once, by an
+    /// instruction.
+    fn sole_user(&self, value: Value) -> Option<Value> {
+        match self.count.get(&value) {
+            Some(1) => self
+                .user
+                .get(&value)
+                .copied()
+                .filter(|user| user.is_valid()),
+            _ => None,
+        }
+    }
+}
+
+/// The operands of a chain node of the given kind: `(operands,
+/// constant to combine)`. A subtract of a constant is an add of its
+/// negation.
+fn node_operands(
+    body: &FunctionBody,
+    value: Value,
+    chain: (Chain, Type),
+) -> Option<(Vec<Value>, Option<u64>)> {
+    let (op, args) = match &body.values[value] {
+        ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+        _ => return None,
+    };
+    if chain_of(op) != Some(chain) {
+        return None;
+    }
+    match op {
+        Operator::I32Sub | Operator::I64Sub => {
+            let c = const_of(body, args[1])?;
+            Some((vec![args[0]], Some(c.wrapping_neg())))
+        }
+        _ => Some((args.to_vec(), None)),
+    }
+}
+
+pub(crate) fn run(body: &mut FunctionBody) {
+    let uses = Uses::compute(body);
+    let mut removed: HashSet<Value> = HashSet::new();
+
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let insts = std::mem::take(&mut body.blocks[block].insts);
+        let mut out = Vec::with_capacity(insts.len());
+        for inst in insts {
+            if removed.contains(&inst) {
+                continue;
+            }
+            if reassociate(body, block, inst, &uses, &mut removed, &mut out) {
+                continue;
+            }
+            canonicalize(body, inst, &uses, &mut removed);
+            out.push(inst);
+        }
+        body.blocks[block].insts = out;
+    }
+
+    // Drop chain interiors and negated compares that were absorbed
+    // by a later user, possibly in another block.
+    if !removed.is_empty() {
+        for block in body.blocks.values_mut() {
+            block.insts.retain(|inst| !removed.contains(inst));
+        }
+    }
+}
+
+/// Try to rebuild the add/mul tree rooted at `root`. Returns `true`
+/// if `root` and any new instructions have been pushed onto `out`
+/// (or `root` became an alias and needs no instruction).
+fn reassociate(
+    body: &mut FunctionBody,
+    block: Block,
+    root: Value,
+    uses: &Uses,
+    removed: &mut HashSet<Value>,
+    out: &mut Vec<Value>,
+) -> bool {
+    let chain = match &body.values[root] {
+        ValueDef::Operator(op, ..) => match chain_of(op) {
+            Some(chain) => chain,
+            None => return false,
+        },
+        _ => return false,
+    };
+    if node_operands(body, root, chain).is_none() {
+        return false;
+    }
+    // A node whose only use is in a larger tree of the same kind is
+    // rebuilt as part of that tree instead.
+    let interior = |value: Value| {
+        uses.sole_user(value)
+            .map(|user| node_operands(body, user, chain).is_some())
+            .unwrap_or(false)
+    };
+    if interior(root) {
+        return false;
+    }
+
+    let (kind, ty) = chain;
+    let mut leaves = vec![];
+    let mut interiors = vec![];
+    let mut constant = kind.identity();
+    let mut constants = 0;
+    let mut stack = vec![root];
+    while let Some(value) = stack.pop() {
+        let value = body.resolve_alias(value);
+        if let Some(c) = const_of(body, value) {
+            constant = kind.combine(constant, c);
+            constants += 1;
+            continue;
+        }
+        if value == root || interior(value) {
+            if let Some((operands, c)) = node_operands(body, value, chain) {
+                if let Some(c) = c {
+                    constant = kind.combine(constant, c);
+                    constants += 1;
+                }
+                stack.extend(operands);
+                if value != root {
+                    interiors.push(value);
+                }
+                continue;
+            }
+        }
+        leaves.push(value);
+    }
+
+    if ty == Type::I32 {
+        constant &= 0xffff_ffff;
+    }
+    let absorbing = kind == Chain::Mul && constants > 0 && constant == 0;
+    if constants < 2 && !(constants == 1 && constant == kind.identity()) && !absorbing {
+        return false;
+    }
+    log::trace!(
+        "reassociate: {} has {} leaves and constant {}",
+        root,
+        leaves.len(),
+        constant
+    );
+    removed.extend(interiors);
+    if absorbing {
+        leaves.clear();
+    }
+
+    // Rebuild as a left-leaning chain over the leaves in value order,
+    // with the folded constant (if not the identity) last.
+    leaves.sort_by_key(|value| value.index());
+    let loc = body.source_locs[root];
+    let offset = body.wasm_offsets[root];
+    let tys = body.single_type_list(ty);
+    let mut new_inst = |body: &mut FunctionBody, op: Operator, args: &[Value]| {
+        let args = body.arg_pool.from_iter(args.iter().copied());
+        let value = body.add_value(ValueDef::Operator(op, args, tys));
+        body.value_blocks[value] = block;
+        body.source_locs[value] = loc;
+        body.wasm_offsets[value] = offset;
+        out.push(value);
+        value
+    };
+    let mut operands = leaves;
+    if constant != kind.identity() || operands.is_empty() {
+        if operands.is_empty() {
+            body.values[root] = ValueDef::Operator(const_op(ty, constant), Default::default(), tys);
+            out.push(root);
+            return true;
+        }
+        let c = new_inst(body, const_op(ty, constant), &[]);
+        operands.push(c);
+    }
+    if operands.len() == 1 {
+        body.set_alias(root, operands[0]);
+        return true;
+    }
+    let last = operands.pop().unwrap();
+    let mut acc = operands[0];
+    for &operand in &operands[1..] {
+        acc = new_inst(body, kind.op(ty), &[acc, operand]);
+    }
+    let args = body.arg_pool.double(acc, last);
+    body.values[root] = ValueDef::Operator(kind.op(ty), args, tys);
+    out.push(root);
+    true
+}
+
+/// Put the operands of a commutative operator or compare in
+/// canonical order (constants last, otherwise by value number), and
+/// fold an `eqz` of a singly-used integer compare into the compare.
+fn canonicalize(body: &mut FunctionBody, inst: Value, uses: &Uses, removed: &mut HashSet<Value>) {
+    let (op, args, tys) = match body.values[inst] {
+        ValueDef::Operator(op, args, tys) => (op, args, tys),
+        _ => return,
+    };
+
+    if op == Operator::I32Eqz {
+        let cmp = body.resolve_alias(body.arg_pool[args][0]);
+        if uses.sole_user(cmp) != Some(inst) {
+            return;
+        }
+        if let ValueDef::Operator(cmp_op, cmp_args, _) = body.values[cmp] {
+            if let Some(inverted) = inverted_compare(&cmp_op) {
+                let (a, b) = (body.arg_pool[cmp_args][0], body.arg_pool[cmp_args][1]);
+                let new_args = body.arg_pool.double(a, b);
+                body.values[inst] = ValueDef::Operator(inverted, new_args, tys);
+                removed.insert(cmp);
+                // The new compare may itself want its operands swapped.
+                canonicalize(body, inst, uses, removed);
+            }
+        }
+        return;
+    }
+
+    let swapped = if is_commutative(&op) {
+        op
+    } else {
+        match swapped_compare(&op) {
+            Some(swapped) => swapped,
+            None => return,
+        }
+    };
+    let (a, b) = (
+        body.resolve_alias(body.arg_pool[args][0]),
+        body.resolve_alias(body.arg_pool[args][1]),
+    );
+    let should_swap = match (const_of(body, a), const_of(body, b)) {
+        (Some(_), None) => true,
+        (None, None) => a.index() > b.index(),
+        _ => false,
+    };
+    if should_swap {
+        let new_args = body.arg_pool.double(b, a);
+        body.values[inst] = ValueDef::Operator(swapped, new_args, tys);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, Module, SignatureData};
+    use crate::{ConstVal, InterpContext, Terminator};
+
+    #[test]
+    fn fold_and_canonicalize() {
+        // f(x, y) = ((x + 1) + (y + 0xffff_ffff)) - (eqz (5 <u x))
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let (x, y) = (
+            body.blocks[entry].params[0].1,
+            body.blocks[entry].params[1].1,
+        );
+        let op = |body: &mut FunctionBody, op: Operator, args: &[Value]| {
+            body.add_op(entry, op, args, &[Type::I32])
+        };
+        let one = op(&mut body, Operator::I32Const { value: 1 }, &[]);
+        let minus_one = op(&mut body, Operator::I32Const { value: 0xffff_ffff }, &[]);
+        let five = op(&mut body, Operator::I32Const { value: 5 }, &[]);
+        let a = op(&mut body, Operator::I32Add, &[x, one]);
+        let b = op(&mut body, Operator::I32Add, &[y, minus_one]);
+        let sum = op(&mut body, Operator::I32Add, &[a, b]);
+        let cmp = op(&mut body, Operator::I32LtU, &[five, x]);
+        let not = op(&mut body, Operator::I32Eqz, &[cmp]);
+        let result = op(&mut body, Operator::I32Sub, &[sum, not]);
+        body.set_terminator(
+            entry,
+            Terminator::Return {
+                values: vec![result],
+            },
+        );
+
+        run(&mut body);
+        body.validate().unwrap();
+        let insts = &body.blocks[entry].insts;
+        assert!(!insts.contains(&a) && !insts.contains(&b) && !insts.contains(&cmp));
+        match body.values[sum] {
+            ValueDef::Operator(Operator::I32Add, args, _) => {
+                assert_eq!(&body.arg_pool[args], &[x, y]);
+            }
+            ref def => panic!("unexpected {:?}", def),
+        }
+        match body.values[not] {
+            ValueDef::Operator(Operator::I32LeU, args, _) => {
+                assert_eq!(&body.arg_pool[args], &[x, five]);
+            }
+            ref def => panic!("unexpected {:?}", def),
+        }
+
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        let mut ctx = InterpContext::new(&module).unwrap();
+        for (x, y, expected) in [(1, 2, 2), (10, 20, 30), (u32::MAX, 0, u32::MAX)] {
+            let args = [ConstVal::I32(x), ConstVal::I32(y)];
+            let result = ctx.call(&module, func, &args).ok().unwrap();
+            assert_eq!(result[0], ConstVal::I32(expected));
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Relaxed-SIMD determinization: replacing relaxed operators with
+//! strict ones, so that results do not depend on the engine.
+//!
+//! Each relaxed operator may give any of a few results, depending on
+//! what the hardware does fastest. Where reproducibility matters more
+//! than speed (as when several parties must agree on a computation),
+//! `determinize()` replaces each with strict code giving one of them:
+//!
+//! - Swizzles, truncations, lane selects, min/max and Q15 multiplies
+//!   become their strict counterparts.
+//! - Multiply-adds become a multiply then an add, rounding twice.
+//! - Dot products treat both operands as signed, and sum each pair of
+//!   products with saturation.
+//!
+//! As with `passes::atomics`, the IR has no relaxed operators, so this
+//! works on bytecode, before expansion, and expands the functions it
+//! rewrites.
+
+use super::bytecode::{expand_rewritten, rewrite_body};
+use crate::ir::{FuncDecl, Module};
+use anyhow::Result;
+use wasm_encoder::{Instruction, ValType};
+use wasmparser::Operator as O;
+
+/// A relaxed operator, classified.
+#[derive(Clone, Debug)]
+enum Relaxed {
+    /// Replaced by the strict operator.
+    Strict(Instruction<'static>),
+    /// `a * b + c`, or `-(a * b) + c` with a negation: the multiply,
+    /// the negation if any, and the add.
+    Madd(
+        Instruction<'static>,
+        Option<Instruction<'static>>,
+        Instruction<'static>,
+    ),
+    Dot,
+    DotAdd,
+}
+
+fn classify(op: &O) -> Option<Relaxed> {
+    use Instruction as I;
+    use Relaxed::*;
+    Some(match *op {
+        O::I8x16RelaxedSwizzle => Strict(I::I8x16Swizzle),
+        O::I32x4RelaxedTruncF32x4S => Strict(I::I32x4TruncSatF32x4S),
+        O::I32x4RelaxedTruncF32x4U => Strict(I::I32x4TruncSatF32x4U),
+        O::I32x4RelaxedTruncF64x2SZero => Strict(I::I32x4TruncSatF64x2SZero),
+        O::I32x4RelaxedTruncF64x2UZero => Strict(I::I32x4TruncSatF64x2UZero),
+        O::I8x16RelaxedLaneselect
+        | O::I16x8RelaxedLaneselect
+        | O::I32x4RelaxedLaneselect
+        | O::I64x2RelaxedLaneselect => Strict(I::V128Bitselect),
+        O::F32x4RelaxedMin => Strict(I::F32x4Min),
+        O::F32x4RelaxedMax => Strict(I::F32x4Max),
+        O::F64x2RelaxedMin => Strict(I::F64x2Min),
+        O::F64x2RelaxedMax => Strict(I::F64x2Max),
+        O::I16x8RelaxedQ15mulrS => Strict(I::I16x8Q15MulrSatS),
+
+        O::F32x4RelaxedMadd => Madd(I::F32x4Mul, None, I::F32x4Add),
+        O::F32x4RelaxedNmadd => Madd(I::F32x4Mul, Some(I::F32x4Neg), I::F32x4Add),
+        O::F64x2RelaxedMadd => Madd(I::F64x2Mul, None, I::F64x2Add),
+        O::F64x2RelaxedNmadd => Madd(I::F64x2Mul, Some(I::F64x2Neg), I::F64x2Add),
+        O::I16x8RelaxedDotI8x16I7x16S => Dot,
+        O::I32x4RelaxedDotI8x16I7x16AddS => DotAdd,
+
+        _ => return None,
+    })
+}
+
+/// `[a, b] -> [dot]` with `i16x8` lanes, through the scratch locals
+/// `a`, `b`, `lo` and `hi`: the products of the low and high halves,
+/// then the sum of their even and odd lanes.
+fn dot(a: u32, b: u32, lo: u32, hi: u32) -> Vec<Instruction<'static>> {
+    use Instruction as I;
+    let lanes = |first: u8| {
+        let mut lanes = [0; 16];
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            *lane = (i as u8 / 2) * 4 + first + i as u8 % 2;
+        }
+        lanes
+    };
+    vec![
+        I::LocalSet(b),
+        I::LocalSet(a),
+        I::LocalGet(a),
+        I::LocalGet(b),
+        I::I16x8ExtMulLowI8x16S,
+        I::LocalSet(lo),
+        I::LocalGet(a),
+        I::LocalGet(b),
+        I::I16x8ExtMulHighI8x16S,
+        I::LocalSet(hi),
+        I::LocalGet(lo),
+        I::LocalGet(hi),
+        I::I8x16Shuffle(lanes(0)),
+        I::LocalGet(lo),
+        I::LocalGet(hi),
+        I::I8x16Shuffle(lanes(2)),
+        I::I16x8AddSatS,
+    ]
+}
+
+/// The instructions replacing `relaxed`, with the five `v128` scratch
+/// locals from `next`.
+fn lower_op(relaxed: Relaxed, next: u32) -> Vec<Instruction<'static>> {
+    use Instruction as I;
+    let [a, b, c, lo, hi] = [next, next + 1, next + 2, next + 3, next + 4];
+    match relaxed {
+        Relaxed::Strict(inst) => vec![inst],
+        Relaxed::Madd(mul, neg, add) => {
+            let mut insts = vec![I::LocalSet(c), mul];
+            insts.extend(neg);
+            insts.extend([I::LocalGet(c), add]);
+            insts
+        }
+        Relaxed::Dot => dot(a, b, lo, hi),
+        Relaxed::DotAdd => {
+            let mut insts = vec![I::LocalSet(c)];
+            insts.extend(dot(a, b, lo, hi));
+            insts.extend([I::I32x4ExtAddPairwiseI16x8S, I::LocalGet(c), I::I32x4Add]);
+            insts
+        }
+    }
+}
+
+/// Rewrite the un-expanded functions of `module` that use relaxed SIMD
+/// with strict SIMD, expanding them. Returns how many there were.
+pub fn determinize(module: &mut Module) -> Result<usize> {
+    let mut rewritten = vec![];
+    for (func, decl) in module.funcs.entries() {
+        if let FuncDecl::Lazy(_, _, body) = decl {
+            let scratch = [(5, ValType::V128)];
+            if let Some(code) = rewrite_body(module, func, body, &scratch, classify, lower_op)? {
+                rewritten.push((func, body.range().start, code));
+            }
+        }
+    }
+    let count = rewritten.len();
+    for (func, offset, code) in rewritten {
+        expand_rewritten(module, func, offset, &code)?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::passes::downgrade::{downgrade, TargetFeatures};
+    use crate::passes::features::Feature;
+    use crate::{ConstVal, ExportKind, FrontendOptions, InterpContext};
+
+    #[test]
+    fn relaxed_simd_becomes_strict() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (func (export "dot") (param i32) (result i32)
+                  local.get 0
+                  i8x16.splat
+                  v128.const i8x16 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16
+                  i16x8.relaxed_dot_i8x16_i7x16_s
+                  i16x8.extract_lane_s 1)
+                (func (export "dot_sat") (param i32) (result i32)
+                  local.get 0
+                  i8x16.splat
+                  local.get 0
+                  i8x16.splat
+                  i16x8.relaxed_dot_i8x16_i7x16_s
+                  i16x8.extract_lane_s 0)
+                (func (export "dot_add") (param i32) (result i32)
+                  local.get 0
+                  i8x16.splat
+                  v128.const i8x16 2 2 2 2 2 2 2 2 2 2 2 2 2 2 2 2
+                  v128.const i32x4 100 100 100 100
+                  i32x4.relaxed_dot_i8x16_i7x16_add_s
+                  i32x4.extract_lane 3)
+                (func (export "madd") (param i32) (result i32)
+                  local.get 0
+                  f32.convert_i32_s
+                  f32x4.splat
+                  v128.const f32x4 2 2 2 2
+                  v128.const f32x4 1 1 1 1
+                  f32x4.relaxed_madd
+                  f32x4.extract_lane 0
+                  i32.trunc_f32_s)
+                (func (export "nmadd") (param i32) (result i32)
+                  local.get 0
+                  f64.convert_i32_s
+                  f64x2.splat
+                  v128.const f64x2 2 2
+                  v128.const f64x2 1 1
+                  f64x2.relaxed_nmadd
+                  f64x2.extract_lane 1
+                  i32.trunc_f64_s)
+                (func (export "swizzle") (param i32) (result i32)
+                  v128.const i8x16 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25
+                  local.get 0
+                  i8x16.splat
+                  i8x16.relaxed_swizzle
+                  i8x16.extract_lane_u 0)
+                (func (export "laneselect") (param i32) (result i32)
+                  v128.const i32x4 -1 -1 -1 -1
+                  v128.const i32x4 0 0 0 0
+                  local.get 0
+                  i32x4.splat
+                  i32x4.relaxed_laneselect
+                  i32x4.extract_lane 0)
+                (func (export "trunc") (param i32) (result i32)
+                  v128.const f32x4 nan 3e9 0 0
+                  i32x4.relaxed_trunc_f32x4_s
+                  local.get 0
+                  i32x4.splat
+                  i8x16.swizzle
+                  i32x4.extract_lane 0)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        assert!(module
+            .detect_features()
+            .unwrap()
+            .contains(Feature::RelaxedSimd));
+
+        let mut strict = module.clone();
+        assert_eq!(determinize(&mut strict).unwrap(), 8);
+        let features = strict.detect_features().unwrap();
+        assert!(!features.contains(Feature::RelaxedSimd));
+        assert!(features.contains(Feature::Simd));
+
+        // Scalarized, the strict code runs in the interpreter.
+        let report = downgrade(&mut module, &TargetFeatures::mvp()).unwrap();
+        assert_eq!(report.relaxed_simd, 8);
+        let cases: &[(&str, u32, u32)] = &[
+            ("dot", 5, 5 * 3 + 5 * 4),
+            ("dot_sat", -128i32 as u32, 0x7fff),
+            ("dot_add", 5, 4 * 5 * 2 + 100),
+            ("madd", 3, 7),
+            ("nmadd", 3, -5i32 as u32),
+            ("swizzle", 3, 13),
+            ("swizzle", 200, 0),
+            ("laneselect", 0x00ff_ff00, 0x00ff_ff00),
+            // Lanes 0 and 1, swizzled to lane 0 byte by byte: NaN
+            // truncates to 0, 3e9 to 0x7fffffff.
+            ("trunc", 0x0302_0100, 0),
+            ("trunc", 0x0706_0504, 0x7fff_ffff),
+        ];
+        for &(name, arg, expected) in cases {
+            let func = module
+                .exports
+                .iter()
+                .find_map(|export| match export.kind {
+                    ExportKind::Func(func) if export.name == name => Some(func),
+                    _ => None,
+                })
+                .unwrap();
+            let mut ctx = InterpContext::new(&module).unwrap();
+            let result = ctx.call(&module, func, &[ConstVal::I32(arg)]).ok().ok();
+            assert_eq!(
+                result.map(|r| r.to_vec()),
+                Some(vec![ConstVal::I32(expected)]),
+                "{}",
+                name
+            );
+        }
+    }
+}
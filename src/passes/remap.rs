@@ -0,0 +1,209 @@
+//! Renumbering of functions, tables, globals, and memories.
+//!
+//! Passes that move entities between modules (extraction, merging,
+//! splitting) build an `EntityMap` from old to new indices and apply
+//! it to every place an index can appear. Signatures are renumbered
+//! separately, by `signatures::visit_refs()`, since they also appear
+//! inside types.
+
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ir::{
+    ExportKind, FuncDecl, FunctionBody, ImportKind, InitExpr, Module, TableData, ValueDef,
+};
+use crate::{Func, Global, Memory, Operator, Table};
+use anyhow::Result;
+
+/// A map from old to new entity indices. Indices that were never
+/// assigned map to an invalid index.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EntityMap {
+    pub funcs: PerEntity<Func, Func>,
+    pub tables: PerEntity<Table, Table>,
+    pub globals: PerEntity<Global, Global>,
+    pub memories: PerEntity<Memory, Memory>,
+}
+
+impl EntityMap {
+    /// Renumber every entity an expanded body refers to.
+    pub fn body(&self, body: &mut FunctionBody) {
+        for value in body.values.values_mut() {
+            let op = match value {
+                ValueDef::Operator(op, ..) => op,
+                _ => continue,
+            };
+            op.update_memory_arg(|memory| memory.memory = self.memories[memory.memory]);
+            match op {
+                Operator::Call { function_index }
+                | Operator::RefFunc {
+                    func_index: function_index,
+                } => *function_index = self.funcs[*function_index],
+                Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                    *global_index = self.globals[*global_index];
+                }
+                Operator::CallIndirect { table_index, .. }
+                | Operator::TableGet { table_index }
+                | Operator::TableSet { table_index }
+                | Operator::TableGrow { table_index }
+                | Operator::TableSize { table_index } => {
+                    *table_index = self.tables[*table_index];
+                }
+                Operator::MemorySize { mem }
+                | Operator::MemoryGrow { mem }
+                | Operator::MemoryFill { mem } => *mem = self.memories[*mem],
+                Operator::MemoryCopy { dst_mem, src_mem } => {
+                    *dst_mem = self.memories[*dst_mem];
+                    *src_mem = self.memories[*src_mem];
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Renumber the globals a global initializer reads.
+    pub fn init(&self, init: &mut InitExpr) {
+        match init {
+            InitExpr::Const(..) => {}
+            InitExpr::GlobalGet(global) => *global = self.globals[*global],
+            InitExpr::Binary(_, a, b) => {
+                self.init(a);
+                self.init(b);
+            }
+        }
+    }
+
+    /// Renumber the (non-null) function elements of a table.
+    pub fn table(&self, data: &mut TableData) {
+        for elt in data.func_elements.iter_mut().flatten() {
+            if elt.is_valid() {
+                *elt = self.funcs[*elt];
+            }
+        }
+    }
+
+    /// Renumber the entity an export refers to.
+    pub fn export(&self, kind: &mut ExportKind) {
+        match kind {
+            ExportKind::Func(func) => *func = self.funcs[*func],
+            ExportKind::Table(table) => *table = self.tables[*table],
+            ExportKind::Global(global) => *global = self.globals[*global],
+            ExportKind::Memory(memory) => *memory = self.memories[*memory],
+        }
+    }
+
+    /// Renumber the entity an import defines.
+    pub fn import(&self, kind: &mut ImportKind) {
+        match kind {
+            ImportKind::Func(func) => *func = self.funcs[*func],
+            ImportKind::Table(table) => *table = self.tables[*table],
+            ImportKind::Global(global) => *global = self.globals[*global],
+            ImportKind::Memory(memory) => *memory = self.memories[*memory],
+        }
+    }
+
+    /// The map that sends every entity of `module` to itself.
+    pub(crate) fn identity(module: &Module) -> EntityMap {
+        let mut map = EntityMap::default();
+        for func in module.funcs.iter() {
+            map.funcs[func] = func;
+        }
+        for table in module.tables.iter() {
+            map.tables[table] = table;
+        }
+        for global in module.globals.iter() {
+            map.globals[global] = global;
+        }
+        for memory in module.memories.iter() {
+            map.memories[memory] = memory;
+        }
+        map
+    }
+
+    /// Renumber every reference to an entity in `module`, other than
+    /// the definitions themselves.
+    pub(crate) fn apply(&self, module: &mut Module) {
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                self.body(body);
+            }
+        }
+        for table in module.tables.values_mut() {
+            self.table(table);
+        }
+        for global in module.globals.values_mut() {
+            if let Some(init) = &mut global.init {
+                self.init(init);
+            }
+        }
+        for import in &mut module.imports {
+            self.import(&mut import.kind);
+        }
+        for export in &mut module.exports {
+            self.export(&mut export.kind);
+        }
+        module.start_func = module.start_func.map(|func| self.funcs[func]);
+    }
+}
+
+/// Permute `items` so that the first `fixed` stay put and the rest
+/// appear in `order`, which lists each of those exactly once.
+fn permute<Idx: EntityRef, T: Clone + std::fmt::Debug>(
+    items: &mut EntityVec<Idx, T>,
+    fixed: usize,
+    order: &[Idx],
+) {
+    debug_assert_eq!(fixed + order.len(), items.len());
+    let mut old = std::mem::take(items)
+        .into_vec()
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    for item in old.iter_mut().take(fixed) {
+        items.push(item.take().unwrap());
+    }
+    for &idx in order {
+        items.push(old[idx.index()].take().unwrap());
+    }
+}
+
+/// Reorder the functions with bodies so that they appear in `order`,
+/// after the imported functions, and renumber every reference to
+/// them. `order` must list each function that is not an import
+/// exactly once. Function bodies are expanded first.
+pub(crate) fn reorder_funcs(module: &mut Module, order: &[Func]) -> Result<()> {
+    module.expand_all_funcs()?;
+    let num_imports = module
+        .funcs
+        .values()
+        .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count();
+
+    let mut map = EntityMap::identity(module);
+    for (i, &func) in order.iter().enumerate() {
+        map.funcs[func] = Func::new(num_imports + i);
+    }
+    permute(&mut module.funcs, num_imports, order);
+    map.apply(module);
+    Ok(())
+}
+
+/// Reorder the globals that are not imports so that they appear in
+/// `order`, after the imported globals, and renumber every reference
+/// to them. `order` must list each such global exactly once, and
+/// after any global its initializer reads. Function bodies are
+/// expanded first.
+pub(crate) fn reorder_globals(module: &mut Module, order: &[Global]) -> Result<()> {
+    module.expand_all_funcs()?;
+    let num_imports = module
+        .imports
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Global(_)))
+        .count();
+
+    let mut map = EntityMap::identity(module);
+    for (i, &global) in order.iter().enumerate() {
+        map.globals[global] = Global::new(num_imports + i);
+    }
+    permute(&mut module.globals, num_imports, order);
+    map.apply(module);
+    Ok(())
+}
@@ -1,4 +1,12 @@
 //! Resolve all aliases.
+//!
+//! Transforms that replace a value make it an alias of its
+//! replacement, and aliases of aliases accumulate into chains that
+//! every later use must walk. This pass rewrites every use (operator
+//! arguments, picked outputs, terminators, and debug values) to the
+//! value at the end of its chain, removes aliases from blocks, and
+//! clears their definitions to `ValueDef::None`. The backend runs it
+//! on any body with aliases before emitting code.
 
 use crate::{FunctionBody, ValueDef};
 
@@ -29,11 +37,73 @@ pub fn run(body: &mut FunctionBody) {
     }
     let mut blocks = std::mem::take(&mut body.blocks);
     for block in blocks.values_mut() {
-        block.terminator.update_targets(|target| {
-            for arg in &mut target.args {
-                *arg = body.resolve_alias(*arg);
-            }
-        });
+        // This includes the targets' arguments.
+        block
+            .terminator
+            .update_uses(|value| *value = body.resolve_alias(*value));
     }
     body.blocks = blocks;
+    let debug_values = std::mem::take(&mut body.debug_values);
+    body.debug_values = debug_values.map_values(|value| body.resolve_alias(value));
+
+    // Every alias now points directly at its target, and nothing uses
+    // an alias, so the definitions can go.
+    let values = &body.values;
+    for block in body.blocks.values_mut() {
+        block
+            .insts
+            .retain(|&inst| !matches!(values[inst], ValueDef::Alias(..)));
+    }
+    for value in body.values.values_mut() {
+        if let ValueDef::Alias(..) = value {
+            *value = ValueDef::None;
+        }
+    }
+    body.mark_changed();
+}
+
+/// Whether `body` has any aliases for `run()` to resolve.
+pub fn has_aliases(body: &FunctionBody) -> bool {
+    body.values
+        .values()
+        .any(|value| matches!(value, ValueDef::Alias(..)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Module, SignatureData, Terminator, Type};
+    use crate::Operator;
+
+    #[test]
+    fn alias_chains_are_flattened() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let a = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let b = body.add_op(entry, Operator::I32Const { value: 2 }, &[], &[Type::I32]);
+        let sum = body.add_op(entry, Operator::I32Add, &[a, b], &[Type::I32]);
+        body.set_terminator(entry, Terminator::Return { values: vec![sum] });
+        // a -> b -> x
+        body.set_alias(b, x);
+        body.set_alias(a, b);
+        let hash = body.structural_hash();
+
+        assert!(has_aliases(&body));
+        run(&mut body);
+        assert!(!has_aliases(&body));
+        assert_eq!(body.blocks[entry].insts, vec![sum]);
+        let ValueDef::Operator(_, args, _) = &body.values[sum] else {
+            unreachable!()
+        };
+        assert_eq!(&body.arg_pool[*args], &[x, x]);
+        assert_eq!(body.values[a], ValueDef::None);
+        body.validate().unwrap();
+        assert_eq!(body.structural_hash(), hash);
+    }
 }
@@ -0,0 +1,148 @@
+//! Read-only data: placing constant blobs, such as strings and
+//! lookup tables for instrumentation, into guest memory.
+//!
+//! Blobs go into the main memory (memory 0), where the guest will not
+//! otherwise use the space:
+//!
+//! - If the module follows the usual toolchain convention of an
+//!   exported `__heap_base` global, a blob is placed at the heap base,
+//!   which is then moved past it, so that the guest's allocator never
+//!   hands the space out.
+//! - Otherwise, a blob is placed after the highest data segment. This
+//!   is only sound if the guest does not use memory above its static
+//!   data without allocating it first (with `memory.grow`).
+//!
+//! Either way, the memory's initial size grows to cover the blob.
+//! Identical blobs share one copy; see `Module::rodata`.
+
+use super::shrink_memory::{exported_global, i32_init};
+use crate::entity::EntityRef;
+use crate::ir::{ImportKind, Memory, MemorySegment, Module, WASM_PAGE};
+use anyhow::{bail, Result};
+
+/// Blobs are aligned to this many bytes, enough for any scalar.
+const ALIGN: usize = 8;
+
+fn align(addr: usize) -> usize {
+    (addr + ALIGN - 1) & !(ALIGN - 1)
+}
+
+/// Place `data` in the main memory, returning the memory and the
+/// address of the blob. See the module documentation.
+pub fn add(module: &mut Module, data: &[u8]) -> Result<(Memory, u64)> {
+    if let Some(&placed) = module.rodata.get(data) {
+        return Ok(placed);
+    }
+    let memory = Memory::new(0);
+    if memory.index() >= module.memories.len() {
+        bail!("Module has no memory to place data in");
+    }
+    if module
+        .imports
+        .iter()
+        .any(|import| import.kind == ImportKind::Memory(memory))
+    {
+        bail!("Cannot place data in imported {}", memory);
+    }
+
+    let data_end = module.memories[memory]
+        .segments
+        .iter()
+        .map(|seg| seg.offset + seg.data.len())
+        .max()
+        .unwrap_or(0);
+    let heap_base = exported_global(module, "__heap_base")
+        .and_then(|global| i32_init(module, global).map(|addr| (global, addr)));
+    let offset = match heap_base {
+        Some((_, addr)) => align(std::cmp::max(addr, data_end)),
+        None => align(data_end),
+    };
+    let end = offset + data.len();
+    // Keep the heap base at least as aligned as toolchains leave it.
+    let top = match heap_base {
+        Some(_) => (end + 15) & !15,
+        None => end,
+    };
+    if top > u32::MAX as usize {
+        bail!("No room for {} bytes of data in {}", data.len(), memory);
+    }
+
+    let mem_data = &mut module.memories[memory];
+    let pages = top.div_ceil(WASM_PAGE);
+    if mem_data.maximum_pages.is_some_and(|max| pages > max) {
+        bail!("No room for {} bytes of data in {}", data.len(), memory);
+    }
+    mem_data.initial_pages = std::cmp::max(mem_data.initial_pages, pages);
+    mem_data.segments.push(MemorySegment {
+        offset,
+        data: data.to_vec(),
+    });
+    if let Some((global, _)) = heap_base {
+        module.globals[global].value = Some(top as u64);
+    }
+
+    let placed = (memory, offset as u64);
+    module.rodata.insert(data.to_vec(), placed);
+    log::debug!(
+        "rodata: placed {} bytes in {} at {:#x}",
+        data.len(),
+        memory,
+        offset
+    );
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Export, GlobalData, MemoryData, Type};
+    use crate::ExportKind;
+
+    fn memory(module: &mut Module, pages: usize) {
+        module.memories.push(MemoryData {
+            initial_pages: pages,
+            maximum_pages: None,
+            segments: vec![MemorySegment {
+                offset: 1024,
+                data: vec![1, 2, 3],
+            }],
+        });
+    }
+
+    #[test]
+    fn blobs_follow_data_and_dedup() {
+        let mut module = Module::empty();
+        assert!(module.add_rodata(b"hello").is_err());
+        memory(&mut module, 0);
+
+        let (mem, hello) = module.add_rodata(b"hello").unwrap();
+        assert_eq!((mem, hello), (Memory::new(0), 1032));
+        let (_, world) = module.add_rodata(b"world").unwrap();
+        assert_eq!(world, 1040);
+        assert_eq!(module.add_rodata(b"hello").unwrap().1, hello);
+        assert_eq!(module.memories[mem].segments.len(), 3);
+        assert_eq!(module.memories[mem].initial_pages, 1);
+        let _ = module.to_wasm_bytes().unwrap();
+    }
+
+    #[test]
+    fn blobs_move_heap_base() {
+        let mut module = Module::empty();
+        memory(&mut module, 1);
+        let heap_base = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(0x1_0000),
+            mutable: false,
+            init: None,
+        });
+        module.exports.push(Export {
+            name: "__heap_base".to_owned(),
+            kind: ExportKind::Global(heap_base),
+        });
+
+        let (_, table) = module.add_rodata(&[7; 100]).unwrap();
+        assert_eq!(table, 0x1_0000);
+        assert_eq!(module.globals[heap_base].value, Some(0x1_0070));
+        assert_eq!(module.memories[Memory::new(0)].initial_pages, 2);
+    }
+}
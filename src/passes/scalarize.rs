@@ -0,0 +1,1411 @@
+//! SIMD scalarization: lowering `v128` code into scalar code for
+//! engines without SIMD.
+//!
+//! Each `v128` value becomes two `i64` values, its low and high
+//! halves, and so does each `v128` blockparam, parameter and result;
+//! signatures change to match, so a function returning a `v128`
+//! returns two values (which `downgrade()` lowers in turn if the
+//! target lacks multi-value). Bitwise operators work on the halves
+//! directly. Lanewise operators extract each lane of their operands
+//! with shifts and wraps, compute it with scalar operators (narrow
+//! integer lanes as `i32`s), and reassemble the halves; memory
+//! accesses become scalar ones.
+//!
+//! The result is larger and slower than the SIMD code, but runs on
+//! old engines, so one build can serve both. Functions that are
+//! imported or exported with `v128` in their signature cannot change
+//! signature, nor can `v128` globals change type; `scalarize()` fails
+//! on those.
+
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, ExportKind, FunctionBody, ImportKind, Module, Terminator, Type, Value, ValueDef,
+};
+use crate::{MemoryArg, Operator};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// Scalarize every function of `module`, which must be expanded.
+/// Returns how many functions used `v128`.
+pub fn scalarize(module: &mut Module) -> Result<usize> {
+    let has_v128 = |tys: &[Type]| tys.contains(&Type::V128);
+    for import in &module.imports {
+        if let ImportKind::Func(func) = import.kind {
+            let sig = &module.signatures[module.funcs[func].sig()];
+            if has_v128(&sig.params) || has_v128(&sig.returns) {
+                bail!(
+                    "Cannot scalarize the signature of import {}.{}",
+                    import.module,
+                    import.name
+                );
+            }
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Func(func) = export.kind {
+            let sig = &module.signatures[module.funcs[func].sig()];
+            if has_v128(&sig.params) || has_v128(&sig.returns) {
+                bail!("Cannot scalarize the signature of export {}", export.name);
+            }
+        }
+    }
+    if module
+        .globals
+        .values()
+        .any(|global| global.ty == Type::V128)
+    {
+        bail!("Cannot scalarize a v128 global");
+    }
+
+    let mut count = 0;
+    for decl in module.funcs.values_mut() {
+        if let Some(body) = decl.body_mut() {
+            if uses_v128(body) {
+                scalarize_body(body)?;
+                count += 1;
+            }
+        }
+    }
+    for sig in module.signatures.values_mut() {
+        sig.params = expand_types(&sig.params);
+        sig.returns = expand_types(&sig.returns);
+    }
+    Ok(count)
+}
+
+fn expand_types(tys: &[Type]) -> Vec<Type> {
+    tys.iter()
+        .flat_map(|&ty| match ty {
+            Type::V128 => vec![Type::I64, Type::I64],
+            ty => vec![ty],
+        })
+        .collect()
+}
+
+fn uses_v128(body: &FunctionBody) -> bool {
+    body.rets.contains(&Type::V128)
+        || body
+            .values
+            .values()
+            .any(|def| def.tys(&body.type_pool).contains(&Type::V128))
+}
+
+/// The lane shape of a `v128`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Shape {
+    I8x16,
+    I16x8,
+    I32x4,
+    I64x2,
+    F32x4,
+    F64x2,
+}
+
+impl Shape {
+    fn lane_bits(self) -> u32 {
+        match self {
+            Shape::I8x16 => 8,
+            Shape::I16x8 => 16,
+            Shape::I32x4 | Shape::F32x4 => 32,
+            Shape::I64x2 | Shape::F64x2 => 64,
+        }
+    }
+
+    fn lanes(self) -> u32 {
+        128 / self.lane_bits()
+    }
+
+    /// The type of a lane as a scalar; narrow lanes are `i32`s.
+    fn scalar(self) -> Type {
+        match self {
+            Shape::I8x16 | Shape::I16x8 | Shape::I32x4 => Type::I32,
+            Shape::I64x2 => Type::I64,
+            Shape::F32x4 => Type::F32,
+            Shape::F64x2 => Type::F64,
+        }
+    }
+
+    /// The integer shape with lanes of the same width.
+    fn int(self) -> Shape {
+        match self {
+            Shape::F32x4 => Shape::I32x4,
+            Shape::F64x2 => Shape::I64x2,
+            shape => shape,
+        }
+    }
+
+    /// Pick the operator for the shape's scalar type.
+    fn pick(self, i32_op: Operator, i64_op: Operator) -> Operator {
+        match self.scalar() {
+            Type::I64 | Type::F64 => i64_op,
+            _ => i32_op,
+        }
+    }
+}
+
+/// A lanewise binary operation on scalars.
+#[derive(Clone, Copy)]
+enum Bin {
+    Op(Operator),
+    /// `a` if `a <cmp> b`, else `b`.
+    Pick(Operator),
+    /// `b` if `b <cmp> a`, else `a`.
+    PickRev(Operator),
+    /// `a <op> b`, saturated to `[min, max]` with signed compares.
+    Sat(Operator, i64, i64),
+    /// `(a + b + 1) >> 1`.
+    Avgr,
+    /// `(a * b + 0x4000) >> 15`, saturated.
+    Q15Mulr,
+    /// All ones if `a <cmp> b`, else zero.
+    Cmp(Operator),
+}
+
+/// The halves of a `v128`, or the scalar result of an operator on
+/// `v128`s.
+enum Lowered {
+    Vector(Value, Value),
+    Scalar(Value),
+    Nothing,
+}
+
+/// Scalarization state: the halves of each `v128` value, and where
+/// new code goes.
+struct Scalarizer<'a> {
+    body: &'a mut FunctionBody,
+    halves: HashMap<Value, (Value, Value)>,
+    block: Block,
+    loc: Value,
+}
+
+impl<'a> Scalarizer<'a> {
+    fn op(&mut self, op: Operator, args: &[Value], ty: Type) -> Value {
+        let value = self.body.add_op(self.block, op, args, &[ty]);
+        self.body.copy_loc(self.loc, value);
+        value
+    }
+
+    fn effect(&mut self, op: Operator, args: &[Value]) {
+        let value = self.body.add_op(self.block, op, args, &[]);
+        self.body.copy_loc(self.loc, value);
+    }
+
+    fn i32(&mut self, value: u32) -> Value {
+        self.op(Operator::I32Const { value }, &[], Type::I32)
+    }
+
+    fn i64(&mut self, value: u64) -> Value {
+        self.op(Operator::I64Const { value }, &[], Type::I64)
+    }
+
+    /// A scalar constant of the shape's lane type, from its bits.
+    fn scalar_const(&mut self, shape: Shape, bits: u64) -> Value {
+        let op = match shape.scalar() {
+            Type::I32 => Operator::I32Const { value: bits as u32 },
+            Type::I64 => Operator::I64Const { value: bits },
+            Type::F32 => Operator::F32Const { value: bits as u32 },
+            _ => Operator::F64Const { value: bits },
+        };
+        self.op(op, &[], shape.scalar())
+    }
+
+    fn halves(&self, value: Value) -> (Value, Value) {
+        self.halves[&self.body.resolve_alias(value)]
+    }
+
+    /// Lane `i` of `value`, as a scalar. Narrow lanes are zero- or
+    /// sign-extended.
+    fn lane(&mut self, value: Value, shape: Shape, i: u32, signed: bool) -> Value {
+        let (lo, hi) = self.halves(value);
+        let bits = shape.lane_bits();
+        let half = if i * bits < 64 { lo } else { hi };
+        let shift = (i * bits) % 64;
+        let half = match shift {
+            0 => half,
+            _ => {
+                let shift = self.i64(shift as u64);
+                self.op(Operator::I64ShrU, &[half, shift], Type::I64)
+            }
+        };
+        match shape {
+            Shape::I64x2 => half,
+            Shape::F64x2 => self.op(Operator::F64ReinterpretI64, &[half], Type::F64),
+            _ => {
+                let word = self.op(Operator::I32WrapI64, &[half], Type::I32);
+                match (shape, signed) {
+                    (Shape::I32x4, _) => word,
+                    (Shape::F32x4, _) => self.op(Operator::F32ReinterpretI32, &[word], Type::F32),
+                    (Shape::I8x16, true) => self.op(Operator::I32Extend8S, &[word], Type::I32),
+                    (Shape::I16x8, true) => self.op(Operator::I32Extend16S, &[word], Type::I32),
+                    (_, false) => {
+                        let mask = self.i32((1 << bits) - 1);
+                        self.op(Operator::I32And, &[word, mask], Type::I32)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    fn lanes(&mut self, value: Value, shape: Shape, signed: bool) -> Vec<Value> {
+        (0..shape.lanes())
+            .map(|i| self.lane(value, shape, i, signed))
+            .collect()
+    }
+
+    /// Assemble the halves of a `v128` from its lanes; narrow lanes
+    /// are truncated.
+    fn build(&mut self, shape: Shape, lanes: &[Value]) -> Lowered {
+        let bits = shape.lane_bits();
+        let mut halves = vec![];
+        for chunk in lanes.chunks((64 / bits) as usize) {
+            let mut half = None;
+            for (j, &lane) in chunk.iter().enumerate() {
+                let bits64 = match shape {
+                    Shape::I64x2 => lane,
+                    Shape::F64x2 => self.op(Operator::I64ReinterpretF64, &[lane], Type::I64),
+                    _ => {
+                        let word = match shape {
+                            Shape::F32x4 => {
+                                self.op(Operator::I32ReinterpretF32, &[lane], Type::I32)
+                            }
+                            Shape::I32x4 => lane,
+                            _ => {
+                                let mask = self.i32((1 << bits) - 1);
+                                self.op(Operator::I32And, &[lane, mask], Type::I32)
+                            }
+                        };
+                        self.op(Operator::I64ExtendI32U, &[word], Type::I64)
+                    }
+                };
+                let shift = j as u64 * bits as u64;
+                let shifted = match shift {
+                    0 => bits64,
+                    _ => {
+                        let shift = self.i64(shift);
+                        self.op(Operator::I64Shl, &[bits64, shift], Type::I64)
+                    }
+                };
+                half = Some(match half {
+                    None => shifted,
+                    Some(half) => self.op(Operator::I64Or, &[half, shifted], Type::I64),
+                });
+            }
+            halves.push(half.unwrap());
+        }
+        Lowered::Vector(halves[0], halves[1])
+    }
+
+    /// Apply `f` to each lane of the operands, which have shape `from`,
+    /// to build a `v128` of shape `to`.
+    fn map(
+        &mut self,
+        from: Shape,
+        to: Shape,
+        signed: bool,
+        args: &[Value],
+        mut f: impl FnMut(&mut Self, &[Value]) -> Value,
+    ) -> Lowered {
+        let lanes = args
+            .iter()
+            .map(|&arg| self.lanes(arg, from, signed))
+            .collect::<Vec<_>>();
+        let results = (0..from.lanes() as usize)
+            .map(|i| {
+                let operands = lanes.iter().map(|lanes| lanes[i]).collect::<Vec<_>>();
+                f(self, &operands)
+            })
+            .collect::<Vec<_>>();
+        self.build(to, &results)
+    }
+
+    fn bitwise(&mut self, op: Operator, a: Value, b: Value) -> Lowered {
+        let ((alo, ahi), (blo, bhi)) = (self.halves(a), self.halves(b));
+        let lo = self.op(op, &[alo, blo], Type::I64);
+        let hi = self.op(op, &[ahi, bhi], Type::I64);
+        Lowered::Vector(lo, hi)
+    }
+
+    fn not(&mut self, value: Value) -> Value {
+        let ones = self.i64(u64::MAX);
+        self.op(Operator::I64Xor, &[value, ones], Type::I64)
+    }
+
+    fn select(&mut self, ty: Type, a: Value, b: Value, cond: Value) -> Value {
+        self.op(Operator::Select, &[a, b, cond], ty)
+    }
+
+    /// `x` clamped to `[min, max]` as an `i32`.
+    fn clamp(&mut self, x: Value, min: i64, max: i64) -> Value {
+        let min = self.i32(min as u32);
+        let below = self.op(Operator::I32LtS, &[x, min], Type::I32);
+        let x = self.select(Type::I32, min, x, below);
+        let max = self.i32(max as u32);
+        let above = self.op(Operator::I32GtS, &[x, max], Type::I32);
+        self.select(Type::I32, max, x, above)
+    }
+
+    fn binary(&mut self, shape: Shape, signed: bool, bin: Bin, args: &[Value]) -> Lowered {
+        let ty = shape.scalar();
+        let to = match bin {
+            Bin::Cmp(..) => shape.int(),
+            _ => shape,
+        };
+        self.map(shape, to, signed, args, |s, lanes| {
+            let (a, b) = (lanes[0], lanes[1]);
+            match bin {
+                Bin::Op(op) => s.op(op, &[a, b], ty),
+                Bin::Pick(cmp) => {
+                    let cond = s.op(cmp, &[a, b], Type::I32);
+                    s.select(ty, a, b, cond)
+                }
+                Bin::PickRev(cmp) => {
+                    let cond = s.op(cmp, &[b, a], Type::I32);
+                    s.select(ty, b, a, cond)
+                }
+                Bin::Sat(op, min, max) => {
+                    let x = s.op(op, &[a, b], Type::I32);
+                    s.clamp(x, min, max)
+                }
+                Bin::Avgr => {
+                    let sum = s.op(Operator::I32Add, &[a, b], Type::I32);
+                    let one = s.i32(1);
+                    let sum = s.op(Operator::I32Add, &[sum, one], Type::I32);
+                    s.op(Operator::I32ShrU, &[sum, one], Type::I32)
+                }
+                Bin::Q15Mulr => {
+                    let product = s.op(Operator::I32Mul, &[a, b], Type::I32);
+                    let round = s.i32(0x4000);
+                    let product = s.op(Operator::I32Add, &[product, round], Type::I32);
+                    let shift = s.i32(15);
+                    let x = s.op(Operator::I32ShrS, &[product, shift], Type::I32);
+                    s.clamp(x, -0x8000, 0x7fff)
+                }
+                Bin::Cmp(cmp) => {
+                    let cond = s.op(cmp, &[a, b], Type::I32);
+                    let ones = s.scalar_const(shape.int(), u64::MAX);
+                    let zero = s.scalar_const(shape.int(), 0);
+                    s.select(shape.int().scalar(), ones, zero, cond)
+                }
+            }
+        })
+    }
+
+    fn unary(&mut self, shape: Shape, op: Operator, arg: Value) -> Lowered {
+        let ty = shape.scalar();
+        self.map(shape, shape, false, &[arg], |s, lanes| {
+            s.op(op, &[lanes[0]], ty)
+        })
+    }
+
+    fn int_neg(&mut self, shape: Shape, arg: Value, abs: bool) -> Lowered {
+        let ty = shape.scalar();
+        let sub = shape.pick(Operator::I32Sub, Operator::I64Sub);
+        let lt = shape.pick(Operator::I32LtS, Operator::I64LtS);
+        self.map(shape, shape, true, &[arg], |s, lanes| {
+            let zero = s.scalar_const(shape, 0);
+            let neg = s.op(sub, &[zero, lanes[0]], ty);
+            match abs {
+                false => neg,
+                true => {
+                    let negative = s.op(lt, &[lanes[0], zero], Type::I32);
+                    s.select(ty, neg, lanes[0], negative)
+                }
+            }
+        })
+    }
+
+    fn shift(&mut self, shape: Shape, op: Operator, signed: bool, args: &[Value]) -> Lowered {
+        let ty = shape.scalar();
+        let mask = self.i32(shape.lane_bits() - 1);
+        let count = self.op(Operator::I32And, &[args[1], mask], Type::I32);
+        let count = match ty {
+            Type::I64 => self.op(Operator::I64ExtendI32U, &[count], Type::I64),
+            _ => count,
+        };
+        self.map(shape, shape, signed, &args[..1], |s, lanes| {
+            s.op(op, &[lanes[0], count], ty)
+        })
+    }
+
+    fn splat(&mut self, shape: Shape, value: Value) -> Lowered {
+        let lanes = vec![value; shape.lanes() as usize];
+        self.build(shape, &lanes)
+    }
+
+    fn replace_lane(&mut self, shape: Shape, lane: u8, args: &[Value]) -> Lowered {
+        let mut lanes = self.lanes(args[0], shape, false);
+        lanes[lane as usize] = args[1];
+        self.build(shape, &lanes)
+    }
+
+    fn all_true(&mut self, shape: Shape, arg: Value) -> Lowered {
+        let ne = shape.pick(Operator::I32Ne, Operator::I64Ne);
+        let lanes = self.lanes(arg, shape, false);
+        let mut all = None;
+        for lane in lanes {
+            let zero = self.scalar_const(shape, 0);
+            let nonzero = self.op(ne, &[lane, zero], Type::I32);
+            all = Some(match all {
+                None => nonzero,
+                Some(all) => self.op(Operator::I32And, &[all, nonzero], Type::I32),
+            });
+        }
+        Lowered::Scalar(all.unwrap())
+    }
+
+    fn bitmask(&mut self, shape: Shape, arg: Value) -> Lowered {
+        let lanes = self.lanes(arg, shape, false);
+        let mut mask = None;
+        for (i, lane) in lanes.into_iter().enumerate() {
+            let top = match shape.scalar() {
+                Type::I64 => {
+                    let shift = self.i64(63);
+                    let top = self.op(Operator::I64ShrU, &[lane, shift], Type::I64);
+                    self.op(Operator::I32WrapI64, &[top], Type::I32)
+                }
+                _ => {
+                    let shift = self.i32(shape.lane_bits() - 1);
+                    self.op(Operator::I32ShrU, &[lane, shift], Type::I32)
+                }
+            };
+            let bit = match i {
+                0 => top,
+                _ => {
+                    let shift = self.i32(i as u32);
+                    self.op(Operator::I32Shl, &[top, shift], Type::I32)
+                }
+            };
+            mask = Some(match mask {
+                None => bit,
+                Some(mask) => self.op(Operator::I32Or, &[mask, bit], Type::I32),
+            });
+        }
+        Lowered::Scalar(mask.unwrap())
+    }
+
+    /// Widen half of the lanes of `arg`, of shape `from`, to `to`.
+    fn extend(&mut self, from: Shape, to: Shape, high: bool, signed: bool, arg: Value) -> Lowered {
+        let n = to.lanes();
+        let start = if high { n } else { 0 };
+        let lanes = (start..start + n)
+            .map(|i| {
+                let lane = self.lane(arg, from, i, signed);
+                self.widen(to, signed, lane)
+            })
+            .collect::<Vec<_>>();
+        self.build(to, &lanes)
+    }
+
+    /// Widen an integer lane to the scalar type of `to`.
+    fn widen(&mut self, to: Shape, signed: bool, lane: Value) -> Value {
+        match (to, signed) {
+            (Shape::I64x2, true) => self.op(Operator::I64ExtendI32S, &[lane], Type::I64),
+            (Shape::I64x2, false) => self.op(Operator::I64ExtendI32U, &[lane], Type::I64),
+            _ => lane,
+        }
+    }
+
+    /// Multiply the widened low or high halves of the lanes of `args`.
+    fn ext_mul(
+        &mut self,
+        from: Shape,
+        to: Shape,
+        high: bool,
+        signed: bool,
+        args: &[Value],
+    ) -> Lowered {
+        let n = to.lanes();
+        let start = if high { n } else { 0 };
+        let mul = to.pick(Operator::I32Mul, Operator::I64Mul);
+        let lanes = (start..start + n)
+            .map(|i| {
+                let a = self.lane(args[0], from, i, signed);
+                let a = self.widen(to, signed, a);
+                let b = self.lane(args[1], from, i, signed);
+                let b = self.widen(to, signed, b);
+                self.op(mul, &[a, b], to.scalar())
+            })
+            .collect::<Vec<_>>();
+        self.build(to, &lanes)
+    }
+
+    /// Add adjacent pairs of lanes of `arg`, widened.
+    fn ext_add_pairwise(&mut self, from: Shape, to: Shape, signed: bool, arg: Value) -> Lowered {
+        let lanes = self.lanes(arg, from, signed);
+        let sums = lanes
+            .chunks(2)
+            .map(|pair| self.op(Operator::I32Add, pair, Type::I32))
+            .collect::<Vec<_>>();
+        self.build(to, &sums)
+    }
+
+    /// Saturate the lanes of both operands, of shape `from`, into the
+    /// narrower `to`.
+    fn narrow(&mut self, from: Shape, to: Shape, signed: bool, args: &[Value]) -> Lowered {
+        let bits = to.lane_bits();
+        let (min, max) = match signed {
+            true => (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1),
+            false => (0, (1i64 << bits) - 1),
+        };
+        let mut lanes = self.lanes(args[0], from, true);
+        lanes.extend(self.lanes(args[1], from, true));
+        let lanes = lanes
+            .into_iter()
+            .map(|lane| self.clamp(lane, min, max))
+            .collect::<Vec<_>>();
+        self.build(to, &lanes)
+    }
+
+    /// Convert the lanes of `arg` with `op`, filling any missing lanes
+    /// of `to` with zero.
+    fn convert(&mut self, from: Shape, to: Shape, op: Operator, arg: Value) -> Lowered {
+        let n = to.lanes().min(from.lanes());
+        let mut lanes = (0..n)
+            .map(|i| {
+                let lane = self.lane(arg, from, i, false);
+                self.op(op, &[lane], to.scalar())
+            })
+            .collect::<Vec<_>>();
+        while lanes.len() < to.lanes() as usize {
+            let zero = self.scalar_const(to, 0);
+            lanes.push(zero);
+        }
+        self.build(to, &lanes)
+    }
+
+    fn shuffle(&mut self, indices: [u8; 16], args: &[Value]) -> Lowered {
+        let mut lanes = self.lanes(args[0], Shape::I8x16, false);
+        lanes.extend(self.lanes(args[1], Shape::I8x16, false));
+        let picked = indices
+            .iter()
+            .map(|&i| lanes[i as usize])
+            .collect::<Vec<_>>();
+        self.build(Shape::I8x16, &picked)
+    }
+
+    /// Lane `i` of the result is the byte of `args[0]` that lane `i`
+    /// of `args[1]` indexes, or zero if it is out of range.
+    fn swizzle(&mut self, args: &[Value]) -> Lowered {
+        let (lo, hi) = self.halves(args[0]);
+        let indices = self.lanes(args[1], Shape::I8x16, false);
+        let lanes = indices
+            .into_iter()
+            .map(|index| {
+                let eight = self.i32(8);
+                let in_lo = self.op(Operator::I32LtU, &[index, eight], Type::I32);
+                let half = self.select(Type::I64, lo, hi, in_lo);
+                let seven = self.i32(7);
+                let byte = self.op(Operator::I32And, &[index, seven], Type::I32);
+                let three = self.i32(3);
+                let shift = self.op(Operator::I32Shl, &[byte, three], Type::I32);
+                let shift = self.op(Operator::I64ExtendI32U, &[shift], Type::I64);
+                let half = self.op(Operator::I64ShrU, &[half, shift], Type::I64);
+                let lane = self.op(Operator::I32WrapI64, &[half], Type::I32);
+                let sixteen = self.i32(16);
+                let in_range = self.op(Operator::I32LtU, &[index, sixteen], Type::I32);
+                let zero = self.i32(0);
+                self.select(Type::I32, lane, zero, in_range)
+            })
+            .collect::<Vec<_>>();
+        self.build(Shape::I8x16, &lanes)
+    }
+
+    fn dot(&mut self, args: &[Value]) -> Lowered {
+        let a = self.lanes(args[0], Shape::I16x8, true);
+        let b = self.lanes(args[1], Shape::I16x8, true);
+        let lanes = (0..4)
+            .map(|i| {
+                let x = self.op(Operator::I32Mul, &[a[2 * i], b[2 * i]], Type::I32);
+                let y = self.op(Operator::I32Mul, &[a[2 * i + 1], b[2 * i + 1]], Type::I32);
+                self.op(Operator::I32Add, &[x, y], Type::I32)
+            })
+            .collect::<Vec<_>>();
+        self.build(Shape::I32x4, &lanes)
+    }
+
+    /// `memory` at `extra` more bytes, aligned to at most `align`.
+    fn mem_at(memory: MemoryArg, extra: u32, align: u32) -> Result<MemoryArg> {
+        match memory.offset.checked_add(extra) {
+            Some(offset) => Ok(MemoryArg {
+                align: memory.align.min(align),
+                offset,
+                memory: memory.memory,
+            }),
+            None => bail!("Cannot scalarize an access at offset {}", memory.offset),
+        }
+    }
+
+    /// Load `shape`'s lanes from consecutive elements, widened.
+    fn load_extend(
+        &mut self,
+        memory: MemoryArg,
+        shape: Shape,
+        signed: bool,
+        addr: Value,
+    ) -> Result<Lowered> {
+        let bytes = shape.lane_bits() / 16;
+        let mut lanes = vec![];
+        for i in 0..shape.lanes() {
+            let memory = Self::mem_at(memory, i * bytes, bytes.trailing_zeros())?;
+            let op = match (shape, signed) {
+                (Shape::I16x8, true) => Operator::I32Load8S { memory },
+                (Shape::I16x8, false) => Operator::I32Load8U { memory },
+                (Shape::I32x4, true) => Operator::I32Load16S { memory },
+                (Shape::I32x4, false) => Operator::I32Load16U { memory },
+                (_, true) => Operator::I64Load32S { memory },
+                (_, false) => Operator::I64Load32U { memory },
+            };
+            lanes.push(self.op(op, &[addr], shape.scalar()));
+        }
+        Ok(self.build(shape, &lanes))
+    }
+
+    /// Load a lane of `shape` as a scalar.
+    fn load_lane(&mut self, memory: MemoryArg, shape: Shape, addr: Value) -> Value {
+        let op = match shape {
+            Shape::I8x16 => Operator::I32Load8U { memory },
+            Shape::I16x8 => Operator::I32Load16U { memory },
+            Shape::I32x4 => Operator::I32Load { memory },
+            _ => Operator::I64Load { memory },
+        };
+        self.op(op, &[addr], shape.scalar())
+    }
+
+    fn store_lane(&mut self, memory: MemoryArg, shape: Shape, lane: u8, args: &[Value]) -> Lowered {
+        let value = self.lane(args[1], shape, lane as u32, false);
+        let op = match shape {
+            Shape::I8x16 => Operator::I32Store8 { memory },
+            Shape::I16x8 => Operator::I32Store16 { memory },
+            Shape::I32x4 => Operator::I32Store { memory },
+            _ => Operator::I64Store { memory },
+        };
+        self.effect(op, &[args[0], value]);
+        Lowered::Nothing
+    }
+
+    /// Lower the `v128` operator `op`, if it is one.
+    fn lower(&mut self, op: Operator, args: &[Value]) -> Result<Option<Lowered>> {
+        use Operator as O;
+        use Shape::*;
+        let arg = args.first().copied();
+        let a = || arg.unwrap();
+        Ok(Some(match op {
+            O::V128Const { value } => {
+                let value = self.body.imm_pool[value];
+                let lo = self.i64(value as u64);
+                let hi = self.i64((value >> 64) as u64);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128Load { memory } => {
+                let lo = Self::mem_at(memory, 0, 3)?;
+                let hi = Self::mem_at(memory, 8, 3)?;
+                let lo = self.op(O::I64Load { memory: lo }, args, Type::I64);
+                let hi = self.op(O::I64Load { memory: hi }, args, Type::I64);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128Load8x8S { memory } => self.load_extend(memory, I16x8, true, a())?,
+            O::V128Load8x8U { memory } => self.load_extend(memory, I16x8, false, a())?,
+            O::V128Load16x4S { memory } => self.load_extend(memory, I32x4, true, a())?,
+            O::V128Load16x4U { memory } => self.load_extend(memory, I32x4, false, a())?,
+            O::V128Load32x2S { memory } => self.load_extend(memory, I64x2, true, a())?,
+            O::V128Load32x2U { memory } => self.load_extend(memory, I64x2, false, a())?,
+            O::V128Load8Splat { memory } => {
+                let x = self.load_lane(memory, I8x16, a());
+                self.splat(I8x16, x)
+            }
+            O::V128Load16Splat { memory } => {
+                let x = self.load_lane(memory, I16x8, a());
+                self.splat(I16x8, x)
+            }
+            O::V128Load32Splat { memory } => {
+                let x = self.load_lane(memory, I32x4, a());
+                self.splat(I32x4, x)
+            }
+            O::V128Load64Splat { memory } => {
+                let x = self.load_lane(memory, I64x2, a());
+                self.splat(I64x2, x)
+            }
+            O::V128Load32Zero { memory } => {
+                let lo = self.op(O::I64Load32U { memory }, args, Type::I64);
+                let hi = self.i64(0);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128Load64Zero { memory } => {
+                let lo = self.op(O::I64Load { memory }, args, Type::I64);
+                let hi = self.i64(0);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128Load8Lane { memory, lane }
+            | O::V128Load16Lane { memory, lane }
+            | O::V128Load32Lane { memory, lane }
+            | O::V128Load64Lane { memory, lane } => {
+                let shape = match op {
+                    O::V128Load8Lane { .. } => I8x16,
+                    O::V128Load16Lane { .. } => I16x8,
+                    O::V128Load32Lane { .. } => I32x4,
+                    _ => I64x2,
+                };
+                let x = self.load_lane(memory, shape, args[0]);
+                self.replace_lane(shape, lane.get(), &[args[1], x])
+            }
+            O::V128Store { memory } => {
+                // The high half first: if it is in bounds, so is the
+                // low half, so nothing is written before a trap.
+                let (lo, hi) = self.halves(args[1]);
+                let hi_mem = Self::mem_at(memory, 8, 3)?;
+                self.effect(O::I64Store { memory: hi_mem }, &[args[0], hi]);
+                let lo_mem = Self::mem_at(memory, 0, 3)?;
+                self.effect(O::I64Store { memory: lo_mem }, &[args[0], lo]);
+                Lowered::Nothing
+            }
+            O::V128Store8Lane { memory, lane } => self.store_lane(memory, I8x16, lane.get(), args),
+            O::V128Store16Lane { memory, lane } => self.store_lane(memory, I16x8, lane.get(), args),
+            O::V128Store32Lane { memory, lane } => self.store_lane(memory, I32x4, lane.get(), args),
+            O::V128Store64Lane { memory, lane } => self.store_lane(memory, I64x2, lane.get(), args),
+
+            O::I8x16Shuffle { lanes } => self.shuffle(self.body.imm_pool.lanes(lanes), args),
+            O::I8x16Swizzle => self.swizzle(args),
+
+            O::I8x16ExtractLaneS { lane } => {
+                Lowered::Scalar(self.lane(a(), I8x16, lane.get() as u32, true))
+            }
+            O::I8x16ExtractLaneU { lane } => {
+                Lowered::Scalar(self.lane(a(), I8x16, lane.get() as u32, false))
+            }
+            O::I16x8ExtractLaneS { lane } => {
+                Lowered::Scalar(self.lane(a(), I16x8, lane.get() as u32, true))
+            }
+            O::I16x8ExtractLaneU { lane } => {
+                Lowered::Scalar(self.lane(a(), I16x8, lane.get() as u32, false))
+            }
+            O::I32x4ExtractLane { lane } => {
+                Lowered::Scalar(self.lane(a(), I32x4, lane.get() as u32, false))
+            }
+            O::I64x2ExtractLane { lane } => {
+                Lowered::Scalar(self.lane(a(), I64x2, lane.get() as u32, false))
+            }
+            O::F32x4ExtractLane { lane } => {
+                Lowered::Scalar(self.lane(a(), F32x4, lane.get() as u32, false))
+            }
+            O::F64x2ExtractLane { lane } => {
+                Lowered::Scalar(self.lane(a(), F64x2, lane.get() as u32, false))
+            }
+            O::I8x16ReplaceLane { lane } => self.replace_lane(I8x16, lane.get(), args),
+            O::I16x8ReplaceLane { lane } => self.replace_lane(I16x8, lane.get(), args),
+            O::I32x4ReplaceLane { lane } => self.replace_lane(I32x4, lane.get(), args),
+            O::I64x2ReplaceLane { lane } => self.replace_lane(I64x2, lane.get(), args),
+            O::F32x4ReplaceLane { lane } => self.replace_lane(F32x4, lane.get(), args),
+            O::F64x2ReplaceLane { lane } => self.replace_lane(F64x2, lane.get(), args),
+
+            O::I8x16Splat => self.splat(I8x16, a()),
+            O::I16x8Splat => self.splat(I16x8, a()),
+            O::I32x4Splat => self.splat(I32x4, a()),
+            O::I64x2Splat => self.splat(I64x2, a()),
+            O::F32x4Splat => self.splat(F32x4, a()),
+            O::F64x2Splat => self.splat(F64x2, a()),
+
+            O::V128Not => {
+                let (lo, hi) = self.halves(a());
+                let lo = self.not(lo);
+                let hi = self.not(hi);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128And => self.bitwise(O::I64And, args[0], args[1]),
+            O::V128Or => self.bitwise(O::I64Or, args[0], args[1]),
+            O::V128Xor => self.bitwise(O::I64Xor, args[0], args[1]),
+            O::V128AndNot => {
+                let ((alo, ahi), (blo, bhi)) = (self.halves(args[0]), self.halves(args[1]));
+                let (blo, bhi) = (self.not(blo), self.not(bhi));
+                let lo = self.op(O::I64And, &[alo, blo], Type::I64);
+                let hi = self.op(O::I64And, &[ahi, bhi], Type::I64);
+                Lowered::Vector(lo, hi)
+            }
+            O::V128Bitselect => {
+                let mut halves = vec![];
+                for half in 0..2 {
+                    let pick = |pair: (Value, Value)| if half == 0 { pair.0 } else { pair.1 };
+                    let a = pick(self.halves(args[0]));
+                    let b = pick(self.halves(args[1]));
+                    let c = pick(self.halves(args[2]));
+                    let not_c = self.not(c);
+                    let a = self.op(O::I64And, &[a, c], Type::I64);
+                    let b = self.op(O::I64And, &[b, not_c], Type::I64);
+                    halves.push(self.op(O::I64Or, &[a, b], Type::I64));
+                }
+                Lowered::Vector(halves[0], halves[1])
+            }
+            O::V128AnyTrue => {
+                let (lo, hi) = self.halves(a());
+                let any = self.op(O::I64Or, &[lo, hi], Type::I64);
+                let zero = self.i64(0);
+                Lowered::Scalar(self.op(O::I64Ne, &[any, zero], Type::I32))
+            }
+
+            O::I8x16Eq => self.binary(I8x16, false, Bin::Cmp(O::I32Eq), args),
+            O::I8x16Ne => self.binary(I8x16, false, Bin::Cmp(O::I32Ne), args),
+            O::I8x16LtS => self.binary(I8x16, true, Bin::Cmp(O::I32LtS), args),
+            O::I8x16LtU => self.binary(I8x16, false, Bin::Cmp(O::I32LtU), args),
+            O::I8x16GtS => self.binary(I8x16, true, Bin::Cmp(O::I32GtS), args),
+            O::I8x16GtU => self.binary(I8x16, false, Bin::Cmp(O::I32GtU), args),
+            O::I8x16LeS => self.binary(I8x16, true, Bin::Cmp(O::I32LeS), args),
+            O::I8x16LeU => self.binary(I8x16, false, Bin::Cmp(O::I32LeU), args),
+            O::I8x16GeS => self.binary(I8x16, true, Bin::Cmp(O::I32GeS), args),
+            O::I8x16GeU => self.binary(I8x16, false, Bin::Cmp(O::I32GeU), args),
+            O::I16x8Eq => self.binary(I16x8, false, Bin::Cmp(O::I32Eq), args),
+            O::I16x8Ne => self.binary(I16x8, false, Bin::Cmp(O::I32Ne), args),
+            O::I16x8LtS => self.binary(I16x8, true, Bin::Cmp(O::I32LtS), args),
+            O::I16x8LtU => self.binary(I16x8, false, Bin::Cmp(O::I32LtU), args),
+            O::I16x8GtS => self.binary(I16x8, true, Bin::Cmp(O::I32GtS), args),
+            O::I16x8GtU => self.binary(I16x8, false, Bin::Cmp(O::I32GtU), args),
+            O::I16x8LeS => self.binary(I16x8, true, Bin::Cmp(O::I32LeS), args),
+            O::I16x8LeU => self.binary(I16x8, false, Bin::Cmp(O::I32LeU), args),
+            O::I16x8GeS => self.binary(I16x8, true, Bin::Cmp(O::I32GeS), args),
+            O::I16x8GeU => self.binary(I16x8, false, Bin::Cmp(O::I32GeU), args),
+            O::I32x4Eq => self.binary(I32x4, false, Bin::Cmp(O::I32Eq), args),
+            O::I32x4Ne => self.binary(I32x4, false, Bin::Cmp(O::I32Ne), args),
+            O::I32x4LtS => self.binary(I32x4, false, Bin::Cmp(O::I32LtS), args),
+            O::I32x4LtU => self.binary(I32x4, false, Bin::Cmp(O::I32LtU), args),
+            O::I32x4GtS => self.binary(I32x4, false, Bin::Cmp(O::I32GtS), args),
+            O::I32x4GtU => self.binary(I32x4, false, Bin::Cmp(O::I32GtU), args),
+            O::I32x4LeS => self.binary(I32x4, false, Bin::Cmp(O::I32LeS), args),
+            O::I32x4LeU => self.binary(I32x4, false, Bin::Cmp(O::I32LeU), args),
+            O::I32x4GeS => self.binary(I32x4, false, Bin::Cmp(O::I32GeS), args),
+            O::I32x4GeU => self.binary(I32x4, false, Bin::Cmp(O::I32GeU), args),
+            O::I64x2Eq => self.binary(I64x2, false, Bin::Cmp(O::I64Eq), args),
+            O::I64x2Ne => self.binary(I64x2, false, Bin::Cmp(O::I64Ne), args),
+            O::I64x2LtS => self.binary(I64x2, false, Bin::Cmp(O::I64LtS), args),
+            O::I64x2GtS => self.binary(I64x2, false, Bin::Cmp(O::I64GtS), args),
+            O::I64x2LeS => self.binary(I64x2, false, Bin::Cmp(O::I64LeS), args),
+            O::I64x2GeS => self.binary(I64x2, false, Bin::Cmp(O::I64GeS), args),
+            O::F32x4Eq => self.binary(F32x4, false, Bin::Cmp(O::F32Eq), args),
+            O::F32x4Ne => self.binary(F32x4, false, Bin::Cmp(O::F32Ne), args),
+            O::F32x4Lt => self.binary(F32x4, false, Bin::Cmp(O::F32Lt), args),
+            O::F32x4Gt => self.binary(F32x4, false, Bin::Cmp(O::F32Gt), args),
+            O::F32x4Le => self.binary(F32x4, false, Bin::Cmp(O::F32Le), args),
+            O::F32x4Ge => self.binary(F32x4, false, Bin::Cmp(O::F32Ge), args),
+            O::F64x2Eq => self.binary(F64x2, false, Bin::Cmp(O::F64Eq), args),
+            O::F64x2Ne => self.binary(F64x2, false, Bin::Cmp(O::F64Ne), args),
+            O::F64x2Lt => self.binary(F64x2, false, Bin::Cmp(O::F64Lt), args),
+            O::F64x2Gt => self.binary(F64x2, false, Bin::Cmp(O::F64Gt), args),
+            O::F64x2Le => self.binary(F64x2, false, Bin::Cmp(O::F64Le), args),
+            O::F64x2Ge => self.binary(F64x2, false, Bin::Cmp(O::F64Ge), args),
+
+            O::I8x16Abs => self.int_neg(I8x16, a(), true),
+            O::I8x16Neg => self.int_neg(I8x16, a(), false),
+            O::I8x16Popcnt => self.unary(I8x16, O::I32Popcnt, a()),
+            O::I8x16AllTrue => self.all_true(I8x16, a()),
+            O::I8x16Bitmask => self.bitmask(I8x16, a()),
+            O::I8x16NarrowI16x8S => self.narrow(I16x8, I8x16, true, args),
+            O::I8x16NarrowI16x8U => self.narrow(I16x8, I8x16, false, args),
+            O::I8x16Shl => self.shift(I8x16, O::I32Shl, false, args),
+            O::I8x16ShrS => self.shift(I8x16, O::I32ShrS, true, args),
+            O::I8x16ShrU => self.shift(I8x16, O::I32ShrU, false, args),
+            O::I8x16Add => self.binary(I8x16, false, Bin::Op(O::I32Add), args),
+            O::I8x16AddSatS => self.binary(I8x16, true, Bin::Sat(O::I32Add, -0x80, 0x7f), args),
+            O::I8x16AddSatU => self.binary(I8x16, false, Bin::Sat(O::I32Add, 0, 0xff), args),
+            O::I8x16Sub => self.binary(I8x16, false, Bin::Op(O::I32Sub), args),
+            O::I8x16SubSatS => self.binary(I8x16, true, Bin::Sat(O::I32Sub, -0x80, 0x7f), args),
+            O::I8x16SubSatU => self.binary(I8x16, false, Bin::Sat(O::I32Sub, 0, 0xff), args),
+            O::I8x16MinS => self.binary(I8x16, true, Bin::Pick(O::I32LtS), args),
+            O::I8x16MinU => self.binary(I8x16, false, Bin::Pick(O::I32LtU), args),
+            O::I8x16MaxS => self.binary(I8x16, true, Bin::Pick(O::I32GtS), args),
+            O::I8x16MaxU => self.binary(I8x16, false, Bin::Pick(O::I32GtU), args),
+            O::I8x16AvgrU => self.binary(I8x16, false, Bin::Avgr, args),
+
+            O::I16x8ExtAddPairwiseI8x16S => self.ext_add_pairwise(I8x16, I16x8, true, a()),
+            O::I16x8ExtAddPairwiseI8x16U => self.ext_add_pairwise(I8x16, I16x8, false, a()),
+            O::I16x8Abs => self.int_neg(I16x8, a(), true),
+            O::I16x8Neg => self.int_neg(I16x8, a(), false),
+            O::I16x8Q15MulrSatS => self.binary(I16x8, true, Bin::Q15Mulr, args),
+            O::I16x8AllTrue => self.all_true(I16x8, a()),
+            O::I16x8Bitmask => self.bitmask(I16x8, a()),
+            O::I16x8NarrowI32x4S => self.narrow(I32x4, I16x8, true, args),
+            O::I16x8NarrowI32x4U => self.narrow(I32x4, I16x8, false, args),
+            O::I16x8ExtendLowI8x16S => self.extend(I8x16, I16x8, false, true, a()),
+            O::I16x8ExtendHighI8x16S => self.extend(I8x16, I16x8, true, true, a()),
+            O::I16x8ExtendLowI8x16U => self.extend(I8x16, I16x8, false, false, a()),
+            O::I16x8ExtendHighI8x16U => self.extend(I8x16, I16x8, true, false, a()),
+            O::I16x8Shl => self.shift(I16x8, O::I32Shl, false, args),
+            O::I16x8ShrS => self.shift(I16x8, O::I32ShrS, true, args),
+            O::I16x8ShrU => self.shift(I16x8, O::I32ShrU, false, args),
+            O::I16x8Add => self.binary(I16x8, false, Bin::Op(O::I32Add), args),
+            O::I16x8AddSatS => self.binary(I16x8, true, Bin::Sat(O::I32Add, -0x8000, 0x7fff), args),
+            O::I16x8AddSatU => self.binary(I16x8, false, Bin::Sat(O::I32Add, 0, 0xffff), args),
+            O::I16x8Sub => self.binary(I16x8, false, Bin::Op(O::I32Sub), args),
+            O::I16x8SubSatS => self.binary(I16x8, true, Bin::Sat(O::I32Sub, -0x8000, 0x7fff), args),
+            O::I16x8SubSatU => self.binary(I16x8, false, Bin::Sat(O::I32Sub, 0, 0xffff), args),
+            O::I16x8Mul => self.binary(I16x8, false, Bin::Op(O::I32Mul), args),
+            O::I16x8MinS => self.binary(I16x8, true, Bin::Pick(O::I32LtS), args),
+            O::I16x8MinU => self.binary(I16x8, false, Bin::Pick(O::I32LtU), args),
+            O::I16x8MaxS => self.binary(I16x8, true, Bin::Pick(O::I32GtS), args),
+            O::I16x8MaxU => self.binary(I16x8, false, Bin::Pick(O::I32GtU), args),
+            O::I16x8AvgrU => self.binary(I16x8, false, Bin::Avgr, args),
+            O::I16x8ExtMulLowI8x16S => self.ext_mul(I8x16, I16x8, false, true, args),
+            O::I16x8ExtMulHighI8x16S => self.ext_mul(I8x16, I16x8, true, true, args),
+            O::I16x8ExtMulLowI8x16U => self.ext_mul(I8x16, I16x8, false, false, args),
+            O::I16x8ExtMulHighI8x16U => self.ext_mul(I8x16, I16x8, true, false, args),
+
+            O::I32x4ExtAddPairwiseI16x8S => self.ext_add_pairwise(I16x8, I32x4, true, a()),
+            O::I32x4ExtAddPairwiseI16x8U => self.ext_add_pairwise(I16x8, I32x4, false, a()),
+            O::I32x4Abs => self.int_neg(I32x4, a(), true),
+            O::I32x4Neg => self.int_neg(I32x4, a(), false),
+            O::I32x4AllTrue => self.all_true(I32x4, a()),
+            O::I32x4Bitmask => self.bitmask(I32x4, a()),
+            O::I32x4ExtendLowI16x8S => self.extend(I16x8, I32x4, false, true, a()),
+            O::I32x4ExtendHighI16x8S => self.extend(I16x8, I32x4, true, true, a()),
+            O::I32x4ExtendLowI16x8U => self.extend(I16x8, I32x4, false, false, a()),
+            O::I32x4ExtendHighI16x8U => self.extend(I16x8, I32x4, true, false, a()),
+            O::I32x4Shl => self.shift(I32x4, O::I32Shl, false, args),
+            O::I32x4ShrS => self.shift(I32x4, O::I32ShrS, false, args),
+            O::I32x4ShrU => self.shift(I32x4, O::I32ShrU, false, args),
+            O::I32x4Add => self.binary(I32x4, false, Bin::Op(O::I32Add), args),
+            O::I32x4Sub => self.binary(I32x4, false, Bin::Op(O::I32Sub), args),
+            O::I32x4Mul => self.binary(I32x4, false, Bin::Op(O::I32Mul), args),
+            O::I32x4MinS => self.binary(I32x4, false, Bin::Pick(O::I32LtS), args),
+            O::I32x4MinU => self.binary(I32x4, false, Bin::Pick(O::I32LtU), args),
+            O::I32x4MaxS => self.binary(I32x4, false, Bin::Pick(O::I32GtS), args),
+            O::I32x4MaxU => self.binary(I32x4, false, Bin::Pick(O::I32GtU), args),
+            O::I32x4DotI16x8S => self.dot(args),
+            O::I32x4ExtMulLowI16x8S => self.ext_mul(I16x8, I32x4, false, true, args),
+            O::I32x4ExtMulHighI16x8S => self.ext_mul(I16x8, I32x4, true, true, args),
+            O::I32x4ExtMulLowI16x8U => self.ext_mul(I16x8, I32x4, false, false, args),
+            O::I32x4ExtMulHighI16x8U => self.ext_mul(I16x8, I32x4, true, false, args),
+
+            O::I64x2Abs => self.int_neg(I64x2, a(), true),
+            O::I64x2Neg => self.int_neg(I64x2, a(), false),
+            O::I64x2AllTrue => self.all_true(I64x2, a()),
+            O::I64x2Bitmask => self.bitmask(I64x2, a()),
+            O::I64x2ExtendLowI32x4S => self.extend(I32x4, I64x2, false, true, a()),
+            O::I64x2ExtendHighI32x4S => self.extend(I32x4, I64x2, true, true, a()),
+            O::I64x2ExtendLowI32x4U => self.extend(I32x4, I64x2, false, false, a()),
+            O::I64x2ExtendHighI32x4U => self.extend(I32x4, I64x2, true, false, a()),
+            O::I64x2Shl => self.shift(I64x2, O::I64Shl, false, args),
+            O::I64x2ShrS => self.shift(I64x2, O::I64ShrS, false, args),
+            O::I64x2ShrU => self.shift(I64x2, O::I64ShrU, false, args),
+            O::I64x2Add => self.binary(I64x2, false, Bin::Op(O::I64Add), args),
+            O::I64x2Sub => self.binary(I64x2, false, Bin::Op(O::I64Sub), args),
+            O::I64x2Mul => self.binary(I64x2, false, Bin::Op(O::I64Mul), args),
+            O::I64x2ExtMulLowI32x4S => self.ext_mul(I32x4, I64x2, false, true, args),
+            O::I64x2ExtMulHighI32x4S => self.ext_mul(I32x4, I64x2, true, true, args),
+            O::I64x2ExtMulLowI32x4U => self.ext_mul(I32x4, I64x2, false, false, args),
+            O::I64x2ExtMulHighI32x4U => self.ext_mul(I32x4, I64x2, true, false, args),
+
+            O::F32x4Ceil => self.unary(F32x4, O::F32Ceil, a()),
+            O::F32x4Floor => self.unary(F32x4, O::F32Floor, a()),
+            O::F32x4Trunc => self.unary(F32x4, O::F32Trunc, a()),
+            O::F32x4Nearest => self.unary(F32x4, O::F32Nearest, a()),
+            O::F32x4Abs => self.unary(F32x4, O::F32Abs, a()),
+            O::F32x4Neg => self.unary(F32x4, O::F32Neg, a()),
+            O::F32x4Sqrt => self.unary(F32x4, O::F32Sqrt, a()),
+            O::F32x4Add => self.binary(F32x4, false, Bin::Op(O::F32Add), args),
+            O::F32x4Sub => self.binary(F32x4, false, Bin::Op(O::F32Sub), args),
+            O::F32x4Mul => self.binary(F32x4, false, Bin::Op(O::F32Mul), args),
+            O::F32x4Div => self.binary(F32x4, false, Bin::Op(O::F32Div), args),
+            O::F32x4Min => self.binary(F32x4, false, Bin::Op(O::F32Min), args),
+            O::F32x4Max => self.binary(F32x4, false, Bin::Op(O::F32Max), args),
+            O::F32x4PMin => self.binary(F32x4, false, Bin::PickRev(O::F32Lt), args),
+            O::F32x4PMax => self.binary(F32x4, false, Bin::PickRev(O::F32Gt), args),
+            O::F64x2Ceil => self.unary(F64x2, O::F64Ceil, a()),
+            O::F64x2Floor => self.unary(F64x2, O::F64Floor, a()),
+            O::F64x2Trunc => self.unary(F64x2, O::F64Trunc, a()),
+            O::F64x2Nearest => self.unary(F64x2, O::F64Nearest, a()),
+            O::F64x2Abs => self.unary(F64x2, O::F64Abs, a()),
+            O::F64x2Neg => self.unary(F64x2, O::F64Neg, a()),
+            O::F64x2Sqrt => self.unary(F64x2, O::F64Sqrt, a()),
+            O::F64x2Add => self.binary(F64x2, false, Bin::Op(O::F64Add), args),
+            O::F64x2Sub => self.binary(F64x2, false, Bin::Op(O::F64Sub), args),
+            O::F64x2Mul => self.binary(F64x2, false, Bin::Op(O::F64Mul), args),
+            O::F64x2Div => self.binary(F64x2, false, Bin::Op(O::F64Div), args),
+            O::F64x2Min => self.binary(F64x2, false, Bin::Op(O::F64Min), args),
+            O::F64x2Max => self.binary(F64x2, false, Bin::Op(O::F64Max), args),
+            O::F64x2PMin => self.binary(F64x2, false, Bin::PickRev(O::F64Lt), args),
+            O::F64x2PMax => self.binary(F64x2, false, Bin::PickRev(O::F64Gt), args),
+
+            O::I32x4TruncSatF32x4S => self.convert(F32x4, I32x4, O::I32TruncSatF32S, a()),
+            O::I32x4TruncSatF32x4U => self.convert(F32x4, I32x4, O::I32TruncSatF32U, a()),
+            O::F32x4ConvertI32x4S => self.convert(I32x4, F32x4, O::F32ConvertI32S, a()),
+            O::F32x4ConvertI32x4U => self.convert(I32x4, F32x4, O::F32ConvertI32U, a()),
+            O::I32x4TruncSatF64x2SZero => self.convert(F64x2, I32x4, O::I32TruncSatF64S, a()),
+            O::I32x4TruncSatF64x2UZero => self.convert(F64x2, I32x4, O::I32TruncSatF64U, a()),
+            O::F64x2ConvertLowI32x4S => self.convert(I32x4, F64x2, O::F64ConvertI32S, a()),
+            O::F64x2ConvertLowI32x4U => self.convert(I32x4, F64x2, O::F64ConvertI32U, a()),
+            O::F32x4DemoteF64x2Zero => self.convert(F64x2, F32x4, O::F32DemoteF64, a()),
+            O::F64x2PromoteLowF32x4 => self.convert(F32x4, F64x2, O::F64PromoteF32, a()),
+
+            _ => return Ok(None),
+        }))
+    }
+
+    /// `values` with each `v128` replaced by its halves.
+    fn expand(&self, values: &[Value]) -> Vec<Value> {
+        values
+            .iter()
+            .flat_map(
+                |&value| match self.halves.get(&self.body.resolve_alias(value)) {
+                    Some(&(lo, hi)) => vec![lo, hi],
+                    None => vec![value],
+                },
+            )
+            .collect()
+    }
+}
+
+fn scalarize_body(body: &mut FunctionBody) -> Result<()> {
+    let mut halves = HashMap::new();
+
+    // Blockparams are split in place; other `v128` values get
+    // placeholder halves, defined as their definitions are lowered.
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let params = std::mem::take(&mut body.blocks[block].params);
+        for (ty, value) in params {
+            match ty {
+                Type::V128 => {
+                    let lo = body.add_blockparam(block, Type::I64);
+                    let hi = body.add_blockparam(block, Type::I64);
+                    halves.insert(value, (lo, hi));
+                    body.values[value] = ValueDef::None;
+                }
+                ty => {
+                    let index = body.blocks[block].params.len() as u32;
+                    body.blocks[block].params.push((ty, value));
+                    body.values[value] = ValueDef::BlockParam(block, index, ty);
+                }
+            }
+        }
+    }
+    let mut picks: HashMap<Value, Vec<Value>> = HashMap::new();
+    for (value, def) in body.values.entries() {
+        if let &ValueDef::PickOutput(from, ..) = def {
+            picks.entry(from).or_default().push(value);
+        }
+    }
+    for value in body.values.iter().collect::<Vec<_>>() {
+        if body.values[value].ty(&body.type_pool) == Some(Type::V128) {
+            let lo = body.add_placeholder(Type::I64);
+            let hi = body.add_placeholder(Type::I64);
+            halves.insert(value, (lo, hi));
+        }
+    }
+
+    let mut s = Scalarizer {
+        body,
+        halves,
+        block: Block::invalid(),
+        loc: Value::invalid(),
+    };
+    for block in s.body.blocks.iter().collect::<Vec<_>>() {
+        s.block = block;
+        for inst in std::mem::take(&mut s.body.blocks[block].insts) {
+            s.loc = inst;
+            let (op, args, tys) = match &s.body.values[inst] {
+                ValueDef::Operator(op, args, tys) => (
+                    *op,
+                    s.body.arg_pool[*args].to_vec(),
+                    s.body.type_pool[*tys].to_vec(),
+                ),
+                ValueDef::PickOutput(_, _, Type::V128) => {
+                    // Replaced with picks of its halves at the call.
+                    s.body.values[inst] = ValueDef::None;
+                    continue;
+                }
+                _ => {
+                    s.body.append_to_block(block, inst);
+                    continue;
+                }
+            };
+            let vector_args = args.iter().any(|&arg| {
+                let arg = s.body.resolve_alias(arg);
+                s.halves.contains_key(&arg)
+            });
+            if !vector_args && !tys.contains(&Type::V128) {
+                s.body.append_to_block(block, inst);
+                continue;
+            }
+
+            let lowered = match op {
+                Operator::Select | Operator::TypedSelect { .. } => {
+                    let ((alo, ahi), (blo, bhi)) = (s.halves(args[0]), s.halves(args[1]));
+                    let lo = s.select(Type::I64, alo, blo, args[2]);
+                    let hi = s.select(Type::I64, ahi, bhi, args[2]);
+                    Lowered::Vector(lo, hi)
+                }
+                Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::CallRef { .. } => {
+                    lower_call(&mut s, inst, &args, &tys, picks.get(&inst));
+                    continue;
+                }
+                op => match s.lower(op, &args)? {
+                    Some(lowered) => lowered,
+                    None => bail!("Cannot scalarize {}", op),
+                },
+            };
+            match lowered {
+                Lowered::Vector(lo, hi) => {
+                    let (plo, phi) = s.halves[&inst];
+                    s.body.replace_placeholder(plo, ValueDef::Alias(lo));
+                    s.body.replace_placeholder(phi, ValueDef::Alias(hi));
+                    s.body.values[inst] = ValueDef::None;
+                }
+                Lowered::Scalar(value) => s.body.values[inst] = ValueDef::Alias(value),
+                Lowered::Nothing => s.body.values[inst] = ValueDef::None,
+            }
+        }
+    }
+
+    for block in s.body.blocks.iter().collect::<Vec<_>>() {
+        let mut terminator = std::mem::take(&mut s.body.blocks[block].terminator);
+        terminator.update_targets(|target| target.args = s.expand(&target.args));
+        if let Terminator::Return { values } = &mut terminator {
+            *values = s.expand(values);
+        }
+        s.body.blocks[block].terminator = terminator;
+    }
+    s.body.rets = expand_types(&s.body.rets);
+    for ty in s.body.locals.values_mut() {
+        if *ty == Type::V128 {
+            *ty = Type::I64;
+        }
+    }
+    s.body.check_no_placeholders()
+}
+
+/// Lower a call with `v128` arguments or results: each becomes two
+/// `i64`s, and the results' picks are renumbered.
+fn lower_call(
+    s: &mut Scalarizer,
+    inst: Value,
+    args: &[Value],
+    tys: &[Type],
+    picks: Option<&Vec<Value>>,
+) {
+    let block = s.block;
+    let new_args = s.expand(args);
+    let new_tys = expand_types(tys);
+    let op = match s.body.values[inst] {
+        ValueDef::Operator(op, ..) => op,
+        _ => unreachable!(),
+    };
+    let arg_list = s.body.arg_pool.from_iter(new_args.into_iter());
+    let ty_list = s.body.type_pool.from_iter(new_tys.iter().copied());
+    s.body.values[inst] = ValueDef::Operator(op, arg_list, ty_list);
+    s.body.append_to_block(block, inst);
+
+    // The index of each old result among the new ones.
+    let mut index = vec![];
+    let mut next = 0;
+    for &ty in tys {
+        index.push(next);
+        next += if ty == Type::V128 { 2 } else { 1 };
+    }
+    let pick_halves = |s: &mut Scalarizer, of: Value, at: u32| {
+        let lo = s.body.add_value(ValueDef::PickOutput(inst, at, Type::I64));
+        s.body.append_to_block(block, lo);
+        let hi = s
+            .body
+            .add_value(ValueDef::PickOutput(inst, at + 1, Type::I64));
+        s.body.append_to_block(block, hi);
+        let (plo, phi) = s.halves[&of];
+        s.body.replace_placeholder(plo, ValueDef::Alias(lo));
+        s.body.replace_placeholder(phi, ValueDef::Alias(hi));
+    };
+    if tys == [Type::V128] {
+        pick_halves(s, inst, 0);
+        return;
+    }
+    for &pick in picks.map_or(&[][..], |picks| &picks[..]) {
+        if let ValueDef::PickOutput(_, i, ty) = s.body.values[pick] {
+            let at = index[i as usize];
+            match ty {
+                Type::V128 => pick_halves(s, pick, at),
+                ty => s.body.values[pick] = ValueDef::PickOutput(inst, at, ty),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::passes::downgrade::{downgrade, TargetFeatures};
+    use crate::passes::features::FeatureSet;
+    use crate::{ConstVal, FrontendOptions, InterpContext};
+
+    #[test]
+    fn simd_becomes_scalar_code() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory 1)
+                (func $twice (param v128) (result v128)
+                  local.get 0
+                  local.get 0
+                  i8x16.add)
+                (func $pair (param i32) (result i32 v128)
+                  local.get 0
+                  i32.const 1
+                  i32.add
+                  local.get 0
+                  i32x4.splat)
+                (func (export "add8") (param i32) (result i32)
+                  local.get 0
+                  i8x16.splat
+                  call $twice
+                  i8x16.extract_lane_u 3)
+                (func (export "shr16") (param i32) (result i32)
+                  local.get 0
+                  i16x8.splat
+                  i32.const 17
+                  i16x8.shr_s
+                  i16x8.extract_lane_s 5)
+                (func (export "mul32") (param i32) (result i32)
+                  v128.const i32x4 1 2 3 4
+                  local.get 0
+                  i32x4.splat
+                  i32x4.mul
+                  i32x4.extract_lane 2)
+                (func (export "add64") (param i32) (result i64)
+                  local.get 0
+                  i64.extend_i32_u
+                  i64x2.splat
+                  v128.const i64x2 1 -1
+                  i64x2.add
+                  i64x2.extract_lane 1)
+                (func (export "cmp") (param i32) (result i32)
+                  v128.const i32x4 1 5 9 13
+                  local.get 0
+                  i32x4.splat
+                  i32x4.lt_s
+                  i32x4.bitmask)
+                (func (export "float") (param i32) (result i32)
+                  local.get 0
+                  f32.convert_i32_s
+                  f32x4.splat
+                  v128.const f32x4 0.5 1.5 2.5 -3
+                  f32x4.add
+                  f32x4.extract_lane 3
+                  i32.trunc_f32_s)
+                (func (export "shuffle") (param i32) (result i32)
+                  v128.const i8x16 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+                  local.get 0
+                  i8x16.splat
+                  i8x16.shuffle 0 17 2 19 4 5 6 7 8 9 10 11 12 13 14 15
+                  i32x4.extract_lane 0)
+                (func (export "swizzle") (param i32) (result i32)
+                  v128.const i8x16 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25
+                  local.get 0
+                  i8x16.splat
+                  i8x16.swizzle
+                  i8x16.extract_lane_u 0)
+                (func (export "sat") (param i32) (result i32)
+                  local.get 0
+                  i8x16.splat
+                  local.get 0
+                  i8x16.splat
+                  i8x16.add_sat_s
+                  i8x16.extract_lane_s 0)
+                (func (export "narrow") (param i32) (result i32)
+                  local.get 0
+                  i16x8.splat
+                  local.get 0
+                  i16x8.splat
+                  i8x16.narrow_i16x8_u
+                  i8x16.extract_lane_u 9)
+                (func (export "dot") (param i32) (result i32)
+                  local.get 0
+                  i16x8.splat
+                  v128.const i16x8 1 2 3 4 5 6 7 8
+                  i32x4.dot_i16x8_s
+                  i32x4.extract_lane 1)
+                (func (export "memory") (param i32) (result i32)
+                  i32.const 16
+                  v128.const i32x4 1 2 3 4
+                  local.get 0
+                  i32x4.splat
+                  i32x4.add
+                  v128.store
+                  i32.const 20
+                  i32.load
+                  i32.const 16
+                  v128.load
+                  i32x4.extract_lane 3
+                  i32.add)
+                (func (export "blocks") (param i32) (result i32)
+                  local.get 0
+                  (if (result v128)
+                    (then v128.const i32x4 1 1 1 1)
+                    (else v128.const i32x4 2 2 2 2))
+                  i32x4.extract_lane 0)
+                (func (export "extend") (param i32) (result i32)
+                  local.get 0
+                  i16x8.splat
+                  i32x4.extend_high_i16x8_s
+                  i32x4.extract_lane 0)
+                (func (export "trunc") (param i32) (result i32)
+                  (local v128)
+                  v128.const f32x4 3e9 -3e9 1.5 nan
+                  i32x4.trunc_sat_f32x4_s
+                  local.tee 1
+                  i32x4.extract_lane 0
+                  local.get 1
+                  i32x4.extract_lane 1
+                  i32.xor
+                  local.get 1
+                  i32x4.extract_lane 2
+                  i32.xor
+                  local.get 1
+                  i32x4.extract_lane 3
+                  i32.xor)
+                (func (export "pair") (param i32) (result i32)
+                  local.get 0
+                  call $pair
+                  i32x4.extract_lane 1
+                  i32.add)
+              )"#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let report = downgrade(&mut module, &TargetFeatures::mvp()).unwrap();
+        assert_eq!(report.simd_funcs, 18);
+        let mvp = FeatureSet::default().wasm_features();
+        wasmparser::Validator::new_with_features(mvp)
+            .validate_all(&module.to_wasm_bytes().unwrap())
+            .unwrap();
+
+        let cases: &[(&str, u32, ConstVal)] = &[
+            ("add8", 200, ConstVal::I32(144)),
+            ("shr16", 0x8002, ConstVal::I32(0xffff_c001)),
+            ("mul32", 7, ConstVal::I32(21)),
+            ("add64", 5, ConstVal::I64(4)),
+            ("cmp", 9, ConstVal::I32(0b11)),
+            ("float", 10, ConstVal::I32(7)),
+            ("shuffle", 0xab, ConstVal::I32(0xab02_ab00)),
+            ("swizzle", 3, ConstVal::I32(13)),
+            ("swizzle", 200, ConstVal::I32(0)),
+            ("sat", 100, ConstVal::I32(127)),
+            ("sat", -100i32 as u32, ConstVal::I32(-128i32 as u32)),
+            ("narrow", 300, ConstVal::I32(255)),
+            ("narrow", 0xffff, ConstVal::I32(0)),
+            ("dot", -2i32 as u32, ConstVal::I32(-14i32 as u32)),
+            ("memory", 1, ConstVal::I32(8)),
+            ("blocks", 0, ConstVal::I32(2)),
+            ("blocks", 1, ConstVal::I32(1)),
+            ("extend", 0xffff, ConstVal::I32(u32::MAX)),
+            ("trunc", 0, ConstVal::I32(0xffff_fffe)),
+            ("pair", 5, ConstVal::I32(11)),
+        ];
+        for &(name, arg, expected) in cases {
+            let func = module
+                .exports
+                .iter()
+                .find_map(|export| match export.kind {
+                    ExportKind::Func(func) if export.name == name => Some(func),
+                    _ => None,
+                })
+                .unwrap();
+            let mut ctx = InterpContext::new(&module).unwrap();
+            let result = ctx.call(&module, func, &[ConstVal::I32(arg)]).ok().ok();
+            assert_eq!(result.map(|r| r.to_vec()), Some(vec![expected]), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Select formation and expansion.
+//!
+//! Small if/else diamonds (and triangles) whose arms only compute
+//! pure values for the join point are cheaper as straight-line code
+//! ending in `select`s: both arms are evaluated, but the branch
+//! disappears. Conversely, a `select` whose operands are expensive
+//! computations used nowhere else wastes work on the operand it
+//! discards, and when its condition is strongly biased a branch
+//! around each computation is the better trade. `form_selects`
+//! performs the first rewrite; `expand_selects` performs the second
+//! for the selects chosen by a caller-provided policy, such as one
+//! driven by profile data.
+
+use crate::ir::{Block, BlockOrigin, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::passes::switch::values_used_outside_def_block;
+use crate::{Operator, OptOptions};
+use std::collections::{HashMap, HashSet};
+
+/// Arms with more than this many instructions are not speculated.
+const MAX_ARM_INSTS: usize = 4;
+/// Arms whose total latency under the cost model exceeds this are
+/// not speculated: executing them on every path would cost more than
+/// the branch saves.
+const MAX_ARM_LATENCY: u32 = 8;
+
+/// Flatten small if/else diamonds and triangles into `select`s.
+/// Returns `true` if anything changed.
+///
+/// Operators that may trap are only speculated if `options` allows
+/// it.
+pub(crate) fn form_selects(body: &mut FunctionBody, options: &OptOptions) -> bool {
+    let used_outside = values_used_outside_def_block(body);
+    let mut changed = false;
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        if flatten_diamond(body, block, &used_outside, options) {
+            // Later diamonds are recognized by their predecessor
+            // counts, which must be kept accurate.
+            body.recompute_edges();
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// If `target` leads to a block that could be speculated into
+/// `from`, return that block and its own branch target.
+fn speculable_arm(
+    body: &FunctionBody,
+    from: Block,
+    target: &BlockTarget,
+    used_outside: &HashSet<Value>,
+    options: &OptOptions,
+) -> Option<(Block, BlockTarget)> {
+    let arm = target.block;
+    let def = &body.blocks[arm];
+    if arm == from || !target.args.is_empty() || !def.params.is_empty() || def.preds.len() != 1 {
+        return None;
+    }
+    if def.insts.len() > MAX_ARM_INSTS
+        || options.cost_model.block_latency(body, arm) > MAX_ARM_LATENCY
+    {
+        return None;
+    }
+    let all_pure = def.insts.iter().all(|&inst| match &body.values[inst] {
+        ValueDef::Operator(op, ..) => options.treat_as_pure(op) && !used_outside.contains(&inst),
+        _ => false,
+    });
+    if !all_pure {
+        return None;
+    }
+    match &def.terminator {
+        Terminator::Br { target } if target.block != arm && target.block != from => {
+            Some((arm, target.clone()))
+        }
+        _ => None,
+    }
+}
+
+fn flatten_diamond(
+    body: &mut FunctionBody,
+    block: Block,
+    used_outside: &HashSet<Value>,
+    options: &OptOptions,
+) -> bool {
+    let (cond, if_true, if_false) = match &body.blocks[block].terminator {
+        Terminator::CondBr {
+            cond,
+            if_true,
+            if_false,
+        } => (*cond, if_true.clone(), if_false.clone()),
+        _ => return false,
+    };
+
+    let true_arm = speculable_arm(body, block, &if_true, used_outside, options);
+    let false_arm = speculable_arm(body, block, &if_false, used_outside, options);
+    let (arms, true_target, false_target) = match (true_arm, false_arm) {
+        (Some((t, tt)), Some((f, ft))) if tt.block == ft.block => (vec![t, f], tt, ft),
+        (Some((t, tt)), _) if tt.block == if_false.block => (vec![t], tt, if_false),
+        (_, Some((f, ft))) if ft.block == if_true.block => (vec![f], if_true, ft),
+        // Both edges go straight to the same block, differing only
+        // in their arguments.
+        _ if if_true.block == if_false.block && if_true.block != block => {
+            (vec![], if_true, if_false)
+        }
+        _ => return false,
+    };
+    let join = true_target.block;
+    log::trace!(
+        "select: flattening {} (arms {:?}) into {}",
+        block,
+        arms,
+        join
+    );
+
+    for &arm in &arms {
+        let insts = std::mem::take(&mut body.blocks[arm].insts);
+        for inst in insts {
+            body.append_to_block(block, inst);
+        }
+        body.blocks[arm].terminator = Terminator::Unreachable;
+    }
+
+    let mut args = vec![];
+    for (i, (&t, &f)) in true_target
+        .args
+        .iter()
+        .zip(false_target.args.iter())
+        .enumerate()
+    {
+        if body.resolve_alias(t) == body.resolve_alias(f) {
+            args.push(t);
+            continue;
+        }
+        let ty = body.blocks[join].params[i].0;
+        let op = select_op(ty);
+        let select = body.add_op(block, op, &[t, f, cond], &[ty]);
+        body.merge_locs(select, &[t, f, cond]);
+        args.push(select);
+    }
+    body.blocks[block].terminator = Terminator::Br {
+        target: BlockTarget { block: join, args },
+    };
+    true
+}
+
+fn select_op(ty: Type) -> Operator {
+    match ty {
+        Type::FuncRef | Type::TypedFuncRef(..) => Operator::TypedSelect { ty },
+        _ => Operator::Select,
+    }
+}
+
+/// Expand each `select` for which `should_expand` returns `true`
+/// into a branch, sinking operand computations that are used only by
+/// the `select` into the arm that needs them. Returns `true` if
+/// anything changed.
+pub fn expand_selects<F: FnMut(&FunctionBody, Value) -> bool>(
+    body: &mut FunctionBody,
+    mut should_expand: F,
+) -> bool {
+    let mut changed = false;
+    let mut worklist = body.blocks.iter().collect::<Vec<_>>();
+    while let Some(block) = worklist.pop() {
+        let select = body.blocks[block].insts.iter().position(|&inst| {
+            matches!(
+                body.values[inst],
+                ValueDef::Operator(Operator::Select | Operator::TypedSelect { .. }, ..)
+            ) && should_expand(body, inst)
+        });
+        if let Some(index) = select {
+            // The remainder of the block moves to a new join block,
+            // which may contain more selects.
+            worklist.push(split_at_select(body, block, index));
+            changed = true;
+        }
+    }
+    if changed {
+        body.recompute_edges();
+    }
+    changed
+}
+
+fn split_at_select(body: &mut FunctionBody, block: Block, index: usize) -> Block {
+    let select = body.blocks[block].insts[index];
+    let (args, ty) = match &body.values[select] {
+        ValueDef::Operator(_, args, tys) => {
+            (body.arg_pool[*args].to_vec(), body.type_pool[*tys][0])
+        }
+        _ => unreachable!(),
+    };
+    let (on_true, on_false, cond) = (args[0], args[1], args[2]);
+
+    let mut uses: HashMap<Value, usize> = HashMap::new();
+    for def in body.blocks.values() {
+        for &inst in &def.insts {
+            match &body.values[inst] {
+                ValueDef::Operator(_, args, _) => {
+                    for &arg in &body.arg_pool[*args] {
+                        *uses.entry(body.resolve_alias(arg)).or_insert(0) += 1;
+                    }
+                }
+                ValueDef::PickOutput(value, ..) => {
+                    *uses.entry(body.resolve_alias(*value)).or_insert(0) += 1;
+                }
+                _ => {}
+            }
+        }
+        def.terminator.visit_uses(|value| {
+            *uses.entry(body.resolve_alias(value)).or_insert(0) += 1;
+        });
+    }
+    let before = body.blocks[block].insts[..index]
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    let true_chain = sinkable(body, on_true, &before, &uses);
+    let false_chain = sinkable(body, on_false, &before, &uses);
+
+    let origin = BlockOrigin::SyntheticFor("select");
+    let if_true = body.add_block_with_origin(origin.clone());
+    let if_false = body.add_block_with_origin(origin.clone());
+    let join = body.add_block_with_origin(origin);
+    let param = body.add_blockparam(join, ty);
+
+    let insts = std::mem::take(&mut body.blocks[block].insts);
+    for (i, &inst) in insts.iter().enumerate() {
+        let dest = if i == index {
+            continue;
+        } else if i > index {
+            join
+        } else if true_chain.contains(&inst) {
+            if_true
+        } else if false_chain.contains(&inst) {
+            if_false
+        } else {
+            block
+        };
+        body.append_to_block(dest, inst);
+    }
+    body.values[select] = ValueDef::Alias(param);
+
+    let terminator = std::mem::take(&mut body.blocks[block].terminator);
+    body.blocks[join].terminator = terminator;
+    body.blocks[if_true].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: join,
+            args: vec![on_true],
+        },
+    };
+    body.blocks[if_false].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: join,
+            args: vec![on_false],
+        },
+    };
+    body.blocks[block].terminator = Terminator::CondBr {
+        cond,
+        if_true: BlockTarget {
+            block: if_true,
+            args: vec![],
+        },
+        if_false: BlockTarget {
+            block: if_false,
+            args: vec![],
+        },
+    };
+    join
+}
+
+/// The instructions among `candidates` that compute `value` and are
+/// used only (transitively) by it, so that they may be sunk into the
+/// arm that uses `value`.
+fn sinkable(
+    body: &FunctionBody,
+    value: Value,
+    candidates: &HashSet<Value>,
+    uses: &HashMap<Value, usize>,
+) -> HashSet<Value> {
+    let mut chain = HashSet::new();
+    let mut stack = vec![body.resolve_alias(value)];
+    while let Some(value) = stack.pop() {
+        if !candidates.contains(&value) || uses.get(&value).copied().unwrap_or(0) != 1 {
+            continue;
+        }
+        match &body.values[value] {
+            ValueDef::Operator(op, args, _) if op.is_pure() => {
+                chain.insert(value);
+                stack.extend(
+                    body.arg_pool[*args]
+                        .iter()
+                        .map(|&arg| body.resolve_alias(arg)),
+                );
+            }
+            _ => {}
+        }
+    }
+    chain
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, Module, SignatureData};
+    use crate::{ConstVal, InterpContext};
+
+    #[test]
+    fn diamond_roundtrip() {
+        // f(x) = if x { x + 1 } else { x * 2 }
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let join = body.add_block();
+        let result = body.add_blockparam(join, Type::I32);
+        body.set_terminator(
+            join,
+            Terminator::Return {
+                values: vec![result],
+            },
+        );
+        let arm = |body: &mut FunctionBody, op: Operator, c: u32| {
+            let block = body.add_block();
+            let c = body.add_op(block, Operator::I32Const { value: c }, &[], &[Type::I32]);
+            let value = body.add_op(block, op, &[x, c], &[Type::I32]);
+            body.set_terminator(
+                block,
+                Terminator::Br {
+                    target: BlockTarget {
+                        block: join,
+                        args: vec![value],
+                    },
+                },
+            );
+            BlockTarget {
+                block,
+                args: vec![],
+            }
+        };
+        let if_true = arm(&mut body, Operator::I32Add, 1);
+        let if_false = arm(&mut body, Operator::I32Mul, 2);
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: x,
+                if_true,
+                if_false,
+            },
+        );
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let check = |module: &Module| {
+            module.funcs[func].body().unwrap().validate().unwrap();
+            let mut ctx = InterpContext::new(module).unwrap();
+            for (arg, expected) in [(0, 0), (1, 2), (7, 8)] {
+                let result = ctx.call(module, func, &[ConstVal::I32(arg)]).ok().unwrap();
+                assert_eq!(result[0], ConstVal::I32(expected));
+            }
+        };
+
+        assert!(form_selects(
+            module.funcs[func].body_mut().unwrap(),
+            &OptOptions::default()
+        ));
+        let body = module.funcs[func].body().unwrap();
+        assert!(matches!(
+            body.blocks[entry].terminator,
+            Terminator::Br { .. }
+        ));
+        assert_eq!(body.blocks[entry].insts.len(), 5);
+        check(&module);
+
+        assert!(expand_selects(
+            module.funcs[func].body_mut().unwrap(),
+            |_, _| true
+        ));
+        let body = module.funcs[func].body().unwrap();
+        assert!(matches!(
+            body.blocks[entry].terminator,
+            Terminator::CondBr { .. }
+        ));
+        assert!(body.blocks[entry].insts.is_empty());
+        check(&module);
+    }
+}
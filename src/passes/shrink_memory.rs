@@ -0,0 +1,277 @@
+//! Memory-layout shrinking.
+//!
+//! Linkers for C and Rust guests commonly reserve a fixed-size stack
+//! and then round the initial memory size up generously, so that a
+//! small guest may still declare many pages that it never touches
+//! before its allocator calls `memory.grow`. Every declared page has
+//! to be reserved (and often zeroed) at instantiation. This pass
+//! reduces `initial_pages` of the main memory to cover only the
+//! static data, the stack, and any constant addresses accessed by
+//! the code, and can optionally shrink the stack itself.
+//!
+//! The transform relies on the usual toolchain convention that all
+//! dynamically-addressed memory lies either below the heap base or
+//! in memory acquired through `memory.grow`. It is therefore only
+//! applied to a memory whose heap base is known.
+
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, ImportKind, Memory, Module, Type, Value, ValueDef, WASM_PAGE};
+use crate::{ExportKind, Global, Operator};
+
+/// Options for memory-layout shrinking.
+#[derive(Clone, Debug, Default)]
+pub struct ShrinkMemoryOptions {
+    /// The global holding the address at which the heap begins. If
+    /// `None`, a global exported as `__heap_base` is used, if any.
+    pub heap_base: Option<Global>,
+    /// The global holding the stack pointer. If `None`, a global
+    /// exported as `__stack_pointer` is used, if any. The stack is
+    /// assumed to grow downward from the pointer's initial value.
+    pub stack_pointer: Option<Global>,
+    /// If set, and the stack lies between the static data and the
+    /// heap, move the top of the stack down so that exactly this many
+    /// bytes remain for it, rewriting the initial values of the stack
+    /// pointer and (if it started at the old stack top) the heap base.
+    ///
+    /// This is only sound if the code reads these addresses through
+    /// the globals; constants baked into function bodies by the
+    /// linker are not rewritten.
+    pub stack_size: Option<usize>,
+}
+
+/// The result of shrinking one memory.
+#[derive(Clone, Debug)]
+pub struct ShrinkResult {
+    /// The memory that was shrunk.
+    pub memory: Memory,
+    /// The initial size in pages before the transform.
+    pub old_pages: usize,
+    /// The initial size in pages after the transform.
+    pub new_pages: usize,
+}
+
+/// Shrink the initial size of the main memory (memory 0) as far as
+/// its static layout allows. Returns a description of the change, or
+/// `None` if the memory was left untouched.
+///
+/// Imported memories are never changed, and neither is a memory
+/// whose heap base is unknown.
+pub fn run(module: &mut Module, options: &ShrinkMemoryOptions) -> Option<ShrinkResult> {
+    let memory = Memory::new(0);
+    if memory.index() >= module.memories.len() {
+        return None;
+    }
+    if module
+        .imports
+        .iter()
+        .any(|import| import.kind == ImportKind::Memory(memory))
+    {
+        log::debug!("shrink_memory: {} is imported; not shrinking", memory);
+        return None;
+    }
+
+    let heap_base_global = options
+        .heap_base
+        .or_else(|| exported_global(module, "__heap_base"));
+    let heap_base = match heap_base_global.and_then(|g| i32_init(module, g)) {
+        Some(addr) => addr,
+        None => {
+            log::debug!("shrink_memory: heap base unknown; not shrinking");
+            return None;
+        }
+    };
+    let stack_pointer_global = options
+        .stack_pointer
+        .or_else(|| exported_global(module, "__stack_pointer"));
+    let stack_top = stack_pointer_global.and_then(|g| i32_init(module, g));
+
+    let data_end = module.memories[memory]
+        .segments
+        .iter()
+        .map(|seg| seg.offset.saturating_add(seg.data.len()))
+        .max()
+        .unwrap_or(0);
+    let static_end = match constant_extent(module, memory) {
+        Some(end) => std::cmp::max(data_end, end),
+        None => {
+            log::debug!("shrink_memory: unbounded constant access; not shrinking");
+            return None;
+        }
+    };
+
+    let mut heap_base = heap_base;
+    let mut stack_top = stack_top;
+    if let (Some(size), Some(sp), Some(old_top)) =
+        (options.stack_size, stack_pointer_global, stack_top)
+    {
+        let stack_bottom = (static_end + 15) & !15;
+        let new_top = stack_bottom.saturating_add(size);
+        // Only a stack that sits between the static data and the heap
+        // can be moved without relocating anything else.
+        if old_top >= stack_bottom && old_top <= heap_base && new_top < old_top {
+            log::debug!(
+                "shrink_memory: moving stack top from {:#x} to {:#x}",
+                old_top,
+                new_top
+            );
+            module.globals[sp].value = Some(new_top as u64);
+            if heap_base == old_top {
+                if let Some(hb) = heap_base_global {
+                    module.globals[hb].value = Some(new_top as u64);
+                    heap_base = new_top;
+                }
+            }
+            stack_top = Some(new_top);
+        }
+    }
+
+    let needed = [static_end, heap_base, stack_top.unwrap_or(0)]
+        .iter()
+        .copied()
+        .max()
+        .unwrap();
+    let new_pages = needed.div_ceil(WASM_PAGE);
+    let mem_data = &mut module.memories[memory];
+    let old_pages = mem_data.initial_pages;
+    if new_pages >= old_pages {
+        return None;
+    }
+    mem_data.initial_pages = new_pages;
+    log::debug!(
+        "shrink_memory: {} shrunk from {} to {} pages",
+        memory,
+        old_pages,
+        new_pages
+    );
+    Some(ShrinkResult {
+        memory,
+        old_pages,
+        new_pages,
+    })
+}
+
+pub(crate) fn exported_global(module: &Module, name: &str) -> Option<Global> {
+    module.exports.iter().find_map(|export| match export.kind {
+        ExportKind::Global(global) if export.name == name => Some(global),
+        _ => None,
+    })
+}
+
+/// The initial value of an `i32` global defined by this module.
+pub(crate) fn i32_init(module: &Module, global: Global) -> Option<usize> {
+    let data = module.globals.get(global)?;
+    if data.ty != Type::I32 {
+        return None;
+    }
+    if module
+        .imports
+        .iter()
+        .any(|import| import.kind == ImportKind::Global(global))
+    {
+        return None;
+    }
+    data.value.map(|value| value as u32 as usize)
+}
+
+/// Compute the end of the highest constant-addressed access to
+/// `memory` in any function body, or `None` if some function body
+/// has not been expanded or a bulk-memory operation has a constant
+/// address but a non-constant length.
+fn constant_extent(module: &Module, memory: Memory) -> Option<usize> {
+    let mut end = 0;
+    for decl in module.funcs.values() {
+        let body = match decl {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Import(..) | FuncDecl::None => continue,
+            FuncDecl::Lazy(..) | FuncDecl::Compiled(..) => return None,
+        };
+        let const_arg = |value: Value| -> Option<usize> {
+            match &body.values[body.resolve_alias(value)] {
+                ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value as usize),
+                _ => None,
+            }
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                let (op, args) = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+                    _ => continue,
+                };
+                let (base, len) = match op {
+                    Operator::MemoryCopy { dst_mem, src_mem } => {
+                        for (mem, addr) in [(*dst_mem, args[0]), (*src_mem, args[1])] {
+                            if mem == memory {
+                                if let Some(addr) = const_arg(addr) {
+                                    end = std::cmp::max(end, addr + const_arg(args[2])?);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    Operator::MemoryFill { mem } if *mem == memory => {
+                        (const_arg(args[0]), const_arg(args[2]))
+                    }
+                    _ => match op.memory_access() {
+                        Some((memarg, width)) if memarg.memory == memory => (
+                            const_arg(args[0]).map(|addr| addr + memarg.offset as usize),
+                            Some(width),
+                        ),
+                        _ => continue,
+                    },
+                };
+                if let Some(base) = base {
+                    end = std::cmp::max(end, base + len?);
+                }
+            }
+        }
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Export, GlobalData, MemoryData, MemorySegment};
+
+    fn global(module: &mut Module, value: u64, mutable: bool) -> Global {
+        module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(value),
+            mutable,
+            init: None,
+        })
+    }
+
+    #[test]
+    fn shrink_with_stack() {
+        let mut module = Module::empty();
+        module.memories.push(MemoryData {
+            initial_pages: 32,
+            maximum_pages: None,
+            segments: vec![MemorySegment {
+                offset: 1024,
+                data: vec![1, 2, 3],
+            }],
+        });
+        let sp = global(&mut module, 0x10_0410, true);
+        let heap_base = global(&mut module, 0x10_0410, false);
+        module.exports.push(Export {
+            name: "__heap_base".to_owned(),
+            kind: ExportKind::Global(heap_base),
+        });
+
+        let mut opts = ShrinkMemoryOptions {
+            stack_pointer: Some(sp),
+            ..Default::default()
+        };
+        let result = run(&mut module, &opts).unwrap();
+        assert_eq!((result.old_pages, result.new_pages), (32, 17));
+
+        opts.stack_size = Some(0x1000);
+        let result = run(&mut module, &opts).unwrap();
+        assert_eq!((result.old_pages, result.new_pages), (17, 1));
+        assert_eq!(module.globals[sp].value, Some(0x1410));
+        assert_eq!(module.globals[heap_base].value, Some(0x1410));
+        let _ = module.to_wasm_bytes().unwrap();
+    }
+}
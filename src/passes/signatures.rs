@@ -0,0 +1,280 @@
+//! Type-section minimization.
+//!
+//! Transforms that remove or rewrite functions leave signatures in
+//! the type section that nothing refers to any more, and modules
+//! merged from several sources often declare the same signature many
+//! times. This pass keeps only the signatures that are referenced,
+//! directly or through a typed function reference in another kept
+//! signature, merges identical ones, and renumbers every reference.
+//!
+//! Each signature is its own recursion group, so merging identical
+//! signatures does not change which `call_indirect`s succeed. The IR
+//! has no GC struct or array types; function signatures are the whole
+//! type section.
+
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{FuncDecl, Module, SignatureData, Type, ValueDef};
+use crate::{Operator, Signature};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Find the signature with `params` and `returns` in `module`, adding
+/// it if there is none.
+pub fn intern(module: &mut Module, params: &[Type], returns: &[Type]) -> Signature {
+    let existing = module
+        .signatures
+        .entries()
+        .find(|(_, data)| data.params == params && data.returns == returns)
+        .map(|(sig, _)| sig);
+    existing.unwrap_or_else(|| {
+        module.signatures.push(SignatureData {
+            params: params.to_vec(),
+            returns: returns.to_vec(),
+        })
+    })
+}
+
+/// Minimize the module's signatures. Returns the mapping from old to
+/// new signature indices, with `Signature::invalid()` for signatures
+/// that were removed, so that tools referring to type indices from
+/// outside the module can follow the renumbering.
+///
+/// All function bodies must be expanded (see
+/// `Module::expand_all_funcs()`), since bytecode cannot be
+/// renumbered; otherwise nothing is changed and `None` is returned.
+pub fn minimize(module: &mut Module) -> Option<PerEntity<Signature, Signature>> {
+    if module
+        .funcs
+        .values()
+        .any(|decl| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Compiled(..)))
+    {
+        log::debug!("signatures: not all function bodies are expanded; not minimizing");
+        return None;
+    }
+
+    // Find all referenced signatures.
+    let mut used = vec![];
+    visit_refs(module, &mut |sig| used.push(*sig));
+    let mut seen = vec![false; module.signatures.len()];
+    let mut order = vec![];
+    while let Some(sig) = used.pop() {
+        if seen[sig.index()] {
+            continue;
+        }
+        seen[sig.index()] = true;
+        order.push(sig);
+        let data = &module.signatures[sig];
+        for &ty in data.params.iter().chain(data.returns.iter()) {
+            if let Type::TypedFuncRef(_, index) = ty {
+                used.push(Signature::new(index as usize));
+            }
+        }
+    }
+    order.sort();
+
+    // Assign new indices, merging identical signatures.
+    let mut mapping = PerEntity::default();
+    let mut dedup: HashMap<&SignatureData, Signature> = HashMap::new();
+    let mut kept = vec![];
+    for &old in &order {
+        let data = &module.signatures[old];
+        let new = *dedup.entry(data).or_insert_with(|| {
+            kept.push(old);
+            Signature::new(kept.len() - 1)
+        });
+        mapping[old] = new;
+    }
+    let mut signatures = kept
+        .iter()
+        .map(|&old| module.signatures[old].clone())
+        .collect::<Vec<_>>();
+    for data in &mut signatures {
+        for ty in data.params.iter_mut().chain(data.returns.iter_mut()) {
+            remap_type(ty, &mapping);
+        }
+    }
+
+    log::debug!(
+        "signatures: kept {} of {}",
+        signatures.len(),
+        module.signatures.len()
+    );
+    module.signatures = Default::default();
+    for data in signatures {
+        module.signatures.push(data);
+    }
+    visit_refs(module, &mut |sig| *sig = mapping[*sig]);
+    Some(mapping)
+}
+
+/// Reorder the signatures so that they appear in `order`, which must
+/// list each exactly once and after any signature it refers to, and
+/// renumber every reference. All function bodies must be expanded,
+/// as for `minimize()`.
+pub(crate) fn reorder(module: &mut Module, order: &[Signature]) {
+    let mut mapping = PerEntity::default();
+    for (i, &old) in order.iter().enumerate() {
+        mapping[old] = Signature::new(i);
+    }
+    let old = std::mem::take(&mut module.signatures);
+    for &sig in order {
+        let mut data = old[sig].clone();
+        for ty in data.params.iter_mut().chain(data.returns.iter_mut()) {
+            remap_type(ty, &mapping);
+        }
+        module.signatures.push(data);
+    }
+    visit_refs(module, &mut |sig| *sig = mapping[*sig]);
+}
+
+fn remap_type(ty: &mut Type, mapping: &PerEntity<Signature, Signature>) {
+    if let Type::TypedFuncRef(_, index) = ty {
+        *index = mapping[Signature::new(*index as usize)].index() as u32;
+    }
+}
+
+/// Call `f` on every reference to a signature outside the type
+/// section itself.
+pub(crate) fn visit_refs<F: FnMut(&mut Signature)>(module: &mut Module, f: &mut F) {
+    let visit_type = |ty: &mut Type, f: &mut F| {
+        if let Type::TypedFuncRef(_, index) = ty {
+            let mut sig = Signature::new(*index as usize);
+            f(&mut sig);
+            *index = sig.index() as u32;
+        }
+    };
+    for global in module.globals.values_mut() {
+        visit_type(&mut global.ty, f);
+    }
+    for table in module.tables.values_mut() {
+        visit_type(&mut table.ty, f);
+    }
+    for decl in module.funcs.values_mut() {
+        match decl {
+            FuncDecl::Import(sig, _) => f(sig),
+            FuncDecl::Body(sig, _, body) => {
+                f(sig);
+                let body = Arc::make_mut(body);
+                for ty in body.rets.iter_mut() {
+                    visit_type(ty, f);
+                }
+                for ty in body.locals.values_mut() {
+                    visit_type(ty, f);
+                }
+                for block in body.blocks.values_mut() {
+                    for (ty, _) in block.params.iter_mut() {
+                        visit_type(ty, f);
+                    }
+                }
+                for ty in body.type_pool.items_mut() {
+                    visit_type(ty, f);
+                }
+                for value in body.values.values_mut() {
+                    match value {
+                        ValueDef::Operator(op, ..) => match op {
+                            Operator::CallIndirect { sig_index, .. }
+                            | Operator::CallRef { sig_index }
+                            | Operator::RefNull { sig_index }
+                                if sig_index.is_valid() =>
+                            {
+                                f(sig_index)
+                            }
+                            Operator::TypedSelect { ty } => visit_type(ty, f),
+                            _ => {}
+                        },
+                        ValueDef::BlockParam(_, _, ty)
+                        | ValueDef::PickOutput(_, _, ty)
+                        | ValueDef::Placeholder(ty) => visit_type(ty, f),
+                        _ => {}
+                    }
+                }
+                // Single-type lists are deduplicated by type, and the
+                // types have changed.
+                let dedup = std::mem::take(&mut body.single_type_dedup);
+                for (_, list) in dedup {
+                    let ty = body.type_pool[list][0];
+                    body.single_type_dedup.insert(ty, list);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, Terminator};
+    use crate::Table;
+
+    #[test]
+    fn unused_and_duplicate_signatures_removed() {
+        let mut module = Module::empty();
+        let unused = module.signatures.push(SignatureData {
+            params: vec![Type::F64],
+            returns: vec![],
+        });
+        let i2i = module.signatures.push(i2i_data());
+        let i2i_dup = module.signatures.push(i2i_data());
+        let takes_ref = module.signatures.push(SignatureData {
+            params: vec![Type::TypedFuncRef(true, i2i_dup.index() as u32)],
+            returns: vec![],
+        });
+        module.tables.push(crate::ir::TableData {
+            ty: Type::FuncRef,
+            initial: 0,
+            max: None,
+            func_elements: Some(vec![]),
+        });
+
+        let mut body = FunctionBody::new(&module, i2i);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let call = Operator::CallIndirect {
+            sig_index: i2i_dup,
+            table_index: Table::new(0),
+        };
+        let r = body.add_op(entry, call, &[x, x], &[Type::I32]);
+        let null = Operator::RefNull {
+            sig_index: takes_ref,
+        };
+        let n = body.add_op(
+            entry,
+            null,
+            &[],
+            &[Type::TypedFuncRef(true, takes_ref.index() as u32)],
+        );
+        body.set_terminator(entry, Terminator::Return { values: vec![r] });
+        module
+            .funcs
+            .push(FuncDecl::Body(i2i, "f".to_owned(), body.into()));
+
+        let mapping = minimize(&mut module).unwrap();
+        assert!(!mapping[unused].is_valid());
+        assert_eq!(mapping[i2i], mapping[i2i_dup]);
+        assert_eq!(module.signatures.len(), 2);
+        let new_ref = mapping[takes_ref];
+        assert_eq!(
+            module.signatures[new_ref].params,
+            vec![Type::TypedFuncRef(true, mapping[i2i].index() as u32)]
+        );
+        let body = module.funcs[crate::Func::new(0)].body().unwrap();
+        assert!(matches!(
+            body.values[r],
+            ValueDef::Operator(Operator::CallIndirect { sig_index, .. }, ..) if sig_index == mapping[i2i]
+        ));
+        assert_eq!(
+            body.values[n].ty(&body.type_pool),
+            Some(Type::TypedFuncRef(true, new_ref.index() as u32))
+        );
+        body.validate().unwrap();
+        module.verify().unwrap();
+    }
+
+    fn i2i_data() -> SignatureData {
+        SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        }
+    }
+}
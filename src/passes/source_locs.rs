@@ -0,0 +1,94 @@
+//! Source-location propagation checking.
+//!
+//! Passes that create instructions are expected to carry source
+//! locations over from the instructions they replace (see
+//! `FunctionBody::source_locs`). A lost location is silent: the
+//! output is still correct, but debuggers and profilers lose track of
+//! the code. `LocChecker` snapshots a function body before a pass runs
+//! and afterward reports the instructions the pass created without a
+//! location.
+
+use crate::entity::EntityRef;
+use crate::ir::{FunctionBody, Value, ValueDef};
+use crate::Operator;
+
+/// A snapshot of a function body against which later additions are
+/// checked.
+#[derive(Clone, Debug)]
+pub struct LocChecker {
+    /// Values numbered at or above this were created after the
+    /// snapshot.
+    first_new: usize,
+    /// Whether the body had any source locations at all. Functions
+    /// without debug info are not checked.
+    had_locs: bool,
+}
+
+impl LocChecker {
+    /// Take a snapshot of `body`.
+    pub fn new(body: &FunctionBody) -> LocChecker {
+        let had_locs = body
+            .blocks
+            .values()
+            .flat_map(|block| block.insts.iter())
+            .any(|&inst| body.source_locs[inst].is_valid());
+        LocChecker {
+            first_new: body.values.len(),
+            had_locs,
+        }
+    }
+
+    /// Return the instructions placed in `body` since the snapshot
+    /// that have no source location. Constants are exempt.
+    pub fn check(&self, body: &FunctionBody) -> Vec<Value> {
+        if !self.had_locs {
+            return vec![];
+        }
+        body.blocks
+            .values()
+            .flat_map(|block| block.insts.iter().copied())
+            .filter(|&inst| inst.index() >= self.first_new)
+            .filter(|&inst| !body.source_locs[inst].is_valid())
+            .filter(|&inst| {
+                !matches!(
+                    &body.values[inst],
+                    ValueDef::Operator(
+                        Operator::I32Const { .. }
+                            | Operator::I64Const { .. }
+                            | Operator::F32Const { .. }
+                            | Operator::F64Const { .. },
+                        ..
+                    )
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Module, SignatureData, SourceLoc, Terminator, Type};
+
+    #[test]
+    fn flags_new_inst_without_loc() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let old = body.add_op(entry, Operator::I32Eqz, &[x], &[Type::I32]);
+        body.source_locs[old] = SourceLoc::new(0);
+        body.set_terminator(entry, Terminator::Return { values: vec![old] });
+
+        let checker = LocChecker::new(&body);
+        let c = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let copied = body.add_op(entry, Operator::I32Add, &[old, c], &[Type::I32]);
+        body.copy_loc(old, copied);
+        let lost = body.add_op(entry, Operator::I32Sub, &[old, c], &[Type::I32]);
+        assert_eq!(checker.check(&body), vec![lost]);
+    }
+}
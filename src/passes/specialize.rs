@@ -0,0 +1,703 @@
+//! Module specialization: partial evaluation for one host
+//! configuration.
+//!
+//! A module built once for many hosts often asks its host how it is
+//! configured: an imported global holding a feature flag, or an
+//! imported function returning a version number. Given the values
+//! one host will supply, `specialize()` bakes them in, so that the
+//! module carries no code for configurations it will never see:
+//!
+//! - Each fixed imported global becomes a defined, immutable global
+//!   with the given value. Each defined exported global, such as a
+//!   build-configuration flag, takes the given value, becomes
+//!   immutable, and loses its export. Reads of these (and of every
+//!   other immutable global with a constant initial value) become
+//!   constants.
+//! - Calls to each fixed imported function are replaced by the given
+//!   results. Any other effect the host function would have had is
+//!   dropped.
+//! - The optimizer then runs in rounds, with effect summaries. In
+//!   between, branches on constants are folded, removing the code on
+//!   the untaken side, and calls to pure functions that always return
+//!   the same constants are replaced by those constants, carrying
+//!   constants across calls.
+//! - Finally, functions no longer reachable from the exports, the
+//!   start function, or a table are removed, along with imported
+//!   functions that are no longer called.
+
+use crate::cfg::CFGInfo;
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::interp::ConstVal;
+use crate::ir::{
+    ExportKind, FuncDecl, FunctionBody, ImportKind, Module, Terminator, Type, ValueDef,
+};
+use crate::passes::basic_opt::{const_op, value_is_const, OptOptions};
+use crate::passes::canonicalize::renumber;
+use crate::passes::remap::EntityMap;
+use crate::pool::ListRef;
+use crate::{Func, Global, Operator};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Options for module specialization.
+#[derive(Clone, Debug, Default)]
+pub struct SpecializeOptions {
+    /// The fixed imports, by module and name: the value of an
+    /// imported global, or the results of an imported function.
+    pub imports: BTreeMap<(String, String), Vec<ConstVal>>,
+    /// The values of defined globals, by export name. The module must
+    /// not write them itself.
+    pub defines: BTreeMap<String, ConstVal>,
+    /// Options for the optimization rounds. Their `effects` are
+    /// computed afresh for each round.
+    pub opt: OptOptions,
+}
+
+/// What specialization did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpecializeReport {
+    /// The number of branches on constants folded.
+    pub folded_branches: usize,
+    /// The number of calls and global reads replaced by constants.
+    pub folded_uses: usize,
+    /// The number of functions found to return constants, whose
+    /// calls were replaced.
+    pub constant_funcs: usize,
+    /// The number of functions removed, imports included.
+    pub removed_funcs: usize,
+}
+
+/// Specialize `module` to the fixed imports and defined globals in
+/// `options`, as described
+/// in the module documentation. All function bodies are expanded.
+pub fn specialize(module: &mut Module, options: &SpecializeOptions) -> Result<SpecializeReport> {
+    module.expand_all_funcs()?;
+    let mut report = SpecializeReport::default();
+
+    let mut constants: BTreeMap<Func, Vec<ConstVal>> = BTreeMap::new();
+    let mut fixed_globals = BTreeSet::new();
+    for ((module_name, name), values) in &options.imports {
+        let Some(kind) = module
+            .imports
+            .iter()
+            .find(|import| &import.module == module_name && &import.name == name)
+            .map(|import| import.kind.clone())
+        else {
+            bail!("Nothing is imported as {}.{}", module_name, name);
+        };
+        let types = values.iter().map(|value| value.ty());
+        match kind {
+            ImportKind::Global(global) => {
+                let data = &mut module.globals[global];
+                if data.mutable {
+                    bail!(
+                        "Cannot fix mutable imported global {}.{}",
+                        module_name,
+                        name
+                    );
+                }
+                if !types.eq([Some(data.ty)]) {
+                    bail!(
+                        "Value {:?} does not match the type {} of {}.{}",
+                        values,
+                        data.ty,
+                        module_name,
+                        name
+                    );
+                }
+                if data.ty == Type::V128 {
+                    bail!(
+                        "Cannot fix v128 imported global {}.{}: global values are at most 64 bits",
+                        module_name,
+                        name
+                    );
+                }
+                data.value = values[0].bits();
+                data.init = None;
+                fixed_globals.insert(global);
+            }
+            ImportKind::Func(func) => {
+                let sig = module.funcs[func].sig();
+                let returns = &module.signatures[sig].returns;
+                if !types.eq(returns.iter().map(|&ty| Some(ty))) {
+                    bail!(
+                        "Results {:?} do not match the results {:?} of {}.{}",
+                        values,
+                        returns,
+                        module_name,
+                        name
+                    );
+                }
+                constants.insert(func, values.clone());
+            }
+            _ => bail!("Cannot fix the value of {}.{}", module_name, name),
+        }
+    }
+    define_globals(module, &fixed_globals);
+
+    for (name, &value) in &options.defines {
+        let Some(global) = module.exports.iter().find_map(|export| match export.kind {
+            ExportKind::Global(global) if &export.name == name => Some(global),
+            _ => None,
+        }) else {
+            bail!("No global is exported as {}", name);
+        };
+        if module
+            .imports
+            .iter()
+            .any(|import| import.kind == ImportKind::Global(global))
+        {
+            bail!("Cannot define imported global {}", name);
+        }
+        let data = &mut module.globals[global];
+        if value.ty() != Some(data.ty) {
+            bail!(
+                "Value {:?} does not match the type {} of {}",
+                value,
+                data.ty,
+                name
+            );
+        }
+        if data.ty == Type::V128 {
+            bail!(
+                "Cannot define v128 global {}: global values are at most 64 bits",
+                name
+            );
+        }
+        let written = module
+            .funcs
+            .values()
+            .filter_map(|decl| decl.body())
+            .any(|body| {
+                body.values.values().any(|def| {
+                    matches!(def, ValueDef::Operator(Operator::GlobalSet { global_index }, ..)
+                    if *global_index == global)
+                })
+            });
+        if written {
+            bail!("Cannot define global {}, which the module writes", name);
+        }
+        data.value = value.bits();
+        data.init = None;
+        data.mutable = false;
+        module.exports.retain(|export| &export.name != name);
+    }
+
+    let globals: BTreeMap<Global, ConstVal> = module
+        .globals
+        .entries()
+        .filter(|(global, data)| {
+            !data.mutable
+                && data.init.is_none()
+                && !module
+                    .imports
+                    .iter()
+                    .any(|import| import.kind == ImportKind::Global(*global))
+        })
+        .filter_map(|(global, data)| Some((global, constant(data.ty, data.value?)?)))
+        .collect();
+
+    loop {
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                report.folded_uses += fold_uses(body, &constants, &globals);
+            }
+        }
+
+        let opts = OptOptions {
+            effects: Some(Arc::new(module.effect_summaries())),
+            ..options.opt.clone()
+        };
+        module.optimize(&opts);
+        let mut folded = 0;
+        for decl in module.funcs.values_mut() {
+            if let Some(body) = decl.body_mut() {
+                folded += fold_branches(body);
+            }
+        }
+        report.folded_branches += folded;
+
+        let summaries = module.effect_summaries();
+        let mut found = 0;
+        for (func, decl) in module.funcs.entries() {
+            let Some(body) = decl.body() else {
+                continue;
+            };
+            if constants.contains_key(&func) || !summaries.get(func).is_pure() {
+                continue;
+            }
+            if let Some(values) = constant_results(body) {
+                log::debug!("specialize: {} always returns {:?}", func, values);
+                constants.insert(func, values);
+                found += 1;
+            }
+        }
+        report.constant_funcs += found;
+
+        if folded == 0 && found == 0 {
+            break;
+        }
+    }
+    report.removed_funcs = remove_unreachable_funcs(module);
+    log::debug!("specialize: {:?}", report);
+    Ok(report)
+}
+
+/// A global's initial value as a constant of type `ty`.
+fn constant(ty: Type, bits: u64) -> Option<ConstVal> {
+    match ty {
+        Type::I32 => Some(ConstVal::I32(bits as u32)),
+        Type::I64 => Some(ConstVal::I64(bits)),
+        Type::F32 => Some(ConstVal::F32(bits as u32)),
+        Type::F64 => Some(ConstVal::F64(bits)),
+        _ => None,
+    }
+}
+
+/// Turn the imported `globals` into definitions, keeping the imported
+/// globals first in the index space.
+fn define_globals(module: &mut Module, globals: &BTreeSet<Global>) {
+    if globals.is_empty() {
+        return;
+    }
+    module.imports.retain(|import| match import.kind {
+        ImportKind::Global(global) => !globals.contains(&global),
+        _ => true,
+    });
+    let mut order: Vec<Global> = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Global(global) => Some(global),
+            _ => None,
+        })
+        .collect();
+    let imported = order.iter().copied().collect::<BTreeSet<_>>();
+    order.extend(
+        module
+            .globals
+            .iter()
+            .filter(|global| !imported.contains(global)),
+    );
+
+    let mut map = EntityMap::identity(module);
+    for (i, &global) in order.iter().enumerate() {
+        map.globals[global] = Global::new(i);
+    }
+    module.globals = EntityVec::from(
+        order
+            .iter()
+            .map(|&global| module.globals[global].clone())
+            .collect::<Vec<_>>(),
+    );
+    map.apply(module);
+}
+
+/// Replace calls to the functions in `constants`, and reads of the
+/// `globals`, by their constant values. Returns the number replaced.
+fn fold_uses(
+    body: &mut FunctionBody,
+    constants: &BTreeMap<Func, Vec<ConstVal>>,
+    globals: &BTreeMap<Global, ConstVal>,
+) -> usize {
+    let mut folded = 0;
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let mut insts = std::mem::take(&mut body.blocks[block].insts);
+        insts.retain(|&inst| {
+            let ValueDef::Operator(op, _, tys) = body.values[inst] else {
+                return true;
+            };
+            let values = match op {
+                Operator::GlobalGet { global_index } => match globals.get(&global_index) {
+                    Some(&value) => vec![value],
+                    None => return true,
+                },
+                Operator::Call { function_index } => match constants.get(&function_index) {
+                    Some(values) => values.clone(),
+                    None => return true,
+                },
+                _ => return true,
+            };
+            folded += 1;
+            if values.len() == 1 {
+                let op = const_op(&mut body.imm_pool, values[0]);
+                body.values[inst] = ValueDef::Operator(op, ListRef::default(), tys);
+                return true;
+            }
+            // The call's results, if any, are picked out by separate
+            // values, which become the constants instead.
+            for def in body.values.values_mut() {
+                if let &mut ValueDef::PickOutput(from, i, ty) = def {
+                    if from == inst {
+                        let ty = body.type_pool.single(ty);
+                        *def = ValueDef::Operator(
+                            const_op(&mut body.imm_pool, values[i as usize]),
+                            ListRef::default(),
+                            ty,
+                        );
+                    }
+                }
+            }
+            body.values[inst] = ValueDef::None;
+            false
+        });
+        body.blocks[block].insts = insts;
+    }
+    if folded > 0 {
+        body.mark_changed();
+    }
+    folded
+}
+
+/// Turn branches on constants into unconditional branches, and drop
+/// the blocks no longer reachable. Returns the number of branches
+/// folded.
+fn fold_branches(body: &mut FunctionBody) -> usize {
+    let mut folded = 0;
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let target = match &body.blocks[block].terminator {
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => match value_is_const(body.resolve_alias(*cond), body) {
+                ConstVal::I32(0) => if_false.clone(),
+                ConstVal::I32(_) => if_true.clone(),
+                _ => continue,
+            },
+            Terminator::Select {
+                value,
+                targets,
+                default,
+            } => match value_is_const(body.resolve_alias(*value), body) {
+                ConstVal::I32(i) => targets.get(i as usize).unwrap_or(default).clone(),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        body.blocks[block].terminator = Terminator::Br { target };
+        folded += 1;
+    }
+    if folded > 0 {
+        body.recompute_edges();
+        *body = renumber(body);
+    }
+    folded
+}
+
+/// The results of `body`, if every return in it returns the same
+/// constants.
+fn constant_results(body: &FunctionBody) -> Option<Vec<ConstVal>> {
+    let cfg = CFGInfo::new(body);
+    let mut results: Option<Vec<ConstVal>> = None;
+    for &block in cfg.rpo.values() {
+        match &body.blocks[block].terminator {
+            Terminator::Return { values } => {
+                let values = values
+                    .iter()
+                    .map(
+                        |&value| match value_is_const(body.resolve_alias(value), body) {
+                            ConstVal::None => None,
+                            value => Some(value),
+                        },
+                    )
+                    .collect::<Option<Vec<_>>>()?;
+                if *results.get_or_insert_with(|| values.clone()) != values {
+                    return None;
+                }
+            }
+            Terminator::None => return None,
+            _ => {}
+        }
+    }
+    results
+}
+
+/// Remove the functions, imports included, that cannot be reached
+/// from the exports, the start function, or a table, renumbering the
+/// rest. Returns the number removed. Does nothing if some body is not
+/// expanded to IR, since its references cannot be seen.
+fn remove_unreachable_funcs(module: &mut Module) -> usize {
+    if module
+        .funcs
+        .values()
+        .any(|decl| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Compiled(..)))
+    {
+        return 0;
+    }
+    let mut reached = BTreeSet::new();
+    let mut worklist: Vec<Func> = module
+        .exports
+        .iter()
+        .filter_map(|export| match export.kind {
+            ExportKind::Func(func) => Some(func),
+            _ => None,
+        })
+        .chain(module.start_func)
+        .chain(
+            module
+                .tables
+                .values()
+                .flat_map(|table| table.func_elements.iter().flatten())
+                .copied()
+                .filter(|func| func.is_valid()),
+        )
+        .collect();
+    while let Some(func) = worklist.pop() {
+        if !reached.insert(func) {
+            continue;
+        }
+        let Some(body) = module.funcs[func].body() else {
+            continue;
+        };
+        for value in body.values.values() {
+            if let ValueDef::Operator(
+                Operator::Call { function_index }
+                | Operator::RefFunc {
+                    func_index: function_index,
+                },
+                ..,
+            ) = value
+            {
+                worklist.push(*function_index);
+            }
+        }
+    }
+
+    let removed = module.funcs.len() - reached.len();
+    if removed == 0 {
+        return 0;
+    }
+    let mut map = EntityMap::identity(module);
+    map.funcs = PerEntity::default();
+    let old = std::mem::take(&mut module.funcs);
+    for (i, decl) in old.into_vec().into_iter().enumerate() {
+        let func = Func::new(i);
+        if reached.contains(&func) {
+            map.funcs[func] = module.funcs.push(decl);
+        }
+    }
+    module.imports.retain(|import| match import.kind {
+        ImportKind::Func(func) => reached.contains(&func),
+        _ => true,
+    });
+    map.apply(module);
+    module.intrinsics = std::mem::take(&mut module.intrinsics)
+        .into_iter()
+        .filter(|(func, _)| reached.contains(func))
+        .map(|(func, intrinsic)| (map.funcs[func], intrinsic))
+        .collect();
+    removed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FrontendOptions, InterpContext};
+    use wasm_encoder::{
+        BlockType, CodeSection, EntityType, ExportSection, FunctionSection, GlobalType,
+        ImportSection, Instruction, TypeSection, ValType,
+    };
+
+    #[test]
+    fn fixed_imports_fold_away() {
+        // Imports: global `env.debug`, function `env.version`.
+        // `log` is called only when debugging; `is_new` compares the
+        // version; `run(x)` returns x + 1 on new hosts, x + 2 on old.
+        let mut types = TypeSection::new();
+        types.function([], [ValType::I32]);
+        types.function([ValType::I32], [ValType::I32]);
+        types.function([], []);
+        let mut imports = ImportSection::new();
+        imports.import(
+            "env",
+            "debug",
+            EntityType::Global(GlobalType {
+                val_type: ValType::I32,
+                mutable: false,
+                shared: false,
+            }),
+        );
+        imports.import("env", "version", EntityType::Function(0));
+        let mut funcs = FunctionSection::new();
+        funcs.function(2);
+        funcs.function(0);
+        funcs.function(1);
+        let mut exports = ExportSection::new();
+        exports.export("run", wasm_encoder::ExportKind::Func, 3);
+        let mut code = CodeSection::new();
+        let mut log = wasm_encoder::Function::new([]);
+        log.instruction(&Instruction::Unreachable);
+        log.instruction(&Instruction::End);
+        code.function(&log);
+        let mut is_new = wasm_encoder::Function::new([]);
+        is_new.instruction(&Instruction::Call(0));
+        is_new.instruction(&Instruction::I32Const(2));
+        is_new.instruction(&Instruction::I32GeU);
+        is_new.instruction(&Instruction::End);
+        code.function(&is_new);
+        let mut run = wasm_encoder::Function::new([]);
+        run.instruction(&Instruction::GlobalGet(0));
+        run.instruction(&Instruction::If(BlockType::Empty));
+        run.instruction(&Instruction::Call(1));
+        run.instruction(&Instruction::End);
+        run.instruction(&Instruction::LocalGet(0));
+        run.instruction(&Instruction::Call(2));
+        run.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+        run.instruction(&Instruction::I32Const(1));
+        run.instruction(&Instruction::Else);
+        run.instruction(&Instruction::I32Const(2));
+        run.instruction(&Instruction::End);
+        run.instruction(&Instruction::I32Add);
+        run.instruction(&Instruction::End);
+        code.function(&run);
+        let mut encoder = wasm_encoder::Module::new();
+        encoder
+            .section(&types)
+            .section(&imports)
+            .section(&funcs)
+            .section(&exports)
+            .section(&code);
+        let bytes = encoder.finish();
+
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let mut options = SpecializeOptions::default();
+        options.imports.insert(
+            ("env".to_owned(), "debug".to_owned()),
+            vec![ConstVal::I32(0)],
+        );
+        options.imports.insert(
+            ("env".to_owned(), "version".to_owned()),
+            vec![ConstVal::I32(3)],
+        );
+        let report = specialize(&mut module, &options).unwrap();
+        assert_eq!(report.folded_branches, 2);
+        assert_eq!(report.constant_funcs, 1);
+        assert_eq!(report.removed_funcs, 3);
+        assert!(module.imports.is_empty());
+        assert_eq!(module.funcs.len(), 1);
+
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx
+            .call(&module, Func::new(0), &[ConstVal::I32(5)])
+            .ok()
+            .unwrap();
+        assert_eq!(&result[..], &[ConstVal::I32(6)]);
+    }
+
+    #[test]
+    fn defined_globals_strip_code() {
+        // An exported mutable global `debug`, set to 1; `run` calls
+        // `log` when it is set. `set` writes the global when asked.
+        let build = |with_set: bool| {
+            let mut types = TypeSection::new();
+            types.function([], []);
+            let mut funcs = FunctionSection::new();
+            funcs.function(0);
+            funcs.function(0);
+            let mut globals = wasm_encoder::GlobalSection::new();
+            globals.global(
+                GlobalType {
+                    val_type: ValType::I32,
+                    mutable: true,
+                    shared: false,
+                },
+                &wasm_encoder::ConstExpr::i32_const(1),
+            );
+            let mut exports = ExportSection::new();
+            exports.export("run", wasm_encoder::ExportKind::Func, 1);
+            exports.export("debug", wasm_encoder::ExportKind::Global, 0);
+            let mut code = CodeSection::new();
+            let mut log = wasm_encoder::Function::new([]);
+            log.instruction(&Instruction::Nop);
+            log.instruction(&Instruction::End);
+            code.function(&log);
+            let mut run = wasm_encoder::Function::new([]);
+            run.instruction(&Instruction::GlobalGet(0));
+            run.instruction(&Instruction::If(BlockType::Empty));
+            run.instruction(&Instruction::Call(0));
+            run.instruction(&Instruction::End);
+            if with_set {
+                run.instruction(&Instruction::I32Const(0));
+                run.instruction(&Instruction::GlobalSet(0));
+            }
+            run.instruction(&Instruction::End);
+            code.function(&run);
+            let mut encoder = wasm_encoder::Module::new();
+            encoder
+                .section(&types)
+                .section(&funcs)
+                .section(&globals)
+                .section(&exports)
+                .section(&code);
+            encoder.finish()
+        };
+        let mut options = SpecializeOptions::default();
+        options.defines.insert("debug".to_owned(), ConstVal::I32(0));
+
+        let bytes = build(true);
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        assert!(specialize(&mut module, &options).is_err());
+
+        let bytes = build(false);
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        let report = specialize(&mut module, &options).unwrap();
+        assert_eq!(report.folded_branches, 1);
+        assert_eq!(report.removed_funcs, 1);
+        assert_eq!(module.exports.len(), 1);
+        let global = &module.globals[Global::new(0)];
+        assert_eq!((global.value, global.mutable), (Some(0), false));
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+    }
+
+    #[test]
+    fn v128_values() {
+        let bytes = wat::parse_str(
+            r#"(module
+                (import "env" "mask" (func $mask (result v128)))
+                (import "env" "bias" (global v128))
+                (global (export "scale") (mut v128) (global.get 0))
+                (func (export "run") (result v128)
+                  call $mask))"#,
+        )
+        .unwrap();
+        // Specialize with the value 7 for an import, or for an export
+        // if `module` is empty.
+        let fix = |module: &str, name: &str| {
+            let mut options = SpecializeOptions::default();
+            let value = ConstVal::V128(7);
+            if module.is_empty() {
+                options.defines.insert(name.to_owned(), value);
+            } else {
+                let key = (module.to_owned(), name.to_owned());
+                options.imports.insert(key, vec![value]);
+            }
+            let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+            specialize(&mut module, &options).map(|_| module)
+        };
+
+        // A function's v128 results fold to constants.
+        let module = fix("env", "mask").unwrap();
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+        module.expand_all_funcs().unwrap();
+        let run = module.funcs.iter().last().unwrap();
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(&module, run, &[]).ok().unwrap();
+        assert_eq!(&result[..], &[ConstVal::V128(7)]);
+
+        // Global values are at most 64 bits, so v128 globals cannot
+        // be fixed or defined.
+        let err = fix("env", "bias").unwrap_err();
+        assert!(err.to_string().contains("v128"), "{}", err);
+        let err = fix("", "scale").unwrap_err();
+        assert!(err.to_string().contains("v128"), "{}", err);
+    }
+}
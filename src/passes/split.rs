@@ -0,0 +1,364 @@
+//! Splitting a module into a primary and a secondary module.
+//!
+//! Large applications often run only a fraction of their code at
+//! startup. `split()` moves the bodies of all functions not selected
+//! to stay into a secondary module that can be fetched and
+//! instantiated later. In the primary module, each moved function is
+//! replaced by a stub of the same signature that calls it through a
+//! new table, exported as `SPLIT_TABLE`, so that every reference to
+//! the function (calls, table elements, exports) keeps working. The
+//! secondary module imports that table and fills it with the moved
+//! functions, and imports everything else its functions use from the
+//! primary module, which exports it if it does not already. Until the
+//! secondary module is instantiated, calling a moved function traps.
+//!
+//! The start function always stays in the primary module.
+
+use crate::entity::EntityRef;
+use crate::ir::{
+    Export, ExportKind, FuncDecl, FunctionBody, FunctionBuilder, GlobalData, Import, ImportKind,
+    MemoryData, Module, TableData, Type, ValueDef,
+};
+use crate::passes::overrides::FuncMatcher;
+use crate::passes::remap::EntityMap;
+use crate::passes::signatures;
+use crate::{Func, Global, Memory, Operator, Table};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+/// The name under which the primary module exports the table of
+/// moved functions.
+pub const SPLIT_TABLE: &str = "__split_table";
+
+/// Options for splitting a module.
+#[derive(Clone, Debug)]
+pub struct SplitOptions {
+    /// The functions to keep in the primary module. All others with
+    /// bodies move to the secondary module.
+    pub keep: Vec<FuncMatcher>,
+    /// The module name the secondary module imports the primary's
+    /// exports from.
+    pub primary_name: String,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        SplitOptions {
+            keep: vec![],
+            primary_name: "primary".to_owned(),
+        }
+    }
+}
+
+/// The seams between the primary and secondary modules.
+#[derive(Clone, Debug, Default)]
+pub struct SplitManifest {
+    /// The moved functions: their slot in `SPLIT_TABLE`, their index
+    /// in the original (and primary) module, and their name.
+    pub moved: Vec<(u32, Func, String)>,
+    /// The exports added to the primary module for the secondary's
+    /// use.
+    pub exports: Vec<(String, ExportKind)>,
+    /// The primary module's exports that the secondary imports.
+    pub imports: Vec<String>,
+}
+
+impl std::fmt::Display for SplitManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (slot, func, name) in &self.moved {
+            writeln!(f, "moved {} {} {}", slot, func, name)?;
+        }
+        for (name, kind) in &self.exports {
+            writeln!(f, "export {} {}", name, kind)?;
+        }
+        for name in &self.imports {
+            writeln!(f, "import {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entities of the primary module that moved functions use.
+#[derive(Default)]
+struct Uses {
+    funcs: BTreeSet<Func>,
+    tables: BTreeSet<Table>,
+    globals: BTreeSet<Global>,
+    memories: BTreeSet<Memory>,
+}
+
+impl Uses {
+    fn scan(&mut self, body: &FunctionBody, moved: &HashSet<Func>) {
+        for value in body.values.values() {
+            let mut op = match value {
+                ValueDef::Operator(op, ..) => *op,
+                _ => continue,
+            };
+            op.update_memory_arg(|memory| {
+                self.memories.insert(memory.memory);
+            });
+            match op {
+                Operator::Call { function_index }
+                | Operator::RefFunc {
+                    func_index: function_index,
+                } if !moved.contains(&function_index) => {
+                    self.funcs.insert(function_index);
+                }
+                Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                    self.globals.insert(global_index);
+                }
+                Operator::CallIndirect { table_index, .. }
+                | Operator::TableGet { table_index }
+                | Operator::TableSet { table_index }
+                | Operator::TableGrow { table_index }
+                | Operator::TableSize { table_index } => {
+                    self.tables.insert(table_index);
+                }
+                Operator::MemorySize { mem }
+                | Operator::MemoryGrow { mem }
+                | Operator::MemoryFill { mem } => {
+                    self.memories.insert(mem);
+                }
+                Operator::MemoryCopy { dst_mem, src_mem } => {
+                    self.memories.insert(dst_mem);
+                    self.memories.insert(src_mem);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The name under which `primary` exports `kind`, adding an export
+/// if there is none.
+fn seam_export(primary: &mut Module, kind: ExportKind, manifest: &mut SplitManifest) -> String {
+    if let Some(export) = primary.exports.iter().find(|export| export.kind == kind) {
+        return export.name.clone();
+    }
+    let name = format!("__split_{}", kind);
+    primary.exports.push(Export {
+        name: name.clone(),
+        kind: kind.clone(),
+    });
+    manifest.exports.push((name.clone(), kind));
+    name
+}
+
+/// Split `module` into a primary and a secondary module, as
+/// described in the module documentation.
+pub fn split(
+    module: &Module,
+    options: &SplitOptions,
+) -> Result<(Module<'static>, Module<'static>, SplitManifest)> {
+    let mut primary = module.clone();
+    primary.expand_all_funcs()?;
+    let mut primary = primary.without_orig_bytes();
+    let moved = primary
+        .funcs
+        .entries()
+        .filter(|&(func, decl)| {
+            matches!(decl, FuncDecl::Body(..))
+                && primary.start_func != Some(func)
+                && !options.keep.iter().any(|m| m.matches(func, decl.name()))
+        })
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    if moved.is_empty() {
+        bail!("No functions to move to the secondary module");
+    }
+    let moved_set = moved.iter().copied().collect::<HashSet<_>>();
+
+    // Replace the moved functions with stubs that call through the
+    // new table.
+    let table = primary.tables.push(TableData {
+        ty: Type::FuncRef,
+        initial: moved.len() as u64,
+        max: Some(moved.len() as u64),
+        func_elements: Some(vec![]),
+    });
+    let mut manifest = SplitManifest::default();
+    let mut uses = Uses::default();
+    let mut bodies = vec![];
+    for (slot, &func) in moved.iter().enumerate() {
+        let sig = primary.funcs[func].sig();
+        let name = primary.funcs[func].name().to_owned();
+        let rets = primary.signatures[sig].returns.clone();
+        let mut stub = FunctionBody::new(&primary, sig);
+        let mut builder = FunctionBuilder::new(&mut stub);
+        let params = builder.params().to_vec();
+        let slot_value = builder.op(Operator::I32Const { value: slot as u32 }, &[], &[Type::I32]);
+        let results = builder.call_indirect(sig, table, slot_value, &params, &rets);
+        builder.ret(&results);
+        let body = match std::mem::replace(
+            &mut primary.funcs[func],
+            FuncDecl::Body(sig, name.clone(), stub.into()),
+        ) {
+            FuncDecl::Body(_, _, body) => body,
+            _ => unreachable!(),
+        };
+        uses.scan(&body, &moved_set);
+        bodies.push((func, sig, name.clone(), body));
+        manifest.moved.push((slot as u32, func, name));
+    }
+    primary.exports.push(Export {
+        name: SPLIT_TABLE.to_owned(),
+        kind: ExportKind::Table(table),
+    });
+
+    // The secondary module imports what its functions use.
+    let mut secondary = Module::empty();
+    secondary.signatures = primary.signatures.clone();
+    secondary.debug = primary.debug.clone();
+    let mut map = EntityMap::default();
+    let mut imported = vec![];
+    let mut import = |secondary: &mut Module, name: String, kind: ImportKind| {
+        imported.push(name.clone());
+        secondary.imports.push(Import {
+            module: options.primary_name.clone(),
+            name,
+            kind,
+        });
+    };
+    for &func in &uses.funcs {
+        let name = seam_export(&mut primary, ExportKind::Func(func), &mut manifest);
+        let decl = &primary.funcs[func];
+        let new = secondary
+            .funcs
+            .push(FuncDecl::Import(decl.sig(), decl.name().to_owned()));
+        map.funcs[func] = new;
+        import(&mut secondary, name, ImportKind::Func(new));
+    }
+    for &t in uses.tables.iter().chain(std::iter::once(&table)) {
+        let name = match t == table {
+            true => SPLIT_TABLE.to_owned(),
+            false => seam_export(&mut primary, ExportKind::Table(t), &mut manifest),
+        };
+        let new = secondary.tables.push(TableData {
+            func_elements: Some(vec![]),
+            ..primary.tables[t].clone()
+        });
+        map.tables[t] = new;
+        import(&mut secondary, name, ImportKind::Table(new));
+    }
+    for &global in &uses.globals {
+        let name = seam_export(&mut primary, ExportKind::Global(global), &mut manifest);
+        let new = secondary.globals.push(GlobalData {
+            init: None,
+            ..primary.globals[global].clone()
+        });
+        map.globals[global] = new;
+        import(&mut secondary, name, ImportKind::Global(new));
+    }
+    for &memory in &uses.memories {
+        let name = seam_export(&mut primary, ExportKind::Memory(memory), &mut manifest);
+        let new = secondary.memories.push(MemoryData {
+            segments: vec![],
+            ..primary.memories[memory].clone()
+        });
+        map.memories[memory] = new;
+        import(&mut secondary, name, ImportKind::Memory(new));
+    }
+
+    manifest.imports = imported;
+
+    // Then defines the moved functions, and places them in the table.
+    for (i, &func) in moved.iter().enumerate() {
+        map.funcs[func] = Func::new(uses.funcs.len() + i);
+    }
+    for (_, sig, name, mut body) in bodies {
+        map.body(Arc::make_mut(&mut body));
+        secondary.funcs.push(FuncDecl::Body(sig, name, body));
+    }
+    secondary.tables[map.tables[table]].func_elements =
+        Some(moved.iter().map(|&func| map.funcs[func]).collect());
+
+    signatures::minimize(&mut primary);
+    signatures::minimize(&mut secondary);
+    Ok((primary, secondary, manifest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{SignatureData, Terminator};
+    use crate::MemoryArg;
+
+    #[test]
+    fn moved_functions_are_called_through_table() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        let memory = module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![],
+        });
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(16),
+            mutable: false,
+            init: None,
+        });
+
+        // `cold` loads from the address in `global`.
+        let mut body = FunctionBody::new(&module, sig);
+        let mut builder = FunctionBuilder::new(&mut body);
+        let addr = builder.op(
+            Operator::GlobalGet {
+                global_index: global,
+            },
+            &[],
+            &[Type::I32],
+        );
+        let memory = MemoryArg {
+            align: 2,
+            offset: 0,
+            memory,
+        };
+        let value = builder.op(Operator::I32Load { memory }, &[addr], &[Type::I32]);
+        builder.ret(&[value]);
+        let cold = module
+            .funcs
+            .push(FuncDecl::Body(sig, "cold".to_owned(), body.into()));
+
+        let mut body = FunctionBody::new(&module, sig);
+        let mut builder = FunctionBuilder::new(&mut body);
+        let value = builder.call(cold, &[], &[Type::I32]);
+        builder.terminate(Terminator::Return { values: value });
+        let main = module
+            .funcs
+            .push(FuncDecl::Body(sig, "main".to_owned(), body.into()));
+        module.exports.push(Export {
+            name: "main".to_owned(),
+            kind: ExportKind::Func(main),
+        });
+
+        let options = SplitOptions {
+            keep: vec![FuncMatcher::Name("main".to_owned())],
+            ..SplitOptions::default()
+        };
+        let (primary, secondary, manifest) = split(&module, &options).unwrap();
+        assert_eq!(manifest.moved, vec![(0, cold, "cold".to_owned())]);
+        assert_eq!(
+            manifest.imports,
+            vec!["__split_table", "__split_global0", "__split_memory0"]
+        );
+        assert_eq!(secondary.funcs.len(), 1);
+        assert_eq!(
+            secondary.tables[Table::new(0)].func_elements,
+            Some(vec![Func::new(0)])
+        );
+        let stub = primary.funcs[cold].body().unwrap();
+        assert!(stub
+            .values
+            .values()
+            .any(|def| matches!(def, ValueDef::Operator(Operator::CallIndirect { .. }, ..))));
+        for module in [&primary, &secondary] {
+            let bytes = module.to_wasm_bytes().unwrap();
+            wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        }
+    }
+}
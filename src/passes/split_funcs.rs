@@ -0,0 +1,465 @@
+//! Long-function splitting: keeping emitted functions within the
+//! limits that engines place on a single function.
+//!
+//! Engines reject functions whose body or locals exceed fixed limits
+//! (V8, for one, allows at most 7,654,321 bytes of body and 50,000
+//! locals). Generated code, and code after aggressive inlining, can
+//! exceed them. This pass measures each function as the backend would
+//! emit it, after localification, and while it is over a limit, moves
+//! a region of it into a new continuation function:
+//!
+//! - The region is a dominator subtree whose blocks branch only to
+//!   each other, so that once control enters it, it never leaves
+//!   except by returning. Its root is the block that is entered.
+//! - The continuation takes the root's blockparams, followed by every
+//!   value the region uses but does not define (its live state), and
+//!   returns what the function returns.
+//! - In the original function, now the driver, branches to the root
+//!   go instead to a new block that calls the continuation and
+//!   returns its results.
+//!
+//! The region chosen is the one closest to half of the function's
+//! instructions, so that repeated splitting converges quickly. Live
+//! state is threaded through parameters only; a region that would
+//! need more than `max_params` of them is not chosen. A function with
+//! no region to split is left as it is, with a warning.
+//!
+//! Only function bodies already expanded to IR are considered.
+
+use crate::backend::WasmFuncBackend;
+use crate::cfg::CFGInfo;
+use crate::diagnostics::Severity;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{
+    Block, BlockOrigin, BlockTarget, Func, FuncDecl, FunctionBody, Module, Terminator, Type, Value,
+    ValueDef,
+};
+use crate::passes::signatures;
+use crate::{BackendOptions, Operator};
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+/// Options for long-function splitting.
+#[derive(Clone, Debug)]
+pub struct SplitFuncOptions {
+    /// The largest body, in bytes, to leave whole.
+    pub max_body_size: usize,
+    /// The most locals, including parameters, to leave whole.
+    pub max_locals: usize,
+    /// The most parameters a continuation may take.
+    pub max_params: usize,
+    /// The options with which the module will be compiled, with which
+    /// functions are measured.
+    pub backend: BackendOptions,
+}
+
+impl std::default::Default for SplitFuncOptions {
+    fn default() -> Self {
+        SplitFuncOptions {
+            max_body_size: 7_654_321,
+            max_locals: 50_000,
+            max_params: 1000,
+            backend: BackendOptions::default(),
+        }
+    }
+}
+
+/// Split every function over the limits in `options`, as described in
+/// the module documentation. Returns the continuations created.
+pub fn run(module: &mut Module, options: &SplitFuncOptions) -> Result<Vec<Func>> {
+    let mut created = vec![];
+    let mut worklist = module
+        .funcs
+        .entries()
+        .filter(|(_, decl)| matches!(decl, FuncDecl::Body(..)))
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    worklist.reverse();
+    while let Some(func) = worklist.pop() {
+        let body = module.funcs[func].body().unwrap();
+        if !over_limits(body, options)? {
+            continue;
+        }
+        let Some(region) = choose_region(body, options.max_params) else {
+            options.backend.diagnostics.report(
+                Severity::Warning,
+                "split_funcs",
+                Some(func),
+                "function is over the size limits but has no region to split".to_owned(),
+            );
+            continue;
+        };
+        let cont = split(module, func, &region);
+        log::debug!(
+            "split_funcs: moved {} blocks from {} at {} into {}",
+            region.blocks.len(),
+            func,
+            region.root,
+            cont
+        );
+        created.push(cont);
+        // Either half may still be too large.
+        worklist.push(cont);
+        worklist.push(func);
+    }
+    Ok(created)
+}
+
+/// Whether `body` as emitted exceeds a limit in `options`.
+fn over_limits(body: &FunctionBody, options: &SplitFuncOptions) -> Result<bool> {
+    let frame = WasmFuncBackend::frame_info_with_options(body, &options.backend)?;
+    if frame.locals > options.max_locals {
+        return Ok(true);
+    }
+    let size = body
+        .compile_with_options(&options.backend)?
+        .into_raw_body()
+        .len();
+    Ok(size > options.max_body_size)
+}
+
+/// A region to move into a continuation.
+struct Region {
+    root: Block,
+    /// The blocks of the region, in reverse postorder.
+    blocks: Vec<Block>,
+    /// The values the region uses but does not define.
+    live_in: Vec<Value>,
+}
+
+/// Choose the closed dominator subtree closest to half of `body`'s
+/// instructions whose continuation needs at most `max_params`
+/// parameters.
+fn choose_region(body: &FunctionBody, max_params: usize) -> Option<Region> {
+    let cfg = CFGInfo::new(body);
+
+    // A subtree is closed if no edge leaves it: for each edge `u ->
+    // v`, the subtrees of `u` and its dominators, up to one that also
+    // dominates `v`, are not.
+    let mut open: PerEntity<Block, bool> = PerEntity::default();
+    for &u in cfg.rpo.values() {
+        for &v in &body.blocks[u].succs {
+            let mut b = u;
+            while b.is_valid() && !cfg.dominates(b, v) {
+                open[b] = true;
+                b = cfg.domtree[b];
+            }
+        }
+    }
+
+    let mut size: PerEntity<Block, usize> = PerEntity::default();
+    for &b in cfg.rpo.values().rev() {
+        size[b] += body.blocks[b].insts.len() + 1;
+        let idom = cfg.domtree[b];
+        if idom.is_valid() {
+            let s = size[b];
+            size[idom] += s;
+        }
+    }
+    let total = size[body.entry];
+    let half = total / 2;
+
+    // Moving out everything but a lone branch to the region, or just
+    // the call to an earlier continuation, would only rename the code.
+    let mut candidates = cfg
+        .rpo
+        .values()
+        .copied()
+        .filter(|&b| {
+            b != body.entry
+                && !open[b]
+                && total - size[b] > 1
+                && body.blocks[b].origin != BlockOrigin::SyntheticFor("split_funcs")
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|&b| (size[b].abs_diff(half), cfg.rpo_pos[b]));
+    candidates.into_iter().find_map(|root| {
+        let region = region(body, &cfg, root);
+        (body.blocks[root].params.len() + region.live_in.len() <= max_params).then_some(region)
+    })
+}
+
+/// The region rooted at `root`, with its live state.
+fn region(body: &FunctionBody, cfg: &CFGInfo, root: Block) -> Region {
+    let mut blocks = vec![];
+    let mut stack = vec![root];
+    while let Some(b) = stack.pop() {
+        blocks.push(b);
+        stack.extend(cfg.dom_children(b));
+    }
+    blocks.sort_by_key(|&b| cfg.rpo_pos[b]);
+    let inside = blocks.iter().copied().collect::<BTreeSet<_>>();
+
+    let mut live_in = BTreeSet::new();
+    let mut use_value = |value: Value| {
+        let value = body.resolve_alias(value);
+        if !inside.contains(&cfg.def_block[value]) {
+            live_in.insert(value);
+        }
+    };
+    for &b in &blocks {
+        for &inst in &body.blocks[b].insts {
+            if let ValueDef::Operator(_, args, _) = &body.values[inst] {
+                body.arg_pool[*args].iter().for_each(|&arg| use_value(arg));
+            }
+        }
+        body.blocks[b].terminator.visit_uses(&mut use_value);
+    }
+    Region {
+        root,
+        blocks,
+        live_in: live_in.into_iter().collect(),
+    }
+}
+
+/// Move `region` of `func` into a new continuation function, and
+/// return it.
+fn split(module: &mut Module, func: Func, region: &Region) -> Func {
+    let mut driver = module.funcs[func].body().unwrap().clone();
+    let root_params = driver.blocks[region.root]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>();
+    let live_in_tys = region
+        .live_in
+        .iter()
+        .map(|&value| driver.values[value].ty(&driver.type_pool).unwrap())
+        .collect::<Vec<_>>();
+    let params = root_params
+        .iter()
+        .chain(live_in_tys.iter())
+        .copied()
+        .collect::<Vec<Type>>();
+    let rets = driver.rets.clone();
+    let sig = signatures::intern(module, &params, &rets);
+
+    // Build the continuation: its entry branches to a copy of the
+    // root, and its parameters stand in for the live state.
+    let mut cont = FunctionBody::new(module, sig);
+    let cont_params = cont.blocks[cont.entry]
+        .params
+        .iter()
+        .map(|&(_, value)| value)
+        .collect::<Vec<_>>();
+    let mut blocks: PerEntity<Block, Block> = PerEntity::default();
+    let mut values: PerEntity<Value, Value> = PerEntity::default();
+    for (&value, &param) in region.live_in.iter().zip(&cont_params[root_params.len()..]) {
+        values[value] = param;
+    }
+    for &b in &region.blocks {
+        let def = &driver.blocks[b];
+        let new = cont.add_block();
+        cont.blocks[new].origin = def.origin.clone();
+        cont.blocks[new].branch_hint = def.branch_hint;
+        for &(ty, param) in &def.params {
+            values[param] = cont.add_blockparam(new, ty);
+        }
+        for &inst in &def.insts {
+            values[inst] = cont.add_value(ValueDef::None);
+            cont.append_to_block(new, values[inst]);
+        }
+        blocks[b] = new;
+    }
+    let map = |value: Value| values[driver.resolve_alias(value)];
+    for &b in &region.blocks {
+        let new = blocks[b];
+        for &inst in &driver.blocks[b].insts {
+            let def = match &driver.values[inst] {
+                ValueDef::Operator(op, args, tys) => {
+                    let args = driver.arg_pool[*args]
+                        .iter()
+                        .map(|&arg| map(arg))
+                        .collect::<Vec<_>>();
+                    let args = cont.arg_pool.from_iter(args.into_iter());
+                    let tys = cont
+                        .type_pool
+                        .from_iter(driver.type_pool[*tys].iter().copied());
+                    ValueDef::Operator(cont.import_op(&driver, *op), args, tys)
+                }
+                ValueDef::PickOutput(of, i, ty) => ValueDef::PickOutput(map(*of), *i, *ty),
+                _ => ValueDef::None,
+            };
+            let value = values[inst];
+            cont.values[value] = def;
+            cont.source_locs[value] = driver.source_locs[inst];
+            cont.wasm_offsets[value] = driver.wasm_offsets[inst];
+        }
+        let mut terminator = driver.blocks[b].terminator.clone();
+        terminator.update_uses(|value| *value = map(*value));
+        terminator.update_targets(|target| target.block = blocks[target.block]);
+        cont.blocks[new].terminator = terminator;
+    }
+    let entry = cont.entry;
+    cont.blocks[entry].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: blocks[region.root],
+            args: cont_params[..root_params.len()].to_vec(),
+        },
+    };
+    cont.recompute_edges();
+
+    let name = format!("{}$cont{}", module.funcs[func].name(), module.funcs.len());
+    let cont_func = module.funcs.push(FuncDecl::Body(sig, name, cont.into()));
+
+    // In the driver, enter the continuation instead of the region.
+    let call_block = driver.add_block_with_origin(BlockOrigin::SyntheticFor("split_funcs"));
+    let mut args = root_params
+        .iter()
+        .map(|&ty| driver.add_blockparam(call_block, ty))
+        .collect::<Vec<_>>();
+    args.extend(region.live_in.iter().copied());
+    let call = driver.add_op(
+        call_block,
+        Operator::Call {
+            function_index: cont_func,
+        },
+        &args,
+        &rets,
+    );
+    let results = if rets.len() == 1 {
+        vec![call]
+    } else {
+        let mut picks = vec![];
+        for (i, &ty) in rets.iter().enumerate() {
+            let pick = driver.add_value(ValueDef::PickOutput(call, i as u32, ty));
+            driver.append_to_block(call_block, pick);
+            picks.push(pick);
+        }
+        picks
+    };
+    driver.blocks[call_block].terminator = Terminator::Return { values: results };
+
+    let inside = region.blocks.iter().copied().collect::<BTreeSet<_>>();
+    let reachable = CFGInfo::new(&driver).rpo_pos;
+    for b in driver.blocks.iter() {
+        if inside.contains(&b) || b == call_block {
+            continue;
+        }
+        if reachable[b].is_none() {
+            // Unreachable code may still refer to the region.
+            driver.blocks[b].insts.clear();
+            driver.blocks[b].terminator = Terminator::Unreachable;
+            continue;
+        }
+        driver.blocks[b].terminator.update_targets(|target| {
+            if target.block == region.root {
+                target.block = call_block;
+            }
+        });
+    }
+    for &b in &region.blocks {
+        let def = std::mem::take(&mut driver.blocks[b]);
+        for value in def.params.iter().map(|&(_, value)| value).chain(def.insts) {
+            driver.values[value] = ValueDef::None;
+        }
+        driver.blocks[b].origin = def.origin;
+        driver.blocks[b].terminator = Terminator::Unreachable;
+    }
+    driver.recompute_edges();
+    *module.funcs[func].body_mut().unwrap() = driver;
+    cont_func
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interp::{ConstVal, InterpContext};
+    use crate::ir::SignatureData;
+
+    #[test]
+    fn split_preserves_behavior() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        // entry: a = x + 1; br_if x, big(a), small
+        // big(p): loop of p += a until p > 100; return p * a
+        // small: return a
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let (big, small, head, exit) = (
+            body.add_block(),
+            body.add_block(),
+            body.add_block(),
+            body.add_block(),
+        );
+        let one = body.add_op(entry, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+        let a = body.add_op(entry, Operator::I32Add, &[x, one], &[Type::I32]);
+        body.set_terminator(
+            entry,
+            Terminator::CondBr {
+                cond: x,
+                if_true: BlockTarget {
+                    block: big,
+                    args: vec![a],
+                },
+                if_false: BlockTarget {
+                    block: small,
+                    args: vec![],
+                },
+            },
+        );
+        let p = body.add_blockparam(big, Type::I32);
+        body.set_terminator(
+            big,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: head,
+                    args: vec![p],
+                },
+            },
+        );
+        let q = body.add_blockparam(head, Type::I32);
+        let next = body.add_op(head, Operator::I32Add, &[q, a], &[Type::I32]);
+        let limit = body.add_op(head, Operator::I32Const { value: 100 }, &[], &[Type::I32]);
+        let done = body.add_op(head, Operator::I32GtS, &[next, limit], &[Type::I32]);
+        body.set_terminator(
+            head,
+            Terminator::CondBr {
+                cond: done,
+                if_true: BlockTarget {
+                    block: exit,
+                    args: vec![],
+                },
+                if_false: BlockTarget {
+                    block: head,
+                    args: vec![next],
+                },
+            },
+        );
+        let product = body.add_op(exit, Operator::I32Mul, &[next, a], &[Type::I32]);
+        body.set_terminator(
+            exit,
+            Terminator::Return {
+                values: vec![product],
+            },
+        );
+        body.set_terminator(small, Terminator::Return { values: vec![a] });
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let call = |module: &Module, x: i32| {
+            let mut ctx = InterpContext::new(module).unwrap();
+            ctx.call(module, func, &[ConstVal::I32(x as u32)])
+                .ok()
+                .unwrap()
+        };
+        let expected = [0, 7, 200].map(|x| call(&module, x));
+
+        let options = SplitFuncOptions {
+            max_body_size: 20,
+            ..SplitFuncOptions::default()
+        };
+        let created = run(&mut module, &options).unwrap();
+        assert!(!created.is_empty());
+        for &func in std::iter::once(&func).chain(&created) {
+            module.funcs[func].body().unwrap().validate().unwrap();
+        }
+        assert_eq!([0, 7, 200].map(|x| call(&module, x)), expected);
+        let _ = module.to_wasm_bytes().unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Static stack-usage bounds.
+//!
+//! Embedders that must keep guests within an engine's stack limit
+//! can check ahead of time how deep a module's calls can go and how
+//! large each frame is. Frame sizes come from the backend (see
+//! `FrameInfo`); call depth is bounded over the direct call graph
+//! wherever it is acyclic.
+
+use crate::backend::frame::FrameInfo;
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, Module, ValueDef};
+use crate::passes::effects::sccs;
+use crate::{Func, Operator};
+
+/// Stack-usage facts for every function in a module.
+#[derive(Clone, Debug, Default)]
+pub struct StackUsage {
+    /// The frame of each function with an expanded body.
+    frames: Vec<Option<FrameInfo>>,
+    /// The maximum number of frames on the stack during a call to
+    /// each function, counting its own, or `None` if unbounded.
+    depths: Vec<Option<usize>>,
+}
+
+impl StackUsage {
+    /// Analyze all functions in `module`. Functions whose bodies are
+    /// not expanded have no frame information. Recursion and
+    /// indirect calls make call depth unbounded; a call to an import
+    /// counts as one frame.
+    pub fn compute(module: &Module) -> StackUsage {
+        let n = module.funcs.len();
+        let mut frames = vec![None; n];
+        let mut callees: Vec<Vec<usize>> = vec![vec![]; n];
+        let mut indirect = vec![false; n];
+        for (func, decl) in module.funcs.entries() {
+            let body = match decl {
+                FuncDecl::Body(_, _, body) => body,
+                _ => continue,
+            };
+            frames[func.index()] = body.frame_info().ok();
+            for block in body.blocks.values() {
+                for &inst in &block.insts {
+                    match &body.values[inst] {
+                        ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                            callees[func.index()].push(function_index.index())
+                        }
+                        ValueDef::Operator(
+                            Operator::CallIndirect { .. } | Operator::CallRef { .. },
+                            ..,
+                        ) => indirect[func.index()] = true,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let mut depths = vec![None; n];
+        for scc in sccs(&callees) {
+            if scc.len() > 1 {
+                continue;
+            }
+            let f = scc[0];
+            if indirect[f] || callees[f].contains(&f) {
+                continue;
+            }
+            let mut depth = Some(1);
+            for &callee in &callees[f] {
+                let callee_depth = match module.funcs.get(Func::new(callee)) {
+                    Some(FuncDecl::Import(..)) => Some(1),
+                    Some(_) => depths[callee],
+                    None => None,
+                };
+                depth = match (depth, callee_depth) {
+                    (Some(depth), Some(callee_depth)) => Some(depth.max(1 + callee_depth)),
+                    _ => None,
+                };
+            }
+            // A lazy or compiled body's calls are not known.
+            if !matches!(
+                module.funcs[Func::new(f)],
+                FuncDecl::Body(..) | FuncDecl::Import(..)
+            ) {
+                depth = None;
+            }
+            depths[f] = depth;
+        }
+
+        StackUsage { frames, depths }
+    }
+
+    /// The frame of `func`, if its body is expanded.
+    pub fn frame(&self, func: Func) -> Option<FrameInfo> {
+        self.frames.get(func.index()).copied().flatten()
+    }
+
+    /// An upper bound on the number of frames on the stack while
+    /// `func` runs, including its own, or `None` if there is none.
+    pub fn max_call_depth(&self, func: Func) -> Option<usize> {
+        self.depths.get(func.index()).copied().flatten()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, SignatureData, Terminator, Type};
+
+    #[test]
+    fn depth_and_frames() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::I32],
+            returns: vec![Type::I32],
+        });
+        let funcs = (0..4)
+            .map(|_| module.funcs.push(FuncDecl::None))
+            .collect::<Vec<_>>();
+        module.funcs[funcs[3]] = FuncDecl::Import(sig, "imp".to_owned());
+        // f0 calls f1, which calls the import; f2 calls itself.
+        for (i, callee) in [(0, funcs[1]), (1, funcs[3]), (2, funcs[2])] {
+            let mut body = FunctionBody::new(&module, sig);
+            let entry = body.entry;
+            let x = body.blocks[entry].params[0].1;
+            let y = body.blocks[entry].params[1].1;
+            let a = body.add_op(entry, Operator::I32Add, &[x, y], &[Type::I32]);
+            let b = body.add_op(entry, Operator::I32Mul, &[x, y], &[Type::I32]);
+            let c = body.add_op(entry, Operator::I32Sub, &[a, b], &[Type::I32]);
+            let call = Operator::Call {
+                function_index: callee,
+            };
+            let r = body.add_op(entry, call, &[c, c], &[Type::I32]);
+            body.set_terminator(entry, Terminator::Return { values: vec![r] });
+            module.funcs[funcs[i]] = FuncDecl::Body(sig, format!("f{}", i), body.into());
+        }
+
+        let usage = StackUsage::compute(&module);
+        assert_eq!(usage.max_call_depth(funcs[0]), Some(3));
+        assert_eq!(usage.max_call_depth(funcs[1]), Some(2));
+        assert_eq!(usage.max_call_depth(funcs[2]), None);
+        assert_eq!(usage.frame(funcs[3]), None);
+        // `(x + y) - (x * y)` is one tree: x and y are pushed for the
+        // mul on top of the add's result.
+        let frame = usage.frame(funcs[0]).unwrap();
+        assert_eq!(frame.max_stack, 3);
+        assert!(frame.locals >= 2);
+        assert_eq!(frame.locals_bytes, 4 * frame.locals);
+    }
+}
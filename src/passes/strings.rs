@@ -0,0 +1,282 @@
+//! String-literal extraction: finding the strings in a module's data
+//! segments, and the functions that refer to them.
+//!
+//! Strings are recognized by their encoding alone: a run of printable
+//! UTF-8 ending in a NUL byte (a C string), or a little-endian `u32`
+//! length at a four-byte-aligned address followed by that many bytes
+//! of printable UTF-8 (a length-prefixed string). A function refers to
+//! a string if its IR has a constant address inside the string: an
+//! `i32.const` operand, or a load or store from a constant address
+//! plus its immediate offset. Pointers computed at runtime, such as
+//! those relative to a `__memory_base` in position-independent code,
+//! are not seen.
+
+use super::data_segments::flatten;
+use crate::ir::{Func, FuncDecl, Memory, Module, ValueDef};
+use crate::Operator;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::ops::Range;
+
+/// Options for string extraction.
+#[derive(Clone, Debug)]
+pub struct StringOptions {
+    /// The minimum length of a string, in bytes.
+    pub min_len: usize,
+    /// Look for length-prefixed strings as well as C strings.
+    pub length_prefixed: bool,
+}
+
+impl std::default::Default for StringOptions {
+    fn default() -> Self {
+        StringOptions {
+            min_len: 4,
+            length_prefixed: true,
+        }
+    }
+}
+
+/// How a string is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StringKind {
+    /// Terminated by a NUL byte.
+    CStr,
+    /// Preceded by a little-endian `u32` length.
+    LengthPrefixed,
+}
+
+/// A string found in a data segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StringLiteral {
+    pub memory: Memory,
+    /// The bytes of the string in memory, including its length prefix
+    /// or NUL terminator.
+    pub range: Range<usize>,
+    pub kind: StringKind,
+    pub text: String,
+    /// Functions whose IR has a constant address inside the string.
+    pub funcs: BTreeSet<Func>,
+    /// Addresses of aligned `u32` words in the data segments whose
+    /// value is an address inside the string, such as the entries of
+    /// a table of strings.
+    pub data_refs: Vec<usize>,
+}
+
+/// The strings of a module, in order of memory and address.
+#[derive(Clone, Debug, Default)]
+pub struct StringReport {
+    pub strings: Vec<StringLiteral>,
+    /// Whether every function body was expanded to IR, so that
+    /// `StringLiteral::funcs` is complete. A string with neither
+    /// function nor data references is then likely dead, unless the
+    /// code computes pointers at runtime.
+    pub complete: bool,
+}
+
+fn is_printable(byte: u8) -> bool {
+    matches!(byte, b'\t' | b'\n' | b'\r' | 0x20..=0x7e | 0x80..)
+}
+
+/// Find the strings in one run of initialized memory at `start`.
+fn scan_run(
+    start: usize,
+    data: &[u8],
+    options: &StringOptions,
+    out: &mut Vec<(Range<usize>, StringKind, String)>,
+) {
+    let min_len = std::cmp::max(options.min_len, 1);
+    let mut i = 0;
+    while i < data.len() {
+        if options.length_prefixed && (start + i).is_multiple_of(4) && i + 4 <= data.len() {
+            let len = u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as usize;
+            let text = &data[i + 4..];
+            if len >= min_len && len <= text.len() && text[..len].iter().all(|&b| is_printable(b)) {
+                if let Ok(text) = std::str::from_utf8(&text[..len]) {
+                    let range = (start + i)..(start + i + 4 + len);
+                    out.push((range, StringKind::LengthPrefixed, text.to_owned()));
+                    i += 4 + len;
+                    continue;
+                }
+            }
+        }
+        if !is_printable(data[i]) {
+            i += 1;
+            continue;
+        }
+        let end = data[i..]
+            .iter()
+            .position(|&b| !is_printable(b))
+            .map_or(data.len(), |len| i + len);
+        if end < data.len() && data[end] == 0 && end - i >= min_len {
+            if let Ok(text) = std::str::from_utf8(&data[i..end]) {
+                out.push((
+                    (start + i)..(start + end + 1),
+                    StringKind::CStr,
+                    text.to_owned(),
+                ));
+            }
+        }
+        i = end;
+    }
+}
+
+/// Find the strings in every memory's data segments, and what refers
+/// to them.
+pub fn scan(module: &Module, options: &StringOptions) -> StringReport {
+    let mut report = StringReport {
+        strings: vec![],
+        complete: module
+            .funcs
+            .values()
+            .all(|decl| matches!(decl, FuncDecl::Body(..) | FuncDecl::Import(..))),
+    };
+    for (memory, mem_data) in module.memories.entries() {
+        let runs = flatten(&mem_data.segments);
+        let mut found = vec![];
+        for (start, data) in &runs {
+            scan_run(*start, data, options, &mut found);
+        }
+        let first = report.strings.len();
+        report
+            .strings
+            .extend(found.into_iter().map(|(range, kind, text)| StringLiteral {
+                memory,
+                range,
+                kind,
+                text,
+                funcs: BTreeSet::new(),
+                data_refs: vec![],
+            }));
+        let strings = &mut report.strings[first..];
+
+        for (start, data) in &runs {
+            let first_word = (start + 3) & !3;
+            for addr in (first_word..start + data.len()).step_by(4) {
+                let at = addr - start;
+                let Some(word) = data.get(at..at + 4) else {
+                    break;
+                };
+                let value = u32::from_le_bytes(word.try_into().unwrap()) as usize;
+                if let Some(string) = find(strings, value) {
+                    string.data_refs.push(addr);
+                }
+            }
+        }
+
+        for (func, decl) in module.funcs.entries() {
+            let Some(body) = decl.body() else {
+                continue;
+            };
+            let const_addr = |value| match &body.values[body.resolve_alias(value)] {
+                ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value as usize),
+                _ => None,
+            };
+            for block in body.blocks.values() {
+                for &inst in &block.insts {
+                    let ValueDef::Operator(op, args, _) = &body.values[inst] else {
+                        continue;
+                    };
+                    let addrs: Vec<usize> = match op {
+                        Operator::I32Const { value } => vec![*value as usize],
+                        _ => match op.memory_access() {
+                            Some((memarg, _)) if memarg.memory == memory => body.arg_pool[*args]
+                                .first()
+                                .and_then(|&base| const_addr(base))
+                                .map(|base| base + memarg.offset as usize)
+                                .into_iter()
+                                .collect(),
+                            _ => vec![],
+                        },
+                    };
+                    for addr in addrs {
+                        if let Some(string) = find(strings, addr) {
+                            string.funcs.insert(func);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    report
+}
+
+/// The string, among `strings` sorted by address, that contains `addr`.
+fn find(strings: &mut [StringLiteral], addr: usize) -> Option<&mut StringLiteral> {
+    let i = strings.partition_point(|s| s.range.start <= addr);
+    strings[..i].last_mut().filter(|s| addr < s.range.end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, MemoryData, MemorySegment, SignatureData, Terminator, Type};
+    use crate::MemoryArg;
+
+    #[test]
+    fn strings_and_references() {
+        let mut module = Module::empty();
+        let mut data = vec![];
+        data.extend_from_slice(b"hello, world\0");
+        data.extend_from_slice(&[0xff, 0x01, 0]);
+        // Aligned at 0x110: a length-prefixed string, then a pointer
+        // to the C string.
+        data.resize(0x10, 0);
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(b"abcde");
+        data.resize(0x1c, 0);
+        data.extend_from_slice(&0x104u32.to_le_bytes());
+        let memory = module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: None,
+            segments: vec![MemorySegment {
+                offset: 0x100,
+                data,
+            }],
+        });
+
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let base = body.add_op(
+            entry,
+            Operator::I32Const { value: 0x100 },
+            &[],
+            &[Type::I32],
+        );
+        let memory_arg = MemoryArg::new(memory, 0, 0x12).unwrap();
+        let load = body.add_op(
+            entry,
+            Operator::I32Load8U { memory: memory_arg },
+            &[base],
+            &[Type::I32],
+        );
+        body.set_terminator(entry, Terminator::Return { values: vec![load] });
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        let report = scan(&module, &StringOptions::default());
+        assert!(report.complete);
+        let found = report
+            .strings
+            .iter()
+            .map(|s| (s.range.clone(), s.kind, &s.text[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (0x100..0x10d, StringKind::CStr, "hello, world"),
+                (0x110..0x119, StringKind::LengthPrefixed, "abcde"),
+            ]
+        );
+        // The `i32.const` points at the C string; the load, with its
+        // offset, into the length-prefixed one.
+        assert_eq!(report.strings[0].funcs.iter().collect::<Vec<_>>(), [&func]);
+        assert_eq!(report.strings[1].funcs.iter().collect::<Vec<_>>(), [&func]);
+        assert_eq!(report.strings[0].data_refs, vec![0x11c]);
+        assert!(report.strings[1].data_refs.is_empty());
+    }
+}
@@ -0,0 +1,465 @@
+//! Switch lowering: conversion between `br_table` and compare trees.
+//!
+//! Engines and size-sensitive targets differ in which form of a
+//! multiway branch they prefer: a `br_table` is compact and constant
+//! time for dense case sets, while a few compares are often cheaper
+//! (and better predicted) than an indirect jump for small or sparse
+//! ones. This pass rewrites `Terminator::Select`s into binary-search
+//! compare trees, and chains of equality compares against one value
+//! into `Terminator::Select`s, according to a `SwitchLowering` policy.
+
+use crate::cfg::CFGInfo;
+use crate::ir::{Block, BlockOrigin, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::passes::terminator_stats::TableStats;
+use crate::Operator;
+use std::collections::HashSet;
+
+/// Policy for converting between `br_table`s and compare trees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SwitchLowering {
+    /// Leave multiway branches in the form the frontend produced.
+    #[default]
+    Preserve,
+    /// Lower every `br_table` into a binary tree of compares.
+    Branches,
+    /// Raise every dense enough chain of equality compares into a
+    /// `br_table`.
+    Tables,
+    /// Lower `br_table`s with few distinct targets and raise long,
+    /// dense compare chains.
+    Auto,
+}
+
+impl std::str::FromStr for SwitchLowering {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<SwitchLowering> {
+        match s {
+            "preserve" => Ok(SwitchLowering::Preserve),
+            "branches" => Ok(SwitchLowering::Branches),
+            "tables" => Ok(SwitchLowering::Tables),
+            "auto" => Ok(SwitchLowering::Auto),
+            _ => anyhow::bail!("Unknown switch lowering: {}", s),
+        }
+    }
+}
+
+/// Under `Auto`, `br_table`s with at most this many runs of
+/// consecutive indices sharing a target (counting the default) are
+/// lowered to compares.
+const AUTO_MAX_BRANCH_CLUSTERS: usize = 3;
+/// Under `Auto`, compare chains need at least this many cases to be
+/// raised into a table.
+const AUTO_MIN_TABLE_CASES: usize = 4;
+/// Under `Tables`, compare chains need at least this many cases to be
+/// raised into a table.
+const MIN_TABLE_CASES: usize = 3;
+/// Compare chains are only raised if at least this percentage of the
+/// table's entries are explicit cases.
+const MIN_TABLE_DENSITY_PERCENT: u64 = 40;
+
+pub(crate) fn run(body: &mut FunctionBody, policy: SwitchLowering) {
+    if policy == SwitchLowering::Preserve {
+        return;
+    }
+    let cfg = CFGInfo::new(body);
+    let used_outside = values_used_outside_def_block(body);
+    let mut changed = false;
+    for &block in cfg.rpo.values() {
+        // Each rewrite checks the form of the block's terminator
+        // itself, and neither produces the other's input form.
+        if policy != SwitchLowering::Tables {
+            changed |= lower_table(body, block, policy);
+        }
+        if policy != SwitchLowering::Branches && raise_chain(body, block, policy, &used_outside) {
+            // Later chain detection relies on accurate predecessor
+            // lists.
+            body.recompute_edges();
+            changed = true;
+        }
+    }
+    if changed {
+        body.recompute_edges();
+    }
+}
+
+/// Replace a `Select` terminator with a binary-search tree of
+/// unsigned compares.
+fn lower_table(body: &mut FunctionBody, block: Block, policy: SwitchLowering) -> bool {
+    let (value, targets, default) = match &body.blocks[block].terminator {
+        Terminator::Select {
+            value,
+            targets,
+            default,
+        } => (*value, targets.clone(), default.clone()),
+        _ => return false,
+    };
+    let stats = TableStats::new(block, &targets[..], &default);
+    if policy == SwitchLowering::Auto && stats.clusters > AUTO_MAX_BRANCH_CLUSTERS {
+        return false;
+    }
+
+    // Partition the index space into clusters `(first index, target)`;
+    // the last cluster extends to `u32::MAX`.
+    let mut clusters: Vec<(u32, BlockTarget)> = vec![];
+    let default_cluster = std::iter::once(&default);
+    for (i, target) in targets.iter().chain(default_cluster).enumerate() {
+        match clusters.last() {
+            Some((_, last)) if last == target => {}
+            _ => clusters.push((i as u32, target.clone())),
+        }
+    }
+    debug_assert_eq!(clusters.len(), stats.clusters);
+
+    log::trace!(
+        "switch: lowering table in {} with {} clusters",
+        block,
+        clusters.len()
+    );
+    let terminator = build_tree(body, block, value, &clusters[..]);
+    body.blocks[block].terminator = terminator;
+    true
+}
+
+fn build_tree(
+    body: &mut FunctionBody,
+    block: Block,
+    value: Value,
+    clusters: &[(u32, BlockTarget)],
+) -> Terminator {
+    if clusters.len() == 1 {
+        return Terminator::Br {
+            target: clusters[0].1.clone(),
+        };
+    }
+    let mid = clusters.len() / 2;
+    let pivot = clusters[mid].0;
+    let subtree = |body: &mut FunctionBody, clusters: &[(u32, BlockTarget)]| {
+        if clusters.len() == 1 {
+            return clusters[0].1.clone();
+        }
+        let subblock = body.add_block_with_origin(BlockOrigin::SyntheticFor("switch"));
+        let terminator = build_tree(body, subblock, value, clusters);
+        body.blocks[subblock].terminator = terminator;
+        BlockTarget {
+            block: subblock,
+            args: vec![],
+        }
+    };
+    let if_true = subtree(body, &clusters[..mid]);
+    let if_false = subtree(body, &clusters[mid..]);
+    let pivot = body.add_op(
+        block,
+        Operator::I32Const { value: pivot },
+        &[],
+        &[Type::I32],
+    );
+    let cond = body.add_op(block, Operator::I32LtU, &[value, pivot], &[Type::I32]);
+    body.copy_loc(value, cond);
+    Terminator::CondBr {
+        cond,
+        if_true,
+        if_false,
+    }
+}
+
+/// If `cond` tests an `i32` for equality with a constant, return the
+/// tested value and the constant.
+fn case_of(body: &FunctionBody, cond: Value) -> Option<(Value, u32)> {
+    let const_of = |value: Value| match &body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value),
+        _ => None,
+    };
+    match &body.values[body.resolve_alias(cond)] {
+        ValueDef::Operator(Operator::I32Eqz, args, _) => {
+            Some((body.resolve_alias(body.arg_pool[*args][0]), 0))
+        }
+        ValueDef::Operator(Operator::I32Eq, args, _) => {
+            let (a, b) = (body.arg_pool[*args][0], body.arg_pool[*args][1]);
+            match (const_of(a), const_of(b)) {
+                (_, Some(c)) => Some((body.resolve_alias(a), c)),
+                (Some(c), _) => Some((body.resolve_alias(b), c)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Parse the compare at the end of a block: `(tested value, constant,
+/// target if equal, target otherwise)`.
+fn compare_branch(
+    body: &FunctionBody,
+    block: Block,
+) -> Option<(Value, u32, BlockTarget, BlockTarget)> {
+    match &body.blocks[block].terminator {
+        Terminator::CondBr {
+            cond,
+            if_true,
+            if_false,
+        } => {
+            let (value, c) = case_of(body, *cond)?;
+            Some((value, c, if_true.clone(), if_false.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Can `block` be bypassed by a table that jumps straight to its
+/// targets? It must be entered only from the previous compare, and
+/// contain nothing but pure computations used by its own terminator.
+fn is_chain_link(body: &FunctionBody, block: Block, used_outside: &HashSet<Value>) -> bool {
+    let def = &body.blocks[block];
+    if !def.params.is_empty() || def.preds.len() != 1 {
+        return false;
+    }
+    if def.insts.iter().any(|&inst| {
+        used_outside.contains(&inst)
+            || match &body.values[inst] {
+                ValueDef::Operator(op, ..) => !op.is_pure(),
+                _ => true,
+            }
+    }) {
+        return false;
+    }
+    let mut local_args = false;
+    def.terminator.visit_targets(|target| {
+        for &arg in &target.args {
+            local_args |= body.value_blocks[body.resolve_alias(arg)] == block;
+        }
+    });
+    !local_args
+}
+
+/// Replace a chain of `if (x == c) goto T` compares, starting at
+/// `head`, with a single `Select` on `x`.
+fn raise_chain(
+    body: &mut FunctionBody,
+    head: Block,
+    policy: SwitchLowering,
+    used_outside: &HashSet<Value>,
+) -> bool {
+    let (subject, c, if_true, mut default) = match compare_branch(body, head) {
+        Some(branch) => branch,
+        None => return false,
+    };
+    let mut cases = vec![(c, if_true)];
+    let mut seen: HashSet<u32> = std::iter::once(c).collect();
+    let mut links = vec![];
+    loop {
+        let next = default.block;
+        if next == head || links.contains(&next) || !default.args.is_empty() {
+            break;
+        }
+        if !is_chain_link(body, next, used_outside) {
+            break;
+        }
+        match compare_branch(body, next) {
+            Some((value, c, if_true, if_false)) if value == subject => {
+                // A repeated constant is shadowed by its first test.
+                if seen.insert(c) {
+                    cases.push((c, if_true));
+                }
+                links.push(next);
+                default = if_false;
+            }
+            _ => break,
+        }
+    }
+
+    let min_cases = match policy {
+        SwitchLowering::Auto => AUTO_MIN_TABLE_CASES,
+        _ => MIN_TABLE_CASES,
+    };
+    if cases.len() < min_cases {
+        return false;
+    }
+    let min = cases.iter().map(|&(c, _)| c).min().unwrap();
+    let max = cases.iter().map(|&(c, _)| c).max().unwrap();
+    let span = (max - min) as u64 + 1;
+    if (cases.len() as u64) * 100 < span * MIN_TABLE_DENSITY_PERCENT {
+        return false;
+    }
+
+    log::trace!(
+        "switch: raising {} compares from {} into a table of {}",
+        cases.len(),
+        head,
+        span
+    );
+    let mut targets = vec![default.clone(); span as usize];
+    for (c, target) in cases {
+        targets[(c - min) as usize] = target;
+    }
+    // Values below `min` wrap around to large indices and so take the
+    // default target, as they should.
+    let index = if min == 0 {
+        subject
+    } else {
+        let min = body.add_op(head, Operator::I32Const { value: min }, &[], &[Type::I32]);
+        let index = body.add_op(head, Operator::I32Sub, &[subject, min], &[Type::I32]);
+        if let Terminator::CondBr { cond, .. } = body.blocks[head].terminator {
+            body.copy_loc(cond, index);
+        }
+        index
+    };
+    body.blocks[head].terminator = Terminator::Select {
+        value: index,
+        targets,
+        default,
+    };
+    for link in links {
+        body.blocks[link].insts.clear();
+        body.blocks[link].terminator = Terminator::Unreachable;
+    }
+    true
+}
+
+/// The values that are used in some block other than the one that
+/// defines them.
+pub(crate) fn values_used_outside_def_block(body: &FunctionBody) -> HashSet<Value> {
+    let mut used = HashSet::new();
+    for (block, def) in body.blocks.entries() {
+        let mut note = |value: Value| {
+            let value = body.resolve_alias(value);
+            if body.value_blocks[value] != block {
+                used.insert(value);
+            }
+        };
+        for &inst in &def.insts {
+            match &body.values[inst] {
+                ValueDef::Operator(_, args, _) => {
+                    for &arg in &body.arg_pool[*args] {
+                        note(arg);
+                    }
+                }
+                ValueDef::PickOutput(value, ..) => note(*value),
+                _ => {}
+            }
+        }
+        def.terminator.visit_uses(&mut note);
+    }
+    used
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FuncDecl, Module, SignatureData};
+    use crate::{ConstVal, InterpContext};
+
+    /// Build `f(x) = [10, 11, 11][x]` (default 12) as a `br_table`.
+    fn table_module() -> (Module<'static>, crate::Func) {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let x = body.blocks[entry].params[0].1;
+        let mut targets = vec![];
+        for value in 10..13 {
+            let block = body.add_block();
+            let ret = body.add_op(block, Operator::I32Const { value }, &[], &[Type::I32]);
+            body.set_terminator(block, Terminator::Return { values: vec![ret] });
+            targets.push(BlockTarget {
+                block,
+                args: vec![],
+            });
+        }
+        let default = targets.pop().unwrap();
+        targets.push(targets[1].clone());
+        body.set_terminator(
+            entry,
+            Terminator::Select {
+                value: x,
+                targets,
+                default,
+            },
+        );
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+        (module, func)
+    }
+
+    #[test]
+    fn lower_to_branches() {
+        let (mut module, func) = table_module();
+        run(
+            module.funcs[func].body_mut().unwrap(),
+            SwitchLowering::Branches,
+        );
+        let body = module.funcs[func].body().unwrap();
+        assert!(body
+            .blocks
+            .values()
+            .all(|block| !matches!(block.terminator, Terminator::Select { .. })));
+        body.validate().unwrap();
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        for (arg, expected) in [(0, 10), (1, 11), (2, 11), (3, 12), (u32::MAX, 12)] {
+            let result = ctx.call(&module, func, &[ConstVal::I32(arg)]).ok().unwrap();
+            assert_eq!(result[0], ConstVal::I32(expected));
+        }
+    }
+
+    #[test]
+    fn raise_to_table() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![Type::I32],
+            returns: vec![Type::I32],
+        });
+        let mut body = FunctionBody::new(&module, sig);
+        let x = body.blocks[body.entry].params[0].1;
+        let mut block = body.entry;
+        for case in 1..5 {
+            let c = body.add_op(block, Operator::I32Const { value: case }, &[], &[Type::I32]);
+            let cond = body.add_op(block, Operator::I32Eq, &[x, c], &[Type::I32]);
+            let taken = body.add_block();
+            let ret = body.add_op(
+                taken,
+                Operator::I32Const { value: case * 10 },
+                &[],
+                &[Type::I32],
+            );
+            body.set_terminator(taken, Terminator::Return { values: vec![ret] });
+            let next = body.add_block();
+            body.set_terminator(
+                block,
+                Terminator::CondBr {
+                    cond,
+                    if_true: BlockTarget {
+                        block: taken,
+                        args: vec![],
+                    },
+                    if_false: BlockTarget {
+                        block: next,
+                        args: vec![],
+                    },
+                },
+            );
+            block = next;
+        }
+        body.set_terminator(block, Terminator::Return { values: vec![x] });
+        let entry = body.entry;
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, "f".to_owned(), body.into()));
+
+        run(module.funcs[func].body_mut().unwrap(), SwitchLowering::Auto);
+        let body = module.funcs[func].body().unwrap();
+        match &body.blocks[entry].terminator {
+            Terminator::Select { targets, .. } => assert_eq!(targets.len(), 4),
+            t => panic!("unexpected terminator {}", t),
+        }
+        body.validate().unwrap();
+
+        let mut ctx = InterpContext::new(&module).unwrap();
+        for (arg, expected) in [(0, 0), (1, 10), (4, 40), (5, 5), (u32::MAX, u32::MAX)] {
+            let result = ctx.call(&module, func, &[ConstVal::I32(arg)]).ok().unwrap();
+            assert_eq!(result[0], ConstVal::I32(expected));
+        }
+    }
+}
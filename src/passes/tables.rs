@@ -0,0 +1,279 @@
+//! Table compaction.
+//!
+//! After dead functions are removed, a module's function tables often
+//! still carry the slots that used to hold them, and linkers tend to
+//! reserve space for entries that are never reached. This pass
+//! removes table entries that can never be accessed, renumbering the
+//! remaining entries and the constant indices that refer to them, and
+//! shrinks the table's limits to match.
+//!
+//! Only tables that are private to the module (neither imported nor
+//! exported) and never modified or resized by the code are touched.
+//! If every access to a table uses a constant index, all entries not
+//! named by some access are removed; otherwise only trailing null
+//! entries, which can only ever trap, are trimmed.
+
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, ImportKind, Module, Type, Value, ValueDef};
+use crate::{ExportKind, Func, Operator, Table};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// How a table is accessed by the module's code.
+#[derive(Clone, Debug, Default)]
+struct TableUses {
+    /// Uses with a constant index: `(func, inst, arg index, table
+    /// index)`.
+    constant: Vec<(Func, Value, usize, usize)>,
+    /// Whether some `call_indirect` has a non-constant index.
+    dynamic_call: bool,
+    /// Whether some `table.get` has a non-constant index; unlike a
+    /// call, this can observe a null entry without trapping.
+    dynamic_get: bool,
+    /// Whether the table is modified, resized, or its size observed.
+    mutated: bool,
+}
+
+/// Compact every eligible function table in the module. Returns the
+/// number of table entries removed.
+///
+/// All function bodies must be expanded (see
+/// `Module::expand_all_funcs()`); otherwise no table is changed.
+pub fn compact(module: &mut Module) -> usize {
+    let uses = match collect_uses(module) {
+        Some(uses) => uses,
+        None => {
+            log::debug!("tables: not all function bodies are expanded; not compacting");
+            return 0;
+        }
+    };
+
+    let mut removed = 0;
+    for (table, table_uses) in uses {
+        let table_data = &module.tables[table];
+        let elements = match &table_data.func_elements {
+            Some(elements) => elements,
+            None => continue,
+        };
+        let initial = table_data.initial as usize;
+        if table_uses.mutated || table_uses.dynamic_get {
+            continue;
+        }
+
+        if table_uses.dynamic_call {
+            // Any in-bounds entry may be called, so only the trailing
+            // nulls (which trap whether in bounds or not) can go.
+            let mut new_len = elements
+                .iter()
+                .rposition(|f| f.is_valid())
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            // A constant `table.get` of a null entry must stay in
+            // bounds to keep returning null rather than trapping.
+            for &(_, _, _, index) in &table_uses.constant {
+                if index < initial {
+                    new_len = std::cmp::max(new_len, index + 1);
+                }
+            }
+            removed += shrink(module, table, new_len, None);
+            continue;
+        }
+
+        // Every access uses a constant index: keep exactly the
+        // in-bounds entries that are named, in their original order.
+        let live = table_uses
+            .constant
+            .iter()
+            .map(|&(_, _, _, index)| index)
+            .filter(|&index| index < initial)
+            .collect::<BTreeSet<_>>();
+        let remap = live
+            .iter()
+            .enumerate()
+            .map(|(new, &old)| (old, new))
+            .collect::<BTreeMap<_, _>>();
+        let new_elements = live
+            .iter()
+            .map(|&old| elements.get(old).copied().unwrap_or(Func::invalid()))
+            .collect::<Vec<_>>();
+
+        for &(func, inst, arg, index) in &table_uses.constant {
+            // Out-of-bounds indices stay out of bounds in the smaller
+            // table and are left alone.
+            if let Some(&new) = remap.get(&index) {
+                if new != index {
+                    rewrite_index(module, func, inst, arg, new as u32);
+                }
+            }
+        }
+        removed += shrink(module, table, live.len(), Some(new_elements));
+    }
+    removed
+}
+
+/// Set a table's size to `new_len`, optionally replacing its
+/// contents. Returns the number of entries removed.
+fn shrink(module: &mut Module, table: Table, new_len: usize, elements: Option<Vec<Func>>) -> usize {
+    let table_data = &mut module.tables[table];
+    let old_len = table_data.initial as usize;
+    if new_len >= old_len {
+        return 0;
+    }
+    log::debug!(
+        "tables: shrinking {} from {} to {} entries",
+        table,
+        old_len,
+        new_len
+    );
+    match elements {
+        Some(elements) => table_data.func_elements = Some(elements),
+        None => {
+            if let Some(elements) = table_data.func_elements.as_mut() {
+                elements.truncate(new_len);
+            }
+        }
+    }
+    table_data.initial = new_len as u64;
+    // Without `table.grow` the maximum is unobservable for a private
+    // table, so it can shrink along with the initial size.
+    table_data.max = table_data.max.map(|_| new_len as u64);
+    old_len - new_len
+}
+
+/// Replace the `arg`th argument of `inst` with a fresh constant,
+/// defined just before `inst`. The old constant may have other uses,
+/// so it is not modified in place.
+fn rewrite_index(module: &mut Module, func: Func, inst: Value, arg: usize, index: u32) {
+    let body = module.funcs[func].body_mut().unwrap();
+    let block = body.value_blocks[inst];
+    let tys = body.single_type_list(Type::I32);
+    let value = body.add_value(ValueDef::Operator(
+        Operator::I32Const { value: index },
+        Default::default(),
+        tys,
+    ));
+    body.value_blocks[value] = block;
+    body.copy_loc(inst, value);
+    let pos = body.blocks[block]
+        .insts
+        .iter()
+        .position(|&v| v == inst)
+        .unwrap();
+    body.blocks[block].insts.insert(pos, value);
+    if let ValueDef::Operator(_, args, _) = &body.values[inst] {
+        let args = *args;
+        body.arg_pool[args][arg] = value;
+    }
+}
+
+/// Collect the uses of every private function table, or return `None`
+/// if some function body is not available for scanning.
+fn collect_uses(module: &Module) -> Option<BTreeMap<Table, TableUses>> {
+    let mut public = HashSet::new();
+    for import in &module.imports {
+        if let ImportKind::Table(table) = import.kind {
+            public.insert(table);
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Table(table) = export.kind {
+            public.insert(table);
+        }
+    }
+    let mut uses = module
+        .tables
+        .entries()
+        .filter(|(table, data)| !public.contains(table) && data.func_elements.is_some())
+        .map(|(table, _)| (table, TableUses::default()))
+        .collect::<BTreeMap<_, _>>();
+
+    for (func, decl) in module.funcs.entries() {
+        let body = match decl {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Import(..) | FuncDecl::None => continue,
+            FuncDecl::Lazy(..) | FuncDecl::Compiled(..) => return None,
+        };
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                let (op, args) = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+                    _ => continue,
+                };
+                let (table, arg, is_call) = match op {
+                    Operator::CallIndirect { table_index, .. } => {
+                        (*table_index, args.len() - 1, true)
+                    }
+                    Operator::TableGet { table_index } => (*table_index, 0, false),
+                    Operator::TableSet { table_index }
+                    | Operator::TableGrow { table_index }
+                    | Operator::TableSize { table_index } => {
+                        if let Some(table_uses) = uses.get_mut(table_index) {
+                            table_uses.mutated = true;
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let table_uses = match uses.get_mut(&table) {
+                    Some(table_uses) => table_uses,
+                    None => continue,
+                };
+                match &body.values[body.resolve_alias(args[arg])] {
+                    ValueDef::Operator(Operator::I32Const { value }, _, _) => {
+                        table_uses.constant.push((func, inst, arg, *value as usize));
+                    }
+                    _ if is_call => table_uses.dynamic_call = true,
+                    _ => table_uses.dynamic_get = true,
+                }
+            }
+        }
+    }
+
+    Some(uses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{FunctionBody, SignatureData, TableData};
+    use crate::Terminator;
+
+    #[test]
+    fn compact_constant_calls() {
+        let mut module = Module::empty();
+        let sig = module.signatures.push(SignatureData {
+            params: vec![],
+            returns: vec![],
+        });
+        let callee = module.funcs.push(FuncDecl::None);
+        let table = module.tables.push(TableData {
+            ty: Type::FuncRef,
+            initial: 8,
+            max: Some(8),
+            func_elements: Some(vec![callee; 8]),
+        });
+
+        let mut body = FunctionBody::new(&module, sig);
+        let entry = body.entry;
+        let index = body.add_op(entry, Operator::I32Const { value: 5 }, &[], &[Type::I32]);
+        let call = Operator::CallIndirect {
+            sig_index: sig,
+            table_index: table,
+        };
+        body.add_op(entry, call, &[index], &[]);
+        body.add_op(entry, call, &[index], &[]);
+        body.set_terminator(entry, Terminator::Return { values: vec![] });
+        module.funcs[callee] = FuncDecl::Body(sig, "f".to_owned(), body.into());
+
+        assert_eq!(compact(&mut module), 7);
+        assert_eq!(module.tables[table].initial, 1);
+        assert_eq!(module.tables[table].max, Some(1));
+        let body = module.funcs[callee].body().unwrap();
+        let insts = &body.blocks[entry].insts;
+        assert_eq!(insts.len(), 5);
+        assert!(matches!(
+            body.values[insts[3]],
+            ValueDef::Operator(Operator::I32Const { value: 0 }, _, _)
+        ));
+        let _ = module.to_wasm_bytes().unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Terminator statistics.
+//!
+//! Summarizes the shapes of a function's block terminators: how many
+//! of each kind there are, and the size, target count, and density of
+//! every `br_table`. The switch-lowering pass uses the same per-table
+//! figures to choose between tables and compare trees, and
+//! `waffle-util stats` reports them to guide code-size tuning.
+
+use crate::ir::{Block, BlockTarget, FunctionBody, Terminator};
+
+/// The shape of one `br_table` (a `Terminator::Select`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// The block that ends in the table.
+    pub block: Block,
+    /// The number of explicit entries, not counting the default.
+    pub entries: usize,
+    /// The number of explicit entries that jump somewhere other than
+    /// the default target.
+    pub non_default: usize,
+    /// The number of distinct targets, counting the default.
+    pub distinct_targets: usize,
+    /// The number of runs of consecutive indices sharing a target,
+    /// counting the default (which covers all indices past the end).
+    /// This is the number of leaves in an equivalent compare tree.
+    pub clusters: usize,
+}
+
+impl TableStats {
+    /// Compute the shape of the table `targets` with `default`.
+    pub fn new(block: Block, targets: &[BlockTarget], default: &BlockTarget) -> TableStats {
+        let mut distinct: Vec<&BlockTarget> = vec![];
+        let mut clusters = 0;
+        let mut last = None;
+        for target in targets.iter().chain(std::iter::once(default)) {
+            if !distinct.contains(&target) {
+                distinct.push(target);
+            }
+            if last != Some(target) {
+                clusters += 1;
+                last = Some(target);
+            }
+        }
+        TableStats {
+            block,
+            entries: targets.len(),
+            non_default: targets.iter().filter(|&t| t != default).count(),
+            distinct_targets: distinct.len(),
+            clusters,
+        }
+    }
+
+    /// The percentage of explicit entries that do not go to the
+    /// default target. A table with no entries has density 0.
+    pub fn density_percent(&self) -> usize {
+        (self.non_default * 100)
+            .checked_div(self.entries)
+            .unwrap_or(0)
+    }
+}
+
+/// Counts of the terminators of a function's blocks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TerminatorStats {
+    /// Unconditional branches.
+    pub branches: usize,
+    /// Conditional branches.
+    pub cond_branches: usize,
+    /// Returns.
+    pub returns: usize,
+    /// `unreachable`s, not counting blocks with no terminator.
+    pub unreachables: usize,
+    /// Every `br_table`, in block order.
+    pub tables: Vec<TableStats>,
+}
+
+impl TerminatorStats {
+    /// Collect statistics over all blocks of `body`, reachable or not.
+    pub fn compute(body: &FunctionBody) -> TerminatorStats {
+        let mut stats = TerminatorStats::default();
+        for (block, def) in body.blocks.entries() {
+            match &def.terminator {
+                Terminator::Br { .. } => stats.branches += 1,
+                Terminator::CondBr { .. } => stats.cond_branches += 1,
+                Terminator::Select {
+                    targets, default, ..
+                } => stats.tables.push(TableStats::new(block, targets, default)),
+                Terminator::Return { .. } => stats.returns += 1,
+                Terminator::Unreachable => stats.unreachables += 1,
+                Terminator::None => {}
+            }
+        }
+        stats
+    }
+
+    /// The largest `br_table`'s number of entries, if any.
+    pub fn max_table_entries(&self) -> Option<usize> {
+        self.tables.iter().map(|table| table.entries).max()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::EntityRef;
+
+    #[test]
+    fn table_shape() {
+        let target = |i| BlockTarget {
+            block: Block::new(i),
+            args: vec![],
+        };
+        let targets = vec![target(1), target(1), target(2), target(9), target(9)];
+        let stats = TableStats::new(Block::new(0), &targets, &target(9));
+        assert_eq!(stats.entries, 5);
+        assert_eq!(stats.non_default, 3);
+        assert_eq!(stats.distinct_targets, 3);
+        assert_eq!(stats.clusters, 3);
+        assert_eq!(stats.density_percent(), 60);
+    }
+}
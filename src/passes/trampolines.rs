@@ -0,0 +1,286 @@
+//! Trampolines: functions that convert between two signatures.
+//!
+//! Splicing modules together, or virtualizing an import whose ABI
+//! does not quite match, needs a function with one signature that
+//! calls a function with another: arguments reordered, integers
+//! widened or narrowed, constants supplied for parameters the caller
+//! does not have. A `TrampolineSpec` describes each argument of the
+//! call and each result of the trampoline in terms of the values at
+//! hand, and `make()` builds the function.
+
+use crate::interp::ConstVal;
+use crate::ir::{FuncDecl, FunctionBody, Module, Terminator, Type, Value, ValueDef};
+use crate::{Block, Func, Operator, Signature};
+use anyhow::{bail, Result};
+
+/// A conversion applied to a value passed through a trampoline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conversion {
+    /// Pass the value unchanged.
+    None,
+    /// Narrow an `i64` to an `i32`, keeping the low bits.
+    WrapI64,
+    /// Widen an `i32` to an `i64`.
+    ExtendI32 { signed: bool },
+    /// Widen an `f32` to an `f64`.
+    PromoteF32,
+    /// Narrow an `f64` to an `f32`.
+    DemoteF64,
+}
+
+impl Conversion {
+    /// The converted type of a value of type `ty`, or `None` if the
+    /// conversion does not apply to it.
+    pub fn apply(self, ty: Type) -> Option<Type> {
+        match (self, ty) {
+            (Conversion::None, ty) => Some(ty),
+            (Conversion::WrapI64, Type::I64) => Some(Type::I32),
+            (Conversion::ExtendI32 { .. }, Type::I32) => Some(Type::I64),
+            (Conversion::PromoteF32, Type::F32) => Some(Type::F64),
+            (Conversion::DemoteF64, Type::F64) => Some(Type::F32),
+            _ => None,
+        }
+    }
+}
+
+/// Where a value passed through a trampoline comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// The value at an index (a trampoline parameter for an argument,
+    /// a callee result for a result), converted.
+    Value(usize, Conversion),
+    /// A constant.
+    Const(ConstVal),
+}
+
+impl Source {
+    /// The value at `index`, unchanged.
+    pub fn forward(index: usize) -> Source {
+        Source::Value(index, Conversion::None)
+    }
+
+    fn ty(&self, available: &[Type]) -> Option<Type> {
+        match *self {
+            Source::Value(index, conversion) => conversion.apply(*available.get(index)?),
+            Source::Const(value) => value.ty(),
+        }
+    }
+}
+
+/// How a trampoline calls its target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrampolineSpec {
+    /// The function called.
+    pub target: Func,
+    /// For each parameter of the target, where its argument comes
+    /// from among the trampoline's parameters.
+    pub args: Vec<Source>,
+    /// For each result of the trampoline, where it comes from among
+    /// the target's results. Target results not used are dropped.
+    pub results: Vec<Source>,
+}
+
+impl TrampolineSpec {
+    /// Check the spec against the trampoline's signature `from` and
+    /// the target's signature `to`.
+    pub fn check(&self, module: &Module, from: Signature, to: Signature) -> Result<()> {
+        let (from, to) = (&module.signatures[from], &module.signatures[to]);
+        let matches = |sources: &[Source], available: &[Type], wanted: &[Type]| {
+            sources.len() == wanted.len()
+                && sources
+                    .iter()
+                    .zip(wanted)
+                    .all(|(source, &ty)| source.ty(available) == Some(ty))
+        };
+        if !matches(&self.args, &from.params, &to.params) {
+            bail!("Trampoline arguments do not match the target's parameters");
+        }
+        if !matches(&self.results, &to.returns, &from.returns) {
+            bail!("Trampoline results do not match its signature");
+        }
+        Ok(())
+    }
+}
+
+/// Build the body of a trampoline with signature `from` calling
+/// `spec.target`, whose signature is `to`.
+pub fn body(
+    module: &Module,
+    from: Signature,
+    to: Signature,
+    spec: &TrampolineSpec,
+) -> Result<FunctionBody> {
+    spec.check(module, from, to)?;
+    let returns = module.signatures[to].returns.clone();
+
+    let mut body = FunctionBody::new(module, from);
+    let entry = body.entry;
+    let params = body.blocks[entry]
+        .params
+        .iter()
+        .map(|&(_, value)| value)
+        .collect::<Vec<_>>();
+    let args = spec
+        .args
+        .iter()
+        .map(|source| materialize(&mut body, entry, source, &params))
+        .collect::<Vec<_>>();
+    let call = body.add_op(
+        entry,
+        Operator::Call {
+            function_index: spec.target,
+        },
+        &args,
+        &returns,
+    );
+    let outputs = if returns.len() == 1 {
+        vec![call]
+    } else {
+        let mut picks = vec![];
+        for (i, &ty) in returns.iter().enumerate() {
+            let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+            body.append_to_block(entry, pick);
+            picks.push(pick);
+        }
+        picks
+    };
+    let values = spec
+        .results
+        .iter()
+        .map(|source| materialize(&mut body, entry, source, &outputs))
+        .collect();
+    body.set_terminator(entry, Terminator::Return { values });
+    Ok(body)
+}
+
+/// Compute `source` from `available` at the end of `block`.
+fn materialize(
+    body: &mut FunctionBody,
+    block: Block,
+    source: &Source,
+    available: &[Value],
+) -> Value {
+    let (op, ty) = match *source {
+        Source::Value(index, conversion) => {
+            let value = available[index];
+            let (op, ty) = match conversion {
+                Conversion::None => return value,
+                Conversion::WrapI64 => (Operator::I32WrapI64, Type::I32),
+                Conversion::ExtendI32 { signed: true } => (Operator::I64ExtendI32S, Type::I64),
+                Conversion::ExtendI32 { signed: false } => (Operator::I64ExtendI32U, Type::I64),
+                Conversion::PromoteF32 => (Operator::F64PromoteF32, Type::F64),
+                Conversion::DemoteF64 => (Operator::F32DemoteF64, Type::F32),
+            };
+            return body.add_op(block, op, &[value], &[ty]);
+        }
+        Source::Const(ConstVal::I32(value)) => (Operator::I32Const { value }, Type::I32),
+        Source::Const(ConstVal::I64(value)) => (Operator::I64Const { value }, Type::I64),
+        Source::Const(ConstVal::F32(value)) => (Operator::F32Const { value }, Type::F32),
+        Source::Const(ConstVal::F64(value)) => (Operator::F64Const { value }, Type::F64),
+        Source::Const(ConstVal::V128(value)) => (
+            Operator::V128Const {
+                value: body.imm_pool.intern(value),
+            },
+            Type::V128,
+        ),
+        Source::Const(ConstVal::Ref(_))
+        | Source::Const(ConstVal::ExternRef(_))
+        | Source::Const(ConstVal::None) => unreachable!("checked by TrampolineSpec::check()"),
+    };
+    body.add_op(block, op, &[], &[ty])
+}
+
+/// Add a trampoline with signature `from` calling `spec.target`, whose
+/// signature must be `to`, to `module`. Returns the trampoline.
+pub fn make(
+    module: &mut Module,
+    from: Signature,
+    to: Signature,
+    spec: &TrampolineSpec,
+) -> Result<Func> {
+    let target = match module.funcs.get(spec.target) {
+        Some(decl) if !matches!(decl, FuncDecl::None) => decl,
+        _ => bail!("Trampoline target {} does not exist", spec.target),
+    };
+    if target.sig() != to {
+        bail!(
+            "Trampoline target {} does not have signature {}",
+            spec.target,
+            to
+        );
+    }
+    let name = format!("{}_trampoline", target.name());
+    let body = body(module, from, to, spec)?;
+    Ok(module.funcs.push(FuncDecl::Body(from, name, body.into())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::SignatureData;
+    use crate::InterpContext;
+
+    #[test]
+    fn widen_reorder_and_insert_constants() {
+        let mut module = Module::empty();
+        // to: (i64, i32, f64) -> (i32, i64)
+        let to = module.signatures.push(SignatureData {
+            params: vec![Type::I64, Type::I32, Type::F64],
+            returns: vec![Type::I32, Type::I64],
+        });
+        // from: (i32, i32) -> i32
+        let from = module.signatures.push(SignatureData {
+            params: vec![Type::I32, Type::I32],
+            returns: vec![Type::I32],
+        });
+
+        // The target returns (b, a + b) where the params are (a, b, _).
+        let mut body = FunctionBody::new(&module, to);
+        let entry = body.entry;
+        let a = body.blocks[entry].params[0].1;
+        let b = body.blocks[entry].params[1].1;
+        let wide_b = body.add_op(entry, Operator::I64ExtendI32S, &[b], &[Type::I64]);
+        let sum = body.add_op(entry, Operator::I64Add, &[a, wide_b], &[Type::I64]);
+        body.set_terminator(
+            entry,
+            Terminator::Return {
+                values: vec![b, sum],
+            },
+        );
+        let target = module
+            .funcs
+            .push(FuncDecl::Body(to, "t".to_owned(), body.into()));
+
+        let spec = TrampolineSpec {
+            target,
+            args: vec![
+                Source::Value(1, Conversion::ExtendI32 { signed: true }),
+                Source::forward(0),
+                Source::Const(ConstVal::F64(0)),
+            ],
+            results: vec![Source::Value(1, Conversion::WrapI64)],
+        };
+        let tramp = make(&mut module, from, to, &spec).unwrap();
+        assert_eq!(module.funcs[tramp].name(), "t_trampoline");
+        module.funcs[tramp].body().unwrap().validate().unwrap();
+        let bytes = module.to_wasm_bytes().unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        // tramp(x, y) = wrap(ext(y) + ext(x)).
+        let mut ctx = InterpContext::new(&module).unwrap();
+        let result = ctx.call(
+            &module,
+            tramp,
+            &[ConstVal::I32(3), ConstVal::I32(-5i32 as u32)],
+        );
+        assert_eq!(&result.ok().unwrap()[..], &[ConstVal::I32(-2i32 as u32)]);
+
+        // Mismatched specs are rejected.
+        let bad = TrampolineSpec {
+            results: vec![Source::forward(1)],
+            ..spec.clone()
+        };
+        assert!(make(&mut module, from, to, &bad).is_err());
+        assert!(make(&mut module, from, from, &spec).is_err());
+    }
+}
@@ -14,21 +14,39 @@
 //! `T`, with a `ListRef<T>` that together with the pool can yield an
 //! actual slice. This container is instantiated several times in the
 //! `FunctionBody`, namely for the `arg_pool` and `type_pool`.
+//!
+//! The 128-bit immediates of SIMD operators are pooled similarly, in
+//! an `ImmPool` per body, so that they do not widen every operator.
+//!
+//! Lists can be edited in place with `push`, `remove`, and `splice`.
+//! When an edit must move a list, its old storage goes on a free list
+//! and is reused by later edits that need a slot of the same size.
+//! Storage that is never reused can be reclaimed with `defrag`, which
+//! compacts the pool and rewrites every live handle.
 
+use crate::entity::EntityVec;
+use crate::V128Imm;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::ops::{Index, IndexMut};
+use std::ops::{Index, IndexMut, Range};
 
 /// A "storage pool" backing many `ListRef`s of the given type.
 #[derive(Clone, Debug)]
 pub struct ListPool<T: Clone + Debug> {
     storage: Vec<T>,
+    /// Start offsets of freed slots in `storage`, indexed by slot
+    /// length.
+    free: Vec<Vec<u32>>,
 }
 
 impl<T: Clone + Debug> Default for ListPool<T> {
     fn default() -> Self {
-        ListPool { storage: vec![] }
+        ListPool {
+            storage: vec![],
+            free: vec![],
+        }
     }
 }
 
@@ -38,9 +56,18 @@ impl<T: Clone + Debug> Default for ListPool<T> {
 /// but has much smaller overhead than a separately-owned `Vec`: e.g.,
 /// 8 bytes on 64-bit systems, rather than 24 bytes, and no separate
 /// memory allocation overhead.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 pub struct ListRef<T>(u32, u32, PhantomData<T>);
 
+// Implemented by hand so that handles are `Copy` even when the items
+// are not.
+impl<T> Clone for ListRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for ListRef<T> {}
+
 impl<T> Default for ListRef<T> {
     fn default() -> Self {
         ListRef(0, 0, PhantomData)
@@ -48,6 +75,11 @@ impl<T> Default for ListRef<T> {
 }
 
 impl<T: Clone + Debug> ListPool<T> {
+    /// Reserve capacity for at least `additional` more items.
+    pub fn reserve(&mut self, additional: usize) {
+        self.storage.reserve(additional);
+    }
+
     /// Create a new list in this pool from the items yielded by the
     /// given iterator.
     pub fn from_iter<I: Iterator<Item = T>>(&mut self, iter: I) -> ListRef<T> {
@@ -88,6 +120,142 @@ impl<T: Clone + Debug> ListPool<T> {
         let end = u32::try_from(self.storage.len()).unwrap();
         ListRef(start, end, PhantomData)
     }
+
+    /// Return a list's storage to the pool for reuse. The list must
+    /// not be used afterward, through this or any other handle;
+    /// note that some handles are shared (e.g., deduplicated type
+    /// lists) and must not be freed.
+    pub fn free(&mut self, list: ListRef<T>) {
+        let len = list.len();
+        if len == 0 {
+            return;
+        }
+        if self.free.len() <= len {
+            self.free.resize(len + 1, vec![]);
+        }
+        self.free[len].push(list.0);
+    }
+
+    /// Store `items` as a new list, reusing a freed slot if one of
+    /// the right size is available.
+    fn alloc(&mut self, items: Vec<T>) -> ListRef<T> {
+        let len = items.len();
+        match self.free.get_mut(len).and_then(|slots| slots.pop()) {
+            Some(start) => {
+                let end = start + len as u32;
+                for (slot, item) in self.storage[start as usize..end as usize]
+                    .iter_mut()
+                    .zip(items)
+                {
+                    *slot = item;
+                }
+                ListRef(start, end, PhantomData)
+            }
+            None => self.from_iter(items.into_iter()),
+        }
+    }
+
+    /// Append an item to a list, updating its handle. The list grows
+    /// in place if it is the last one in the pool; otherwise it is
+    /// moved and its old slot freed, so it must not be shared with
+    /// another handle.
+    pub fn push(&mut self, list: &mut ListRef<T>, item: T) {
+        if list.1 as usize == self.storage.len() {
+            self.storage.push(item);
+            list.1 += 1;
+            return;
+        }
+        let mut items = self[*list].to_vec();
+        items.push(item);
+        let old = std::mem::replace(list, self.alloc(items));
+        self.free(old);
+    }
+
+    /// Remove and return the item at `index` of a list, shifting the
+    /// later items down. This happens in place; the vacated slot at
+    /// the end is freed.
+    pub fn remove(&mut self, list: &mut ListRef<T>, index: usize) -> T {
+        let removed = self[*list][index].clone();
+        self[*list][index..].rotate_left(1);
+        list.1 -= 1;
+        self.free(ListRef(list.1, list.1 + 1, PhantomData));
+        removed
+    }
+
+    /// Replace the items of a list in `range` with those yielded by
+    /// `replace_with`, updating its handle and returning the removed
+    /// items. A list that does not grow is edited in place; one that
+    /// does is moved as for `push`.
+    pub fn splice<I: IntoIterator<Item = T>>(
+        &mut self,
+        list: &mut ListRef<T>,
+        range: Range<usize>,
+        replace_with: I,
+    ) -> Vec<T> {
+        let mut items = self[*list].to_vec();
+        let removed = items.splice(range, replace_with).collect::<Vec<_>>();
+        if items.len() <= list.len() {
+            let end = list.0 + items.len() as u32;
+            for (slot, item) in self[*list].iter_mut().zip(items) {
+                *slot = item;
+            }
+            self.free(ListRef(end, list.1, PhantomData));
+            list.1 = end;
+        } else {
+            let old = std::mem::replace(list, self.alloc(items));
+            self.free(old);
+        }
+        removed
+    }
+
+    /// Compact the pool so that it holds only the given lists,
+    /// rewriting their handles. Handles that shared a list still
+    /// share it afterward; any handle not passed here is invalidated.
+    pub fn defrag<'a, I: IntoIterator<Item = &'a mut ListRef<T>>>(&mut self, lists: I)
+    where
+        T: 'a,
+    {
+        let mut storage = vec![];
+        let mut moved: HashMap<(u32, u32), ListRef<T>> = HashMap::new();
+        for list in lists {
+            if list.is_empty() {
+                *list = ListRef::default();
+                continue;
+            }
+            *list = *moved.entry((list.0, list.1)).or_insert_with(|| {
+                let start = u32::try_from(storage.len()).unwrap();
+                storage.extend_from_slice(&self.storage[list.0 as usize..list.1 as usize]);
+                let end = u32::try_from(storage.len()).unwrap();
+                ListRef(start, end, PhantomData)
+            });
+        }
+        self.storage = storage;
+        self.free.clear();
+    }
+
+    /// Return the number of items stored in the pool, including freed
+    /// and unreachable ones.
+    pub fn storage_len(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// Return the number of bytes of heap memory the pool holds,
+    /// including unused capacity and the free lists.
+    pub fn heap_size(&self) -> usize {
+        self.storage.capacity() * std::mem::size_of::<T>()
+            + self.free.capacity() * std::mem::size_of::<Vec<u32>>()
+            + self
+                .free
+                .iter()
+                .map(|slots| slots.capacity() * std::mem::size_of::<u32>())
+                .sum::<usize>()
+    }
+
+    /// Mutable access to every item stored in the pool, for rewrites
+    /// that apply to all lists alike.
+    pub fn items_mut(&mut self) -> &mut [T] {
+        &mut self.storage[..]
+    }
 }
 
 impl<T: Clone + Debug> Index<ListRef<T>> for ListPool<T> {
@@ -115,3 +283,101 @@ impl<T> ListRef<T> {
         self.len() == 0
     }
 }
+
+/// A pool of 128-bit immediates: the values of `v128.const`s and the
+/// lane patterns of `i8x16.shuffle`s. Each `FunctionBody` has its own,
+/// so a `V128Imm` means nothing outside the body it came from; see
+/// `FunctionBody::import_op()`.
+///
+/// Values are deduplicated, so that within a pool, handles are equal
+/// exactly when their values are.
+#[derive(Clone, Debug, Default)]
+pub struct ImmPool {
+    values: EntityVec<V128Imm, u128>,
+    dedup: HashMap<u128, V128Imm>,
+}
+
+impl ImmPool {
+    /// Return the handle of `value`, adding it if it is new.
+    pub fn intern(&mut self, value: u128) -> V128Imm {
+        let values = &mut self.values;
+        *self
+            .dedup
+            .entry(value)
+            .or_insert_with(|| values.push(value))
+    }
+
+    /// Return the handle of the lane bytes `lanes`, lane 0 first.
+    pub fn intern_lanes(&mut self, lanes: [u8; 16]) -> V128Imm {
+        self.intern(u128::from_le_bytes(lanes))
+    }
+
+    /// Return the lane bytes of `imm`, lane 0 first.
+    pub fn lanes(&self, imm: V128Imm) -> [u8; 16] {
+        self[imm].to_le_bytes()
+    }
+
+    /// Return the number of distinct immediates in the pool.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Return whether the pool is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of bytes of heap memory the pool holds,
+    /// including unused capacity.
+    pub fn heap_size(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<u128>()
+            + self.dedup.capacity() * (std::mem::size_of::<(u128, V128Imm)>() + 1)
+    }
+}
+
+impl Index<V128Imm> for ImmPool {
+    type Output = u128;
+    fn index(&self, index: V128Imm) -> &u128 {
+        &self.values[index]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edit_and_defrag() {
+        let mut pool = ListPool::default();
+        let mut a = pool.from_iter(vec![1, 2, 3].into_iter());
+        let mut b = pool.from_iter(vec![4, 5].into_iter());
+        pool.push(&mut a, 9);
+        assert_eq!(&pool[a], &[1, 2, 3, 9]);
+        assert_eq!(pool.remove(&mut b, 0), 4);
+        assert_eq!(&pool[b], &[5]);
+        assert_eq!(pool.splice(&mut a, 1..3, vec![7]), vec![2, 3]);
+        assert_eq!(&pool[a], &[1, 7, 9]);
+        // The freed slot of `a`'s old storage is reused.
+        let len = pool.storage_len();
+        pool.alloc(vec![0, 0, 0]);
+        assert_eq!(pool.storage_len(), len);
+
+        let mut shared = a;
+        pool.defrag(vec![&mut a, &mut b, &mut shared]);
+        assert_eq!(pool.storage_len(), 4);
+        assert_eq!(a, shared);
+        assert_eq!(&pool[a], &[1, 7, 9]);
+        assert_eq!(&pool[b], &[5]);
+    }
+
+    #[test]
+    fn imm_pool_dedups() {
+        let mut pool = ImmPool::default();
+        let a = pool.intern(0x0201);
+        assert_eq!(pool.intern(0x0201), a);
+        assert_ne!(pool.intern(0x0301), a);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(&pool.lanes(a)[..3], &[1, 2, 0]);
+        assert_eq!(pool.intern_lanes(pool.lanes(a)), a);
+    }
+}
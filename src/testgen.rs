@@ -0,0 +1,453 @@
+//! Random IR test-case generation.
+//!
+//! Fuzzing passes and the backend by way of Wasm (e.g. with
+//! wasm-smith) spends most of its effort in the frontend, and can only
+//! produce the IR shapes that the frontend does. This module instead
+//! generates valid `FunctionBody`s and `Module`s directly at the IR
+//! level: arbitrary CFGs with blockparams, multi-way branches, and
+//! values flowing across blocks. Generation is deterministic: the
+//! same seed and options always yield the same module.
+
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Export, ExportKind, FuncDecl, FunctionBody, Memory, MemoryData, Module,
+    Signature, SignatureData, Terminator, Type, Value,
+};
+use crate::{MemoryArg, Operator};
+
+pub mod mutate;
+
+/// Which IR features generated code may use.
+#[derive(Clone, Debug)]
+pub struct GenOptions {
+    /// How many functions to generate.
+    pub funcs: usize,
+    /// Maximum number of blocks per function.
+    pub max_blocks: usize,
+    /// Maximum number of instructions per block.
+    pub max_insts: usize,
+    /// Maximum number of parameters per function and per block.
+    pub max_params: usize,
+    /// Generate `f32`/`f64` arithmetic.
+    pub floats: bool,
+    /// Generate loads and stores to a one-page memory. Addresses are
+    /// masked so that accesses stay in bounds.
+    pub memory: bool,
+    /// Generate calls. Functions only call those generated before
+    /// them, so there is no recursion.
+    pub calls: bool,
+    /// Generate backward branches. Functions with loops may not
+    /// terminate when executed.
+    pub loops: bool,
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        GenOptions {
+            funcs: 4,
+            max_blocks: 8,
+            max_insts: 8,
+            max_params: 3,
+            floats: true,
+            memory: true,
+            calls: true,
+            loops: false,
+        }
+    }
+}
+
+/// A small, fast, deterministic pseudo-random number generator
+/// (SplitMix64).
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a generator from a seed.
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    /// Return the next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Return a number in `0..n`. `n` must be nonzero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Return `true` with probability `1/n`.
+    pub fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+
+    /// Choose an element of a nonempty slice.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Generate a module from `seed`. Every function is exported, as
+/// `f0`, `f1`, and so on, and the module passes validation when
+/// compiled to Wasm.
+pub fn gen_module(seed: u64, options: &GenOptions) -> Module<'static> {
+    let mut rng = Rng::new(seed);
+    let mut module = Module::empty();
+    if options.memory {
+        module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: Some(1),
+            segments: vec![],
+        });
+    }
+    for i in 0..options.funcs {
+        let sig = gen_signature(&mut rng, &mut module, options);
+        let body = gen_body(&mut rng, &module, sig, options);
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, format!("f{}", i), body.into()));
+        module.exports.push(Export {
+            name: format!("f{}", i),
+            kind: ExportKind::Func(func),
+        });
+    }
+    module
+}
+
+fn value_types(options: &GenOptions) -> &'static [Type] {
+    if options.floats {
+        &[Type::I32, Type::I64, Type::F32, Type::F64]
+    } else {
+        &[Type::I32, Type::I64]
+    }
+}
+
+fn gen_types(rng: &mut Rng, options: &GenOptions, max: usize) -> Vec<Type> {
+    let count = rng.below(max + 1);
+    (0..count)
+        .map(|_| *rng.choose(value_types(options)))
+        .collect()
+}
+
+fn gen_signature(rng: &mut Rng, module: &mut Module, options: &GenOptions) -> Signature {
+    let params = gen_types(rng, options, options.max_params);
+    // Single results keep calls simple: no `PickOutput`s needed.
+    let returns = gen_types(rng, options, 1);
+    module.signatures.push(SignatureData { params, returns })
+}
+
+/// Generate a body with signature `sig`, calling only functions
+/// already in `module`.
+pub fn gen_body(
+    rng: &mut Rng,
+    module: &Module,
+    sig: Signature,
+    options: &GenOptions,
+) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let n_blocks = 1 + rng.below(options.max_blocks.max(1));
+    let mut blocks = vec![body.entry];
+    for _ in 1..n_blocks {
+        let block = body.add_block();
+        for ty in gen_types(rng, options, options.max_params) {
+            body.add_blockparam(block, ty);
+        }
+        blocks.push(block);
+    }
+
+    // The entry dominates every block, so its values are usable
+    // everywhere; other blocks see only their own values.
+    let mut entry_values = vec![];
+    for (i, &block) in blocks.iter().enumerate() {
+        let mut values = body.blocks[block]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>();
+        if i > 0 {
+            values.extend(entry_values.iter().copied());
+        }
+        for _ in 0..rng.below(options.max_insts + 1) {
+            if let Some(value) = gen_inst(rng, module, &mut body, block, &values, options) {
+                values.push(value);
+            }
+        }
+        if i == 0 {
+            entry_values = values.clone();
+        }
+
+        let terminator = if i + 1 == blocks.len() {
+            let rets = module.signatures[sig].returns.clone();
+            let values = rets
+                .iter()
+                .map(|&ty| gen_operand(rng, &mut body, block, &values, ty))
+                .collect();
+            Terminator::Return { values }
+        } else {
+            let lo = if options.loops { 1 } else { i + 1 };
+            let target = |rng: &mut Rng, body: &mut FunctionBody| {
+                let to = blocks[lo + rng.below(blocks.len() - lo)];
+                let tys = body.blocks[to]
+                    .params
+                    .iter()
+                    .map(|&(ty, _)| ty)
+                    .collect::<Vec<_>>();
+                let args = tys
+                    .into_iter()
+                    .map(|ty| gen_operand(rng, body, block, &values, ty))
+                    .collect();
+                BlockTarget { block: to, args }
+            };
+            match rng.below(4) {
+                0 | 1 => Terminator::Br {
+                    target: target(rng, &mut body),
+                },
+                2 => {
+                    let if_true = target(rng, &mut body);
+                    let if_false = target(rng, &mut body);
+                    let cond = gen_operand(rng, &mut body, block, &values, Type::I32);
+                    Terminator::CondBr {
+                        cond,
+                        if_true,
+                        if_false,
+                    }
+                }
+                _ => {
+                    let targets = (0..rng.below(4)).map(|_| target(rng, &mut body)).collect();
+                    let default = target(rng, &mut body);
+                    let value = gen_operand(rng, &mut body, block, &values, Type::I32);
+                    Terminator::Select {
+                        value,
+                        targets,
+                        default,
+                    }
+                }
+            }
+        };
+        body.set_terminator(block, terminator);
+    }
+    body
+}
+
+/// Pick an available value of type `ty`, or materialize a constant.
+fn gen_operand(
+    rng: &mut Rng,
+    body: &mut FunctionBody,
+    block: Block,
+    values: &[Value],
+    ty: Type,
+) -> Value {
+    let candidates = values
+        .iter()
+        .copied()
+        .filter(|&v| body.values[v].ty(&body.type_pool) == Some(ty))
+        .collect::<Vec<_>>();
+    if !candidates.is_empty() && !rng.one_in(4) {
+        return *rng.choose(&candidates);
+    }
+    let bits = rng.next_u64();
+    let op = match ty {
+        Type::I32 => Operator::I32Const { value: bits as u32 },
+        Type::I64 => Operator::I64Const { value: bits },
+        Type::F32 => Operator::F32Const {
+            value: (bits as f32).to_bits(),
+        },
+        Type::F64 => Operator::F64Const {
+            value: ((bits >> 11) as f64).to_bits(),
+        },
+        _ => unreachable!(),
+    };
+    body.add_op(block, op, &[], &[ty])
+}
+
+/// Generate one instruction, returning its value if it has one.
+fn gen_inst(
+    rng: &mut Rng,
+    module: &Module,
+    body: &mut FunctionBody,
+    block: Block,
+    values: &[Value],
+    options: &GenOptions,
+) -> Option<Value> {
+    let memarg = MemoryArg {
+        align: 2,
+        offset: 0,
+        memory: Memory::new(0),
+    };
+    let mut choices = 8;
+    if options.floats {
+        choices += 2;
+    }
+    if options.memory {
+        choices += 2;
+    }
+    if options.calls && module.funcs.len() > 0 {
+        choices += 1;
+    }
+    let mut choice = rng.below(choices);
+    let binary = |rng: &mut Rng, body: &mut FunctionBody, ops: &[Operator], ty: Type| {
+        let op = *rng.choose(ops);
+        let a = gen_operand(rng, body, block, values, ty);
+        let b = gen_operand(rng, body, block, values, ty);
+        body.add_op(block, op, &[a, b], &[ty])
+    };
+    let unary = |rng: &mut Rng, body: &mut FunctionBody, op: Operator, from: Type, to: Type| {
+        let a = gen_operand(rng, body, block, values, from);
+        body.add_op(block, op, &[a], &[to])
+    };
+    let i32_ops = [
+        Operator::I32Add,
+        Operator::I32Sub,
+        Operator::I32Mul,
+        Operator::I32And,
+        Operator::I32Or,
+        Operator::I32Xor,
+        Operator::I32Shl,
+        Operator::I32ShrU,
+        Operator::I32Rotl,
+    ];
+    let i64_ops = [
+        Operator::I64Add,
+        Operator::I64Sub,
+        Operator::I64Mul,
+        Operator::I64And,
+        Operator::I64Xor,
+        Operator::I64ShrS,
+    ];
+    let compares = [
+        Operator::I32Eq,
+        Operator::I32Ne,
+        Operator::I32LtU,
+        Operator::I32GtS,
+        Operator::I32LeS,
+    ];
+    let value = match choice {
+        0..=2 => binary(rng, body, &i32_ops, Type::I32),
+        3 => binary(rng, body, &i64_ops, Type::I64),
+        4 => {
+            let op = *rng.choose(&compares);
+            let a = gen_operand(rng, body, block, values, Type::I32);
+            let b = gen_operand(rng, body, block, values, Type::I32);
+            body.add_op(block, op, &[a, b], &[Type::I32])
+        }
+        5 => unary(rng, body, Operator::I32Eqz, Type::I32, Type::I32),
+        6 => unary(rng, body, Operator::I64ExtendI32U, Type::I32, Type::I64),
+        7 => unary(rng, body, Operator::I32WrapI64, Type::I64, Type::I32),
+        _ => {
+            choice -= 8;
+            if options.floats {
+                match choice {
+                    0 => {
+                        return Some(binary(
+                            rng,
+                            body,
+                            &[Operator::F32Add, Operator::F32Mul],
+                            Type::F32,
+                        ))
+                    }
+                    1 => {
+                        return Some(binary(
+                            rng,
+                            body,
+                            &[Operator::F64Sub, Operator::F64Div],
+                            Type::F64,
+                        ))
+                    }
+                    _ => choice -= 2,
+                }
+            }
+            if options.memory {
+                // Mask the address to an aligned, in-bounds offset.
+                let addr = gen_operand(rng, body, block, values, Type::I32);
+                let mask = body.add_op(
+                    block,
+                    Operator::I32Const { value: 0xfffc },
+                    &[],
+                    &[Type::I32],
+                );
+                let addr = body.add_op(block, Operator::I32And, &[addr, mask], &[Type::I32]);
+                match choice {
+                    0 => {
+                        return Some(body.add_op(
+                            block,
+                            Operator::I32Load { memory: memarg },
+                            &[addr],
+                            &[Type::I32],
+                        ))
+                    }
+                    1 => {
+                        let data = gen_operand(rng, body, block, values, Type::I32);
+                        body.add_op(
+                            block,
+                            Operator::I32Store { memory: memarg },
+                            &[addr, data],
+                            &[],
+                        );
+                        return None;
+                    }
+                    _ => {}
+                }
+            }
+            // Call an earlier function.
+            let callee = crate::Func::new(rng.below(module.funcs.len()));
+            let sig = module.funcs[callee].sig();
+            let sig = &module.signatures[sig];
+            let args = sig
+                .params
+                .iter()
+                .map(|&ty| gen_operand(rng, body, block, values, ty))
+                .collect::<Vec<_>>();
+            let call = body.add_op(
+                block,
+                Operator::Call {
+                    function_index: callee,
+                },
+                &args,
+                &sig.returns,
+            );
+            if sig.returns.is_empty() {
+                return None;
+            }
+            call
+        }
+    };
+    Some(value)
+}
+
+/// Parse a module from WAT text with the given frontend options, for
+/// tests. The bytes are leaked so that the module, and its lazy bodies,
+/// can borrow them for as long as it lives.
+#[cfg(test)]
+pub(crate) fn wat_module(wat: &str, options: &crate::FrontendOptions) -> Module<'static> {
+    let bytes = wat::parse_str(wat).unwrap();
+    Module::from_wasm_bytes(Box::leak(bytes.into_boxed_slice()), options).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_modules_are_valid() {
+        for seed in 0..32 {
+            let module = gen_module(seed, &GenOptions::default());
+            for (_, decl) in module.funcs.entries() {
+                decl.body().unwrap().validate().unwrap();
+            }
+            let bytes = module.to_wasm_bytes().unwrap();
+            wasmparser::Validator::new().validate_all(&bytes).unwrap();
+            // Deterministic for a given seed.
+            assert_eq!(
+                bytes,
+                gen_module(seed, &GenOptions::default())
+                    .to_wasm_bytes()
+                    .unwrap()
+            );
+        }
+    }
+}
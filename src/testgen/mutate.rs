@@ -0,0 +1,440 @@
+//! IR mutation for fuzzing passes.
+//!
+//! Starting from a valid body (generated or parsed), small mutations
+//! reach IR shapes that neither the frontend nor the generator
+//! produces directly. Some mutations preserve the function's
+//! semantics and so may be used for differential testing of a pass
+//! against the original; the others change behavior but keep the IR
+//! valid. `check_pass` drives a pass over a sequence of mutants and
+//! checks that its output stays valid and that it is idempotent.
+
+use super::Rng;
+use crate::ir::{Block, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::passes::switch::values_used_outside_def_block;
+use crate::Operator;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A kind of mutation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mutation {
+    /// Swap the operands of a commutative operator.
+    SwapOperands,
+    /// Replace a value `v` with `v + 0`.
+    AddZero,
+    /// Give one edge into a block its own copy of that block.
+    DuplicateBlock,
+    /// Insert an empty block on an edge.
+    SplitEdge,
+    /// Change the value of an integer constant.
+    ChangeConstant,
+    /// Swap the targets of a conditional branch.
+    InvertBranch,
+}
+
+impl Mutation {
+    /// Every kind of mutation.
+    pub const ALL: &'static [Mutation] = &[
+        Mutation::SwapOperands,
+        Mutation::AddZero,
+        Mutation::DuplicateBlock,
+        Mutation::SplitEdge,
+        Mutation::ChangeConstant,
+        Mutation::InvertBranch,
+    ];
+
+    /// Does this mutation leave the function's behavior unchanged?
+    pub fn preserves_semantics(self) -> bool {
+        !matches!(self, Mutation::ChangeConstant | Mutation::InvertBranch)
+    }
+}
+
+/// Apply `mutation` at a randomly chosen place in `body`. Returns
+/// `false` if the body has no place where it applies.
+pub fn mutate(rng: &mut Rng, body: &mut FunctionBody, mutation: Mutation) -> bool {
+    let applied = match mutation {
+        Mutation::SwapOperands => swap_operands(rng, body),
+        Mutation::AddZero => add_zero(rng, body),
+        Mutation::DuplicateBlock => duplicate_block(rng, body),
+        Mutation::SplitEdge => split_edge(rng, body),
+        Mutation::ChangeConstant => change_constant(rng, body),
+        Mutation::InvertBranch => invert_branch(rng, body),
+    };
+    if applied {
+        body.recompute_edges();
+    }
+    applied
+}
+
+/// Apply a randomly chosen mutation, restricted to
+/// semantics-preserving ones if `preserving` is set. Returns the
+/// mutation applied, if any applied.
+pub fn mutate_random(rng: &mut Rng, body: &mut FunctionBody, preserving: bool) -> Option<Mutation> {
+    let choices = Mutation::ALL
+        .iter()
+        .copied()
+        .filter(|m| !preserving || m.preserves_semantics())
+        .collect::<Vec<_>>();
+    // Try a few times, since not every mutation applies to every body.
+    for _ in 0..choices.len() * 2 {
+        let mutation = *rng.choose(&choices);
+        if mutate(rng, body, mutation) {
+            return Some(mutation);
+        }
+    }
+    None
+}
+
+/// Apply `rounds` random mutations to `body`, starting from `seed`,
+/// and after each one run `pass` on a copy of the mutant. Fails if a
+/// mutant, the pass's output, or the output of running the pass a
+/// second time does not validate, or if the second run changes the
+/// body (i.e., the pass is not idempotent).
+pub fn check_pass<F: FnMut(&mut FunctionBody)>(
+    seed: u64,
+    body: &FunctionBody,
+    rounds: usize,
+    mut pass: F,
+) -> Result<()> {
+    let mut rng = Rng::new(seed);
+    let mut mutant = body.clone();
+    for round in 0..rounds {
+        let mutation = mutate_random(&mut rng, &mut mutant, false);
+        mutant
+            .validate()
+            .map_err(|e| e.context(format!("round {}: mutant after {:?}", round, mutation)))?;
+
+        let mut once = mutant.clone();
+        pass(&mut once);
+        once.validate()
+            .map_err(|e| e.context(format!("round {}: pass output", round)))?;
+        let mut twice = once.clone();
+        pass(&mut twice);
+        twice
+            .validate()
+            .map_err(|e| e.context(format!("round {}: second pass output", round)))?;
+        let (once, twice) = (
+            once.display("", None).to_string(),
+            twice.display("", None).to_string(),
+        );
+        if once != twice {
+            anyhow::bail!(
+                "round {}: pass is not idempotent after {:?}:\n{}\nbecame:\n{}",
+                round,
+                mutation,
+                once,
+                twice
+            );
+        }
+    }
+    Ok(())
+}
+
+/// All instructions placed in blocks, with their blocks and
+/// positions.
+fn insts(body: &FunctionBody) -> Vec<(Block, usize, Value)> {
+    body.blocks
+        .entries()
+        .flat_map(|(block, def)| {
+            def.insts
+                .iter()
+                .enumerate()
+                .map(move |(i, &inst)| (block, i, inst))
+        })
+        .collect()
+}
+
+fn choose_where<T: Copy, P: Fn(&T) -> bool>(rng: &mut Rng, items: &[T], pred: P) -> Option<T> {
+    let matching = items
+        .iter()
+        .copied()
+        .filter(|t| pred(t))
+        .collect::<Vec<_>>();
+    if matching.is_empty() {
+        None
+    } else {
+        Some(*rng.choose(&matching))
+    }
+}
+
+fn swap_operands(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    let insts = insts(body);
+    let chosen = choose_where(rng, &insts, |&(_, _, inst)| {
+        matches!(
+            body.values[inst],
+            ValueDef::Operator(
+                Operator::I32Add
+                    | Operator::I32Mul
+                    | Operator::I32And
+                    | Operator::I32Or
+                    | Operator::I32Xor
+                    | Operator::I32Eq
+                    | Operator::I32Ne
+                    | Operator::I64Add
+                    | Operator::I64Mul
+                    | Operator::I64And
+                    | Operator::I64Or
+                    | Operator::I64Xor
+                    | Operator::I64Eq
+                    | Operator::I64Ne,
+                ..
+            )
+        )
+    });
+    match chosen {
+        Some((_, _, inst)) => {
+            if let ValueDef::Operator(_, args, _) = body.values[inst] {
+                body.arg_pool[args].swap(0, 1);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+fn add_zero(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    let insts = insts(body);
+    let chosen = choose_where(rng, &insts, |&(_, _, inst)| match &body.values[inst] {
+        ValueDef::Operator(_, _, tys) => {
+            matches!(&body.type_pool[*tys], [Type::I32] | [Type::I64])
+        }
+        _ => false,
+    });
+    let (block, pos, inst) = match chosen {
+        Some(chosen) => chosen,
+        None => return false,
+    };
+    // Move the original definition to a new value and redefine the
+    // old one as `new + 0`, so that every use sees the addition.
+    let tys = match body.values[inst] {
+        ValueDef::Operator(_, _, tys) => tys,
+        _ => unreachable!(),
+    };
+    let (zero, add) = match body.type_pool[tys][0] {
+        Type::I32 => (Operator::I32Const { value: 0 }, Operator::I32Add),
+        _ => (Operator::I64Const { value: 0 }, Operator::I64Add),
+    };
+    let orig = body.add_value(body.values[inst].clone());
+    let zero = body.add_value(ValueDef::Operator(zero, Default::default(), tys));
+    for value in [orig, zero] {
+        body.value_blocks[value] = block;
+        body.copy_loc(inst, value);
+    }
+    let args = body.arg_pool.double(orig, zero);
+    body.values[inst] = ValueDef::Operator(add, args, tys);
+    body.blocks[block].insts.splice(pos..pos, [orig, zero]);
+    true
+}
+
+/// The (block, target index) of every edge in the body.
+fn edges(body: &FunctionBody) -> Vec<(Block, usize)> {
+    let mut edges = vec![];
+    for (block, def) in body.blocks.entries() {
+        let mut index = 0;
+        def.terminator.visit_targets(|_| {
+            edges.push((block, index));
+            index += 1;
+        });
+    }
+    edges
+}
+
+fn duplicate_block(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    // A copy of a block cannot supply values to the blocks that the
+    // original dominates, so only self-contained blocks are copied.
+    let used_outside = values_used_outside_def_block(body);
+    let edges = edges(body);
+    let chosen = choose_where(rng, &edges, |&(from, index)| {
+        let to = body.blocks[from]
+            .terminator
+            .visit_target(index, |target| target.block);
+        let def = &body.blocks[to];
+        to != body.entry
+            && to != from
+            && def
+                .params
+                .iter()
+                .map(|&(_, param)| param)
+                .chain(def.insts.iter().copied())
+                .all(|value| !used_outside.contains(&value))
+    });
+    let (from, index) = match chosen {
+        Some(chosen) => chosen,
+        None => return false,
+    };
+    let orig = body.blocks[from]
+        .terminator
+        .visit_target(index, |target| target.block);
+
+    let copy = body.add_block();
+    let mut map: HashMap<Value, Value> = HashMap::new();
+    for (ty, param) in body.blocks[orig].params.clone() {
+        map.insert(param, body.add_blockparam(copy, ty));
+    }
+    for inst in body.blocks[orig].insts.clone() {
+        let mut def = body.values[inst].clone();
+        if let ValueDef::Operator(_, args, _) = &mut def {
+            *args = body.arg_pool.deep_clone(*args);
+        }
+        def.update_uses(&mut body.arg_pool, |value| {
+            if let Some(&new) = map.get(value) {
+                *value = new;
+            }
+        });
+        let new = body.add_value(def);
+        body.append_to_block(copy, new);
+        body.copy_loc(inst, new);
+        map.insert(inst, new);
+    }
+    let mut terminator = body.blocks[orig].terminator.clone();
+    terminator.update_uses(|value| {
+        if let Some(&new) = map.get(value) {
+            *value = new;
+        }
+    });
+    body.blocks[copy].terminator = terminator;
+    body.blocks[from]
+        .terminator
+        .update_target(index, |target| target.block = copy);
+    true
+}
+
+fn split_edge(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    let edges = edges(body);
+    let (from, index) = match choose_where(rng, &edges, |_| true) {
+        Some(chosen) => chosen,
+        None => return false,
+    };
+    let target = body.blocks[from]
+        .terminator
+        .visit_target(index, |target| target.clone());
+    let split = body.add_block();
+    let params = target
+        .args
+        .iter()
+        .map(|&arg| {
+            let arg = body.resolve_alias(arg);
+            let ty = body.values[arg].ty(&body.type_pool).unwrap();
+            body.add_blockparam(split, ty)
+        })
+        .collect();
+    body.blocks[split].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: target.block,
+            args: params,
+        },
+    };
+    body.blocks[from]
+        .terminator
+        .update_target(index, |target| target.block = split);
+    true
+}
+
+fn change_constant(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    let insts = insts(body);
+    let chosen = choose_where(rng, &insts, |&(_, _, inst)| {
+        matches!(
+            body.values[inst],
+            ValueDef::Operator(Operator::I32Const { .. } | Operator::I64Const { .. }, ..)
+        )
+    });
+    let delta = 1 + rng.below(16) as u64;
+    match chosen {
+        Some((_, _, inst)) => {
+            if let ValueDef::Operator(op, ..) = &mut body.values[inst] {
+                match op {
+                    Operator::I32Const { value } => *value = value.wrapping_add(delta as u32),
+                    Operator::I64Const { value } => *value = value.wrapping_add(delta),
+                    _ => unreachable!(),
+                }
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+fn invert_branch(rng: &mut Rng, body: &mut FunctionBody) -> bool {
+    let blocks = body.blocks.iter().collect::<Vec<_>>();
+    let chosen = choose_where(rng, &blocks, |&block| {
+        matches!(body.blocks[block].terminator, Terminator::CondBr { .. })
+    });
+    match chosen {
+        Some(block) => {
+            if let Terminator::CondBr {
+                if_true, if_false, ..
+            } = &mut body.blocks[block].terminator
+            {
+                std::mem::swap(if_true, if_false);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testgen::{gen_module, GenOptions};
+    use crate::{ConstVal, InterpContext};
+
+    #[test]
+    fn mutants_stay_valid_through_resolve_aliases() {
+        for seed in 0..8 {
+            let module = gen_module(seed, &GenOptions::default());
+            for (_, decl) in module.funcs.entries() {
+                check_pass(seed, decl.body().unwrap(), 16, |body| {
+                    crate::passes::resolve_aliases::run(body)
+                })
+                .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn preserving_mutants_compute_the_same_results() {
+        let options = GenOptions {
+            calls: false,
+            ..GenOptions::default()
+        };
+        for seed in 0..8 {
+            let module = gen_module(seed, &options);
+            let mut mutated = module.clone();
+            let mut rng = Rng::new(seed);
+            for (_, decl) in mutated.funcs.entries_mut() {
+                let body = decl.body_mut().unwrap();
+                for _ in 0..16 {
+                    mutate_random(&mut rng, body, true);
+                }
+                body.validate().unwrap();
+            }
+
+            for (func, decl) in module.funcs.entries() {
+                let sig = &module.signatures[decl.sig()];
+                let args = sig
+                    .params
+                    .iter()
+                    .map(|ty| match ty {
+                        Type::I32 => ConstVal::I32(seed as u32),
+                        Type::I64 => ConstVal::I64(seed),
+                        Type::F32 => ConstVal::F32((seed as f32).to_bits()),
+                        _ => ConstVal::F64((seed as f64).to_bits()),
+                    })
+                    .collect::<Vec<_>>();
+                let expected = InterpContext::new(&module)
+                    .unwrap()
+                    .call(&module, func, &args)
+                    .ok()
+                    .ok();
+                let actual = InterpContext::new(&mutated)
+                    .unwrap()
+                    .call(&mutated, func, &args)
+                    .ok()
+                    .ok();
+                assert!(expected.is_some());
+                assert_eq!(expected, actual);
+            }
+        }
+    }
+}